@@ -0,0 +1,210 @@
+//! Generates the board-specific parts of the memory layout.
+//!
+//! The board is chosen by the `EMBRS_BOARD` environment variable (default
+//! `stm32f4discovery`), which names a description in `boards/`.  From it this
+//! writes two files to `OUT_DIR`:
+//!
+//! - `memory.ld`, which `layout.ld` includes: the `MEMORY` regions, plus the
+//!   stack and heap bounds (`__STACK_BASE`, `__STACK_LIMIT`, `__HEAP_START`,
+//!   `__HEAP_END`) and the smallest stack allowed (`__STACK_MIN`).
+//! - `board.rs`, which the application includes: the same facts as Rust
+//!   constants.
+//!
+//! A board description is a list of `key = value` lines; `#` starts a
+//! comment.  Regions are given as an origin and a size, which may carry a `K`
+//! or `M` suffix:
+//!
+//!     flash = 0x08000000 1024K
+//!     ram   = 0x20000000 128K
+//!     ccm   = 0x10000000 64K
+//!
+//! `flash` and `ram` are required.  `stack` (default 8K) is the smallest
+//! stack the board's programs can live with.  With `ccm`, the stack gets all
+//! of CCM that the CCM statics don't use, which must be at least `stack`
+//! bytes; without it, the stack is the top `stack` bytes of main RAM.
+
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+const DEFAULT_BOARD : &'static str = "stm32f4discovery";
+const DEFAULT_STACK_SIZE : u32 = 8 * 1024;
+
+#[derive(Copy, Clone)]
+struct Region {
+    origin: u32,
+    size: u32,
+}
+
+struct Board {
+    name: String,
+    flash: Region,
+    ram: Region,
+    ccm: Option<Region>,
+    stack_size: u32,
+}
+
+fn parse_number(s: &str) -> Result<u32, String> {
+    let (digits, scale) = if s.ends_with('K') {
+        (&s[..s.len() - 1], 1024)
+    } else if s.ends_with('M') {
+        (&s[..s.len() - 1], 1024 * 1024)
+    } else {
+        (s, 1)
+    };
+    let n = if digits.starts_with("0x") {
+        u32::from_str_radix(&digits[2..], 16)
+    } else {
+        digits.parse()
+    };
+    n.map(|n| n * scale).map_err(|_| format!("bad number: {}", s))
+}
+
+fn parse_region(s: &str) -> Result<Region, String> {
+    let parts: Vec<&str> = s.split_whitespace().collect();
+    if parts.len() != 2 {
+        return Err(format!("expected origin and size: {}", s))
+    }
+    Ok(Region {
+        origin: try!(parse_number(parts[0])),
+        size: try!(parse_number(parts[1])),
+    })
+}
+
+fn parse_board(name: &str, text: &str) -> Result<Board, String> {
+    let mut flash = None;
+    let mut ram = None;
+    let mut ccm = None;
+    let mut stack_size = DEFAULT_STACK_SIZE;
+
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue
+        }
+        let mut kv = line.splitn(2, '=');
+        let key = kv.next().unwrap().trim();
+        let value = match kv.next() {
+            Some(v) => v.trim(),
+            None => return Err(format!("expected key = value: {}", line)),
+        };
+        match key {
+            "flash" => flash = Some(try!(parse_region(value))),
+            "ram" => ram = Some(try!(parse_region(value))),
+            "ccm" => ccm = Some(try!(parse_region(value))),
+            "stack" => stack_size = try!(parse_number(value)),
+            _ => return Err(format!("unknown key: {}", key)),
+        }
+    }
+
+    // The stack's MPU guard (see `arm_m::stack`) needs 32-byte alignment.
+    if stack_size % 32 != 0 {
+        return Err("stack size must be a multiple of 32".to_string())
+    }
+
+    Ok(Board {
+        name: name.to_string(),
+        flash: try!(flash.ok_or("missing flash".to_string())),
+        ram: try!(ram.ok_or("missing ram".to_string())),
+        ccm: ccm,
+        stack_size: stack_size,
+    })
+}
+
+fn memory_ld(b: &Board) -> String {
+    let mut s = String::new();
+    s.push_str(&format!("/* Generated by build.rs for board {}. */\n\n",
+                        b.name));
+    s.push_str("MEMORY {\n");
+    s.push_str(&format!("    rom(RX)     : ORIGIN = {:#010x}, LENGTH = {}\n",
+                        b.flash.origin, b.flash.size));
+    if let Some(c) = b.ccm {
+        s.push_str(&format!("    ram_c(WAIL) : ORIGIN = {:#010x}, \
+                             LENGTH = {}\n",
+                            c.origin, c.size));
+    }
+    s.push_str(&format!("    ram(WAIL)   : ORIGIN = {:#010x}, LENGTH = {}\n",
+                        b.ram.origin, b.ram.size));
+    s.push_str("}\n\n");
+    s.push_str(&format!("__STACK_MIN = {};\n\n", b.stack_size));
+
+    if b.ccm.is_some() {
+        s.push_str("\
+/* The stack gets whatever of CCM the CCM statics leave. */
+__STACK_BASE = ORIGIN(ram_c) + LENGTH(ram_c);
+__STACK_LIMIT = ALIGN(_eccm_bss, 32);
+
+/* The heap gets the rest of main RAM after static data. */
+__HEAP_START = _ebss;
+__HEAP_END = ORIGIN(ram) + LENGTH(ram);
+");
+    } else {
+        s.push_str("\
+/* Without CCM, its statics share main RAM... */
+REGION_ALIAS(\"ram_c\", ram);
+
+/* ...and the stack takes the top of it. */
+__STACK_BASE = ORIGIN(ram) + LENGTH(ram);
+__STACK_LIMIT = __STACK_BASE - __STACK_MIN;
+ASSERT(_eccm_bss <= __STACK_LIMIT, \"static data overlaps the stack\")
+
+/* The heap gets what's left in between. */
+__HEAP_START = _eccm_bss;
+__HEAP_END = __STACK_LIMIT;
+");
+    }
+    s
+}
+
+fn board_rs(b: &Board) -> String {
+    let mut s = String::new();
+    s.push_str(&format!("// Generated by build.rs for board {}.\n\n", b.name));
+    s.push_str(&format!("pub const BOARD_NAME : &'static str = {:?};\n\n",
+                        b.name));
+    s.push_str(&format!("pub const FLASH_ORIGIN : usize = {:#010x};\n",
+                        b.flash.origin));
+    s.push_str(&format!("pub const FLASH_SIZE : usize = {:#x};\n",
+                        b.flash.size));
+    s.push_str(&format!("pub const RAM_ORIGIN : usize = {:#010x};\n",
+                        b.ram.origin));
+    s.push_str(&format!("pub const RAM_SIZE : usize = {:#x};\n", b.ram.size));
+    let ccm = b.ccm.unwrap_or(Region { origin: 0, size: 0 });
+    s.push_str("/// Zero if the part has no CCM.\n");
+    s.push_str(&format!("pub const CCM_ORIGIN : usize = {:#010x};\n",
+                        ccm.origin));
+    s.push_str(&format!("pub const CCM_SIZE : usize = {:#x};\n", ccm.size));
+    s
+}
+
+fn write_file(path: &Path, contents: &str) {
+    let mut f = File::create(path).unwrap();
+    f.write_all(contents.as_bytes()).unwrap();
+}
+
+fn main() {
+    let board_name = env::var("EMBRS_BOARD")
+        .unwrap_or(DEFAULT_BOARD.to_string());
+    let board_path = format!("boards/{}.board", board_name);
+
+    let mut text = String::new();
+    match File::open(&board_path) {
+        Ok(mut f) => { let _ = f.read_to_string(&mut text).unwrap(); }
+        Err(e) => panic!("can't read board description {}: {}",
+                         board_path, e),
+    }
+    let board = match parse_board(&board_name, &text) {
+        Ok(b) => b,
+        Err(e) => panic!("{}: {}", board_path, e),
+    };
+
+    let out = env::var("OUT_DIR").unwrap();
+    let out = Path::new(&out);
+    write_file(&out.join("memory.ld"), &memory_ld(&board));
+    write_file(&out.join("board.rs"), &board_rs(&board));
+
+    // Lets `layout.ld` find `memory.ld`.
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed={}", board_path);
+    println!("cargo:rerun-if-env-changed=EMBRS_BOARD");
+}
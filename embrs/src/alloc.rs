@@ -0,0 +1,350 @@
+//! A simple, deterministic heap.
+//!
+//! emb.rs itself never allocates, and by default neither can applications.
+//! With the `heap` feature, this module provides a first-fit allocator over
+//! the RAM left after `.data` and `.bss` (from `__HEAP_START` to `__HEAP_END`
+//! in the linker script) and registers it as the global allocator, so that
+//! the `alloc` and `collections` crates work.
+//!
+//! The free list is kept in address order and neighbours are merged as soon
+//! as they're freed, so allocation time depends only on the number of free
+//! blocks -- which is small in programs that allocate mostly at startup, the
+//! pattern that suits embedded systems best.  `stats` reports usage and
+//! fragmentation so that this can be checked.
+//!
+//! Operations run with interrupts masked, so the heap may be used from
+//! interrupt handlers (though it's rarely a good idea).
+
+use core::ptr;
+
+#[cfg(not(feature = "host-test"))]
+use arm_m;
+
+/// Header of a free block, stored in the block itself.
+struct FreeBlock {
+    /// Size of the block in bytes, including this header.
+    size: usize,
+    /// Address of the next free block, or zero.
+    next: usize,
+}
+
+/// Allocation granularity, and the smallest block: every block must be able
+/// to hold a `FreeBlock` header once freed.  Block addresses and sizes are
+/// multiples of this.
+#[cfg(target_pointer_width = "32")]
+const GRANULE : usize = 8;
+#[cfg(target_pointer_width = "64")]
+const GRANULE : usize = 16;
+
+fn round_up(x: usize, to: usize) -> usize {
+    (x + to - 1) & !(to - 1)
+}
+
+/// Usage statistics; see `stats`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct HeapStats {
+    /// Size of the heap region in bytes.
+    pub total: usize,
+    /// Bytes currently allocated, including rounding.
+    pub used: usize,
+    /// The most bytes that have been allocated at once.
+    pub peak_used: usize,
+    /// Size of the largest free block: the biggest allocation that can
+    /// currently succeed (alignment permitting).
+    pub largest_free: usize,
+    /// Number of free blocks.
+    pub free_blocks: usize,
+    /// Number of live allocations.
+    pub allocations: usize,
+    /// Number of allocation requests that have failed.
+    pub failures: usize,
+}
+
+impl HeapStats {
+    /// Bytes not currently allocated.
+    pub fn free(&self) -> usize {
+        self.total - self.used
+    }
+
+    /// Fraction of free memory that is *not* in the largest free block, from
+    /// zero (all free memory is contiguous) towards one.
+    pub fn fragmentation(&self) -> f32 {
+        let free = self.free();
+        if free == 0 {
+            0.
+        } else {
+            1. - (self.largest_free as f32 / free as f32)
+        }
+    }
+}
+
+/// A first-fit heap over a fixed region of memory.
+pub struct Heap {
+    start: usize,
+    end: usize,
+    /// Address of the lowest free block, or zero.
+    free: usize,
+    used: usize,
+    peak_used: usize,
+    allocations: usize,
+    failures: usize,
+}
+
+impl Heap {
+    /// Creates a heap with no memory; every allocation will fail until
+    /// `init`.
+    pub const fn empty() -> Heap {
+        Heap {
+            start: 0,
+            end: 0,
+            free: 0,
+            used: 0,
+            peak_used: 0,
+            allocations: 0,
+            failures: 0,
+        }
+    }
+
+    /// Gives the heap the `size` bytes at `start`, trimmed inward to the
+    /// allocation granularity.  Any previous region is forgotten.
+    ///
+    /// This is unsafe because the heap will hand out the memory without any
+    /// way of knowing whether something else is using it.
+    pub unsafe fn init(&mut self, start: usize, size: usize) {
+        *self = Heap::empty();
+        let aligned = round_up(start, GRANULE);
+        let end = (start + size) & !(GRANULE - 1);
+        if aligned >= end {
+            return
+        }
+        self.start = aligned;
+        self.end = end;
+        self.free = aligned;
+        ptr::write(aligned as *mut FreeBlock, FreeBlock {
+            size: end - aligned,
+            next: 0,
+        })
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.end != 0
+    }
+
+    fn block(addr: usize) -> &'static mut FreeBlock {
+        unsafe { &mut *(addr as *mut FreeBlock) }
+    }
+
+    /// Allocates `size` bytes aligned to `align` (a power of two), returning
+    /// null if no free block can hold them.
+    pub fn allocate(&mut self, size: usize, align: usize) -> *mut u8 {
+        let size = round_up(if size == 0 { 1 } else { size }, GRANULE);
+        let align = if align < GRANULE { GRANULE } else { align };
+
+        let mut prev = 0;
+        let mut cur = self.free;
+        while cur != 0 {
+            let (cur_size, next) = {
+                let b = Heap::block(cur);
+                (b.size, b.next)
+            };
+            // Both `cur` and `align` are multiples of the granule, so any gap
+            // before the aligned address is big enough to stay free.
+            let addr = round_up(cur, align);
+            if addr + size <= cur + cur_size {
+                let lead = addr - cur;
+                let tail = cur + cur_size - (addr + size);
+
+                // Whatever follows the allocation in this block remains
+                // free, as a block of its own.
+                let after = if tail != 0 {
+                    let t = addr + size;
+                    unsafe {
+                        ptr::write(t as *mut FreeBlock, FreeBlock {
+                            size: tail,
+                            next: next,
+                        })
+                    }
+                    t
+                } else {
+                    next
+                };
+
+                if lead != 0 {
+                    let b = Heap::block(cur);
+                    b.size = lead;
+                    b.next = after;
+                } else if prev == 0 {
+                    self.free = after;
+                } else {
+                    Heap::block(prev).next = after;
+                }
+
+                self.used += size;
+                if self.used > self.peak_used {
+                    self.peak_used = self.used
+                }
+                self.allocations += 1;
+                return addr as *mut u8
+            }
+            prev = cur;
+            cur = next;
+        }
+
+        self.failures += 1;
+        ptr::null_mut()
+    }
+
+    /// Returns the `size` bytes at `p`, which must have come from `allocate`
+    /// with the same `size`, to the heap.
+    pub unsafe fn deallocate(&mut self, p: *mut u8, size: usize) {
+        let addr = p as usize;
+        let size = round_up(if size == 0 { 1 } else { size }, GRANULE);
+
+        // Find the free blocks on either side, to keep the list in address
+        // order.
+        let mut prev = 0;
+        let mut next = self.free;
+        while next != 0 && next < addr {
+            prev = next;
+            next = Heap::block(next).next;
+        }
+
+        ptr::write(addr as *mut FreeBlock, FreeBlock {
+            size: size,
+            next: next,
+        });
+
+        // Merge with the following block...
+        if next != 0 && addr + size == next {
+            let n = Heap::block(next);
+            let b = Heap::block(addr);
+            b.size += n.size;
+            b.next = n.next;
+        }
+
+        // ...and the preceding one.
+        if prev == 0 {
+            self.free = addr;
+        } else {
+            let p = Heap::block(prev);
+            if prev + p.size == addr {
+                let b = Heap::block(addr);
+                p.size += b.size;
+                p.next = b.next;
+            } else {
+                p.next = addr;
+            }
+        }
+
+        self.used -= size;
+        self.allocations -= 1;
+    }
+
+    /// Number of bytes actually set aside for an allocation of `size`.
+    pub fn usable_size(size: usize) -> usize {
+        round_up(if size == 0 { 1 } else { size }, GRANULE)
+    }
+
+    /// Gathers usage statistics, walking the free list.
+    pub fn stats(&self) -> HeapStats {
+        let mut s = HeapStats {
+            total: self.end - self.start,
+            used: self.used,
+            peak_used: self.peak_used,
+            allocations: self.allocations,
+            failures: self.failures,
+            .. HeapStats::default()
+        };
+        let mut cur = self.free;
+        while cur != 0 {
+            let b = Heap::block(cur);
+            s.free_blocks += 1;
+            if b.size > s.largest_free {
+                s.largest_free = b.size
+            }
+            cur = b.next;
+        }
+        s
+    }
+}
+
+#[cfg(not(feature = "host-test"))]
+extern {
+    static __HEAP_START: u8;
+    static __HEAP_END: u8;
+}
+
+/// The system heap.  Only touched with interrupts masked.
+#[cfg(not(feature = "host-test"))]
+static mut HEAP : Heap = Heap::empty();
+
+/// Runs `body` on the system heap, giving it the linker-defined region first
+/// if this is the first use.
+#[cfg(not(feature = "host-test"))]
+fn with_heap<R, F: FnOnce(&mut Heap) -> R>(body: F) -> R {
+    arm_m::without_interrupts(|| unsafe {
+        if !HEAP.is_initialized() {
+            let start = &__HEAP_START as *const u8 as usize;
+            let end = &__HEAP_END as *const u8 as usize;
+            HEAP.init(start, end - start)
+        }
+        body(&mut HEAP)
+    })
+}
+
+/// Reports usage statistics for the system heap.
+#[cfg(not(feature = "host-test"))]
+pub fn stats() -> HeapStats {
+    with_heap(|h| h.stats())
+}
+
+// Entry points for the standard library's allocator interface, which make
+// this crate the global allocator (see the `allocator` attribute in lib.rs).
+
+#[cfg(not(feature = "host-test"))]
+#[no_mangle]
+pub extern fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
+    with_heap(|h| h.allocate(size, align))
+}
+
+#[cfg(not(feature = "host-test"))]
+#[no_mangle]
+pub extern fn __rust_deallocate(p: *mut u8, old_size: usize, _align: usize) {
+    with_heap(|h| unsafe { h.deallocate(p, old_size) })
+}
+
+#[cfg(not(feature = "host-test"))]
+#[no_mangle]
+pub extern fn __rust_reallocate(p: *mut u8, old_size: usize, size: usize,
+                                align: usize) -> *mut u8 {
+    if Heap::usable_size(size) == Heap::usable_size(old_size) {
+        return p
+    }
+    with_heap(|h| {
+        let q = h.allocate(size, align);
+        if !q.is_null() {
+            let n = if size < old_size { size } else { old_size };
+            unsafe {
+                ptr::copy_nonoverlapping(p, q, n);
+                h.deallocate(p, old_size)
+            }
+        }
+        q
+    })
+}
+
+#[cfg(not(feature = "host-test"))]
+#[no_mangle]
+pub extern fn __rust_reallocate_inplace(_p: *mut u8, old_size: usize,
+                                        _size: usize, _align: usize)
+    -> usize {
+    // Blocks are never resized in place; report the existing size, which
+    // tells the caller whether the new size already fits.
+    Heap::usable_size(old_size)
+}
+
+#[cfg(not(feature = "host-test"))]
+#[no_mangle]
+pub extern fn __rust_usable_size(size: usize, _align: usize) -> usize {
+    Heap::usable_size(size)
+}
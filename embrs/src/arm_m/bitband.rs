@@ -0,0 +1,172 @@
+//! ARMv7-M bit-band support.
+//!
+//! The ARMv7-M memory map includes two 1MiB bit-band regions, one at the start
+//! of the SRAM area and one at the start of the peripheral area.  Each bit in
+//! these regions is mirrored as a full word in a corresponding 32MiB *alias*
+//! region.  Writing 0 or 1 to the alias word clears or sets the bit as a
+//! single, indivisible read-modify-write performed by the bus; reading it
+//! returns the bit.
+//!
+//! This gives us atomic single-bit updates without `LDREX`/`STREX` loops or
+//! masking interrupts.
+//!
+//! Beware that the bus implements a bit-band write as a read-modify-write of
+//! the *whole* word.  On registers with write-one-to-clear or read-to-clear
+//! bits, setting one bit through the alias can clobber the others; use the
+//! register's documented clear mechanism for those.
+
+use core::cell::UnsafeCell;
+use core::ptr;
+
+use arm_m::reg::Reg;
+
+/// Base of the SRAM bit-band region.
+pub const SRAM_BASE : usize = 0x2000_0000;
+/// Base of the SRAM bit-band alias region.
+pub const SRAM_ALIAS_BASE : usize = 0x2200_0000;
+/// Base of the peripheral bit-band region.
+pub const PERIPH_BASE : usize = 0x4000_0000;
+/// Base of the peripheral bit-band alias region.
+pub const PERIPH_ALIAS_BASE : usize = 0x4200_0000;
+/// Size of each bit-band region in bytes.
+pub const REGION_SIZE : usize = 0x10_0000;
+
+/// Computes the alias word address for bit `bit` of the byte at `addr`, or
+/// `None` if `addr` is outside both bit-band regions.
+///
+/// Bits are numbered from the LSB.  `bit` may exceed 7, in which case it
+/// addresses bits in subsequent bytes (so bit 31 of a word-aligned `addr` is
+/// the MSB of the little-endian word).
+#[inline]
+pub fn alias_address(addr: usize, bit: u32) -> Option<usize> {
+    let (base, alias) = if addr >= SRAM_BASE && addr < SRAM_BASE + REGION_SIZE {
+        (SRAM_BASE, SRAM_ALIAS_BASE)
+    } else if addr >= PERIPH_BASE && addr < PERIPH_BASE + REGION_SIZE {
+        (PERIPH_BASE, PERIPH_ALIAS_BASE)
+    } else {
+        return None
+    };
+
+    Some(alias + (addr - base) * 32 + (bit as usize) * 4)
+}
+
+fn reg_alias(reg: &Reg<u32>, bit: u32) -> *mut u32 {
+    assert!(bit < 32);
+    match alias_address(reg as *const Reg<u32> as usize, bit) {
+        Some(a) => a as *mut u32,
+        None => panic!("register outside bit-band region"),
+    }
+}
+
+/// Sets bit `bit` (0-31) of `reg` with a single store to its alias.
+///
+/// # Panics
+///
+/// If `reg` is outside the bit-band regions, or `bit` is out of range.
+#[inline]
+pub fn set_bit(reg: &Reg<u32>, bit: u32) {
+    unsafe { ptr::write_volatile(reg_alias(reg, bit), 1) }
+}
+
+/// Clears bit `bit` (0-31) of `reg` with a single store to its alias.
+///
+/// # Panics
+///
+/// If `reg` is outside the bit-band regions, or `bit` is out of range.
+#[inline]
+pub fn clear_bit(reg: &Reg<u32>, bit: u32) {
+    unsafe { ptr::write_volatile(reg_alias(reg, bit), 0) }
+}
+
+/// Sets or clears bit `bit` (0-31) of `reg` according to `v`.
+///
+/// # Panics
+///
+/// If `reg` is outside the bit-band regions, or `bit` is out of range.
+#[inline]
+pub fn write_bit(reg: &Reg<u32>, bit: u32, v: bool) {
+    unsafe { ptr::write_volatile(reg_alias(reg, bit), v as u32) }
+}
+
+/// Reads bit `bit` (0-31) of `reg` through its alias.
+///
+/// # Panics
+///
+/// If `reg` is outside the bit-band regions, or `bit` is out of range.
+#[inline]
+pub fn read_bit(reg: &Reg<u32>, bit: u32) -> bool {
+    unsafe { ptr::read_volatile(reg_alias(reg, bit)) != 0 }
+}
+
+/// A word of 32 boolean flags that can be set, cleared, and tested
+/// individually and atomically through the bit-band alias.
+///
+/// Each set or clear is a single store, making this a cheap way to signal
+/// between interrupt handlers and thread code.  It is intended to be placed in
+/// a `static`:
+///
+/// ```
+/// static EVENTS: BitbandFlags = BitbandFlags::new();
+///
+/// extern "C" fn uart_isr() {
+///     EVENTS.set(RX_READY);
+/// }
+/// ```
+///
+/// # Panics
+///
+/// All operations panic if the flags don't live in the SRAM bit-band region.
+/// In particular, on parts with Core Coupled Memory, statics placed in CCM
+/// can't be used.
+pub struct BitbandFlags {
+    word: UnsafeCell<u32>,
+}
+
+unsafe impl Sync for BitbandFlags {}
+
+impl BitbandFlags {
+    /// Creates a set of flags, all clear.
+    pub const fn new() -> BitbandFlags {
+        BitbandFlags {
+            word: UnsafeCell::new(0),
+        }
+    }
+
+    fn alias(&self, flag: u32) -> *mut u32 {
+        assert!(flag < 32);
+        match alias_address(self.word.get() as usize, flag) {
+            Some(a) => a as *mut u32,
+            None => panic!("BitbandFlags outside bit-band region"),
+        }
+    }
+
+    /// Sets flag number `flag` (0-31).
+    #[inline]
+    pub fn set(&self, flag: u32) {
+        unsafe { ptr::write_volatile(self.alias(flag), 1) }
+    }
+
+    /// Clears flag number `flag` (0-31).
+    #[inline]
+    pub fn clear(&self, flag: u32) {
+        unsafe { ptr::write_volatile(self.alias(flag), 0) }
+    }
+
+    /// Sets flag number `flag` (0-31) to `v`.
+    #[inline]
+    pub fn write(&self, flag: u32, v: bool) {
+        unsafe { ptr::write_volatile(self.alias(flag), v as u32) }
+    }
+
+    /// Tests flag number `flag` (0-31).
+    #[inline]
+    pub fn is_set(&self, flag: u32) -> bool {
+        unsafe { ptr::read_volatile(self.alias(flag)) != 0 }
+    }
+
+    /// Reads all 32 flags at once, as a bit mask.
+    #[inline]
+    pub fn get_all(&self) -> u32 {
+        unsafe { ptr::read_volatile(self.word.get()) }
+    }
+}
@@ -0,0 +1,245 @@
+//! Crash dumps that survive reset.
+//!
+//! Install `crash_dump_isr` as the HardFault vector (and, if they're enabled,
+//! the MemManage, BusFault and UsageFault vectors).  When a fault occurs, it
+//! records the stacked registers, the fault status registers, and the tail of
+//! `log::LOG_RING` in a region of RAM that startup code neither initializes
+//! nor zeroes, and then resets the system.
+//!
+//! On the next boot, an init hook checks the region's magic number and CRC.
+//! If they're good, `previous` returns the dump, so the application can
+//! report it -- over a serial port, say -- before calling `clear`:
+//!
+//!     if let Some(d) = crashdump::previous() {
+//!         log_error!("crashed at pc {:#010x} (cfsr {:#010x})",
+//!                    d.pc(), d.cfsr);
+//!         crashdump::clear()
+//!     }
+//!
+//! The region lives at the start of RAM (see `.noinit` in `layout.ld`), so it
+//! stays put across firmware updates; a dump written by firmware with a
+//! different `CrashDump` layout fails the CRC and is ignored.  Only a reset
+//! preserves RAM, of course; a dump doesn't survive losing power.
+//!
+//! Not every crash is a fault.  `record_starvation` leaves a dump for a
+//! watchdog reset that's known to be coming (see `drivers::liveness`).
+
+use core::mem;
+use core::ptr;
+use core::slice;
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+use arm_m;
+use arm_m::scb::SCB;
+use crc::crc32;
+use log::LOG_RING;
+
+/// Marks a dump as complete.
+const DUMP_MAGIC : u32 = 0xdead_c0de;
+
+/// Bytes of log output kept in a dump.
+pub const LOG_TAIL_LEN : usize = 256;
+
+/// Offset of the first byte covered by the CRC: everything after `magic` and
+/// `crc` themselves.
+const CRC_START : usize = 8;
+
+/// CFSR bits indicating that the fault occurred while stacking the exception
+/// frame (`MSTKERR` and `STKERR`), so the frame can't be trusted.
+const CFSR_STACKING_ERRORS : u32 = (1 << 4) | (1 << 12);
+
+/// Indices of registers in the exception frame.
+const FRAME_LR : usize = 5;
+const FRAME_PC : usize = 6;
+
+/// The state of the system when it crashed.
+#[repr(C)]
+pub struct CrashDump {
+    magic: u32,
+    crc: u32,
+    /// Number of the exception that recorded the dump (3 for HardFault).
+    pub exception: u32,
+    /// The `EXC_RETURN` value in `lr` on entry to the handler, which says
+    /// (among other things) which stack was in use.
+    pub exc_return: u32,
+    /// Stack pointer at the time of the fault, pointing to `frame`.
+    pub sp: u32,
+    /// Registers stacked on exception entry: `r0`-`r3`, `r12`, `lr`, `pc`,
+    /// `xPSR`.  All zero if stacking itself faulted.
+    pub frame: [u32; 8],
+    pub cfsr: u32,
+    pub hfsr: u32,
+    pub mmfar: u32,
+    pub bfar: u32,
+    /// For a watchdog reset recorded by `record_starvation`, the tasks that
+    /// failed to check in; zero for faults.
+    pub missing_tasks: u32,
+    log_len: u32,
+    log: [u8; LOG_TAIL_LEN],
+}
+
+impl CrashDump {
+    /// Address of the faulting instruction (or, for imprecise faults, of an
+    /// instruction somewhat after it).
+    pub fn pc(&self) -> u32 {
+        self.frame[FRAME_PC]
+    }
+
+    /// Link register at the time of the fault.
+    pub fn lr(&self) -> u32 {
+        self.frame[FRAME_LR]
+    }
+
+    /// The last log output before the crash.  This may begin in the middle
+    /// of a line.
+    pub fn log_tail(&self) -> &[u8] {
+        &self.log[..self.log_len as usize]
+    }
+
+    fn compute_crc(&self) -> u32 {
+        let bytes = unsafe {
+            slice::from_raw_parts(self as *const CrashDump as *const u8,
+                                  mem::size_of::<CrashDump>())
+        };
+        crc32(&bytes[CRC_START..])
+    }
+}
+
+/// The dump region.  Its initializer is ignored: `.noinit` isn't loaded.
+#[link_section = ".noinit"]
+#[no_mangle]
+#[allow(private_no_mangle_statics)]
+static mut EMBRS_CRASH_DUMP : CrashDump = CrashDump {
+    magic: 0,
+    crc: 0,
+    exception: 0,
+    exc_return: 0,
+    sp: 0,
+    frame: [0; 8],
+    cfsr: 0,
+    hfsr: 0,
+    mmfar: 0,
+    bfar: 0,
+    missing_tasks: 0,
+    log_len: 0,
+    log: [0; LOG_TAIL_LEN],
+};
+
+/// Set at startup if the dump region holds a valid dump.
+static VALID : AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Checks the dump region for a valid dump.  This is called by an init hook
+/// in `startup`, before `main`.
+pub fn validate() {
+    let d = unsafe { &EMBRS_CRASH_DUMP };
+    let magic = unsafe { ptr::read_volatile(&d.magic) };
+    let valid = magic == DUMP_MAGIC
+        && d.log_len as usize <= LOG_TAIL_LEN
+        && d.crc == d.compute_crc();
+    VALID.store(valid, Ordering::Relaxed)
+}
+
+/// Gets the dump left by the crash that caused the last reset, if any.
+pub fn previous() -> Option<&'static CrashDump> {
+    if VALID.load(Ordering::Relaxed) {
+        Some(unsafe { &EMBRS_CRASH_DUMP })
+    } else {
+        None
+    }
+}
+
+/// Discards the dump, so that it won't be reported again after the next
+/// reset.
+pub fn clear() {
+    VALID.store(false, Ordering::Relaxed);
+    unsafe { ptr::write_volatile(&mut EMBRS_CRASH_DUMP.magic, 0) }
+}
+
+/// Records a dump, overwriting any previous one.  `frame` is the exception
+/// frame and `exc_return` the `EXC_RETURN` value from the handler's `lr`.
+///
+/// `crash_dump_isr` calls this for you; it's exposed for other fault
+/// handlers that want to leave a dump before giving up.
+///
+/// # Safety
+///
+/// This must be called from a fault handler, with `frame` pointing to the
+/// exception frame, and the system must be reset soon after: the previous
+/// dump may be in use by the application.
+pub unsafe fn record(frame: *const u32, exc_return: u32) {
+    let d = begin();
+    d.exception = arm_m::get_ipsr();
+    d.exc_return = exc_return;
+    d.sp = frame as usize as u32;
+    for (i, r) in d.frame.iter_mut().enumerate() {
+        *r = if (d.cfsr & CFSR_STACKING_ERRORS) == 0 {
+            ptr::read_volatile(frame.offset(i as isize))
+        } else {
+            0
+        }
+    }
+    finish(d)
+}
+
+/// Records a dump for a watchdog reset caused by the tasks in `missing`
+/// failing to check in.  There's no exception frame; `sp` and `frame` are
+/// zero.
+///
+/// # Safety
+///
+/// As for `record`, the system must be about to reset.
+pub unsafe fn record_starvation(missing: u32) {
+    let d = begin();
+    d.missing_tasks = missing;
+    finish(d)
+}
+
+/// Invalidates the dump region and fills in the parts common to all dumps.
+unsafe fn begin() -> &'static mut CrashDump {
+    let d = &mut EMBRS_CRASH_DUMP;
+    ptr::write_volatile(&mut d.magic, 0);
+
+    d.exception = 0;
+    d.exc_return = 0;
+    d.sp = 0;
+    d.frame = [0; 8];
+    d.cfsr = SCB.read_cfsr();
+    d.hfsr = SCB.read_hfsr();
+    d.mmfar = SCB.read_mmfar();
+    d.bfar = SCB.read_bfar();
+    d.missing_tasks = 0;
+    d.log_len = LOG_RING.snapshot(&mut d.log) as u32;
+    d
+}
+
+/// Seals the dump.
+unsafe fn finish(d: &mut CrashDump) {
+    d.crc = d.compute_crc();
+    ptr::write_volatile(&mut d.magic, DUMP_MAGIC)
+}
+
+/// Fault handler that records a dump and resets.  Install this as the
+/// `hard_fault` vector, and optionally as the other fault vectors.
+///
+/// This finds the exception frame -- on whichever stack was in use -- and
+/// passes it, with `EXC_RETURN`, to the handler proper.
+#[naked]
+pub extern "C" fn crash_dump_isr() {
+    unsafe {
+        asm!("tst lr, #4
+              ite eq
+              mrseq r0, MSP
+              mrsne r0, PSP
+              mov r1, lr
+              b embrs_crash_dump"
+             :::: "volatile")
+    }
+}
+
+/// Body of `crash_dump_isr`.
+#[no_mangle]
+pub unsafe extern "C" fn embrs_crash_dump(frame: *const u32,
+                                          exc_return: u32) -> ! {
+    record(frame, exc_return);
+    SCB.system_reset()
+}
@@ -0,0 +1,149 @@
+//! DebugMonitor exception support, for on-target debugging without a debugger.
+//!
+//! With monitor mode enabled and no halting debugger attached, debug events
+//! -- DWT watchpoint hits, FPB breakpoints, `BKPT` instructions -- raise the
+//! DebugMonitor exception instead of halting the processor.  Install
+//! `debug_mon_isr` as the `debug_mon` vector and register a handler, and the
+//! firmware can, say, log the culprit when a watched variable gets clobbered:
+//!
+//!     debug_monitor::set_handler(report_corruption);
+//!     debug_monitor::enable();
+//!     DWT.set_watchpoint(0, &CANARY as *const _ as usize, 4,
+//!                        WatchAccess::Write).unwrap();
+//!
+//! Breakpoints are treated as one-shot: the FPB comparator that fired is
+//! cleared before the handler is called, and a `BKPT` instruction is
+//! stepped over, so that returning from the exception doesn't immediately
+//! re-trigger it.
+
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use arm_m::dwt::{self, DWT};
+use arm_m::fpb::FPB;
+use arm_m::reg::{mmio, AtomicReg, Reg, RoReg};
+use arm_m::scb::SCB;
+
+/// Debug Halting Control and Status Register.
+const DHCSR_ADDRESS : usize = 0xe000edf0;
+
+/// Bit position of `C_DEBUGEN` in `DHCSR`: a halting debugger is in control.
+const DHCSR_C_DEBUGEN : u32 = 1 << 0;
+
+/// Bit position of `MON_EN` in `DEMCR`.
+const DEMCR_MON_EN : u32 = 1 << 16;
+
+/// Bit position of `TRCENA` in `DEMCR`, needed for DWT watchpoints.
+const DEMCR_TRCENA : u32 = 1 << 24;
+
+/// Offset, in words, of the stacked return address in an exception frame.
+const FRAME_PC : isize = 6;
+
+/// A debug event reported to the monitor handler.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum DebugEvent {
+    /// DWT comparator `comparator` matched.  The access is imprecise: `pc`
+    /// is the return address, typically an instruction or two past the one
+    /// that made the access.
+    Watchpoint { comparator: usize, pc: u32 },
+    /// An FPB breakpoint or `BKPT` instruction at `pc`.
+    Breakpoint { pc: u32 },
+    /// The external debug request signal was asserted.
+    External,
+}
+
+/// Function called from `debug_mon_isr` for each event.
+pub type MonitorHandler = fn(DebugEvent);
+
+/// Registered handler, stored as an address (or zero).
+static HANDLER : AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Registers `handler` to receive debug events.
+pub fn set_handler(handler: MonitorHandler) {
+    HANDLER.store(handler as usize, Ordering::Release)
+}
+
+fn demcr() -> &'static Reg<u32> {
+    unsafe { mmio(dwt::DEMCR_ADDRESS) }
+}
+
+/// Enables monitor mode, routing debug events to the DebugMonitor exception.
+/// This has no effect while a halting debugger is attached, which takes the
+/// events itself.
+pub fn enable() {
+    demcr().atomic_or(DEMCR_MON_EN | DEMCR_TRCENA)
+}
+
+/// Disables monitor mode.
+pub fn disable() {
+    demcr().atomic_nand(DEMCR_MON_EN)
+}
+
+/// Checks whether a halting debugger is attached, in which case the monitor
+/// won't see any events.
+pub fn is_debugger_attached() -> bool {
+    let dhcsr: &RoReg<u32> = unsafe { mmio(DHCSR_ADDRESS) };
+    (dhcsr.get() & DHCSR_C_DEBUGEN) != 0
+}
+
+fn dispatch(event: DebugEvent) {
+    let h = HANDLER.load(Ordering::Acquire);
+    if h != 0 {
+        let handler: MonitorHandler = unsafe { mem::transmute(h) };
+        handler(event)
+    }
+}
+
+/// Checks whether the Thumb instruction at `pc` is `BKPT`.
+fn is_bkpt_insn(pc: u32) -> bool {
+    let insn = unsafe { *(pc as usize as *const u16) };
+    (insn & 0xFF00) == 0xBE00
+}
+
+/// DebugMonitor exception handler.  Install this as the `debug_mon` vector.
+///
+/// This finds the exception frame -- on whichever stack was in use -- and
+/// passes it to the handler proper, which may need to adjust the stacked PC.
+#[cfg(not(feature = "host-test"))]
+#[naked]
+pub extern "C" fn debug_mon_isr() {
+    unsafe {
+        asm!("tst lr, #4
+              ite eq
+              mrseq r0, MSP
+              mrsne r0, PSP
+              b embrs_debug_mon"
+             :::: "volatile")
+    }
+}
+
+/// Body of `debug_mon_isr`, given the exception `frame`.
+#[no_mangle]
+pub unsafe extern "C" fn embrs_debug_mon(frame: *mut u32) {
+    let dfsr = SCB.read_dfsr();
+    let pc = *frame.offset(FRAME_PC);
+
+    if dfsr.get_dwttrap() {
+        for i in 0..DWT.num_comparators() {
+            if DWT.take_match(i) {
+                dispatch(DebugEvent::Watchpoint { comparator: i, pc: pc })
+            }
+        }
+    }
+
+    if dfsr.get_bkpt() {
+        match FPB.find_breakpoint(pc as usize) {
+            Some(i) => FPB.clear_breakpoint(i),
+            None => if is_bkpt_insn(pc) {
+                *frame.offset(FRAME_PC) = pc + 2
+            },
+        }
+        dispatch(DebugEvent::Breakpoint { pc: pc })
+    }
+
+    if dfsr.get_external() {
+        dispatch(DebugEvent::External)
+    }
+
+    SCB.clear_dfsr(dfsr)
+}
@@ -0,0 +1,262 @@
+//! ARMv7-M Data Watchpoint and Trace (DWT) unit support.
+//!
+//! This exposes the cycle counter, which is handy for measuring short
+//! intervals (and bounding busy-waits) without tying up a timer, and the
+//! comparators, which can be used as data watchpoints.  Without a debugger
+//! attached, watchpoint hits are delivered to the DebugMonitor exception; see
+//! `debug_monitor`.
+
+use arm_m::reg::{mmio, AtomicReg, Reg, RoReg, ReservedReg};
+use hal::DelayUs;
+
+#[repr(C, packed)]
+struct Registers {
+    ctrl:     Reg<u32>,
+    cyccnt:   Reg<u32>,
+    cpicnt:   Reg<u32>,
+    exccnt:   Reg<u32>,
+    sleepcnt: Reg<u32>,
+    lsucnt:   Reg<u32>,
+    foldcnt:  Reg<u32>,
+    pcsr:     RoReg<u32>,
+    /// Comparators.  Up to four are implemented (see `Ctrl::get_numcomp`);
+    /// the rest of the array reads as zero.
+    comp:     [Comparator; 4],
+}
+
+register_layout! {
+    fn check_layout: Registers [0x60] {
+        ctrl @ 0x00,
+        cyccnt @ 0x04,
+        cpicnt @ 0x08,
+        exccnt @ 0x0C,
+        sleepcnt @ 0x10,
+        lsucnt @ 0x14,
+        foldcnt @ 0x18,
+        pcsr @ 0x1C,
+        comp @ 0x20,
+    }
+}
+
+#[repr(C, packed)]
+struct Comparator {
+    comp:      Reg<u32>,
+    mask:      Reg<u32>,
+    function:  Reg<u32>,
+    _reserved: ReservedReg,
+}
+
+register_layout! {
+    fn check_comparator_layout: Comparator [0x10] {
+        comp @ 0x00,
+        mask @ 0x04,
+        function @ 0x08,
+    }
+}
+
+const DWT_ADDRESS : usize = 0xe0001000;
+
+/// The Debug Exception and Monitor Control Register lives in the debug block
+/// rather than the DWT, but its `TRCENA` bit gates the entire DWT, so we poke
+/// it from here (and from `debug_monitor`).
+pub const DEMCR_ADDRESS : usize = 0xe000edfc;
+
+/// Bit position of `TRCENA` in `DEMCR`.
+const DEMCR_TRCENA : u32 = 1 << 24;
+
+bit_wrappers! {
+    /// DWT Control Register.
+    pub struct Ctrl(pub u32);
+}
+
+impl Ctrl {
+    bitfield_accessors! {
+        /// Number of comparators implemented (read-only).
+        pub total [31:28] get_numcomp / with_numcomp: u32,
+        /// Enables the cycle counter.
+        pub total [0] get_cyccntena / with_cyccntena: bool,
+    }
+}
+
+bit_wrappers! {
+    /// Comparator Function Register.
+    pub struct Function(pub u32);
+}
+
+impl Function {
+    bitfield_accessors! {
+        /// Set when the comparator has matched since the register was last
+        /// read.  Reading clears it.
+        pub total [24] get_matched / with_matched: bool,
+        pub total [11:10] get_datavsize / with_datavsize: u32,
+        pub total [8] get_datavmatch / with_datavmatch: bool,
+        pub total [7] get_cycmatch / with_cycmatch: bool,
+        pub total [5] get_emitrange / with_emitrange: bool,
+        /// What the comparator does on a match; zero disables it.  See
+        /// `WatchAccess` for the values that generate debug events.
+        pub total [3:0] get_function / with_function: u32,
+    }
+}
+
+/// Accesses that trigger a watchpoint.  The values are the comparator
+/// `FUNCTION` settings that generate a debug event.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum WatchAccess {
+    /// Instruction fetch, i.e. a breakpoint.  Unlike FPB breakpoints this
+    /// works in RAM, but it triggers after the instruction executes.
+    Execute = 4,
+    Read = 5,
+    Write = 6,
+    ReadWrite = 7,
+}
+
+/// Reasons a watchpoint can't be set.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum WatchpointError {
+    /// The comparator index is out of range.
+    NoSuchComparator,
+    /// The size is not a power of two, or is larger than the comparator can
+    /// mask.
+    BadSize,
+    /// The address isn't aligned to the size.
+    Misaligned,
+}
+
+/// DWT driver.
+pub struct Dwt;
+
+impl Dwt {
+    fn reg(&self) -> &'static Registers {
+        unsafe { mmio(DWT_ADDRESS) }
+    }
+
+    fn demcr(&self) -> &'static Reg<u32> {
+        unsafe { mmio(DEMCR_ADDRESS) }
+    }
+
+    pub fn read_ctrl(&self) -> Ctrl {
+        Ctrl(self.reg().ctrl.get())
+    }
+
+    pub fn write_ctrl(&self, v: Ctrl) {
+        self.reg().ctrl.set(v.0)
+    }
+
+    pub fn update_ctrl<F: FnOnce(Ctrl) -> Ctrl>(&self, f: F) {
+        self.write_ctrl(f(self.read_ctrl()))
+    }
+
+    /// Starts the cycle counter, enabling the trace subsystem first if
+    /// necessary.  The counter is not reset.
+    ///
+    /// This is safe to call repeatedly, and from any context.
+    pub fn enable_cycle_counter(&self) {
+        self.demcr().atomic_or(DEMCR_TRCENA);
+        self.reg().ctrl.atomic_or(Ctrl::default().with_cyccntena(true).0)
+    }
+
+    /// Checks whether the cycle counter is running.
+    pub fn is_cycle_counter_enabled(&self) -> bool {
+        (self.demcr().get() & DEMCR_TRCENA) != 0
+            && self.read_ctrl().get_cyccntena()
+    }
+
+    /// Reads the current value of the cycle counter.  The counter wraps, so
+    /// intervals should be computed using `wrapping_sub`.
+    #[inline]
+    pub fn read_cycle_count(&self) -> u32 {
+        self.reg().cyccnt.get()
+    }
+
+    /// Number of comparators this implementation provides.
+    pub fn num_comparators(&self) -> usize {
+        self.read_ctrl().get_numcomp() as usize
+    }
+
+    /// Arms comparator `index` to watch `size` bytes at `address` for
+    /// `access`.  The region must be a naturally aligned power of two in size;
+    /// how large is implementation-defined (32KiB on the Cortex-M4).
+    ///
+    /// Hits are reported to the DebugMonitor exception if it's enabled (see
+    /// `debug_monitor::enable`), or to an attached debugger.
+    pub fn set_watchpoint(&self, index: usize, address: usize, size: usize,
+                          access: WatchAccess)
+        -> Result<(), WatchpointError> {
+        if index >= self.num_comparators() {
+            return Err(WatchpointError::NoSuchComparator)
+        }
+        if size == 0 || (size & (size - 1)) != 0 {
+            return Err(WatchpointError::BadSize)
+        }
+        if (address & (size - 1)) != 0 {
+            return Err(WatchpointError::Misaligned)
+        }
+
+        self.demcr().atomic_or(DEMCR_TRCENA);
+        let c = &self.reg().comp[index];
+        c.function.set(0);
+        // MASK ignores this many low-order address bits.  Unimplemented high
+        // bits read back as zero, which is how we learn the limit.
+        let mask = size.trailing_zeros();
+        c.mask.set(mask);
+        if c.mask.get() != mask {
+            c.mask.set(0);
+            return Err(WatchpointError::BadSize)
+        }
+        c.comp.set(address as u32);
+        c.function.set(Function::default()
+                       .with_function(access as u32).0);
+        Ok(())
+    }
+
+    /// Disarms comparator `index`.
+    pub fn clear_watchpoint(&self, index: usize) {
+        if index < self.num_comparators() {
+            self.reg().comp[index].function.set(0)
+        }
+    }
+
+    /// Checks whether comparator `index` has matched since the last call.
+    /// (The hardware clears the flag when read.)
+    pub fn take_match(&self, index: usize) -> bool {
+        index < self.num_comparators()
+            && Function(self.reg().comp[index].function.get()).get_matched()
+    }
+}
+
+/// Shared instance of the `Dwt` driver.
+pub static DWT: Dwt = Dwt;
+
+/// Busy-wait delays measured with the DWT cycle counter.
+#[derive(Copy, Clone)]
+pub struct CycleDelay {
+    cycles_per_us: u32,
+}
+
+impl CycleDelay {
+    /// Creates a delay provider for a CPU clocked at `cpu_hz`, starting the
+    /// cycle counter if necessary.  If the CPU clock changes, make a new one.
+    pub fn new(cpu_hz: u32) -> CycleDelay {
+        DWT.enable_cycle_counter();
+        CycleDelay {
+            cycles_per_us: (cpu_hz + 999_999) / 1_000_000,
+        }
+    }
+}
+
+impl DelayUs for CycleDelay {
+    fn delay_us(&self, us: u32) {
+        // Wait in chunks short enough that the counter can't lap us.
+        let mut remaining = (us as u64) * (self.cycles_per_us as u64);
+        while remaining > 0 {
+            let chunk = if remaining > (1 << 30) {
+                1 << 30
+            } else {
+                remaining as u32
+            };
+            let start = DWT.read_cycle_count();
+            while DWT.read_cycle_count().wrapping_sub(start) < chunk {}
+            remaining -= chunk as u64;
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! ARMv7-M Flash Patch and Breakpoint (FPB) unit support.
+//!
+//! The FPB's code comparators can act as hardware breakpoints on instruction
+//! addresses in the Code region (below `0x2000_0000`, which on most parts is
+//! where Flash lives).  Without a debugger attached, hits are delivered to the
+//! DebugMonitor exception; see `debug_monitor`.
+//!
+//! The FPB can also remap code and literal fetches to RAM, but that feature
+//! isn't exposed here.
+
+use arm_m::reg::{mmio, Reg};
+
+#[repr(C, packed)]
+struct Registers {
+    ctrl:  Reg<u32>,
+    remap: Reg<u32>,
+    /// Comparators: first the code comparators, then the literal comparators.
+    /// The Cortex-M4 implements six and two respectively.
+    comp:  [Reg<u32>; 8],
+}
+
+register_layout! {
+    fn check_layout: Registers [0x28] {
+        ctrl @ 0x00,
+        remap @ 0x04,
+        comp @ 0x08,
+    }
+}
+
+const FPB_ADDRESS : usize = 0xe0002000;
+
+/// Breakpoints can only be set below this address.
+const CODE_REGION_END : usize = 0x2000_0000;
+
+bit_wrappers! {
+    /// Flash Patch Control Register.
+    pub struct Ctrl(pub u32);
+    /// Flash Patch Comparator Register.
+    pub struct Comp(pub u32);
+}
+
+impl Ctrl {
+    bitfield_accessors! {
+        /// Bits 6:4 of the number of code comparators.
+        pub total [14:12] get_num_code_hi / with_num_code_hi: u32,
+        /// Number of literal comparators.
+        pub total [11:8] get_num_lit / with_num_lit: u32,
+        /// Bits 3:0 of the number of code comparators.
+        pub total [7:4] get_num_code_lo / with_num_code_lo: u32,
+        /// Must be written as `true` for a write to take effect.
+        pub total [1] get_key / with_key: bool,
+        pub total [0] get_enable / with_enable: bool,
+    }
+
+    /// Number of code comparators implemented.
+    pub fn get_num_code(self) -> u32 {
+        (self.get_num_code_hi() << 4) | self.get_num_code_lo()
+    }
+}
+
+impl Comp {
+    bitfield_accessors! {
+        /// What a match on the word at `comp` does.
+        pub total [31:30] get_replace / with_replace: Replace,
+        /// Bits 28:2 of the word address to match.
+        pub total [28:2] get_comp / with_comp: u32,
+        pub total [0] get_enable / with_enable: bool,
+    }
+
+    /// The word address this comparator matches.
+    pub fn get_address(self) -> usize {
+        (self.get_comp() << 2) as usize
+    }
+}
+
+bit_enums! {
+    pub bit_enum Replace {
+        /// Remap the fetch (not supported by this driver).
+        Remap = 0b00,
+        /// Breakpoint on the lower halfword of the word.
+        Lower = 0b01,
+        /// Breakpoint on the upper halfword of the word.
+        Upper = 0b10,
+        /// Breakpoint on both halfwords.
+        Both = 0b11,
+    }
+}
+
+/// Reasons a breakpoint can't be set.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum BreakpointError {
+    /// The comparator index is out of range.
+    NoSuchComparator,
+    /// The address is outside the Code region, or isn't halfword aligned.
+    BadAddress,
+}
+
+/// FPB driver.
+pub struct Fpb;
+
+impl Fpb {
+    fn reg(&self) -> &'static Registers {
+        unsafe { mmio(FPB_ADDRESS) }
+    }
+
+    pub fn read_ctrl(&self) -> Ctrl {
+        Ctrl(self.reg().ctrl.get())
+    }
+
+    /// Number of code comparators, which is the number of breakpoints that
+    /// can be set at once.
+    pub fn num_breakpoints(&self) -> usize {
+        self.read_ctrl().get_num_code() as usize
+    }
+
+    /// Enables or disables the unit as a whole.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.reg().ctrl.set(Ctrl::default()
+                            .with_key(true)
+                            .with_enable(enabled).0)
+    }
+
+    /// Sets breakpoint `index` on the instruction at `address`, and enables
+    /// the unit.
+    pub fn set_breakpoint(&self, index: usize, address: usize)
+        -> Result<(), BreakpointError> {
+        if index >= self.num_breakpoints() {
+            return Err(BreakpointError::NoSuchComparator)
+        }
+        if address >= CODE_REGION_END || (address & 1) != 0 {
+            return Err(BreakpointError::BadAddress)
+        }
+
+        let replace = if (address & 2) != 0 {
+            Replace::Upper
+        } else {
+            Replace::Lower
+        };
+        self.reg().comp[index].set(Comp::default()
+                                   .with_replace(replace)
+                                   .with_comp((address >> 2) as u32)
+                                   .with_enable(true).0);
+        self.set_enabled(true);
+        Ok(())
+    }
+
+    /// Clears breakpoint `index`.
+    pub fn clear_breakpoint(&self, index: usize) {
+        if index < self.num_breakpoints() {
+            self.reg().comp[index].set(0)
+        }
+    }
+
+    /// Finds the enabled breakpoint, if any, on the instruction at `address`.
+    pub fn find_breakpoint(&self, address: usize) -> Option<usize> {
+        let half = if (address & 2) != 0 {
+            Replace::Upper
+        } else {
+            Replace::Lower
+        };
+        (0..self.num_breakpoints()).find(|&i| {
+            let c = Comp(self.reg().comp[i].get());
+            c.get_enable()
+                && c.get_address() == (address & !3)
+                && (c.get_replace() == half
+                    || c.get_replace() == Replace::Both)
+        })
+    }
+}
+
+/// Shared instance of the `Fpb` driver.
+pub static FPB: Fpb = Fpb;
@@ -0,0 +1,146 @@
+//! Floating Point Unit (FPU) context and status control for the Cortex-M4F.
+//!
+//! The startup code grants access to the FPU (see `startup`), but leaves the
+//! context saving policy at its reset default: lazy stacking.  On exception
+//! entry the hardware reserves space for the FP registers, but only writes
+//! them if the handler itself executes an FP instruction.  This module lets
+//! applications change that policy, and access the FP status register.
+
+use arm_m::scb::SCB_FP;
+
+/// How the FP register context is saved on exception entry.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum ContextSaving {
+    /// FP context is never saved.  Exception entry is as fast as on a core
+    /// without an FPU, but *any* handler that uses floating point will corrupt
+    /// the FP state of the code it interrupted.  Only appropriate when
+    /// handlers never touch FP (note that the compiler may use FP registers
+    /// for things that don't look like floating point math), or when only one
+    /// context ever does.
+    Disabled,
+    /// Space is reserved on exception entry, and the registers are saved only
+    /// if the handler uses the FPU.  This is the reset default.
+    Lazy,
+    /// FP registers are saved on every exception entry from a context that
+    /// has used the FPU.  Costs latency, but makes it deterministic.
+    Immediate,
+}
+
+/// Sets the FP context saving policy.
+///
+/// This should be done early, before any exception handlers that might use
+/// the FPU can run; changing it while an FP context is active on the stack
+/// can leave that context unrestorable.
+pub fn set_context_saving(mode: ContextSaving) {
+    let (aspen, lspen) = match mode {
+        ContextSaving::Disabled => (false, false),
+        ContextSaving::Lazy => (true, true),
+        ContextSaving::Immediate => (true, false),
+    };
+    SCB_FP.update_fpccr(|v| v.with_aspen(aspen).with_lspen(lspen))
+}
+
+/// Disables FP context saving entirely, for applications where exception
+/// latency matters more than floating point in handlers.  See
+/// `ContextSaving::Disabled` for the (considerable) caveats.
+pub fn disable_context_saving() {
+    set_context_saving(ContextSaving::Disabled)
+}
+
+/// Reads back the FP context saving policy.
+pub fn get_context_saving() -> ContextSaving {
+    let fpccr = SCB_FP.read_fpccr();
+    match (fpccr.get_aspen(), fpccr.get_lspen()) {
+        (Ok(true), Ok(true)) => ContextSaving::Lazy,
+        (Ok(true), _) => ContextSaving::Immediate,
+        _ => ContextSaving::Disabled,
+    }
+}
+
+bit_wrappers! {
+    /// Floating Point Status and Control Register.
+    pub struct Fpscr(pub u32);
+}
+
+impl Fpscr {
+    bitfield_accessors! {
+        /// Condition flags from the last FP comparison.
+        pub total [31] get_n / with_n: bool,
+        pub total [30] get_z / with_z: bool,
+        pub total [29] get_c / with_c: bool,
+        pub total [28] get_v / with_v: bool,
+        /// Selects the alternative half-precision format.
+        pub total [26] get_ahp / with_ahp: bool,
+        /// Default NaN mode: operations return the default NaN rather than
+        /// propagating input NaNs.
+        pub total [25] get_dn / with_dn: bool,
+        /// Flush-to-zero mode: denormal inputs and results are replaced by
+        /// zero.
+        pub total [24] get_fz / with_fz: bool,
+        /// Rounding mode.
+        pub total [23:22] get_rmode / with_rmode: RoundingMode,
+        /// Cumulative exception flag: input denormal flushed to zero.
+        pub total [7] get_idc / with_idc: bool,
+        /// Cumulative exception flag: inexact result.
+        pub total [4] get_ixc / with_ixc: bool,
+        /// Cumulative exception flag: underflow.
+        pub total [3] get_ufc / with_ufc: bool,
+        /// Cumulative exception flag: overflow.
+        pub total [2] get_ofc / with_ofc: bool,
+        /// Cumulative exception flag: division by zero.
+        pub total [1] get_dzc / with_dzc: bool,
+        /// Cumulative exception flag: invalid operation.
+        pub total [0] get_ioc / with_ioc: bool,
+    }
+
+    /// Returns a copy with all cumulative exception flags cleared.
+    pub fn without_exceptions(self) -> Self {
+        Fpscr(self.0 & !0x9F)
+    }
+}
+
+bit_enums! {
+    /// FP rounding modes.
+    pub bit_enum RoundingMode {
+        ToNearest = 0b00,
+        TowardPlusInfinity = 0b01,
+        TowardMinusInfinity = 0b10,
+        TowardZero = 0b11,
+    }
+}
+
+/// Reads the FPSCR.  Like any FP instruction, this will cause the current
+/// context to be treated as an FP context.
+#[inline]
+pub fn read_fpscr() -> Fpscr {
+    let v: u32;
+    unsafe {
+        asm!("vmrs $0, fpscr"
+             : "=r"(v)
+             ::: "volatile")
+    }
+    Fpscr(v)
+}
+
+/// Writes the FPSCR.
+#[inline]
+pub fn write_fpscr(v: Fpscr) {
+    unsafe {
+        asm!("vmsr fpscr, $0"
+             :: "r"(v.0)
+             :: "volatile")
+    }
+}
+
+/// Updates the FPSCR using `f`.
+#[inline]
+pub fn update_fpscr<F: FnOnce(Fpscr) -> Fpscr>(f: F) {
+    write_fpscr(f(read_fpscr()))
+}
+
+/// Sets the default FPSCR value used for the FP context of exception
+/// handlers (the FPDSCR).  Only the `ahp`, `dn`, `fz`, and `rmode` fields are
+/// significant.
+pub fn set_handler_defaults(v: Fpscr) {
+    SCB_FP.write_fpdscr(v.0 & 0x07C0_0000)
+}
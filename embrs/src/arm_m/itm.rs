@@ -0,0 +1,106 @@
+//! ARMv7-M Instrumentation Trace Macrocell (ITM) support.
+//!
+//! The ITM's stimulus ports carry software-generated data out through the
+//! trace port (usually SWO) to a debug probe, without disturbing the
+//! processor much: a write costs a few cycles once the port's FIFO has room.
+//!
+//! The debugger normally sets up the trace path -- the TPIU's baud rate and
+//! protocol, and `ITM_TCR` -- when it starts capturing, and enables the ports
+//! it cares about.  The writers here check that a port is enabled and quietly
+//! discard data otherwise, so it's safe to leave instrumentation in builds
+//! that run without a probe attached.
+
+use core::ptr;
+
+use arm_m::reg::{mmio, Reg, ReservedReg};
+
+#[repr(C, packed)]
+struct Registers {
+    stim:       [Reg<u32>; 256],
+    _reserved0: [ReservedReg; 640],
+    ter:        [Reg<u32>; 8],
+    _reserved1: [ReservedReg; 8],
+    tpr:        Reg<u32>,
+    _reserved2: [ReservedReg; 15],
+    tcr:        Reg<u32>,
+}
+
+register_layout! {
+    fn check_layout: Registers [0xE84] {
+        stim @ 0x000,
+        ter @ 0xE00,
+        tpr @ 0xE40,
+        tcr @ 0xE80,
+    }
+}
+
+const ITM_ADDRESS : usize = 0xe0000000;
+
+/// Number of stimulus ports.
+pub const PORT_COUNT : usize = 256;
+
+/// Bit position of `ITMENA` in `ITM_TCR`.
+const TCR_ITMENA : u32 = 1 << 0;
+
+/// Reading a stimulus port yields this bit set when its FIFO can accept a
+/// write.
+const STIM_FIFOREADY : u32 = 1 << 0;
+
+/// ITM driver.
+pub struct Itm;
+
+impl Itm {
+    fn reg(&self) -> &'static Registers {
+        unsafe { mmio(ITM_ADDRESS) }
+    }
+
+    /// Checks whether the ITM as a whole is enabled.
+    pub fn is_enabled(&self) -> bool {
+        (self.reg().tcr.get() & TCR_ITMENA) != 0
+    }
+
+    /// Checks whether stimulus port `port` is enabled, which includes the ITM
+    /// as a whole being enabled.
+    ///
+    /// # Panics
+    ///
+    /// If `port` is not less than `PORT_COUNT`.
+    pub fn is_port_enabled(&self, port: usize) -> bool {
+        assert!(port < PORT_COUNT);
+        self.is_enabled()
+            && (self.reg().ter[port / 32].get() & (1 << (port % 32))) != 0
+    }
+
+    /// Sends `byte` on stimulus port `port`, waiting for room in its FIFO.
+    /// If the port is not enabled, the byte is dropped.
+    ///
+    /// # Panics
+    ///
+    /// If `port` is not less than `PORT_COUNT`.
+    pub fn write_u8(&self, port: usize, byte: u8) {
+        if !self.is_port_enabled(port) {
+            return
+        }
+        let stim = &self.reg().stim[port];
+        while (stim.get() & STIM_FIFOREADY) == 0 {}
+        // A byte-wide write produces a one-byte packet.
+        unsafe {
+            ptr::write_volatile(stim as *const Reg<u32> as *mut u8, byte)
+        }
+    }
+
+    /// Sends all of `bytes` on stimulus port `port`, one byte per packet.  If
+    /// the port is not enabled (or becomes disabled partway), the rest are
+    /// dropped.
+    pub fn write_all(&self, port: usize, bytes: &[u8]) {
+        for &b in bytes {
+            if !self.is_port_enabled(port) {
+                return
+            }
+            self.write_u8(port, b)
+        }
+    }
+}
+
+/// Shared instance of the `Itm` driver.
+pub static ITM: Itm = Itm;
@@ -1,13 +1,100 @@
+//! Support for ARM M-profile processors.
+//!
+//! By default this targets ARMv7-M (Cortex-M3/M4).  With the `arch:armv6-m`
+//! feature (implied by `cpu:cortex-m0` and `cpu:cortex-m0plus`), it avoids
+//! instructions and features that ARMv6-M lacks: exclusive loads/stores, byte
+//! access to the NVIC priority registers, bit-banding, and the DWT.
+//!
+//! With the `host-test` feature, the instruction wrappers here become no-ops
+//! (`PRIMASK` is simulated) and registers are backed by `sim`, so that drivers
+//! can be unit tested off-target.
+
+#[cfg(feature = "host-test")]
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+// Declared first so that its macros are available to the drivers below.
+#[macro_use]
+pub mod reg;
+
+#[cfg(not(any(feature = "arch:armv6-m", feature = "host-test")))]
+pub mod bitband;
+#[cfg(all(target_os = "none", not(feature = "arch:armv6-m")))]
+pub mod crashdump;
+#[cfg(not(feature = "arch:armv6-m"))]
+pub mod debug_monitor;
+#[cfg(not(feature = "arch:armv6-m"))]
+pub mod dwt;
 pub mod exc;
+#[cfg(not(feature = "arch:armv6-m"))]
+pub mod fpb;
+#[cfg(all(feature = "cpu:cortex-m4f", not(feature = "host-test")))]
+pub mod fpu;
+#[cfg(not(feature = "arch:armv6-m"))]
+pub mod itm;
+#[cfg(not(feature = "arch:armv6-m"))]
+pub mod mpu;
 pub mod nvic;
-pub mod reg;
 pub mod scb;
+pub mod simd;
+#[cfg(feature = "host-test")]
+pub mod sim;
 pub mod sys_tick;
 
+#[cfg(target_os = "none")]
+pub mod stack;
 #[cfg(target_os = "none")]
 pub mod startup;
 
+/// Checks the layout of the architectural register blocks (see
+/// `register_layout!`).
+#[cfg(all(test, feature = "host-test"))]
+#[test]
+fn check_layouts() {
+    #[cfg(not(feature = "arch:armv6-m"))]
+    fn check_dwt() {
+        dwt::check_layout();
+        dwt::check_comparator_layout();
+        fpb::check_layout();
+        itm::check_layout();
+        mpu::check_layout()
+    }
+    #[cfg(feature = "arch:armv6-m")]
+    fn check_dwt() {}
+    check_dwt();
+    #[cfg(feature = "cpu:cortex-m4f")]
+    fn check_fp() {
+        scb::check_fp_layout()
+    }
+    #[cfg(not(feature = "cpu:cortex-m4f"))]
+    fn check_fp() {}
+    check_fp();
+    nvic::check_layout();
+    scb::check_layout();
+    sys_tick::check_layout();
+}
+
+/// Generates a wrapper for a single instruction that takes no operands.  Under
+/// `host-test` the wrapper does nothing.
+macro_rules! insn_fn {
+    ($(#[$m:meta])* pub fn $name:ident() => $insn:tt) => {
+        $(#[$m])*
+        #[cfg(not(feature = "host-test"))]
+        #[inline]
+        pub fn $name() {
+            unsafe {
+                asm!($insn :::: "volatile")
+            }
+        }
+
+        $(#[$m])*
+        #[cfg(feature = "host-test")]
+        #[inline]
+        pub fn $name() {}
+    };
+}
+
 /// Sets the processor's `PRIMASK` register to `val`.
+#[cfg(not(feature = "host-test"))]
 #[inline]
 pub fn set_primask(val: bool) {
     unsafe {
@@ -17,33 +104,122 @@ pub fn set_primask(val: bool) {
     }
 }
 
-/// Generates an instruction synchronization barrier (`ISB`) instruction.
+/// Reads the processor's `PRIMASK` register; `true` means interrupts are
+/// masked.
+#[cfg(not(feature = "host-test"))]
 #[inline]
-pub fn instruction_synchronization_barrier() {
+pub fn get_primask() -> bool {
+    let val: u32;
     unsafe {
-        asm!("isb" :::: "volatile")
+        asm!("mrs $0, PRIMASK"
+             : "=r"(val)
+             ::: "volatile")
     }
+    (val & 1) != 0
 }
 
-/// Generates a data synchronization barrier (`DSB`) instruction.
+/// Reads the processor's `IPSR` register: the number of the exception being
+/// handled, or zero in thread mode.  Interrupt *n* is exception *n* + 16.
+#[cfg(not(feature = "host-test"))]
 #[inline]
-pub fn data_synchronization_barrier() {
+pub fn get_ipsr() -> u32 {
+    let val: u32;
     unsafe {
-        asm!("dsb" :::: "volatile")
+        asm!("mrs $0, IPSR"
+             : "=r"(val)
+             ::: "volatile")
     }
+    val & 0x1FF
+}
+
+/// Simulated `IPSR`: host tests always run in thread mode.
+#[cfg(feature = "host-test")]
+pub fn get_ipsr() -> u32 {
+    0
+}
+
+#[cfg(feature = "host-test")]
+static SIM_PRIMASK: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Sets the simulated `PRIMASK`.
+#[cfg(feature = "host-test")]
+pub fn set_primask(val: bool) {
+    SIM_PRIMASK.store(val, Ordering::SeqCst)
+}
+
+/// Reads the simulated `PRIMASK`.
+#[cfg(feature = "host-test")]
+pub fn get_primask() -> bool {
+    SIM_PRIMASK.load(Ordering::SeqCst)
 }
 
-/// Generates a data memory barrier (`DMB`) instruction.
+/// Runs `body` with interrupts masked, restoring the previous `PRIMASK` state
+/// afterwards (so nested use is safe).
 #[inline]
-pub fn data_memory_barrier() {
-    unsafe {
-        asm!("dmb" :::: "volatile")
+pub fn without_interrupts<R, F: FnOnce() -> R>(body: F) -> R {
+    let was_masked = get_primask();
+    set_primask(true);
+    let r = body();
+    if !was_masked {
+        set_primask(false)
     }
+    r
+}
+
+insn_fn! {
+    /// Generates an instruction synchronization barrier (`ISB`) instruction.
+    pub fn instruction_synchronization_barrier() => "isb"
+}
+
+insn_fn! {
+    /// Generates a data synchronization barrier (`DSB`) instruction.
+    pub fn data_synchronization_barrier() => "dsb"
+}
+
+insn_fn! {
+    /// Generates a data memory barrier (`DMB`) instruction.
+    pub fn data_memory_barrier() => "dmb"
+}
+
+insn_fn! {
+    pub fn wait_for_interrupt() => "wfi"
+}
+
+insn_fn! {
+    /// Sleeps until an event is signaled, unless the processor's event
+    /// register is already set, in which case it's cleared and this returns
+    /// immediately.
+    ///
+    /// Events are signaled by `send_event` (from this or another processor),
+    /// by exception entry/return, and -- if `Scb::set_sevonpend` is enabled
+    /// -- by interrupts becoming pending even while disabled.
+    pub fn wait_for_event() => "wfe"
 }
 
+insn_fn! {
+    /// Signals an event, setting the event register and waking any processor
+    /// in `wait_for_event`.
+    pub fn send_event() => "sev"
+}
+
+/// Clears the event register without sleeping.
+///
+/// Because an event may have been latched at any point in the past (any
+/// exception return sets it), a single `wait_for_event` often returns
+/// immediately.  The usual fix is "WFE twice": `send_event` guarantees the
+/// register is set, and the first WFE then consumes it without sleeping.  A
+/// typical sleep loop looks like this:
+///
+///     arm_m::clear_event_register();
+///     while !condition() {
+///         arm_m::wait_for_event();
+///     }
+///
+/// Note that an event arriving between the clear and the check of
+/// `condition` is *not* lost: it sets the register, and the following WFE
+/// returns immediately.
 #[inline]
-pub fn wait_for_interrupt() {
-    unsafe {
-        asm!("wfi" :::: "volatile")
-    }
+pub fn clear_event_register() {
+    send_event();
+    wait_for_event()
 }
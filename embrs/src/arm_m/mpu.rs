@@ -0,0 +1,179 @@
+//! ARMv7-M Memory Protection Unit support.
+//!
+//! The MPU overlays up to eight regions on the memory map, each a naturally
+//! aligned power of two in size (at least 32 bytes), with its own access
+//! permissions.  Where regions overlap, the highest-numbered one wins.  With
+//! `PRIVDEFENA` set, privileged code sees the default memory map wherever no
+//! region applies, which lets a few regions carve restrictions out of an
+//! otherwise unprotected system -- as `stack`'s guard page does.
+
+use arm_m;
+use arm_m::reg::{mmio, Reg, RoReg};
+
+#[repr(C, packed)]
+struct Registers {
+    typer: RoReg<u32>,
+    ctrl:  Reg<u32>,
+    rnr:   Reg<u32>,
+    rbar:  Reg<u32>,
+    rasr:  Reg<u32>,
+}
+
+register_layout! {
+    fn check_layout: Registers [0x14] {
+        typer @ 0x00,
+        ctrl @ 0x04,
+        rnr @ 0x08,
+        rbar @ 0x0C,
+        rasr @ 0x10,
+    }
+}
+
+const MPU_ADDRESS : usize = 0xe000ed90;
+
+/// Smallest region size the MPU supports, as a power of two.
+pub const MIN_REGION_SIZE_LOG2 : u32 = 5;
+
+bit_wrappers! {
+    /// MPU Control Register.
+    pub struct Ctrl(pub u32);
+    /// MPU Region Base Address Register.
+    pub struct Rbar(pub u32);
+    /// MPU Region Attribute and Size Register.
+    pub struct Rasr(pub u32);
+}
+
+impl Ctrl {
+    bitfield_accessors! {
+        /// Gives privileged code the default memory map where no region
+        /// applies.
+        pub total [2] get_privdefena / with_privdefena: bool,
+        /// Keeps the MPU enabled in HardFault, NMI, and with `FAULTMASK` set.
+        pub total [1] get_hfnmiena / with_hfnmiena: bool,
+        pub total [0] get_enable / with_enable: bool,
+    }
+}
+
+impl Rbar {
+    bitfield_accessors! {
+        /// Bits 31:5 of the region base address.
+        pub total [31:5] get_addr / with_addr: u32,
+        /// When set, `region` selects the region written, instead of `RNR`.
+        pub total [4] get_valid / with_valid: bool,
+        pub total [3:0] get_region / with_region: u32,
+    }
+}
+
+impl Rasr {
+    bitfield_accessors! {
+        /// Forbids instruction fetches from the region.
+        pub total [28] get_xn / with_xn: bool,
+        pub [26:24] get_ap / with_ap: AccessPermission,
+        pub total [21:19] get_tex / with_tex: u32,
+        pub total [18] get_s / with_s: bool,
+        pub total [17] get_c / with_c: bool,
+        pub total [16] get_b / with_b: bool,
+        /// Subregion disable bits, one per eighth of the region.
+        pub total [15:8] get_srd / with_srd: u32,
+        /// The region is 2^(size + 1) bytes.
+        pub total [5:1] get_size / with_size: u32,
+        pub total [0] get_enable / with_enable: bool,
+    }
+}
+
+bit_enums! {
+    /// Region access permissions, as (privileged, unprivileged) rights.
+    pub bit_enum AccessPermission {
+        NoAccess = 0b000,
+        PrivilegedReadWrite = 0b001,
+        PrivilegedReadWriteUserReadOnly = 0b010,
+        ReadWrite = 0b011,
+        PrivilegedReadOnly = 0b101,
+        ReadOnly = 0b110,
+    }
+}
+
+/// Reasons a region can't be configured.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum RegionError {
+    /// The region number is out of range.
+    NoSuchRegion,
+    /// The size is below the minimum, or larger than the address space.
+    BadSize,
+    /// The base address isn't aligned to the region size.
+    Misaligned,
+}
+
+/// MPU driver.
+pub struct Mpu;
+
+impl Mpu {
+    fn reg(&self) -> &'static Registers {
+        unsafe { mmio(MPU_ADDRESS) }
+    }
+
+    /// Number of regions implemented; zero if there's no MPU.
+    pub fn num_regions(&self) -> usize {
+        ((self.reg().typer.get() >> 8) & 0xFF) as usize
+    }
+
+    pub fn read_ctrl(&self) -> Ctrl {
+        Ctrl(self.reg().ctrl.get())
+    }
+
+    /// Enables the MPU.  If `privileged_default` is set, privileged code can
+    /// access memory not covered by any region as if the MPU were off.
+    pub fn enable(&self, privileged_default: bool) {
+        self.reg().ctrl.set(Ctrl::default()
+                            .with_privdefena(privileged_default)
+                            .with_enable(true).0);
+        arm_m::data_synchronization_barrier();
+        arm_m::instruction_synchronization_barrier()
+    }
+
+    pub fn disable(&self) {
+        arm_m::data_memory_barrier();
+        self.reg().ctrl.set(0)
+    }
+
+    /// Configures region `index` to cover the `1 << size_log2` bytes at
+    /// `base`, with attributes `attrs` (whose size and enable fields are
+    /// filled in here).
+    pub fn set_region(&self, index: usize, base: usize, size_log2: u32,
+                      attrs: Rasr)
+        -> Result<(), RegionError> {
+        if index >= self.num_regions() {
+            return Err(RegionError::NoSuchRegion)
+        }
+        if size_log2 < MIN_REGION_SIZE_LOG2 || size_log2 > 32 {
+            return Err(RegionError::BadSize)
+        }
+        if size_log2 < 32 && (base & ((1 << size_log2) - 1)) != 0 {
+            return Err(RegionError::Misaligned)
+        }
+
+        // Disable the region while it changes, so that it never applies with
+        // a mix of old and new settings.
+        self.reg().rnr.set(index as u32);
+        self.reg().rasr.set(0);
+        self.reg().rbar.set(Rbar::default()
+                            .with_addr((base >> 5) as u32).0);
+        self.reg().rasr.set(attrs
+                            .with_size(size_log2 - 1)
+                            .with_enable(true).0);
+        arm_m::data_synchronization_barrier();
+        arm_m::instruction_synchronization_barrier();
+        Ok(())
+    }
+
+    /// Disables region `index`.
+    pub fn clear_region(&self, index: usize) {
+        if index < self.num_regions() {
+            self.reg().rnr.set(index as u32);
+            self.reg().rasr.set(0)
+        }
+    }
+}
+
+/// Shared instance of the `Mpu` driver.
+pub static MPU: Mpu = Mpu;
@@ -6,7 +6,9 @@
 use core::sync::atomic;
 
 use arm_m;
-use arm_m::reg::Reg;
+use arm_m::reg::{mmio, Reg, ReservedReg};
+#[cfg(feature = "arch:armv6-m")]
+use arm_m::reg::AtomicReg;
 
 /// The NVIC register set layout.
 ///
@@ -18,26 +20,26 @@ struct Registers {
     /// The Interrupt Set Enabled Registers have one bit for each potential
     /// interrupt source.  Writing ones causes the corresponding interrupt(s) to
     /// become enabled; others remain unchanged.
-    iser: [Reg<u32>; 16], _reserved_after_iser: [Reg<u32>; 16],
+    iser: [Reg<u32>; 16], _reserved_after_iser: [ReservedReg; 16],
 
     /// The Interrupt Clear Enabled Registers have one bit for each potential
     /// interrupt source.  Writing ones causes the corresponding interrupt(s) to
     /// become disabled; others remain unchanged.
-    icer: [Reg<u32>; 16], _reserved_after_icer: [Reg<u32>; 16],
+    icer: [Reg<u32>; 16], _reserved_after_icer: [ReservedReg; 16],
 
     /// The Interrupt Set Pending Registers have one bit for each potential
     /// interrupt source.  Writing ones causes the corresponding interrupt(s) to
     /// become pending; others remain unchanged.
-    ispr: [Reg<u32>; 16], _reserved_after_ispr: [Reg<u32>; 16],
+    ispr: [Reg<u32>; 16], _reserved_after_ispr: [ReservedReg; 16],
 
     /// The Interrupt Clear Pending Registers have one bit for each potential
     /// interrupt source.  Writing ones causes the corresponding interrupt(s) to
     /// become non-pending; others remain unchanged.
-    icpr: [Reg<u32>; 16], _reserved_after_icpr: [Reg<u32>; 16],
+    icpr: [Reg<u32>; 16], _reserved_after_icpr: [ReservedReg; 16],
 
     /// The Interrupt Active Bit Registers have one bit for each potential
     /// interrupt source.  The bit is 1 if the interrupt is active, 0 otherwise.
-    iabr: [Reg<u32>; 16], _reserved_after_iabr: [Reg<u32>; 48],
+    iabr: [Reg<u32>; 16], _reserved_after_iabr: [ReservedReg; 48],
 
     /// The Interrupt Priority Registers contain an 8-bit field for each
     /// potential interrupt source.  The field contains the interrupt's
@@ -45,8 +47,24 @@ struct Registers {
     /// unimplemented.
     ///
     /// While described in the ARM as 32-bit registers, these registers are
-    /// explicitly permitted for byte access, which is how we model them here.
+    /// explicitly permitted for byte access on ARMv7-M, which is how we model
+    /// them here.
+    #[cfg(not(feature = "arch:armv6-m"))]
     ipr: [Reg<u8>; 496],
+    /// ARMv6-M only permits word access, so there we model them as words.
+    #[cfg(feature = "arch:armv6-m")]
+    ipr: [Reg<u32>; 124],
+}
+
+register_layout! {
+    fn check_layout: Registers [0x4F0] {
+        iser @ 0x000,
+        icer @ 0x080,
+        ispr @ 0x100,
+        icpr @ 0x180,
+        iabr @ 0x200,
+        ipr @ 0x300,
+    }
 }
 
 const NVIC_ADDRESS : usize = 0xe000e100_usize;
@@ -104,6 +122,29 @@ impl Nvic {
         Self::write_barriers()
     }
 
+    /// Checks whether an interrupt is enabled.
+    #[inline]
+    pub fn is_irq_enabled_raw(&self, irq: u32) -> bool {
+        let (bank, index) = ((irq / 32) as usize, irq % 32);
+
+        unsafe {
+            (self.reg().iser[bank].get() & (1 << index)) != 0
+        }
+    }
+
+    /// Disables every interrupt and clears any that are pending, leaving the
+    /// NVIC as it was at reset -- say, before handing over to another
+    /// program.
+    pub fn disable_all(&self) {
+        unsafe {
+            for bank in 0..16 {
+                self.reg().icer[bank].set(!0);
+                self.reg().icpr[bank].set(!0);
+            }
+        }
+        Self::write_barriers()
+    }
+
     /// Sets the priority of an interrupt, synchronously.
     ///
     /// This may cause immediate preemption in the following cases:
@@ -126,6 +167,7 @@ impl Nvic {
     /// ergonomic (taking an enum instead of a `u32`) and *more performant*
     /// (because the enum lets us eliminate some range checks).
     #[inline]  // into the SoC layer
+    #[cfg(not(feature = "arch:armv6-m"))]
     pub fn set_priority_raw(&self, irq: u32, priority: u8) {
         unsafe {
             self.reg().ipr[irq as usize].set(priority);
@@ -133,6 +175,17 @@ impl Nvic {
         Self::write_barriers()
     }
 
+    #[inline]  // into the SoC layer
+    #[cfg(feature = "arch:armv6-m")]
+    pub fn set_priority_raw(&self, irq: u32, priority: u8) {
+        let shift = (irq % 4) * 8;
+        unsafe {
+            self.reg().ipr[(irq / 4) as usize]
+                .atomic_nand_and_or(0xFF << shift, (priority as u32) << shift);
+        }
+        Self::write_barriers()
+    }
+
     /// Reads the priority of an interrupt.
     ///
     /// This operation is atomic with respect to `set_priority_raw`, but makes
@@ -143,12 +196,22 @@ impl Nvic {
         atomic::fence(atomic::Ordering::Acquire);
 
         unsafe {
-            self.reg().ipr[irq as usize].get()
+            self.read_ipr(irq)
         }
     }
 
+    #[cfg(not(feature = "arch:armv6-m"))]
+    unsafe fn read_ipr(&self, irq: u32) -> u8 {
+        self.reg().ipr[irq as usize].get()
+    }
+
+    #[cfg(feature = "arch:armv6-m")]
+    unsafe fn read_ipr(&self, irq: u32) -> u8 {
+        (self.reg().ipr[(irq / 4) as usize].get() >> ((irq % 4) * 8)) as u8
+    }
+
     unsafe fn reg(&self) -> &'static Registers {
-        &*(NVIC_ADDRESS as *const Registers)
+        mmio(NVIC_ADDRESS)
     }
 
     #[inline]
@@ -1,7 +1,119 @@
 //! Support for memory-mapped registers of various sizes.
 
+#![macro_use]
+
 use core::cell::UnsafeCell;
 use core::ptr;
+#[cfg(feature = "host-test")]
+use core::mem;
+
+#[cfg(any(feature = "arch:armv6-m", feature = "host-test"))]
+use arm_m;
+#[cfg(feature = "host-test")]
+use arm_m::sim;
+
+/// Produces a reference to the register (or block of registers) `T` at
+/// physical address `address`.  All peripheral statics should be reached this
+/// way, so that they can be simulated under `host-test`.
+///
+/// This is unsafe because nothing checks that `T` actually lives at
+/// `address`.
+#[cfg(not(feature = "host-test"))]
+#[inline]
+pub unsafe fn mmio<T>(address: usize) -> &'static T {
+    &*(address as *const T)
+}
+
+/// Produces a reference to the simulated register (or block of registers) `T`
+/// standing in for physical address `address`.
+#[cfg(feature = "host-test")]
+pub unsafe fn mmio<T>(address: usize) -> &'static T {
+    &*(sim::map(address, mem::size_of::<T>()) as *const T)
+}
+
+/// Checks the layout of a register block against the offsets documented in
+/// the reference manual, since a miscounted reserved field silently shifts
+/// every register after it:
+///
+///     register_layout! {
+///         fn check_layout: Registers [0x10] {
+///             csr @ 0x00,
+///             rvr @ 0x04,
+///             cvr @ 0x08,
+///             calib @ 0x0C,
+///         }
+///     }
+///
+/// The total size (in brackets) is checked at compile time, by a `transmute`
+/// that won't build if it's wrong.  Field offsets can't be computed in a
+/// constant, so they're checked by the named function, which is generated
+/// under the `host-test` feature for `cargo test` to call.
+macro_rules! register_layout {
+    (
+        fn $check:ident: $ty:ident [$size:expr] {
+            $($field:ident @ $off:expr,)*
+        }
+    ) => {
+        impl $ty {
+            #[allow(dead_code)]
+            fn __check_size() {
+                let _: $ty = unsafe {
+                    ::core::mem::transmute([0u8; $size])
+                };
+            }
+        }
+
+        #[cfg(feature = "host-test")]
+        pub fn $check() {
+            // Any non-null address will do; it's never dereferenced.
+            const BASE : usize = 0x1000;
+            let base = BASE as *const $ty;
+            $(
+                $crate::arm_m::reg::check_offset(
+                    concat!(stringify!($ty), ".", stringify!($field)),
+                    unsafe { &(*base).$field as *const _ as usize } - BASE,
+                    $off);
+            )*
+        }
+    };
+}
+
+/// Panics if a register is not at its documented offset.  Used by
+/// `register_layout!`.
+#[cfg(feature = "host-test")]
+pub fn check_offset(name: &str, actual: usize, expected: usize) {
+    assert!(actual == expected,
+            "{} is at offset {:#x}, expected {:#x}", name, actual, expected)
+}
+
+/// Volatile load.
+#[cfg(not(feature = "host-test"))]
+#[inline]
+unsafe fn load<T>(p: *mut T) -> T {
+    ptr::read_volatile(p)
+}
+
+/// Volatile store.
+#[cfg(not(feature = "host-test"))]
+#[inline]
+unsafe fn store<T>(p: *mut T, value: T) {
+    ptr::write_volatile(p, value)
+}
+
+/// Volatile load, logged.
+#[cfg(feature = "host-test")]
+unsafe fn load<T>(p: *mut T) -> T {
+    let v = ptr::read_volatile(p);
+    sim::log_read(p as usize, &v);
+    v
+}
+
+/// Volatile store, logged and subject to write hooks.
+#[cfg(feature = "host-test")]
+unsafe fn store<T>(p: *mut T, mut value: T) {
+    sim::log_write(p as usize, &mut value);
+    ptr::write_volatile(p, value)
+}
 
 /// A register whose contents can be represented as `T`.  The contents are
 /// accessed using `volatile` operations only, ensuring that apparently dead
@@ -20,14 +132,12 @@ pub struct Reg<T> {
 impl<T> Reg<T> {
     /// Reads the contents of the register using a volatile load.
     pub fn get(&self) -> T {
-        unsafe { ptr::read_volatile(self.value.get()) }
+        unsafe { load(self.value.get()) }
     }
 
     /// Replaces the contents of the register using a volatile store.
     pub fn set(&self, value: T) {
-        unsafe {
-            ptr::write_volatile(self.value.get(), value)
-        }
+        unsafe { store(self.value.get(), value) }
     }
 
     pub fn update<F: FnOnce(T) -> T>(&self, f: F) {
@@ -35,8 +145,123 @@ impl<T> Reg<T> {
     }
 }
 
+/// 64-bit registers, or adjacent pairs of 32-bit registers (low word first)
+/// modeled as one.
+///
+/// The generic `get` and `set` leave the choice of instructions to the
+/// compiler, which may split the access in either order.  These methods pin
+/// it down.  Note that even `LDRD`/`STRD` are performed as two word accesses
+/// on the bus, so they are *not* atomic with respect to hardware updating the
+/// register; for free-running split counters, see `read_pair_coherent`.
+#[cfg(not(any(feature = "arch:armv6-m", feature = "host-test")))]
+impl Reg<u64> {
+    /// Reads both words, low then high, with a single `LDRD`.  The register
+    /// must be word-aligned.
+    #[inline]
+    pub fn get_ldrd(&self) -> u64 {
+        let lo: u32;
+        let hi: u32;
+        unsafe {
+            asm!("ldrd $0, $1, [$2]"
+                 : "=&r"(lo), "=&r"(hi)
+                 : "r"(self.value.get())
+                 : "memory"
+                 : "volatile");
+        }
+        ((hi as u64) << 32) | (lo as u64)
+    }
+
+    /// Writes both words, low then high, with a single `STRD`.  The register
+    /// must be word-aligned.
+    #[inline]
+    pub fn set_strd(&self, value: u64) {
+        unsafe {
+            asm!("strd $0, $1, [$2]"
+                 :: "r"(value as u32), "r"((value >> 32) as u32),
+                    "r"(self.value.get())
+                 : "memory"
+                 : "volatile");
+        }
+    }
+}
+
+/// Registers that can be read, for generic helpers like `read_pair_coherent`.
+pub trait ReadableReg<T> {
+    fn read(&self) -> T;
+}
+
+impl<T> ReadableReg<T> for Reg<T> {
+    fn read(&self) -> T {
+        self.get()
+    }
+}
+
+impl<T> ReadableReg<T> for RoReg<T> {
+    fn read(&self) -> T {
+        self.get()
+    }
+}
+
+/// Reads a 64-bit value split across two 32-bit registers that the hardware
+/// updates independently (e.g. a counter whose low word may carry into the
+/// high word between our two reads).
+///
+/// Uses the standard sequence: read high, read low, re-read high, and retry if
+/// the high word changed.  The result is a value the pair actually held at
+/// some instant.
+pub fn read_pair_coherent<H, L>(hi: &H, lo: &L) -> u64
+    where H: ReadableReg<u32>, L: ReadableReg<u32> {
+    loop {
+        let h1 = hi.read();
+        let l = lo.read();
+        let h2 = hi.read();
+        if h1 == h2 {
+            return ((h1 as u64) << 32) | (l as u64)
+        }
+    }
+}
+
+/// A read-only register, such as a status or input data register.  Like
+/// `Reg<T>`, but offering no way to write.
+#[repr(C, packed)]
+pub struct RoReg<T> {
+    value: UnsafeCell<T>,
+}
+
+impl<T> RoReg<T> {
+    /// Reads the contents of the register using a volatile load.
+    pub fn get(&self) -> T {
+        unsafe { load(self.value.get()) }
+    }
+}
+
+/// A write-only register, such as a set/reset or flag-clear register, whose
+/// reads are meaningless.  Like `Reg<T>`, but offering no way to read.
+#[repr(C, packed)]
+pub struct WoReg<T> {
+    value: UnsafeCell<T>,
+}
+
+impl<T> WoReg<T> {
+    /// Replaces the contents of the register using a volatile store.
+    pub fn set(&self, value: T) {
+        unsafe { store(self.value.get(), value) }
+    }
+}
+
+/// A reserved word in a register block, present only to pad out the layout.
+/// It can be neither read nor written.
+#[repr(C, packed)]
+pub struct ReservedReg {
+    _value: UnsafeCell<u32>,
+}
+
 /// Additional features that become available when a register contains a
 /// hardware-supported atomic type.
+///
+/// On ARMv6-M, which lacks exclusive loads and stores, these operations are
+/// instead made atomic by briefly masking interrupts.  The descriptions below
+/// of restarting on a race apply to ARMv7-M only.
 pub trait AtomicReg {
     type Type;
 
@@ -67,6 +292,7 @@ pub trait AtomicReg {
 }
 
 // Implementation shorthand for the atomic RMW sequence on ARMv7M
+#[cfg(not(any(feature = "arch:armv6-m", feature = "host-test")))]
 macro_rules! atomic_rmw {
     ($cell:expr, $ty:ident, $code:expr, $($arg:expr),+) => {
         loop {
@@ -86,6 +312,7 @@ macro_rules! atomic_rmw {
     };
 }
 
+#[cfg(not(any(feature = "arch:armv6-m", feature = "host-test")))]
 macro_rules! ex_suffix {
     (u32) => { "" };
     (i32) => { "" };
@@ -95,6 +322,7 @@ macro_rules! ex_suffix {
     (i8) => { "b" };
 }
 
+#[cfg(not(any(feature = "arch:armv6-m", feature = "host-test")))]
 macro_rules! ex_impl {
     ($ty:ident) => {
         impl AtomicReg for Reg<$ty> {
@@ -123,6 +351,29 @@ macro_rules! ex_impl {
     };
 }
 
+// On ARMv6-M (and in simulation), a read-modify-write with interrupts masked.
+#[cfg(any(feature = "arch:armv6-m", feature = "host-test"))]
+macro_rules! ex_impl {
+    ($ty:ident) => {
+        impl AtomicReg for Reg<$ty> {
+            type Type = $ty;
+
+            fn atomic_nand(&self, clear: $ty) {
+                arm_m::without_interrupts(|| self.set(self.get() & !clear))
+            }
+
+            fn atomic_or(&self, set: $ty) {
+                arm_m::without_interrupts(|| self.set(self.get() | set))
+            }
+
+            fn atomic_nand_and_or(&self, clear: $ty, set: $ty) {
+                arm_m::without_interrupts(||
+                    self.set((self.get() & !clear) | set))
+            }
+        }
+    };
+}
+
 ex_impl!(u32);
 ex_impl!(u16);
 ex_impl!(u8);
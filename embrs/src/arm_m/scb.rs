@@ -1,6 +1,7 @@
 //! ARMv7-M System Control Block support.
 
-use arm_m::reg::Reg;
+use arm_m;
+use arm_m::reg::{mmio, AtomicReg, Reg, ReservedReg};
 
 #[repr(C, packed)]
 struct Registers {
@@ -19,11 +20,31 @@ struct Registers {
     pub bfar:    Reg<u32>,
     pub afsr:    Reg<u32>,
 
-    _reserved:   [Reg<u32>; 18],
+    _reserved:   [ReservedReg; 18],
 
     pub cpacr:   Reg<u32>,
 }
 
+register_layout! {
+    fn check_layout: Registers [0x8C] {
+        cpuid @ 0x00,
+        icsr @ 0x04,
+        vtor @ 0x08,
+        aircr @ 0x0C,
+        scr @ 0x10,
+        ccr @ 0x14,
+        shpr @ 0x18,
+        shcsr @ 0x24,
+        cfsr @ 0x28,
+        hfsr @ 0x2C,
+        dfsr @ 0x30,
+        mmfar @ 0x34,
+        bfar @ 0x38,
+        afsr @ 0x3C,
+        cpacr @ 0x88,
+    }
+}
+
 const SCB_ADDRESS : usize = 0xe000ed00;
 
 pub struct Scb;
@@ -32,6 +53,130 @@ pub static SCB : Scb = Scb;
 
 bit_wrappers! {
     pub struct Cpacr(pub u32);
+    /// System Handler Control and State Register.
+    pub struct Shcsr(pub u32);
+    /// Application Interrupt and Reset Control Register.
+    pub struct Aircr(pub u32);
+    /// System Control Register.
+    pub struct Scr(pub u32);
+    /// Debug Fault Status Register.
+    pub struct Dfsr(pub u32);
+    /// MemManage Fault Status Register: the low byte of the CFSR.
+    pub struct Mmfsr(pub u32);
+}
+
+impl Scr {
+    bitfield_accessors! {
+        /// Makes interrupts that become pending wake the processor from
+        /// `wait_for_event`, even if they're disabled.
+        pub total [4] get_sevonpend / with_sevonpend: bool,
+        /// Selects deep sleep (as defined by the SoC) rather than sleep.
+        pub total [2] get_sleepdeep / with_sleepdeep: bool,
+        /// Returns the processor to sleep after the last exception handler
+        /// completes, rather than to thread mode.
+        pub total [1] get_sleeponexit / with_sleeponexit: bool,
+    }
+}
+
+impl Dfsr {
+    bitfield_accessors! {
+        /// An external debug request (EDBGRQ) was asserted.
+        pub total [4] get_external / with_external: bool,
+        /// A vector catch was triggered.
+        pub total [3] get_vcatch / with_vcatch: bool,
+        /// A DWT watchpoint matched.
+        pub total [2] get_dwttrap / with_dwttrap: bool,
+        /// A `BKPT` instruction executed, or an FPB breakpoint matched.
+        pub total [1] get_bkpt / with_bkpt: bool,
+        /// A halt or step request was taken.
+        pub total [0] get_halted / with_halted: bool,
+    }
+}
+
+impl Mmfsr {
+    bitfield_accessors! {
+        /// `MMFAR` holds the address of the faulting access.
+        pub total [7] get_mmarvalid / with_mmarvalid: bool,
+        /// A fault occurred during lazy floating point state preservation.
+        pub total [5] get_mlsperr / with_mlsperr: bool,
+        /// A fault occurred while stacking for exception entry.
+        pub total [4] get_mstkerr / with_mstkerr: bool,
+        /// A fault occurred while unstacking on exception return.
+        pub total [3] get_munstkerr / with_munstkerr: bool,
+        /// A load or store violated the MPU's permissions.
+        pub total [1] get_daccviol / with_daccviol: bool,
+        /// An instruction fetch violated the MPU's permissions.
+        pub total [0] get_iaccviol / with_iaccviol: bool,
+    }
+}
+
+/// `PENDSVSET` bit in the ICSR.
+const ICSR_PENDSVSET : u32 = 1 << 28;
+/// `PENDSTCLR` bit in the ICSR.
+const ICSR_PENDSTCLR : u32 = 1 << 25;
+
+/// Value that must be written to `Aircr::with_vectkey` for a write to take
+/// effect.
+pub const AIRCR_VECTKEY : u32 = 0x05fa;
+
+impl Aircr {
+    bitfield_accessors! {
+        /// Must be written as `AIRCR_VECTKEY`; reads as its complement.
+        pub total [31:16] get_vectkey / with_vectkey: u32,
+        /// Reads as `true` on big-endian systems.
+        pub total [15] get_endianness / with_endianness: bool,
+        /// Priority grouping: the position of the binary point splitting each
+        /// eight-bit priority into preemption priority (above) and
+        /// subpriority (at and below).
+        pub total [10:8] get_prigroup / with_prigroup: u32,
+        /// Requests a system reset.
+        pub total [2] get_sysresetreq / with_sysresetreq: bool,
+    }
+}
+
+impl Shcsr {
+    bitfield_accessors! {
+        /// Enables UsageFault as a separate exception; otherwise usage faults
+        /// escalate to HardFault.
+        pub total [18] get_usgfaultena / with_usgfaultena: bool,
+        /// Enables BusFault as a separate exception.
+        pub total [17] get_busfaultena / with_busfaultena: bool,
+        /// Enables MemManage as a separate exception.
+        pub total [16] get_memfaultena / with_memfaultena: bool,
+        pub total [15] get_svcallpended / with_svcallpended: bool,
+        pub total [14] get_busfaultpended / with_busfaultpended: bool,
+        pub total [13] get_memfaultpended / with_memfaultpended: bool,
+        pub total [12] get_usgfaultpended / with_usgfaultpended: bool,
+        pub total [11] get_systickact / with_systickact: bool,
+        pub total [10] get_pendsvact / with_pendsvact: bool,
+        pub total [8] get_monitoract / with_monitoract: bool,
+        pub total [7] get_svcallact / with_svcallact: bool,
+        pub total [3] get_usgfaultact / with_usgfaultact: bool,
+        pub total [1] get_busfaultact / with_busfaultact: bool,
+        pub total [0] get_memfaultact / with_memfaultact: bool,
+    }
+}
+
+/// System exceptions with configurable priority, numbered as in the
+/// architecture.  (ARMv6-M only implements `SvCall`, `PendSv`, and `SysTick`.)
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum SystemException {
+    MemManage = 4,
+    BusFault = 5,
+    UsageFault = 6,
+    SvCall = 11,
+    DebugMonitor = 12,
+    PendSv = 14,
+    SysTick = 15,
+}
+
+/// Faults that can be enabled as separate exceptions, rather than escalating
+/// to HardFault.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum Fault {
+    MemManage,
+    BusFault,
+    UsageFault,
 }
 
 bit_enums! {
@@ -67,10 +212,170 @@ macro_rules! reg_accessors {
 
 impl Scb {
     fn reg(&self) -> &'static Registers {
-        unsafe { &*(SCB_ADDRESS as *const Registers) }
+        unsafe { mmio(SCB_ADDRESS) }
     }
 
     reg_accessors!(cpacr, Cpacr, read_cpacr, write_cpacr, update_cpacr);
+    reg_accessors!(shcsr, Shcsr, read_shcsr, write_shcsr, update_shcsr);
+    reg_accessors!(scr, Scr, read_scr, write_scr, update_scr);
+
+    pub fn read_dfsr(&self) -> Dfsr {
+        Dfsr(self.reg().dfsr.get())
+    }
+
+    /// Clears the debug fault status bits set in `v`, which are
+    /// write-one-to-clear.
+    pub fn clear_dfsr(&self, v: Dfsr) {
+        self.reg().dfsr.set(v.0)
+    }
+
+    pub fn read_mmfsr(&self) -> Mmfsr {
+        Mmfsr(self.reg().cfsr.get() & 0xFF)
+    }
+
+    /// Clears the MemManage fault status bits set in `v`, which are
+    /// write-one-to-clear.  The other fault status fields in the CFSR are
+    /// left alone.
+    pub fn clear_mmfsr(&self, v: Mmfsr) {
+        self.reg().cfsr.set(v.0 & 0xFF)
+    }
+
+    /// Reads the MemManage Fault Address Register, which is meaningful only
+    /// if `Mmfsr::get_mmarvalid` is set.
+    pub fn read_mmfar(&self) -> u32 {
+        self.reg().mmfar.get()
+    }
+
+    /// Reads the whole Configurable Fault Status Register: the MemManage,
+    /// BusFault and UsageFault status, in bytes 0, 1 and 2-3.
+    pub fn read_cfsr(&self) -> u32 {
+        self.reg().cfsr.get()
+    }
+
+    /// Reads the HardFault Status Register.
+    pub fn read_hfsr(&self) -> u32 {
+        self.reg().hfsr.get()
+    }
+
+    /// Reads the BusFault Address Register, which is meaningful only if
+    /// `BFARVALID` (CFSR bit 15) is set.
+    pub fn read_bfar(&self) -> u32 {
+        self.reg().bfar.get()
+    }
+
+    /// Requests a system reset, and waits for it to happen.
+    pub fn system_reset(&self) -> ! {
+        arm_m::data_synchronization_barrier();
+        self.update_aircr(|v| v.with_sysresetreq(true));
+        arm_m::data_synchronization_barrier();
+        loop {}
+    }
+
+    /// Pends the PendSV exception, which runs once no higher-priority
+    /// exception is active.  Interrupt handlers use this to defer work to a
+    /// lower priority.
+    pub fn set_pendsv(&self) {
+        // Zeros written to the other ICSR bits have no effect.
+        self.reg().icsr.set(ICSR_PENDSVSET)
+    }
+
+    /// Clears a pending SysTick exception.
+    pub fn clear_pending_systick(&self) {
+        self.reg().icsr.set(ICSR_PENDSTCLR)
+    }
+
+    /// Reads the Vector Table Offset Register: the address of the vector
+    /// table in use.
+    pub fn read_vtor(&self) -> u32 {
+        self.reg().vtor.get()
+    }
+
+    /// Moves the vector table to `address`, which must be aligned to the
+    /// table's size rounded up to a power of two (512 bytes will do for the
+    /// STM32F4).
+    pub fn write_vtor(&self, address: u32) {
+        self.reg().vtor.set(address)
+    }
+
+    /// Configures the processor to go back to sleep when returning from the
+    /// last active exception handler.  This suits applications that do all
+    /// their work in interrupt handlers: thread mode runs only once, to set
+    /// things up.
+    pub fn set_sleep_on_exit(&self, enabled: bool) {
+        self.update_scr(|v| v.with_sleeponexit(enabled))
+    }
+
+    /// Makes pending interrupts -- even disabled ones -- count as events for
+    /// `wait_for_event`.  This lets an application sleep waiting for a
+    /// peripheral without taking an interrupt for it.
+    pub fn set_sevonpend(&self, enabled: bool) {
+        self.update_scr(|v| v.with_sevonpend(enabled))
+    }
+
+    /// Selects deep sleep for subsequent WFI/WFE.
+    pub fn set_sleep_deep(&self, enabled: bool) {
+        self.update_scr(|v| v.with_sleepdeep(enabled))
+    }
+
+    pub fn read_aircr(&self) -> Aircr {
+        Aircr(self.reg().aircr.get())
+    }
+
+    /// Updates the AIRCR using `f`, supplying the `VECTKEY` that writes
+    /// require.  `f` should leave the reset request bits clear unless a reset
+    /// is intended.
+    pub fn update_aircr<F: FnOnce(Aircr) -> Aircr>(&self, f: F) {
+        // Reads return the complement of the key, and the reset bits read as
+        // zero, so start from a clean slate rather than echoing them.
+        let current = Aircr(self.read_aircr().0 & 0x0000_0700);
+        self.reg().aircr.set(f(current).with_vectkey(AIRCR_VECTKEY).0)
+    }
+
+    /// Reads the priority grouping (PRIGROUP) field.
+    pub fn get_priority_grouping_raw(&self) -> u32 {
+        self.read_aircr().get_prigroup()
+    }
+
+    /// Sets the priority grouping (PRIGROUP) field.  Note that the SoC layer
+    /// usually provides a more meaningful wrapper (e.g.
+    /// `stm32f4::irq::set_priority_grouping`).
+    ///
+    /// This should be set once, early; changing it while interrupts are
+    /// active can reorder preemption in surprising ways.
+    pub fn set_priority_grouping_raw(&self, prigroup: u32) {
+        self.update_aircr(|v| v.with_prigroup(prigroup))
+    }
+
+    /// Sets the priority of a system exception, as a raw eight-bit value of
+    /// which the SoC may implement only the top few bits.  See the SoC
+    /// layer's wrapper (e.g. `stm32f4::irq::ScbExt`) for a typed version.
+    pub fn set_priority_raw(&self, exc: SystemException, priority: u8) {
+        let n = (exc as usize) - 4;
+        let shift = (n % 4) * 8;
+        self.reg().shpr[n / 4]
+            .atomic_nand_and_or(0xFF << shift, (priority as u32) << shift)
+    }
+
+    /// Reads the raw priority of a system exception.
+    pub fn get_priority_raw(&self, exc: SystemException) -> u8 {
+        let n = (exc as usize) - 4;
+        (self.reg().shpr[n / 4].get() >> ((n % 4) * 8)) as u8
+    }
+
+    /// Enables or disables `fault` as a separate exception.  When disabled,
+    /// the fault escalates to HardFault.
+    pub fn set_fault_enabled(&self, fault: Fault, enabled: bool) {
+        let bit = match fault {
+            Fault::MemManage => Shcsr(0).with_memfaultena(true),
+            Fault::BusFault => Shcsr(0).with_busfaultena(true),
+            Fault::UsageFault => Shcsr(0).with_usgfaultena(true),
+        };
+        if enabled {
+            self.reg().shcsr.atomic_or(bit.0)
+        } else {
+            self.reg().shcsr.atomic_nand(bit.0)
+        }
+    }
 }
 
 #[cfg(feature = "cpu:cortex-m4f")]
@@ -82,6 +387,16 @@ struct FpRegisters {
     pub mvfr:    [Reg<u32>; 2],
 }
 
+#[cfg(feature = "cpu:cortex-m4f")]
+register_layout! {
+    fn check_fp_layout: FpRegisters [0x14] {
+        fpccr @ 0x00,
+        fpcar @ 0x04,
+        fpdscr @ 0x08,
+        mvfr @ 0x0C,
+    }
+}
+
 #[cfg(feature = "cpu:cortex-m4f")]
 const SCB_FP_ADDRESS : usize = 0xe000ef34;
 
@@ -112,10 +427,16 @@ impl Fpccr {
 
 impl ScbFp {
     fn reg(&self) -> &'static FpRegisters {
-        unsafe { &*(SCB_FP_ADDRESS as *const FpRegisters) }
+        unsafe { mmio(SCB_FP_ADDRESS) }
     }
 
     reg_accessors!(fpccr, Fpccr, read_fpccr, write_fpccr, update_fpccr);
+
+    /// Writes the FP Default Status Control Register, which supplies the
+    /// initial FPSCR for exception handlers.
+    pub fn write_fpdscr(&self, v: u32) {
+        self.reg().fpdscr.set(v)
+    }
 }
 
 
@@ -0,0 +1,207 @@
+//! Simulated register memory for host-side testing.
+//!
+//! With the `host-test` feature, `reg::mmio` maps peripheral addresses into
+//! memory managed by this module instead of dereferencing them, so driver
+//! logic can be exercised by `cargo test` on the development machine.
+//!
+//! Each test thread gets its own simulated memory, initially all zeros, and
+//! its own access log.  Tests arrange for the "hardware" state a driver
+//! expects using `poke` (and `set_write_hook`, for registers whose bits must
+//! respond to writes), run the driver, and check the result using `peek` or
+//! `take_log`:
+//!
+//! ```
+//! sim::reset();
+//! sim::poke(RCC_CR, HSERDY);
+//! // ... call the driver ...
+//! assert!(sim::take_log().iter().any(|a| a.kind == AccessKind::Write
+//!                                        && a.address == RCC_CR));
+//! ```
+//!
+//! Limitations: registers are simulated as plain memory, so hardware behavior
+//! (read-to-clear bits, status flags) must be supplied by the test; pointer
+//! registers are host-sized, which changes the layout of blocks containing
+//! them; and accesses that bypass `mmio` (bit-band aliases, Flash
+//! programming) are not simulated at all.
+
+use std::cell::RefCell;
+use std::mem;
+use std::ptr;
+use std::vec::Vec;
+
+/// Direction of a logged register access.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// A logged register access.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Access {
+    pub kind: AccessKind,
+    /// Target (not simulated) address of the register.
+    pub address: usize,
+    /// Value read or written, zero-extended.
+    pub value: u64,
+}
+
+/// Transforms the word written to a register before it lands in simulated
+/// memory.  Receives the target address and written value.
+pub type WriteHook = fn(usize, u32) -> u32;
+
+/// Simulated memory is allocated in aligned chunks of this size, so that
+/// registers mapped separately (a whole peripheral, or a single word poked by
+/// a test) still share storage.
+const CHUNK_SIZE : usize = 1 << 20;
+
+struct Chunk {
+    /// Target address of the start of the chunk.
+    base: usize,
+    /// Simulated storage.  Backed by `u64`s for alignment.
+    storage: *mut u64,
+}
+
+struct Sim {
+    chunks: Vec<Chunk>,
+    log: Vec<Access>,
+    hooks: Vec<(usize, WriteHook)>,
+}
+
+thread_local! {
+    static SIM: RefCell<Sim> = RefCell::new(Sim {
+        chunks: Vec::new(),
+        log: Vec::new(),
+        hooks: Vec::new(),
+    })
+}
+
+/// Maps `size` bytes at target address `address` into simulated memory,
+/// returning the simulated address.  Used by `reg::mmio`.
+///
+/// # Panics
+///
+/// If the range crosses a (1MiB) chunk boundary.
+pub fn map(address: usize, size: usize) -> usize {
+    let base = address & !(CHUNK_SIZE - 1);
+    assert!(address + size <= base + CHUNK_SIZE,
+            "simulated range crosses chunk boundary");
+
+    SIM.with(|sim| {
+        let mut sim = sim.borrow_mut();
+        if let Some(c) = sim.chunks.iter().find(|c| c.base == base) {
+            return (c.storage as usize) + (address - base)
+        }
+
+        // Simulated memory is deliberately leaked: references to it may
+        // outlive a `reset`.
+        let words = vec![0u64; CHUNK_SIZE / 8].into_boxed_slice();
+        let storage = Box::into_raw(words) as *mut u64;
+        sim.chunks.push(Chunk {
+            base: base,
+            storage: storage,
+        });
+        (storage as usize) + (address - base)
+    })
+}
+
+/// Forgets all simulated memory, hooks, and log entries for this thread.
+/// Simulated memory reads as zero again afterwards.
+pub fn reset() {
+    SIM.with(|sim| {
+        let mut sim = sim.borrow_mut();
+        sim.chunks.clear();
+        sim.log.clear();
+        sim.hooks.clear();
+    })
+}
+
+/// Translates a simulated address back to its target address, if it's in
+/// simulated memory.
+fn target_address(sim: &Sim, simulated: usize) -> Option<usize> {
+    sim.chunks.iter()
+        .find(|c| simulated >= c.storage as usize
+              && simulated < (c.storage as usize) + CHUNK_SIZE)
+        .map(|c| c.base + (simulated - c.storage as usize))
+}
+
+fn value_bits<T>(value: &T) -> u64 {
+    let mut bits = 0u64;
+    let size = mem::size_of::<T>();
+    assert!(size <= 8);
+    unsafe {
+        ptr::copy_nonoverlapping(value as *const T as *const u8,
+                                 &mut bits as *mut u64 as *mut u8,
+                                 size);
+    }
+    bits
+}
+
+/// Records a read of `value` from simulated address `simulated`.  Used by
+/// the register types.
+pub fn log_read<T>(simulated: usize, value: &T) {
+    SIM.with(|sim| {
+        let mut sim = sim.borrow_mut();
+        if let Some(address) = target_address(&sim, simulated) {
+            let bits = value_bits(value);
+            sim.log.push(Access {
+                kind: AccessKind::Read,
+                address: address,
+                value: bits,
+            })
+        }
+    })
+}
+
+/// Records a write of `value` to simulated address `simulated`, and applies
+/// any write hook for that address to the value.  Used by the register
+/// types.
+pub fn log_write<T>(simulated: usize, value: &mut T) {
+    SIM.with(|sim| {
+        let mut sim = sim.borrow_mut();
+        if let Some(address) = target_address(&sim, simulated) {
+            let bits = value_bits(value);
+            sim.log.push(Access {
+                kind: AccessKind::Write,
+                address: address,
+                value: bits,
+            });
+            let hook = sim.hooks.iter()
+                .find(|&&(a, _)| a == address)
+                .map(|&(_, h)| h);
+            if let Some(h) = hook {
+                if mem::size_of::<T>() == 4 {
+                    let new = h(address, bits as u32);
+                    unsafe {
+                        ptr::write(value as *mut T as *mut u32, new)
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Installs a hook that transforms every word written to the 32-bit
+/// register at `address`.  For example, a hook for an RCC configuration
+/// register can copy the requested clock source into the status field, as
+/// the hardware would.
+pub fn set_write_hook(address: usize, hook: WriteHook) {
+    SIM.with(|sim| sim.borrow_mut().hooks.push((address, hook)))
+}
+
+/// Writes a word of simulated memory directly, without logging.
+pub fn poke(address: usize, value: u32) {
+    let p = map(address, 4) as *mut u32;
+    unsafe { ptr::write_volatile(p, value) }
+}
+
+/// Reads a word of simulated memory directly, without logging.
+pub fn peek(address: usize) -> u32 {
+    let p = map(address, 4) as *const u32;
+    unsafe { ptr::read_volatile(p) }
+}
+
+/// Removes and returns the access log for this thread.
+pub fn take_log() -> Vec<Access> {
+    SIM.with(|sim| mem::replace(&mut sim.borrow_mut().log, Vec::new()))
+}
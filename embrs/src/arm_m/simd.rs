@@ -0,0 +1,231 @@
+//! The ARMv7E-M DSP extension: saturating and SIMD arithmetic.
+//!
+//! The Cortex-M4 can saturate to an arbitrary width, add and subtract with
+//! saturation, and treat a 32-bit register as two 16-bit or four 8-bit lanes
+//! -- multiplying and accumulating both halves at once, for instance.  The
+//! functions here wrap those instructions.  On other targets (and under
+//! `host-test`) they compute the same results in plain Rust, more slowly, so
+//! code using them stays portable.
+//!
+//! Packed operands are `u32`s with lane 0 in the least significant bits;
+//! `pack16` and `unpack16` convert to and from pairs of `i16`.
+//!
+//! `SEL`, which picks lanes based on flags set by an earlier SIMD
+//! instruction, can't safely be exposed alone -- the compiler doesn't know
+//! about those flags -- so it appears only fused with the subtraction that
+//! sets them, as the lane-wise `umax8`, `smax16` and friends.
+//!
+//! The saturating instructions also set the sticky Q flag, which these
+//! wrappers ignore.
+
+macro_rules! dsp_fn {
+    ($(#[$m:meta])*
+     pub fn $name:ident($($arg:ident: $ty:ty),*) -> $r:ty
+         => $insn:tt, else $fallback:expr) => {
+        $(#[$m])*
+        #[cfg(all(feature = "cpu:cortex-m4f", not(feature = "host-test")))]
+        #[inline]
+        pub fn $name($($arg: $ty),*) -> $r {
+            let r: $r;
+            unsafe {
+                asm!($insn : "=&r"(r) : $("r"($arg)),* : "cc")
+            }
+            r
+        }
+
+        $(#[$m])*
+        #[cfg(not(all(feature = "cpu:cortex-m4f", not(feature = "host-test"))))]
+        #[inline]
+        pub fn $name($($arg: $ty),*) -> $r {
+            $fallback
+        }
+    };
+}
+
+/// Clamps `x` to `[lo, hi]`.
+#[cfg(not(all(feature = "cpu:cortex-m4f", not(feature = "host-test"))))]
+#[inline]
+fn clamp(x: i32, lo: i32, hi: i32) -> i32 {
+    if x < lo { lo } else if x > hi { hi } else { x }
+}
+
+/// Applies `f` to corresponding signed 16-bit lanes of `a` and `b`.
+#[cfg(not(all(feature = "cpu:cortex-m4f", not(feature = "host-test"))))]
+#[inline]
+fn lanes16<F: Fn(i32, i32) -> i32>(a: u32, b: u32, f: F) -> u32 {
+    let (a0, a1) = unpack16(a);
+    let (b0, b1) = unpack16(b);
+    (f(a0 as i32, b0 as i32) as u16 as u32)
+        | (f(a1 as i32, b1 as i32) as u16 as u32) << 16
+}
+
+/// Applies `f` to corresponding unsigned 8-bit lanes of `a` and `b`.
+#[cfg(not(all(feature = "cpu:cortex-m4f", not(feature = "host-test"))))]
+#[inline]
+fn lanes8<F: Fn(u32, u32) -> u32>(a: u32, b: u32, f: F) -> u32 {
+    (0..4).fold(0, |r, i| {
+        let s = i * 8;
+        r | (f((a >> s) & 0xFF, (b >> s) & 0xFF) & 0xFF) << s
+    })
+}
+
+dsp_fn! {
+    /// Saturates `x` to a signed 16-bit value.
+    pub fn ssat16(x: i32) -> i32
+        => "ssat $0, #16, $1", else clamp(x, -0x8000, 0x7FFF)
+}
+
+dsp_fn! {
+    /// Saturates `x` to a signed 8-bit value.
+    pub fn ssat8(x: i32) -> i32
+        => "ssat $0, #8, $1", else clamp(x, -0x80, 0x7F)
+}
+
+dsp_fn! {
+    /// Saturates `x` to an unsigned 16-bit value.
+    pub fn usat16(x: i32) -> u32
+        => "usat $0, #16, $1", else clamp(x, 0, 0xFFFF) as u32
+}
+
+dsp_fn! {
+    /// Saturates `x` to an unsigned 12-bit value -- the range of the ADC
+    /// and DAC.
+    pub fn usat12(x: i32) -> u32
+        => "usat $0, #12, $1", else clamp(x, 0, 0xFFF) as u32
+}
+
+dsp_fn! {
+    /// Saturates `x` to an unsigned 8-bit value.
+    pub fn usat8(x: i32) -> u32
+        => "usat $0, #8, $1", else clamp(x, 0, 0xFF) as u32
+}
+
+dsp_fn! {
+    /// Adds, saturating.
+    pub fn qadd(a: i32, b: i32) -> i32
+        => "qadd $0, $1, $2", else a.saturating_add(b)
+}
+
+dsp_fn! {
+    /// Subtracts `b` from `a`, saturating.
+    pub fn qsub(a: i32, b: i32) -> i32
+        => "qsub $0, $1, $2", else a.saturating_sub(b)
+}
+
+dsp_fn! {
+    /// Adds signed 16-bit lanes, saturating.
+    pub fn qadd16(a: u32, b: u32) -> u32
+        => "qadd16 $0, $1, $2",
+        else lanes16(a, b, |x, y| clamp(x + y, -0x8000, 0x7FFF))
+}
+
+dsp_fn! {
+    /// Subtracts signed 16-bit lanes, saturating.
+    pub fn qsub16(a: u32, b: u32) -> u32
+        => "qsub16 $0, $1, $2",
+        else lanes16(a, b, |x, y| clamp(x - y, -0x8000, 0x7FFF))
+}
+
+dsp_fn! {
+    /// Sum of the products of corresponding signed 16-bit lanes: a dot
+    /// product of two pairs.
+    pub fn smuad(a: u32, b: u32) -> i32
+        => "smuad $0, $1, $2",
+        else {
+            let (a0, a1) = unpack16(a);
+            let (b0, b1) = unpack16(b);
+            (a0 as i32 * b0 as i32).wrapping_add(a1 as i32 * b1 as i32)
+        }
+}
+
+dsp_fn! {
+    /// Difference of the products of corresponding signed 16-bit lanes:
+    /// lane 0's minus lane 1's.  With one operand's lanes swapped, that's
+    /// the real part of a complex multiply.
+    pub fn smusd(a: u32, b: u32) -> i32
+        => "smusd $0, $1, $2",
+        else {
+            let (a0, a1) = unpack16(a);
+            let (b0, b1) = unpack16(b);
+            (a0 as i32 * b0 as i32).wrapping_sub(a1 as i32 * b1 as i32)
+        }
+}
+
+dsp_fn! {
+    /// `acc` plus the products of corresponding signed 16-bit lanes: the
+    /// inner step of a 16-bit FIR filter or dot product, two taps at a time.
+    pub fn smlad(a: u32, b: u32, acc: i32) -> i32
+        => "smlad $0, $1, $2, $3",
+        else acc.wrapping_add(smuad(a, b))
+}
+
+dsp_fn! {
+    /// Packs the bottom half of `lo` and the bottom half of `hi` into one
+    /// word (PKHBT).
+    pub fn pkhbt(lo: u32, hi: u32) -> u32
+        => "pkhbt $0, $1, $2, lsl #16",
+        else (lo & 0xFFFF) | hi << 16
+}
+
+dsp_fn! {
+    /// Packs the top half of `hi` and the top half of `lo` into one word
+    /// (PKHTB).
+    pub fn pkhtb(hi: u32, lo: u32) -> u32
+        => "pkhtb $0, $1, $2, asr #16",
+        else (hi & 0xFFFF_0000) | lo >> 16
+}
+
+dsp_fn! {
+    /// Sign-extends bytes 0 and 2 to fill 16-bit lanes 0 and 1.
+    pub fn sxtb16(x: u32) -> u32
+        => "sxtb16 $0, $1",
+        else (x as u8 as i8 as i16 as u16 as u32)
+            | (((x >> 16) as u8 as i8 as i16 as u16 as u32) << 16)
+}
+
+dsp_fn! {
+    /// Zero-extends bytes 0 and 2 to fill 16-bit lanes 0 and 1.
+    pub fn uxtb16(x: u32) -> u32
+        => "uxtb16 $0, $1",
+        else x & 0x00FF_00FF
+}
+
+dsp_fn! {
+    /// Lane-wise maximum of unsigned bytes.
+    pub fn umax8(a: u32, b: u32) -> u32
+        => "usub8 $0, $1, $2\n\tsel $0, $1, $2",
+        else lanes8(a, b, |x, y| if x >= y { x } else { y })
+}
+
+dsp_fn! {
+    /// Lane-wise minimum of unsigned bytes.
+    pub fn umin8(a: u32, b: u32) -> u32
+        => "usub8 $0, $1, $2\n\tsel $0, $2, $1",
+        else lanes8(a, b, |x, y| if x >= y { y } else { x })
+}
+
+dsp_fn! {
+    /// Lane-wise maximum of signed 16-bit values.
+    pub fn smax16(a: u32, b: u32) -> u32
+        => "ssub16 $0, $1, $2\n\tsel $0, $1, $2",
+        else lanes16(a, b, |x, y| if x >= y { x } else { y })
+}
+
+dsp_fn! {
+    /// Lane-wise minimum of signed 16-bit values.
+    pub fn smin16(a: u32, b: u32) -> u32
+        => "ssub16 $0, $1, $2\n\tsel $0, $2, $1",
+        else lanes16(a, b, |x, y| if x >= y { y } else { x })
+}
+
+/// Packs two `i16`s into 16-bit lanes, `lo` in lane 0.
+#[inline]
+pub fn pack16(lo: i16, hi: i16) -> u32 {
+    pkhbt(lo as u16 as u32, hi as u16 as u32)
+}
+
+/// Splits a word into its 16-bit lanes, lane 0 first.
+#[inline]
+pub fn unpack16(x: u32) -> (i16, i16) {
+    (x as i16, (x >> 16) as i16)
+}
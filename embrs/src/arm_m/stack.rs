@@ -0,0 +1,241 @@
+//! Main stack usage measurement and overflow detection.
+//!
+//! The main stack runs from `__STACK_LIMIT` up to `__STACK_BASE`, both
+//! provided by the linker script, and grows down.  With the `stack:paint`
+//! feature, startup fills the unused part of it with `PAINT` before `main`;
+//! since the pattern is overwritten wherever the stack has reached, the
+//! lowest disturbed word marks the deepest the stack has ever been, which
+//! `high_water_mark` reports.
+//!
+//! On ARMv7-M, `enable_guard` can also use the MPU to make the bottom
+//! `GUARD_SIZE` bytes of the stack inaccessible.  An overflow then faults at
+//! the offending access instead of quietly corrupting whatever lies below.
+//! Install `stack_guard_isr` as the `mem_manage` vector to have it reported:
+//!
+//!     stack::set_overflow_handler(report_overflow);
+//!     stack::enable_guard(7).unwrap();
+//!
+//! Because the handler runs on a stack that has, by definition, run out, it
+//! first resets the stack pointer to the top of the stack.  The interrupted
+//! code's stack contents are lost, and the handler must not return.
+
+#[cfg(not(feature = "arch:armv6-m"))]
+use core::mem;
+use core::ptr;
+#[cfg(not(feature = "arch:armv6-m"))]
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+#[cfg(not(feature = "arch:armv6-m"))]
+use arm_m::mpu::{self, AccessPermission, MPU, Rasr};
+#[cfg(not(feature = "arch:armv6-m"))]
+use arm_m::scb::{Fault, Mmfsr, SCB};
+
+extern {
+    static __STACK_LIMIT: u32;
+    static __STACK_BASE: u32;
+}
+
+/// Pattern written over unused stack.
+pub const PAINT : u32 = 0xDEAD_BEEF;
+
+/// Bytes left unpainted just below the stack pointer in `paint`, to avoid
+/// disturbing the frame of the code doing the painting.
+const PAINT_MARGIN : usize = 64;
+
+/// Size of the MPU guard region at the bottom of the stack, as a power of two.
+/// The guard is excluded from painting and from the usable stack size.
+#[cfg(not(feature = "arch:armv6-m"))]
+pub const GUARD_SIZE_LOG2 : u32 = mpu::MIN_REGION_SIZE_LOG2;
+
+/// Size of the MPU guard region at the bottom of the stack, in bytes.
+#[cfg(not(feature = "arch:armv6-m"))]
+pub const GUARD_SIZE : usize = 1 << GUARD_SIZE_LOG2;
+
+#[cfg(feature = "arch:armv6-m")]
+const GUARD_SIZE : usize = 0;
+
+/// Lowest address of the stack region, including any guard.
+pub fn limit() -> usize {
+    unsafe { &__STACK_LIMIT as *const u32 as usize }
+}
+
+/// Address just above the stack region; the initial stack pointer.
+pub fn base() -> usize {
+    unsafe { &__STACK_BASE as *const u32 as usize }
+}
+
+/// Usable size of the stack, in bytes, not counting the guard.
+pub fn size() -> usize {
+    base() - limit() - GUARD_SIZE
+}
+
+/// Reads the current stack pointer.
+#[inline(always)]
+pub fn current_sp() -> usize {
+    let sp: usize;
+    unsafe {
+        asm!("mov $0, sp"
+             : "=r"(sp)
+             ::: "volatile")
+    }
+    sp
+}
+
+/// Bytes of stack currently in use.
+pub fn current_usage() -> usize {
+    base() - current_sp()
+}
+
+/// Fills the stack from just above the guard to just below the current
+/// stack pointer with `PAINT`.  This is normally done by the `stack:paint`
+/// init hook; calling it later discards the history `high_water_mark` relies
+/// on.
+#[inline(never)]
+pub fn paint() {
+    let end = (current_sp() - PAINT_MARGIN) & !3;
+    let mut p = (limit() + GUARD_SIZE) as *mut u32;
+    while (p as usize) < end {
+        unsafe {
+            ptr::write_volatile(p, PAINT);
+            p = p.offset(1)
+        }
+    }
+}
+
+/// Reports the largest number of bytes of stack that have been in use since
+/// it was painted, found by scanning up from the bottom for the first word
+/// that no longer holds `PAINT`.
+///
+/// This can under-report by a word or so if the deepest use happened to
+/// store `PAINT` itself, and it's meaningless if the stack was never painted.
+pub fn high_water_mark() -> usize {
+    let mut p = (limit() + GUARD_SIZE) as *const u32;
+    while (p as usize) < base() && unsafe { ptr::read_volatile(p) } == PAINT {
+        p = unsafe { p.offset(1) }
+    }
+    base() - p as usize
+}
+
+/// Bytes of stack that have never been used since painting.
+pub fn headroom() -> usize {
+    size() - high_water_mark()
+}
+
+/// Details of a MemManage fault, captured by `stack_guard_isr`.
+#[cfg(not(feature = "arch:armv6-m"))]
+#[derive(Copy, Clone, Debug)]
+pub struct StackFault {
+    /// Contents of the MemManage Fault Status Register.
+    pub mmfsr: Mmfsr,
+    /// The faulting data address, if the hardware recorded one.
+    pub address: Option<usize>,
+    /// Value of the main stack pointer when the fault was taken.
+    pub sp: usize,
+}
+
+#[cfg(not(feature = "arch:armv6-m"))]
+impl StackFault {
+    /// Checks whether this fault looks like a stack overflow: either an
+    /// access to the guard region, or a failure to push an exception frame.
+    pub fn is_overflow(&self) -> bool {
+        let (lo, hi) = (limit(), limit() + GUARD_SIZE);
+        self.mmfsr.get_mstkerr()
+            || self.address.map(|a| a >= lo && a < hi).unwrap_or(false)
+    }
+}
+
+/// Function called from `stack_guard_isr`.  It runs on a fresh stack, and
+/// must not return.
+#[cfg(not(feature = "arch:armv6-m"))]
+pub type OverflowHandler = fn(StackFault) -> !;
+
+/// Registered handler, stored as an address (or zero).
+#[cfg(not(feature = "arch:armv6-m"))]
+static HANDLER : AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Registers `handler` to receive MemManage faults.  Without one, faults
+/// `panic!`.
+#[cfg(not(feature = "arch:armv6-m"))]
+pub fn set_overflow_handler(handler: OverflowHandler) {
+    HANDLER.store(handler as usize, Ordering::Release)
+}
+
+/// Reasons the guard can't be enabled.
+#[cfg(not(feature = "arch:armv6-m"))]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum GuardError {
+    /// The processor has no MPU, or not enough regions.
+    NoMpu,
+    /// `__STACK_LIMIT` isn't aligned to `GUARD_SIZE`.
+    Misaligned,
+}
+
+/// Makes the bottom `GUARD_SIZE` bytes of the stack inaccessible using MPU
+/// region `region`, enables the MPU with the default map for privileged
+/// code, and enables the MemManage fault.
+///
+/// Since the highest-numbered matching region wins, `region` should be above
+/// any regions that cover the stack.
+#[cfg(not(feature = "arch:armv6-m"))]
+pub fn enable_guard(region: usize) -> Result<(), GuardError> {
+    let attrs = Rasr::default()
+        .with_ap(AccessPermission::NoAccess)
+        .with_xn(true);
+    match MPU.set_region(region, limit(), GUARD_SIZE_LOG2, attrs) {
+        Ok(()) => (),
+        Err(mpu::RegionError::Misaligned) =>
+            return Err(GuardError::Misaligned),
+        Err(_) => return Err(GuardError::NoMpu),
+    }
+    SCB.set_fault_enabled(Fault::MemManage, true);
+    MPU.enable(true);
+    Ok(())
+}
+
+/// MemManage fault handler.  Install this as the `mem_manage` vector.
+///
+/// This records the main stack pointer, moves it back to the top of the
+/// stack so that there's room to run, and calls the handler proper.
+#[cfg(not(feature = "arch:armv6-m"))]
+#[naked]
+pub extern "C" fn stack_guard_isr() {
+    unsafe {
+        asm!("mrs r0, MSP
+              ldr r1, =__STACK_BASE
+              msr MSP, r1
+              b embrs_stack_guard_fault"
+             :::: "volatile")
+    }
+}
+
+/// Body of `stack_guard_isr`, given the stack pointer at the time of the
+/// fault.
+#[cfg(not(feature = "arch:armv6-m"))]
+#[no_mangle]
+pub extern "C" fn embrs_stack_guard_fault(sp: usize) -> ! {
+    let mmfsr = SCB.read_mmfsr();
+    let address = if mmfsr.get_mmarvalid() {
+        Some(SCB.read_mmfar() as usize)
+    } else {
+        None
+    };
+    SCB.clear_mmfsr(mmfsr);
+
+    let fault = StackFault {
+        mmfsr: mmfsr,
+        address: address,
+        sp: sp,
+    };
+
+    let h = HANDLER.load(Ordering::Acquire);
+    if h != 0 {
+        let handler: OverflowHandler = unsafe { mem::transmute(h) };
+        handler(fault)
+    }
+
+    if fault.is_overflow() {
+        panic!("stack overflow: {:?}", fault)
+    } else {
+        panic!("MemManage fault: {:?}", fault)
+    }
+}
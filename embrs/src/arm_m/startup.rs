@@ -14,6 +14,9 @@
 
 #![macro_use]
 
+#[cfg(feature = "soc_family:stm32f4")]
+use core::ptr;
+
 use arm_m;
 use arm_m::scb::{self, SCB};
 
@@ -26,24 +29,30 @@ pub unsafe extern fn _reset_vector() -> ! {
     .extern _embrs_init_array_start, _embrs_init_array_end
     .extern embrs_main
 
+    @ This sequence sticks to instructions available on ARMv6-M.
+
     @ Initialize data.
     ldr r0, =_data_load
     ldr r1, =_data
     ldr r2, =_edata
     b 1f
 
-0:  ldr r3, [r0], #4
-    str r3, [r1], #4
+0:  ldr r3, [r0]
+    adds r0, #4
+    str r3, [r1]
+    adds r1, #4
 1:  cmp r1, r2
     bne 0b
 
-    @ Zero BSS.
+    @ Zero BSS.  (.noinit, which holds state that must survive reset, is
+    @ deliberately left alone.)
     ldr r0, =_bss
     ldr r1, =_ebss
     movs r2, #0
     b 1f
 
-0:  str r2, [r0], #4
+0:  str r2, [r0]
+    adds r0, #4
 1:  cmp r0, r1
     bne 0b
 
@@ -52,7 +61,8 @@ pub unsafe extern fn _reset_vector() -> ! {
     ldr r5, =_embrs_init_array_end
     b 1f
 
-0:  ldr r0, [r4], #4
+0:  ldr r0, [r4]
+    adds r4, #4
     blx r0
 1:  cmp r4, r5
     bne 0b
@@ -112,7 +122,56 @@ extern fn enable_cortex_m4_fpu() {
     arm_m::instruction_synchronization_barrier()
 }
 
+/// Initializes the STM32F4's Core Coupled Memory sections, as the reset vector
+/// does `.data` and `.bss`.  See `stm32f4::ccm`.
+#[cfg(feature = "soc_family:stm32f4")]
+extern fn init_ccm() {
+    extern {
+        static _ccm_data_load: u32;
+        static mut _ccm_data: u32;
+        static mut _eccm_data: u32;
+        static mut _ccm_bss: u32;
+        static mut _eccm_bss: u32;
+    }
+
+    unsafe {
+        let mut src = &_ccm_data_load as *const u32;
+        let mut dst = &mut _ccm_data as *mut u32;
+        let end = &mut _eccm_data as *mut u32;
+        while dst < end {
+            ptr::write_volatile(dst, ptr::read(src));
+            dst = dst.offset(1);
+            src = src.offset(1);
+        }
+
+        let mut dst = &mut _ccm_bss as *mut u32;
+        let end = &mut _eccm_bss as *mut u32;
+        while dst < end {
+            ptr::write_volatile(dst, 0);
+            dst = dst.offset(1);
+        }
+    }
+}
+
 embrs_init_hooks! {
+    #[cfg(feature = "soc_family:stm32f4")]
+    pub init_hook EMBRS_CCM_INIT = init_ccm;
     #[cfg(feature = "cpu:cortex-m4f")]
     pub init_hook EMBRS_FPU_ON = enable_cortex_m4_fpu;
+    #[cfg(feature = "stack:paint")]
+    pub init_hook EMBRS_STACK_PAINT = paint_stack;
+    #[cfg(not(feature = "arch:armv6-m"))]
+    pub init_hook EMBRS_CRASH_DUMP_CHECK = check_crash_dump;
+}
+
+#[cfg(feature = "stack:paint")]
+extern fn paint_stack() {
+    arm_m::stack::paint()
+}
+
+/// Looks for a dump left by a crash before the last reset; see
+/// `arm_m::crashdump`.
+#[cfg(not(feature = "arch:armv6-m"))]
+extern fn check_crash_dump() {
+    arm_m::crashdump::validate()
 }
@@ -1,6 +1,17 @@
 //! ARMv7-M SysTick timer support.
+//!
+//! The control and status register (CSR) mixes read/write configuration bits
+//! with the read-to-clear `COUNTFLAG`, so the usual read-modify-write update
+//! would silently consume any pending wrap indication.  To avoid this, the
+//! driver caches the configuration it last wrote; `configure` and friends
+//! write the CSR without reading it, and `poll_countflag` is the only routine
+//! that reads it.
 
-use arm_m::reg::Reg;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use arm_m::reg::{mmio, Reg};
+#[cfg(not(feature = "arch:armv6-m"))]
+use clock::ClockSpeeds;
 
 #[repr(C, packed)]
 struct Registers {
@@ -10,27 +21,104 @@ struct Registers {
     calib: Reg<u32>,
 }
 
+register_layout! {
+    fn check_layout: Registers [0x10] {
+        csr @ 0x00,
+        rvr @ 0x04,
+        cvr @ 0x08,
+        calib @ 0x0C,
+    }
+}
+
 const SYS_TICK_ADDRESS : usize = 0xe000e010;
 
+/// The configuration bits most recently written to the CSR, as a `Csr`.
+static CSR_INTENT : AtomicUsize = AtomicUsize::new(0);
+
+/// Largest value the 24-bit reload register can hold.
+pub const MAX_RELOAD : u32 = (1 << 24) - 1;
+
 pub struct SysTick;
 
-impl SysTick {
-    // TODO: this peripheral, unusually for something designed by ARM, contains
-    // read-to-clear bits and R/W bits without inherent atomic updates.  So this
-    // API is probably wrong.
+/// Settings for the SysTick's read/write control bits.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct Config {
+    /// Whether the counter runs.
+    pub enable: bool,
+    /// Whether wrapping to zero raises the SysTick exception.
+    pub tickint: bool,
+    /// Clock that decrements the counter.
+    pub clksource: ClkSource,
+}
 
+impl SysTick {
     fn reg(&self) -> &'static Registers {
-        unsafe { &*(SYS_TICK_ADDRESS as *const Registers) }
+        unsafe { mmio(SYS_TICK_ADDRESS) }
     }
 
+    /// Reads the CSR directly.  Note that this clears `COUNTFLAG`; use
+    /// `config` and `poll_countflag` instead.
+    #[deprecated(since = "0.1.0",
+                 note = "clears COUNTFLAG; use config or poll_countflag")]
     pub fn read_csr(&self) -> Csr {
         Csr(self.reg().csr.get())
     }
 
+    /// Writes the CSR directly, bypassing the driver's cached configuration.
+    /// Use `configure` instead.
+    #[deprecated(since = "0.1.0", note = "use configure")]
     pub fn write_csr(&self, v: Csr) {
+        self.write_config_bits(v)
+    }
+
+    fn write_config_bits(&self, v: Csr) {
+        CSR_INTENT.store(v.0 as usize, Ordering::Relaxed);
         self.reg().csr.set(v.into())
     }
 
+    /// Applies `cfg` to the control bits, without disturbing `COUNTFLAG`.
+    pub fn configure(&self, cfg: Config) {
+        self.write_config_bits(Csr(0)
+                               .with_enable(cfg.enable)
+                               .with_tickint(cfg.tickint)
+                               .with_clksource(cfg.clksource))
+    }
+
+    /// Returns the configuration most recently applied through this driver.
+    pub fn config(&self) -> Config {
+        let csr = Csr(CSR_INTENT.load(Ordering::Relaxed) as u32);
+        Config {
+            enable: csr.get_enable(),
+            tickint: csr.get_tickint(),
+            clksource: csr.get_clksource(),
+        }
+    }
+
+    /// Starts or stops the counter, leaving other settings unchanged.
+    ///
+    /// Like `update_config`, this is not atomic with respect to other
+    /// configuration changes made from interrupt handlers.
+    pub fn set_enabled(&self, enable: bool) {
+        self.update_config(|c| Config { enable: enable, .. c })
+    }
+
+    /// Enables or disables the SysTick exception, leaving other settings
+    /// unchanged.
+    pub fn set_tickint(&self, tickint: bool) {
+        self.update_config(|c| Config { tickint: tickint, .. c })
+    }
+
+    /// Changes the configuration using `f`, starting from the cached value.
+    pub fn update_config<F: FnOnce(Config) -> Config>(&self, f: F) {
+        self.configure(f(self.config()))
+    }
+
+    /// Checks whether the counter has wrapped to zero since the last check.
+    /// This is the only operation that reads the CSR, and clears the flag.
+    pub fn poll_countflag(&self) -> bool {
+        Csr(self.reg().csr.get()).get_countflag()
+    }
+
     pub fn read_rvr(&self) -> u32 {
         self.reg().rvr.get()
     }
@@ -46,12 +134,89 @@ impl SysTick {
     pub fn write_cvr(&self, v: u32) {
         self.reg().cvr.set(v)
     }
+
+    /// Reads and decodes the (read-only) calibration register.
+    pub fn calibration(&self) -> Calibration {
+        let v = self.reg().calib.get();
+        Calibration {
+            noref: (v & (1 << 31)) != 0,
+            skew: (v & (1 << 30)) != 0,
+            tenms: v & MAX_RELOAD,
+        }
+    }
+
+    /// Stops the timer and sets it up to wrap at `hz` times per second,
+    /// choosing a clock source: the processor clock if the reload value fits,
+    /// otherwise the external reference -- unless the calibration register
+    /// reports that there isn't one.  Returns the chosen source.
+    ///
+    /// This assumes the external reference runs at the AHB clock divided by
+    /// eight, as on STM32 parts.
+    ///
+    /// The timer is left stopped and cleared; enable it (and its interrupt) to
+    /// start ticking.
+    #[cfg(not(feature = "arch:armv6-m"))]
+    pub fn configure_for_hz(&self, speeds: &ClockSpeeds, hz: u32)
+        -> Result<ClkSource, TickRateError> {
+        // Zero would divide to infinity, which has no integer value.
+        if hz == 0 {
+            return Err(TickRateError::TooSlow)
+        }
+        let cpu_cycles = (speeds.cpu / (hz as f32)) as u32;
+        let (source, cycles) = if cpu_cycles <= MAX_RELOAD + 1 {
+            (ClkSource::ProcessorClock, cpu_cycles)
+        } else if !self.calibration().noref {
+            let ref_cycles = (speeds.ahb / 8. / (hz as f32)) as u32;
+            (ClkSource::ExternalReference, ref_cycles)
+        } else {
+            return Err(TickRateError::TooSlow)
+        };
+
+        if cycles > MAX_RELOAD + 1 {
+            return Err(TickRateError::TooSlow)
+        }
+        if cycles < 2 {
+            return Err(TickRateError::TooFast)
+        }
+
+        self.configure(Config {
+            enable: false,
+            tickint: false,
+            clksource: source,
+        });
+        self.write_rvr(cycles - 1);
+        self.write_cvr(0);
+        Ok(source)
+    }
 }
 
+/// Decoded contents of the SysTick calibration register.
 #[derive(Copy, Clone)]
-pub struct Csr(u32);
+pub struct Calibration {
+    /// The implementation provides no external reference clock; only the
+    /// processor clock can be used.
+    pub noref: bool,
+    /// `tenms` is not exact, due to clock frequency.
+    pub skew: bool,
+    /// Reload value for a 10ms period at the reference clock frequency, or
+    /// zero if unknown.
+    pub tenms: u32,
+}
+
+/// Errors from `SysTick::configure_for_hz`.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum TickRateError {
+    /// The requested rate needs a longer period than the timer can count with
+    /// any available clock.
+    TooSlow,
+    /// The requested rate is too close to the clock rate.
+    TooFast,
+}
 
 #[derive(Copy, Clone)]
+pub struct Csr(u32);
+
+#[derive(Copy, Clone, Eq, PartialEq)]
 pub enum ClkSource {
     ExternalReference = 0,
     ProcessorClock = 1,
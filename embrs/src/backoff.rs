@@ -0,0 +1,92 @@
+//! Randomized retry backoff.
+//!
+//! When many identical devices hit the same failure at the same moment (say,
+//! a whole network losing its DHCP or SNTP server, a CAN bus fault knocking
+//! every node bus-off, or two bus masters colliding), retrying on a fixed
+//! schedule keeps them colliding forever.  `Backoff` produces exponentially
+//! growing, randomly jittered delays so that their retries spread out.
+//!
+//! Delays are unitless; callers interpret them as ticks, milliseconds, or
+//! whatever suits.  `Backoff` paces `net::sntp`'s queries, the CAN transmit
+//! queue's bus-off recovery, I2C retries after lost arbitration or a stuck
+//! bus (`I2c::transaction_with_recovery`), and the Modbus master's retries
+//! on a shared RS-485 line (`Master::set_retries`).  `net::dhcp` uses
+//! `jittered` for its retransmission timer.
+
+use prng::XorShift32;
+
+/// How randomness is applied to each delay.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum Jitter {
+    /// Delay is uniform in `0..=ceiling`.  Spreads retries best.
+    Full,
+    /// Delay is uniform in `ceiling/2..=ceiling`, guaranteeing some minimum
+    /// wait.
+    Equal,
+    /// No randomness: delay is exactly `ceiling`.
+    None,
+}
+
+/// Exponential backoff state for a single retrying activity.
+pub struct Backoff {
+    rng: XorShift32,
+    base: u32,
+    max: u32,
+    jitter: Jitter,
+    attempt: u32,
+}
+
+impl Backoff {
+    /// Creates backoff state whose ceiling starts at `base` and doubles on
+    /// each attempt up to `max`.
+    pub const fn new(rng: XorShift32, base: u32, max: u32, jitter: Jitter)
+        -> Backoff {
+        Backoff {
+            rng: rng,
+            base: base,
+            max: max,
+            jitter: jitter,
+            attempt: 0,
+        }
+    }
+
+    /// Number of delays produced since the last `reset`.
+    pub fn attempts(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Returns to the initial delay, typically after a successful operation.
+    pub fn reset(&mut self) {
+        self.attempt = 0
+    }
+
+    /// Computes the current ceiling without advancing.
+    pub fn ceiling(&self) -> u32 {
+        let shift = if self.attempt > 31 { 31 } else { self.attempt };
+        let scaled = if self.base.leading_zeros() < shift {
+            !0
+        } else {
+            self.base << shift
+        };
+        if scaled > self.max { self.max } else { scaled }
+    }
+
+    /// Produces the delay to wait before the next retry, and advances.
+    pub fn next_delay(&mut self) -> u32 {
+        let ceiling = self.ceiling();
+        self.attempt = self.attempt.saturating_add(1);
+        match self.jitter {
+            Jitter::Full => self.rng.next_in_range(0, ceiling),
+            Jitter::Equal => self.rng.next_in_range(ceiling / 2, ceiling),
+            Jitter::None => ceiling,
+        }
+    }
+}
+
+/// Adds up to `spread` of random jitter to a fixed `delay`, saturating rather
+/// than overflowing.  Useful when the schedule itself is fixed and only
+/// needs dithering (e.g. DHCP's 4, 8, 16... second retransmissions, each
+/// give or take a second).
+pub fn jittered(rng: &mut XorShift32, delay: u32, spread: u32) -> u32 {
+    delay.saturating_add(rng.next_in_range(0, spread))
+}
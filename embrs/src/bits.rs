@@ -11,7 +11,7 @@ use core;
 /// Error type indicating that some bits read from the hardware weren't valid
 /// for the expected type.  This usually indicates a driver bug, but can also
 /// indicate misbehaving hardware.
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub struct BadBits(pub u32);
 
 /// Result type for `BadBits`.
@@ -95,6 +95,56 @@ impl FromBitsTotal for u32 {
     }
 }
 
+/// Maps 0..255 to the two's complement `i8` with the same bits, i.e. treats
+/// bit 7 as the sign.  For narrower fields, use a `signed` accessor instead.
+impl FromBitsTotal for i8 {
+    fn from_bits_total(bits: u32) -> Self {
+        <u8 as FromBitsTotal>::from_bits_total(bits) as i8
+    }
+}
+
+/// Maps 0..65535 to the two's complement `i16` with the same bits, i.e. treats
+/// bit 15 as the sign.  For narrower fields, use a `signed` accessor instead.
+impl FromBitsTotal for i16 {
+    fn from_bits_total(bits: u32) -> Self {
+        <u16 as FromBitsTotal>::from_bits_total(bits) as i16
+    }
+}
+
+/// Construct `Self` from a signed value that has already been sign-extended
+/// from its field width, panicking if it's out of range.  Used by `signed`
+/// accessors in `bitfield_accessors!`.
+pub trait FromBitsSigned {
+    fn from_bits_signed(bits: i32) -> Self;
+}
+
+impl FromBitsSigned for i8 {
+    fn from_bits_signed(bits: i32) -> Self {
+        if bits < core::i8::MIN as i32 || bits > core::i8::MAX as i32 {
+            unreachable!()
+        } else {
+            bits as i8
+        }
+    }
+}
+
+impl FromBitsSigned for i16 {
+    fn from_bits_signed(bits: i32) -> Self {
+        if bits < core::i16::MIN as i32 || bits > core::i16::MAX as i32 {
+            unreachable!()
+        } else {
+            bits as i16
+        }
+    }
+}
+
+/// Identity map.
+impl FromBitsSigned for i32 {
+    fn from_bits_signed(bits: i32) -> Self {
+        bits
+    }
+}
+
 /// Converts `self` into a small bitwise representation.  For small integers and
 /// C-like enumerations, this is equivalent to widening casts using `as`.  It
 /// should not panic.
@@ -129,6 +179,26 @@ impl IntoBits for u32 {
     }
 }
 
+/// Signed values convert to their two's complement representation, which
+/// `bitfield_replace` truncates to the field width.
+impl IntoBits for i8 {
+    fn into_bits(self) -> u32 {
+        self as u32
+    }
+}
+
+impl IntoBits for i16 {
+    fn into_bits(self) -> u32 {
+        self as u32
+    }
+}
+
+impl IntoBits for i32 {
+    fn into_bits(self) -> u32 {
+        self as u32
+    }
+}
+
 /// Associates a wrapped bits type (e.g. the typesafe contents of a packed
 /// register) with both its underlying `Raw` type, and a function for
 /// constructing from that type.
@@ -140,6 +210,16 @@ pub trait BitsWrapper {
     fn from_raw(v: Self::Raw) -> Self;
 }
 
+/// Turns an accessor name (`get_foo`) into a field name (`foo`) for
+/// `fmt_fields`.
+pub fn field_name(getter: &'static str) -> &'static str {
+    if getter.starts_with("get_") {
+        &getter[4..]
+    } else {
+        getter
+    }
+}
+
 /// Given a value `v`, extracts bits `hi` through `lo` (inclusive).
 #[inline(always)]
 pub fn bitfield_extract(v: u32, hi: usize, lo: usize) -> u32 {
@@ -153,6 +233,23 @@ pub fn bitfield_extract(v: u32, hi: usize, lo: usize) -> u32 {
     (v >> lo) & mask
 }
 
+/// Given a value `v`, extracts bits `hi` through `lo` (inclusive) and
+/// sign-extends them, treating bit `hi` as the sign.
+#[inline(always)]
+pub fn bitfield_extract_signed(v: u32, hi: usize, lo: usize) -> i32 {
+    let width = hi - lo + 1;
+    sign_extend(bitfield_extract(v, hi, lo), width)
+}
+
+/// Sign-extends the low `width` bits of `bits`, treating bit `width - 1` as
+/// the sign.
+#[inline(always)]
+pub fn sign_extend(bits: u32, width: usize) -> i32 {
+    let unused = core::mem::size_of::<u32>() * 8 - width;
+    // Shift the field to the top, then arithmetic-shift it back down.
+    ((bits << unused) as i32) >> unused
+}
+
 /// Given a value `v`, replaces bits `hi` through `lo` (inclusive) with the
 /// same number of low-order bits from `new`.
 #[inline(always)]
@@ -166,17 +263,109 @@ pub fn bitfield_replace(orig: u32, hi: usize, lo: usize, new: u32) -> u32 {
 
     (orig & !(mask << lo)) | ((new & mask) << lo)
 }
-    
+
+/// Raw representations that bitfields can be packed into: `u32` (the common
+/// case, for registers), and for structures wider than a register, `u64` or
+/// arrays of `u32` (least significant word first).  Bit indices count up from
+/// the LSB of the first word, so bit 32 is bit 0 of the second word.
+///
+/// An individual field is at most 32 bits wide, but may straddle words.
+pub trait RawBits: Copy {
+    /// Extracts bits `hi` through `lo` (inclusive).
+    fn extract(self, hi: usize, lo: usize) -> u32;
+
+    /// Replaces bits `hi` through `lo` (inclusive) with the same number of
+    /// low-order bits from `new`.
+    fn replace(self, hi: usize, lo: usize, new: u32) -> Self;
+}
+
+/// Same as `bitfield_extract` and `bitfield_replace`.
+impl RawBits for u32 {
+    #[inline(always)]
+    fn extract(self, hi: usize, lo: usize) -> u32 {
+        bitfield_extract(self, hi, lo)
+    }
+
+    #[inline(always)]
+    fn replace(self, hi: usize, lo: usize, new: u32) -> Self {
+        bitfield_replace(self, hi, lo, new)
+    }
+}
+
+impl RawBits for u64 {
+    #[inline(always)]
+    fn extract(self, hi: usize, lo: usize) -> u32 {
+        let width = hi - lo + 1;
+        debug_assert!(width <= 32 && hi < 64);
+        let mask : u64 = (1 << width) - 1;
+        ((self >> lo) & mask) as u32
+    }
+
+    #[inline(always)]
+    fn replace(self, hi: usize, lo: usize, new: u32) -> Self {
+        let width = hi - lo + 1;
+        debug_assert!(width <= 32 && hi < 64);
+        let mask : u64 = (1 << width) - 1;
+        (self & !(mask << lo)) | (((new as u64) & mask) << lo)
+    }
+}
+
+macro_rules! raw_bits_arrays {
+    ($($n:expr),*) => {
+        $(
+            impl RawBits for [u32; $n] {
+                fn extract(self, hi: usize, lo: usize) -> u32 {
+                    let word = lo / 32;
+                    let lo = lo % 32;
+                    let hi = hi - word * 32;
+                    if hi < 32 {
+                        bitfield_extract(self[word], hi, lo)
+                    } else {
+                        // The field straddles two words.
+                        let low_bits = 32 - lo;
+                        bitfield_extract(self[word], 31, lo)
+                            | (bitfield_extract(self[word + 1], hi - 32, 0)
+                               << low_bits)
+                    }
+                }
+
+                fn replace(mut self, hi: usize, lo: usize, new: u32) -> Self {
+                    let word = lo / 32;
+                    let lo = lo % 32;
+                    let hi = hi - word * 32;
+                    if hi < 32 {
+                        self[word] = bitfield_replace(self[word], hi, lo, new);
+                    } else {
+                        let low_bits = 32 - lo;
+                        self[word] = bitfield_replace(self[word], 31, lo, new);
+                        self[word + 1] = bitfield_replace(
+                            self[word + 1], hi - 32, 0, new >> low_bits);
+                    }
+                    self
+                }
+            }
+        )*
+    };
+}
+
+raw_bits_arrays!(1, 2, 3, 4, 5, 6, 7, 8);
+
 /// Declares wrapped bits types.  A wrapped bits type declaration looks like a
 /// newtype around an integer:
 ///
 ///     pub struct MyType(pub u32);
 ///
-/// This macro automatically derives `Copy`, `Clone`, and `BitsWrapper`.
+/// To use `bitfield_accessors!` on the type, the integer must implement
+/// `RawBits`: `u32`, or for wider structures `u64` or `[u32; N]`:
+///
+///     pub struct FilterBank(pub [u32; 2]);
+///
+/// This macro automatically derives `Copy`, `Clone`, `Eq`, `PartialEq`,
+/// `Debug` (which shows the raw value), and `BitsWrapper`.
 macro_rules! bit_wrappers {
     () => {};
     ($(#[$m:meta])* pub struct $name:ident(pub $ty:ty); $($rest:tt)*) => {
-        #[derive(Copy, Clone)]
+        #[derive(Copy, Clone, Eq, PartialEq, Debug)]
         #[repr(C, packed)]
         $(#[$m])*
         pub struct $name(pub $ty);
@@ -214,7 +403,8 @@ macro_rules! bit_wrappers {
 /// - Access modifier(s) (`pub` is currently required).
 /// - Bit range, given as either a single bit index, or high and low indices
 ///   (inclusive).
-/// - Getter name and builder name, separated by a slash.
+/// - Getter name and builder name, separated by a slash, optionally followed
+///   by a slash and a map name (see below).
 /// - Rust type.
 ///
 /// The special modifier `total` says that every bit pattern that may appear in
@@ -224,6 +414,14 @@ macro_rules! bit_wrappers {
 /// (Under the hood, `total` uses a `FromBitsTotal` impl, while otherwise
 /// `FromBits` is used.)
 ///
+/// The modifier `signed` (in place of `total`) declares a two's complement
+/// field, which is sign-extended from its own width -- so a 12-bit field read
+/// into an `i16` yields -2048..2047.  The type must implement
+/// `FromBitsSigned` (`i8`, `i16`, or `i32`), and the getter returns it
+/// directly:
+///
+///     pub signed [27:16] get_offset / with_offset: i16,
+///
 /// The declarations above will produce the following methods:
 ///
 ///     pub fn get_sign(self) -> bool { ... }
@@ -234,15 +432,46 @@ macro_rules! bit_wrappers {
 ///
 ///     pub fn get_mode(self) -> BitsResult<Mode> { ... }
 ///     pub fn with_value(self, v: Mode) -> Self { ... }
+///
+/// If a map name is given, as in `get_mode / with_mode / map_mode: MyMode`,
+/// a method is also generated that replaces the field with a function of its
+/// current value (the getter's return type, so `BitsResult<MyMode>` here):
+///
+///     pub fn map_mode<F: FnOnce(BitsResult<Mode>) -> Mode>(self, f: F)
+///         -> Self { ... }
+///
+/// along with a dump of all the fields, so each field type must implement
+/// `Debug`:
+///
+///     pub fn fmt_fields<W: fmt::Write>(self, w: &mut W) -> fmt::Result
+///
+/// which writes e.g. `sign: false, value: 1234, mode: Ok(Coddle)`.  Because
+/// of this, each wrapper type can have only one `bitfield_accessors!` block.
 macro_rules! bitfield_accessors {
-    // Terminal.
+    // Terminal: generate the field dump from the accumulated getters.
     (
         @BEGIN
         input [ ]
         att [ ]
         viz [ ]
         cov [ ]
+        fields [ $($field:ident)* ]
     ) => {
+        /// Writes each field's name and decoded value to `w`, for register
+        /// dumps.
+        #[allow(unused_mut, unused_assignments)]
+        pub fn fmt_fields<W: ::core::fmt::Write>(self, w: &mut W)
+            -> ::core::fmt::Result {
+            let mut sep = "";
+            $(
+                try!(write!(w, "{}{}: {:?}",
+                            sep,
+                            $crate::bits::field_name(stringify!($field)),
+                            self.$field()));
+                sep = ", ";
+            )*
+            Ok(())
+        }
     };
 
     // Munch attributes.
@@ -252,6 +481,7 @@ macro_rules! bitfield_accessors {
         att [ $($atts:tt)* ]
         viz [ ]
         cov [ ]
+        fields [ $($fields:tt)* ]
     ) => {
         bitfield_accessors! {
             @BEGIN
@@ -259,6 +489,7 @@ macro_rules! bitfield_accessors {
             att [ #[$m] $($atts)* ]
             viz [ ]
             cov [ ]
+            fields [ $($fields)* ]
         }
     };
 
@@ -269,6 +500,7 @@ macro_rules! bitfield_accessors {
         att [ $($atts:tt)* ]
         viz [ ]
         cov [ ]
+        fields [ $($fields:tt)* ]
     ) => {
         bitfield_accessors! {
             @BEGIN
@@ -276,6 +508,7 @@ macro_rules! bitfield_accessors {
             att [ $($atts)* ]
             viz [ pub ]
             cov [ ]
+            fields [ $($fields)* ]
         }
     };
 
@@ -286,6 +519,7 @@ macro_rules! bitfield_accessors {
         att [ $($atts:tt)* ]
         viz [ $($viz:tt)* ]
         cov [ ]
+        fields [ $($fields:tt)* ]
     ) => {
         bitfield_accessors! {
             @BEGIN
@@ -293,6 +527,26 @@ macro_rules! bitfield_accessors {
             att [ $($atts)* ]
             viz [ $($viz)* ]
             cov [ total ]
+            fields [ $($fields)* ]
+        }
+    };
+
+    // Munch `signed`
+    (
+        @BEGIN
+        input [ signed $($rest:tt)* ]
+        att [ $($atts:tt)* ]
+        viz [ $($viz:tt)* ]
+        cov [ ]
+        fields [ $($fields:tt)* ]
+    ) => {
+        bitfield_accessors! {
+            @BEGIN
+            input [ $($rest)* ]
+            att [ $($atts)* ]
+            viz [ $($viz)* ]
+            cov [ signed ]
+            fields [ $($fields)* ]
         }
     };
 
@@ -303,6 +557,7 @@ macro_rules! bitfield_accessors {
         att [ $($atts:tt)* ]
         viz [ $($viz:tt)* ]
         cov [ $($cov:tt)* ]
+        fields [ $($fields:tt)* ]
     ) => {
         bitfield_accessors! {
             @COMMIT
@@ -310,6 +565,7 @@ macro_rules! bitfield_accessors {
             att [ $($atts)* ]
             viz [ $($viz)* ]
             cov [ $($cov)* ]
+            fields [ $($fields)* ]
             bits [ $bit , $bit ]
         }
     };
@@ -321,6 +577,7 @@ macro_rules! bitfield_accessors {
         att [ $($atts:tt)* ]
         viz [ $($viz:tt)* ]
         cov [ $($cov:tt)* ]
+        fields [ $($fields:tt)* ]
     ) => {
         bitfield_accessors! {
             @COMMIT
@@ -328,10 +585,58 @@ macro_rules! bitfield_accessors {
             att [ $($atts)* ]
             viz [ $($viz)* ]
             cov [ $($cov)* ]
+            fields [ $($fields)* ]
             bits [ $hi , $lo ]
         }
     };
 
+    // Munch names, including a map name, and type (non-terminal edition).
+    (
+        @COMMIT
+        input [ $get:ident / $with:ident / $map:ident: $ty:ty ,
+                $($rest:tt)* ]
+        att [ $($atts:tt)* ]
+        viz [ $($viz:tt)* ]
+        cov [ $($cov:tt)* ]
+        fields [ $($fields:tt)* ]
+        bits [ $($bits:tt)* ]
+    ) => {
+        bitfield_accessors! {
+            @FINISH
+            input [ $($rest)* ]
+            att [ $($atts)* ]
+            viz [ $($viz)* ]
+            cov [ $($cov)* ]
+            fields [ $($fields)* $get ]
+            bits [ $($bits)* ]
+            acc [ $get $with $ty ]
+            maps [ $map ]
+        }
+    };
+
+    // Munch names, including a map name, and type (terminal edition).
+    (
+        @COMMIT
+        input [ $get:ident / $with:ident / $map:ident: $ty:ty ]
+        att [ $($atts:tt)* ]
+        viz [ $($viz:tt)* ]
+        cov [ $($cov:tt)* ]
+        fields [ $($fields:tt)* ]
+        bits [ $($bits:tt)* ]
+    ) => {
+        bitfield_accessors! {
+            @FINISH
+            input [ ]
+            att [ $($atts)* ]
+            viz [ $($viz)* ]
+            cov [ $($cov)* ]
+            fields [ $($fields)* $get ]
+            bits [ $($bits)* ]
+            acc [ $get $with $ty ]
+            maps [ $map ]
+        }
+    };
+
     // Munch names and type (non-terminal edition).
     (
         @COMMIT
@@ -339,6 +644,7 @@ macro_rules! bitfield_accessors {
         att [ $($atts:tt)* ]
         viz [ $($viz:tt)* ]
         cov [ $($cov:tt)* ]
+        fields [ $($fields:tt)* ]
         bits [ $($bits:tt)* ]
     ) => {
         bitfield_accessors! {
@@ -347,8 +653,10 @@ macro_rules! bitfield_accessors {
             att [ $($atts)* ]
             viz [ $($viz)* ]
             cov [ $($cov)* ]
+            fields [ $($fields)* $get ]
             bits [ $($bits)* ]
             acc [ $get $with $ty ]
+            maps [ ]
         }
     };
 
@@ -359,6 +667,7 @@ macro_rules! bitfield_accessors {
         att [ $($atts:tt)* ]
         viz [ $($viz:tt)* ]
         cov [ $($cov:tt)* ]
+        fields [ $($fields:tt)* ]
         bits [ $($bits:tt)* ]
     ) => {
         bitfield_accessors! {
@@ -367,8 +676,10 @@ macro_rules! bitfield_accessors {
             att [ $($atts)* ]
             viz [ $($viz)* ]
             cov [ $($cov)* ]
-            bits [ $($bits:tt)* ]
+            fields [ $($fields)* $get ]
+            bits [ $($bits)* ]
             acc [ $get $with $ty ]
+            maps [ ]
         }
     };
 
@@ -380,31 +691,92 @@ macro_rules! bitfield_accessors {
         att [ $(#[$meta:meta])* ]
         viz [ $($viz:ident)* ]
         cov [ total ]
+        fields [ $($fields:tt)* ]
         bits [ $hi:expr , $lo:expr ]
         acc [ $get:ident $with:ident $ty:ty ]
+        maps [ $($map:ident)* ]
     ) => {
         $(#[$meta])*
         #[inline]
         $($viz)* fn $get(self) -> $ty {
             <$ty as $crate::bits::FromBitsTotal>::from_bits_total(
-                $crate::bits::bitfield_extract(self.0, $hi, $lo))
+                $crate::bits::RawBits::extract(self.0, $hi, $lo))
+        }
+
+        $(#[$meta])*
+        #[inline]
+        $($viz)* fn $with(self, v: $ty) -> Self {
+            $crate::bits::BitsWrapper::from_raw(
+                $crate::bits::RawBits::replace(
+                    self.0, $hi, $lo,
+                    <$ty as $crate::bits::IntoBits>::into_bits(v)))
+        }
+
+        $(
+            $(#[$meta])*
+            #[inline]
+            $($viz)* fn $map<F: FnOnce($ty) -> $ty>(self, f: F) -> Self {
+                let v = self.$get();
+                self.$with(f(v))
+            }
+        )*
+
+        bitfield_accessors! {
+            @BEGIN
+            input [ $($rest)* ]
+            att [ ]
+            viz [ ]
+            cov [ ]
+            fields [ $($fields)* ]
+        }
+    };
+
+    // Accessor generation: signed.
+    (
+        @FINISH
+        input [ $($rest:tt)* ]
+        att [ $(#[$meta:meta])* ]
+        viz [ $($viz:ident)* ]
+        cov [ signed ]
+        fields [ $($fields:tt)* ]
+        bits [ $hi:expr , $lo:expr ]
+        acc [ $get:ident $with:ident $ty:ty ]
+        maps [ $($map:ident)* ]
+    ) => {
+        $(#[$meta])*
+        #[inline]
+        $($viz)* fn $get(self) -> $ty {
+            <$ty as $crate::bits::FromBitsSigned>::from_bits_signed(
+                $crate::bits::sign_extend(
+                    $crate::bits::RawBits::extract(self.0, $hi, $lo),
+                    $hi - $lo + 1))
         }
 
         $(#[$meta])*
         #[inline]
         $($viz)* fn $with(self, v: $ty) -> Self {
             $crate::bits::BitsWrapper::from_raw(
-                $crate::bits::bitfield_replace(
+                $crate::bits::RawBits::replace(
                     self.0, $hi, $lo,
                     <$ty as $crate::bits::IntoBits>::into_bits(v)))
         }
 
+        $(
+            $(#[$meta])*
+            #[inline]
+            $($viz)* fn $map<F: FnOnce($ty) -> $ty>(self, f: F) -> Self {
+                let v = self.$get();
+                self.$with(f(v))
+            }
+        )*
+
         bitfield_accessors! {
             @BEGIN
             input [ $($rest)* ]
             att [ ]
             viz [ ]
             cov [ ]
+            fields [ $($fields)* ]
         }
     };
 
@@ -415,31 +787,43 @@ macro_rules! bitfield_accessors {
         att [ $(#[$meta:meta])* ]
         viz [ $($viz:ident)* ]
         cov [ ]
+        fields [ $($fields:tt)* ]
         bits [ $hi:expr , $lo:expr ]
         acc [ $get:ident $with:ident $ty:ty ]
+        maps [ $($map:ident)* ]
     ) => {
         $(#[$meta])*
         #[inline]
         $($viz)* fn $get(self) -> $crate::bits::BitsResult<$ty> {
             <$ty as $crate::bits::FromBits>::from_bits(
-                $crate::bits::bitfield_extract(self.0, $hi, $lo))
+                $crate::bits::RawBits::extract(self.0, $hi, $lo))
         }
 
         $(#[$meta])*
         #[inline]
         $($viz)* fn $with(self, v: $ty) -> Self {
             $crate::bits::BitsWrapper::from_raw(
-                $crate::bits::bitfield_replace(
+                $crate::bits::RawBits::replace(
                     self.0, $hi, $lo,
                     <$ty as $crate::bits::IntoBits>::into_bits(v)))
         }
 
+        $(
+            $(#[$meta])*
+            #[inline]
+            $($viz)* fn $map<F: FnOnce($crate::bits::BitsResult<$ty>) -> $ty>(self, f: F) -> Self {
+                let v = self.$get();
+                self.$with(f(v))
+            }
+        )*
+
         bitfield_accessors! {
             @BEGIN
             input [ $($rest)* ]
             att [ ]
             viz [ ]
             cov [ ]
+            fields [ $($fields)* ]
         }
     };
 
@@ -453,6 +837,7 @@ macro_rules! bitfield_accessors {
             att [ ]
             viz [ ]
             cov [ ]
+            fields [ ]
         }
     };
 
@@ -485,7 +870,7 @@ macro_rules! bit_enums {
         }
         $($rest:tt)*
     ) => {
-        #[derive(Copy, Clone, Eq, PartialEq)]
+        #[derive(Copy, Clone, Eq, PartialEq, Debug)]
         $(#[$m])*
         pub enum $name {
             $($e_name = $e_val),+
@@ -518,3 +903,118 @@ macro_rules! bit_enums {
         bit_enums!{$($rest)*}
     };
 }
+
+/// Generates accessors that combine an enable flag and a value field (usually a
+/// prescaler selection) into an `Option`.  The enable and value fields must
+/// already have accessors, typically from `bitfield_accessors!`:
+///
+///     en_option_accessors! {
+///         enable get_hpre_en / with_hpre_en
+///         value get_hpre_div / with_hpre_div : AhbPrescaler
+///         as get_hpre / with_hpre;
+///     }
+macro_rules! en_option_accessors {
+    () => {};
+    (
+        $(#[$m:meta])*
+        enable $get_en:ident / $with_en:ident
+        value $get_div:ident / $with_div:ident : $ty:ty
+        as $get_opt:ident / $with_opt:ident;
+
+        $($rest:tt)*
+    ) => {
+        $(#[$m])*
+        pub fn $get_opt(self) -> Option<$ty> {
+            if self.$get_en() {
+                Some(self.$get_div())
+            } else {
+                None
+            }
+        }
+
+        $(#[$m])*
+        pub fn $with_opt(self, v: Option<$ty>) -> Self {
+            if let Some(wrapped) = v {
+                self.$with_div(wrapped).$with_en(true)
+            } else {
+                self.$with_en(false)
+            }
+        }
+
+        en_option_accessors!{$($rest)*}
+    };
+}
+
+/// Applies several bitfield builders in one read-modify-write, through a
+/// driver's `update_` method.  This:
+///
+///     update_fields!(FLASH => update_acr: with_latency(5), with_prften(true));
+///
+/// is shorthand for:
+///
+///     FLASH.update_acr(|v| v.with_latency(5).with_prften(true));
+macro_rules! update_fields {
+    ($target:expr => $update:ident : $($with:ident($($arg:expr),*)),+) => {
+        $target.$update(|v| v$(.$with($($arg),*))+)
+    };
+}
+
+#[cfg(all(test, feature = "host-test"))]
+mod tests {
+    use super::*;
+
+    bit_wrappers! {
+        pub struct Test(pub u32);
+    }
+
+    bit_enums! {
+        pub bit_enum Mode {
+            Idle = 0b00,
+            Run = 0b01,
+            Stop = 0b10,
+        }
+    }
+
+    impl Test {
+        bitfield_accessors! {
+            pub total [31] get_sign / with_sign: bool,
+            pub total [30:15] get_value / with_value: u32,
+            pub signed [14:4] get_offset / with_offset: i16,
+            pub       [3:2] get_mode / with_mode / map_mode: Mode,
+            pub total [0] get_flag / with_flag: bool,
+        }
+    }
+
+    #[test]
+    fn fields_land_in_place() {
+        assert_eq!(Test(0).with_sign(true).0, 1 << 31);
+        assert_eq!(Test(0).with_value(0xFFFF).0, 0xFFFF << 15);
+        assert_eq!(Test(0).with_mode(Mode::Stop).0, 0b10 << 2);
+        assert_eq!(Test(0).with_flag(true).0, 1);
+    }
+
+    #[test]
+    fn builders_preserve_other_fields() {
+        let t = Test(!0).with_value(0);
+        assert_eq!(t.0, !(0xFFFF << 15));
+        assert!(t.get_sign());
+        assert!(t.get_flag());
+        assert_eq!(Test(0x1234_5678).with_value(0x5A5A).get_value(), 0x5A5A);
+    }
+
+    #[test]
+    fn signed_fields_sign_extend() {
+        assert_eq!(Test(0).with_offset(-1).get_offset(), -1);
+        assert_eq!(Test(0).with_offset(-1024).get_offset(), -1024);
+        assert_eq!(Test(0).with_offset(1023).get_offset(), 1023);
+        assert_eq!(Test(0).with_offset(-1).0, 0x7FF << 4);
+    }
+
+    #[test]
+    fn partial_fields_report_bad_bits() {
+        assert_eq!(Test(0b01 << 2).get_mode(), Ok(Mode::Run));
+        assert_eq!(Test(0b11 << 2).get_mode(), Err(BadBits(0b11)));
+        let t = Test(0b11 << 2).map_mode(|m| m.unwrap_or(Mode::Idle));
+        assert_eq!(t.get_mode(), Ok(Mode::Idle));
+    }
+}
@@ -0,0 +1,54 @@
+//! Bootloader support.
+//!
+//! A bootloader is a small program, at the start of Flash, that picks one of
+//! several application images (see `slots`) and starts it with
+//! `start_image`.  Keeping images elsewhere lets an application replace
+//! itself -- over a serial port, say -- without risking a device that can't
+//! boot.
+
+pub mod slots;
+
+#[cfg(not(feature = "host-test"))]
+use core::ptr;
+
+#[cfg(not(feature = "host-test"))]
+use arm_m;
+#[cfg(not(feature = "host-test"))]
+use arm_m::nvic::NVIC;
+#[cfg(not(feature = "host-test"))]
+use arm_m::scb::SCB;
+#[cfg(not(feature = "host-test"))]
+use arm_m::sys_tick::{Config, SYS_TICK};
+
+/// Starts the image whose vector table is at `vector_table`, much as the
+/// processor would at reset: the stack pointer and vector table are taken
+/// from the image, SysTick and every interrupt are disabled, and control
+/// passes to the image's reset handler.  Nothing on the current stack
+/// survives.
+///
+/// # Safety
+///
+/// There had better be an image there.  Peripherals the bootloader set up
+/// keep running, and should be reset first if the image doesn't expect
+/// them; likewise any clock configuration.
+#[cfg(not(feature = "host-test"))]
+pub unsafe fn start_image(vector_table: usize) -> ! {
+    arm_m::set_primask(true);
+
+    SYS_TICK.update_config(|c| Config { enable: false, tickint: false, .. c });
+    SCB.clear_pending_systick();
+    NVIC.disable_all();
+
+    let sp = ptr::read_volatile(vector_table as *const u32);
+    let entry = ptr::read_volatile((vector_table + 4) as *const u32);
+    SCB.write_vtor(vector_table as u32);
+    arm_m::data_synchronization_barrier();
+    arm_m::instruction_synchronization_barrier();
+
+    // Nothing can be pending now, so this is the state at reset.
+    arm_m::set_primask(false);
+    asm!("msr MSP, $0\n\tbx $1"
+         :: "r"(sp), "r"(entry)
+         :: "volatile");
+    loop {}
+}
@@ -0,0 +1,315 @@
+//! A/B application image slots, with rollback.
+//!
+//! `Slots` manages two application images, each in its own `NorFlash`
+//! region, described by one metadata sector each in a third region.  The
+//! metadata records the image's version, length, and CRC-32, whether it has
+//! been confirmed, and how many times it has been tried.  A typical layout
+//! on an STM32F4 with 1MiB of Flash:
+//!
+//! - sectors 0-1: the bootloader;
+//! - sectors 2-3: metadata for slots A and B;
+//! - sectors 5-7: slot A;
+//! - sectors 8-10: slot B.
+//!
+//! Updating goes like this:
+//!
+//! 1. The running application calls `begin_install` on the slot it isn't
+//!    running from (`install_target` names it), writes the new image with
+//!    `write_image`, calls `finish_install` to record its version and CRC,
+//!    and resets.
+//! 2. The bootloader calls `select`, which picks the usable image with the
+//!    highest version -- the new one -- and counts an attempt against it,
+//!    since it hasn't been confirmed.
+//! 3. Once the new image is satisfied that it works -- it has started up,
+//!    and perhaps checked in with a server -- it calls `confirm`.
+//!
+//! If the new image crashes, hangs (and is reset by the watchdog), or is
+//! just never confirmed, each boot counts another attempt against it, and
+//! once it has used up its attempts `select` falls back to the old image.
+//! An image can also be abandoned explicitly with `reject`.
+//!
+//! Metadata changes only ever clear bits, so they need no erase, and the
+//! header is written last, so an interrupted install leaves a slot that
+//! simply isn't used.
+
+use core::cmp;
+
+use crc::{crc32, crc32_continue};
+use hal::NorFlash;
+
+/// Magic number opening a slot's metadata.
+const META_MAGIC : u32 = 0x494d_4721;
+/// Offset of the header (magic, version, length, image CRC, header CRC).
+const HEADER_OFFSET : usize = 0x00;
+/// Bytes of header covered by the header CRC.
+const HEADER_CRC_LEN : usize = 16;
+/// Offset of the confirmation word, cleared when the image is confirmed.
+const CONFIRMED_OFFSET : usize = 0x20;
+/// Offset of the rejection word, cleared when the image is rejected.
+const REJECTED_OFFSET : usize = 0x24;
+/// Offset of the attempt counter: one byte cleared per attempt.
+const ATTEMPTS_OFFSET : usize = 0x40;
+/// The most attempts the counter can record.
+pub const MAX_ATTEMPTS : u32 = 64;
+
+/// Names the two slots.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Slot {
+    A = 0,
+    B = 1,
+}
+
+impl Slot {
+    /// Returns the other slot.
+    pub fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+}
+
+/// Ways that a `Slots` operation can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SlotError<E> {
+    /// The Flash reported an error.
+    Flash(E),
+    /// The image is bigger than the slot.
+    TooLong,
+}
+
+/// What the metadata says about an intact image.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct ImageInfo {
+    /// The version given to `finish_install`.
+    pub version: u32,
+    /// Length in bytes.
+    pub length: usize,
+    /// Whether the image has called `confirm`.
+    pub confirmed: bool,
+    /// Whether the image has been `reject`ed.
+    pub rejected: bool,
+    /// Number of times the bootloader has started the image unconfirmed.
+    pub attempts: u32,
+}
+
+fn le32(raw: &[u8]) -> u32 {
+    raw[0] as u32
+        | (raw[1] as u32) << 8
+        | (raw[2] as u32) << 16
+        | (raw[3] as u32) << 24
+}
+
+fn put_le32(raw: &mut [u8], v: u32) {
+    raw[0] = v as u8;
+    raw[1] = (v >> 8) as u8;
+    raw[2] = (v >> 16) as u8;
+    raw[3] = (v >> 24) as u8;
+}
+
+/// Two image slots and their metadata.
+pub struct Slots<F: NorFlash> {
+    /// Metadata: sector 0 for slot A, sector 1 for slot B.
+    meta: F,
+    images: [F; 2],
+    /// Attempts an unconfirmed image gets before it's passed over.
+    max_attempts: u32,
+}
+
+impl<F: NorFlash> Slots<F> {
+    /// Manages images in `a` and `b`, with metadata in the first two sectors
+    /// of `meta`.  An unconfirmed image is started at most `max_attempts`
+    /// times.
+    ///
+    /// # Panics
+    ///
+    /// If `meta` has fewer than two sectors, or `max_attempts` is zero or
+    /// more than `MAX_ATTEMPTS`.
+    pub fn new(meta: F, a: F, b: F, max_attempts: u32) -> Slots<F> {
+        assert!(meta.sector_count() >= 2);
+        assert!(max_attempts > 0 && max_attempts <= MAX_ATTEMPTS);
+        Slots {
+            meta: meta,
+            images: [a, b],
+            max_attempts: max_attempts,
+        }
+    }
+
+    /// Describes the image in `slot`, if there is an intact one: its
+    /// metadata must be complete and its CRC must match.  Checking the CRC
+    /// means reading the whole image, which takes a while.
+    pub fn info(&self, slot: Slot)
+        -> Result<Option<ImageInfo>, SlotError<F::Error>> {
+        let mut header = [0; HEADER_CRC_LEN + 4];
+        try!(self.read_meta(slot, HEADER_OFFSET, &mut header));
+        if le32(&header[0..]) != META_MAGIC
+            || le32(&header[HEADER_CRC_LEN..])
+                != crc32(&header[..HEADER_CRC_LEN]) {
+            return Ok(None)
+        }
+
+        let version = le32(&header[4..]);
+        let length = le32(&header[8..]) as usize;
+        if try!(self.image_crc(slot, length)) != Some(le32(&header[12..])) {
+            return Ok(None)
+        }
+
+        let mut words = [0; 8];
+        try!(self.read_meta(slot, CONFIRMED_OFFSET, &mut words));
+        let mut counter = [0; MAX_ATTEMPTS as usize];
+        try!(self.read_meta(slot, ATTEMPTS_OFFSET, &mut counter));
+        let attempts = counter.iter().filter(|&&b| b != 0xFF).count();
+
+        Ok(Some(ImageInfo {
+            version: version,
+            length: length,
+            confirmed: le32(&words[0..]) != !0,
+            rejected: le32(&words[4..]) != !0,
+            attempts: attempts as u32,
+        }))
+    }
+
+    /// Picks the image to boot: the usable one with the highest version,
+    /// preferring slot A on a tie.  An image is usable if it's intact, it
+    /// hasn't been rejected, and it has been confirmed or has attempts left.
+    /// If the chosen image is unconfirmed, this counts an attempt against
+    /// it.  Returns `None` if neither image is usable.
+    pub fn select(&self) -> Result<Option<Slot>, SlotError<F::Error>> {
+        let choice = try!(self.preferred());
+        if let Some((slot, info)) = choice {
+            if !info.confirmed {
+                let offset = ATTEMPTS_OFFSET + info.attempts as usize;
+                try!(self.program_meta(slot, offset, &[0]));
+            }
+        }
+        Ok(choice.map(|(slot, _)| slot))
+    }
+
+    /// Returns the slot that the next update should go in: the one `select`
+    /// wouldn't pick.  If the running image is unconfirmed, confirm or
+    /// reject it first, or this may name the slot holding the fallback.
+    pub fn install_target(&self) -> Result<Slot, SlotError<F::Error>> {
+        Ok(match try!(self.preferred()) {
+            Some((slot, _)) => slot.other(),
+            None => Slot::A,
+        })
+    }
+
+    /// Marks the image in `slot` as confirmed, so that it's no longer
+    /// subject to the attempt limit.
+    pub fn confirm(&self, slot: Slot) -> Result<(), SlotError<F::Error>> {
+        self.program_meta(slot, CONFIRMED_OFFSET, &[0; 4])
+    }
+
+    /// Marks the image in `slot` as rejected, so that it's never picked
+    /// again.
+    pub fn reject(&self, slot: Slot) -> Result<(), SlotError<F::Error>> {
+        self.program_meta(slot, REJECTED_OFFSET, &[0; 4])
+    }
+
+    /// Erases `slot` -- metadata first, so that it's never picked while
+    /// partly written -- ready for `write_image`.
+    pub fn begin_install(&self, slot: Slot)
+        -> Result<(), SlotError<F::Error>> {
+        try!(self.meta.erase_sector(slot as u32).map_err(SlotError::Flash));
+        let image = &self.images[slot as usize];
+        for sector in 0..image.sector_count() {
+            try!(image.erase_sector(sector).map_err(SlotError::Flash))
+        }
+        Ok(())
+    }
+
+    /// Programs `data` at `offset` in the image in `slot`.
+    pub fn write_image(&self, slot: Slot, offset: usize, data: &[u8])
+        -> Result<(), SlotError<F::Error>> {
+        if offset + data.len() > self.capacity(slot) {
+            return Err(SlotError::TooLong)
+        }
+        self.images[slot as usize].program(offset, data)
+            .map_err(SlotError::Flash)
+    }
+
+    /// Completes an install by computing the CRC of the first `length`
+    /// bytes of `slot` and writing the metadata, after which `select` will
+    /// consider it.
+    pub fn finish_install(&self, slot: Slot, version: u32, length: usize)
+        -> Result<(), SlotError<F::Error>> {
+        let crc = match try!(self.image_crc(slot, length)) {
+            Some(crc) => crc,
+            None => return Err(SlotError::TooLong),
+        };
+
+        let mut header = [0; HEADER_CRC_LEN + 4];
+        put_le32(&mut header[0..], META_MAGIC);
+        put_le32(&mut header[4..], version);
+        put_le32(&mut header[8..], length as u32);
+        put_le32(&mut header[12..], crc);
+        let header_crc = crc32(&header[..HEADER_CRC_LEN]);
+        put_le32(&mut header[HEADER_CRC_LEN..], header_crc);
+
+        // Everything but the magic first, so that a header with good magic
+        // is complete.
+        try!(self.program_meta(slot, HEADER_OFFSET + 4, &header[4..]));
+        self.program_meta(slot, HEADER_OFFSET, &header[..4])
+    }
+
+    /// Returns the size of `slot`, in bytes.
+    pub fn capacity(&self, slot: Slot) -> usize {
+        let image = &self.images[slot as usize];
+        image.sector_size() * image.sector_count() as usize
+    }
+
+    fn preferred(&self)
+        -> Result<Option<(Slot, ImageInfo)>, SlotError<F::Error>> {
+        let mut best: Option<(Slot, ImageInfo)> = None;
+        for &slot in [Slot::A, Slot::B].iter() {
+            let info = match try!(self.info(slot)) {
+                Some(info) => info,
+                None => continue,
+            };
+            let usable = !info.rejected
+                && (info.confirmed || info.attempts < self.max_attempts);
+            if !usable {
+                continue
+            }
+            match best {
+                Some((_, b)) if b.version >= info.version => (),
+                _ => best = Some((slot, info)),
+            }
+        }
+        Ok(best)
+    }
+
+    /// Computes the CRC of the first `length` bytes of `slot`, or returns
+    /// `None` if the slot isn't that big.
+    fn image_crc(&self, slot: Slot, length: usize)
+        -> Result<Option<u32>, SlotError<F::Error>> {
+        if length > self.capacity(slot) {
+            return Ok(None)
+        }
+        let image = &self.images[slot as usize];
+        let mut crc = 0;
+        let mut offset = 0;
+        let mut chunk = [0; 64];
+        while offset < length {
+            let n = cmp::min(length - offset, chunk.len());
+            try!(image.read(offset, &mut chunk[..n])
+                 .map_err(SlotError::Flash));
+            crc = crc32_continue(crc, &chunk[..n]);
+            offset += n;
+        }
+        Ok(Some(crc))
+    }
+
+    fn read_meta(&self, slot: Slot, offset: usize, buf: &mut [u8])
+        -> Result<(), SlotError<F::Error>> {
+        let address = slot as usize * self.meta.sector_size() + offset;
+        self.meta.read(address, buf).map_err(SlotError::Flash)
+    }
+
+    fn program_meta(&self, slot: Slot, offset: usize, data: &[u8])
+        -> Result<(), SlotError<F::Error>> {
+        let address = slot as usize * self.meta.sector_size() + offset;
+        self.meta.program(address, data).map_err(SlotError::Flash)
+    }
+}
@@ -0,0 +1,45 @@
+//! Global record of the system clock speeds.
+//!
+//! Many drivers need to know how fast their peripheral is clocked (to compute
+//! baud rates, prescalers, timeouts).  Rather than threading a `ClockSpeeds`
+//! through every call, the application can `freeze` the speeds once, right
+//! after configuring the clock tree; drivers then offer variants of their
+//! clock-dependent operations that consult `frozen()`.
+//!
+//! The explicit-parameter variants remain available for applications that
+//! switch between clock configurations at runtime, which must *not* freeze.
+
+pub use stm32f4::rcc::ClockSpeeds;
+
+use sync::OnceInit;
+
+static SPEEDS : OnceInit<ClockSpeeds> = OnceInit::new();
+
+/// Records `speeds` as the system's clock speeds for the rest of its run.
+///
+/// # Panics
+///
+/// If speeds have already been frozen.
+pub fn freeze(speeds: ClockSpeeds) {
+    if SPEEDS.set(speeds).is_err() {
+        panic!("clock speeds frozen twice")
+    }
+}
+
+/// Gets the frozen clock speeds, or `None` if `freeze` has not (yet) been
+/// called.
+pub fn try_frozen() -> Option<&'static ClockSpeeds> {
+    SPEEDS.try_get()
+}
+
+/// Gets the frozen clock speeds.
+///
+/// # Panics
+///
+/// If `freeze` has not been called.
+pub fn frozen() -> &'static ClockSpeeds {
+    match try_frozen() {
+        Some(s) => s,
+        None => panic!("clock speeds not frozen"),
+    }
+}
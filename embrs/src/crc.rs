@@ -0,0 +1,106 @@
+//! Cyclic redundancy checks.
+//!
+//! These are computed bitwise: slow, but small, and free of tables.
+
+/// Computes the CRC-32 of `bytes`, as used by Ethernet and zlib.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    crc32_continue(0, bytes)
+}
+
+/// Extends `crc`, the CRC-32 of some earlier bytes, over `bytes`, so that
+/// data can be checked a piece at a time: `crc32_continue(crc32(a), b)` is
+/// the CRC-32 of `a` followed by `b`.
+pub fn crc32_continue(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Computes the CRC-16 used by XMODEM and YMODEM: the CCITT polynomial,
+/// 0x1021, applied most significant bit first, starting from zero.
+pub fn crc16_xmodem(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in bytes {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Computes the CRC-16/MCRF4XX of `bytes`: the CCITT polynomial applied
+/// least significant bit first, starting from 0xFFFF.  MAVLink uses this,
+/// calling it X.25.
+pub fn crc16_mcrf4xx(bytes: &[u8]) -> u16 {
+    crc16_mcrf4xx_continue(0xFFFF, bytes)
+}
+
+/// Extends `crc`, the CRC-16/MCRF4XX of some earlier bytes, over `bytes`.
+/// Unlike `crc32_continue`, there is no final inversion to undo, so this
+/// also serves to start from an arbitrary value.
+pub fn crc16_mcrf4xx_continue(crc: u16, bytes: &[u8]) -> u16 {
+    let mut crc = crc;
+    for &b in bytes {
+        crc ^= b as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x8408
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// Computes the CRC-32/MPEG-2 of `bytes`: the CRC-32 polynomial applied
+/// most significant bit first, starting from 0xFFFFFFFF, without the final
+/// inversion.  This is what the STM32 CRC unit computes.
+pub fn crc32_mpeg2(bytes: &[u8]) -> u32 {
+    crc32_mpeg2_continue(0xFFFF_FFFF, bytes)
+}
+
+/// Extends `crc`, the CRC-32/MPEG-2 of some earlier bytes, over `bytes`.
+pub fn crc32_mpeg2_continue(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = crc;
+    for &b in bytes {
+        crc ^= (b as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Computes the CRC-16 used by Modbus: the reversed form of polynomial
+/// 0x8005, applied least significant bit first, starting from 0xFFFF.  It
+/// is sent low byte first.
+pub fn crc16_modbus(bytes: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for &b in bytes {
+        crc ^= b as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
@@ -0,0 +1,339 @@
+//! The STM32F4 Discovery board's accelerometer.
+//!
+//! Depending on the board revision, this is an ST LIS3DSH (MB997C and later)
+//! or the older LIS302DL (MB997B).  They sit on the same pins -- SPI1 on PA5-7,
+//! chip select on PE3, and INT1 on PE0 -- and answer the same `WHO_AM_I`
+//! register, so `Accel::probe` works out which is fitted and the rest of the
+//! driver papers over the differences as far as it can.
+//!
+//!     let cs = Pins { port: gpioe, pins: P3 };
+//!     let mut accel = try!(Accel::probe(&SPI1, cs));
+//!     try!(accel.set_scale(Scale::G2));
+//!     try!(accel.set_data_rate(DataRate::Hz100));
+//!     let mg = try!(accel.read_mg());
+//!
+//! Both parts use SPI mode 3, at up to 10 MHz.  The chip select must be
+//! configured as an output, and idle high, before probing.
+//!
+//! # Data ready interrupt
+//!
+//! `enable_data_ready_interrupt` makes the part raise INT1 whenever a new
+//! sample is ready.  On the F4, `configure_data_ready_exti` turns an edge on
+//! that pin into an EXTI interrupt (EXTI0, for PE0); its handler should clear
+//! the pending line and read the sample, which releases INT1.
+
+use hal::{DigitalOutput, SpiTransfer};
+
+#[cfg(not(feature = "arch:armv6-m"))]
+use stm32f4::exti::{Trigger, EXTI};
+#[cfg(not(feature = "arch:armv6-m"))]
+use stm32f4::gpio::{self, Pins};
+
+/// Register shared by both parts.
+const WHO_AM_I : u8 = 0x0F;
+/// Its value on each part.
+const LIS3DSH_ID : u8 = 0x3F;
+const LIS302DL_ID : u8 = 0x3B;
+
+/// Also shared: bit 3 (ZYXDA) reports a new sample on all axes.
+const STATUS : u8 = 0x27;
+const STATUS_ZYXDA : u8 = 1 << 3;
+
+/// SPI address byte flags.
+const SPI_READ : u8 = 0x80;
+/// LIS302DL only: auto-increment the address.  (The LIS3DSH does this by
+/// itself once `CTRL6_ADD_INC` is set.)
+const SPI_MULTI : u8 = 0x40;
+
+// LIS3DSH registers.
+const LIS3DSH_CTRL3 : u8 = 0x23;
+const LIS3DSH_CTRL4 : u8 = 0x20;
+const LIS3DSH_CTRL5 : u8 = 0x24;
+const LIS3DSH_CTRL6 : u8 = 0x25;
+const LIS3DSH_OUT_X_L : u8 = 0x28;
+
+/// CTRL4: block data update, and all three axes.
+const CTRL4_BDU_XYZ : u8 = 0b1111;
+/// CTRL3: data ready on INT1, active high.
+const CTRL3_DR_INT1 : u8 = (1 << 7) | (1 << 6) | (1 << 3);
+const CTRL6_ADD_INC : u8 = 1 << 4;
+
+// LIS302DL registers.
+const LIS302DL_CTRL1 : u8 = 0x20;
+const LIS302DL_CTRL3 : u8 = 0x22;
+const LIS302DL_OUT_X : u8 = 0x29;
+
+/// CTRL1: 400 Hz rather than 100.
+const CTRL1_DR : u8 = 1 << 7;
+/// CTRL1: powered up.
+const CTRL1_PD : u8 = 1 << 6;
+/// CTRL1: 9.2 g rather than 2.3 g full scale.
+const CTRL1_FS : u8 = 1 << 5;
+const CTRL1_XYZ : u8 = 0b111;
+/// CTRL3: data ready on INT1.
+const CTRL3_I1_DATA_READY : u8 = 0b100;
+
+/// Which part is fitted.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Variant {
+    Lis3dsh,
+    Lis302dl,
+}
+
+/// Output data rates.  The LIS302DL only supports `PowerDown`, `Hz100`, and
+/// `Hz400`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum DataRate {
+    PowerDown,
+    Hz3_125,
+    Hz6_25,
+    Hz12_5,
+    Hz25,
+    Hz50,
+    Hz100,
+    Hz400,
+    Hz800,
+    Hz1600,
+}
+
+/// Full-scale ranges.  The LIS302DL only has two, which are near enough:
+/// `G2` selects its 2.3 g range, and `G8` its 9.2 g.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Scale {
+    G2,
+    G4,
+    G6,
+    G8,
+    G16,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum AccelError<E> {
+    /// The SPI transfer failed.
+    Spi(E),
+    /// `WHO_AM_I` returned something other than a known part's ID.
+    UnknownDevice(u8),
+    /// The fitted part can't do what was asked.
+    Unsupported,
+}
+
+/// An accelerometer on SPI bus `S`, selected by `C`.
+pub struct Accel<'a, S: SpiTransfer + 'a, C: DigitalOutput> {
+    spi: &'a S,
+    cs: C,
+    variant: Variant,
+    scale: Scale,
+}
+
+impl<'a, S: SpiTransfer, C: DigitalOutput> Accel<'a, S, C> {
+    /// Identifies the accelerometer on `spi` and `cs`, leaving it powered
+    /// down at its default (2 g) scale.
+    pub fn probe(spi: &'a S, cs: C) -> Result<Self, AccelError<S::Error>> {
+        let mut accel = Accel {
+            spi: spi,
+            cs: cs,
+            // Either will do for reading WHO_AM_I.
+            variant: Variant::Lis3dsh,
+            scale: Scale::G2,
+        };
+        accel.variant = match try!(accel.read_reg(WHO_AM_I)) {
+            LIS3DSH_ID => Variant::Lis3dsh,
+            LIS302DL_ID => Variant::Lis302dl,
+            id => return Err(AccelError::UnknownDevice(id)),
+        };
+        match accel.variant {
+            Variant::Lis3dsh => {
+                try!(accel.write_reg(LIS3DSH_CTRL6, CTRL6_ADD_INC));
+                try!(accel.write_reg(LIS3DSH_CTRL5, 0));
+                try!(accel.write_reg(LIS3DSH_CTRL4, CTRL4_BDU_XYZ));
+            },
+            Variant::Lis302dl => {
+                try!(accel.write_reg(LIS302DL_CTRL1, CTRL1_XYZ));
+            },
+        }
+        Ok(accel)
+    }
+
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    pub fn scale(&self) -> Scale {
+        self.scale
+    }
+
+    /// Sets the output data rate, or powers the part down.
+    pub fn set_data_rate(&mut self, rate: DataRate)
+        -> Result<(), AccelError<S::Error>> {
+        match self.variant {
+            Variant::Lis3dsh => {
+                let odr = match rate {
+                    DataRate::PowerDown => 0,
+                    DataRate::Hz3_125 => 1,
+                    DataRate::Hz6_25 => 2,
+                    DataRate::Hz12_5 => 3,
+                    DataRate::Hz25 => 4,
+                    DataRate::Hz50 => 5,
+                    DataRate::Hz100 => 6,
+                    DataRate::Hz400 => 7,
+                    DataRate::Hz800 => 8,
+                    DataRate::Hz1600 => 9,
+                };
+                self.write_reg(LIS3DSH_CTRL4, (odr << 4) | CTRL4_BDU_XYZ)
+            },
+            Variant::Lis302dl => {
+                let bits = match rate {
+                    DataRate::PowerDown => 0,
+                    DataRate::Hz100 => CTRL1_PD,
+                    DataRate::Hz400 => CTRL1_PD | CTRL1_DR,
+                    _ => return Err(AccelError::Unsupported),
+                };
+                let v = try!(self.read_reg(LIS302DL_CTRL1));
+                self.write_reg(LIS302DL_CTRL1,
+                               (v & !(CTRL1_PD | CTRL1_DR)) | bits)
+            },
+        }
+    }
+
+    /// Sets the full-scale range.
+    pub fn set_scale(&mut self, scale: Scale)
+        -> Result<(), AccelError<S::Error>> {
+        match self.variant {
+            Variant::Lis3dsh => {
+                let fscale = match scale {
+                    Scale::G2 => 0,
+                    Scale::G4 => 1,
+                    Scale::G6 => 2,
+                    Scale::G8 => 3,
+                    Scale::G16 => 4,
+                };
+                try!(self.write_reg(LIS3DSH_CTRL5, fscale << 3))
+            },
+            Variant::Lis302dl => {
+                let fs = match scale {
+                    Scale::G2 => 0,
+                    Scale::G8 => CTRL1_FS,
+                    _ => return Err(AccelError::Unsupported),
+                };
+                let v = try!(self.read_reg(LIS302DL_CTRL1));
+                try!(self.write_reg(LIS302DL_CTRL1, (v & !CTRL1_FS) | fs))
+            },
+        }
+        self.scale = scale;
+        Ok(())
+    }
+
+    /// Checks whether a new sample is waiting.
+    pub fn is_data_ready(&self) -> Result<bool, AccelError<S::Error>> {
+        Ok(try!(self.read_reg(STATUS)) & STATUS_ZYXDA != 0)
+    }
+
+    /// Makes the part drive INT1 high while a new sample is waiting.
+    pub fn enable_data_ready_interrupt(&self)
+        -> Result<(), AccelError<S::Error>> {
+        match self.variant {
+            Variant::Lis3dsh => self.write_reg(LIS3DSH_CTRL3, CTRL3_DR_INT1),
+            Variant::Lis302dl =>
+                self.write_reg(LIS302DL_CTRL3, CTRL3_I1_DATA_READY),
+        }
+    }
+
+    pub fn disable_data_ready_interrupt(&self)
+        -> Result<(), AccelError<S::Error>> {
+        match self.variant {
+            Variant::Lis3dsh => self.write_reg(LIS3DSH_CTRL3, 0),
+            Variant::Lis302dl => self.write_reg(LIS302DL_CTRL3, 0),
+        }
+    }
+
+    /// Reads the latest sample as raw X, Y, and Z counts.  LIS302DL samples
+    /// are eight bits, so they range only from -128 to 127.
+    pub fn read_raw(&self) -> Result<[i16; 3], AccelError<S::Error>> {
+        match self.variant {
+            Variant::Lis3dsh => {
+                let mut b = [0; 6];
+                try!(self.read_regs(LIS3DSH_OUT_X_L, &mut b));
+                Ok([
+                    (b[0] as u16 | (b[1] as u16) << 8) as i16,
+                    (b[2] as u16 | (b[3] as u16) << 8) as i16,
+                    (b[4] as u16 | (b[5] as u16) << 8) as i16,
+                ])
+            },
+            Variant::Lis302dl => {
+                // X, Y, and Z are interleaved with unused registers.
+                let mut b = [0; 5];
+                try!(self.read_regs(LIS302DL_OUT_X, &mut b));
+                Ok([b[0] as i8 as i16, b[2] as i8 as i16, b[4] as i8 as i16])
+            },
+        }
+    }
+
+    /// Reads the latest sample in milli-g.
+    pub fn read_mg(&self) -> Result<[i32; 3], AccelError<S::Error>> {
+        let raw = try!(self.read_raw());
+        let ug = self.micro_g_per_count();
+        Ok([
+            raw[0] as i32 * ug / 1000,
+            raw[1] as i32 * ug / 1000,
+            raw[2] as i32 * ug / 1000,
+        ])
+    }
+
+    /// Sensitivity at the current scale, from the datasheets.
+    fn micro_g_per_count(&self) -> i32 {
+        match (self.variant, self.scale) {
+            (Variant::Lis3dsh, Scale::G2) => 60,
+            (Variant::Lis3dsh, Scale::G4) => 120,
+            (Variant::Lis3dsh, Scale::G6) => 180,
+            (Variant::Lis3dsh, Scale::G8) => 240,
+            (Variant::Lis3dsh, Scale::G16) => 730,
+            (Variant::Lis302dl, Scale::G8) => 72000,
+            (Variant::Lis302dl, _) => 18000,
+        }
+    }
+
+    fn read_reg(&self, reg: u8) -> Result<u8, AccelError<S::Error>> {
+        let mut b = [0];
+        try!(self.read_regs(reg, &mut b));
+        Ok(b[0])
+    }
+
+    fn write_reg(&self, reg: u8, value: u8)
+        -> Result<(), AccelError<S::Error>> {
+        self.cs.set_low();
+        let r = self.spi.write(&[reg, value]);
+        self.cs.set_high();
+        r.map_err(AccelError::Spi)
+    }
+
+    /// Reads registers starting at `reg` to fill `buf`, which can hold at
+    /// most six.
+    fn read_regs(&self, reg: u8, buf: &mut [u8])
+        -> Result<(), AccelError<S::Error>> {
+        let n = buf.len();
+        let mut frame = [0; 7];
+        frame[0] = reg | SPI_READ;
+        if n > 1 && self.variant == Variant::Lis302dl {
+            frame[0] |= SPI_MULTI
+        }
+        self.cs.set_low();
+        let r = self.spi.transfer(&mut frame[..n + 1]);
+        self.cs.set_high();
+        try!(r.map_err(AccelError::Spi));
+        buf.copy_from_slice(&frame[1..n + 1]);
+        Ok(())
+    }
+}
+
+/// Configures `int` (the accelerometer's INT1, PE0 on the Discovery) as an
+/// input raising an EXTI interrupt when a sample is ready, and enables the
+/// interrupt in the EXTI.  The SYSCFG clock must be on.  Returns the EXTI
+/// line mask.
+#[cfg(not(feature = "arch:armv6-m"))]
+pub fn configure_data_ready_exti(int: &Pins) -> u32 {
+    (int.port)().set_mode(int.pins, gpio::Mode::Input);
+    let lines = EXTI.configure_pins(int, Trigger::Rising);
+    EXTI.clear_pending(lines);
+    EXTI.enable_interrupt(lines);
+    lines
+}
@@ -0,0 +1,252 @@
+//! Debounced push buttons.
+//!
+//! Mechanical contacts bounce for a few milliseconds when they open or close,
+//! so a raw pin read (or a raw edge interrupt) sees a burst of transitions for
+//! each press.  `Button` samples an input periodically -- from SysTick or a
+//! timer interrupt, say -- and reports a change only once the input has held
+//! steady for the debounce time.  A button held down past the long-press time
+//! also reports a `LongPress`, once, before its eventual `Released`.
+//!
+//!     static BUTTON: Button<Pins> = Button::new(
+//!         Pins { port: gpioa, pins: P0 },
+//!         ButtonConfig { active_low: true, .. button::DEFAULT_CONFIG });
+//!
+//!     extern "C" fn sys_tick_isr() {
+//!         MILLIS.fetch_add(1, Ordering::Relaxed);
+//!         BUTTON.sample(MILLIS.load(Ordering::Relaxed) as u32);
+//!     }
+//!
+//! Events are delivered to a handler registered with `set_handler`, which is
+//! called from `sample` (and thus usually in interrupt context), or, if there
+//! isn't one, queued for the application to collect with `take_event`.
+//!
+//! To avoid sampling continuously, an EXTI interrupt on the pin (see
+//! `stm32f4::exti`) can call `wake` on each edge; `is_idle` then says when the
+//! periodic sampling can be turned off again.
+
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use arm_m;
+use hal::DigitalInput;
+
+/// Number of events `take_event` can hold before further events are dropped.
+pub const QUEUE_LEN: usize = 4;
+
+/// Things that happen to a button.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ButtonEvent {
+    Pressed,
+    Released,
+    /// The button has been held for the long-press time.
+    LongPress,
+}
+
+impl ButtonEvent {
+    fn to_usize(self) -> usize {
+        match self {
+            ButtonEvent::Pressed => 0,
+            ButtonEvent::Released => 1,
+            ButtonEvent::LongPress => 2,
+        }
+    }
+
+    fn from_usize(v: usize) -> ButtonEvent {
+        match v {
+            0 => ButtonEvent::Pressed,
+            1 => ButtonEvent::Released,
+            _ => ButtonEvent::LongPress,
+        }
+    }
+}
+
+/// Function called from `sample` for each event.
+pub type ButtonHandler = fn(ButtonEvent);
+
+/// Button timing and polarity.
+#[derive(Copy, Clone, Debug)]
+pub struct ButtonConfig {
+    /// The input reads low while the button is pressed (as with a pull-up and
+    /// a switch to ground).
+    pub active_low: bool,
+    /// How long, in milliseconds, the input must hold steady to be believed.
+    pub debounce_ms: u32,
+    /// How long, in milliseconds, the button must be held to produce
+    /// `LongPress`; zero disables long presses.
+    pub long_press_ms: u32,
+}
+
+/// Active high, with 20ms of debouncing and a one second long press.
+pub const DEFAULT_CONFIG: ButtonConfig = ButtonConfig {
+    active_low: false,
+    debounce_ms: 20,
+    long_press_ms: 1000,
+};
+
+impl Default for ButtonConfig {
+    fn default() -> ButtonConfig {
+        DEFAULT_CONFIG
+    }
+}
+
+/// Bits of `Button::state`.
+const STABLE_PRESSED : usize = 1 << 0;
+const RAW_PRESSED : usize = 1 << 1;
+const LONG_SENT : usize = 1 << 2;
+const AWAKE : usize = 1 << 3;
+
+/// A debounced button on a digital input.
+pub struct Button<I: DigitalInput> {
+    input: I,
+    config: ButtonConfig,
+    state: AtomicUsize,
+    /// Time of the last raw change, in milliseconds.
+    changed_at: AtomicUsize,
+    /// Time the current press was accepted, in milliseconds.
+    pressed_at: AtomicUsize,
+    handler: AtomicUsize,
+    queue: [AtomicUsize; QUEUE_LEN],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+impl<I: DigitalInput> Button<I> {
+    /// Creates a button on `input`, which must already be configured as an
+    /// input (with any pull it needs).  The button is assumed released.
+    pub const fn new(input: I, config: ButtonConfig) -> Button<I> {
+        Button {
+            input: input,
+            config: config,
+            state: ATOMIC_USIZE_INIT,
+            changed_at: ATOMIC_USIZE_INIT,
+            pressed_at: ATOMIC_USIZE_INIT,
+            handler: ATOMIC_USIZE_INIT,
+            queue: [
+                ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+            ],
+            head: ATOMIC_USIZE_INIT,
+            tail: ATOMIC_USIZE_INIT,
+            dropped: ATOMIC_USIZE_INIT,
+        }
+    }
+
+    /// Registers `handler` to receive events as they happen, instead of
+    /// queueing them.
+    pub fn set_handler(&self, handler: ButtonHandler) {
+        self.handler.store(handler as usize, Ordering::Release)
+    }
+
+    fn raw_pressed(&self) -> bool {
+        self.input.is_high() != self.config.active_low
+    }
+
+    /// Samples the input at time `now_ms` (a free-running millisecond count,
+    /// which may wrap), delivering any resulting events.  This should be
+    /// called every few milliseconds, from one context only.
+    pub fn sample(&self, now_ms: u32) {
+        let observed = self.state.load(Ordering::Relaxed);
+        let mut state = observed;
+        let raw = self.raw_pressed();
+
+        if raw != ((state & RAW_PRESSED) != 0) {
+            // Still bouncing (or just started): restart the clock.
+            state ^= RAW_PRESSED;
+            self.changed_at.store(now_ms as usize, Ordering::Relaxed);
+        } else {
+            let stable = (state & STABLE_PRESSED) != 0;
+            let since_change = now_ms.wrapping_sub(
+                self.changed_at.load(Ordering::Relaxed) as u32);
+
+            if raw != stable && since_change >= self.config.debounce_ms {
+                if raw {
+                    state = (state | STABLE_PRESSED) & !LONG_SENT;
+                    self.pressed_at.store(now_ms as usize, Ordering::Relaxed);
+                    self.deliver(ButtonEvent::Pressed)
+                } else {
+                    state &= !STABLE_PRESSED;
+                    self.deliver(ButtonEvent::Released)
+                }
+            } else if stable && (state & LONG_SENT) == 0
+                    && self.config.long_press_ms != 0 {
+                let held = now_ms.wrapping_sub(
+                    self.pressed_at.load(Ordering::Relaxed) as u32);
+                if held >= self.config.long_press_ms {
+                    state |= LONG_SENT;
+                    self.deliver(ButtonEvent::LongPress)
+                }
+            }
+
+            if (state & (RAW_PRESSED | STABLE_PRESSED)) == 0 {
+                // Settled and released: nothing more can happen until the
+                // next edge.
+                state &= !AWAKE;
+            }
+        }
+
+        // `wake` may have set AWAKE since we looked; if so, keep it, since
+        // the edge it reports hasn't been sampled yet.
+        arm_m::without_interrupts(|| {
+            let woke = self.state.load(Ordering::Relaxed) & !observed & AWAKE;
+            self.state.store(state | woke, Ordering::Relaxed)
+        })
+    }
+
+    /// Notes that the input has changed, e.g. from an EXTI interrupt, so that
+    /// `is_idle` reports `false` until the change has been dealt with.
+    pub fn wake(&self) {
+        arm_m::without_interrupts(|| {
+            let s = self.state.load(Ordering::Relaxed);
+            self.state.store(s | AWAKE, Ordering::Relaxed)
+        })
+    }
+
+    /// Checks whether the button is released and settled, with no `wake`
+    /// since, so that sampling can stop until the next edge.
+    pub fn is_idle(&self) -> bool {
+        let s = self.state.load(Ordering::Relaxed);
+        (s & (AWAKE | STABLE_PRESSED | RAW_PRESSED)) == 0
+    }
+
+    /// Checks whether the button is (in debounced terms) held down.
+    pub fn is_pressed(&self) -> bool {
+        (self.state.load(Ordering::Relaxed) & STABLE_PRESSED) != 0
+    }
+
+    fn deliver(&self, event: ButtonEvent) {
+        let h = self.handler.load(Ordering::Acquire);
+        if h != 0 {
+            let handler: ButtonHandler = unsafe { mem::transmute(h) };
+            handler(event);
+            return
+        }
+
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) >= QUEUE_LEN {
+            let d = self.dropped.load(Ordering::Relaxed);
+            self.dropped.store(d.wrapping_add(1), Ordering::Relaxed);
+            return
+        }
+        self.queue[head % QUEUE_LEN].store(event.to_usize(), Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release)
+    }
+
+    /// Takes the oldest queued event, if any.
+    pub fn take_event(&self) -> Option<ButtonEvent> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if self.head.load(Ordering::Acquire) == tail {
+            return None
+        }
+        let e = self.queue[tail % QUEUE_LEN].load(Ordering::Relaxed);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(ButtonEvent::from_usize(e))
+    }
+
+    /// Number of events dropped because the queue was full (modulo the word
+    /// size).
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,358 @@
+//! The STM32F4 Discovery board's CS43L22 audio DAC and headphone amplifier.
+//!
+//! The CS43L22 takes control commands over I2C (I2C1 on PB6/PB9 on the
+//! Discovery, at address `ADDRESS`) and audio over I2S (I2S3: MCK on PC7,
+//! SCK on PC10, SD on PC12, WS on PA4), and is held in reset by PD4.
+//!
+//! Bringing it up follows the datasheet's recommended sequence:
+//!
+//! 1. `Cs43l22::init` resets the part, checks its ID, loads the required
+//!    initialization settings, and sets it up for 16-bit I2S on the
+//!    headphone outputs, leaving it powered down.
+//! 2. The application starts the I2S master clock -- configure SPI3 with
+//!    `configure_i2s`, with `mclk_output`, and start a `Player`.
+//! 3. `Cs43l22::power_up` powers the part up.
+//!
+//! After that, volume, muting and the beep generator can be changed at any
+//! time.  `power_down` reverses step 3, after which the clock can be
+//! stopped.
+//!
+//! # Streaming
+//!
+//! `Player` (F4 only) keeps the I2S fed from a pair of buffers by
+//! double-buffered DMA.  `Player::play` copies interleaved left/right
+//! samples into whichever buffer is idle, waiting for one to become free as
+//! needed, so an application can produce audio in whatever chunks suit it.
+//! If it falls behind, the player outputs silence rather than stale data,
+//! and counts the underrun.
+
+use hal::{DelayUs, DigitalOutput, I2cBus};
+
+#[cfg(not(feature = "arch:armv6-m"))]
+use core::cmp;
+#[cfg(not(feature = "arch:armv6-m"))]
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+#[cfg(not(feature = "arch:armv6-m"))]
+use stm32f4::dma::{Direction, DoubleBuffer, Request};
+#[cfg(not(feature = "arch:armv6-m"))]
+use stm32f4::dma::double_buffer::{DoubleBufferError, Event};
+#[cfg(not(feature = "arch:armv6-m"))]
+use stm32f4::spi::Spi;
+
+/// The part's seven-bit I2C address, with AD0 low as on the Discovery.
+pub const ADDRESS : u8 = 0x4A;
+
+const ID : u8 = 0x01;
+const POWER_CTL1 : u8 = 0x02;
+const POWER_CTL2 : u8 = 0x04;
+const CLOCKING_CTL : u8 = 0x05;
+const INTERFACE_CTL1 : u8 = 0x06;
+const PLAYBACK_CTL2 : u8 = 0x0F;
+const BEEP_FREQ_ON : u8 = 0x1C;
+const BEEP_VOL_OFF : u8 = 0x1D;
+const BEEP_TONE_CFG : u8 = 0x1E;
+const MASTER_A_VOL : u8 = 0x20;
+const MASTER_B_VOL : u8 = 0x21;
+
+/// The chip ID, in the top five bits of `ID`.
+const CHIP_ID : u8 = 0b11100;
+
+/// POWER_CTL1 values.
+const POWER_DOWN : u8 = 0x01;
+const POWER_UP : u8 = 0x9E;
+/// POWER_CTL2: headphones always on, speakers always off.
+const HEADPHONES_ONLY : u8 = 0xAF;
+/// CLOCKING_CTL: detect the MCLK/LRCK ratio automatically.
+const AUTO_CLOCK : u8 = 0x80;
+/// INTERFACE_CTL1: slave, I2S, 16-bit samples.
+const I2S_16BIT : u8 = 0x04;
+/// PLAYBACK_CTL2: mute headphone channels B and A.
+const HP_MUTE : u8 = 0xC0;
+/// BEEP_TONE_CFG: the beep mode field.
+const BEEP_MODE_MASK : u8 = 0xC0;
+
+/// The master volume range, in half-decibel steps.
+pub const MIN_VOLUME : i16 = -204;
+pub const MAX_VOLUME : i16 = 24;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum CodecError<E> {
+    /// The I2C transfer failed.
+    I2c(E),
+    /// The part at `ADDRESS` isn't a CS43L22; this is what it reported.
+    UnknownDevice(u8),
+}
+
+/// How the beep generator sounds.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum BeepMode {
+    Off = 0,
+    /// One beep, for the on time.
+    Single = 1,
+    /// Beeps separated by the off time, until turned off.
+    Multiple = 2,
+    /// A continuous tone.
+    Continuous = 3,
+}
+
+/// Beep generator settings.  See the datasheet for the pitches and times
+/// each code selects.
+#[derive(Copy, Clone, Debug)]
+pub struct Beep {
+    /// Pitch code, 0 (about C4, 260.87 Hz) to 15 (about C7, 2181.82 Hz).
+    pub pitch: u8,
+    /// On time code, 0 (about 86 ms) to 15 (about 5.2 s).
+    pub on_time: u8,
+    /// Off time code, 0 (about 1.2 s) to 7 (about 10.8 s).
+    pub off_time: u8,
+    /// Volume, in decibels, from -56 to +6 in steps of 2.
+    pub volume_db: i8,
+}
+
+/// A CS43L22 on I2C bus `I`, with its reset line on `R`.
+pub struct Cs43l22<'a, I: I2cBus + 'a, R: DigitalOutput> {
+    i2c: &'a I,
+    reset: R,
+}
+
+impl<'a, I: I2cBus, R: DigitalOutput> Cs43l22<'a, I, R> {
+    /// Creates a driver for the part at `ADDRESS` on `i2c`.  `reset` must be
+    /// configured as an output.
+    pub const fn new(i2c: &'a I, reset: R) -> Cs43l22<'a, I, R> {
+        Cs43l22 {
+            i2c: i2c,
+            reset: reset,
+        }
+    }
+
+    /// Resets the part and sets it up for 16-bit I2S audio on the headphone
+    /// outputs, leaving it powered down.  Its supplies must be stable.
+    pub fn init<D: DelayUs>(&self, delay: &D)
+        -> Result<(), CodecError<I::Error>> {
+        self.reset.set_low();
+        delay.delay_ms(1);
+        self.reset.set_high();
+        delay.delay_ms(1);
+
+        let id = try!(self.read_reg(ID));
+        if id >> 3 != CHIP_ID {
+            return Err(CodecError::UnknownDevice(id))
+        }
+
+        try!(self.write_reg(POWER_CTL1, POWER_DOWN));
+        // The "required initialization settings" from the datasheet, which
+        // poke undocumented registers.
+        try!(self.write_reg(0x00, 0x99));
+        try!(self.write_reg(0x47, 0x80));
+        let v = try!(self.read_reg(0x32));
+        try!(self.write_reg(0x32, v | 0x80));
+        try!(self.write_reg(0x32, v & !0x80));
+        try!(self.write_reg(0x00, 0x00));
+
+        try!(self.write_reg(POWER_CTL2, HEADPHONES_ONLY));
+        try!(self.write_reg(CLOCKING_CTL, AUTO_CLOCK));
+        self.write_reg(INTERFACE_CTL1, I2S_16BIT)
+    }
+
+    /// Powers the part up.  The I2S master clock must already be running.
+    pub fn power_up(&self) -> Result<(), CodecError<I::Error>> {
+        self.write_reg(POWER_CTL1, POWER_UP)
+    }
+
+    /// Mutes and powers the part down.  The I2S clock may be stopped
+    /// afterwards.
+    pub fn power_down(&self) -> Result<(), CodecError<I::Error>> {
+        try!(self.set_mute(true));
+        self.write_reg(POWER_CTL1, POWER_DOWN)
+    }
+
+    /// Sets the master volume of both channels, in half-decibel steps from
+    /// `MIN_VOLUME` (-102 dB) to `MAX_VOLUME` (+12 dB); values outside that
+    /// range are clamped.
+    pub fn set_volume(&self, half_db: i16)
+        -> Result<(), CodecError<I::Error>> {
+        let v = if half_db < MIN_VOLUME {
+            MIN_VOLUME
+        } else if half_db > MAX_VOLUME {
+            MAX_VOLUME
+        } else {
+            half_db
+        };
+        // Two's complement, in a byte.
+        try!(self.write_reg(MASTER_A_VOL, v as u8));
+        self.write_reg(MASTER_B_VOL, v as u8)
+    }
+
+    /// Mutes (or unmutes) the headphone outputs.
+    pub fn set_mute(&self, muted: bool) -> Result<(), CodecError<I::Error>> {
+        let v = try!(self.read_reg(PLAYBACK_CTL2));
+        self.write_reg(PLAYBACK_CTL2,
+                       if muted { v | HP_MUTE } else { v & !HP_MUTE })
+    }
+
+    /// Configures the beep generator and sets it going in `mode` (or stops
+    /// it, for `BeepMode::Off`).  The beep is mixed with any audio playing,
+    /// and needs the part powered up.
+    pub fn beep(&self, beep: &Beep, mode: BeepMode)
+        -> Result<(), CodecError<I::Error>> {
+        let db = if beep.volume_db < -56 {
+            -56
+        } else if beep.volume_db > 6 {
+            6
+        } else {
+            beep.volume_db
+        };
+        // The volume code wraps: 0 is -6 dB, 6 is +6 dB, and 7 is -56 dB.
+        let vol = (((db + 6) >> 1) as u8) & 0x1F;
+
+        try!(self.write_reg(BEEP_FREQ_ON,
+                            (beep.pitch & 0xF) << 4 | (beep.on_time & 0xF)));
+        try!(self.write_reg(BEEP_VOL_OFF, (beep.off_time & 0x7) << 5 | vol));
+        self.set_beep_mode(mode)
+    }
+
+    /// Changes the beep generator's mode without touching its settings.
+    pub fn set_beep_mode(&self, mode: BeepMode)
+        -> Result<(), CodecError<I::Error>> {
+        let v = try!(self.read_reg(BEEP_TONE_CFG));
+        self.write_reg(BEEP_TONE_CFG,
+                       (v & !BEEP_MODE_MASK) | (mode as u8) << 6)
+    }
+
+    fn read_reg(&self, reg: u8) -> Result<u8, CodecError<I::Error>> {
+        let mut v = [0];
+        try!(self.i2c.write_read(ADDRESS, &[reg], &mut v)
+             .map_err(CodecError::I2c));
+        Ok(v[0])
+    }
+
+    fn write_reg(&self, reg: u8, value: u8)
+        -> Result<(), CodecError<I::Error>> {
+        self.i2c.write(ADDRESS, &[reg, value]).map_err(CodecError::I2c)
+    }
+}
+
+/// Streams audio to an I2S by double-buffered DMA.
+#[cfg(not(feature = "arch:armv6-m"))]
+pub struct Player {
+    i2s: &'static Spi,
+    dma: DoubleBuffer<u16>,
+    /// Set when the buffer the stream isn't using has been played, and may
+    /// be refilled.
+    free: AtomicBool,
+    /// Samples written to the idle buffer so far.
+    fill: AtomicUsize,
+    underruns: AtomicUsize,
+}
+
+#[cfg(not(feature = "arch:armv6-m"))]
+impl Player {
+    /// Creates a player for `i2s`, which must be configured as an I2S
+    /// master transmitter with 16-bit data, using the DMA request `tx` --
+    /// `Request::Spi3TxS5` or `Request::Spi3TxS7` for I2S3 on the Discovery.
+    pub const fn new(i2s: &'static Spi, tx: Request) -> Player {
+        Player {
+            i2s: i2s,
+            dma: DoubleBuffer::new(tx),
+            free: AtomicBool::new(false),
+            fill: AtomicUsize::new(0),
+            underruns: AtomicUsize::new(0),
+        }
+    }
+
+    /// Starts the I2S, playing silence, using `buf0` and `buf1` (which must
+    /// be the same length) as the DMA buffers.  Each holds interleaved
+    /// left/right samples, so a length of 2N buffers N frames.
+    ///
+    /// The DMA stream's interrupt must be routed to a handler that calls
+    /// `handle_interrupt`.
+    pub fn start(&self, buf0: &'static mut [u16], buf1: &'static mut [u16])
+        -> Result<(), DoubleBufferError> {
+        for s in buf0.iter_mut().chain(buf1.iter_mut()) {
+            *s = 0
+        }
+        self.fill.store(0, Ordering::Relaxed);
+        self.free.store(true, Ordering::Release);
+        try!(self.dma.start(self.i2s.dr_address(),
+                            Direction::MemoryToPeripheral,
+                            buf0, buf1));
+        self.i2s.update_cr2(|v| v.with_txdmaen(true));
+        self.i2s.set_i2s_enabled(true);
+        Ok(())
+    }
+
+    /// Stops the I2S and the stream.
+    pub fn stop(&self) {
+        self.i2s.set_i2s_enabled(false);
+        self.i2s.update_cr2(|v| v.with_txdmaen(false));
+        self.dma.stop();
+        self.free.store(false, Ordering::Release)
+    }
+
+    /// Queues interleaved left/right `samples` for playback, waiting for
+    /// buffer space as needed.  Returns once the last of them is buffered,
+    /// or early if the player is stopped.
+    ///
+    /// Each buffer is filled with interrupts masked (by `with_idle_buffer`),
+    /// so that the stream's interrupt can't swap buffers mid-copy.
+    pub fn play(&self, samples: &[i16]) {
+        let mut rest = samples;
+        while !rest.is_empty() {
+            if !self.free.load(Ordering::Acquire) {
+                continue
+            }
+            let mut taken = 0;
+            let r = self.dma.with_idle_buffer(|buf| {
+                let fill = self.fill.load(Ordering::Relaxed);
+                let n = cmp::min(buf.len() - fill, rest.len());
+                for (d, &s) in buf[fill..fill + n].iter_mut().zip(rest) {
+                    *d = s as u16
+                }
+                if fill + n == buf.len() {
+                    self.fill.store(0, Ordering::Relaxed);
+                    self.free.store(false, Ordering::Release)
+                } else {
+                    self.fill.store(fill + n, Ordering::Relaxed)
+                }
+                taken = n
+            });
+            match r {
+                // On overrun, the samples are playing, if late; the
+                // interrupt will count the underrun.
+                Ok(()) | Err(DoubleBufferError::Overrun) =>
+                    rest = &rest[taken..],
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// Gets the number of times the stream finished a buffer before the
+    /// other had been completely refilled.
+    pub fn underruns(&self) -> usize {
+        self.underruns.load(Ordering::Relaxed)
+    }
+
+    /// To be called from the DMA stream's interrupt handler.  Returns
+    /// `false` if the stream has stopped on an error.
+    pub fn handle_interrupt(&self) -> bool {
+        let mut ok = true;
+        let _ = self.dma.handle_interrupt(|event, buf| match event {
+            Event::TransferComplete(_) => {
+                // `buf` has just been played; silence it, so that if the
+                // application doesn't refill it in time, that's what plays.
+                for s in buf.iter_mut() {
+                    *s = 0
+                }
+                if self.free.load(Ordering::Acquire) {
+                    let _ = self.underruns.fetch_add(1, Ordering::Relaxed);
+                }
+                self.fill.store(0, Ordering::Relaxed);
+                self.free.store(true, Ordering::Release)
+            },
+            Event::HalfTransfer(_) => (),
+            Event::Error => ok = false,
+        });
+        ok
+    }
+}
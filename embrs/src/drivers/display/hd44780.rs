@@ -0,0 +1,238 @@
+//! HD44780 character LCDs, in four-bit mode.
+//!
+//! The display needs six or seven wires: RS (register select), RW
+//! (read/write), E (enable), and the top four data lines, D4-D7; D0-D3 are
+//! left unconnected.  Reading the busy flag needs RW and bidirectional data
+//! pins, so the data lines are `DigitalInOut`s.  Most modules run at 5V, so
+//! the data pins should be 5V-tolerant (on the STM32F4, most are) and run
+//! open-drain with pull-ups, or the module run at 3.3V.
+//!
+//!     let lcd = Hd44780::new(&rs, &rw, &e, [&d4, &d5, &d6, &d7], &delay);
+//!     try!(lcd.init(2));
+//!     try!(lcd.set_cursor(0, 1));
+//!     try!(lcd.print("Hello"));
+//!
+//! Between commands, the driver polls the busy flag rather than waiting out
+//! worst-case execution times, which keeps updates quick.
+
+use hal::{DelayUs, DigitalInOut, DigitalOutput};
+
+const CMD_CLEAR : u8 = 0x01;
+const CMD_HOME : u8 = 0x02;
+const CMD_ENTRY_MODE : u8 = 0x04;
+const ENTRY_INCREMENT : u8 = 0x02;
+const CMD_DISPLAY_CONTROL : u8 = 0x08;
+const DISPLAY_ON : u8 = 0x04;
+const DISPLAY_CURSOR : u8 = 0x02;
+const DISPLAY_BLINK : u8 = 0x01;
+const CMD_FUNCTION_SET : u8 = 0x20;
+const FUNCTION_TWO_LINES : u8 = 0x08;
+const CMD_SET_CGRAM : u8 = 0x40;
+const CMD_SET_DDRAM : u8 = 0x80;
+
+/// The busy flag, in the top bit of a status read.
+const BUSY : u8 = 0x80;
+/// Times the busy flag is polled before giving up.  Clearing the display,
+/// the slowest command, takes about 1.5ms.
+const BUSY_POLLS : u32 = 2000;
+
+/// DDRAM address of the start of each row.  Four-row displays split two
+/// long lines across rows 0 and 2, and 1 and 3.
+const ROW_OFFSETS : [u8; 4] = [0x00, 0x40, 0x14, 0x54];
+
+/// Ways that display operations can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum LcdError {
+    /// The busy flag never cleared: the display is missing, or RW isn't
+    /// connected.
+    Busy,
+    /// A row or column is off the edge of the display.
+    OutOfRange,
+}
+
+/// An HD44780 LCD on six GPIOs plus RW.
+pub struct Hd44780<'a, O, D, T>
+    where O: DigitalOutput + 'a, D: DigitalInOut + 'a, T: DelayUs + 'a {
+    rs: &'a O,
+    rw: &'a O,
+    e: &'a O,
+    /// D4-D7, in that order.
+    data: [&'a D; 4],
+    delay: &'a T,
+}
+
+impl<'a, O, D, T> Hd44780<'a, O, D, T>
+    where O: DigitalOutput + 'a, D: DigitalInOut + 'a, T: DelayUs + 'a {
+    pub fn new(rs: &'a O, rw: &'a O, e: &'a O, data: [&'a D; 4],
+               delay: &'a T)
+        -> Hd44780<'a, O, D, T> {
+        Hd44780 {
+            rs: rs,
+            rw: rw,
+            e: e,
+            data: data,
+            delay: delay,
+        }
+    }
+
+    /// Puts the controller in four-bit mode, whatever state it was in, and
+    /// sets it up for `lines` (1 or 2; four-row displays count as two)
+    /// with the display on, the cursor hidden, and a blank screen.
+    pub fn init(&self, lines: u8) -> Result<(), LcdError> {
+        self.e.set_low();
+        self.rs.set_low();
+        self.rw.set_low();
+        for d in &self.data {
+            d.set_output_enabled(true)
+        }
+        // Power-on settling time.
+        self.delay.delay_ms(50);
+
+        // The busy flag can't be read until the interface width is known,
+        // so this part runs on worst-case delays.  Three "8-bit" function
+        // sets bring the controller to a known state from either width; the
+        // fourth switches to four bits.
+        self.write_nibble(0x3);
+        self.delay.delay_us(4500);
+        self.write_nibble(0x3);
+        self.delay.delay_us(150);
+        self.write_nibble(0x3);
+        self.delay.delay_us(150);
+        self.write_nibble(0x2);
+        self.delay.delay_us(150);
+
+        let function = if lines > 1 { FUNCTION_TWO_LINES } else { 0 };
+        try!(self.command(CMD_FUNCTION_SET | function));
+        try!(self.command(CMD_DISPLAY_CONTROL));
+        try!(self.clear());
+        try!(self.command(CMD_ENTRY_MODE | ENTRY_INCREMENT));
+        self.set_display(true, false, false)
+    }
+
+    /// Blanks the display and homes the cursor.
+    pub fn clear(&self) -> Result<(), LcdError> {
+        self.command(CMD_CLEAR)
+    }
+
+    /// Moves the cursor to the top left, and undoes any scrolling.
+    pub fn home(&self) -> Result<(), LcdError> {
+        self.command(CMD_HOME)
+    }
+
+    /// Turns the display, the underline cursor, and the blinking block
+    /// cursor on or off.
+    pub fn set_display(&self, on: bool, cursor: bool, blink: bool)
+        -> Result<(), LcdError> {
+        let mut v = CMD_DISPLAY_CONTROL;
+        if on { v |= DISPLAY_ON }
+        if cursor { v |= DISPLAY_CURSOR }
+        if blink { v |= DISPLAY_BLINK }
+        self.command(v)
+    }
+
+    /// Moves the cursor to `col` on `row` (both from 0).
+    pub fn set_cursor(&self, col: u8, row: u8) -> Result<(), LcdError> {
+        if row as usize >= ROW_OFFSETS.len() || col >= 40 {
+            return Err(LcdError::OutOfRange)
+        }
+        self.command(CMD_SET_DDRAM | (ROW_OFFSETS[row as usize] + col))
+    }
+
+    /// Writes one character code at the cursor.  Codes 0-7 are the custom
+    /// characters; 0x20-0x7D are mostly ASCII.
+    pub fn write_char(&self, code: u8) -> Result<(), LcdError> {
+        try!(self.wait_ready());
+        self.rs.set_high();
+        self.write_byte(code);
+        Ok(())
+    }
+
+    /// Writes `s` at the cursor.  Characters outside ASCII are shown as
+    /// '?'.
+    pub fn print(&self, s: &str) -> Result<(), LcdError> {
+        for c in s.chars() {
+            let code = if (c as u32) < 0x80 { c as u8 } else { b'?' };
+            try!(self.write_char(code))
+        }
+        Ok(())
+    }
+
+    /// Defines custom character `index` (0-7) from eight rows of five
+    /// pixels, top first, in the low bits of each byte.  This moves the
+    /// cursor; set it again afterwards.
+    pub fn define_char(&self, index: u8, rows: &[u8; 8])
+        -> Result<(), LcdError> {
+        if index > 7 {
+            return Err(LcdError::OutOfRange)
+        }
+        try!(self.command(CMD_SET_CGRAM | index << 3));
+        for &row in rows {
+            try!(self.write_char(row & 0x1F))
+        }
+        Ok(())
+    }
+
+    /// Sends a command, once the controller is ready for it.
+    fn command(&self, v: u8) -> Result<(), LcdError> {
+        try!(self.wait_ready());
+        self.rs.set_low();
+        self.write_byte(v);
+        Ok(())
+    }
+
+    /// Polls the busy flag until it clears.
+    fn wait_ready(&self) -> Result<(), LcdError> {
+        self.rs.set_low();
+        self.rw.set_high();
+        for d in &self.data {
+            d.set_output_enabled(false)
+        }
+        let mut result = Err(LcdError::Busy);
+        for _ in 0..BUSY_POLLS {
+            // Both nibbles have to be read, though only the first has the
+            // flag.
+            let status = self.read_nibble() << 4 | self.read_nibble();
+            if status & BUSY == 0 {
+                result = Ok(());
+                break
+            }
+            self.delay.delay_us(1)
+        }
+        self.rw.set_low();
+        for d in &self.data {
+            d.set_output_enabled(true)
+        }
+        result
+    }
+
+    fn write_byte(&self, v: u8) {
+        self.write_nibble(v >> 4);
+        self.write_nibble(v & 0xF)
+    }
+
+    /// Presents `v` on D4-D7 and pulses E; the controller latches it on the
+    /// falling edge.
+    fn write_nibble(&self, v: u8) {
+        for (i, d) in self.data.iter().enumerate() {
+            d.set_state(v & (1 << i) != 0)
+        }
+        self.e.set_high();
+        self.delay.delay_us(1);
+        self.e.set_low();
+        self.delay.delay_us(1)
+    }
+
+    fn read_nibble(&self) -> u8 {
+        self.e.set_high();
+        self.delay.delay_us(1);
+        let mut v = 0;
+        for (i, d) in self.data.iter().enumerate() {
+            if d.is_high() {
+                v |= 1 << i
+            }
+        }
+        self.e.set_low();
+        self.delay.delay_us(1);
+        v
+    }
+}
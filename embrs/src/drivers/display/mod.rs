@@ -0,0 +1,11 @@
+//! Small displays.
+//!
+//! - `hd44780`: character LCDs built on the Hitachi HD44780 (or one of its
+//!   many clones), driven over GPIO in four-bit mode.
+//! - `ssd1306`: 128x64 and 128x32 monochrome OLEDs, over I2C or SPI, with a
+//!   framebuffer.
+//! - `font`: a 5x7 monospace font for the graphical displays.
+
+pub mod font;
+pub mod hd44780;
+pub mod ssd1306;
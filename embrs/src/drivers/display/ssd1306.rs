@@ -0,0 +1,276 @@
+//! SSD1306 monochrome OLED controllers, as found on the ubiquitous 0.96"
+//! 128x64 and 0.91" 128x32 modules.
+//!
+//! The controller talks either I2C (address 0x3C or 0x3D, set by a strap)
+//! or four-wire SPI (with a data/command pin); `I2cInterface` and
+//! `SpiInterface` adapt the `hal` buses to the `Interface` the driver uses.
+//!
+//! Drawing happens in a framebuffer in RAM -- 1KiB for 128x64 -- which
+//! `flush` copies to the display in one go:
+//!
+//!     let mut oled = Ssd1306::new(I2cInterface::new(&I2C1, 0x3C),
+//!                                 Size::W128H64);
+//!     try!(oled.init());
+//!     oled.draw_str(0, 0, "Hello");
+//!     try!(oled.flush());
+
+use core::cmp;
+
+use hal::{DigitalOutput, I2cBus, SpiTransfer};
+use super::font;
+
+const WIDTH : usize = 128;
+/// Bytes of framebuffer for the tallest display.
+const BUFFER_SIZE : usize = WIDTH * 64 / 8;
+
+/// I2C control byte announcing commands.
+const CONTROL_COMMAND : u8 = 0x00;
+/// I2C control byte announcing display data.
+const CONTROL_DATA : u8 = 0x40;
+
+/// Display data sent per I2C transaction.
+const I2C_CHUNK : usize = 32;
+
+/// How the driver reaches the controller.
+pub trait Interface {
+    type Error;
+
+    /// Sends command bytes.
+    fn command(&self, bytes: &[u8]) -> Result<(), Self::Error>;
+
+    /// Sends display data.
+    fn data(&self, bytes: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// An SSD1306 on I2C.
+pub struct I2cInterface<'a, B: I2cBus + 'a> {
+    bus: &'a B,
+    address: u8,
+}
+
+impl<'a, B: I2cBus + 'a> I2cInterface<'a, B> {
+    pub fn new(bus: &'a B, address: u8) -> I2cInterface<'a, B> {
+        I2cInterface {
+            bus: bus,
+            address: address,
+        }
+    }
+
+    /// Sends `bytes` after `control`, in chunks.
+    fn send(&self, control: u8, bytes: &[u8]) -> Result<(), B::Error> {
+        let mut buf = [0; I2C_CHUNK + 1];
+        buf[0] = control;
+        for chunk in bytes.chunks(I2C_CHUNK) {
+            buf[1 .. chunk.len() + 1].copy_from_slice(chunk);
+            try!(self.bus.write(self.address, &buf[.. chunk.len() + 1]))
+        }
+        Ok(())
+    }
+}
+
+impl<'a, B: I2cBus + 'a> Interface for I2cInterface<'a, B> {
+    type Error = B::Error;
+
+    fn command(&self, bytes: &[u8]) -> Result<(), B::Error> {
+        self.send(CONTROL_COMMAND, bytes)
+    }
+
+    fn data(&self, bytes: &[u8]) -> Result<(), B::Error> {
+        self.send(CONTROL_DATA, bytes)
+    }
+}
+
+/// An SSD1306 on SPI (mode 0 or 3, up to 10 MHz), with its data/command
+/// and chip select pins.
+pub struct SpiInterface<'a, S, O>
+    where S: SpiTransfer + 'a, O: DigitalOutput + 'a {
+    spi: &'a S,
+    dc: &'a O,
+    cs: &'a O,
+}
+
+impl<'a, S, O> SpiInterface<'a, S, O>
+    where S: SpiTransfer + 'a, O: DigitalOutput + 'a {
+    pub fn new(spi: &'a S, dc: &'a O, cs: &'a O) -> SpiInterface<'a, S, O> {
+        cs.set_high();
+        SpiInterface {
+            spi: spi,
+            dc: dc,
+            cs: cs,
+        }
+    }
+
+    fn send(&self, data: bool, bytes: &[u8]) -> Result<(), S::Error> {
+        self.dc.set_state(data);
+        self.cs.set_low();
+        let result = self.spi.write(bytes);
+        self.cs.set_high();
+        result
+    }
+}
+
+impl<'a, S, O> Interface for SpiInterface<'a, S, O>
+    where S: SpiTransfer + 'a, O: DigitalOutput + 'a {
+    type Error = S::Error;
+
+    fn command(&self, bytes: &[u8]) -> Result<(), S::Error> {
+        self.send(false, bytes)
+    }
+
+    fn data(&self, bytes: &[u8]) -> Result<(), S::Error> {
+        self.send(true, bytes)
+    }
+}
+
+/// Display sizes.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Size {
+    W128H64,
+    W128H32,
+}
+
+impl Size {
+    pub fn height(self) -> usize {
+        match self {
+            Size::W128H64 => 64,
+            Size::W128H32 => 32,
+        }
+    }
+}
+
+/// An SSD1306 display and its framebuffer.
+pub struct Ssd1306<I: Interface> {
+    interface: I,
+    size: Size,
+    /// One byte per column per eight-row page, as the controller lays it
+    /// out: bit 0 is the top row of the page.
+    buffer: [u8; BUFFER_SIZE],
+}
+
+impl<I: Interface> Ssd1306<I> {
+    pub fn new(interface: I, size: Size) -> Ssd1306<I> {
+        Ssd1306 {
+            interface: interface,
+            size: size,
+            buffer: [0; BUFFER_SIZE],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        WIDTH
+    }
+
+    pub fn height(&self) -> usize {
+        self.size.height()
+    }
+
+    /// Configures the controller -- including its charge pump, which most
+    /// modules rely on -- and turns the display on, blank.
+    pub fn init(&mut self) -> Result<(), I::Error> {
+        let (multiplex, pins) = match self.size {
+            Size::W128H64 => (0x3F, 0x12),
+            Size::W128H32 => (0x1F, 0x02),
+        };
+        try!(self.interface.command(&[
+            0xAE,               // display off
+            0xD5, 0x80,         // clock divide and oscillator frequency
+            0xA8, multiplex,    // multiplex ratio: rows - 1
+            0xD3, 0x00,         // no display offset
+            0x40,               // start line 0
+            0x8D, 0x14,         // charge pump on
+            0x20, 0x00,         // horizontal addressing
+            0xA1,               // column 127 is SEG0 (mirror horizontally)
+            0xC8,               // scan COM from the bottom (mirror vertically)
+            0xDA, pins,         // COM pin configuration
+            0x81, 0xCF,         // contrast
+            0xD9, 0xF1,         // precharge period
+            0xDB, 0x40,         // VCOMH deselect level
+            0xA4,               // display RAM contents
+            0xA6,               // not inverted
+        ]));
+        self.clear();
+        try!(self.flush());
+        self.interface.command(&[0xAF])
+    }
+
+    /// Turns the panel on or off; the contents are kept while it's off.
+    pub fn set_on(&self, on: bool) -> Result<(), I::Error> {
+        self.interface.command(&[if on { 0xAF } else { 0xAE }])
+    }
+
+    /// Sets the brightness.
+    pub fn set_contrast(&self, contrast: u8) -> Result<(), I::Error> {
+        self.interface.command(&[0x81, contrast])
+    }
+
+    /// Swaps lit and unlit pixels, without changing the framebuffer.
+    pub fn set_inverted(&self, inverted: bool) -> Result<(), I::Error> {
+        self.interface.command(&[if inverted { 0xA7 } else { 0xA6 }])
+    }
+
+    /// Copies the framebuffer to the display.
+    pub fn flush(&self) -> Result<(), I::Error> {
+        let pages = self.height() / 8;
+        try!(self.interface.command(&[
+            0x21, 0, (WIDTH - 1) as u8,     // column range
+            0x22, 0, (pages - 1) as u8,     // page range
+        ]));
+        self.interface.data(&self.buffer[.. WIDTH * pages])
+    }
+
+    /// Blanks the framebuffer.
+    pub fn clear(&mut self) {
+        for b in self.buffer.iter_mut() {
+            *b = 0
+        }
+    }
+
+    /// Gives direct access to the framebuffer, in the controller's layout:
+    /// `height() / 8` pages of `width()` bytes, each byte a column of eight
+    /// pixels with bit 0 at the top.
+    pub fn buffer_mut(&mut self) -> &mut [u8] {
+        let len = WIDTH * self.height() / 8;
+        &mut self.buffer[.. len]
+    }
+
+    /// Lights (or clears) the pixel at `x`, `y`, counting from the top
+    /// left.  Pixels off the display are ignored.
+    pub fn set_pixel(&mut self, x: usize, y: usize, on: bool) {
+        if x >= WIDTH || y >= self.height() {
+            return
+        }
+        let i = (y / 8) * WIDTH + x;
+        let bit = 1 << (y % 8);
+        if on {
+            self.buffer[i] |= bit
+        } else {
+            self.buffer[i] &= !bit
+        }
+    }
+
+    /// Draws `c` in a `font::ADVANCE`-wide cell with its top left corner at
+    /// `x`, `y`, clearing the rest of the cell.
+    pub fn draw_char(&mut self, x: usize, y: usize, c: char) {
+        let glyph = font::glyph(c);
+        for col in 0..font::ADVANCE {
+            let bits = if col < font::WIDTH { glyph[col] } else { 0 };
+            for row in 0..font::HEIGHT + 1 {
+                self.set_pixel(x + col, y + row, bits & (1 << row) != 0)
+            }
+        }
+    }
+
+    /// Draws `s` from `x`, `y`, stopping at the right edge.  Returns the x
+    /// coordinate just past the text.
+    pub fn draw_str(&mut self, x: usize, y: usize, s: &str) -> usize {
+        let mut x = x;
+        for c in s.chars() {
+            if x >= WIDTH {
+                break
+            }
+            self.draw_char(x, y, c);
+            x += font::ADVANCE
+        }
+        cmp::min(x, WIDTH)
+    }
+}
@@ -0,0 +1,413 @@
+//! DShot, the digital protocol spoken by modern multirotor ESCs.
+//!
+//! Each DShot frame is sixteen bits, most significant first: an 11-bit
+//! throttle value (0 to stop, 1-47 for commands, 48-2047 for throttle), a
+//! telemetry request bit, and a 4-bit checksum.  Bits are sent as pulses in
+//! fixed-length periods -- 6.67us, 3.33us, or 1.67us for DShot150, 300 and
+//! 600 -- high for three quarters of the period for a one, and three
+//! eighths for a zero.
+//!
+//! `DShot` generates the pulses with a timer running PWM at the bit rate on
+//! up to four channels (channel 1 upwards), and DMA in burst mode: at each
+//! update event, one DMA request loads the next compare value into every
+//! channel's CCR, through DMAR.  So one stream serves all the motors on a
+//! timer, and the CPU only has to encode the frame.
+//!
+//!     let mut motors = try!(DShot::configure(
+//!         &TIM1, 4, Speed::DShot600, false, Request::Tim1Up,
+//!         clock::frozen(), &mut DSHOT_BUF));
+//!     try!(motors.send(&[throttle(0.1); 4], false));
+//!
+//! The request must be the timer's update request, and the channels' pins
+//! routed to the timer (see `Pwm::route_pins`).  Frames should be sent at
+//! least every few milliseconds, or the ESCs will time out and stop.
+//!
+//! # Bidirectional DShot
+//!
+//! With bidirectional DShot, the signal is inverted (idle high), the
+//! checksum is inverted, and about 30us after each frame the ESC answers on
+//! the same wire with its electrical RPM, at 5/4 of the bit rate.
+//! `configure` with `bidirectional` set produces the inverted frames.
+//! Receiving the answer means releasing the pin and timing its edges --
+//! typically by switching the channel to input capture, with DMA collecting
+//! the captured times -- and is up to the application; `decode_reply` and
+//! `decode_erpm` turn the edge times into an RPM.
+
+use core::sync::atomic::{self, Ordering};
+
+use stm32f4::dma::{self, Request};
+use stm32f4::rcc::ClockSpeeds;
+use stm32f4::tim::{self, Channel, Polarity, Timer};
+use stm32f4::tim::pwm::{Pwm, PwmError, PwmMode};
+
+/// Bits in a frame.
+const FRAME_BITS : usize = 16;
+/// Bit periods per frame in the DMA buffer: the frame, then two idle periods
+/// (CCR loads are buffered, so one is needed just to get the last bit out).
+pub const FRAME_SLOTS : usize = FRAME_BITS + 2;
+/// Bits in a bidirectional reply.
+const REPLY_BITS : u32 = 21;
+/// Largest throttle (or command) value.
+pub const MAX_VALUE : u16 = 2047;
+/// Smallest throttle value that runs the motor; below this are commands.
+pub const MIN_THROTTLE : u16 = 48;
+
+/// DShot bit rates.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Speed {
+    DShot150,
+    DShot300,
+    DShot600,
+}
+
+impl Speed {
+    pub fn bit_hz(self) -> u32 {
+        match self {
+            Speed::DShot150 => 150_000,
+            Speed::DShot300 => 300_000,
+            Speed::DShot600 => 600_000,
+        }
+    }
+}
+
+/// Special commands, sent in place of throttle while the motors are
+/// stopped.  Most must be sent several times (commonly six) in a row to
+/// take effect, and the ESC needs time to act on the settings commands.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Command {
+    MotorStop = 0,
+    Beep1 = 1,
+    Beep2 = 2,
+    Beep3 = 3,
+    Beep4 = 4,
+    Beep5 = 5,
+    EscInfo = 6,
+    SpinDirection1 = 7,
+    SpinDirection2 = 8,
+    Mode3dOff = 9,
+    Mode3dOn = 10,
+    SaveSettings = 12,
+    ExtendedTelemetryEnable = 13,
+    ExtendedTelemetryDisable = 14,
+    SpinDirectionNormal = 20,
+    SpinDirectionReversed = 21,
+}
+
+/// Ways that DShot operations can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum DShotError {
+    Pwm(PwmError),
+    /// The previous frame is still being sent.
+    Busy,
+    /// The DMA buffer is too short for the number of channels.
+    BufferTooShort,
+    /// The number of channels is not 1-4, or more than the timer has.
+    BadChannels,
+    /// A throttle value is over `MAX_VALUE`.
+    BadValue,
+}
+
+/// Converts a throttle fraction (0 to 1, clamped) into a DShot value,
+/// using the whole throttle range; zero maps to stop.
+pub fn throttle(fraction: f32) -> u16 {
+    if !(fraction > 0.) {
+        return 0
+    }
+    let f = if fraction > 1. { 1. } else { fraction };
+    let span = (MAX_VALUE - MIN_THROTTLE) as f32;
+    MIN_THROTTLE + (f * span + 0.5) as u16
+}
+
+/// Builds the frame carrying `value` (11 bits), with the telemetry request
+/// bit and the checksum -- inverted, for bidirectional DShot.
+pub fn frame(value: u16, telemetry: bool, bidirectional: bool) -> u16 {
+    let v = (value & MAX_VALUE) << 1 | telemetry as u16;
+    let crc = v ^ (v >> 4) ^ (v >> 8);
+    let crc = if bidirectional { !crc } else { crc };
+    v << 4 | (crc & 0xF)
+}
+
+/// DShot outputs on the first few channels of a timer.
+pub struct DShot {
+    timer: &'static Timer,
+    channels: usize,
+    request: Request,
+    bidirectional: bool,
+    /// Compare values for one and zero bits.
+    one: u32,
+    zero: u32,
+    /// Frame slots, interleaved by channel.
+    buffer: &'static mut [u32],
+}
+
+impl DShot {
+    /// Configures `timer` to run at the bit rate of `speed`, sets up its
+    /// first `channels` channels as outputs, idle, and readies DMA burst
+    /// mode.  `buffer` must hold at least `FRAME_SLOTS * channels` words.
+    /// The timer's clock must already be enabled.
+    pub fn configure(timer: &'static Timer,
+                     channels: usize,
+                     speed: Speed,
+                     bidirectional: bool,
+                     request: Request,
+                     speeds: &ClockSpeeds,
+                     buffer: &'static mut [u32])
+        -> Result<DShot, DShotError> {
+        if channels == 0 || channels > 4 {
+            return Err(DShotError::BadChannels)
+        }
+        if buffer.len() < FRAME_SLOTS * channels {
+            return Err(DShotError::BufferTooShort)
+        }
+
+        // The output is active for the compare count at the start of each
+        // period; for bidirectional DShot, active is low.
+        let polarity = if bidirectional {
+            Polarity::ActiveLow
+        } else {
+            Polarity::ActiveHigh
+        };
+        let mut period = 0;
+        for i in 0..channels {
+            let ch = CHANNELS[i];
+            let pwm = try!(Pwm::new(timer, ch)
+                           .map_err(|_| DShotError::BadChannels));
+            if i == 0 {
+                let _ = try!(pwm.configure(speeds, speed.bit_hz() as f32, 0.,
+                                           PwmMode::Mode1)
+                             .map_err(DShotError::Pwm));
+                period = pwm.get_period();
+            }
+            pwm.configure_channel(PwmMode::Mode1, polarity);
+            pwm.set_compare(0);
+            pwm.enable();
+        }
+        timer.load_preloads();
+        timer.set_dma_burst(tim::CCR1_OFFSET, channels);
+
+        Ok(DShot {
+            timer: timer,
+            channels: channels,
+            request: request,
+            bidirectional: bidirectional,
+            one: (period * 3 + 2) / 4,
+            zero: (period * 3 + 4) / 8,
+            buffer: buffer,
+        })
+    }
+
+    /// Checks whether a frame is still being sent.
+    pub fn is_busy(&self) -> bool {
+        self.request.route().get_stream().is_enabled()
+    }
+
+    /// Sends one frame to each channel: `values[i]` (a throttle, or a
+    /// `Command` as `u16`) to channel `i + 1`, with the telemetry request
+    /// bit set to `telemetry`.  Returns as soon as the frame has started.
+    pub fn send(&mut self, values: &[u16], telemetry: bool)
+        -> Result<(), DShotError> {
+        if values.len() != self.channels {
+            return Err(DShotError::BadChannels)
+        }
+        if values.iter().any(|&v| v > MAX_VALUE) {
+            return Err(DShotError::BadValue)
+        }
+        if self.is_busy() {
+            return Err(DShotError::Busy)
+        }
+
+        let n = self.channels;
+        for (ch, &v) in values.iter().enumerate() {
+            let f = frame(v, telemetry, self.bidirectional);
+            for bit in 0..FRAME_BITS {
+                self.buffer[bit * n + ch] = if f & (0x8000 >> bit) != 0 {
+                    self.one
+                } else {
+                    self.zero
+                }
+            }
+            for slot in FRAME_BITS..FRAME_SLOTS {
+                self.buffer[slot * n + ch] = 0
+            }
+        }
+        atomic::fence(Ordering::Release);
+
+        let len = FRAME_SLOTS * n;
+        let route = self.request.route();
+        let stream = route.get_stream();
+        route.clear_interrupt_flags(dma::InterruptFlags::all());
+        stream.par.set(self.timer.dmar_address());
+        stream.mar[0].set(self.buffer.as_ptr() as *const ());
+        stream.ndtr.set(dma::Ndtr::default().with_ndt(len as u16));
+        stream.cr.set(dma::Cr::default()
+                      .with_chsel(route.get_channel())
+                      .with_dir(dma::Direction::MemoryToPeripheral)
+                      .with_msize(dma::TransferSize::Word)
+                      .with_psize(dma::TransferSize::Word)
+                      .with_minc(true));
+        stream.cr.update(|v| v.with_en(true));
+        self.timer.update_dier(|v| v.with_ude(true));
+        Ok(())
+    }
+
+    /// Sends `command` to every channel, with the telemetry bit set as the
+    /// protocol requires.
+    pub fn send_command(&mut self, command: Command)
+        -> Result<(), DShotError> {
+        let values = [command as u16; 4];
+        let n = self.channels;
+        self.send(&values[..n], true)
+    }
+}
+
+const CHANNELS : [Channel; 4] =
+    [Channel::Ch1, Channel::Ch2, Channel::Ch3, Channel::Ch4];
+
+/// Reassembles a bidirectional reply from the times of its edges (in any
+/// unit, with `bit_time` the length of a reply bit in the same unit),
+/// starting with the falling edge of its start bit.  Returns the 21 bits
+/// with a one wherever the line changed level (the start bit, then the GCR
+/// code), or `None` if the edges don't make sense.
+pub fn decode_reply(edges: &[u32], bit_time: f32) -> Option<u32> {
+    if edges.len() < 2 || !(bit_time > 0.) {
+        return None
+    }
+    // Each edge is a transition, which marks a one at the start of its run;
+    // the rest of the run is zeros.
+    let mut value = 0u32;
+    let mut bits = 0;
+    for pair in edges.windows(2) {
+        let dt = pair[1].wrapping_sub(pair[0]) as f32;
+        let len = (dt / bit_time + 0.5) as u32;
+        if len == 0 || bits + len > REPLY_BITS {
+            return None
+        }
+        value = (value << len) | (1 << (len - 1));
+        bits += len
+    }
+    // The last edge starts a run that lasts until the end of the reply,
+    // unless it was the return to idle after the final bit.
+    if bits < REPLY_BITS {
+        let len = REPLY_BITS - bits;
+        value = (value << len) | (1 << (len - 1))
+    }
+    Some(value)
+}
+
+/// The 5-bit codes for each nibble.
+const GCR : [u8; 16] = [
+    0x19, 0x1B, 0x12, 0x13, 0x1D, 0x15, 0x16, 0x17,
+    0x1A, 0x09, 0x0A, 0x0B, 0x1E, 0x0D, 0x0E, 0x0F,
+];
+
+/// Decodes a reply, as returned by `decode_reply`, into electrical RPM
+/// (divide by the motor's pole pairs for mechanical RPM).  Returns `None`
+/// if the reply is corrupt.
+pub fn decode_erpm(reply: u32) -> Option<u32> {
+    // `decode_reply` has already undone the transition coding; below the
+    // start bit are twenty bits of GCR.
+    let gcr = reply & 0xF_FFFF;
+    let mut v = 0u32;
+    for i in 0..4 {
+        let code = (gcr >> (15 - 5 * i)) as u8 & 0x1F;
+        let nibble = match GCR.iter().position(|&c| c == code) {
+            Some(n) => n as u32,
+            None => return None,
+        };
+        v = v << 4 | nibble
+    }
+
+    let data = v >> 4;
+    let crc = !(data ^ (data >> 4) ^ (data >> 8)) & 0xF;
+    if crc != v & 0xF {
+        return None
+    }
+    // Twelve bits of period in microseconds: a 3-bit shift and a 9-bit
+    // mantissa.
+    let period_us = (data & 0x1FF) << (data >> 9);
+    if period_us == 0 || data == 0xFFF {
+        return Some(0)
+    }
+    Some(60_000_000 / period_us)
+}
+
+#[cfg(all(test, feature = "host-test"))]
+mod tests {
+    use super::*;
+
+    const BIT_TIME : u32 = 100;
+
+    /// Builds the edge times an ESC would produce for the 12-bit `data`,
+    /// returning them and how many there are.
+    fn encode_reply(data: u32) -> ([u32; 24], usize) {
+        let crc = !(data ^ (data >> 4) ^ (data >> 8)) & 0xF;
+        let v = data << 4 | crc;
+        let mut gcr = 0u32;
+        for i in 0..4 {
+            gcr = gcr << 5 | GCR[(v >> (12 - 4 * i)) as usize & 0xF] as u32
+        }
+        // Start bit, then the code; each one is a transition.
+        let transitions = 1 << 20 | gcr;
+
+        let mut edges = [0; 24];
+        let mut n = 0;
+        let mut high = true;
+        for i in 0..REPLY_BITS {
+            if transitions & (1 << (REPLY_BITS - 1 - i)) != 0 {
+                // A little jitter, as from a real capture.
+                edges[n] = i * BIT_TIME + (n as u32 % 3) * 10;
+                n += 1;
+                high = !high
+            }
+        }
+        if !high {
+            // Return to idle.
+            edges[n] = REPLY_BITS * BIT_TIME;
+            n += 1
+        }
+        (edges, n)
+    }
+
+    fn round_trip(data: u32) -> Option<u32> {
+        let (edges, n) = encode_reply(data);
+        let reply = decode_reply(&edges[..n], BIT_TIME as f32);
+        assert!(reply.is_some());
+        decode_erpm(reply.unwrap())
+    }
+
+    #[test]
+    fn replies_round_trip() {
+        for shift in 0..8 {
+            for &mantissa in &[1, 2, 0x55, 0xAA, 0x100, 0x1FE, 0x1FF] {
+                let data = shift << 9 | mantissa;
+                let expected = if data == 0xFFF {
+                    0
+                } else {
+                    60_000_000 / (mantissa << shift)
+                };
+                assert_eq!(round_trip(data), Some(expected));
+            }
+        }
+    }
+
+    #[test]
+    fn periods_decode_to_erpm() {
+        // 500us is 120000 eRPM, and 500 << 1 is 1000us, 60000 eRPM.
+        assert_eq!(round_trip(500), Some(120_000));
+        assert_eq!(round_trip(1 << 9 | 500), Some(60_000));
+        // A stopped motor sends the largest period.
+        assert_eq!(round_trip(0xFFF), Some(0));
+    }
+
+    #[test]
+    fn corrupt_replies_are_rejected() {
+        let (mut edges, n) = encode_reply(1 << 9 | 500);
+        // Stretching a run shifts everything after it out of place.
+        for e in &mut edges[2..n] {
+            *e += BIT_TIME
+        }
+        let reply = decode_reply(&edges[..n], BIT_TIME as f32);
+        assert_eq!(reply.and_then(decode_erpm), None);
+        assert_eq!(decode_reply(&edges[..1], BIT_TIME as f32), None);
+    }
+}
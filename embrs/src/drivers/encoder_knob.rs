@@ -0,0 +1,327 @@
+//! Mechanical rotary encoder knobs with a push switch, for user interfaces.
+//!
+//! These knobs produce a two-bit Gray code on their A and B contacts --
+//! usually four transitions ("steps") per detent, sometimes two -- and close
+//! a switch when pushed.  The contacts bounce, so `Knob` decodes the
+//! quadrature through a transition table that only accepts single-bit
+//! changes between adjacent states: a bounce that goes forward one step and
+//! back one cancels out, and an impossible two-bit change is ignored.  The
+//! switch is debounced by a `Button`.
+//!
+//! Where the A and B pins can be routed to channels 1 and 2 of a timer, the
+//! timer's encoder mode (`stm32f4::tim::Encoder`, with its input filter set)
+//! can do the decoding instead, as `Rotation::Timer`.
+//!
+//! Like `Button`, `Knob` is sampled periodically, from one context:
+//!
+//!     static KNOB: Knob<Pins, Pins, Pins> = Knob::new(
+//!         Rotation::Pins(Pins { port: gpiob, pins: P4 },
+//!                        Pins { port: gpiob, pins: P5 }),
+//!         Pins { port: gpiob, pins: P6 },
+//!         encoder_knob::DEFAULT_CONFIG);
+//!
+//!     extern "C" fn sys_tick_isr() {
+//!         KNOB.sample(MILLIS.load(Ordering::Relaxed) as u32);
+//!     }
+//!
+//! Software decoding needs samples at least as often as the fastest step:
+//! every millisecond copes with a brisk twist of a 24-detent knob.
+//!
+//! Each detent turned, and each switch event, is delivered to a handler
+//! registered with `set_handler` or, failing that, queued for
+//! `take_event`.  `position` keeps a running count of detents for code
+//! that would rather poll.
+
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use arm_m;
+use hal::DigitalInput;
+use super::button::{self, Button, ButtonConfig, ButtonEvent};
+
+#[cfg(not(feature = "arch:armv6-m"))]
+use stm32f4::tim::Encoder;
+
+/// Number of events `take_event` can hold before further events are dropped.
+pub const QUEUE_LEN: usize = 16;
+
+/// Things that happen to a knob.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum KnobEvent {
+    /// Turned one detent clockwise.
+    Clockwise,
+    /// Turned one detent counterclockwise.
+    Counterclockwise,
+    /// Something happened to the switch.
+    Button(ButtonEvent),
+}
+
+impl KnobEvent {
+    fn to_usize(self) -> usize {
+        match self {
+            KnobEvent::Clockwise => 0,
+            KnobEvent::Counterclockwise => 1,
+            KnobEvent::Button(ButtonEvent::Pressed) => 2,
+            KnobEvent::Button(ButtonEvent::Released) => 3,
+            KnobEvent::Button(ButtonEvent::LongPress) => 4,
+        }
+    }
+
+    fn from_usize(v: usize) -> KnobEvent {
+        match v {
+            0 => KnobEvent::Clockwise,
+            1 => KnobEvent::Counterclockwise,
+            2 => KnobEvent::Button(ButtonEvent::Pressed),
+            3 => KnobEvent::Button(ButtonEvent::Released),
+            _ => KnobEvent::Button(ButtonEvent::LongPress),
+        }
+    }
+}
+
+/// Function called from `sample` for each event.
+pub type KnobHandler = fn(KnobEvent);
+
+/// Where the rotation comes from.
+pub enum Rotation<A: DigitalInput, B: DigitalInput> {
+    /// The A and B contacts, decoded in software.  The pins must already be
+    /// configured as inputs, with pull-ups if the knob switches to ground.
+    Pins(A, B),
+    /// A timer in encoder mode, configured and counting.
+    #[cfg(not(feature = "arch:armv6-m"))]
+    Timer(&'static Encoder),
+}
+
+/// Knob geometry and switch behavior.
+#[derive(Copy, Clone, Debug)]
+pub struct KnobConfig {
+    /// Quadrature steps (counts, for a timer) per detent.
+    pub steps_per_detent: u32,
+    /// Swaps clockwise and counterclockwise, for knobs wired the other way.
+    pub reverse: bool,
+    pub button: ButtonConfig,
+}
+
+/// Four steps per detent, with an active-low switch.
+pub const DEFAULT_CONFIG: KnobConfig = KnobConfig {
+    steps_per_detent: 4,
+    reverse: false,
+    button: ButtonConfig {
+        active_low: true,
+        .. button::DEFAULT_CONFIG
+    },
+};
+
+impl Default for KnobConfig {
+    fn default() -> KnobConfig {
+        DEFAULT_CONFIG
+    }
+}
+
+/// Change in position, in steps, indexed by the previous two-bit state
+/// (shifted left two) and the new one.  Zero for no change, and for changes
+/// of both bits, which can't happen in one step.
+const TRANSITIONS: [i8; 16] = [
+    0, 1, -1, 0,
+    -1, 0, 0, 1,
+    1, 0, 0, -1,
+    0, -1, 1, 0,
+];
+
+/// Bits of `Knob::quad`: the last A/B state, and whether there is one.
+const QUAD_STATE : usize = 0b11;
+const QUAD_VALID : usize = 1 << 2;
+/// Set by `wake`, cleared once the knob has come to rest.
+const QUAD_AWAKE : usize = 1 << 3;
+
+/// A rotary encoder knob with a push switch.
+pub struct Knob<A: DigitalInput, B: DigitalInput, S: DigitalInput> {
+    rotation: Rotation<A, B>,
+    button: Button<S>,
+    config: KnobConfig,
+    quad: AtomicUsize,
+    /// Steps taken since the last detent, in two's complement.
+    steps: AtomicUsize,
+    /// Timer position at the last sample, for `Rotation::Timer`.
+    last_count: AtomicUsize,
+    /// Detents turned, clockwise positive, in two's complement.
+    position: AtomicUsize,
+    handler: AtomicUsize,
+    queue: [AtomicUsize; QUEUE_LEN],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+impl<A, B, S> Knob<A, B, S>
+    where A: DigitalInput, B: DigitalInput, S: DigitalInput {
+    /// Creates a knob turning `rotation`, with its switch on `switch`.
+    pub const fn new(rotation: Rotation<A, B>, switch: S, config: KnobConfig)
+        -> Knob<A, B, S> {
+        Knob {
+            rotation: rotation,
+            button: Button::new(switch, config.button),
+            config: config,
+            quad: ATOMIC_USIZE_INIT,
+            steps: ATOMIC_USIZE_INIT,
+            last_count: ATOMIC_USIZE_INIT,
+            position: ATOMIC_USIZE_INIT,
+            handler: ATOMIC_USIZE_INIT,
+            queue: [
+                ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+            ],
+            head: ATOMIC_USIZE_INIT,
+            tail: ATOMIC_USIZE_INIT,
+            dropped: ATOMIC_USIZE_INIT,
+        }
+    }
+
+    /// Registers `handler` to receive events as they happen, instead of
+    /// queueing them.
+    pub fn set_handler(&self, handler: KnobHandler) {
+        self.handler.store(handler as usize, Ordering::Release)
+    }
+
+    /// Samples the knob at time `now_ms` (a free-running millisecond count,
+    /// which may wrap), delivering any resulting events.  This should be
+    /// called regularly, from one context only.
+    pub fn sample(&self, now_ms: u32) {
+        match self.rotation {
+            Rotation::Pins(ref a, ref b) => {
+                let state = (a.is_high() as usize) << 1 | b.is_high() as usize;
+                let quad = self.quad.load(Ordering::Relaxed);
+                if quad & QUAD_VALID != 0 {
+                    let index = (quad & QUAD_STATE) << 2 | state;
+                    self.step(TRANSITIONS[index] as i32)
+                }
+                let mut next = (quad & !QUAD_STATE) | QUAD_VALID | state;
+                if self.steps.load(Ordering::Relaxed) == 0 {
+                    next &= !QUAD_AWAKE
+                }
+                self.commit_quad(quad, next)
+            }
+            #[cfg(not(feature = "arch:armv6-m"))]
+            Rotation::Timer(encoder) => {
+                let count = encoder.position();
+                let last = self.last_count.load(Ordering::Relaxed) as i32;
+                self.last_count.store(count as usize, Ordering::Relaxed);
+                let quad = self.quad.load(Ordering::Relaxed);
+                if quad & QUAD_VALID != 0 {
+                    self.step(count.wrapping_sub(last))
+                }
+                self.commit_quad(quad, (quad | QUAD_VALID) & !QUAD_AWAKE)
+            }
+        }
+
+        self.button.sample(now_ms);
+        while let Some(e) = self.button.take_event() {
+            self.deliver(KnobEvent::Button(e))
+        }
+    }
+
+    /// Notes that an input has changed, e.g. from an EXTI interrupt, so that
+    /// `is_idle` reports `false` until the change has been dealt with.
+    pub fn wake(&self) {
+        arm_m::without_interrupts(|| {
+            let q = self.quad.load(Ordering::Relaxed);
+            self.quad.store(q | QUAD_AWAKE, Ordering::Relaxed)
+        });
+        self.button.wake()
+    }
+
+    /// Checks whether the knob is at rest on a detent and its switch
+    /// released and settled, with no `wake` since, so that sampling can
+    /// stop until the next edge.
+    pub fn is_idle(&self) -> bool {
+        (self.quad.load(Ordering::Relaxed) & QUAD_AWAKE) == 0
+            && self.steps.load(Ordering::Relaxed) == 0
+            && self.button.is_idle()
+    }
+
+    /// Checks whether the switch is (in debounced terms) held down.
+    pub fn is_pressed(&self) -> bool {
+        self.button.is_pressed()
+    }
+
+    /// Returns the number of detents turned since creation, clockwise
+    /// positive.  Wraps.
+    pub fn position(&self) -> i32 {
+        self.position.load(Ordering::Relaxed) as i32
+    }
+
+    /// Accumulates `delta` steps, delivering an event for each detent
+    /// completed.
+    fn step(&self, delta: i32) {
+        if delta == 0 {
+            return
+        }
+        let per_detent = self.config.steps_per_detent as i32;
+        let mut steps = (self.steps.load(Ordering::Relaxed) as i32) + delta;
+        while steps >= per_detent || steps <= -per_detent {
+            let clockwise = (steps > 0) != self.config.reverse;
+            steps -= if steps > 0 { per_detent } else { -per_detent };
+            let p = self.position.load(Ordering::Relaxed) as i32;
+            let p = p.wrapping_add(if clockwise { 1 } else { -1 });
+            self.position.store(p as usize, Ordering::Relaxed);
+            self.deliver(if clockwise {
+                KnobEvent::Clockwise
+            } else {
+                KnobEvent::Counterclockwise
+            })
+        }
+        self.steps.store(steps as usize, Ordering::Relaxed)
+    }
+
+    /// Replaces `quad`, last read as `observed`, with `next`.  A `wake` may
+    /// have set QUAD_AWAKE since it was read; that edge hasn't been sampled
+    /// yet, so the bit is kept.
+    fn commit_quad(&self, observed: usize, next: usize) {
+        arm_m::without_interrupts(|| {
+            let woke =
+                self.quad.load(Ordering::Relaxed) & !observed & QUAD_AWAKE;
+            self.quad.store(next | woke, Ordering::Relaxed)
+        })
+    }
+
+    fn deliver(&self, event: KnobEvent) {
+        let h = self.handler.load(Ordering::Acquire);
+        if h != 0 {
+            let handler: KnobHandler = unsafe { mem::transmute(h) };
+            handler(event);
+            return
+        }
+
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) >= QUEUE_LEN {
+            let d = self.dropped.load(Ordering::Relaxed);
+            self.dropped.store(d.wrapping_add(1), Ordering::Relaxed);
+            return
+        }
+        self.queue[head % QUEUE_LEN].store(event.to_usize(), Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release)
+    }
+
+    /// Takes the oldest queued event, if any.
+    pub fn take_event(&self) -> Option<KnobEvent> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if self.head.load(Ordering::Acquire) == tail {
+            return None
+        }
+        let e = self.queue[tail % QUEUE_LEN].load(Ordering::Relaxed);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(KnobEvent::from_usize(e))
+    }
+
+    /// Number of events dropped because the queue was full (modulo the word
+    /// size).
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,146 @@
+//! Watchdog feeding gated on the health of several tasks.
+//!
+//! Feeding a watchdog from one place (the main loop, say) only proves that
+//! that one place is running.  A `Liveness` monitor feeds it only once every
+//! task the application cares about -- the main loop, a receive interrupt's
+//! worker, a periodic sensor poll -- has checked in since the last feeding.
+//!
+//! Each task is a bit in a mask.  Tasks call `check_in` whenever they make
+//! progress, and something periodic calls `poll` with the time, usually from
+//! a software timer (see `time::TimerWheel`):
+//!
+//!     const MAIN_LOOP : u32 = 1 << 0;
+//!     const RX_WORKER : u32 = 1 << 1;
+//!
+//!     static LIVENESS: Liveness<Iwdg> =
+//!         Liveness::new(&IWDG, MAIN_LOOP | RX_WORKER, 500);
+//!
+//!     fn liveness_timer(_: TimerId) {
+//!         LIVENESS.poll(time::now_ms());
+//!     }
+//!
+//! If a window of `window_ms` passes without every task checking in, the
+//! monitor stops feeding the watchdog for good, and the watchdog resets the
+//! system.  Before it does, the missing tasks are recorded in the crash dump
+//! region (see `arm_m::crashdump`), so that they can be reported after the
+//! reset.
+//!
+//! The watchdog's own timeout must be longer than `window_ms` plus the
+//! interval between `poll`s, or it will fire while the monitor is still
+//! waiting for a slow task.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT,
+                         ATOMIC_USIZE_INIT};
+
+#[cfg(all(target_os = "none", not(feature = "arch:armv6-m")))]
+use arm_m::crashdump;
+use hal::Watchdog;
+use sync::fetch_update;
+
+/// Outcomes of `Liveness::poll`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum LivenessStatus {
+    /// All tasks had checked in; the watchdog was fed and a new window
+    /// started.
+    Fed,
+    /// The window is still open, and these tasks haven't checked in yet.
+    Waiting(u32),
+    /// The window closed with these tasks missing.  The watchdog is no longer
+    /// being fed.
+    Starved(u32),
+}
+
+/// A watchdog feeder that waits for a set of tasks; see the module docs.
+pub struct Liveness<'a, W: Watchdog + 'a> {
+    watchdog: &'a W,
+    /// Bits of the tasks that must check in.
+    tasks: u32,
+    window_ms: u32,
+    /// Bits of the tasks that have checked in during this window.
+    checked_in: AtomicUsize,
+    /// Time at which the current window opened.
+    window_start: AtomicUsize,
+    /// Set once a window closes with tasks missing.
+    starved: AtomicBool,
+}
+
+impl<'a, W: Watchdog + 'a> Liveness<'a, W> {
+    /// Creates a monitor that feeds `watchdog` once all of the tasks in
+    /// `tasks` have checked in, and gives up on them if they haven't after
+    /// `window_ms` milliseconds.
+    ///
+    /// The first window opens at time zero, so start polling promptly after
+    /// `time::start` (or feed the watchdog yourself until then).
+    pub const fn new(watchdog: &'a W, tasks: u32, window_ms: u32)
+        -> Liveness<'a, W> {
+        Liveness {
+            watchdog: watchdog,
+            tasks: tasks,
+            window_ms: window_ms,
+            checked_in: ATOMIC_USIZE_INIT,
+            window_start: ATOMIC_USIZE_INIT,
+            starved: ATOMIC_BOOL_INIT,
+        }
+    }
+
+    /// Records that the tasks in `task` are alive.  This can be called from
+    /// any context.
+    #[inline]
+    pub fn check_in(&self, task: u32) {
+        let _ = fetch_update(&self.checked_in,
+                             |v| Some(v | task as usize));
+    }
+
+    /// Gets the tasks that haven't checked in during the current window.
+    pub fn missing(&self) -> u32 {
+        self.tasks & !(self.checked_in.load(Ordering::Relaxed) as u32)
+    }
+
+    /// Checks whether the monitor has given up and stopped feeding the
+    /// watchdog.
+    pub fn is_starved(&self) -> bool {
+        self.starved.load(Ordering::Relaxed)
+    }
+
+    /// Feeds the watchdog if every task has checked in, and notices if the
+    /// window has closed without them.  `now` is the time in milliseconds, as
+    /// from `time::now_ms`.
+    ///
+    /// This must not be called from more than one context.
+    pub fn poll(&self, now: u32) -> LivenessStatus {
+        if self.is_starved() {
+            return LivenessStatus::Starved(self.missing())
+        }
+
+        let missing = self.missing();
+        if missing == 0 {
+            // Start the new window before feeding, so that check-ins that
+            // race with us count towards it.
+            let tasks = self.tasks as usize;
+            let _ = fetch_update(&self.checked_in, |v| Some(v & !tasks));
+            self.window_start.store(now as usize, Ordering::Relaxed);
+            self.watchdog.feed();
+            return LivenessStatus::Fed
+        }
+
+        let start = self.window_start.load(Ordering::Relaxed) as u32;
+        if now.wrapping_sub(start) < self.window_ms {
+            return LivenessStatus::Waiting(missing)
+        }
+
+        self.starved.store(true, Ordering::Relaxed);
+        log_error!("liveness: tasks {:#x} missing; awaiting watchdog reset",
+                   missing);
+        record_starvation(missing);
+        LivenessStatus::Starved(missing)
+    }
+}
+
+#[cfg(all(target_os = "none", not(feature = "arch:armv6-m")))]
+fn record_starvation(missing: u32) {
+    // Safe because we've stopped feeding the watchdog, so a reset is coming.
+    unsafe { crashdump::record_starvation(missing) }
+}
+
+#[cfg(not(all(target_os = "none", not(feature = "arch:armv6-m"))))]
+fn record_starvation(_: u32) {}
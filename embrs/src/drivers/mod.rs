@@ -0,0 +1,27 @@
+//! Drivers for things attached to the SoC, rather than part of it.
+//!
+//! These are written against the `hal` traits, so that they work with any
+//! SoC module that implements them.  The exceptions are the parts of
+//! drivers that need SoC features the traits don't cover -- DMA-fed I2S
+//! audio, say -- which use the `stm32f4` modules directly.
+
+pub mod accel_lis3dsh;
+pub mod button;
+pub mod cs43l22;
+pub mod display;
+#[cfg(not(feature = "arch:armv6-m"))]
+pub mod dshot;
+pub mod encoder_knob;
+pub mod liveness;
+#[cfg(not(feature = "arch:armv6-m"))]
+pub mod pdm_mic;
+#[cfg(not(feature = "arch:armv6-m"))]
+pub mod rc;
+#[cfg(not(feature = "arch:armv6-m"))]
+pub mod servo;
+pub mod shell;
+pub mod swd_host;
+#[cfg(not(feature = "arch:armv6-m"))]
+pub mod ws2812;
+#[cfg(not(feature = "arch:armv6-m"))]
+pub mod xmodem;
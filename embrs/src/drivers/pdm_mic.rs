@@ -0,0 +1,139 @@
+//! The STM32F4 Discovery board's MP45DT02 MEMS microphone.
+//!
+//! The MP45DT02 outputs PDM: a one-bit stream, clocked by the host at 1 to
+//! 3.25 MHz.  On the Discovery, it hangs off I2S2, with its clock on PB10
+//! and data on PC3 (both AF5).  Running I2S2 as a master receiver provides
+//! the clock and collects the bits sixteen at a time; `PdmMic` moves them
+//! into memory by double-buffered DMA and runs them through a
+//! `dsp::pdm::PdmFilter`, delivering 16-bit PCM.
+//!
+//!     static MIC: PdmMic =
+//!         PdmMic::new(&SPI2, Request::Spi2Rx, Interrupt::Dma1Stream3);
+//!
+//!     try!(MIC.start(1024000, 86000000, Decimation::By64,
+//!                    &mut PDM0, &mut PDM1));   // 16 kHz
+//!
+//!     extern "C" fn dma1_stream3_isr() {
+//!         MIC.handle_interrupt(|pcm| { /* ... */ });
+//!     }
+//!
+//! The PCM callback runs in the DMA interrupt, once per short chunk; it
+//! should queue the samples somewhere rather than process them at length.
+
+use dsp::pdm::{Decimation, PdmFilter};
+use stm32f4::dma::{Direction, DoubleBuffer, Request};
+use stm32f4::dma::double_buffer::{DoubleBufferError, Event};
+use stm32f4::irq::Interrupt;
+use stm32f4::spi::{I2sConfig, I2sDataLength, I2sMode, I2sStandard, Spi,
+                   SpiError};
+use sync::IrqCell;
+
+/// PCM samples handed to the callback at a time.
+pub const CHUNK : usize = 32;
+
+/// I2S words (sixteen PDM bits each) filtered per chunk.  Enough for
+/// `CHUNK` samples at the smallest decimation factor.
+const WORDS_PER_CHUNK : usize = CHUNK * 32 / 16;
+
+/// Ways that `PdmMic::start` can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum MicError {
+    /// The I2S clock can't be divided down to the PDM clock.
+    I2s(SpiError),
+    Dma(DoubleBufferError),
+}
+
+/// A PDM microphone on an I2S.
+pub struct PdmMic {
+    i2s: &'static Spi,
+    dma: DoubleBuffer<u16>,
+    /// Owned by the DMA stream's interrupt; `None` until started.
+    filter: IrqCell<Option<PdmFilter>>,
+}
+
+impl PdmMic {
+    /// Creates a driver for a microphone on `i2s`, using the DMA request
+    /// `rx`, whose stream raises `irq`.
+    pub const fn new(i2s: &'static Spi, rx: Request, irq: Interrupt)
+        -> PdmMic {
+        PdmMic {
+            i2s: i2s,
+            dma: DoubleBuffer::new(rx),
+            filter: IrqCell::new(irq as u32, None),
+        }
+    }
+
+    /// Starts capturing, clocking the microphone at `pdm_clock_hz` (derived
+    /// from the I2S clock, `i2s_clock_hz`) and delivering PCM at
+    /// `pdm_clock_hz / decimation.factor()`.  `buf0` and `buf1` receive the
+    /// raw PDM words; they must be the same length, a multiple of 32 words.
+    /// Returns the actual PDM clock.
+    ///
+    /// The I2S's pins and clock must already be set up, and the DMA
+    /// stream's interrupt routed to a handler that calls
+    /// `handle_interrupt`.
+    pub fn start(&self,
+                 pdm_clock_hz: u32,
+                 i2s_clock_hz: u32,
+                 decimation: Decimation,
+                 buf0: &'static mut [u16],
+                 buf1: &'static mut [u16])
+        -> Result<u32, MicError> {
+        // With 16-bit channels, the bit clock runs at 32 times the "sample
+        // rate"; the word select output goes unused.
+        let rate = try!(self.i2s.configure_i2s(i2s_clock_hz, &I2sConfig {
+            mode: I2sMode::MasterRx,
+            standard: I2sStandard::Lsb,
+            data: I2sDataLength::Bits16,
+            extended: false,
+            sample_rate: pdm_clock_hz / 32,
+            mclk_output: false,
+            clock_idle_high: true,
+        }).map_err(MicError::I2s));
+
+        self.filter.lock(|f| *f = Some(PdmFilter::new(decimation)));
+        try!(self.dma.start(self.i2s.dr_address(),
+                            Direction::PeripheralToMemory,
+                            buf0, buf1)
+             .map_err(MicError::Dma));
+        self.i2s.update_cr2(|v| v.with_rxdmaen(true));
+        self.i2s.set_i2s_enabled(true);
+        Ok(rate * 32)
+    }
+
+    /// Stops capturing.
+    pub fn stop(&self) {
+        self.i2s.set_i2s_enabled(false);
+        self.i2s.update_cr2(|v| v.with_rxdmaen(false));
+        self.dma.stop();
+        self.filter.lock(|f| *f = None)
+    }
+
+    /// To be called from the DMA stream's interrupt handler.  Filters each
+    /// buffer of PDM as it fills, passing the PCM to `callback` in chunks
+    /// of up to `CHUNK` samples.  Returns `false` if the stream has stopped
+    /// on an error.
+    pub fn handle_interrupt<F: FnMut(&[i16])>(&self, mut callback: F)
+        -> bool {
+        let mut ok = true;
+        self.filter.lock(|f| {
+            let filter = match f.as_mut() {
+                Some(filter) => filter,
+                None => return,
+            };
+            let _ = self.dma.handle_interrupt(|event, pdm| match event {
+                Event::TransferComplete(_) => {
+                    let mut pcm = [0; CHUNK];
+                    for words in pdm.chunks(WORDS_PER_CHUNK) {
+                        let n = filter.process(words, &mut pcm);
+                        callback(&pcm[..n])
+                    }
+                },
+                // Whole buffers are easier to keep track of.
+                Event::HalfTransfer(_) => (),
+                Event::Error => ok = false,
+            });
+        });
+        ok
+    }
+}
@@ -0,0 +1,414 @@
+//! RC receivers: SBUS and PPM-sum decoders.
+//!
+//! Both give channel values and a way to tell when the link has failed, so
+//! that a flight controller can do something sensible about it.
+//!
+//! # SBUS
+//!
+//! SBUS is a UART at 100000 baud, 8E2, sending a 25-byte frame every 7ms or
+//! 14ms: a 0x0F header, sixteen 11-bit channels packed LSB first, a flags
+//! byte, and a footer.  The signal is *inverted*, and the STM32F4 USARTs
+//! can't invert their inputs, so it needs an inverter in front of the RX pin
+//! (a transistor, or the one many flight controller boards already have).
+//!
+//!     let mut sbus = Sbus::new(&USART6);
+//!     try!(sbus.configure(clock::frozen()));
+//!     // ...then, often:
+//!     if let Some(frame) = sbus.poll(time::now_ms()) {
+//!         let roll = sbus_channel_us(frame.channels[0]);
+//!     }
+//!
+//! Frames are found by the gap between them, and checked by their header and
+//! footer bytes.  The receiver reports its own failsafe in the flags; `Sbus`
+//! also notices when frames stop arriving at all.
+//!
+//! # PPM
+//!
+//! PPM-sum sends the channels as the intervals between pulses on one wire,
+//! followed by a long gap to mark the end of the frame.  `Ppm` decodes the
+//! edge timestamps collected by a timer's input capture channel, which
+//! should be configured to capture one edge (whichever starts the pulses),
+//! with a timer prescaler giving at least 1MHz resolution:
+//!
+//!     static CAPTURE: Capture = Capture::new(&TIM3, Channel::Ch1);
+//!     CAPTURE.configure(&CaptureConfig {
+//!         timer_prescaler: 83,    // 1MHz from an 84MHz timer clock
+//!         .. CaptureConfig::default()
+//!     });
+//!     let mut ppm = Ppm::new(&CAPTURE, CAPTURE.tick_hz(clock::frozen()));
+//!
+//! PPM has no failsafe flag -- most receivers simply stop sending it -- so
+//! `Ppm` only reports the loss of frames.
+
+use hal::{NbError, SerialRead};
+use stm32f4::rcc::ClockSpeeds;
+use stm32f4::tim::Capture;
+use stm32f4::usart::{BaudConfig, BaudError, Parity, StopBits, Usart,
+                     WordLength};
+
+/// How long without a good frame before the link is considered lost.
+pub const SIGNAL_TIMEOUT_MS : u32 = 100;
+
+/// Largest number of channels either decoder produces.
+pub const MAX_CHANNELS : usize = 16;
+
+/// Converts a channel pulse width in microseconds to -1..1 around 1500us,
+/// clamped.
+pub fn normalize_us(us: u16) -> f32 {
+    let x = (us as f32 - 1500.) / 500.;
+    if x < -1. {
+        -1.
+    } else if x > 1. {
+        1.
+    } else {
+        x
+    }
+}
+
+/*****************************************************************************
+ * SBUS
+ */
+
+/// SBUS line rate.
+pub const SBUS_BAUD : u32 = 100_000;
+/// Bytes in an SBUS frame.
+pub const SBUS_FRAME_LEN : usize = 25;
+const SBUS_HEADER : u8 = 0x0F;
+/// Silence, in milliseconds, that marks the start of a frame.  A frame takes
+/// 3ms to send, and the gap between them is at least 4ms.
+const SBUS_GAP_MS : u32 = 2;
+
+/// Raw channel values sent at full stick by most receivers.  Under the
+/// usual scaling (see `sbus_channel_us`) they come to 988us and 2011us, a
+/// little beyond the nominal 1000-2000us, which are raw 192 and 1792.
+pub const SBUS_RAW_MIN : u16 = 172;
+pub const SBUS_RAW_MAX : u16 = 1811;
+
+/// Converts a raw SBUS channel value to the equivalent PWM pulse width in
+/// microseconds.
+pub fn sbus_channel_us(raw: u16) -> u16 {
+    ((raw as i32 - 992) * 5 / 8 + 1500) as u16
+}
+
+/// Checks an SBUS footer byte: 0x00, or any of the four SBUS2 slot markers.
+fn is_sbus_footer(b: u8) -> bool {
+    b == 0x00 || b & 0xCF == 0x04
+}
+
+/// One SBUS frame.
+#[derive(Copy, Clone, Debug)]
+pub struct SbusFrame {
+    /// Raw 11-bit channel values, nominally `SBUS_RAW_MIN` to `SBUS_RAW_MAX`.
+    pub channels: [u16; MAX_CHANNELS],
+    /// The two digital channels.
+    pub ch17: bool,
+    pub ch18: bool,
+    /// The receiver missed a frame from the transmitter.  Occasional lost
+    /// frames are normal.
+    pub frame_lost: bool,
+    /// The receiver has lost the transmitter and is in failsafe; the channel
+    /// values are whatever it was set to send then.
+    pub failsafe: bool,
+}
+
+impl SbusFrame {
+    /// Unpacks a frame, returning `None` if its header or footer are wrong.
+    pub fn parse(frame: &[u8; SBUS_FRAME_LEN]) -> Option<SbusFrame> {
+        if frame[0] != SBUS_HEADER || !is_sbus_footer(frame[24]) {
+            return None
+        }
+        let mut channels = [0; MAX_CHANNELS];
+        let mut acc = 0u32;
+        let mut bits = 0;
+        let mut ch = 0;
+        for &b in frame[1..23].iter() {
+            acc |= (b as u32) << bits;
+            bits += 8;
+            if bits >= 11 {
+                channels[ch] = (acc & 0x7FF) as u16;
+                ch += 1;
+                acc >>= 11;
+                bits -= 11
+            }
+        }
+        let flags = frame[23];
+        Some(SbusFrame {
+            channels: channels,
+            ch17: flags & 1 != 0,
+            ch18: flags & 2 != 0,
+            frame_lost: flags & 4 != 0,
+            failsafe: flags & 8 != 0,
+        })
+    }
+}
+
+/// Reassembles SBUS frames from received bytes.
+pub struct SbusDecoder {
+    buf: [u8; SBUS_FRAME_LEN],
+    len: usize,
+    /// Time of the last byte, for spotting the gap before a frame.
+    last_ms: Option<u32>,
+}
+
+impl SbusDecoder {
+    pub fn new() -> SbusDecoder {
+        SbusDecoder {
+            buf: [0; SBUS_FRAME_LEN],
+            len: 0,
+            last_ms: None,
+        }
+    }
+
+    /// Drops any partial frame and waits for the next gap.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.last_ms = None
+    }
+
+    /// Adds `byte`, received at `now_ms`.  Returns the frame it completes,
+    /// if any.
+    pub fn push(&mut self, byte: u8, now_ms: u32) -> Option<SbusFrame> {
+        let after_gap = match self.last_ms {
+            Some(t) => now_ms.wrapping_sub(t) >= SBUS_GAP_MS,
+            None => true,
+        };
+        self.last_ms = Some(now_ms);
+        if after_gap {
+            self.len = 0
+        }
+
+        // Frames start with the header after a gap.  Without a gap, we may
+        // be out of step: wait for one.
+        if self.len == 0 && (byte != SBUS_HEADER || !after_gap) {
+            return None
+        }
+        if self.len == SBUS_FRAME_LEN {
+            // More bytes than a frame holds: out of step.
+            return None
+        }
+        self.buf[self.len] = byte;
+        self.len += 1;
+        if self.len < SBUS_FRAME_LEN {
+            return None
+        }
+        SbusFrame::parse(&self.buf)
+    }
+}
+
+/// An SBUS receiver on a USART.
+pub struct Sbus<'a> {
+    usart: &'a Usart,
+    decoder: SbusDecoder,
+    /// Time of the last good frame.
+    last_frame_ms: Option<u32>,
+    errors: usize,
+}
+
+impl<'a> Sbus<'a> {
+    pub fn new(usart: &'a Usart) -> Sbus<'a> {
+        Sbus {
+            usart: usart,
+            decoder: SbusDecoder::new(),
+            last_frame_ms: None,
+            errors: 0,
+        }
+    }
+
+    /// Configures the USART for SBUS: 100000 baud, eight data bits, even
+    /// parity, two stop bits, receiver enabled.  Its clock must already be
+    /// enabled, and its RX pin routed (see `Usart::configure_pins`).
+    pub fn configure(&self, speeds: &ClockSpeeds)
+        -> Result<BaudConfig, BaudError> {
+        let u = self.usart;
+        u.update_cr1(|v| v.with_ue(false));
+        let cfg = try!(u.set_baud(speeds, SBUS_BAUD));
+        u.update_cr2(|v| v.with_stop(StopBits::Two));
+        // With parity, the ninth bit of the word carries it.
+        u.update_cr1(|v| v.with_m(WordLength::NineBits)
+                     .with_pce(true)
+                     .with_ps(Parity::Even)
+                     .with_re(true)
+                     .with_ue(true));
+        Ok(cfg)
+    }
+
+    /// Decodes whatever has been received, returning the latest complete
+    /// frame, if any.  This must be called more often than every byte time
+    /// (120us) unless something else -- DMA, or an interrupt-fed queue --
+    /// is keeping up with the USART; lost bytes just lose frames, though.
+    pub fn poll(&mut self, now_ms: u32) -> Option<SbusFrame> {
+        let mut latest = None;
+        loop {
+            match self.usart.try_read() {
+                Ok(b) => if let Some(f) = self.decoder.push(b, now_ms) {
+                    self.last_frame_ms = Some(now_ms);
+                    latest = Some(f)
+                },
+                Err(NbError::WouldBlock) => return latest,
+                Err(NbError::Other(_)) => {
+                    self.errors = self.errors.wrapping_add(1);
+                    self.decoder.reset()
+                },
+            }
+        }
+    }
+
+    /// Checks whether no good frame has arrived for `SIGNAL_TIMEOUT_MS`
+    /// (or ever).  Note that a receiver in failsafe usually keeps sending
+    /// frames, with the `failsafe` flag set.
+    pub fn is_signal_lost(&self, now_ms: u32) -> bool {
+        match self.last_frame_ms {
+            Some(t) => now_ms.wrapping_sub(t) > SIGNAL_TIMEOUT_MS,
+            None => true,
+        }
+    }
+
+    /// Number of bytes received with errors (parity, framing, noise or
+    /// overrun).
+    pub fn errors(&self) -> usize {
+        self.errors
+    }
+}
+
+/*****************************************************************************
+ * PPM
+ */
+
+/// Shortest gap, in microseconds, taken as the end of a frame.
+pub const PPM_SYNC_US : f32 = 2700.;
+/// Range of believable channel intervals, in microseconds.
+pub const PPM_MIN_US : f32 = 750.;
+pub const PPM_MAX_US : f32 = 2250.;
+/// Fewest channels in a believable frame.
+pub const PPM_MIN_CHANNELS : usize = 4;
+
+/// One PPM frame.
+#[derive(Copy, Clone, Debug)]
+pub struct PpmFrame {
+    /// Channel values in microseconds; only the first `count` are valid.
+    pub channels: [u16; MAX_CHANNELS],
+    pub count: usize,
+}
+
+impl PpmFrame {
+    pub fn channels(&self) -> &[u16] {
+        &self.channels[..self.count]
+    }
+}
+
+/// Reassembles PPM frames from edge timestamps.
+pub struct PpmDecoder {
+    /// Timestamp ticks per microsecond.
+    ticks_per_us: f32,
+    last_edge: Option<u32>,
+    /// Whether we've seen a sync gap, and are collecting channels.
+    synced: bool,
+    frame: PpmFrame,
+}
+
+impl PpmDecoder {
+    /// Creates a decoder for timestamps counting at `tick_hz`.
+    pub fn new(tick_hz: f32) -> PpmDecoder {
+        PpmDecoder {
+            ticks_per_us: tick_hz / 1_000_000.,
+            last_edge: None,
+            synced: false,
+            frame: PpmFrame {
+                channels: [0; MAX_CHANNELS],
+                count: 0,
+            },
+        }
+    }
+
+    /// Forgets any partial frame and waits for the next sync gap.
+    pub fn reset(&mut self) {
+        self.last_edge = None;
+        self.synced = false
+    }
+
+    /// Adds the timestamp of an edge.  Returns the frame that it completes,
+    /// if any.
+    pub fn push(&mut self, timestamp: u32) -> Option<PpmFrame> {
+        let last = self.last_edge;
+        self.last_edge = Some(timestamp);
+        let last = match last {
+            Some(t) => t,
+            None => return None,
+        };
+        let us = timestamp.wrapping_sub(last) as f32 / self.ticks_per_us;
+
+        if us >= PPM_SYNC_US {
+            let frame = self.frame;
+            let done = self.synced && frame.count >= PPM_MIN_CHANNELS;
+            self.synced = true;
+            self.frame.count = 0;
+            return if done { Some(frame) } else { None }
+        }
+
+        if !self.synced {
+            return None
+        }
+        if us < PPM_MIN_US || us > PPM_MAX_US
+            || self.frame.count == MAX_CHANNELS {
+            // Noise, or not PPM: start again at the next gap.
+            self.synced = false;
+            self.frame.count = 0;
+            return None
+        }
+        self.frame.channels[self.frame.count] = (us + 0.5) as u16;
+        self.frame.count += 1;
+        None
+    }
+}
+
+/// A PPM receiver on a timer capture channel.
+pub struct Ppm<'a> {
+    capture: &'a Capture,
+    decoder: PpmDecoder,
+    last_frame_ms: Option<u32>,
+    dropped: usize,
+}
+
+impl<'a> Ppm<'a> {
+    /// Decodes the timestamps collected by `capture`, which tick at
+    /// `tick_hz` (see `Capture::tick_hz`).  The capture channel must be
+    /// configured separately.
+    pub fn new(capture: &'a Capture, tick_hz: f32) -> Ppm<'a> {
+        Ppm {
+            capture: capture,
+            decoder: PpmDecoder::new(tick_hz),
+            last_frame_ms: None,
+            dropped: capture.dropped(),
+        }
+    }
+
+    /// Decodes the captured edges, returning the latest complete frame, if
+    /// any.  Call this more often than the capture FIFO fills -- every few
+    /// milliseconds.
+    pub fn poll(&mut self, now_ms: u32) -> Option<PpmFrame> {
+        // A lost edge would shift every channel after it.
+        let dropped = self.capture.dropped();
+        if dropped != self.dropped {
+            self.dropped = dropped;
+            self.decoder.reset()
+        }
+
+        let mut latest = None;
+        while let Some(t) = self.capture.pop() {
+            if let Some(f) = self.decoder.push(t) {
+                self.last_frame_ms = Some(now_ms);
+                latest = Some(f)
+            }
+        }
+        latest
+    }
+
+    /// Checks whether no good frame has arrived for `SIGNAL_TIMEOUT_MS`
+    /// (or ever).
+    pub fn is_signal_lost(&self, now_ms: u32) -> bool {
+        match self.last_frame_ms {
+            Some(t) => now_ms.wrapping_sub(t) > SIGNAL_TIMEOUT_MS,
+            None => true,
+        }
+    }
+}
@@ -0,0 +1,265 @@
+//! RC servos and ESCs (electronic speed controllers), driven by timer PWM.
+//!
+//! Both take a pulse every frame -- 20ms, classically, though many accept
+//! faster -- whose width, nominally 1000us to 2000us, sets the servo's
+//! position or the ESC's throttle.  `ServoTimer` sets up a timer for the
+//! frame rate, and hands out a `Servo` for each channel, which takes pulse
+//! widths in microseconds and does the conversion to compare counts.
+//!
+//!     let frame = try!(ServoTimer::configure_frozen(&TIM3, 50.));
+//!     let mut pan = try!(frame.servo(Channel::Ch1, servo::STANDARD));
+//!     try!(pan.route_pins(&Pins { port: gpioc, pins: P6 }));
+//!     pan.set_position(-0.5);
+//!
+//! Individual servos' travel varies, so each `Servo` has a `Calibration`
+//! limiting its pulses to a range it can follow:
+//!
+//!     let cal = Calibration::new(900, 1450, 2100).unwrap();
+//!
+//! # ESCs
+//!
+//! A motor that starts unexpectedly can do real damage, so `Esc` wraps a
+//! `Servo` with an arming state.  Until `arm` is called, and again after
+//! `disarm`, it sends zero throttle whatever is asked for.
+
+use clock;
+use stm32f4::gpio::Pins;
+use stm32f4::rcc::ClockSpeeds;
+use stm32f4::tim::{Channel, Polarity, Timebase, Timer};
+use stm32f4::tim::pwm::{Pwm, PwmError, PwmMode};
+
+/// The pulse widths a servo or ESC accepts.  Made by `new`, which checks
+/// that they're in order.
+#[derive(Copy, Clone, Debug)]
+pub struct Calibration {
+    /// Shortest pulse: full travel one way, or zero throttle.
+    min_us: u32,
+    /// Pulse giving the center position.
+    center_us: u32,
+    /// Longest pulse: full travel the other way, or full throttle.
+    max_us: u32,
+}
+
+impl Calibration {
+    /// Creates a calibration, or returns `None` unless
+    /// `0 < min_us < center_us < max_us`.
+    pub fn new(min_us: u32, center_us: u32, max_us: u32)
+        -> Option<Calibration> {
+        if 0 < min_us && min_us < center_us && center_us < max_us {
+            Some(Calibration {
+                min_us: min_us,
+                center_us: center_us,
+                max_us: max_us,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn min_us(&self) -> u32 {
+        self.min_us
+    }
+
+    pub fn center_us(&self) -> u32 {
+        self.center_us
+    }
+
+    pub fn max_us(&self) -> u32 {
+        self.max_us
+    }
+}
+
+/// The nominal 1000-2000us range.
+pub const STANDARD: Calibration = Calibration {
+    min_us: 1000,
+    center_us: 1500,
+    max_us: 2000,
+};
+
+/// Ways that ESC commands can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum EscError {
+    /// The ESC hasn't been armed.
+    Disarmed,
+}
+
+/// A timer generating servo frames.
+#[derive(Copy, Clone)]
+pub struct ServoTimer {
+    timer: &'static Timer,
+    /// Compare counts per microsecond of pulse.
+    counts_per_us: f32,
+}
+
+impl ServoTimer {
+    /// Configures `timer` to produce a frame (one pulse on each channel) at
+    /// `frame_hz`, and starts it.  All its channels are left disabled until
+    /// handed out by `servo`.  The timer's clock must already be enabled.
+    pub fn configure(timer: &'static Timer, speeds: &ClockSpeeds,
+                     frame_hz: f32)
+        -> Result<ServoTimer, PwmError> {
+        let tb = try!(Timebase::compute(timer.get_clock(speeds),
+                                        frame_hz,
+                                        timer.counter_max()));
+        timer.stop();
+        timer.set_timebase(tb);
+        timer.load_preloads();
+        timer.start();
+
+        let frame_us = 1_000_000. / tb.actual_hz;
+        Ok(ServoTimer {
+            timer: timer,
+            counts_per_us: (tb.arr as f32 + 1.) / frame_us,
+        })
+    }
+
+    /// Like `configure`, using the clock speeds recorded by `clock::freeze`.
+    ///
+    /// # Panics
+    ///
+    /// If the clock speeds have not been frozen.
+    pub fn configure_frozen(timer: &'static Timer, frame_hz: f32)
+        -> Result<ServoTimer, PwmError> {
+        ServoTimer::configure(timer, clock::frozen(), frame_hz)
+    }
+
+    /// Sets up `channel` as a servo output with calibration `cal`.  It
+    /// sends no pulses until given a position or pulse width.
+    pub fn servo(&self, channel: Channel, cal: Calibration)
+        -> Result<Servo, PwmError> {
+        let pwm = try!(Pwm::new(self.timer, channel));
+        pwm.configure_channel(PwmMode::Mode1, Polarity::ActiveHigh);
+        pwm.set_compare(0);
+        pwm.enable();
+        Ok(Servo {
+            pwm: pwm,
+            cal: cal,
+            counts_per_us: self.counts_per_us,
+            pulse_us: 0,
+        })
+    }
+}
+
+/// One servo output.
+pub struct Servo {
+    pwm: Pwm,
+    cal: Calibration,
+    counts_per_us: f32,
+    /// Current pulse width, or 0 for none.
+    pulse_us: u32,
+}
+
+impl Servo {
+    /// Routes `pins` to this servo's channel; see `Pwm::route_pins`.
+    pub fn route_pins(&self, pins: &Pins) -> Result<(), PwmError> {
+        self.pwm.route_pins(pins)
+    }
+
+    pub fn calibration(&self) -> Calibration {
+        self.cal
+    }
+
+    /// Sends pulses of `us` microseconds, clamped to the calibrated range,
+    /// from the next frame on.
+    pub fn set_pulse_us(&mut self, us: u32) {
+        let us = if us < self.cal.min_us {
+            self.cal.min_us
+        } else if us > self.cal.max_us {
+            self.cal.max_us
+        } else {
+            us
+        };
+        self.pulse_us = us;
+        self.pwm.set_compare((us as f32 * self.counts_per_us + 0.5) as u32)
+    }
+
+    /// Returns the width of the pulses being sent, or 0 if there are none.
+    pub fn pulse_us(&self) -> u32 {
+        self.pulse_us
+    }
+
+    /// Moves to `position`, from -1 (the minimum pulse) through 0 (the
+    /// center) to 1 (the maximum); values outside that range are clamped.
+    pub fn set_position(&mut self, position: f32) {
+        let cal = self.cal;
+        let us = if position < 0. {
+            let p = if position < -1. { -1. } else { position };
+            cal.center_us as f32 + p * (cal.center_us - cal.min_us) as f32
+        } else {
+            let p = if position > 1. { 1. } else { position };
+            cal.center_us as f32 + p * (cal.max_us - cal.center_us) as f32
+        };
+        self.set_pulse_us((us + 0.5) as u32)
+    }
+
+    /// Stops sending pulses.  Most servos then stop holding their position.
+    pub fn release(&mut self) {
+        self.pulse_us = 0;
+        self.pwm.set_compare(0)
+    }
+}
+
+/// An ESC, with an arming interlock.
+pub struct Esc {
+    servo: Servo,
+    armed: bool,
+}
+
+impl Esc {
+    /// Wraps `servo`, disarmed: it immediately starts sending zero
+    /// throttle, which most ESCs need to see before they'll run.
+    pub fn new(servo: Servo) -> Esc {
+        let mut esc = Esc {
+            servo: servo,
+            armed: false,
+        };
+        esc.idle();
+        esc
+    }
+
+    /// Allows `set_throttle` to run the motor, starting from zero.
+    pub fn arm(&mut self) {
+        self.idle();
+        self.armed = true
+    }
+
+    /// Drops to zero throttle and ignores further throttle settings until
+    /// `arm`.
+    pub fn disarm(&mut self) {
+        self.armed = false;
+        self.idle()
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.armed
+    }
+
+    /// Sets the throttle, from 0 to 1 (clamped), if armed.
+    pub fn set_throttle(&mut self, throttle: f32) -> Result<(), EscError> {
+        if !self.armed {
+            return Err(EscError::Disarmed)
+        }
+        let t = if throttle < 0. {
+            0.
+        } else if throttle > 1. {
+            1.
+        } else {
+            throttle
+        };
+        let cal = self.servo.cal;
+        let span = (cal.max_us - cal.min_us) as f32;
+        self.servo.set_pulse_us((cal.min_us as f32 + t * span + 0.5) as u32);
+        Ok(())
+    }
+
+    /// Gives back the servo output, disarmed and at zero throttle.
+    pub fn into_inner(mut self) -> Servo {
+        self.disarm();
+        self.servo
+    }
+
+    fn idle(&mut self) {
+        let min = self.servo.cal.min_us;
+        self.servo.set_pulse_us(min)
+    }
+}
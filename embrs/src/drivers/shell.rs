@@ -0,0 +1,441 @@
+//! A small interactive command shell, for poking at a running system.
+//!
+//! `Shell` reads lines from any `SerialRead` and writes to any `SerialWrite`
+//! -- usually both the same USART.  Lines can be edited with backspace,
+//! Ctrl-U (erase line), and Ctrl-C (abandon line), and the up and down arrow
+//! keys recall recent lines.  Each line is split at whitespace; the first
+//! word names a command, and the rest are its arguments.
+//!
+//! Applications register commands in a table:
+//!
+//!     fn blink(args: &mut Args, out: &mut Output)
+//!         -> Result<(), CommandError> {
+//!         let count = try!(args.number());
+//!         ...
+//!         writeln!(out, "blinked {} times", count)
+//!             .map_err(|_| CommandError::Output)
+//!     }
+//!
+//!     static COMMANDS: [Command; 1] = [
+//!         Command { name: "blink", help: "blink <count>", handler: blink },
+//!     ];
+//!
+//! A few commands are built in:
+//!
+//! - `help` lists the commands.
+//! - `peek <addr> [words]` displays memory, a word at a time.
+//! - `poke <addr> <value>` writes a word of memory.
+//! - `regs [name]` dumps the registers in the shell's register table,
+//!   decoded field by field (see `Register`).
+//!
+//! Input is handled a byte at a time by `poll`, which never blocks waiting
+//! for input, so the shell can be run from a main loop alongside other work.
+//! Output does wait for the transmitter.
+
+use core::fmt::{self, Write};
+use core::ptr;
+use core::str;
+
+use hal::{NbError, SerialRead, SerialWrite};
+
+/// Longest line the shell accepts.
+pub const LINE_LEN : usize = 80;
+/// Number of lines of history kept.
+pub const HISTORY_LEN : usize = 4;
+
+const PROMPT : &'static str = "> ";
+
+/// Ways a command can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum CommandError {
+    /// The arguments were wrong; the shell prints the command's help line.
+    Usage,
+    /// Writing the output failed.
+    Output,
+    /// The command failed, for the reason given.
+    Failed(&'static str),
+}
+
+/// Command handler functions.
+pub type Handler = fn(&mut Args, &mut Output) -> Result<(), CommandError>;
+
+/// A command in the shell's table.
+pub struct Command {
+    pub name: &'static str,
+    /// One line of help, shown by `help` and after usage errors.
+    pub help: &'static str,
+    pub handler: Handler,
+}
+
+/// A register the `regs` command can dump.
+///
+/// `fields`, if given, decodes the raw value.  For register wrappers declared
+/// with `bitfield_accessors!`, it's usually just a call to `fmt_fields`:
+///
+///     fn maccr_fields(v: u32, out: &mut Output) -> fmt::Result {
+///         eth::Maccr(v).fmt_fields(out)
+///     }
+pub struct Register {
+    pub name: &'static str,
+    pub address: usize,
+    pub fields: Option<fn(u32, &mut Output) -> fmt::Result>,
+}
+
+/// A command's arguments.
+pub struct Args<'l> {
+    words: str::SplitWhitespace<'l>,
+}
+
+impl<'l> Args<'l> {
+    /// Takes the next argument, if there is one.
+    pub fn next(&mut self) -> Option<&'l str> {
+        self.words.next()
+    }
+
+    /// Takes the next argument, which must be present.
+    pub fn word(&mut self) -> Result<&'l str, CommandError> {
+        self.next().ok_or(CommandError::Usage)
+    }
+
+    /// Takes the next argument as a number: decimal, or hex with a `0x`
+    /// prefix.
+    pub fn number(&mut self) -> Result<u32, CommandError> {
+        parse_number(try!(self.word()))
+    }
+
+    /// Takes the next argument as a number, if there is one.
+    pub fn optional_number(&mut self) -> Result<Option<u32>, CommandError> {
+        match self.next() {
+            Some(w) => parse_number(w).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Checks that all the arguments have been used.
+    pub fn finish(&mut self) -> Result<(), CommandError> {
+        match self.next() {
+            Some(_) => Err(CommandError::Usage),
+            None => Ok(()),
+        }
+    }
+}
+
+fn parse_number(w: &str) -> Result<u32, CommandError> {
+    let r = if w.starts_with("0x") || w.starts_with("0X") {
+        u32::from_str_radix(&w[2..], 16)
+    } else {
+        u32::from_str_radix(w, 10)
+    };
+    r.map_err(|_| CommandError::Usage)
+}
+
+/// Where command output goes.  Newlines are sent as CR-LF.
+pub struct Output<'w> {
+    w: &'w mut Write,
+}
+
+impl<'w> Write for Output<'w> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.w.write_str(s)
+    }
+}
+
+/// `fmt::Write` adapter for a `SerialWrite`, translating newlines.
+struct SerialFmt<'s, W: SerialWrite + 's>(&'s W);
+
+impl<'s, W: SerialWrite + 's> Write for SerialFmt<'s, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &b in s.as_bytes() {
+            if b == b'\n' {
+                try!(self.0.write(b'\r').map_err(|_| fmt::Error))
+            }
+            try!(self.0.write(b).map_err(|_| fmt::Error))
+        }
+        Ok(())
+    }
+}
+
+/// States of the escape sequence decoder.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Escape {
+    None,
+    /// Saw ESC.
+    Esc,
+    /// Saw ESC `[`.
+    Csi,
+}
+
+/// A command shell.
+pub struct Shell<'a, R: SerialRead + 'a, W: SerialWrite + 'a> {
+    input: &'a R,
+    output: &'a W,
+    commands: &'a [Command],
+    registers: &'a [Register],
+    line: [u8; LINE_LEN],
+    len: usize,
+    history: [[u8; LINE_LEN]; HISTORY_LEN],
+    history_lens: [usize; HISTORY_LEN],
+    /// Total lines ever added to the history.
+    history_count: usize,
+    /// How far back in the history the line came from (0 if it's new).
+    recall: usize,
+    escape: Escape,
+    /// The last byte was CR, so an LF should be ignored.
+    after_cr: bool,
+}
+
+impl<'a, R: SerialRead + 'a, W: SerialWrite + 'a> Shell<'a, R, W> {
+    pub fn new(input: &'a R,
+               output: &'a W,
+               commands: &'a [Command],
+               registers: &'a [Register]) -> Shell<'a, R, W> {
+        Shell {
+            input: input,
+            output: output,
+            commands: commands,
+            registers: registers,
+            line: [0; LINE_LEN],
+            len: 0,
+            history: [[0; LINE_LEN]; HISTORY_LEN],
+            history_lens: [0; HISTORY_LEN],
+            history_count: 0,
+            recall: 0,
+            escape: Escape::None,
+            after_cr: false,
+        }
+    }
+
+    fn out(&self) -> SerialFmt<W> {
+        SerialFmt(self.output)
+    }
+
+    /// Prints the prompt.  Call once at startup.
+    pub fn start(&mut self) {
+        let _ = self.out().write_str(PROMPT);
+    }
+
+    /// Processes any input that's waiting, running commands as lines are
+    /// completed.  Receive errors (noise, overruns) drop the affected byte.
+    pub fn poll(&mut self) {
+        loop {
+            match self.input.try_read() {
+                Ok(b) => self.input_byte(b),
+                Err(NbError::WouldBlock) => return,
+                Err(NbError::Other(_)) => continue,
+            }
+        }
+    }
+
+    fn input_byte(&mut self, b: u8) {
+        let after_cr = self.after_cr;
+        self.after_cr = b == b'\r';
+
+        match self.escape {
+            Escape::Esc => {
+                self.escape = if b == b'[' {
+                    Escape::Csi
+                } else {
+                    Escape::None
+                };
+                return
+            },
+            Escape::Csi => {
+                // Parameters and intermediates continue the sequence; a
+                // final byte ends it.
+                if b >= 0x40 {
+                    self.escape = Escape::None;
+                    match b {
+                        b'A' => self.recall_older(),
+                        b'B' => self.recall_newer(),
+                        _ => (),
+                    }
+                }
+                return
+            },
+            Escape::None => (),
+        }
+
+        match b {
+            b'\n' if after_cr => (),
+            b'\r' | b'\n' => {
+                let _ = self.out().write_str("\n");
+                if self.len > 0 {
+                    self.push_history();
+                    self.execute();
+                }
+                self.len = 0;
+                self.recall = 0;
+                let _ = self.out().write_str(PROMPT);
+            },
+            0x08 | 0x7F => if self.len > 0 {
+                self.len -= 1;
+                let _ = self.out().write_str("\x08 \x08");
+            },
+            0x03 => {
+                let _ = self.out().write_str("^C\n");
+                self.len = 0;
+                self.recall = 0;
+                let _ = self.out().write_str(PROMPT);
+            },
+            0x15 => self.erase_line(),
+            0x1B => self.escape = Escape::Esc,
+            0x20...0x7E => if self.len < LINE_LEN {
+                self.line[self.len] = b;
+                self.len += 1;
+                let _ = self.output.write(b);
+            },
+            _ => (),
+        }
+    }
+
+    /// Erases the line, on screen and in the buffer.
+    fn erase_line(&mut self) {
+        for _ in 0..self.len {
+            let _ = self.out().write_str("\x08 \x08");
+        }
+        self.len = 0
+    }
+
+    fn push_history(&mut self) {
+        let slot = self.history_count % HISTORY_LEN;
+        self.history[slot] = self.line;
+        self.history_lens[slot] = self.len;
+        self.history_count += 1
+    }
+
+    /// Replaces the line with the entry `self.recall` back in the history,
+    /// or an empty line for 0.
+    fn show_recalled(&mut self) {
+        self.erase_line();
+        if self.recall > 0 {
+            let slot = (self.history_count - self.recall) % HISTORY_LEN;
+            self.line = self.history[slot];
+            self.len = self.history_lens[slot];
+            let _ = self.output.write_all(&self.line[..self.len]);
+        }
+    }
+
+    fn recall_older(&mut self) {
+        let available = if self.history_count < HISTORY_LEN {
+            self.history_count
+        } else {
+            HISTORY_LEN
+        };
+        if self.recall < available {
+            self.recall += 1;
+            self.show_recalled()
+        }
+    }
+
+    fn recall_newer(&mut self) {
+        if self.recall > 0 {
+            self.recall -= 1;
+            self.show_recalled()
+        }
+    }
+
+    fn execute(&mut self) {
+        let mut fmt = SerialFmt(self.output);
+        let mut out = Output { w: &mut fmt };
+        // Only printable ASCII gets into the line.
+        let text = str::from_utf8(&self.line[..self.len]).unwrap_or("");
+        let mut args = Args { words: text.split_whitespace() };
+        let name = match args.next() {
+            Some(n) => n,
+            None => return,
+        };
+
+        let (result, help) = match name {
+            "help" => (self.help(&mut out), "help"),
+            "peek" => (peek(&mut args, &mut out), "peek <addr> [words]"),
+            "poke" => (poke(&mut args, &mut out), "poke <addr> <value>"),
+            "regs" => (self.regs(&mut args, &mut out), "regs [name]"),
+            _ => match self.commands.iter().find(|c| c.name == name) {
+                Some(c) => ((c.handler)(&mut args, &mut out), c.help),
+                None => {
+                    let _ = writeln!(out, "unknown command: {}", name);
+                    return
+                },
+            },
+        };
+
+        let _ = match result {
+            Ok(()) => Ok(()),
+            Err(CommandError::Usage) => writeln!(out, "usage: {}", help),
+            Err(CommandError::Output) => Ok(()),
+            Err(CommandError::Failed(why)) => writeln!(out, "error: {}", why),
+        };
+    }
+
+    fn help(&self, out: &mut Output) -> Result<(), CommandError> {
+        let builtins = ["help", "peek <addr> [words]", "poke <addr> <value>",
+                        "regs [name]"];
+        for h in builtins.iter().chain(self.commands.iter().map(|c| &c.help)) {
+            try!(writeln!(out, "  {}", h).map_err(|_| CommandError::Output))
+        }
+        Ok(())
+    }
+
+    fn regs(&self, args: &mut Args, out: &mut Output)
+        -> Result<(), CommandError> {
+        let only = args.next();
+        try!(args.finish());
+        let mut found = false;
+        for r in self.registers {
+            if only.map_or(false, |n| n != r.name) {
+                continue
+            }
+            found = true;
+            let v = unsafe { ptr::read_volatile(r.address as *const u32) };
+            try!(write!(out, "{:>10} {:08x}", r.name, v)
+                 .map_err(|_| CommandError::Output));
+            if let Some(f) = r.fields {
+                try!(out.write_str("  ")
+                     .and_then(|_| f(v, out))
+                     .map_err(|_| CommandError::Output))
+            }
+            try!(out.write_str("\n").map_err(|_| CommandError::Output))
+        }
+        if found {
+            Ok(())
+        } else {
+            Err(CommandError::Failed("no such register"))
+        }
+    }
+}
+
+/// Words per line of `peek` output.
+const PEEK_COLUMNS : u32 = 4;
+
+fn peek(args: &mut Args, out: &mut Output) -> Result<(), CommandError> {
+    let addr = try!(args.number());
+    let words = try!(args.optional_number()).unwrap_or(1);
+    try!(args.finish());
+    if addr & 3 != 0 {
+        return Err(CommandError::Failed("address must be word aligned"))
+    }
+    for i in 0..words {
+        let a = addr.wrapping_add(i * 4);
+        let v = unsafe { ptr::read_volatile(a as usize as *const u32) };
+        let r = if i % PEEK_COLUMNS == 0 {
+            let sep = if i == 0 { "" } else { "\n" };
+            write!(out, "{}{:08x}: {:08x}", sep, a, v)
+        } else {
+            write!(out, " {:08x}", v)
+        };
+        try!(r.map_err(|_| CommandError::Output))
+    }
+    writeln!(out, "").map_err(|_| CommandError::Output)
+}
+
+fn poke(args: &mut Args, out: &mut Output) -> Result<(), CommandError> {
+    let addr = try!(args.number());
+    let value = try!(args.number());
+    try!(args.finish());
+    if addr & 3 != 0 {
+        return Err(CommandError::Failed("address must be word aligned"))
+    }
+    unsafe { ptr::write_volatile(addr as usize as *mut u32, value) }
+    writeln!(out, "{:08x} <- {:08x}", addr, value)
+        .map_err(|_| CommandError::Output)
+}
@@ -0,0 +1,413 @@
+//! Serial Wire Debug host, bit-banged on two pins, for inspecting and
+//! programming another Cortex-M -- from a production test fixture, say, or
+//! from the main processor on a board with several.
+//!
+//! `SwdHost::connect` switches the target's debug port to SWD, reads its ID,
+//! and powers up its debug domain.  After that, `read_dp`/`write_dp` and
+//! `read_ap`/`write_ap` reach the Debug Port and Access Port registers, and
+//! the memory methods use the first MEM-AP (AP 0, the processor's view of
+//! memory on most parts) to read and write the target's address space --
+//! its RAM, its peripherals, and its Flash controller, through which its
+//! Flash can be programmed.  `halt`, `resume`, `reset_and_halt` and the
+//! core register methods drive the target's debug logic.
+//!
+//! SWCLK is any `DigitalOutput`; SWDIO must be a `DigitalInOut`, since it
+//! changes direction within each transaction.  `half_period_us` sets the
+//! clock rate; with zero, it runs as fast as the pins can be toggled, which
+//! on a 168MHz STM32F4 may be faster than some targets accept.
+
+use hal::{DelayUs, DigitalInOut, DigitalOutput};
+
+/// DP register addresses.
+pub const DP_IDR : u8 = 0x0;
+pub const DP_ABORT : u8 = 0x0;
+pub const DP_CTRL_STAT : u8 = 0x4;
+pub const DP_SELECT : u8 = 0x8;
+pub const DP_RDBUFF : u8 = 0xC;
+
+/// MEM-AP register addresses.
+pub const AP_CSW : u8 = 0x00;
+pub const AP_TAR : u8 = 0x04;
+pub const AP_DRW : u8 = 0x0C;
+pub const AP_IDR : u8 = 0xFC;
+
+const ACK_OK : u32 = 0b001;
+const ACK_WAIT : u32 = 0b010;
+const ACK_FAULT : u32 = 0b100;
+
+/// Sequence that switches a SWJ-DP from JTAG to SWD.
+const JTAG_TO_SWD : u32 = 0xE79E;
+
+/// `ABORT` bits clearing all the sticky error flags.
+const ABORT_CLEAR_ERRORS : u32 = 0x1E;
+/// `CTRL/STAT` power-up requests (system and debug).
+const CTRL_POWER_UP_REQ : u32 = (1 << 30) | (1 << 28);
+/// `CTRL/STAT` power-up acknowledgements.
+const CTRL_POWER_UP_ACK : u32 = (1 << 31) | (1 << 29);
+
+/// `CSW` for 32-bit accesses with auto-incrementing address: privileged,
+/// data, master type debug.
+const CSW_WORD_INC : u32 = 0x2300_0012;
+/// `TAR` auto-increment is only guaranteed within 1KiB.
+const TAR_WRAP : u32 = 1024;
+
+/// Debug Halting Control and Status Register.
+const DHCSR : u32 = 0xE000_EDF0;
+/// Debug Core Register Selector Register.
+const DCRSR : u32 = 0xE000_EDF4;
+/// Debug Core Register Data Register.
+const DCRDR : u32 = 0xE000_EDF8;
+/// Debug Exception and Monitor Control Register.
+const DEMCR : u32 = 0xE000_EDFC;
+/// Application Interrupt and Reset Control Register.
+const AIRCR : u32 = 0xE000_ED0C;
+
+const DHCSR_KEY : u32 = 0xA05F << 16;
+const DHCSR_DEBUGEN : u32 = 1 << 0;
+const DHCSR_HALT : u32 = 1 << 1;
+const DHCSR_S_REGRDY : u32 = 1 << 16;
+const DHCSR_S_HALT : u32 = 1 << 17;
+const DCRSR_WRITE : u32 = 1 << 16;
+const DEMCR_VC_CORERESET : u32 = 1 << 0;
+const AIRCR_SYSRESETREQ : u32 = (0x05FA << 16) | (1 << 2);
+
+/// Times a transaction is retried when the target answers WAIT.
+const WAIT_RETRIES : u32 = 100;
+/// Times a status bit is polled before giving up.
+const POLL_LIMIT : u32 = 1000;
+
+/// Ways that a debug transaction can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SwdError {
+    /// The target kept answering WAIT.
+    Wait,
+    /// The target answered FAULT; the sticky error flags have been
+    /// cleared.
+    Fault,
+    /// The target didn't answer sensibly -- it's probably not connected, or
+    /// has lost sync, and needs `connect` again.
+    Protocol,
+    /// Read data failed its parity check.
+    Parity,
+    /// A status bit never changed: the debug domain didn't power up, the
+    /// core didn't halt, or a core register transfer didn't finish.
+    Timeout,
+}
+
+/// An SWD host.
+pub struct SwdHost<'a, C, D, T>
+    where C: DigitalOutput + 'a, D: DigitalInOut + 'a, T: DelayUs + 'a {
+    swclk: &'a C,
+    swdio: &'a D,
+    delay: &'a T,
+    half_period_us: u32,
+    /// The last value written to `SELECT`, if known.
+    select: Option<u32>,
+}
+
+impl<'a, C, D, T> SwdHost<'a, C, D, T>
+    where C: DigitalOutput + 'a, D: DigitalInOut + 'a, T: DelayUs + 'a {
+    pub fn new(swclk: &'a C, swdio: &'a D, delay: &'a T, half_period_us: u32)
+        -> SwdHost<'a, C, D, T> {
+        SwdHost {
+            swclk: swclk,
+            swdio: swdio,
+            delay: delay,
+            half_period_us: half_period_us,
+            select: None,
+        }
+    }
+
+    /// Switches the target to SWD, clears any errors, and powers up its
+    /// debug domain.  Returns the contents of the DP's ID register.
+    pub fn connect(&mut self) -> Result<u32, SwdError> {
+        self.select = None;
+        self.swclk.set_high();
+        self.swdio.set_output_enabled(true);
+        self.line_reset();
+        self.clock_out(JTAG_TO_SWD, 16);
+        self.line_reset();
+        self.clock_out(0, 8);
+
+        // The ID must be read before anything else after a line reset.
+        let idr = try!(self.read_dp(DP_IDR));
+        try!(self.write_dp(DP_ABORT, ABORT_CLEAR_ERRORS));
+        try!(self.write_dp(DP_CTRL_STAT, CTRL_POWER_UP_REQ));
+        for _ in 0..POLL_LIMIT {
+            let status = try!(self.read_dp(DP_CTRL_STAT));
+            if status & CTRL_POWER_UP_ACK == CTRL_POWER_UP_ACK {
+                return Ok(idr)
+            }
+        }
+        Err(SwdError::Timeout)
+    }
+
+    /// Reads a Debug Port register.
+    pub fn read_dp(&mut self, addr: u8) -> Result<u32, SwdError> {
+        self.transfer(false, true, addr, 0)
+    }
+
+    /// Writes a Debug Port register.
+    pub fn write_dp(&mut self, addr: u8, value: u32) -> Result<(), SwdError> {
+        self.transfer(false, false, addr, value).map(|_| ())
+    }
+
+    /// Reads register `addr` of Access Port `ap`.
+    pub fn read_ap(&mut self, ap: u8, addr: u8) -> Result<u32, SwdError> {
+        try!(self.select_ap(ap, addr));
+        // AP reads are posted: this one returns stale data, and the result
+        // turns up in RDBUFF.
+        try!(self.transfer(true, true, addr, 0));
+        self.read_dp(DP_RDBUFF)
+    }
+
+    /// Writes register `addr` of Access Port `ap`.
+    pub fn write_ap(&mut self, ap: u8, addr: u8, value: u32)
+        -> Result<(), SwdError> {
+        try!(self.select_ap(ap, addr));
+        self.transfer(true, false, addr, value).map(|_| ())
+    }
+
+    /// Reads a word of target memory.
+    pub fn read_mem32(&mut self, address: u32) -> Result<u32, SwdError> {
+        try!(self.write_ap(0, AP_CSW, CSW_WORD_INC));
+        try!(self.write_ap(0, AP_TAR, address));
+        self.read_ap(0, AP_DRW)
+    }
+
+    /// Writes a word of target memory.
+    pub fn write_mem32(&mut self, address: u32, value: u32)
+        -> Result<(), SwdError> {
+        try!(self.write_ap(0, AP_CSW, CSW_WORD_INC));
+        try!(self.write_ap(0, AP_TAR, address));
+        try!(self.write_ap(0, AP_DRW, value));
+        self.finish_writes()
+    }
+
+    /// Reads consecutive words of target memory, starting at `address`
+    /// (which must be word-aligned).
+    pub fn read_mem(&mut self, address: u32, buf: &mut [u32])
+        -> Result<(), SwdError> {
+        try!(self.write_ap(0, AP_CSW, CSW_WORD_INC));
+        for (i, w) in buf.iter_mut().enumerate() {
+            let a = address.wrapping_add(4 * i as u32);
+            if i == 0 || a % TAR_WRAP == 0 {
+                try!(self.write_ap(0, AP_TAR, a))
+            }
+            *w = try!(self.read_ap(0, AP_DRW))
+        }
+        Ok(())
+    }
+
+    /// Writes consecutive words of target memory, starting at `address`
+    /// (which must be word-aligned).
+    pub fn write_mem(&mut self, address: u32, data: &[u32])
+        -> Result<(), SwdError> {
+        try!(self.write_ap(0, AP_CSW, CSW_WORD_INC));
+        for (i, &w) in data.iter().enumerate() {
+            let a = address.wrapping_add(4 * i as u32);
+            if i == 0 || a % TAR_WRAP == 0 {
+                try!(self.write_ap(0, AP_TAR, a))
+            }
+            try!(self.write_ap(0, AP_DRW, w))
+        }
+        self.finish_writes()
+    }
+
+    /// Waits for posted AP writes to land.  An AP write is acknowledged
+    /// before the target performs it, so a fault in the last one of a run
+    /// would otherwise go unnoticed; a DP read stalls until the write is
+    /// done, and reports FAULT if it failed.
+    fn finish_writes(&mut self) -> Result<(), SwdError> {
+        self.read_dp(DP_RDBUFF).map(|_| ())
+    }
+
+    /// Halts the target's processor, and waits for it to stop.
+    pub fn halt(&mut self) -> Result<(), SwdError> {
+        try!(self.write_mem32(DHCSR,
+                              DHCSR_KEY | DHCSR_DEBUGEN | DHCSR_HALT));
+        self.wait_dhcsr(DHCSR_S_HALT)
+    }
+
+    /// Lets the target's processor run again.
+    pub fn resume(&mut self) -> Result<(), SwdError> {
+        self.write_mem32(DHCSR, DHCSR_KEY | DHCSR_DEBUGEN)
+    }
+
+    /// Checks whether the target's processor is halted.
+    pub fn is_halted(&mut self) -> Result<bool, SwdError> {
+        Ok(try!(self.read_mem32(DHCSR)) & DHCSR_S_HALT != 0)
+    }
+
+    /// Resets the target, halting its processor before it runs the first
+    /// instruction -- the usual state for programming Flash.
+    pub fn reset_and_halt(&mut self) -> Result<(), SwdError> {
+        try!(self.write_mem32(DHCSR, DHCSR_KEY | DHCSR_DEBUGEN));
+        try!(self.write_mem32(DEMCR, DEMCR_VC_CORERESET));
+        // The target may reset before it acknowledges this.
+        let _ = self.write_mem32(AIRCR, AIRCR_SYSRESETREQ);
+        let result = self.wait_dhcsr(DHCSR_S_HALT);
+        try!(self.write_mem32(DEMCR, 0));
+        result
+    }
+
+    /// Reads core register `reg` (0-12 are r0-r12, 13 is sp, 14 lr, 15 the
+    /// debug return address, 16 xPSR) of the halted processor.
+    pub fn read_core_register(&mut self, reg: u32) -> Result<u32, SwdError> {
+        try!(self.write_mem32(DCRSR, reg));
+        try!(self.wait_dhcsr(DHCSR_S_REGRDY));
+        self.read_mem32(DCRDR)
+    }
+
+    /// Writes core register `reg` of the halted processor.
+    pub fn write_core_register(&mut self, reg: u32, value: u32)
+        -> Result<(), SwdError> {
+        try!(self.write_mem32(DCRDR, value));
+        try!(self.write_mem32(DCRSR, reg | DCRSR_WRITE));
+        self.wait_dhcsr(DHCSR_S_REGRDY)
+    }
+
+    fn wait_dhcsr(&mut self, bit: u32) -> Result<(), SwdError> {
+        for _ in 0..POLL_LIMIT {
+            if try!(self.read_mem32(DHCSR)) & bit != 0 {
+                return Ok(())
+            }
+        }
+        Err(SwdError::Timeout)
+    }
+
+    fn select_ap(&mut self, ap: u8, addr: u8) -> Result<(), SwdError> {
+        let select = (ap as u32) << 24 | (addr as u32 & 0xF0);
+        if self.select != Some(select) {
+            try!(self.write_dp(DP_SELECT, select));
+            self.select = Some(select);
+        }
+        Ok(())
+    }
+
+    /// Performs a transaction, retrying while the target answers WAIT, and
+    /// clearing the sticky errors if it answers FAULT.
+    fn transfer(&mut self, ap: bool, read: bool, addr: u8, value: u32)
+        -> Result<u32, SwdError> {
+        for _ in 0..WAIT_RETRIES {
+            match self.raw_transfer(ap, read, addr, value) {
+                Err(SwdError::Wait) => continue,
+                Err(SwdError::Fault) => {
+                    let _ = self.raw_transfer(false, false, DP_ABORT,
+                                              ABORT_CLEAR_ERRORS);
+                    return Err(SwdError::Fault)
+                }
+                Err(SwdError::Protocol) => {
+                    self.select = None;
+                    return Err(SwdError::Protocol)
+                }
+                result => return result,
+            }
+        }
+        Err(SwdError::Wait)
+    }
+
+    /// Performs one transaction: request, ACK, and data.
+    fn raw_transfer(&mut self, ap: bool, read: bool, addr: u8, value: u32)
+        -> Result<u32, SwdError> {
+        let bits = (ap as u32)
+            | (read as u32) << 1
+            | ((addr as u32 >> 2) & 0b11) << 2;
+        let parity = bits.count_ones() & 1;
+        // Start, the four bits, parity, stop, park.
+        let request = 1 | bits << 1 | parity << 5 | 1 << 7;
+        self.clock_out(request, 8);
+
+        self.turn_to_target();
+        let ack = self.clock_in(3);
+        match ack {
+            ACK_OK if read => {
+                let data = self.clock_in(32);
+                let parity = self.clock_in(1);
+                self.turn_to_host();
+                self.clock_out(0, 8);
+                if data.count_ones() & 1 == parity {
+                    Ok(data)
+                } else {
+                    Err(SwdError::Parity)
+                }
+            }
+            ACK_OK => {
+                self.turn_to_host();
+                self.clock_out(value, 32);
+                self.clock_out(value.count_ones() & 1, 1);
+                // Idle cycles give the target time to finish the write.
+                self.clock_out(0, 8);
+                Ok(0)
+            }
+            _ => {
+                self.turn_to_host();
+                self.clock_out(0, 8);
+                Err(match ack {
+                    ACK_WAIT => SwdError::Wait,
+                    ACK_FAULT => SwdError::Fault,
+                    _ => SwdError::Protocol,
+                })
+            }
+        }
+    }
+
+    /// Holds SWDIO high for more than 50 cycles, which resets the target's
+    /// SWD state machine.
+    fn line_reset(&self) {
+        self.clock_out(!0, 32);
+        self.clock_out(!0, 24);
+    }
+
+    /// Drives the low `n` bits of `bits` onto SWDIO, least significant
+    /// first.
+    fn clock_out(&self, bits: u32, n: u32) {
+        for i in 0..n {
+            self.swdio.set_state(bits >> i & 1 != 0);
+            self.swclk.set_low();
+            self.half_period();
+            self.swclk.set_high();
+            self.half_period();
+        }
+    }
+
+    /// Samples `n` bits from SWDIO, least significant first.
+    fn clock_in(&self, n: u32) -> u32 {
+        let mut bits = 0;
+        for i in 0..n {
+            self.swclk.set_low();
+            self.half_period();
+            if self.swdio.is_high() {
+                bits |= 1 << i
+            }
+            self.swclk.set_high();
+            self.half_period();
+        }
+        bits
+    }
+
+    /// Releases SWDIO, and gives the target a cycle to take it.
+    fn turn_to_target(&self) {
+        self.swdio.set_output_enabled(false);
+        self.cycle();
+    }
+
+    /// Gives the target a cycle to release SWDIO, and takes it back.
+    fn turn_to_host(&self) {
+        self.cycle();
+        self.swdio.set_output_enabled(true);
+    }
+
+    fn cycle(&self) {
+        self.swclk.set_low();
+        self.half_period();
+        self.swclk.set_high();
+        self.half_period();
+    }
+
+    fn half_period(&self) {
+        if self.half_period_us != 0 {
+            self.delay.delay_us(self.half_period_us)
+        }
+    }
+}
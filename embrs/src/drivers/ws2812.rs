@@ -0,0 +1,369 @@
+//! WS2812 ("NeoPixel") and compatible RGB LED strips.
+//!
+//! The WS2812 takes a self-clocked serial stream at 800 kbit/s: each bit is a
+//! 1.25us period, high for about 0.4us for a zero or 0.8us for a one.  Each
+//! LED takes the first 24 bits (green, red, blue, most significant bit
+//! first) and passes the rest along the strip; holding the line low for a
+//! while (280us for current parts) latches the colors and readies the strip
+//! for the next frame.
+//!
+//! `Ws2812` generates the stream with DMA, in one of two ways:
+//!
+//! - `Output::Pwm`: a timer channel runs PWM at 800 kHz, and DMA, triggered
+//!   by each update event, loads the compare value for the next bit.  The
+//!   DMA request must be the timer's update request (e.g. `Tim4Up` for
+//!   TIM4), and the channel's pin must already be routed (see
+//!   `Pwm::route_pins`).
+//! - `Output::Spi`: the SPI's MOSI sends each bit as four SPI bits, `1000`
+//!   for a zero and `1100` for a one, at about 2.6 MHz.  Only MOSI needs
+//!   routing to a pin.  The DMA request must be the SPI's TX request.
+//!
+//! Rather than encode the whole strip at once -- 48 bytes per LED with PWM
+//! -- `Ws2812` streams through two short buffers, encoding the next chunk
+//! of the frame into one while DMA sends the other.  Each buffer must last
+//! longer than the DMA interrupt can be kept waiting; 48 words (two LEDs,
+//! or eight with SPI) gives 60us.  The stream runs from `start` to `stop`,
+//! holding the line low between frames, so the interrupt keeps firing even
+//! when nothing is being shown.
+//!
+//! The colors come from two frames of `Rgb`, also double-buffered: the
+//! application draws into the back frame with `with_frame`, and `show` swaps
+//! it to the front once the frame being sent has finished, so a frame is
+//! never sent half-drawn.  After the swap, the back frame holds the frame
+//! before last.
+//!
+//!     static LEDS: Ws2812 = Ws2812::new(Output::Pwm(&TIM4, Channel::Ch1),
+//!                                       Request::Tim4Up,
+//!                                       Interrupt::Dma1Stream6);
+//!
+//!     try!(LEDS.start(speeds, &mut DMA0, &mut DMA1, &mut FRAME0,
+//!                     &mut FRAME1));
+//!     loop {
+//!         LEDS.with_frame(|leds| { /* ... */ });
+//!         LEDS.show();
+//!     }
+//!
+//!     extern "C" fn dma1_stream6_isr() {
+//!         LEDS.handle_interrupt();
+//!     }
+
+use core::slice;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use stm32f4::dma::{Direction, DoubleBuffer, Request};
+use stm32f4::dma::double_buffer::{DoubleBufferError, Event};
+use stm32f4::irq::Interrupt;
+use stm32f4::rcc::ClockSpeeds;
+use stm32f4::spi::{DataFrame, FrameFormat, MasterConfig, Spi, SpiError,
+                   SpiMode};
+use stm32f4::tim::{Channel, Timer};
+use stm32f4::tim::pwm::{Pwm, PwmError, PwmMode};
+use sync::IrqCell;
+
+/// Bit rate of the stream.
+const BIT_HZ : u32 = 800_000;
+/// Low time after a frame, with some margin over the 280us that current
+/// parts need.
+const LATCH_US : u32 = 300;
+/// SPI clocks at which the four-bit encoding meets the WS2812's timing.
+const SPI_MIN_HZ : u32 = 2_200_000;
+const SPI_MAX_HZ : u32 = 3_400_000;
+
+/// The color of one LED.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+/// The peripheral generating the stream.
+#[derive(Copy, Clone)]
+pub enum Output {
+    /// A channel of a timer with a 16-bit counter (not TIM2 or TIM5).
+    Pwm(&'static Timer, Channel),
+    /// An SPI, as master.
+    Spi(&'static Spi),
+}
+
+/// Ways that `Ws2812::start` can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Ws2812Error {
+    Pwm(PwmError),
+    Spi(SpiError),
+    Dma(DoubleBufferError),
+    /// The timer has 32-bit compare registers, which the 16-bit DMA
+    /// transfers can't load.
+    WideTimer,
+    /// The SPI's clock can't be divided to a rate the encoding works at.
+    SpiClock,
+    /// The two frames differ in length.
+    FrameMismatch,
+}
+
+/// How bits become DMA words.
+#[derive(Copy, Clone)]
+enum Encoding {
+    /// One compare value per bit.
+    Pwm { zero: u16, one: u16 },
+    /// Four SPI bits per bit, four bits to a word.
+    Spi,
+}
+
+/// Where the stream is up to.
+#[derive(Copy, Clone)]
+enum Phase {
+    /// Between frames, holding the line low.
+    Idle,
+    /// Sending the bit at this index in the front frame.
+    Data(usize),
+    /// Holding the line low for this many more bits.
+    Latch(u32),
+}
+
+/// Encoder state, owned by the DMA interrupt.
+struct Cursor {
+    phase: Phase,
+    encoding: Encoding,
+    latch_bits: u32,
+}
+
+/// A strip of WS2812s on a timer channel or SPI.
+pub struct Ws2812 {
+    output: Output,
+    dma: DoubleBuffer<u16>,
+    /// Addresses of the two frames.
+    frames: [AtomicUsize; 2],
+    /// LEDs in each frame.
+    leds: AtomicUsize,
+    /// Index of the frame being sent.
+    front: AtomicUsize,
+    /// Set by `show`, cleared when the frames have been swapped.
+    pending: AtomicBool,
+    cursor: IrqCell<Cursor>,
+}
+
+impl Ws2812 {
+    /// Creates a driver for a strip on `output`, using the DMA request
+    /// `request`, whose stream raises `irq`.
+    pub const fn new(output: Output, request: Request, irq: Interrupt)
+        -> Ws2812 {
+        Ws2812 {
+            output: output,
+            dma: DoubleBuffer::new(request),
+            frames: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            leds: AtomicUsize::new(0),
+            front: AtomicUsize::new(0),
+            pending: AtomicBool::new(false),
+            cursor: IrqCell::new(irq as u32, Cursor {
+                phase: Phase::Idle,
+                encoding: Encoding::Spi,
+                latch_bits: 0,
+            }),
+        }
+    }
+
+    /// Configures the output and starts streaming, with the line held low
+    /// until the first `show`.  `buf0` and `buf1` are the DMA buffers, which
+    /// must be the same length; `frame0` and `frame1` hold the colors, one
+    /// `Rgb` per LED, and must also be the same length.
+    ///
+    /// The peripheral's clock must already be enabled, and the DMA stream's
+    /// interrupt routed to a handler that calls `handle_interrupt`.
+    pub fn start(&self,
+                 speeds: &ClockSpeeds,
+                 buf0: &'static mut [u16],
+                 buf1: &'static mut [u16],
+                 frame0: &'static mut [Rgb],
+                 frame1: &'static mut [Rgb])
+        -> Result<(), Ws2812Error> {
+        if frame0.len() != frame1.len() {
+            return Err(Ws2812Error::FrameMismatch)
+        }
+
+        let (encoding, latch_bits) = try!(self.configure(speeds));
+        self.frames[0].store(frame0.as_ptr() as usize, Ordering::Relaxed);
+        self.frames[1].store(frame1.as_ptr() as usize, Ordering::Relaxed);
+        self.leds.store(frame0.len(), Ordering::Relaxed);
+        self.front.store(0, Ordering::Relaxed);
+        self.pending.store(false, Ordering::Release);
+
+        self.cursor.lock(|c| {
+            *c = Cursor {
+                phase: Phase::Latch(latch_bits),
+                encoding: encoding,
+                latch_bits: latch_bits,
+            };
+            self.fill(c, buf0);
+            self.fill(c, buf1);
+        });
+
+        let peripheral = match self.output {
+            Output::Pwm(timer, channel) => timer.ccr_address(channel),
+            Output::Spi(spi) => spi.dr_address(),
+        };
+        try!(self.dma.start(peripheral, Direction::MemoryToPeripheral,
+                            buf0, buf1)
+             .map_err(Ws2812Error::Dma));
+        match self.output {
+            Output::Pwm(timer, _) => timer.update_dier(|v| v.with_ude(true)),
+            Output::Spi(spi) => spi.update_cr2(|v| v.with_txdmaen(true)),
+        }
+        Ok(())
+    }
+
+    /// Stops streaming, leaving the line low.
+    pub fn stop(&self) {
+        match self.output {
+            Output::Pwm(timer, channel) => {
+                timer.update_dier(|v| v.with_ude(false));
+                timer.set_compare(channel, 0)
+            }
+            Output::Spi(spi) => spi.update_cr2(|v| v.with_txdmaen(false)),
+        }
+        self.dma.stop()
+    }
+
+    /// Runs `f` on the back frame, if it's free: it isn't while a `show` is
+    /// waiting for the frame being sent to finish, and this returns `None`.
+    pub fn with_frame<R, F>(&self, f: F) -> Option<R>
+        where F: FnOnce(&mut [Rgb]) -> R {
+        if self.pending.load(Ordering::Acquire) {
+            return None
+        }
+        let back = 1 - self.front.load(Ordering::Relaxed);
+        Some(f(self.frame(back)))
+    }
+
+    /// Swaps the back frame to the front once the frame being sent (if any)
+    /// has finished, and sends it.
+    pub fn show(&self) {
+        self.pending.store(true, Ordering::Release)
+    }
+
+    /// Checks whether a `show` is still waiting to take effect.
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    /// To be called from the DMA stream's interrupt handler.  Encodes the
+    /// next chunk of the stream into the buffer DMA has just finished with.
+    /// Returns `false` if the stream has stopped on an error.
+    pub fn handle_interrupt(&self) -> bool {
+        let mut ok = true;
+        self.cursor.lock(|c| {
+            let _ = self.dma.handle_interrupt(|event, words| match event {
+                Event::TransferComplete(_) => self.fill(c, words),
+                Event::HalfTransfer(_) => (),
+                Event::Error => ok = false,
+            });
+        });
+        ok
+    }
+
+    /// Sets up the peripheral, returning the encoding and the number of
+    /// bits in the latch time.
+    fn configure(&self, speeds: &ClockSpeeds)
+        -> Result<(Encoding, u32), Ws2812Error> {
+        match self.output {
+            Output::Pwm(timer, channel) => {
+                if timer.counter_max() > 0xFFFF {
+                    return Err(Ws2812Error::WideTimer)
+                }
+                let pwm = try!(Pwm::new(timer, channel)
+                               .map_err(Ws2812Error::Pwm));
+                let _ = try!(pwm.configure(speeds, BIT_HZ as f32, 0.,
+                                           PwmMode::Mode1)
+                             .map_err(Ws2812Error::Pwm));
+                let period = pwm.get_period();
+                let encoding = Encoding::Pwm {
+                    zero: ((period * 8 + 12) / 25) as u16,   // 0.32
+                    one: ((period * 16 + 12) / 25) as u16,   // 0.64
+                };
+                Ok((encoding, LATCH_US * BIT_HZ / 1_000_000))
+            }
+            Output::Spi(spi) => {
+                let hz = try!(spi.configure_master(speeds, &MasterConfig {
+                    mode: SpiMode::Mode0,
+                    max_clock_hz: SPI_MAX_HZ,
+                    frame: DataFrame::Bits16,
+                    lsb_first: false,
+                    format: FrameFormat::Motorola,
+                    crc: None,
+                }).map_err(Ws2812Error::Spi));
+                if hz < SPI_MIN_HZ {
+                    return Err(Ws2812Error::SpiClock)
+                }
+                Ok((Encoding::Spi, LATCH_US * (hz / 4) / 1_000_000 + 1))
+            }
+        }
+    }
+
+    fn frame(&self, i: usize) -> &mut [Rgb] {
+        let p = self.frames[i].load(Ordering::Relaxed) as *mut Rgb;
+        let len = self.leds.load(Ordering::Relaxed);
+        unsafe { slice::from_raw_parts_mut(p, len) }
+    }
+
+    /// Encodes the next `words.len()` words of the stream.
+    fn fill(&self, c: &mut Cursor, words: &mut [u16]) {
+        let encoding = c.encoding;
+        for w in words.iter_mut() {
+            *w = match encoding {
+                Encoding::Pwm { zero, one } => match self.next_bit(c) {
+                    Some(true) => one,
+                    Some(false) => zero,
+                    None => 0,
+                },
+                Encoding::Spi => {
+                    let mut word = 0;
+                    for _ in 0..4 {
+                        word = word << 4 | match self.next_bit(c) {
+                            Some(true) => 0b1100,
+                            Some(false) => 0b1000,
+                            None => 0,
+                        }
+                    }
+                    word
+                }
+            }
+        }
+    }
+
+    /// Produces the next bit of the stream, or `None` where the line should
+    /// stay low.
+    fn next_bit(&self, c: &mut Cursor) -> Option<bool> {
+        loop {
+            match c.phase {
+                Phase::Idle => {
+                    if !self.pending.load(Ordering::Acquire) {
+                        return None
+                    }
+                    let back = 1 - self.front.load(Ordering::Relaxed);
+                    self.front.store(back, Ordering::Relaxed);
+                    self.pending.store(false, Ordering::Release);
+                    c.phase = Phase::Data(0)
+                }
+                Phase::Data(i) => {
+                    let frame = self.frame(self.front.load(Ordering::Relaxed));
+                    if i < frame.len() * 24 {
+                        let led = frame[i / 24];
+                        let byte = match i % 24 / 8 {
+                            0 => led.g,
+                            1 => led.r,
+                            _ => led.b,
+                        };
+                        c.phase = Phase::Data(i + 1);
+                        return Some(byte & (0x80 >> (i % 8)) != 0)
+                    }
+                    c.phase = Phase::Latch(c.latch_bits)
+                }
+                Phase::Latch(0) => c.phase = Phase::Idle,
+                Phase::Latch(n) => {
+                    c.phase = Phase::Latch(n - 1);
+                    return None
+                }
+            }
+        }
+    }
+}
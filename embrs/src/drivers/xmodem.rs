@@ -0,0 +1,466 @@
+//! XMODEM-CRC and YMODEM file transfer, for loading firmware over a serial
+//! port with nothing more than a terminal program.
+//!
+//! `Xmodem` runs either end of a transfer over any `SerialRead` and
+//! `SerialWrite` -- usually both the same USART.  Received data is handed
+//! to a *sink* callback as each packet arrives, along with its offset in
+//! the file, so it can be programmed straight into Flash (see
+//! `boot::slots`); sent data is fetched from a *source* callback the same
+//! way.  Either callback can fail, which cancels the transfer.
+//!
+//! Only the CRC variant of XMODEM is supported, with 128-byte packets when
+//! sending and either size when receiving.  YMODEM adds a header packet
+//! carrying the file's name and size, so the receiver can drop the padding
+//! XMODEM leaves at the end, and uses 1KiB packets.  `receive_file` takes
+//! one file, and cancels a batch of more than one after the first.
+//!
+//! Timeouts use `time::now_ms`, so `time::start` must have been called.
+//! Transfers block until they finish, and use a 1KiB packet buffer on the
+//! stack.
+
+use core::cmp;
+
+use crc::crc16_xmodem;
+use hal::{NbError, SerialRead, SerialWrite};
+use time;
+
+/// Starts a 128-byte packet.
+const SOH : u8 = 0x01;
+/// Starts a 1KiB packet.
+const STX : u8 = 0x02;
+/// Ends the transfer.
+const EOT : u8 = 0x04;
+const ACK : u8 = 0x06;
+const NAK : u8 = 0x15;
+/// Two in a row cancel the transfer.
+const CAN : u8 = 0x18;
+/// Pads the last packet.
+const SUB : u8 = 0x1A;
+/// Sent by the receiver to ask for a CRC-checked transfer.
+const CRC_REQUEST : u8 = b'C';
+
+/// How long the receiver waits for a packet before asking again, and the
+/// sender waits for a reply before retrying.
+const PACKET_TIMEOUT_MS : u32 = 3000;
+/// How long to wait for each byte within a packet.
+const BYTE_TIMEOUT_MS : u32 = 1000;
+/// How long the sender waits for the receiver to start.
+const START_TIMEOUT_MS : u32 = 60000;
+/// Consecutive failures after which a transfer is abandoned.
+const MAX_ERRORS : u32 = 10;
+
+/// Ways that a transfer can fail.  Except for `Serial` and `Cancelled`, the
+/// other end is told that the transfer has been cancelled.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum XmodemError<E, C> {
+    /// Writing to the serial port failed.
+    Serial(E),
+    /// The other end never started.
+    Timeout,
+    /// The other end cancelled the transfer.
+    Cancelled,
+    /// Too many packets in a row were corrupted or went unanswered.
+    TooManyErrors,
+    /// A packet arrived out of sequence, so some data has been lost.
+    Sequence,
+    /// The sink or source callback failed.
+    Callback(C),
+    /// A YMODEM header was malformed, or the file name didn't fit.
+    BadHeader,
+}
+
+/// Describes a file received by `Xmodem::receive_file`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct FileInfo {
+    /// Length of the name copied into the caller's buffer.
+    pub name_len: usize,
+    /// Size according to the header, if the sender gave one.
+    pub size: Option<usize>,
+    /// Bytes passed to the sink.
+    pub received: usize,
+}
+
+/// Result of reading a packet.
+enum Packet {
+    /// A packet with this block number and length, now in the buffer.
+    Data(u8, usize),
+    Eot,
+    Cancel,
+    /// Something arrived, but not an intact packet.
+    Bad,
+    Timeout,
+}
+
+/// One end of a transfer.
+pub struct Xmodem<'a, R: SerialRead + 'a, W: SerialWrite + 'a> {
+    input: &'a R,
+    output: &'a W,
+}
+
+impl<'a, R, W> Xmodem<'a, R, W>
+    where R: SerialRead + 'a, W: SerialWrite<Error = R::Error> + 'a {
+    pub fn new(input: &'a R, output: &'a W) -> Xmodem<'a, R, W> {
+        Xmodem {
+            input: input,
+            output: output,
+        }
+    }
+
+    /// Receives a file by XMODEM-CRC, passing its contents to `sink` a
+    /// packet at a time.  The sender pads the end of the file to a whole
+    /// packet, and the padding reaches `sink` too.  Returns the number of
+    /// bytes received.
+    pub fn receive<C, F>(&self, mut sink: F)
+        -> Result<usize, XmodemError<R::Error, C>>
+        where F: FnMut(usize, &[u8]) -> Result<(), C> {
+        let mut buf = [0; 1024];
+        self.receive_blocks(&mut buf, false, None, &mut sink)
+    }
+
+    /// Receives a file by YMODEM, copying its name into `name` and passing
+    /// its contents to `sink` a packet at a time.  Returns `None` if the
+    /// sender had no file to send.
+    pub fn receive_file<C, F>(&self, name: &mut [u8], mut sink: F)
+        -> Result<Option<FileInfo>, XmodemError<R::Error, C>>
+        where F: FnMut(usize, &[u8]) -> Result<(), C> {
+        let mut buf = [0; 1024];
+        let len = try!(self.receive_header(&mut buf));
+        if buf[0] == 0 {
+            try!(self.put(ACK));
+            return Ok(None)
+        }
+
+        let (name_len, size) = {
+            let header = &buf[..len];
+            let name_len =
+                header.iter().position(|&b| b == 0).unwrap_or(len);
+            if name_len > name.len() {
+                return Err(self.cancel(XmodemError::BadHeader))
+            }
+            name[..name_len].copy_from_slice(&header[..name_len]);
+            match parse_size(&header[cmp::min(name_len + 1, len)..]) {
+                Ok(size) => (name_len, size),
+                Err(()) => return Err(self.cancel(XmodemError::BadHeader)),
+            }
+        };
+        try!(self.put(ACK));
+
+        let received =
+            try!(self.receive_blocks(&mut buf, true, size, &mut sink));
+
+        // The sender ends the batch with an empty header.
+        try!(self.receive_header(&mut buf));
+        if buf[0] == 0 {
+            try!(self.put(ACK))
+        } else {
+            // Only one file was wanted.
+            let _ = self.output.write_all(&[CAN, CAN, CAN]);
+        }
+
+        Ok(Some(FileInfo {
+            name_len: name_len,
+            size: size,
+            received: received,
+        }))
+    }
+
+    /// Sends `size` bytes by XMODEM-CRC, fetching them from `source` a
+    /// packet at a time.  The last packet is padded with SUB (0x1A).
+    pub fn send<C, F>(&self, size: usize, mut source: F)
+        -> Result<(), XmodemError<R::Error, C>>
+        where F: FnMut(usize, &mut [u8]) -> Result<(), C> {
+        try!(self.await_request());
+        self.send_blocks(size, false, &mut source)
+    }
+
+    /// Sends `size` bytes as a file called `name` by YMODEM, fetching them
+    /// from `source` a packet at a time.
+    pub fn send_file<C, F>(&self, name: &str, size: usize, mut source: F)
+        -> Result<(), XmodemError<R::Error, C>>
+        where F: FnMut(usize, &mut [u8]) -> Result<(), C> {
+        let mut header = [0; 128];
+        // Room for the name, its NUL, and up to 20 digits.
+        if name.len() + 21 > header.len() {
+            return Err(XmodemError::BadHeader)
+        }
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        format_size(&mut header[name.len() + 1..], size);
+
+        try!(self.await_request());
+        try!(self.send_packet(0, &header));
+        try!(self.await_request());
+        try!(self.send_blocks(size, true, &mut source));
+
+        // End the batch.
+        try!(self.await_request());
+        self.send_packet(0, &[0; 128])
+    }
+
+    /// Receives data packets, up to and including EOT.  If `limit` is given,
+    /// anything past it is padding, and isn't passed to `sink`.
+    fn receive_blocks<C, F>(&self,
+                            buf: &mut [u8; 1024],
+                            ymodem: bool,
+                            limit: Option<usize>,
+                            sink: &mut F)
+        -> Result<usize, XmodemError<R::Error, C>>
+        where F: FnMut(usize, &[u8]) -> Result<(), C> {
+        let mut expected = 1u8;
+        let mut offset = 0;
+        let mut errors = 0;
+        let mut started = false;
+        let mut eot_seen = false;
+        let mut reply = Some(CRC_REQUEST);
+
+        loop {
+            if let Some(r) = reply.take() {
+                try!(self.put(r))
+            }
+
+            match self.read_packet(buf) {
+                Packet::Data(block, len) if block == expected => {
+                    started = true;
+                    errors = 0;
+                    let n = match limit {
+                        Some(l) => cmp::min(len, l.saturating_sub(offset)),
+                        None => len,
+                    };
+                    if n > 0 {
+                        if let Err(e) = (*sink)(offset, &buf[..n]) {
+                            return Err(self.cancel(XmodemError::Callback(e)))
+                        }
+                    }
+                    offset += n;
+                    expected = expected.wrapping_add(1);
+                    reply = Some(ACK);
+                }
+                Packet::Data(block, _)
+                    if block == expected.wrapping_sub(1) => {
+                    // A repeat: our ACK was lost.
+                    reply = Some(ACK);
+                }
+                Packet::Data(..) => {
+                    return Err(self.cancel(XmodemError::Sequence))
+                }
+                Packet::Eot => {
+                    if ymodem && !eot_seen {
+                        // YMODEM senders expect the first EOT to be refused,
+                        // as a check that it wasn't line noise.
+                        eot_seen = true;
+                        reply = Some(NAK);
+                    } else {
+                        try!(self.put(ACK));
+                        return Ok(offset)
+                    }
+                }
+                Packet::Cancel => return Err(XmodemError::Cancelled),
+                Packet::Bad | Packet::Timeout => {
+                    errors += 1;
+                    reply = Some(if started { NAK } else { CRC_REQUEST });
+                }
+            }
+
+            if errors >= MAX_ERRORS {
+                return Err(self.cancel(XmodemError::TooManyErrors))
+            }
+        }
+    }
+
+    /// Asks for a YMODEM header until one arrives, returning its length.
+    fn receive_header<C>(&self, buf: &mut [u8; 1024])
+        -> Result<usize, XmodemError<R::Error, C>> {
+        for _ in 0..MAX_ERRORS {
+            try!(self.put(CRC_REQUEST));
+            match self.read_packet(buf) {
+                Packet::Data(0, len) => return Ok(len),
+                Packet::Cancel => return Err(XmodemError::Cancelled),
+                _ => (),
+            }
+        }
+        Err(self.cancel(XmodemError::Timeout))
+    }
+
+    /// Reads a packet into `buf`, discarding the rest of the line if it's
+    /// corrupt.
+    fn read_packet(&self, buf: &mut [u8; 1024]) -> Packet {
+        let len = match self.read_byte(PACKET_TIMEOUT_MS) {
+            Some(SOH) => 128,
+            Some(STX) => 1024,
+            Some(EOT) => return Packet::Eot,
+            Some(CAN) => {
+                return match self.read_byte(BYTE_TIMEOUT_MS) {
+                    Some(CAN) => Packet::Cancel,
+                    _ => Packet::Bad,
+                }
+            }
+            Some(_) => {
+                self.purge();
+                return Packet::Bad
+            }
+            None => return Packet::Timeout,
+        };
+
+        let mut header = [0; 2];
+        let mut trailer = [0; 2];
+        let complete = self.read_exact(&mut header)
+            && self.read_exact(&mut buf[..len])
+            && self.read_exact(&mut trailer);
+        let crc = (trailer[0] as u16) << 8 | trailer[1] as u16;
+        if !complete || header[0] != !header[1]
+            || crc != crc16_xmodem(&buf[..len]) {
+            self.purge();
+            return Packet::Bad
+        }
+        Packet::Data(header[0], len)
+    }
+
+    /// Sends data packets for `size` bytes, then EOT.  YMODEM uses 1KiB
+    /// packets, except at the end.
+    fn send_blocks<C, F>(&self, size: usize, ymodem: bool, source: &mut F)
+        -> Result<(), XmodemError<R::Error, C>>
+        where F: FnMut(usize, &mut [u8]) -> Result<(), C> {
+        let mut buf = [0; 1024];
+        let mut block = 1u8;
+        let mut offset = 0;
+        while offset < size {
+            let len = if ymodem && size - offset > 128 { 1024 } else { 128 };
+            let n = cmp::min(len, size - offset);
+            if let Err(e) = (*source)(offset, &mut buf[..n]) {
+                return Err(self.cancel(XmodemError::Callback(e)))
+            }
+            for b in &mut buf[n..len] {
+                *b = SUB
+            }
+            try!(self.send_packet(block, &buf[..len]));
+            offset += n;
+            block = block.wrapping_add(1);
+        }
+
+        for _ in 0..MAX_ERRORS {
+            try!(self.put(EOT));
+            match self.read_byte(PACKET_TIMEOUT_MS) {
+                Some(ACK) => return Ok(()),
+                Some(CAN) if self.read_byte(BYTE_TIMEOUT_MS) == Some(CAN) => {
+                    return Err(XmodemError::Cancelled)
+                }
+                _ => (),
+            }
+        }
+        Err(self.cancel(XmodemError::TooManyErrors))
+    }
+
+    /// Sends one packet, repeating it until it's acknowledged.
+    fn send_packet<C>(&self, block: u8, data: &[u8])
+        -> Result<(), XmodemError<R::Error, C>> {
+        let start = if data.len() == 1024 { STX } else { SOH };
+        let crc = crc16_xmodem(data);
+        for _ in 0..MAX_ERRORS {
+            try!(self.output.write_all(&[start, block, !block])
+                 .map_err(XmodemError::Serial));
+            try!(self.output.write_all(data).map_err(XmodemError::Serial));
+            try!(self.output.write_all(&[(crc >> 8) as u8, crc as u8])
+                 .map_err(XmodemError::Serial));
+            match self.read_byte(PACKET_TIMEOUT_MS) {
+                Some(ACK) => return Ok(()),
+                Some(CAN) if self.read_byte(BYTE_TIMEOUT_MS) == Some(CAN) => {
+                    return Err(XmodemError::Cancelled)
+                }
+                _ => self.purge(),
+            }
+        }
+        Err(self.cancel(XmodemError::TooManyErrors))
+    }
+
+    /// Waits for the receiver to ask for a CRC-checked transfer.
+    fn await_request<C>(&self) -> Result<(), XmodemError<R::Error, C>> {
+        let deadline = time::now_ms().wrapping_add(START_TIMEOUT_MS);
+        while !time::deadline_passed(time::now_ms(), deadline) {
+            match self.read_byte(PACKET_TIMEOUT_MS) {
+                Some(CRC_REQUEST) => return Ok(()),
+                Some(CAN) if self.read_byte(BYTE_TIMEOUT_MS) == Some(CAN) => {
+                    return Err(XmodemError::Cancelled)
+                }
+                _ => (),
+            }
+        }
+        Err(self.cancel(XmodemError::Timeout))
+    }
+
+    /// Tells the other end the transfer is over, and returns `e`.
+    fn cancel<C>(&self, e: XmodemError<R::Error, C>)
+        -> XmodemError<R::Error, C> {
+        let _ = self.output.write_all(&[CAN, CAN, CAN]);
+        e
+    }
+
+    fn put<C>(&self, byte: u8) -> Result<(), XmodemError<R::Error, C>> {
+        self.output.write(byte).map_err(XmodemError::Serial)
+    }
+
+    /// Waits up to `timeout_ms` for a byte.  Receive errors (overruns,
+    /// framing errors) count as garbage, to be dealt with by retrying.
+    fn read_byte(&self, timeout_ms: u32) -> Option<u8> {
+        let deadline = time::now_ms().wrapping_add(timeout_ms);
+        loop {
+            match self.input.try_read() {
+                Ok(b) => return Some(b),
+                Err(NbError::Other(_)) => return Some(0),
+                Err(NbError::WouldBlock) => {
+                    if time::deadline_passed(time::now_ms(), deadline) {
+                        return None
+                    }
+                }
+            }
+        }
+    }
+
+    fn read_exact(&self, buf: &mut [u8]) -> bool {
+        for b in buf {
+            match self.read_byte(BYTE_TIMEOUT_MS) {
+                Some(v) => *b = v,
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Discards input until the line goes quiet, so that the next read
+    /// starts at a packet boundary.
+    fn purge(&self) {
+        while self.read_byte(BYTE_TIMEOUT_MS).is_some() {}
+    }
+}
+
+/// Parses the decimal size that follows the name in a YMODEM header, if
+/// present.
+fn parse_size(field: &[u8]) -> Result<Option<usize>, ()> {
+    let mut size: Option<usize> = None;
+    for &b in field {
+        if b < b'0' || b > b'9' {
+            break
+        }
+        let digit = (b - b'0') as usize;
+        size = Some(try!(size.unwrap_or(0).checked_mul(10)
+                         .and_then(|v| v.checked_add(digit))
+                         .ok_or(())));
+    }
+    Ok(size)
+}
+
+/// Writes `size` in decimal at the start of `out`, which must be long
+/// enough.
+fn format_size(out: &mut [u8], mut size: usize) {
+    let mut digits = [0; 20];
+    let mut n = 0;
+    loop {
+        digits[n] = b'0' + (size % 10) as u8;
+        n += 1;
+        size /= 10;
+        if size == 0 {
+            break
+        }
+    }
+    for i in 0..n {
+        out[i] = digits[n - 1 - i]
+    }
+}
@@ -0,0 +1,129 @@
+//! Smoothing filters for noisy readings.
+//!
+//! `MovingAverage` is the mean of the last N samples: cheap, and good at
+//! taking the edge off noise.  `Median` is the median of the last N: dearer,
+//! but it ignores isolated spikes entirely rather than smearing them, which
+//! suits sensors with occasional wild readings.
+//!
+//! Both keep their history in buffers the caller provides, whose length
+//! sets N, and both produce output from the first sample, over however many
+//! samples they've seen until the window fills.
+
+use super::Q15;
+
+/// Mean of the most recent samples.
+pub struct MovingAverage<'a> {
+    window: &'a mut [Q15],
+    /// Index of the oldest sample, once the window is full.
+    pos: usize,
+    count: usize,
+    sum: i32,
+}
+
+impl<'a> MovingAverage<'a> {
+    /// Creates a filter averaging over `window.len()` samples.  The window
+    /// can hold up to 65536 samples without the sum overflowing.
+    pub fn new(window: &'a mut [Q15]) -> MovingAverage<'a> {
+        assert!(!window.is_empty() && window.len() <= 65536);
+        MovingAverage {
+            window: window,
+            pos: 0,
+            count: 0,
+            sum: 0,
+        }
+    }
+
+    /// Forgets all samples.
+    pub fn reset(&mut self) {
+        self.pos = 0;
+        self.count = 0;
+        self.sum = 0;
+    }
+
+    /// Adds `x` and returns the new average.
+    pub fn push(&mut self, x: Q15) -> Q15 {
+        if self.count == self.window.len() {
+            self.sum -= self.window[self.pos] as i32
+        } else {
+            self.count += 1
+        }
+        self.window[self.pos] = x;
+        self.sum += x as i32;
+        self.pos = (self.pos + 1) % self.window.len();
+        self.average()
+    }
+
+    /// Gets the current average, or zero if there are no samples.
+    pub fn average(&self) -> Q15 {
+        if self.count == 0 {
+            0
+        } else {
+            (self.sum / self.count as i32) as i16
+        }
+    }
+}
+
+/// Median of the most recent samples.
+pub struct Median<'a> {
+    /// Samples in arrival order, as a ring.
+    history: &'a mut [Q15],
+    /// The same samples, sorted.
+    sorted: &'a mut [Q15],
+    pos: usize,
+    count: usize,
+}
+
+impl<'a> Median<'a> {
+    /// Creates a filter over `history.len()` samples; `sorted` is working
+    /// space of the same length.  Odd lengths make the most sense.  Each
+    /// sample costs time proportional to the length, so keep it short.
+    ///
+    /// # Panics
+    ///
+    /// If the buffers are empty or differ in length.
+    pub fn new(history: &'a mut [Q15], sorted: &'a mut [Q15]) -> Median<'a> {
+        assert!(!history.is_empty() && history.len() == sorted.len());
+        Median {
+            history: history,
+            sorted: sorted,
+            pos: 0,
+            count: 0,
+        }
+    }
+
+    /// Forgets all samples.
+    pub fn reset(&mut self) {
+        self.pos = 0;
+        self.count = 0;
+    }
+
+    /// Adds `x` and returns the new median.  With an even number of
+    /// samples, it's the upper of the middle two.
+    pub fn push(&mut self, x: Q15) -> Q15 {
+        let n = self.count;
+        let mut len = n;
+        if n == self.history.len() {
+            // Drop the oldest from the sorted copy.
+            let old = self.history[self.pos];
+            if let Some(i) = self.sorted[..n].iter().position(|&v| v == old) {
+                for j in i..n - 1 {
+                    self.sorted[j] = self.sorted[j + 1]
+                }
+            }
+            len -= 1
+        } else {
+            self.count += 1
+        }
+        self.history[self.pos] = x;
+        self.pos = (self.pos + 1) % self.history.len();
+
+        // Insert the newest, keeping order.
+        let mut i = len;
+        while i > 0 && self.sorted[i - 1] > x {
+            self.sorted[i] = self.sorted[i - 1];
+            i -= 1
+        }
+        self.sorted[i] = x;
+        self.sorted[self.count / 2]
+    }
+}
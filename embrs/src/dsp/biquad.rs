@@ -0,0 +1,169 @@
+//! Second-order IIR ("biquad") filter sections.
+//!
+//! Each `Biquad` computes
+//!
+//!     y[n] = b0 x[n] + b1 x[n-1] + b2 x[n-2] - a1 y[n-1] - a2 y[n-2]
+//!
+//! in direct form I, with Q31 samples and Q2.30 coefficients (`i32`s
+//! representing -2.0 to just under 2.0, which covers every stable section).
+//! Higher-order filters are cascades of sections; see `process_cascade`.
+//!
+//! `Coefficients::low_pass` and friends design sections from a corner
+//! frequency and Q, following Robert Bristow-Johnson's "Audio EQ Cookbook".
+//! They use floating point, so they're best called once at startup.
+
+use core::f32::consts::PI;
+
+use super::{sat_q31, Q15, Q31};
+
+/// Coefficients of one section, in Q2.30, with `a0` normalized to 1.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Coefficients {
+    pub b0: i32,
+    pub b1: i32,
+    pub b2: i32,
+    pub a1: i32,
+    pub a2: i32,
+}
+
+/// The section that passes its input unchanged.
+pub const IDENTITY: Coefficients = Coefficients {
+    b0: 1 << 30,
+    b1: 0,
+    b2: 0,
+    a1: 0,
+    a2: 0,
+};
+
+/// The Butterworth Q, giving the flattest passband for a single section.
+pub const BUTTERWORTH_Q : f32 = 0.70710678;
+
+impl Coefficients {
+    /// Low-pass, with its corner at `freq_hz` for sample rate `rate_hz`.
+    pub fn low_pass(freq_hz: f32, rate_hz: f32, q: f32) -> Coefficients {
+        let (cos, alpha) = prewarp(freq_hz, rate_hz, q);
+        let b1 = 1.0 - cos;
+        normalize(b1 / 2.0, b1, b1 / 2.0, 1.0 + alpha, -2.0 * cos,
+                  1.0 - alpha)
+    }
+
+    /// High-pass, with its corner at `freq_hz` for sample rate `rate_hz`.
+    pub fn high_pass(freq_hz: f32, rate_hz: f32, q: f32) -> Coefficients {
+        let (cos, alpha) = prewarp(freq_hz, rate_hz, q);
+        let b1 = 1.0 + cos;
+        normalize(b1 / 2.0, -b1, b1 / 2.0, 1.0 + alpha, -2.0 * cos,
+                  1.0 - alpha)
+    }
+
+    /// Band-pass centered on `freq_hz`, with unity gain at the center and
+    /// a bandwidth of about `freq_hz / q`.
+    pub fn band_pass(freq_hz: f32, rate_hz: f32, q: f32) -> Coefficients {
+        let (cos, alpha) = prewarp(freq_hz, rate_hz, q);
+        normalize(alpha, 0.0, -alpha, 1.0 + alpha, -2.0 * cos, 1.0 - alpha)
+    }
+
+    /// Notch at `freq_hz` -- mains hum, say -- about `freq_hz / q` wide.
+    pub fn notch(freq_hz: f32, rate_hz: f32, q: f32) -> Coefficients {
+        let (cos, alpha) = prewarp(freq_hz, rate_hz, q);
+        normalize(1.0, -2.0 * cos, 1.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha)
+    }
+}
+
+/// Computes the cookbook's `cos(w0)` and `alpha` for a corner at `freq_hz`.
+fn prewarp(freq_hz: f32, rate_hz: f32, q: f32) -> (f32, f32) {
+    let w0 = 2.0 * PI * freq_hz / rate_hz;
+    let (sin, cos) = sin_cos(w0);
+    (cos, sin / (2.0 * q))
+}
+
+/// Divides through by `a0` and converts to Q2.30.
+fn normalize(b0: f32, b1: f32, b2: f32, a0: f32, a1: f32, a2: f32)
+    -> Coefficients {
+    Coefficients {
+        b0: q30(b0 / a0),
+        b1: q30(b1 / a0),
+        b2: q30(b2 / a0),
+        a1: q30(a1 / a0),
+        a2: q30(a2 / a0),
+    }
+}
+
+fn q30(x: f32) -> i32 {
+    sat_q31((x * 1073741824.0) as i64)
+}
+
+/// Sine and cosine of `x`, which must be in [0, pi], to within about 1e-7.
+/// (`core` has no trigonometry.)
+fn sin_cos(x: f32) -> (f32, f32) {
+    // Shift to [-pi/2, pi/2], where the Taylor series converge quickly:
+    // sin(x) = cos(t) and cos(x) = -sin(t), for t = x - pi/2.
+    let t = x - PI / 2.0;
+    let t2 = t * t;
+    let sin_t = t * (1.0 - t2 / 6.0 * (1.0 - t2 / 20.0 * (1.0 - t2 / 42.0
+        * (1.0 - t2 / 72.0 * (1.0 - t2 / 110.0)))));
+    let cos_t = 1.0 - t2 / 2.0 * (1.0 - t2 / 12.0 * (1.0 - t2 / 30.0
+        * (1.0 - t2 / 56.0 * (1.0 - t2 / 90.0 * (1.0 - t2 / 132.0)))));
+    (cos_t, -sin_t)
+}
+
+/// One filter section and its history.
+#[derive(Clone, Debug)]
+pub struct Biquad {
+    coeffs: Coefficients,
+    x1: Q31,
+    x2: Q31,
+    y1: Q31,
+    y2: Q31,
+}
+
+impl Biquad {
+    pub const fn new(coeffs: Coefficients) -> Biquad {
+        Biquad {
+            coeffs: coeffs,
+            x1: 0,
+            x2: 0,
+            y1: 0,
+            y2: 0,
+        }
+    }
+
+    /// Changes the coefficients, keeping the history, so a filter can be
+    /// retuned on the fly (with a small glitch).
+    pub fn set_coefficients(&mut self, coeffs: Coefficients) {
+        self.coeffs = coeffs
+    }
+
+    /// Forgets the history, as if the input had always been zero.
+    pub fn reset(&mut self) {
+        self.x1 = 0;
+        self.x2 = 0;
+        self.y1 = 0;
+        self.y2 = 0;
+    }
+
+    /// Filters one sample.
+    pub fn process(&mut self, x: Q31) -> Q31 {
+        let c = &self.coeffs;
+        let acc = c.b0 as i64 * x as i64
+            + c.b1 as i64 * self.x1 as i64
+            + c.b2 as i64 * self.x2 as i64
+            - c.a1 as i64 * self.y1 as i64
+            - c.a2 as i64 * self.y2 as i64;
+        let y = sat_q31(acc >> 30);
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+
+    /// Filters one `Q15` sample, keeping full precision internally.
+    pub fn process_q15(&mut self, x: Q15) -> Q15 {
+        (self.process((x as i32) << 16) >> 16) as i16
+    }
+}
+
+/// Runs `x` through each section of `stages` in turn.
+pub fn process_cascade(stages: &mut [Biquad], x: Q31) -> Q31 {
+    stages.iter_mut().fold(x, |v, s| s.process(v))
+}
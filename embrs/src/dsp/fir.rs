@@ -0,0 +1,75 @@
+//! Finite impulse response filters.
+//!
+//! A `Fir` computes `y[n] = sum(h[k] x[n-k])` over `Q15` coefficients `h`
+//! and samples `x`, both of which the caller provides storage for:
+//!
+//!     static TAPS: [Q15; 16] = [ /* ... */ ];
+//!     let mut state = [0; 32];
+//!     let mut fir = Fir::new(&TAPS, &mut state);
+//!
+//! The state buffer must be twice the length of the coefficients.  Each
+//! sample is stored twice, half a buffer apart, so that the last N samples
+//! are always contiguous and the inner loop can run over them without
+//! wrapping -- two at a time, with SMLAD, on the Cortex-M4.
+//!
+//! The accumulator doesn't saturate: coefficients whose absolute values sum
+//! to less than 2.0 are safe for any input.
+
+use super::{dot_q15, sat_q15, Q15};
+
+pub struct Fir<'a> {
+    coeffs: &'a [Q15],
+    state: &'a mut [Q15],
+    /// Index of the newest sample in `state` (and, plus the number of
+    /// taps, its copy).
+    pos: usize,
+}
+
+impl<'a> Fir<'a> {
+    /// Creates a filter with the given coefficients, and history buffer,
+    /// which is cleared.
+    ///
+    /// # Panics
+    ///
+    /// If `state` isn't twice as long as `coeffs`.
+    pub fn new(coeffs: &'a [Q15], state: &'a mut [Q15]) -> Fir<'a> {
+        assert!(state.len() == 2 * coeffs.len());
+        for s in state.iter_mut() {
+            *s = 0
+        }
+        Fir {
+            coeffs: coeffs,
+            state: state,
+            pos: 0,
+        }
+    }
+
+    /// Forgets the history, as if the input had always been zero.
+    pub fn reset(&mut self) {
+        for s in self.state.iter_mut() {
+            *s = 0
+        }
+    }
+
+    /// Filters one sample.
+    pub fn process(&mut self, x: Q15) -> Q15 {
+        let n = self.coeffs.len();
+        if n == 0 {
+            return 0
+        }
+        // Walk backwards, so that the window starting at `pos` runs from
+        // newest to oldest, lining up with the coefficients.
+        self.pos = if self.pos == 0 { n - 1 } else { self.pos - 1 };
+        self.state[self.pos] = x;
+        self.state[self.pos + n] = x;
+        let window = &self.state[self.pos..self.pos + n];
+        sat_q15(dot_q15(self.coeffs, window) >> 15)
+    }
+
+    /// Filters `input` into `output`, which must be at least as long.
+    pub fn process_block(&mut self, input: &[Q15], output: &mut [Q15]) {
+        for (o, &x) in output.iter_mut().zip(input) {
+            *o = self.process(x)
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Signal processing helpers.
+//!
+//! Everything here is fixed-point, so it runs at a predictable cost on parts
+//! without an FPU (and without waking the FPU on parts that have one), and
+//! none of it allocates: filter state lives in the filter structs, or in
+//! buffers the caller provides, which can sit in a `static` (usually inside
+//! an `IrqCell`, since filters tend to run in the interrupt handler that
+//! collects their input).
+//!
+//! Samples are Q15 (`i16`, representing -1.0 to just under 1.0) or Q31
+//! (`i32`, likewise); the helpers below convert and combine them.
+//!
+//! - `biquad` has second-order IIR sections, and functions designing the
+//!   usual low-pass, high-pass, band-pass, and notch responses.
+//! - `fir` has FIR filters with caller-supplied coefficients and state.
+//! - `average` has moving-average and median filters, for smoothing and
+//!   de-spiking sensor readings.
+//! - `pdm` converts the one-bit output of a PDM microphone to PCM samples.
+//!
+//! On the Cortex-M4, the inner loops use its dual 16-bit multiply-accumulate
+//! (SMLAD) and saturation instructions, through `arm_m::simd`.
+
+pub mod average;
+pub mod biquad;
+pub mod fir;
+pub mod pdm;
+
+use arm_m::simd::{pack16, smlad, ssat16};
+
+/// A fixed-point fraction with 15 fractional bits.
+pub type Q15 = i16;
+/// A fixed-point fraction with 31 fractional bits.
+pub type Q31 = i32;
+
+/// Clamps `v` to the range of `Q15`.
+pub fn sat_q15(v: i32) -> Q15 {
+    ssat16(v) as i16
+}
+
+/// Clamps `v` to the range of `Q31`.
+pub fn sat_q31(v: i64) -> Q31 {
+    if v > 0x7FFF_FFFF {
+        0x7FFF_FFFF
+    } else if v < -0x8000_0000 {
+        -0x8000_0000
+    } else {
+        v as i32
+    }
+}
+
+/// Multiplies two `Q15`s, saturating (only -1.0 * -1.0 needs it).
+pub fn q15_mul(a: Q15, b: Q15) -> Q15 {
+    sat_q15((a as i32 * b as i32) >> 15)
+}
+
+/// Multiplies two `Q31`s, saturating.
+pub fn q31_mul(a: Q31, b: Q31) -> Q31 {
+    sat_q31((a as i64 * b as i64) >> 31)
+}
+
+/// Converts `x`, nominally in [-1.0, 1.0), to `Q15`, saturating.
+pub fn q15_from_f32(x: f32) -> Q15 {
+    sat_q15((x * 32768.0) as i32)
+}
+
+/// Converts `x`, nominally in [-1.0, 1.0), to `Q31`, saturating.
+pub fn q31_from_f32(x: f32) -> Q31 {
+    sat_q31((x * 2147483648.0) as i64)
+}
+
+/// Computes the dot product of `a` and `b` (which must be the same length)
+/// as a Q30 value.  It doesn't saturate: keep the sum of the absolute values
+/// of one operand below 2.0 to be safe.
+pub fn dot_q15(a: &[Q15], b: &[Q15]) -> i32 {
+    assert!(a.len() == b.len());
+    let pairs = a.len() / 2;
+    let mut acc = 0;
+    for i in 0..pairs {
+        acc = smlad(pack16(a[2 * i], a[2 * i + 1]),
+                    pack16(b[2 * i], b[2 * i + 1]),
+                    acc)
+    }
+    if a.len() % 2 != 0 {
+        let last = a.len() - 1;
+        acc += a[last] as i32 * b[last] as i32
+    }
+    acc
+}
@@ -0,0 +1,168 @@
+//! Pulse density modulation (PDM) to PCM conversion.
+//!
+//! A PDM microphone produces a one-bit stream at a few MHz, in which the
+//! density of ones follows the signal.  Turning that into PCM means low-pass
+//! filtering and decimating by a large factor -- 64, say, to get 16 kHz from
+//! 1.024 MHz.  `PdmFilter` does it in the usual two steps:
+//!
+//! 1. A fourth-order CIC (cascaded integrator-comb) filter decimates by all
+//!    but the last factor of two.  It needs no multiplies, so it can afford
+//!    to run at the bit rate.
+//! 2. A 24-tap FIR low-pass decimates by the remaining two, cutting off what
+//!    the CIC lets through above the output's Nyquist frequency.
+//!
+//! A DC-blocking high-pass then removes the offset that PDM microphones
+//! usually have.
+//!
+//! The whole thing costs something like 20 CPU cycles per input bit, which
+//! is about 12% of a 168 MHz Cortex-M4 for a 1 MHz stream.
+
+use super::sat_q15;
+
+/// Order (number of integrator and comb stages) of the CIC filter.
+const CIC_ORDER : usize = 4;
+
+/// Q15 coefficients of the decimating FIR: a Hamming-windowed sinc with its
+/// cutoff at 0.21 of the CIC output rate, summing to 1.0.
+const FIR : [i32; 24] = [
+    37, 92, -5, -270, -207, 536, 894, -543,
+    -2382, -584, 6121, 12695, 12695, 6121, -584, -2382,
+    -543, 894, 536, -207, -270, -5, 92, 37,
+];
+
+/// Pole of the DC blocker, in Q15: 0.995, for a corner around 13 Hz at
+/// 16 kHz.
+const DC_POLE : i32 = 32604;
+
+/// Overall decimation factors, from the PDM bit rate to the PCM sample rate.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Decimation {
+    By32,
+    By64,
+    By128,
+}
+
+impl Decimation {
+    /// Gets the factor as a number.
+    pub fn factor(self) -> u32 {
+        match self {
+            Decimation::By32 => 32,
+            Decimation::By64 => 64,
+            Decimation::By128 => 128,
+        }
+    }
+
+    /// Right shift bringing the CIC's output, whose gain is its ratio to
+    /// the power `CIC_ORDER`, back to the Q15 range.
+    fn cic_shift(self) -> u32 {
+        match self {
+            Decimation::By32 => 1,
+            Decimation::By64 => 5,
+            Decimation::By128 => 9,
+        }
+    }
+}
+
+/// Converts a PDM bit stream to 16-bit PCM.
+pub struct PdmFilter {
+    decimation: Decimation,
+    integrators: [i32; CIC_ORDER],
+    combs: [i32; CIC_ORDER],
+    /// Bits into the current CIC output.
+    count: u32,
+    /// Recent CIC outputs, as a ring, for the FIR.
+    history: [i32; 24],
+    /// Index of the oldest entry in `history`.
+    head: usize,
+    /// Set when the next CIC output completes an FIR output.
+    odd: bool,
+    /// DC blocker input and output at the previous sample.
+    dc_in: i32,
+    dc_out: i32,
+}
+
+impl PdmFilter {
+    pub fn new(decimation: Decimation) -> PdmFilter {
+        PdmFilter {
+            decimation: decimation,
+            integrators: [0; CIC_ORDER],
+            combs: [0; CIC_ORDER],
+            count: 0,
+            history: [0; 24],
+            head: 0,
+            odd: false,
+            dc_in: 0,
+            dc_out: 0,
+        }
+    }
+
+    pub fn decimation(&self) -> Decimation {
+        self.decimation
+    }
+
+    /// Filters `pdm` -- sixteen bits per word, earliest in the most
+    /// significant bit, as an I2S receiver collects them -- into `pcm`.
+    /// Returns the number of PCM samples produced, which is the input bit
+    /// count divided by the decimation factor (give or take one, as the
+    /// filter carries partial samples between calls).  Samples that don't
+    /// fit in `pcm` are dropped.
+    pub fn process(&mut self, pdm: &[u16], pcm: &mut [i16]) -> usize {
+        let mut n = 0;
+        for &w in pdm {
+            for b in (0..16).rev() {
+                let x = if w & (1 << b) != 0 { 1 } else { -1 };
+                if let Some(s) = self.push_bit(x) {
+                    if n < pcm.len() {
+                        pcm[n] = s;
+                        n += 1
+                    }
+                }
+            }
+        }
+        n
+    }
+
+    /// Feeds one bit (as +1 or -1) through the filter chain, returning a
+    /// PCM sample if one is complete.
+    fn push_bit(&mut self, x: i32) -> Option<i16> {
+        // The CIC relies on wrapping arithmetic: the integrators overflow
+        // freely, and the combs' differences come out right regardless.
+        let mut v = x;
+        for i in self.integrators.iter_mut() {
+            *i = i.wrapping_add(v);
+            v = *i
+        }
+        self.count += 1;
+        if self.count < self.decimation.factor() / 2 {
+            return None
+        }
+        self.count = 0;
+        for c in self.combs.iter_mut() {
+            let prev = *c;
+            *c = v;
+            v = v.wrapping_sub(prev)
+        }
+
+        let v = sat_q15(v >> self.decimation.cic_shift()) as i32;
+        self.history[self.head] = v;
+        self.head = (self.head + 1) % FIR.len();
+        self.odd = !self.odd;
+        if self.odd {
+            return None
+        }
+
+        // `head` now indexes the oldest sample.  The coefficients are
+        // symmetric, so which end is which doesn't matter.
+        let acc = FIR.iter().enumerate().fold(0i32, |acc, (k, &h)| {
+            let i = (self.head + k) % FIR.len();
+            acc + h * self.history[i]
+        });
+        let y = sat_q15(acc >> 15) as i32;
+
+        let out = y - self.dc_in + ((DC_POLE * self.dc_out) >> 15);
+        self.dc_in = y;
+        let out = sat_q15(out);
+        self.dc_out = out as i32;
+        Some(out)
+    }
+}
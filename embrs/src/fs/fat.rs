@@ -0,0 +1,1171 @@
+//! FAT16 and FAT32 filesystem support.
+//!
+//! A `Volume` mounts a FAT filesystem from a `BlockDevice` -- either the
+//! whole device, or the first FAT partition in its MBR.  The volume owns a
+//! single sector buffer, through which all access passes; `Dir` and `File`
+//! handles are small plain values that say where things are, so any number of
+//! them can be open without a heap.  Operations take the handle and go
+//! through the volume: `volume.read(&mut file, buf)`.
+//!
+//! Limitations:
+//!
+//! - Only 8.3 names.  Long names are skipped when listing directories, and
+//!   files can only be created and opened by their short names.
+//! - Timestamps aren't maintained.
+//! - Writes are cached: a `File`'s size and first cluster reach its directory
+//!   entry on `flush`, and the last sector written reaches the device on
+//!   `flush` or `sync`.  Data written after the last `flush` may be lost on
+//!   power failure, so loggers should `flush` periodically.
+//! - FAT12 (small floppies) isn't supported.
+
+use core::str;
+
+use hal::{BlockDevice, BLOCK_SIZE};
+
+const SECTOR_SIZE : usize = BLOCK_SIZE;
+const ENTRY_SIZE : usize = 32;
+const ENTRIES_PER_SECTOR : u32 = (SECTOR_SIZE / ENTRY_SIZE) as u32;
+
+// Directory entry attribute bits.
+pub const ATTR_READ_ONLY : u8 = 0x01;
+pub const ATTR_HIDDEN : u8 = 0x02;
+pub const ATTR_SYSTEM : u8 = 0x04;
+pub const ATTR_VOLUME_ID : u8 = 0x08;
+pub const ATTR_DIRECTORY : u8 = 0x10;
+pub const ATTR_ARCHIVE : u8 = 0x20;
+/// Attribute combination marking a long name fragment.
+const ATTR_LONG_NAME : u8 = 0x0F;
+
+/// First byte of a deleted directory entry.
+const ENTRY_DELETED : u8 = 0xE5;
+/// First byte of the entry after the last in use.
+const ENTRY_END : u8 = 0x00;
+
+/// FAT date for 1980-01-01, used for every timestamp we write.
+const EPOCH_DATE : u16 = (1 << 5) | 1;
+
+/// Offset of the free cluster count in the FAT32 FSInfo sector.
+const FSINFO_FREE_COUNT : usize = 488;
+
+/// Most clusters a FAT32 volume can have: cluster numbers are 28 bits, and
+/// the top few values are reserved.
+const FAT32_MAX_CLUSTERS : u32 = 0x0FFFFFF5;
+
+/// Ways filesystem operations can fail.  `E` is the device's error type.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum FatError<E> {
+    Device(E),
+    /// The device doesn't hold a FAT16 or FAT32 filesystem we can use.
+    NotFat,
+    /// A FAT chain or directory is damaged.
+    Corrupt,
+    NotFound,
+    /// The name isn't a valid 8.3 name.
+    BadName,
+    NotAFile,
+    NotADirectory,
+    /// There are no free clusters.
+    DiskFull,
+    /// A FAT16 root directory has no free entries.
+    DirectoryFull,
+}
+
+/// FAT variants.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum FatType {
+    Fat16,
+    Fat32,
+}
+
+/// A directory.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Dir {
+    /// First cluster, or 0 for the fixed FAT16 root directory.
+    cluster: u32,
+}
+
+impl Dir {
+    /// Returns a cursor for listing the directory with
+    /// `Volume::next_entry`.
+    pub fn entries(&self) -> DirCursor {
+        DirCursor {
+            cluster: self.cluster,
+            index: 0,
+            done: false,
+        }
+    }
+}
+
+/// A position in a directory listing.
+#[derive(Copy, Clone, Debug)]
+pub struct DirCursor {
+    /// Current cluster, or 0 for the fixed FAT16 root directory.
+    cluster: u32,
+    /// Index of the next entry within `cluster` (or the root directory).
+    index: u32,
+    done: bool,
+}
+
+/// A directory entry.
+#[derive(Copy, Clone, Debug)]
+pub struct DirEntry {
+    /// The name in 8.3 form: eight characters of name and three of
+    /// extension, space padded, without the dot.
+    pub short_name: [u8; 11],
+    pub attributes: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+    /// Where the entry itself lives.
+    sector: u32,
+    offset: usize,
+}
+
+impl DirEntry {
+    fn parse(b: &[u8], sector: u32, offset: usize) -> DirEntry {
+        let mut short_name = [0; 11];
+        short_name.copy_from_slice(&b[..11]);
+        DirEntry {
+            short_name: short_name,
+            attributes: b[11],
+            first_cluster: ((le16(&b[20..]) as u32) << 16)
+                | le16(&b[26..]) as u32,
+            size: le32(&b[28..]),
+            sector: sector,
+            offset: offset,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    /// Formats the name as `NAME.EXT` into `buf`.
+    pub fn name<'b>(&self, buf: &'b mut [u8; 12]) -> &'b str {
+        let mut n = 0;
+        for &c in self.short_name[..8].iter().filter(|&&c| c != b' ') {
+            buf[n] = c;
+            n += 1;
+        }
+        if self.short_name[8] != b' ' {
+            buf[n] = b'.';
+            n += 1;
+            for &c in self.short_name[8..].iter().filter(|&&c| c != b' ') {
+                buf[n] = c;
+                n += 1;
+            }
+        }
+        str::from_utf8(&buf[..n]).unwrap_or("?")
+    }
+}
+
+/// An open file.  Don't keep two handles to one file: each tracks the size
+/// separately.
+#[derive(Debug)]
+pub struct File {
+    /// Where the file's directory entry lives.
+    entry_sector: u32,
+    entry_offset: usize,
+    /// First cluster, or 0 if the file is empty and has none.
+    first_cluster: u32,
+    size: u32,
+    pos: u32,
+    /// A cluster in the chain, and its index, from which to find the cluster
+    /// holding `pos`.
+    cluster: u32,
+    cluster_index: u32,
+    /// Set when the directory entry needs updating.
+    dirty: bool,
+}
+
+impl File {
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn position(&self) -> u32 {
+        self.pos
+    }
+}
+
+/// Converts `name` to 8.3 form.
+fn short_name<E>(name: &str) -> Result<[u8; 11], FatError<E>> {
+    let bytes = name.as_bytes();
+    let (base, ext) = match bytes.iter().rposition(|&c| c == b'.') {
+        Some(i) => (&bytes[..i], &bytes[i + 1..]),
+        None => (bytes, &bytes[bytes.len()..]),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+        return Err(FatError::BadName)
+    }
+    let mut out = [b' '; 11];
+    for (i, &c) in base.iter().enumerate() {
+        out[i] = try!(name_char(c));
+    }
+    for (i, &c) in ext.iter().enumerate() {
+        out[8 + i] = try!(name_char(c));
+    }
+    Ok(out)
+}
+
+fn name_char<E>(c: u8) -> Result<u8, FatError<E>> {
+    match c {
+        b'a'...b'z' => Ok(c - b'a' + b'A'),
+        b'A'...b'Z' | b'0'...b'9' => Ok(c),
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'(' | b')' | b'-'
+            | b'@' | b'^' | b'_' | b'`' | b'{' | b'}' | b'~' => Ok(c),
+        _ => Err(FatError::BadName),
+    }
+}
+
+fn le16(b: &[u8]) -> u16 {
+    (b[0] as u16) | ((b[1] as u16) << 8)
+}
+
+fn le32(b: &[u8]) -> u32 {
+    (le16(b) as u32) | ((le16(&b[2..]) as u32) << 16)
+}
+
+fn put_le16(b: &mut [u8], v: u16) {
+    b[0] = v as u8;
+    b[1] = (v >> 8) as u8;
+}
+
+fn put_le32(b: &mut [u8], v: u32) {
+    put_le16(b, v as u16);
+    put_le16(&mut b[2..], (v >> 16) as u16);
+}
+
+fn min(a: u32, b: u32) -> u32 {
+    if a < b { a } else { b }
+}
+
+/// A mounted FAT volume.
+pub struct Volume<'a, B: BlockDevice + 'a> {
+    device: &'a B,
+    kind: FatType,
+    fat_start: u32,
+    fat_size: u32,
+    num_fats: u32,
+    /// Fixed root directory location and size (FAT16).
+    root_start: u32,
+    root_entries: u32,
+    /// First cluster of the root directory (FAT32).
+    root_cluster: u32,
+    data_start: u32,
+    sectors_per_cluster: u32,
+    cluster_count: u32,
+    /// FSInfo sector (FAT32), until its free count has been invalidated.
+    fsinfo: Option<u32>,
+    /// Where to start looking for free clusters.
+    free_hint: u32,
+    buf: [u8; SECTOR_SIZE],
+    cached: Option<u32>,
+    buf_dirty: bool,
+}
+
+impl<'a, B: BlockDevice + 'a> Volume<'a, B> {
+    /// Mounts the filesystem on `device`.
+    pub fn mount(device: &'a B) -> Result<Volume<'a, B>, FatError<B::Error>> {
+        let mut vol = Volume {
+            device: device,
+            kind: FatType::Fat16,
+            fat_start: 0,
+            fat_size: 0,
+            num_fats: 0,
+            root_start: 0,
+            root_entries: 0,
+            root_cluster: 0,
+            data_start: 0,
+            sectors_per_cluster: 0,
+            cluster_count: 0,
+            fsinfo: None,
+            free_hint: 2,
+            buf: [0; SECTOR_SIZE],
+            cached: None,
+            buf_dirty: false,
+        };
+
+        try!(vol.load(0));
+        if vol.buf[510] != 0x55 || vol.buf[511] != 0xAA {
+            return Err(FatError::NotFat)
+        }
+        // A boot sector starts with a jump; otherwise this should be an MBR.
+        let start = if vol.buf[0] == 0xEB || vol.buf[0] == 0xE9 {
+            0
+        } else {
+            let found = (0..4).map(|i| &vol.buf[446 + 16 * i..])
+                .find(|p| match p[4] {
+                    0x04 | 0x06 | 0x0E | 0x0B | 0x0C => true,
+                    _ => false,
+                })
+                .map(|p| le32(&p[8..]));
+            match found {
+                Some(s) => s,
+                None => return Err(FatError::NotFat),
+            }
+        };
+
+        try!(vol.load(start));
+        let b = &vol.buf;
+        if b[510] != 0x55 || b[511] != 0xAA
+            || le16(&b[11..]) as usize != SECTOR_SIZE || b[13] == 0 {
+            return Err(FatError::NotFat)
+        }
+        let spc = b[13] as u32;
+        let reserved = le16(&b[14..]) as u32;
+        let num_fats = b[16] as u32;
+        let root_entries = le16(&b[17..]) as u32;
+        let total = match le16(&b[19..]) {
+            0 => le32(&b[32..]),
+            n => n as u32,
+        };
+        let fat_size = match le16(&b[22..]) {
+            0 => le32(&b[36..]),
+            n => n as u32,
+        };
+        let root_sectors = (root_entries + ENTRIES_PER_SECTOR - 1)
+            / ENTRIES_PER_SECTOR;
+        // The BPB comes from the medium and can't be trusted not to
+        // overflow.  Once the volume is known to end within the sector
+        // numbers, every sector worked out below is within it, too.
+        let meta = num_fats.checked_mul(fat_size)
+            .and_then(|f| f.checked_add(reserved))
+            .and_then(|m| m.checked_add(root_sectors));
+        let meta = match meta {
+            Some(m) => m,
+            None => return Err(FatError::NotFat),
+        };
+        if num_fats == 0 || fat_size == 0 || total <= meta
+            || start.checked_add(total).is_none() {
+            return Err(FatError::NotFat)
+        }
+        let cluster_count = (total - meta) / spc;
+
+        // The cluster count alone decides the FAT type.
+        if cluster_count < 4085 || cluster_count > FAT32_MAX_CLUSTERS {
+            return Err(FatError::NotFat)
+        }
+        vol.kind = if cluster_count < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+        // Each FAT must have an entry for every cluster.
+        let entry_size = match vol.kind {
+            FatType::Fat16 => 2,
+            FatType::Fat32 => 4,
+        };
+        if (cluster_count as u64 + 2) * entry_size
+            > fat_size as u64 * SECTOR_SIZE as u64 {
+            return Err(FatError::NotFat)
+        }
+        vol.fat_start = start + reserved;
+        vol.fat_size = fat_size;
+        vol.num_fats = num_fats;
+        vol.root_start = vol.fat_start + num_fats * fat_size;
+        vol.root_entries = root_entries;
+        vol.data_start = vol.root_start + root_sectors;
+        vol.sectors_per_cluster = spc;
+        vol.cluster_count = cluster_count;
+        if vol.kind == FatType::Fat32 {
+            vol.root_cluster = le32(&b[44..]);
+            if !vol.is_valid_cluster(vol.root_cluster) {
+                return Err(FatError::NotFat)
+            }
+            vol.fsinfo = match le16(&b[48..]) {
+                0 | 0xFFFF => None,
+                n => Some(start + n as u32),
+            };
+        }
+        Ok(vol)
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.kind
+    }
+
+    /// Returns the size of a cluster, the unit of allocation, in bytes.
+    pub fn cluster_size(&self) -> u32 {
+        self.sectors_per_cluster * SECTOR_SIZE as u32
+    }
+
+    pub fn root_dir(&self) -> Dir {
+        Dir { cluster: self.root_cluster }
+    }
+
+    // Sector cache.
+
+    /// Writes the cached sector back to the device, if it's been changed.
+    pub fn sync(&mut self) -> Result<(), FatError<B::Error>> {
+        if let (true, Some(s)) = (self.buf_dirty, self.cached) {
+            try!(self.device.write_blocks(s, &self.buf)
+                 .map_err(FatError::Device));
+            self.buf_dirty = false
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, sector: u32) -> Result<(), FatError<B::Error>> {
+        if self.cached != Some(sector) {
+            try!(self.sync());
+            self.cached = None;
+            try!(self.device.read_blocks(sector, &mut self.buf)
+                 .map_err(FatError::Device));
+            self.cached = Some(sector)
+        }
+        Ok(())
+    }
+
+    /// Makes `sector` the cached sector without reading it, for when it's
+    /// about to be entirely overwritten.
+    fn load_for_overwrite(&mut self, sector: u32)
+        -> Result<(), FatError<B::Error>> {
+        if self.cached != Some(sector) {
+            try!(self.sync());
+            self.cached = Some(sector)
+        }
+        Ok(())
+    }
+
+    // FAT access.
+
+    fn cluster_sector(&self, cluster: u32) -> u32 {
+        self.data_start + (cluster - 2) * self.sectors_per_cluster
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+
+    /// Locates `cluster`'s entry in FAT copy `copy`.
+    fn fat_location(&self, cluster: u32, copy: u32) -> (u32, usize) {
+        let offset = match self.kind {
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        };
+        (self.fat_start + copy * self.fat_size + offset / SECTOR_SIZE as u32,
+         offset as usize % SECTOR_SIZE)
+    }
+
+    fn fat_entry(&mut self, cluster: u32) -> Result<u32, FatError<B::Error>> {
+        let (sector, offset) = self.fat_location(cluster, 0);
+        try!(self.load(sector));
+        Ok(match self.kind {
+            FatType::Fat16 => le16(&self.buf[offset..]) as u32,
+            FatType::Fat32 => le32(&self.buf[offset..]) & 0x0FFFFFFF,
+        })
+    }
+
+    fn set_fat_entry(&mut self, cluster: u32, value: u32)
+        -> Result<(), FatError<B::Error>> {
+        for copy in 0..self.num_fats {
+            let (sector, offset) = self.fat_location(cluster, copy);
+            try!(self.load(sector));
+            match self.kind {
+                FatType::Fat16 =>
+                    put_le16(&mut self.buf[offset..], value as u16),
+                FatType::Fat32 => {
+                    // The top four bits are reserved, and preserved.
+                    let old = le32(&self.buf[offset..]);
+                    put_le32(&mut self.buf[offset..],
+                             (old & 0xF0000000) | (value & 0x0FFFFFFF))
+                },
+            }
+            self.buf_dirty = true
+        }
+        Ok(())
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.kind {
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFFFFFF,
+        }
+    }
+
+    /// Follows the chain from `cluster`, returning `None` at its end.
+    fn next_cluster(&mut self, cluster: u32)
+        -> Result<Option<u32>, FatError<B::Error>> {
+        let next = try!(self.fat_entry(cluster));
+        if next >= self.end_of_chain() - 7 {
+            Ok(None)
+        } else if self.is_valid_cluster(next) {
+            Ok(Some(next))
+        } else {
+            Err(FatError::Corrupt)
+        }
+    }
+
+    /// Allocates a free cluster, linking it after `prev` if given, and
+    /// zeroing it if `zero`.
+    fn alloc_cluster(&mut self, prev: Option<u32>, zero: bool)
+        -> Result<u32, FatError<B::Error>> {
+        let mut c = self.free_hint;
+        let mut found = None;
+        for _ in 0..self.cluster_count {
+            if !self.is_valid_cluster(c) {
+                c = 2
+            }
+            if try!(self.fat_entry(c)) == 0 {
+                found = Some(c);
+                break
+            }
+            c += 1
+        }
+        let c = match found {
+            Some(c) => c,
+            None => return Err(FatError::DiskFull),
+        };
+
+        let eoc = self.end_of_chain();
+        try!(self.set_fat_entry(c, eoc));
+        if let Some(p) = prev {
+            try!(self.set_fat_entry(p, c))
+        }
+        self.free_hint = c + 1;
+
+        // We don't keep the FSInfo free count, so mark it unknown.
+        if let Some(s) = self.fsinfo.take() {
+            try!(self.load(s));
+            put_le32(&mut self.buf[FSINFO_FREE_COUNT..], !0);
+            self.buf_dirty = true
+        }
+
+        if zero {
+            let first = self.cluster_sector(c);
+            for s in first..(first + self.sectors_per_cluster) {
+                try!(self.load_for_overwrite(s));
+                for b in self.buf.iter_mut() {
+                    *b = 0
+                }
+                self.buf_dirty = true
+            }
+        }
+        Ok(c)
+    }
+
+    /// Frees the chain starting at `cluster`.
+    fn free_chain(&mut self, cluster: u32) -> Result<(), FatError<B::Error>> {
+        let mut c = Some(cluster);
+        while let Some(cluster) = c {
+            c = try!(self.next_cluster(cluster));
+            try!(self.set_fat_entry(cluster, 0));
+            if cluster < self.free_hint {
+                self.free_hint = cluster
+            }
+        }
+        Ok(())
+    }
+
+    // Directories.
+
+    /// Finds the entry at `cur`, moving to the next cluster (or, if `extend`,
+    /// allocating one) as needed.  Returns `None` past the end.
+    fn entry_location(&mut self, cur: &mut DirCursor, extend: bool)
+        -> Result<Option<(u32, usize)>, FatError<B::Error>> {
+        if cur.cluster == 0 {
+            if cur.index >= self.root_entries {
+                return Ok(None)
+            }
+            return Ok(Some((
+                self.root_start + cur.index / ENTRIES_PER_SECTOR,
+                (cur.index % ENTRIES_PER_SECTOR) as usize * ENTRY_SIZE)))
+        }
+
+        if cur.index >= self.sectors_per_cluster * ENTRIES_PER_SECTOR {
+            cur.cluster = match try!(self.next_cluster(cur.cluster)) {
+                Some(next) => next,
+                None if extend => try!(self.alloc_cluster(Some(cur.cluster),
+                                                          true)),
+                None => return Ok(None),
+            };
+            cur.index = 0
+        }
+        Ok(Some((
+            self.cluster_sector(cur.cluster) + cur.index / ENTRIES_PER_SECTOR,
+            (cur.index % ENTRIES_PER_SECTOR) as usize * ENTRY_SIZE)))
+    }
+
+    /// Returns the next entry in a directory listing, skipping deleted
+    /// entries, long name fragments, and volume labels.
+    pub fn next_entry(&mut self, cur: &mut DirCursor)
+        -> Result<Option<DirEntry>, FatError<B::Error>> {
+        while !cur.done {
+            let (sector, offset) = match try!(self.entry_location(cur, false)) {
+                Some(loc) => loc,
+                None => break,
+            };
+            cur.index += 1;
+            try!(self.load(sector));
+            let e = &self.buf[offset..offset + ENTRY_SIZE];
+            if e[0] == ENTRY_END {
+                break
+            }
+            if e[0] == ENTRY_DELETED
+                || e[11] & ATTR_LONG_NAME == ATTR_LONG_NAME
+                || e[11] & ATTR_VOLUME_ID != 0 {
+                continue
+            }
+            return Ok(Some(DirEntry::parse(e, sector, offset)))
+        }
+        cur.done = true;
+        Ok(None)
+    }
+
+    /// Finds `name` in `dir`.
+    pub fn lookup(&mut self, dir: &Dir, name: &str)
+        -> Result<DirEntry, FatError<B::Error>> {
+        let short = try!(short_name(name));
+        let mut cur = dir.entries();
+        while let Some(e) = try!(self.next_entry(&mut cur)) {
+            if e.short_name == short {
+                return Ok(e)
+            }
+        }
+        Err(FatError::NotFound)
+    }
+
+    /// Opens subdirectory `name` of `dir`.
+    pub fn open_dir(&mut self, dir: &Dir, name: &str)
+        -> Result<Dir, FatError<B::Error>> {
+        let e = try!(self.lookup(dir, name));
+        if !e.is_dir() {
+            return Err(FatError::NotADirectory)
+        }
+        self.dir_from_entry(&e)
+    }
+
+    /// Returns `e`'s first cluster -- 0 if it has none -- after checking
+    /// that it's on the volume.
+    fn entry_cluster(&self, e: &DirEntry) -> Result<u32, FatError<B::Error>> {
+        if e.first_cluster == 0 || self.is_valid_cluster(e.first_cluster) {
+            Ok(e.first_cluster)
+        } else {
+            Err(FatError::Corrupt)
+        }
+    }
+
+    fn dir_from_entry(&self, e: &DirEntry) -> Result<Dir, FatError<B::Error>> {
+        // ".." entries pointing at the root say cluster 0, even on FAT32.
+        Ok(match try!(self.entry_cluster(e)) {
+            0 => self.root_dir(),
+            c => Dir { cluster: c },
+        })
+    }
+
+    /// Adds an entry to `dir`, returning its location.
+    fn add_entry(&mut self,
+                 dir: &Dir,
+                 name: &[u8; 11],
+                 attributes: u8,
+                 cluster: u32) -> Result<(u32, usize), FatError<B::Error>> {
+        let mut cur = dir.entries();
+        loop {
+            let (sector, offset) = match try!(self.entry_location(&mut cur,
+                                                                  true)) {
+                Some(loc) => loc,
+                None => return Err(FatError::DirectoryFull),
+            };
+            cur.index += 1;
+            try!(self.load(sector));
+            let first = self.buf[offset];
+            if first == ENTRY_END || first == ENTRY_DELETED {
+                self.write_entry(offset, name, attributes, cluster);
+                return Ok((sector, offset))
+            }
+        }
+    }
+
+    /// Fills in a fresh entry at `offset` in the cached sector.
+    fn write_entry(&mut self,
+                   offset: usize,
+                   name: &[u8; 11],
+                   attributes: u8,
+                   cluster: u32) {
+        let e = &mut self.buf[offset..offset + ENTRY_SIZE];
+        for b in e.iter_mut() {
+            *b = 0
+        }
+        e[..11].copy_from_slice(name);
+        e[11] = attributes;
+        put_le16(&mut e[16..], EPOCH_DATE);  // created
+        put_le16(&mut e[18..], EPOCH_DATE);  // accessed
+        put_le16(&mut e[20..], (cluster >> 16) as u16);
+        put_le16(&mut e[24..], EPOCH_DATE);  // written
+        put_le16(&mut e[26..], cluster as u16);
+        self.buf_dirty = true
+    }
+
+    /// Creates subdirectory `name` in `dir`.
+    pub fn create_dir(&mut self, dir: &Dir, name: &str)
+        -> Result<Dir, FatError<B::Error>> {
+        let short = try!(short_name(name));
+        match self.lookup(dir, name) {
+            Ok(e) if e.is_dir() => return self.dir_from_entry(&e),
+            Ok(_) => return Err(FatError::NotADirectory),
+            Err(FatError::NotFound) => (),
+            Err(e) => return Err(e),
+        }
+
+        let cluster = try!(self.alloc_cluster(None, true));
+        let sector = self.cluster_sector(cluster);
+        try!(self.load(sector));
+        let parent = if *dir == self.root_dir() { 0 } else { dir.cluster };
+        self.write_entry(0, b".          ", ATTR_DIRECTORY, cluster);
+        self.write_entry(ENTRY_SIZE, b"..         ", ATTR_DIRECTORY, parent);
+        let _ = try!(self.add_entry(dir, &short, ATTR_DIRECTORY, cluster));
+        try!(self.sync());
+        Ok(Dir { cluster: cluster })
+    }
+
+    // Files.
+
+    fn file_from_entry(&self, e: &DirEntry)
+        -> Result<File, FatError<B::Error>> {
+        let cluster = try!(self.entry_cluster(e));
+        Ok(File {
+            entry_sector: e.sector,
+            entry_offset: e.offset,
+            first_cluster: cluster,
+            size: e.size,
+            pos: 0,
+            cluster: cluster,
+            cluster_index: 0,
+            dirty: false,
+        })
+    }
+
+    /// Opens existing file `name` in `dir`, positioned at the start.
+    pub fn open(&mut self, dir: &Dir, name: &str)
+        -> Result<File, FatError<B::Error>> {
+        let e = try!(self.lookup(dir, name));
+        if e.is_dir() {
+            return Err(FatError::NotAFile)
+        }
+        self.file_from_entry(&e)
+    }
+
+    /// Creates file `name` in `dir`, or truncates it if it exists.
+    pub fn create(&mut self, dir: &Dir, name: &str)
+        -> Result<File, FatError<B::Error>> {
+        let short = try!(short_name(name));
+        match self.lookup(dir, name) {
+            Ok(e) => {
+                if e.is_dir() {
+                    return Err(FatError::NotAFile)
+                }
+                let mut f = try!(self.file_from_entry(&e));
+                try!(self.truncate(&mut f));
+                Ok(f)
+            },
+            Err(FatError::NotFound) => {
+                let (sector, offset) =
+                    try!(self.add_entry(dir, &short, ATTR_ARCHIVE, 0));
+                try!(self.sync());
+                Ok(File {
+                    entry_sector: sector,
+                    entry_offset: offset,
+                    first_cluster: 0,
+                    size: 0,
+                    pos: 0,
+                    cluster: 0,
+                    cluster_index: 0,
+                    dirty: false,
+                })
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Discards a file's contents.
+    pub fn truncate(&mut self, file: &mut File)
+        -> Result<(), FatError<B::Error>> {
+        if file.first_cluster != 0 {
+            try!(self.free_chain(file.first_cluster))
+        }
+        file.first_cluster = 0;
+        file.cluster = 0;
+        file.cluster_index = 0;
+        file.size = 0;
+        file.pos = 0;
+        file.dirty = true;
+        self.flush(file)
+    }
+
+    /// Deletes file `name` from `dir`.
+    pub fn remove(&mut self, dir: &Dir, name: &str)
+        -> Result<(), FatError<B::Error>> {
+        let e = try!(self.lookup(dir, name));
+        if e.is_dir() {
+            return Err(FatError::NotAFile)
+        }
+        let cluster = try!(self.entry_cluster(&e));
+        if cluster != 0 {
+            try!(self.free_chain(cluster))
+        }
+        try!(self.load(e.sector));
+        self.buf[e.offset] = ENTRY_DELETED;
+        self.buf_dirty = true;
+        self.sync()
+    }
+
+    /// Moves a file's position to `pos`, which is clamped to its size.
+    pub fn seek(&mut self, file: &mut File, pos: u32) {
+        file.pos = min(pos, file.size);
+        if file.pos / self.cluster_size() < file.cluster_index {
+            file.cluster = file.first_cluster;
+            file.cluster_index = 0
+        }
+    }
+
+    /// Finds the sector holding `file.pos`, extending the chain if
+    /// `extend`.  Returns `None` if the chain ends first.
+    fn file_sector(&mut self, file: &mut File, extend: bool)
+        -> Result<Option<u32>, FatError<B::Error>> {
+        let cluster_size = self.cluster_size();
+        if file.first_cluster == 0 {
+            if !extend {
+                return Ok(None)
+            }
+            let c = try!(self.alloc_cluster(None, false));
+            file.first_cluster = c;
+            file.cluster = c;
+            file.cluster_index = 0;
+            file.dirty = true
+        }
+        let want = file.pos / cluster_size;
+        while file.cluster_index < want {
+            file.cluster = match try!(self.next_cluster(file.cluster)) {
+                Some(next) => next,
+                None if extend => try!(self.alloc_cluster(Some(file.cluster),
+                                                          false)),
+                None => return Ok(None),
+            };
+            file.cluster_index += 1
+        }
+        Ok(Some(self.cluster_sector(file.cluster)
+                + (file.pos % cluster_size) / SECTOR_SIZE as u32))
+    }
+
+    /// Reads from `file` at its position into `buf`, returning the number of
+    /// bytes read: less than `buf.len()` only at the end of the file.
+    pub fn read(&mut self, file: &mut File, buf: &mut [u8])
+        -> Result<usize, FatError<B::Error>> {
+        let mut n = 0;
+        while n < buf.len() && file.pos < file.size {
+            let sector = match try!(self.file_sector(file, false)) {
+                Some(s) => s,
+                None => return Err(FatError::Corrupt),
+            };
+            let offset = file.pos as usize % SECTOR_SIZE;
+            let chunk = min(min((SECTOR_SIZE - offset) as u32,
+                                (buf.len() - n) as u32),
+                            file.size - file.pos) as usize;
+            try!(self.load(sector));
+            buf[n..n + chunk]
+                .copy_from_slice(&self.buf[offset..offset + chunk]);
+            n += chunk;
+            file.pos += chunk as u32
+        }
+        Ok(n)
+    }
+
+    /// Writes `data` to `file` at its position, extending the file as
+    /// needed.
+    pub fn write(&mut self, file: &mut File, data: &[u8])
+        -> Result<(), FatError<B::Error>> {
+        let mut n = 0;
+        while n < data.len() {
+            let sector = match try!(self.file_sector(file, true)) {
+                Some(s) => s,
+                None => unreachable!(),
+            };
+            let offset = file.pos as usize % SECTOR_SIZE;
+            let chunk = min((SECTOR_SIZE - offset) as u32,
+                            (data.len() - n) as u32) as usize;
+            // Whole sectors past the end of the file needn't be read first.
+            if chunk == SECTOR_SIZE && file.pos >= file.size {
+                try!(self.load_for_overwrite(sector))
+            } else {
+                try!(self.load(sector))
+            }
+            self.buf[offset..offset + chunk]
+                .copy_from_slice(&data[n..n + chunk]);
+            self.buf_dirty = true;
+            n += chunk;
+            file.pos += chunk as u32;
+            if file.pos > file.size {
+                file.size = file.pos;
+                file.dirty = true
+            }
+        }
+        Ok(())
+    }
+
+    /// Brings `file`'s directory entry up to date, and writes everything
+    /// cached to the device.
+    pub fn flush(&mut self, file: &mut File) -> Result<(), FatError<B::Error>> {
+        if file.dirty {
+            try!(self.load(file.entry_sector));
+            let e = &mut self.buf[file.entry_offset..
+                                  file.entry_offset + ENTRY_SIZE];
+            e[11] |= ATTR_ARCHIVE;
+            put_le16(&mut e[20..], (file.first_cluster >> 16) as u16);
+            put_le16(&mut e[26..], file.first_cluster as u16);
+            put_le32(&mut e[28..], file.size);
+            self.buf_dirty = true;
+            file.dirty = false
+        }
+        self.sync()
+    }
+}
+
+#[cfg(all(test, feature = "host-test"))]
+mod tests {
+    use std::cell::RefCell;
+
+    use hal::{BlockDevice, BLOCK_SIZE};
+
+    use super::{FatError, FatType, Volume, le16, put_le16, put_le32};
+
+    /// A device held in memory.
+    struct RamDisk {
+        data: RefCell<Vec<u8>>,
+    }
+
+    impl RamDisk {
+        fn new(data: Vec<u8>) -> RamDisk {
+            RamDisk { data: RefCell::new(data) }
+        }
+
+        fn range(&self, block: u32, len: usize) -> Result<(usize, usize), ()> {
+            let start = block as usize * BLOCK_SIZE;
+            if start + len > self.data.borrow().len() {
+                Err(())
+            } else {
+                Ok((start, start + len))
+            }
+        }
+    }
+
+    impl BlockDevice for RamDisk {
+        type Error = ();
+
+        fn block_count(&self) -> u32 {
+            (self.data.borrow().len() / BLOCK_SIZE) as u32
+        }
+
+        fn read_blocks(&self, block: u32, buf: &mut [u8]) -> Result<(), ()> {
+            let (start, end) = try!(self.range(block, buf.len()));
+            buf.copy_from_slice(&self.data.borrow()[start..end]);
+            Ok(())
+        }
+
+        fn write_blocks(&self, block: u32, data: &[u8]) -> Result<(), ()> {
+            let (start, end) = try!(self.range(block, data.len()));
+            self.data.borrow_mut()[start..end].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn erase_blocks(&self, block: u32, count: u32) -> Result<(), ()> {
+            self.range(block, count as usize * BLOCK_SIZE).map(|_| ())
+        }
+    }
+
+    // A small FAT16 volume: one sector per cluster, just over the 4085
+    // clusters FAT16 needs.
+    const TOTAL : usize = 4200;
+    const FAT_SIZE : usize = 17;
+    const ROOT_START : usize = 1 + 2 * FAT_SIZE;
+
+    fn fat16_image() -> Vec<u8> {
+        let mut d = vec![0; TOTAL * BLOCK_SIZE];
+        d[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+        put_le16(&mut d[11..], BLOCK_SIZE as u16);
+        d[13] = 1;                              // sectors per cluster
+        put_le16(&mut d[14..], 1);              // reserved sectors
+        d[16] = 2;                              // FATs
+        put_le16(&mut d[17..], 512);            // root entries
+        put_le16(&mut d[19..], TOTAL as u16);
+        d[21] = 0xF8;                           // media
+        put_le16(&mut d[22..], FAT_SIZE as u16);
+        d[510] = 0x55;
+        d[511] = 0xAA;
+        for fat in 0..2 {
+            let at = (1 + fat * FAT_SIZE) * BLOCK_SIZE;
+            put_le16(&mut d[at..], 0xFFF8);
+            put_le16(&mut d[at + 2..], 0xFFFF);
+        }
+        d
+    }
+
+    /// A boot sector alone, for BPBs that `mount` should reject before
+    /// reading any further.
+    fn fat32_boot_sector(root_cluster: u32) -> Vec<u8> {
+        let mut d = vec![0; BLOCK_SIZE];
+        d[0] = 0xEB;
+        put_le16(&mut d[11..], BLOCK_SIZE as u16);
+        d[13] = 1;
+        put_le16(&mut d[14..], 32);
+        d[16] = 2;
+        put_le32(&mut d[32..], 32 + 2 * 600 + 70000);
+        put_le32(&mut d[36..], 600);
+        put_le32(&mut d[44..], root_cluster);
+        d[510] = 0x55;
+        d[511] = 0xAA;
+        d
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    #[test]
+    fn mounts_fat16() {
+        let disk = RamDisk::new(fat16_image());
+        let vol = Volume::mount(&disk).unwrap();
+        assert_eq!(FatType::Fat16, vol.fat_type());
+        assert_eq!(512, vol.cluster_size());
+    }
+
+    #[test]
+    fn written_file_reads_back_after_remount() {
+        let disk = RamDisk::new(fat16_image());
+        let data = pattern(1300);
+        {
+            let mut vol = Volume::mount(&disk).unwrap();
+            let root = vol.root_dir();
+            let mut f = vol.create(&root, "log.txt").unwrap();
+            vol.write(&mut f, &data).unwrap();
+            vol.flush(&mut f).unwrap();
+        }
+
+        let mut vol = Volume::mount(&disk).unwrap();
+        let root = vol.root_dir();
+        let e = vol.lookup(&root, "LOG.TXT").unwrap();
+        assert_eq!(1300, e.size);
+        let mut f = vol.open(&root, "log.txt").unwrap();
+        let mut buf = [0; 2000];
+        assert_eq!(1300, vol.read(&mut f, &mut buf).unwrap());
+        assert_eq!(&data[..], &buf[..1300]);
+        assert_eq!(0, vol.read(&mut f, &mut buf).unwrap());
+    }
+
+    #[test]
+    fn appending_extends_the_chain() {
+        let disk = RamDisk::new(fat16_image());
+        let data = pattern(1112);
+        let mut vol = Volume::mount(&disk).unwrap();
+        let root = vol.root_dir();
+        let mut f = vol.create(&root, "data.bin").unwrap();
+        vol.write(&mut f, &data[..512]).unwrap();
+        vol.flush(&mut f).unwrap();
+
+        let mut f = vol.open(&root, "data.bin").unwrap();
+        let end = f.size();
+        vol.seek(&mut f, end);
+        vol.write(&mut f, &data[512..]).unwrap();
+        vol.flush(&mut f).unwrap();
+
+        let mut f = vol.open(&root, "data.bin").unwrap();
+        assert_eq!(1112, f.size());
+        let mut buf = [0; 1112];
+        assert_eq!(1112, vol.read(&mut f, &mut buf).unwrap());
+        assert_eq!(&data[..], &buf[..]);
+
+        // The file takes clusters 2, 3 and 4, in order.
+        let fat = &disk.data.borrow()[BLOCK_SIZE..];
+        assert_eq!(3, le16(&fat[4..]));
+        assert_eq!(4, le16(&fat[6..]));
+        assert_eq!(0xFFFF, le16(&fat[8..]));
+    }
+
+    #[test]
+    fn lookup_finds_names_in_directories() {
+        let disk = RamDisk::new(fat16_image());
+        let mut vol = Volume::mount(&disk).unwrap();
+        let root = vol.root_dir();
+        assert_eq!(Some(FatError::NotFound),
+                   vol.lookup(&root, "NONE.TXT").err());
+        assert_eq!(Some(FatError::BadName),
+                   vol.lookup(&root, "TOOLONGNAME.TXT").err());
+
+        let sub = vol.create_dir(&root, "logs").unwrap();
+        let mut f = vol.create(&sub, "a.txt").unwrap();
+        vol.write(&mut f, b"hello").unwrap();
+        vol.flush(&mut f).unwrap();
+
+        assert_eq!(Some(sub), vol.open_dir(&root, "LOGS").ok());
+        assert_eq!(5, vol.lookup(&sub, "A.TXT").unwrap().size);
+        assert_eq!(Some(FatError::NotFound),
+                   vol.lookup(&root, "A.TXT").err());
+        assert_eq!(Some(FatError::NotAFile),
+                   vol.open(&root, "LOGS").err());
+        assert_eq!(Some(FatError::NotADirectory),
+                   vol.open_dir(&sub, "A.TXT").err());
+    }
+
+    #[test]
+    fn rejects_malformed_bpbs() {
+        let mut d = fat16_image();
+        d[511] = 0;
+        assert_eq!(Some(FatError::NotFat),
+                   Volume::mount(&RamDisk::new(d)).err());
+
+        let mut d = fat16_image();
+        put_le16(&mut d[11..], 1024);
+        assert_eq!(Some(FatError::NotFat),
+                   Volume::mount(&RamDisk::new(d)).err());
+
+        // FATs too big to add up.
+        let mut d = fat16_image();
+        put_le16(&mut d[22..], 0);
+        put_le32(&mut d[36..], 0x8000_0000);
+        assert_eq!(Some(FatError::NotFat),
+                   Volume::mount(&RamDisk::new(d)).err());
+
+        // FATs too small to hold every cluster.
+        let mut d = fat16_image();
+        put_le16(&mut d[22..], 1);
+        assert_eq!(Some(FatError::NotFat),
+                   Volume::mount(&RamDisk::new(d)).err());
+
+        let d = fat32_boot_sector(2);
+        assert_eq!(Some(FatType::Fat32),
+                   Volume::mount(&RamDisk::new(d)).ok().map(|v| v.fat_type()));
+        for &c in &[0, 1, 0x0FFFFFFF] {
+            assert_eq!(Some(FatError::NotFat),
+                       Volume::mount(&RamDisk::new(fat32_boot_sector(c)))
+                           .err());
+        }
+    }
+
+    #[test]
+    fn rejects_entries_with_reserved_clusters() {
+        let disk = RamDisk::new(fat16_image());
+        {
+            let mut vol = Volume::mount(&disk).unwrap();
+            let root = vol.root_dir();
+            let mut f = vol.create(&root, "a.txt").unwrap();
+            vol.write(&mut f, b"hello").unwrap();
+            vol.flush(&mut f).unwrap();
+            let _ = vol.create_dir(&root, "sub").unwrap();
+        }
+        // Point both entries at cluster 1.
+        for i in 0..2 {
+            let at = ROOT_START * BLOCK_SIZE + i * 32;
+            put_le16(&mut disk.data.borrow_mut()[at + 26..], 1);
+        }
+
+        let mut vol = Volume::mount(&disk).unwrap();
+        let root = vol.root_dir();
+        assert_eq!(Some(FatError::Corrupt), vol.open(&root, "A.TXT").err());
+        assert_eq!(Some(FatError::Corrupt),
+                   vol.create(&root, "A.TXT").err());
+        assert_eq!(Some(FatError::Corrupt), vol.remove(&root, "A.TXT").err());
+        assert_eq!(Some(FatError::Corrupt),
+                   vol.open_dir(&root, "SUB").err());
+        assert_eq!(Some(FatError::Corrupt),
+                   vol.create_dir(&root, "SUB").err());
+    }
+}
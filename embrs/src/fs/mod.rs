@@ -0,0 +1,5 @@
+//! Filesystems.
+//!
+//! These are written against `hal::BlockDevice`, and need no heap.
+
+pub mod fat;
@@ -0,0 +1,210 @@
+//! Hardware abstraction traits.
+//!
+//! Device drivers (sensors, displays, radios) should be written against these
+//! traits rather than against a particular SoC's drivers, so that they can be
+//! reused as the crate grows support for other parts.  The SoC modules (e.g.
+//! `stm32f4`) implement them for their drivers.
+//!
+//! The traits are deliberately small.  Operations that can stall come in two
+//! flavors: a non-blocking `try_` form, which returns `NbError::WouldBlock`
+//! rather than waiting, and a blocking form that spins on the `try_` form.
+//! Implementations normally only provide the `try_` form.
+
+/// Error type for non-blocking operations.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum NbError<E> {
+    /// The operation can't complete without waiting; try again later.
+    WouldBlock,
+    /// The operation failed.
+    Other(E),
+}
+
+/// Result of a non-blocking operation.
+pub type NbResult<T, E> = Result<T, NbError<E>>;
+
+/// Repeats the non-blocking operation `op` until it stops returning
+/// `WouldBlock`.
+pub fn block<T, E, F: FnMut() -> NbResult<T, E>>(mut op: F) -> Result<T, E> {
+    loop {
+        match op() {
+            Ok(v) => return Ok(v),
+            Err(NbError::Other(e)) => return Err(e),
+            Err(NbError::WouldBlock) => continue,
+        }
+    }
+}
+
+/// A pin (or group of pins) that can be driven high or low.
+pub trait DigitalOutput {
+    /// Drives the output to logic high.
+    fn set_high(&self);
+
+    /// Drives the output to logic low.
+    fn set_low(&self);
+
+    /// Drives the output high if `high`, low otherwise.
+    fn set_state(&self, high: bool) {
+        if high { self.set_high() } else { self.set_low() }
+    }
+}
+
+/// A pin (or group of pins) whose logic level can be read.
+pub trait DigitalInput {
+    /// Checks whether the input is at logic high.  For groups of pins, this
+    /// requires *all* pins to be high.
+    fn is_high(&self) -> bool;
+
+    /// Checks whether the input is at logic low.  For groups of pins, this
+    /// requires *all* pins to be low.
+    fn is_low(&self) -> bool;
+}
+
+/// A pin that can switch between driving its output and listening as an
+/// input, for bidirectional signals handled in software.
+pub trait DigitalInOut: DigitalOutput + DigitalInput {
+    /// Drives the pin (at the level last set) if `enabled`, or releases it
+    /// to float as an input.
+    fn set_output_enabled(&self, enabled: bool);
+}
+
+/// Byte-oriented serial transmitter.
+pub trait SerialWrite {
+    type Error;
+
+    /// Queues `byte` for transmission if there's room.
+    fn try_write(&self, byte: u8) -> NbResult<(), Self::Error>;
+
+    /// Checks that all queued bytes have been transmitted.
+    fn try_flush(&self) -> NbResult<(), Self::Error>;
+
+    /// Transmits `byte`, waiting for room if necessary.
+    fn write(&self, byte: u8) -> Result<(), Self::Error> {
+        block(|| self.try_write(byte))
+    }
+
+    /// Transmits all of `bytes`, waiting for room as necessary.
+    fn write_all(&self, bytes: &[u8]) -> Result<(), Self::Error> {
+        for &b in bytes {
+            try!(self.write(b));
+        }
+        Ok(())
+    }
+
+    /// Waits for all queued bytes to be transmitted.
+    fn flush(&self) -> Result<(), Self::Error> {
+        block(|| self.try_flush())
+    }
+}
+
+/// Byte-oriented serial receiver.
+pub trait SerialRead {
+    type Error;
+
+    /// Takes a received byte, if one is available.
+    fn try_read(&self) -> NbResult<u8, Self::Error>;
+
+    /// Waits for, and takes, a received byte.
+    fn read(&self) -> Result<u8, Self::Error> {
+        block(|| self.try_read())
+    }
+}
+
+/// SPI master, operating on eight-bit words.  Chip select is the caller's
+/// responsibility (typically through a `DigitalOutput`).
+pub trait SpiTransfer {
+    type Error;
+
+    /// Sends the contents of `words`, replacing each with the word received
+    /// at the same time.
+    fn transfer(&self, words: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Sends `words`, discarding whatever is received.
+    fn write(&self, words: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// I2C master, using seven-bit addresses.
+pub trait I2cBus {
+    type Error;
+
+    /// Writes `bytes` to the device at `address`.
+    fn write(&self, address: u8, bytes: &[u8]) -> Result<(), Self::Error>;
+
+    /// Reads enough bytes to fill `buffer` from the device at `address`.
+    fn read(&self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Writes `bytes` to the device at `address`, then -- using a repeated
+    /// start, without releasing the bus -- reads enough to fill `buffer`.
+    /// This is the usual way to read a device register.
+    fn write_read(&self, address: u8, bytes: &[u8], buffer: &mut [u8])
+        -> Result<(), Self::Error>;
+}
+
+/// Busy-wait delays.
+pub trait DelayUs {
+    /// Waits for at least `us` microseconds.
+    fn delay_us(&self, us: u32);
+
+    /// Waits for at least `ms` milliseconds.
+    fn delay_ms(&self, ms: u32) {
+        for _ in 0..ms {
+            self.delay_us(1000)
+        }
+    }
+}
+
+/// A watchdog timer, which resets the system unless it's fed regularly.
+pub trait Watchdog {
+    /// Restarts the watchdog's countdown.
+    fn feed(&self);
+}
+
+/// Size of the blocks a `BlockDevice` deals in, in bytes.
+pub const BLOCK_SIZE : usize = 512;
+
+/// Storage addressed in `BLOCK_SIZE`-byte blocks, such as a memory card.
+///
+/// Buffers passed to `read_blocks` and `write_blocks` must be a whole number
+/// of blocks long; the number of blocks transferred is implied by the
+/// length.
+pub trait BlockDevice {
+    type Error;
+
+    /// Returns the number of blocks on the device.
+    fn block_count(&self) -> u32;
+
+    /// Reads blocks starting at `block` to fill `buf`.
+    fn read_blocks(&self, block: u32, buf: &mut [u8])
+        -> Result<(), Self::Error>;
+
+    /// Writes `data` to blocks starting at `block`.  On devices that need
+    /// erasing, the blocks must have been erased first.
+    fn write_blocks(&self, block: u32, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Erases `count` blocks starting at `block`, leaving their contents
+    /// unspecified but ready for `write_blocks`.  Devices that don't need
+    /// erasing just check the range.
+    fn erase_blocks(&self, block: u32, count: u32) -> Result<(), Self::Error>;
+}
+
+/// NOR-style Flash, addressed in bytes from the start of the region: it
+/// reads as ones once erased, erases only a whole sector at a time, and
+/// programming can only clear bits.
+pub trait NorFlash {
+    type Error;
+
+    /// Returns the size of each sector, in bytes.
+    fn sector_size(&self) -> usize;
+
+    /// Returns the number of sectors in the region.
+    fn sector_count(&self) -> u32;
+
+    /// Reads from `offset` to fill `buf`.
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Programs `data` at `offset`, clearing bits; the bytes must have been
+    /// erased first.
+    fn program(&self, offset: usize, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Erases sector `sector` of the region to all ones.
+    fn erase_sector(&self, sector: u32) -> Result<(), Self::Error>;
+}
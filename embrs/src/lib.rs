@@ -3,7 +3,12 @@
 #![feature(lang_items)]
 #![feature(naked_functions)]
 
-#![no_std]
+// With `heap`, this crate supplies the global allocator (see `alloc`).
+#![cfg_attr(all(feature = "heap", not(feature = "host-test")),
+            feature(allocator))]
+#![cfg_attr(all(feature = "heap", not(feature = "host-test")), allocator)]
+
+#![cfg_attr(not(feature = "host-test"), no_std)]
 
 #![no_builtins]
 
@@ -21,8 +26,40 @@
 #[macro_use]
 extern crate bitflags;
 
+// With `host-test` we link `std`, which doesn't bring `core` into scope.
+#[cfg(feature = "host-test")]
+extern crate core;
+
 pub mod bits;
 
+#[macro_use]
 pub mod arm_m;
+// Declared early so that its macros are available to the modules below.
+#[macro_use]
+pub mod log;
+#[cfg(feature = "heap")]
+pub mod alloc;
+pub mod backoff;
+pub mod boot;
+#[cfg(not(feature = "arch:armv6-m"))]
+pub mod clock;
+pub mod crc;
+pub mod drivers;
+pub mod dsp;
+pub mod fs;
+pub mod hal;
+#[cfg(not(feature = "host-test"))]
 pub mod lang;
+pub mod net;
+pub mod prng;
+pub mod proto;
+pub mod stm32;
+#[cfg(feature = "soc_family:stm32f1")]
+pub mod stm32f1;
+#[cfg(not(feature = "arch:armv6-m"))]
 pub mod stm32f4;
+pub mod storage;
+pub mod sync;
+#[cfg(not(feature = "arch:armv6-m"))]
+pub mod time;
+pub mod usb;
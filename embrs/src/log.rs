@@ -0,0 +1,373 @@
+//! Diagnostic logging.
+//!
+//! Drivers and applications log through four macros, one per severity:
+//!
+//!     log_warn!("rx overrun on {:?}", port);
+//!     log_debug!("flushed {} bytes", n);
+//!
+//! Each message becomes a `Record` -- severity, the `time::now_ms` timestamp,
+//! the calling module, and the formatted text -- which is handed to every
+//! registered `Sink`.  Three sinks are provided:
+//!
+//! - `SerialSink` writes lines to a `SerialWrite`, such as a USART.
+//! - `ItmSink` writes lines to an ITM stimulus port, for a debug probe
+//!   capturing SWO.
+//! - `LogRing` keeps the most recent output in RAM.  `LOG_RING` is exported
+//!   unmangled, so a debugger can read it after a crash (or a crash handler
+//!   can, with `LogRing::snapshot`).
+//!
+//! Sinks are registered at startup with `add_sink`; until then, messages go
+//! nowhere.
+//!
+//! # Filtering
+//!
+//! Severities are enabled at compile time by the features `log:error`,
+//! `log:warn`, `log:info` and `log:debug`, each of which implies the more
+//! severe ones.  `log:info` is on by default.  Messages at disabled
+//! severities are compiled out, arguments and all, so `log_debug!` costs
+//! nothing in builds that don't want it.
+//!
+//! # Concurrency
+//!
+//! The macros can be used from any context, including interrupt handlers.
+//! Messages aren't atomic, though: a message logged by a handler that
+//! preempts another message will appear in the middle of it.  Sinks that
+//! block (like `SerialSink` on a slow port) block their caller, so keep
+//! logging out of latency-sensitive handlers.
+
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+#[cfg(not(feature = "arch:armv6-m"))]
+use arm_m::itm::ITM;
+use hal::SerialWrite;
+use sync::{fetch_update, OnceInit};
+#[cfg(not(feature = "arch:armv6-m"))]
+use time;
+
+/// Message severities, most severe first.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug)]
+pub enum Level {
+    /// Something has failed, and the system may not recover.
+    Error,
+    /// Something unexpected happened, but was handled.
+    Warn,
+    /// Normal but significant events.
+    Info,
+    /// Detail for whoever is debugging the code that logs it.
+    Debug,
+}
+
+impl Level {
+    /// Checks whether messages at this severity are compiled in.
+    #[inline]
+    pub fn is_enabled(self) -> bool {
+        match self {
+            Level::Error => cfg!(feature = "log:error"),
+            Level::Warn => cfg!(feature = "log:warn"),
+            Level::Info => cfg!(feature = "log:info"),
+            Level::Debug => cfg!(feature = "log:debug"),
+        }
+    }
+
+    /// A short, fixed-width name for the severity.
+    pub fn tag(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN ",
+            Level::Info => "INFO ",
+            Level::Debug => "DEBUG",
+        }
+    }
+}
+
+/// A message on its way to the sinks.
+pub struct Record<'a> {
+    pub level: Level,
+    /// Milliseconds since `time::start` (or zero on parts without it).
+    pub timestamp_ms: u32,
+    /// Path of the module that logged the message.
+    pub module: &'static str,
+    /// The message itself.
+    pub args: fmt::Arguments<'a>,
+}
+
+impl<'a> Record<'a> {
+    /// Formats the record as a single line, without a line terminator:
+    ///
+    ///     [  12.345] WARN  embrs::stm32f4::usart: rx overrun
+    pub fn format<W: Write>(&self, out: &mut W) -> fmt::Result {
+        write!(out, "[{:4}.{:03}] {} {}: {}",
+               self.timestamp_ms / 1000,
+               self.timestamp_ms % 1000,
+               self.level.tag(),
+               self.module,
+               self.args)
+    }
+}
+
+/// A destination for log messages.
+///
+/// `write` may be called from any context, including from within itself (by
+/// an interrupt handler that preempts it), and has nowhere to report
+/// failure: a sink that can't deliver a message should drop it.
+pub trait Sink: Sync {
+    fn write(&self, record: &Record);
+}
+
+/// Maximum number of sinks that can be registered.
+pub const MAX_SINKS : usize = 4;
+
+static SINKS : [OnceInit<&'static Sink>; MAX_SINKS] = [
+    OnceInit::new(),
+    OnceInit::new(),
+    OnceInit::new(),
+    OnceInit::new(),
+];
+
+/// Registers `sink` to receive all subsequent messages.  Sinks can't be
+/// removed.
+///
+/// If `MAX_SINKS` sinks are already registered, the sink is handed back.
+pub fn add_sink(sink: &'static Sink) -> Result<(), &'static Sink> {
+    let mut sink = sink;
+    for slot in &SINKS {
+        match slot.set(sink) {
+            Ok(_) => return Ok(()),
+            Err(s) => sink = s,
+        }
+    }
+    Err(sink)
+}
+
+#[cfg(not(feature = "arch:armv6-m"))]
+fn timestamp() -> u32 {
+    time::now_ms()
+}
+
+#[cfg(feature = "arch:armv6-m")]
+fn timestamp() -> u32 {
+    0
+}
+
+/// Sends a message to the registered sinks, regardless of whether `level`
+/// is enabled.  This is the back end of the logging macros, which should be
+/// used instead.
+pub fn log(level: Level, module: &'static str, args: fmt::Arguments) {
+    let record = Record {
+        level: level,
+        timestamp_ms: timestamp(),
+        module: module,
+        args: args,
+    };
+    for slot in &SINKS {
+        if let Some(sink) = slot.try_get() {
+            sink.write(&record)
+        }
+    }
+}
+
+/// Logs a message at `level`, if that level is enabled.
+#[macro_export]
+macro_rules! log_at {
+    ($level:expr, $($arg:tt)+) => {
+        if $level.is_enabled() {
+            $crate::log::log($level, module_path!(), format_args!($($arg)+))
+        }
+    };
+}
+
+/// Logs a message at `Level::Error`.  Arguments are as for `format!`.
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)+) => { log_at!($crate::log::Level::Error, $($arg)+) };
+}
+
+/// Logs a message at `Level::Warn`.  Arguments are as for `format!`.
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)+) => { log_at!($crate::log::Level::Warn, $($arg)+) };
+}
+
+/// Logs a message at `Level::Info`.  Arguments are as for `format!`.
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)+) => { log_at!($crate::log::Level::Info, $($arg)+) };
+}
+
+/// Logs a message at `Level::Debug`.  Arguments are as for `format!`.
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)+) => { log_at!($crate::log::Level::Debug, $($arg)+) };
+}
+
+/// Sink writing CR-LF terminated lines to a serial port.
+///
+///     static CONSOLE: SerialSink<Usart> = SerialSink::new(&USART2);
+///
+///     log::add_sink(&CONSOLE).ok().expect("no room for sink");
+pub struct SerialSink<W: SerialWrite + Sync + 'static> {
+    port: &'static W,
+}
+
+impl<W: SerialWrite + Sync + 'static> SerialSink<W> {
+    pub const fn new(port: &'static W) -> SerialSink<W> {
+        SerialSink { port: port }
+    }
+}
+
+/// `fmt::Write` adapter for a `SerialWrite`.
+struct SerialFmt<'s, W: SerialWrite + 's>(&'s W);
+
+impl<'s, W: SerialWrite + 's> Write for SerialFmt<'s, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_all(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+impl<W: SerialWrite + Sync + 'static> Sink for SerialSink<W> {
+    fn write(&self, record: &Record) {
+        let mut out = SerialFmt(self.port);
+        if record.format(&mut out).is_ok() {
+            let _ = out.write_str("\r\n");
+        }
+    }
+}
+
+/// Sink writing newline-terminated lines to an ITM stimulus port.  Output is
+/// dropped unless a debugger has enabled the port.
+#[cfg(not(feature = "arch:armv6-m"))]
+pub struct ItmSink {
+    port: usize,
+}
+
+#[cfg(not(feature = "arch:armv6-m"))]
+impl ItmSink {
+    pub const fn new(port: usize) -> ItmSink {
+        ItmSink { port: port }
+    }
+}
+
+/// `fmt::Write` adapter for an ITM stimulus port.
+#[cfg(not(feature = "arch:armv6-m"))]
+struct ItmFmt(usize);
+
+#[cfg(not(feature = "arch:armv6-m"))]
+impl Write for ItmFmt {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        ITM.write_all(self.0, s.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "arch:armv6-m"))]
+impl Sink for ItmSink {
+    fn write(&self, record: &Record) {
+        if !ITM.is_port_enabled(self.port) {
+            return
+        }
+        let mut out = ItmFmt(self.port);
+        let _ = record.format(&mut out);
+        let _ = out.write_str("\n");
+    }
+}
+
+/// Size of the `LogRing` buffer, in bytes.  This is a power of two, so that
+/// `head` can wrap without upsetting the modulus.
+pub const RING_LEN : usize = 1024;
+
+/// Sink keeping the last `RING_LEN` bytes of output, as newline-terminated
+/// lines, in RAM.
+///
+/// The text is in `buf`, oldest first starting at `head % RING_LEN` once the
+/// buffer has filled.  `head` counts every byte ever written, so a debugger
+/// can tell whether the buffer has wrapped.
+#[repr(C)]
+pub struct LogRing {
+    head: AtomicUsize,
+    buf: UnsafeCell<[u8; RING_LEN]>,
+}
+
+unsafe impl Sync for LogRing {}
+
+impl LogRing {
+    pub const fn new() -> LogRing {
+        LogRing {
+            head: ATOMIC_USIZE_INIT,
+            buf: UnsafeCell::new([0; RING_LEN]),
+        }
+    }
+
+    /// Appends `bytes`, overwriting the oldest output.
+    ///
+    /// Space is claimed before it's filled, so concurrent writers don't
+    /// overwrite each other (unless one writes more than `RING_LEN` bytes
+    /// while the other is preempted).
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        let n = bytes.len();
+        let start = match fetch_update(&self.head,
+                                       |h| Some(h.wrapping_add(n))) {
+            Ok(h) => h,
+            Err(h) => h,
+        };
+        let buf = self.buf.get();
+        for (i, &b) in bytes.iter().enumerate() {
+            unsafe {
+                (*buf)[start.wrapping_add(i) % RING_LEN] = b
+            }
+        }
+    }
+
+    /// Total bytes written since startup, wrapping at the word size.
+    pub fn bytes_written(&self) -> usize {
+        self.head.load(Ordering::Relaxed)
+    }
+
+    /// Copies the most recent output, oldest first, into `out`, and returns
+    /// the number of bytes copied.  This is the lesser of `out.len()`,
+    /// `RING_LEN`, and `bytes_written()`.
+    ///
+    /// Messages logged during the copy may leave it with a mix of old and
+    /// new text.
+    pub fn snapshot(&self, out: &mut [u8]) -> usize {
+        let head = self.bytes_written();
+        let mut n = out.len();
+        if n > RING_LEN {
+            n = RING_LEN
+        }
+        if n > head {
+            n = head
+        }
+        let start = head.wrapping_sub(n);
+        let buf = self.buf.get();
+        for (i, b) in out[..n].iter_mut().enumerate() {
+            *b = unsafe { (*buf)[start.wrapping_add(i) % RING_LEN] }
+        }
+        n
+    }
+}
+
+/// `fmt::Write` adapter for a `LogRing`.
+struct RingFmt<'r>(&'r LogRing);
+
+impl<'r> Write for RingFmt<'r> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl Sink for LogRing {
+    fn write(&self, record: &Record) {
+        let mut out = RingFmt(self);
+        let _ = record.format(&mut out);
+        let _ = out.write_str("\n");
+    }
+}
+
+/// The in-RAM log.  It only receives messages once registered:
+///
+///     log::add_sink(&log::LOG_RING).ok().expect("no room for sink");
+#[no_mangle]
+pub static LOG_RING: LogRing = LogRing::new();
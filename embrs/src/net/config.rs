@@ -0,0 +1,188 @@
+//! IPv4 interface configuration.
+//!
+//! `IpConfig` is the handful of numbers an interface needs to take part in
+//! an IPv4 network, whether fixed in the firmware or leased from a DHCP
+//! server (see `dhcp`).  `IpConfigCell` holds the current configuration
+//! where the rest of the application can see it:
+//!
+//!     static IP: IpConfigCell = IpConfigCell::new();
+//!
+//!     IP.set(&IpConfig::new_static([192, 168, 1, 50], 24,
+//!                                  Some([192, 168, 1, 1])));
+//!
+//!     if let Some(cfg) = IP.get() {
+//!         stack.set_address(cfg.address, cfg.prefix_len());
+//!     }
+//!
+//! Addresses are in network order: `[192, 168, 1, 50]` is 192.168.1.50.
+
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use arm_m;
+
+pub type Ipv4Address = [u8; 4];
+
+/// The unspecified address, 0.0.0.0.
+pub const UNSPECIFIED : Ipv4Address = [0; 4];
+/// The limited broadcast address, 255.255.255.255.
+pub const BROADCAST : Ipv4Address = [255; 4];
+
+/// An interface's IPv4 settings.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct IpConfig {
+    pub address: Ipv4Address,
+    pub netmask: Ipv4Address,
+    /// Default router, if there is one.
+    pub gateway: Option<Ipv4Address>,
+    /// DNS server, if one is known.
+    pub dns: Option<Ipv4Address>,
+}
+
+impl IpConfig {
+    /// Creates a fixed configuration of `address` on a subnet of
+    /// `prefix_len` bits.
+    pub fn new_static(address: Ipv4Address,
+                      prefix_len: u8,
+                      gateway: Option<Ipv4Address>)
+        -> IpConfig {
+        IpConfig {
+            address: address,
+            netmask: netmask(prefix_len),
+            gateway: gateway,
+            dns: None,
+        }
+    }
+
+    /// Length of the netmask in bits, assuming it's contiguous.
+    pub fn prefix_len(&self) -> u8 {
+        to_u32(&self.netmask).count_ones() as u8
+    }
+
+    /// The subnet's own address: the host part all zeros.
+    pub fn network(&self) -> Ipv4Address {
+        from_u32(to_u32(&self.address) & to_u32(&self.netmask))
+    }
+
+    /// The subnet's broadcast address: the host part all ones.
+    pub fn broadcast(&self) -> Ipv4Address {
+        from_u32(to_u32(&self.address) | !to_u32(&self.netmask))
+    }
+
+    /// Checks whether `addr` is on our subnet, and so reachable without the
+    /// gateway.
+    pub fn is_local(&self, addr: &Ipv4Address) -> bool {
+        let mask = to_u32(&self.netmask);
+        to_u32(addr) & mask == to_u32(&self.address) & mask
+    }
+
+    /// Picks where to send a packet for `addr`: straight there if it's on
+    /// our subnet, otherwise to the gateway (if we have one).
+    pub fn next_hop(&self, addr: &Ipv4Address) -> Option<Ipv4Address> {
+        if self.is_local(addr) || *addr == BROADCAST {
+            Some(*addr)
+        } else {
+            self.gateway
+        }
+    }
+}
+
+/// Produces the netmask with the top `prefix_len` bits set.  Lengths over
+/// 32 are treated as 32.
+pub fn netmask(prefix_len: u8) -> Ipv4Address {
+    if prefix_len == 0 {
+        UNSPECIFIED
+    } else if prefix_len >= 32 {
+        BROADCAST
+    } else {
+        from_u32(!0 << (32 - prefix_len as u32))
+    }
+}
+
+pub fn to_u32(a: &Ipv4Address) -> u32 {
+    (a[0] as u32) << 24 | (a[1] as u32) << 16 | (a[2] as u32) << 8
+        | a[3] as u32
+}
+
+pub fn from_u32(v: u32) -> Ipv4Address {
+    [(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]
+}
+
+fn load(a: &AtomicUsize) -> Ipv4Address {
+    from_u32(a.load(Ordering::Relaxed) as u32)
+}
+
+fn store(a: &AtomicUsize, v: &Ipv4Address) {
+    a.store(to_u32(v) as usize, Ordering::Relaxed)
+}
+
+/// Maps 0.0.0.0, which stands for an absent address, to `None`.
+fn nonzero(a: Ipv4Address) -> Option<Ipv4Address> {
+    if a == UNSPECIFIED { None } else { Some(a) }
+}
+
+/// Holds the current `IpConfig` (or its absence) for sharing between the
+/// code that maintains it and the code that uses it, which may run at
+/// different interrupt priorities.
+pub struct IpConfigCell {
+    address: AtomicUsize,
+    netmask: AtomicUsize,
+    gateway: AtomicUsize,
+    dns: AtomicUsize,
+    /// Bumped on every change; odd while a configuration is held.
+    generation: AtomicUsize,
+}
+
+impl IpConfigCell {
+    pub const fn new() -> IpConfigCell {
+        IpConfigCell {
+            address: ATOMIC_USIZE_INIT,
+            netmask: ATOMIC_USIZE_INIT,
+            gateway: ATOMIC_USIZE_INIT,
+            dns: ATOMIC_USIZE_INIT,
+            generation: ATOMIC_USIZE_INIT,
+        }
+    }
+
+    /// Gets the current configuration, if there is one.
+    pub fn get(&self) -> Option<IpConfig> {
+        arm_m::without_interrupts(|| {
+            if self.generation.load(Ordering::Relaxed) & 1 == 0 {
+                return None
+            }
+            Some(IpConfig {
+                address: load(&self.address),
+                netmask: load(&self.netmask),
+                gateway: nonzero(load(&self.gateway)),
+                dns: nonzero(load(&self.dns)),
+            })
+        })
+    }
+
+    /// Replaces the configuration.
+    pub fn set(&self, cfg: &IpConfig) {
+        arm_m::without_interrupts(|| {
+            store(&self.address, &cfg.address);
+            store(&self.netmask, &cfg.netmask);
+            store(&self.gateway, &cfg.gateway.unwrap_or(UNSPECIFIED));
+            store(&self.dns, &cfg.dns.unwrap_or(UNSPECIFIED));
+            let g = self.generation.load(Ordering::Relaxed);
+            self.generation.store((g | 1).wrapping_add(2), Ordering::Relaxed)
+        })
+    }
+
+    /// Forgets the configuration, as when a lease runs out.
+    pub fn clear(&self) {
+        arm_m::without_interrupts(|| {
+            let g = self.generation.load(Ordering::Relaxed);
+            if g & 1 != 0 {
+                self.generation.store(g.wrapping_add(1), Ordering::Relaxed)
+            }
+        })
+    }
+
+    /// Counts changes to the configuration, so that users can notice them
+    /// by comparing with the value they last saw.
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::Relaxed)
+    }
+}
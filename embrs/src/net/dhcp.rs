@@ -0,0 +1,809 @@
+//! DHCP client.
+//!
+//! `DhcpClient` gets an IPv4 configuration from a DHCP server and keeps the
+//! lease renewed, publishing the result in an `IpConfigCell`.  It only
+//! builds and interprets DHCP messages; moving them is left to the
+//! application's IP stack, through a UDP socket bound to port 68:
+//!
+//!     static IP: IpConfigCell = IpConfigCell::new();
+//!
+//!     fn dhcp_timer(_: TimerId) {
+//!         // Wake the main loop, if it sleeps.
+//!     }
+//!
+//!     let timer = WHEEL.claim().unwrap();
+//!     let mut dhcp = DhcpClient::new(&mac, seed, &WHEEL, timer, dhcp_timer,
+//!                                    &IP);
+//!     loop {
+//!         while let Some((msg, _)) = socket.recv() {
+//!             dhcp.receive(msg);
+//!         }
+//!         let mut buf = [0; MESSAGE_LEN];
+//!         if let Some(to) = dhcp.poll(&mut buf) {
+//!             socket.send_to(&buf, to, SERVER_PORT);
+//!         }
+//!         // ...
+//!     }
+//!
+//! Until the client is bound the interface has no address, so the stack
+//! must be willing to send from 0.0.0.0 and to accept broadcasts.
+//!
+//! Timing runs on a `time::TimerWheel` timer: the client arms it for its
+//! next retransmission or lease event, and notices that it has expired the
+//! next time it's polled.  The callback given to `new` runs on expiry too,
+//! so that the application can arrange that poll.
+//!
+//! The client follows RFC 2131's state machine, less the INIT-REBOOT path
+//! for reclaiming a remembered address, and doesn't check offered addresses
+//! for conflicts with ARP.
+
+use core::cmp;
+
+use backoff::jittered;
+use prng::XorShift32;
+use time::{TimerCallback, TimerId, TimerWheel};
+use super::config::{IpConfig, IpConfigCell, Ipv4Address, BROADCAST,
+                    UNSPECIFIED};
+
+/// UDP port servers listen on.
+pub const SERVER_PORT : u16 = 67;
+/// UDP port clients listen on.
+pub const CLIENT_PORT : u16 = 68;
+
+/// Length of the messages `poll` builds, padded out to the minimum BOOTP
+/// message size.
+pub const MESSAGE_LEN : usize = 300;
+
+// Fixed-format part of the message.
+const OP : usize = 0;
+const XID : usize = 4;
+const FLAGS : usize = 10;
+const CIADDR : usize = 12;
+const YIADDR : usize = 16;
+const CHADDR : usize = 28;
+const COOKIE : usize = 236;
+const OPTIONS : usize = 240;
+
+const BOOTREQUEST : u8 = 1;
+const BOOTREPLY : u8 = 2;
+const HTYPE_ETHERNET : u8 = 1;
+/// Asks the server to broadcast its replies, since we can't take unicast
+/// before we have an address.
+const FLAG_BROADCAST : u8 = 0x80;
+const MAGIC_COOKIE : [u8; 4] = [99, 130, 83, 99];
+
+// Options.
+const OPT_PAD : u8 = 0;
+const OPT_SUBNET_MASK : u8 = 1;
+const OPT_ROUTER : u8 = 3;
+const OPT_DNS : u8 = 6;
+const OPT_REQUESTED_IP : u8 = 50;
+const OPT_LEASE_TIME : u8 = 51;
+const OPT_MESSAGE_TYPE : u8 = 53;
+const OPT_SERVER_ID : u8 = 54;
+const OPT_PARAMETER_LIST : u8 = 55;
+const OPT_RENEWAL_TIME : u8 = 58;
+const OPT_REBINDING_TIME : u8 = 59;
+const OPT_CLIENT_ID : u8 = 61;
+const OPT_END : u8 = 255;
+
+// Message types.
+const DHCPDISCOVER : u8 = 1;
+const DHCPOFFER : u8 = 2;
+const DHCPREQUEST : u8 = 3;
+const DHCPACK : u8 = 5;
+const DHCPNAK : u8 = 6;
+
+/// First retransmission delay, in seconds; it doubles with each attempt.
+const RETRY_FIRST_S : u32 = 4;
+/// Longest retransmission delay while looking for a server.
+const RETRY_MAX_S : u32 = 64;
+/// REQUESTs sent for an offer before starting over.
+const REQUEST_ATTEMPTS : u32 = 4;
+/// Shortest retransmission delay while renewing or rebinding.
+const RENEW_RETRY_MIN_S : u32 = 60;
+/// Longest single timer run, keeping well inside the timer's range.
+const MAX_WAIT_S : u32 = 24 * 60 * 60;
+/// Lease assumed if the server doesn't give one.
+const DEFAULT_LEASE_S : u32 = 60 * 60;
+
+/// Client states, after RFC 2131 figure 5.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum State {
+    /// About to look for a server.
+    Init,
+    /// Broadcasting DISCOVERs, waiting for an offer.
+    Selecting,
+    /// Requesting an offered address.
+    Requesting,
+    /// Holding a lease.
+    Bound,
+    /// Asking the leasing server to extend the lease.
+    Renewing,
+    /// Asking any server to extend the lease.
+    Rebinding,
+}
+
+/// A lease, with its times in seconds from when it was granted.
+#[derive(Copy, Clone, Debug)]
+struct Lease {
+    config: IpConfig,
+    server: Ipv4Address,
+    t1: u32,
+    t2: u32,
+    end: u32,
+}
+
+/// The parts of a server's reply we use.
+struct Reply {
+    kind: u8,
+    yiaddr: Ipv4Address,
+    server: Option<Ipv4Address>,
+    netmask: Option<Ipv4Address>,
+    router: Option<Ipv4Address>,
+    dns: Option<Ipv4Address>,
+    lease: Option<u32>,
+    t1: Option<u32>,
+    t2: Option<u32>,
+}
+
+/// A DHCP client for one interface.
+pub struct DhcpClient<'a> {
+    mac: [u8; 6],
+    rng: XorShift32,
+    wheel: &'a TimerWheel,
+    timer: TimerId,
+    callback: TimerCallback,
+    config: &'a IpConfigCell,
+
+    state: State,
+    xid: u32,
+    /// Retransmissions in the current state.
+    attempts: u32,
+    /// A message is due to be sent.
+    send_pending: bool,
+    /// The timer is running, for `armed_s` seconds.
+    waiting: bool,
+    armed_s: u32,
+    /// Seconds since the lease was granted, as of the last timer expiry.
+    elapsed_s: u32,
+    /// The address being requested, and the server offering it.
+    offer: Ipv4Address,
+    offer_server: Ipv4Address,
+    lease: Option<Lease>,
+}
+
+impl<'a> DhcpClient<'a> {
+    /// Creates a client for the interface with address `mac`, which will
+    /// publish its configuration to `config`.  `timer` must be a slot
+    /// claimed from `wheel`, and `callback` is called when it expires.
+    /// `seed` should differ between devices, such as one taken from the
+    /// unique ID, so that they don't retransmit in lockstep.
+    pub fn new(mac: &[u8; 6],
+               seed: u32,
+               wheel: &'a TimerWheel,
+               timer: TimerId,
+               callback: TimerCallback,
+               config: &'a IpConfigCell)
+        -> DhcpClient<'a> {
+        DhcpClient {
+            mac: *mac,
+            rng: XorShift32::new(seed),
+            wheel: wheel,
+            timer: timer,
+            callback: callback,
+            config: config,
+            state: State::Init,
+            xid: 0,
+            attempts: 0,
+            send_pending: false,
+            waiting: false,
+            armed_s: 0,
+            elapsed_s: 0,
+            offer: UNSPECIFIED,
+            offer_server: UNSPECIFIED,
+            lease: None,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Drops any lease and starts over, as when the link has been down and
+    /// we may be on a different network.
+    pub fn restart(&mut self) {
+        self.enter_init()
+    }
+
+    fn enter_init(&mut self) {
+        if self.lease.take().is_some() {
+            self.config.clear()
+        }
+        let _ = self.wheel.cancel(self.timer);
+        self.waiting = false;
+        self.send_pending = false;
+        self.state = State::Init;
+    }
+
+    /// Starts the timer for `secs` seconds, or as much of that as it can
+    /// manage in one go; `on_timeout` goes back to waiting if it was cut
+    /// short.
+    fn wait(&mut self, secs: u32) {
+        self.wait_ms(cmp::min(secs, MAX_WAIT_S) * 1000)
+    }
+
+    fn wait_ms(&mut self, ms: u32) {
+        // The timer was claimed for us, so this can't fail.
+        let _ = self.wheel.start_one_shot(self.timer, ms, self.callback);
+        self.armed_s = ms / 1000;
+        self.waiting = true;
+    }
+
+    /// Waits out the next retransmission while looking for a lease: 4
+    /// seconds, then 8, and so on up to 64, each give or take a second.
+    fn wait_retry(&mut self) {
+        let shift = cmp::min(self.attempts, 4);
+        let base = cmp::min(RETRY_FIRST_S << shift, RETRY_MAX_S) * 1000;
+        let ms = jittered(&mut self.rng, base - 1000, 2000);
+        self.attempts += 1;
+        self.wait_ms(ms)
+    }
+
+    /// Sends a message for the current state, and arranges to send it again
+    /// if there's no reply.
+    fn transmit(&mut self) {
+        self.send_pending = true;
+        match self.state {
+            State::Selecting | State::Requesting => self.wait_retry(),
+            State::Renewing | State::Rebinding => {
+                // Half the time left to the next deadline, but not too
+                // often.
+                let deadline = match (self.state, self.lease) {
+                    (State::Renewing, Some(l)) => l.t2,
+                    (_, Some(l)) => l.end,
+                    (_, None) => self.elapsed_s,
+                };
+                let left = deadline.saturating_sub(self.elapsed_s);
+                self.wait(cmp::min(left,
+                                   cmp::max(left / 2, RENEW_RETRY_MIN_S)))
+            },
+            _ => (),
+        }
+    }
+
+    /// Handles the expiry of the timer.
+    fn on_timeout(&mut self) {
+        self.elapsed_s = self.elapsed_s.saturating_add(self.armed_s);
+        let lease = match self.lease {
+            Some(l) => l,
+            None => {
+                // Selecting or requesting: try again.
+                if self.state == State::Requesting
+                    && self.attempts >= REQUEST_ATTEMPTS {
+                    self.enter_init()
+                } else {
+                    self.transmit()
+                }
+                return
+            },
+        };
+        if self.elapsed_s >= lease.end {
+            return self.enter_init()
+        }
+        match self.state {
+            State::Bound | State::Renewing if self.elapsed_s >= lease.t2 => {
+                self.state = State::Rebinding;
+                self.new_xid();
+                self.transmit()
+            },
+            State::Bound if self.elapsed_s >= lease.t1 => {
+                self.state = State::Renewing;
+                self.new_xid();
+                self.transmit()
+            },
+            State::Bound => self.wait(lease.t1 - self.elapsed_s),
+            _ => self.transmit(),
+        }
+    }
+
+    fn new_xid(&mut self) {
+        self.xid = self.rng.next_u32();
+        self.attempts = 0;
+    }
+
+    /// Advances the client, returning the address to which the message it
+    /// has left in `out` should be sent, if there is one.  `out` must be at
+    /// least `MESSAGE_LEN` long, and the message always fills exactly that
+    /// much of it.
+    pub fn poll(&mut self, out: &mut [u8]) -> Option<Ipv4Address> {
+        if self.waiting && !self.wheel.is_active(self.timer) {
+            self.waiting = false;
+            self.on_timeout()
+        }
+        if self.state == State::Init {
+            self.state = State::Selecting;
+            self.new_xid();
+            self.transmit()
+        }
+        if !self.send_pending {
+            return None
+        }
+        self.send_pending = false;
+
+        let (kind, to) = match (self.state, self.lease) {
+            (State::Selecting, _) => (DHCPDISCOVER, BROADCAST),
+            (State::Renewing, Some(l)) => (DHCPREQUEST, l.server),
+            _ => (DHCPREQUEST, BROADCAST),
+        };
+        self.build(kind, out);
+        Some(to)
+    }
+
+    /// Writes a `kind` message into `out`.
+    fn build(&self, kind: u8, out: &mut [u8]) {
+        let out = &mut out[..MESSAGE_LEN];
+        for b in out.iter_mut() {
+            *b = 0
+        }
+        out[OP] = BOOTREQUEST;
+        out[OP + 1] = HTYPE_ETHERNET;
+        out[OP + 2] = 6;
+        put_be32(&mut out[XID..], self.xid);
+        out[CHADDR..CHADDR + 6].copy_from_slice(&self.mac);
+        out[COOKIE..OPTIONS].copy_from_slice(&MAGIC_COOKIE);
+
+        let mut o = Options { buf: out, pos: OPTIONS };
+        o.put(OPT_MESSAGE_TYPE, &[kind]);
+        let mut id = [HTYPE_ETHERNET; 7];
+        id[1..].copy_from_slice(&self.mac);
+        o.put(OPT_CLIENT_ID, &id);
+        match (self.state, self.lease) {
+            (State::Renewing, Some(l)) | (State::Rebinding, Some(l)) => {
+                // We own the address, so can say so, and take unicast.
+                o.buf[CIADDR..CIADDR + 4].copy_from_slice(&l.config.address)
+            },
+            (State::Requesting, _) => {
+                o.put(OPT_REQUESTED_IP, &self.offer);
+                o.put(OPT_SERVER_ID, &self.offer_server);
+                o.buf[FLAGS] = FLAG_BROADCAST
+            },
+            _ => o.buf[FLAGS] = FLAG_BROADCAST,
+        }
+        o.put(OPT_PARAMETER_LIST, &[OPT_SUBNET_MASK, OPT_ROUTER, OPT_DNS,
+                                    OPT_LEASE_TIME, OPT_RENEWAL_TIME,
+                                    OPT_REBINDING_TIME]);
+        o.buf[o.pos] = OPT_END;
+    }
+
+    /// Takes a message received on `CLIENT_PORT`.  Messages that aren't
+    /// replies to our latest request are ignored.
+    pub fn receive(&mut self, msg: &[u8]) {
+        let reply = match self.parse(msg) {
+            Some(r) => r,
+            None => return,
+        };
+        match (self.state, reply.kind) {
+            (State::Selecting, DHCPOFFER) => {
+                let server = match reply.server {
+                    Some(s) => s,
+                    None => return,
+                };
+                // Take the first offer.
+                self.offer = reply.yiaddr;
+                self.offer_server = server;
+                self.state = State::Requesting;
+                self.attempts = 0;
+                self.transmit()
+            },
+            (State::Requesting, DHCPACK)
+                | (State::Renewing, DHCPACK)
+                | (State::Rebinding, DHCPACK) => self.bind(&reply),
+            (State::Requesting, DHCPNAK)
+                | (State::Renewing, DHCPNAK)
+                | (State::Rebinding, DHCPNAK) => self.enter_init(),
+            _ => (),
+        }
+    }
+
+    /// Takes up the lease granted by `ack`.
+    fn bind(&mut self, ack: &Reply) {
+        let server = match (ack.server, self.lease) {
+            (Some(s), _) => s,
+            (None, Some(l)) => l.server,
+            (None, None) => self.offer_server,
+        };
+        let end = ack.lease.unwrap_or(DEFAULT_LEASE_S);
+        let t2 = ack.t2.unwrap_or(end / 8 * 7);
+        let t1 = ack.t1.unwrap_or(end / 2);
+        let config = IpConfig {
+            address: ack.yiaddr,
+            netmask: match ack.netmask {
+                Some(m) => m,
+                None => default_netmask(&ack.yiaddr),
+            },
+            gateway: ack.router,
+            dns: ack.dns,
+        };
+        self.lease = Some(Lease {
+            config: config,
+            server: server,
+            t1: cmp::min(t1, t2),
+            t2: cmp::min(t2, end),
+            end: end,
+        });
+        self.config.set(&config);
+        self.state = State::Bound;
+        self.attempts = 0;
+        self.elapsed_s = 0;
+        if end == !0 {
+            // An infinite lease never needs renewing.
+            let _ = self.wheel.cancel(self.timer);
+            self.waiting = false;
+        } else {
+            self.wait(cmp::min(t1, t2))
+        }
+    }
+
+    /// Picks out a server reply to our current transaction.
+    fn parse(&self, msg: &[u8]) -> Option<Reply> {
+        if msg.len() < OPTIONS
+            || msg[OP] != BOOTREPLY
+            || be32(&msg[XID..]) != self.xid
+            || msg[CHADDR..CHADDR + 6] != self.mac
+            || msg[COOKIE..OPTIONS] != MAGIC_COOKIE {
+            return None
+        }
+        let mut r = Reply {
+            kind: 0,
+            yiaddr: [msg[YIADDR], msg[YIADDR + 1], msg[YIADDR + 2],
+                     msg[YIADDR + 3]],
+            server: None,
+            netmask: None,
+            router: None,
+            dns: None,
+            lease: None,
+            t1: None,
+            t2: None,
+        };
+
+        let mut pos = OPTIONS;
+        while pos < msg.len() {
+            let code = msg[pos];
+            if code == OPT_END {
+                break
+            }
+            if code == OPT_PAD {
+                pos += 1;
+                continue
+            }
+            if pos + 2 > msg.len() {
+                return None
+            }
+            let len = msg[pos + 1] as usize;
+            let start = pos + 2;
+            if start + len > msg.len() {
+                return None
+            }
+            let v = &msg[start..start + len];
+            match code {
+                OPT_MESSAGE_TYPE if len == 1 => r.kind = v[0],
+                OPT_SERVER_ID if len == 4 => r.server = Some(addr(v)),
+                OPT_SUBNET_MASK if len == 4 => r.netmask = Some(addr(v)),
+                // Only the first router and DNS server are kept.
+                OPT_ROUTER if len >= 4 => r.router = Some(addr(v)),
+                OPT_DNS if len >= 4 => r.dns = Some(addr(v)),
+                OPT_LEASE_TIME if len == 4 => r.lease = Some(be32(v)),
+                OPT_RENEWAL_TIME if len == 4 => r.t1 = Some(be32(v)),
+                OPT_REBINDING_TIME if len == 4 => r.t2 = Some(be32(v)),
+                _ => (),
+            }
+            pos = start + len;
+        }
+        if r.kind == 0 {
+            return None
+        }
+        Some(r)
+    }
+}
+
+/// Appends options to a message under construction.
+struct Options<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+}
+
+impl<'b> Options<'b> {
+    fn put(&mut self, code: u8, value: &[u8]) {
+        let p = self.pos;
+        self.buf[p] = code;
+        self.buf[p + 1] = value.len() as u8;
+        self.buf[p + 2..p + 2 + value.len()].copy_from_slice(value);
+        self.pos = p + 2 + value.len();
+    }
+}
+
+/// The netmask of `a`'s classful network, for servers that don't send one.
+fn default_netmask(a: &Ipv4Address) -> Ipv4Address {
+    match a[0] {
+        0...127 => [255, 0, 0, 0],
+        128...191 => [255, 255, 0, 0],
+        _ => [255, 255, 255, 0],
+    }
+}
+
+fn addr(b: &[u8]) -> Ipv4Address {
+    [b[0], b[1], b[2], b[3]]
+}
+
+fn be32(b: &[u8]) -> u32 {
+    ((b[0] as u32) << 24)
+        | ((b[1] as u32) << 16)
+        | ((b[2] as u32) << 8)
+        | (b[3] as u32)
+}
+
+fn put_be32(b: &mut [u8], v: u32) {
+    b[0] = (v >> 24) as u8;
+    b[1] = (v >> 16) as u8;
+    b[2] = (v >> 8) as u8;
+    b[3] = v as u8;
+}
+
+#[cfg(all(test, feature = "host-test"))]
+mod tests {
+    use time::{now_ms, SoftTimer, TimerId, TimerWheel};
+    use super::super::config::{IpConfig, IpConfigCell, Ipv4Address,
+                               BROADCAST};
+    use super::*;
+
+    const MAC : [u8; 6] = [0x02, 0x00, 0x00, 0x12, 0x34, 0x56];
+    const SERVER : Ipv4Address = [192, 168, 1, 1];
+    const OFFERED : Ipv4Address = [192, 168, 1, 50];
+
+    fn nothing(_: TimerId) {}
+
+    fn client<'a>(wheel: &'a TimerWheel, cell: &'a IpConfigCell)
+        -> DhcpClient<'a> {
+        DhcpClient::new(&MAC, 1, wheel, wheel.claim().unwrap(), nothing,
+                        cell)
+    }
+
+    /// Runs the client's timer out.
+    fn expire(wheel: &TimerWheel) {
+        wheel.tick(now_ms().wrapping_add(MAX_WAIT_S * 1000 + 1));
+        wheel.dispatch()
+    }
+
+    /// Polls `c`, returning where its message goes, and the message.
+    fn poll(c: &mut DhcpClient) -> Option<(Ipv4Address, Vec<u8>)> {
+        let mut buf = vec![0; MESSAGE_LEN];
+        c.poll(&mut buf).map(|to| (to, buf))
+    }
+
+    /// Finds option `code` in `msg`.
+    fn option(msg: &[u8], code: u8) -> Option<&[u8]> {
+        let mut pos = OPTIONS;
+        while msg[pos] != OPT_END {
+            let len = msg[pos + 1] as usize;
+            if msg[pos] == code {
+                return Some(&msg[pos + 2..pos + 2 + len])
+            }
+            pos += 2 + len
+        }
+        None
+    }
+
+    /// Builds a server's reply of type `kind` to `request`, followed by
+    /// the raw option bytes `options` (and nothing else, not even an end
+    /// option).
+    fn reply(request: &[u8], kind: u8, options: &[u8]) -> Vec<u8> {
+        let mut m = vec![0; OPTIONS];
+        m[OP] = BOOTREPLY;
+        m[OP + 1] = HTYPE_ETHERNET;
+        m[OP + 2] = 6;
+        m[XID..XID + 4].copy_from_slice(&request[XID..XID + 4]);
+        m[YIADDR..YIADDR + 4].copy_from_slice(&OFFERED);
+        m[CHADDR..CHADDR + 6].copy_from_slice(&MAC);
+        m[COOKIE..OPTIONS].copy_from_slice(&MAGIC_COOKIE);
+        m.extend(&[OPT_MESSAGE_TYPE, 1, kind]);
+        m.extend(options);
+        m
+    }
+
+    fn offer(request: &[u8]) -> Vec<u8> {
+        reply(request, DHCPOFFER,
+              &[OPT_SERVER_ID, 4, 192, 168, 1, 1, OPT_END])
+    }
+
+    /// Runs `c` through to a lease of `lease_s` seconds.
+    fn bind(c: &mut DhcpClient, lease_s: u8) {
+        let (_, discover) = poll(c).unwrap();
+        c.receive(&offer(&discover));
+        let (_, request) = poll(c).unwrap();
+        c.receive(&reply(&request, DHCPACK,
+                         &[OPT_SERVER_ID, 4, 192, 168, 1, 1,
+                           OPT_LEASE_TIME, 4, 0, 0, 0, lease_s,
+                           OPT_END]));
+        assert_eq!(State::Bound, c.state());
+    }
+
+    #[test]
+    fn binds_after_offer_and_ack() {
+        static TIMERS : [SoftTimer; 1] = [SoftTimer::new()];
+        let wheel = TimerWheel::new(&TIMERS);
+        let cell = IpConfigCell::new();
+        let mut c = client(&wheel, &cell);
+
+        let (to, discover) = poll(&mut c).unwrap();
+        assert_eq!(BROADCAST, to);
+        assert_eq!(State::Selecting, c.state());
+        assert_eq!(Some(&[DHCPDISCOVER][..]),
+                   option(&discover, OPT_MESSAGE_TYPE));
+        assert_eq!(&MAC, &discover[CHADDR..CHADDR + 6]);
+        assert!(poll(&mut c).is_none());
+
+        // Unanswered, the DISCOVER goes again, in the same transaction.
+        expire(&wheel);
+        let (_, again) = poll(&mut c).unwrap();
+        assert_eq!(&discover[XID..XID + 4], &again[XID..XID + 4]);
+
+        c.receive(&offer(&discover));
+        assert_eq!(State::Requesting, c.state());
+        let (to, request) = poll(&mut c).unwrap();
+        assert_eq!(BROADCAST, to);
+        assert_eq!(Some(&[DHCPREQUEST][..]),
+                   option(&request, OPT_MESSAGE_TYPE));
+        assert_eq!(Some(&OFFERED[..]), option(&request, OPT_REQUESTED_IP));
+        assert_eq!(Some(&SERVER[..]), option(&request, OPT_SERVER_ID));
+        assert_eq!(None, cell.get());
+
+        c.receive(&reply(&request, DHCPACK,
+                         &[OPT_SERVER_ID, 4, 192, 168, 1, 1,
+                           OPT_SUBNET_MASK, 4, 255, 255, 254, 0,
+                           OPT_ROUTER, 8, 192, 168, 1, 1, 192, 168, 1, 2,
+                           OPT_DNS, 4, 8, 8, 8, 8,
+                           OPT_LEASE_TIME, 4, 0, 0, 0x0E, 0x10,
+                           OPT_END]));
+        assert_eq!(State::Bound, c.state());
+        assert_eq!(Some(IpConfig {
+                       address: OFFERED,
+                       netmask: [255, 255, 254, 0],
+                       gateway: Some(SERVER),
+                       dns: Some([8, 8, 8, 8]),
+                   }),
+                   cell.get());
+        assert!(poll(&mut c).is_none());
+    }
+
+    #[test]
+    fn ack_without_options_takes_defaults() {
+        static TIMERS : [SoftTimer; 1] = [SoftTimer::new()];
+        let wheel = TimerWheel::new(&TIMERS);
+        let cell = IpConfigCell::new();
+        let mut c = client(&wheel, &cell);
+
+        let (_, discover) = poll(&mut c).unwrap();
+        c.receive(&offer(&discover));
+        let (_, request) = poll(&mut c).unwrap();
+        c.receive(&reply(&request, DHCPACK, &[OPT_END]));
+        assert_eq!(State::Bound, c.state());
+        assert_eq!(Some(IpConfig {
+                       address: OFFERED,
+                       netmask: [255, 255, 255, 0],
+                       gateway: None,
+                       dns: None,
+                   }),
+                   cell.get());
+    }
+
+    #[test]
+    fn ignores_replies_to_others_and_malformed_replies() {
+        static TIMERS : [SoftTimer; 1] = [SoftTimer::new()];
+        let wheel = TimerWheel::new(&TIMERS);
+        let cell = IpConfigCell::new();
+        let mut c = client(&wheel, &cell);
+        let (_, discover) = poll(&mut c).unwrap();
+
+        let mut wrong_xid = offer(&discover);
+        wrong_xid[XID] ^= 1;
+        let mut wrong_mac = offer(&discover);
+        wrong_mac[CHADDR + 5] ^= 1;
+        let mut wrong_cookie = offer(&discover);
+        wrong_cookie[COOKIE] = 0;
+        let bad = [
+            wrong_xid,
+            wrong_mac,
+            wrong_cookie,
+            offer(&discover)[..OPTIONS - 1].to_vec(),
+            // An option running past the end.
+            reply(&discover, DHCPOFFER, &[OPT_SERVER_ID, 4, 192, 168]),
+            // An option code with no length.
+            reply(&discover, DHCPOFFER, &[OPT_SERVER_ID]),
+            // No server identifier.
+            reply(&discover, DHCPOFFER, &[OPT_END]),
+            // Not an offer.
+            reply(&discover, DHCPACK,
+                  &[OPT_SERVER_ID, 4, 192, 168, 1, 1, OPT_END]),
+        ];
+        for m in bad.iter() {
+            c.receive(m);
+            assert_eq!(State::Selecting, c.state());
+        }
+        // A reply without a message type isn't DHCP at all.
+        let mut bootp = offer(&discover);
+        bootp.truncate(OPTIONS);
+        bootp.extend(&[OPT_SERVER_ID, 4, 192, 168, 1, 1, OPT_END]);
+        c.receive(&bootp);
+        assert_eq!(State::Selecting, c.state());
+
+        c.receive(&offer(&discover));
+        assert_eq!(State::Requesting, c.state());
+    }
+
+    #[test]
+    fn nak_while_requesting_starts_over() {
+        static TIMERS : [SoftTimer; 1] = [SoftTimer::new()];
+        let wheel = TimerWheel::new(&TIMERS);
+        let cell = IpConfigCell::new();
+        let mut c = client(&wheel, &cell);
+
+        let (_, discover) = poll(&mut c).unwrap();
+        c.receive(&offer(&discover));
+        let (_, request) = poll(&mut c).unwrap();
+        c.receive(&reply(&request, DHCPNAK, &[OPT_END]));
+        assert_eq!(State::Init, c.state());
+        assert_eq!(None, cell.get());
+
+        let (_, discover2) = poll(&mut c).unwrap();
+        assert_eq!(State::Selecting, c.state());
+        assert_eq!(Some(&[DHCPDISCOVER][..]),
+                   option(&discover2, OPT_MESSAGE_TYPE));
+    }
+
+    #[test]
+    fn renews_with_the_server_then_loses_the_lease_on_nak() {
+        static TIMERS : [SoftTimer; 1] = [SoftTimer::new()];
+        let wheel = TimerWheel::new(&TIMERS);
+        let cell = IpConfigCell::new();
+        let mut c = client(&wheel, &cell);
+        bind(&mut c, 100);
+        let generation = cell.generation();
+
+        // T1 is half the lease.
+        expire(&wheel);
+        let (to, renew) = poll(&mut c).unwrap();
+        assert_eq!(State::Renewing, c.state());
+        assert_eq!(SERVER, to);
+        assert_eq!(&OFFERED, &renew[CIADDR..CIADDR + 4]);
+        assert_eq!(None, option(&renew, OPT_REQUESTED_IP));
+        assert!(cell.get().is_some());
+
+        // A NAK to some other transaction changes nothing.
+        let mut stale = reply(&renew, DHCPNAK, &[OPT_END]);
+        stale[XID + 3] ^= 1;
+        c.receive(&stale);
+        assert_eq!(State::Renewing, c.state());
+
+        c.receive(&reply(&renew, DHCPNAK, &[OPT_END]));
+        assert_eq!(State::Init, c.state());
+        assert_eq!(None, cell.get());
+        assert!(cell.generation() != generation);
+    }
+
+    #[test]
+    fn renewal_ack_extends_the_lease() {
+        static TIMERS : [SoftTimer; 1] = [SoftTimer::new()];
+        let wheel = TimerWheel::new(&TIMERS);
+        let cell = IpConfigCell::new();
+        let mut c = client(&wheel, &cell);
+        bind(&mut c, 100);
+
+        expire(&wheel);
+        let (_, renew) = poll(&mut c).unwrap();
+        c.receive(&reply(&renew, DHCPACK,
+                         &[OPT_LEASE_TIME, 4, 0, 0, 0, 100, OPT_END]));
+        assert_eq!(State::Bound, c.state());
+        assert_eq!(Some(OFFERED), cell.get().map(|cfg| cfg.address));
+    }
+}
@@ -0,0 +1,117 @@
+//! Network interfaces, as seen by an IP stack.
+//!
+//! `NetDevice` is the one interface between link drivers -- the Ethernet
+//! MAC, USB networking -- and whatever IP stack the application brings,
+//! modelled on smoltcp's `Device`: rather than copying frames in and out,
+//! the device hands out *tokens*, each granting the use of one buffer.
+//!
+//!     if let Some((rx, tx)) = dev.receive() {
+//!         rx.consume(|frame| stack.process(frame, tx));
+//!     }
+//!
+//! A receive token comes paired with a transmit token, so that the stack
+//! can answer the frame (an ARP reply, say) while handling it.  Tokens
+//! dropped unused give their buffers back.
+//!
+//! Frames are Ethernet frames -- destination address through payload --
+//! without the CRC, which the hardware handles.
+//!
+//! Above the link, `config` holds an interface's IPv4 settings, `dhcp`
+//! obtains them from the network, and `sntp` gets the time of day.
+
+pub mod config;
+#[cfg(not(feature = "arch:armv6-m"))]
+pub mod dhcp;
+#[cfg(not(feature = "arch:armv6-m"))]
+pub mod sntp;
+
+/// Ways that token operations can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum NetError {
+    /// The frame is longer than the device's MTU.
+    FrameTooLong,
+    /// No transmit buffer is free.
+    Exhausted,
+}
+
+/// Which directions of a checksum the device handles itself.  Where it
+/// does, the stack can skip computing (on transmit) or checking (on
+/// receive) that checksum.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Offload {
+    None,
+    /// Checked on receive; frames failing the check are dropped.
+    Rx,
+    /// Filled in on transmit.
+    Tx,
+    Both,
+}
+
+impl Offload {
+    pub fn rx(self) -> bool {
+        self == Offload::Rx || self == Offload::Both
+    }
+
+    pub fn tx(self) -> bool {
+        self == Offload::Tx || self == Offload::Both
+    }
+}
+
+/// Checksums the device can offload.
+#[derive(Copy, Clone, Debug)]
+pub struct ChecksumCapabilities {
+    pub ipv4: Offload,
+    pub udp: Offload,
+    pub tcp: Offload,
+    pub icmpv4: Offload,
+}
+
+/// No offloading at all.
+pub const NO_CHECKSUM_OFFLOAD : ChecksumCapabilities = ChecksumCapabilities {
+    ipv4: Offload::None,
+    udp: Offload::None,
+    tcp: Offload::None,
+    icmpv4: Offload::None,
+};
+
+/// What a device can do.
+#[derive(Copy, Clone, Debug)]
+pub struct Capabilities {
+    /// Longest frame, without CRC: 1514 bytes for standard Ethernet.
+    pub mtu: usize,
+    /// Most frames the device can take in one burst, if limited -- e.g. by
+    /// its number of transmit buffers.
+    pub max_burst_size: Option<usize>,
+    pub checksum: ChecksumCapabilities,
+}
+
+/// Grants access to one received frame.
+pub trait RxToken {
+    /// Calls `f` with the frame, and gives back its buffer.
+    fn consume<R, F: FnOnce(&[u8]) -> R>(self, f: F) -> R;
+}
+
+/// Grants the right to send one frame.
+pub trait TxToken {
+    /// Calls `f` to fill in a `len`-byte frame, then sends it.
+    fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F)
+        -> Result<R, NetError>;
+}
+
+/// A network interface.  The lifetime is that of the borrow of the device
+/// that each token holds.
+pub trait NetDevice<'a> {
+    type Rx: RxToken + 'a;
+    type Tx: TxToken + 'a;
+
+    /// Takes the next received frame, if there is one.
+    fn receive(&'a mut self) -> Option<(Self::Rx, Self::Tx)>;
+
+    /// Gets a token for sending a frame, if a buffer is free.
+    fn transmit(&'a mut self) -> Option<Self::Tx>;
+
+    fn capabilities(&self) -> Capabilities;
+
+    /// The interface's own MAC address.
+    fn mac_address(&self) -> [u8; 6];
+}
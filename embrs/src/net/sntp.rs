@@ -0,0 +1,669 @@
+//! SNTP client.
+//!
+//! `SntpClient` asks an NTP server for the time every so often, and relates
+//! each answer to the monotonic millisecond clock (`time::now_ms`), giving
+//! wall-clock timestamps for anything the application logs.  Like the DHCP
+//! client, it leaves moving the messages to the application's IP stack,
+//! here through a UDP socket talking to the server's port 123:
+//!
+//!     let mut sntp = SntpClient::new(server, 1024, seed, &WHEEL, timer,
+//!                                    sntp_timer);
+//!     let mut servo = PtpServo::new(&increment);
+//!     loop {
+//!         while let Some((msg, _)) = socket.recv() {
+//!             if let Some(sample) = sntp.receive(msg) {
+//!                 servo.discipline(&sample);
+//!             }
+//!         }
+//!         let mut buf = [0; MESSAGE_LEN];
+//!         if let Some(to) = sntp.poll(&mut buf) {
+//!             socket.send_to(&buf, to, SERVER_PORT);
+//!         }
+//!         // ...log with sntp.now_unix_ms()...
+//!     }
+//!
+//! Other clocks can be kept in step by passing them each `Sample` through
+//! the `Disciplined` trait: `PtpServo` does this for the Ethernet MAC's PTP
+//! clock, steering its addend, and `RtcServo` for the RTC, so that the time
+//! survives resets and power loss, steering its calibration.
+//!
+//! Times are kept to the millisecond, the resolution of the monotonic
+//! clock, which is plenty for timestamps but limits how finely the servos
+//! can steer.  Leap seconds are ignored.
+
+use backoff::{Backoff, Jitter};
+use prng::XorShift32;
+use stm32f4::eth::ptp::{nanos_to_subseconds, scaled_addend,
+                        ClockIncrement, TimeOffset, Timestamp, PTP};
+use stm32f4::rtc::RTC;
+use time::{self, TimerCallback, TimerId, TimerWheel};
+use super::config::Ipv4Address;
+
+/// UDP port servers listen on.
+pub const SERVER_PORT : u16 = 123;
+
+/// Length of an SNTP message, without authentication.
+pub const MESSAGE_LEN : usize = 48;
+
+// Message layout.
+const STRATUM : usize = 1;
+const ORIGINATE : usize = 24;
+const RECEIVE : usize = 32;
+const TRANSMIT : usize = 40;
+
+const VERSION : u8 = 4;
+const MODE_CLIENT : u8 = 3;
+const MODE_SERVER : u8 = 4;
+/// Leap indicator meaning the server's clock isn't synchronized.
+const LEAP_ALARM : u8 = 3;
+
+/// Seconds from the NTP epoch (1900) to the Unix epoch (1970).
+const NTP_TO_UNIX_S : u64 = 2_208_988_800;
+
+/// How long to wait for a reply before giving up on a query.
+const REPLY_TIMEOUT_MS : u32 = 5_000;
+/// First retry delay after a failed query; it doubles up to the polling
+/// interval.
+const RETRY_FIRST_MS : u32 = 8_000;
+/// Shortest polling interval that's polite to public servers.
+const MIN_INTERVAL_S : u32 = 16;
+/// Longest polling interval, keeping well inside the timer's range.
+const MAX_INTERVAL_S : u32 = 24 * 60 * 60;
+
+/// One answer from the server: the wall-clock time at a moment on the
+/// monotonic clock.
+#[derive(Copy, Clone, Debug)]
+pub struct Sample {
+    /// Monotonic time when the reply arrived, from `time::now_ms`.
+    pub at_ms: u32,
+    /// Milliseconds since the Unix epoch at `at_ms`.
+    pub unix_ms: u64,
+    /// Round-trip network delay, which bounds the sample's error.
+    pub delay_ms: u32,
+}
+
+impl Sample {
+    /// Estimates the wall-clock time at monotonic time `now_ms`, which must
+    /// be no more than 49 days after `at_ms`.
+    pub fn unix_ms_at(&self, now_ms: u32) -> u64 {
+        self.unix_ms + now_ms.wrapping_sub(self.at_ms) as u64
+    }
+}
+
+/// A clock the client's samples can correct.
+pub trait Disciplined {
+    /// Brings the clock into line with `sample`.
+    fn discipline(&mut self, sample: &Sample);
+}
+
+/// Ways that a query can go unanswered.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Stats {
+    /// Queries that got no usable reply in time.
+    pub timeouts: usize,
+    /// Replies from servers that said they weren't synchronized, or (with
+    /// a "kiss-o'-death") asked us to go away, or whose timestamps made no
+    /// sense.
+    pub rejected: usize,
+}
+
+/// Queries one NTP server periodically.
+pub struct SntpClient<'a> {
+    server: Ipv4Address,
+    interval_ms: u32,
+    rng: XorShift32,
+    backoff: Backoff,
+    wheel: &'a TimerWheel,
+    timer: TimerId,
+    callback: TimerCallback,
+
+    /// A query is due to be sent.
+    send_pending: bool,
+    /// A query is out, sent at `sent_ms` with `nonce` as its transmit
+    /// timestamp.
+    awaiting: bool,
+    sent_ms: u32,
+    nonce: [u8; 8],
+    /// The timer is running, towards either the next query or the reply
+    /// timeout.
+    waiting: bool,
+
+    last: Option<Sample>,
+    stats: Stats,
+}
+
+impl<'a> SntpClient<'a> {
+    /// Creates a client for `server`, to query it every `interval_s`
+    /// seconds (between 16 and a day; 1024 is customary), starting at the
+    /// first `poll`.  `timer` must be a slot claimed from `wheel`, and
+    /// `callback` is called when it expires.  `seed` should differ between
+    /// devices.
+    pub fn new(server: Ipv4Address,
+               interval_s: u32,
+               seed: u32,
+               wheel: &'a TimerWheel,
+               timer: TimerId,
+               callback: TimerCallback)
+        -> SntpClient<'a> {
+        let interval_ms = if interval_s < MIN_INTERVAL_S {
+            MIN_INTERVAL_S * 1000
+        } else if interval_s > MAX_INTERVAL_S {
+            MAX_INTERVAL_S * 1000
+        } else {
+            interval_s * 1000
+        };
+        let rng = XorShift32::new(seed);
+        SntpClient {
+            server: server,
+            interval_ms: interval_ms,
+            rng: rng,
+            backoff: Backoff::new(XorShift32::new(!seed), RETRY_FIRST_MS,
+                                  interval_ms, Jitter::Equal),
+            wheel: wheel,
+            timer: timer,
+            callback: callback,
+            send_pending: true,
+            awaiting: false,
+            sent_ms: 0,
+            nonce: [0; 8],
+            waiting: false,
+            last: None,
+            stats: Stats::default(),
+        }
+    }
+
+    /// Switches to `server`, querying it at the next `poll`.
+    pub fn set_server(&mut self, server: Ipv4Address) {
+        self.server = server;
+        let _ = self.wheel.cancel(self.timer);
+        self.waiting = false;
+        self.awaiting = false;
+        self.backoff.reset();
+        self.send_pending = true;
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// The most recent sample, if any query has succeeded.
+    pub fn last_sample(&self) -> Option<Sample> {
+        self.last
+    }
+
+    /// The current wall-clock time, in milliseconds since the Unix epoch,
+    /// if it's known.
+    pub fn now_unix_ms(&self) -> Option<u64> {
+        self.last.map(|s| s.unix_ms_at(time::now_ms()))
+    }
+
+    fn wait_ms(&mut self, ms: u32) {
+        // The timer was claimed for us, so this can't fail.
+        let _ = self.wheel.start_one_shot(self.timer, ms, self.callback);
+        self.waiting = true;
+    }
+
+    /// Advances the client, returning the address to which the query it
+    /// has left in `out` should be sent, if there is one.  `out` must be at
+    /// least `MESSAGE_LEN` long, and the query fills exactly that much of
+    /// it.
+    pub fn poll(&mut self, out: &mut [u8]) -> Option<Ipv4Address> {
+        if self.waiting && !self.wheel.is_active(self.timer) {
+            self.waiting = false;
+            if self.awaiting {
+                // No answer in time: try again, backing off.
+                self.awaiting = false;
+                self.stats.timeouts += 1;
+                let delay = self.backoff.next_delay();
+                self.wait_ms(delay)
+            } else {
+                self.send_pending = true
+            }
+        }
+        if !self.send_pending {
+            return None
+        }
+        self.send_pending = false;
+
+        let out = &mut out[..MESSAGE_LEN];
+        for b in out.iter_mut() {
+            *b = 0
+        }
+        out[0] = VERSION << 3 | MODE_CLIENT;
+        // Rather than our idea of the time, the transmit timestamp carries
+        // a random value, which the server echoes back, so that we can
+        // tell its reply from a forgery or a stale answer.
+        put_be32(&mut self.nonce[..4], self.rng.next_u32());
+        put_be32(&mut self.nonce[4..], self.rng.next_u32());
+        out[TRANSMIT..TRANSMIT + 8].copy_from_slice(&self.nonce);
+
+        self.sent_ms = time::now_ms();
+        self.awaiting = true;
+        self.wait_ms(REPLY_TIMEOUT_MS);
+        Some(self.server)
+    }
+
+    /// Takes a message received from the server's port.  Returns the new
+    /// sample if it's a good answer to our outstanding query.
+    pub fn receive(&mut self, msg: &[u8]) -> Option<Sample> {
+        let now = time::now_ms();
+        if !self.awaiting
+            || msg.len() < MESSAGE_LEN
+            || msg[0] & 7 != MODE_SERVER
+            || msg[ORIGINATE..ORIGINATE + 8] != self.nonce {
+            return None
+        }
+        self.awaiting = false;
+        let _ = self.wheel.cancel(self.timer);
+
+        let stratum = msg[STRATUM];
+        let synchronized = msg[0] >> 6 != LEAP_ALARM
+            && stratum != 0 && stratum <= 15;
+        let times = (ntp_to_unix_ms(&msg[RECEIVE..]),
+                     ntp_to_unix_ms(&msg[TRANSMIT..]));
+        let (t2, t3) = match times {
+            (Some(t2), Some(t3)) if synchronized && t3 >= t2 => (t2, t3),
+            _ => {
+                self.stats.rejected += 1;
+                let delay = self.backoff.next_delay();
+                self.wait_ms(delay);
+                return None
+            },
+        };
+
+        let sample = make_sample(self.sent_ms, now, t2, t3);
+        self.last = Some(sample);
+
+        self.backoff.reset();
+        let interval = self.interval_ms;
+        self.wait_ms(interval);
+        Some(sample)
+    }
+}
+
+/// Works out the sample given by a query sent at monotonic time `sent_ms`
+/// and answered at `now`, which the server received at Unix time `t2` and
+/// answered at `t3`.
+fn make_sample(sent_ms: u32, now: u32, t2: u64, t3: u64) -> Sample {
+    // With t1 and t4 read from the monotonic clock, the usual offset
+    // calculation gives the difference between that and the server's
+    // clock.
+    let rtt = now.wrapping_sub(sent_ms) as i64;
+    let t1 = sent_ms as i64;
+    let t4 = t1 + rtt;
+    let offset = ((t2 as i64 - t1) + (t3 as i64 - t4)) / 2;
+    let server_hold = (t3 - t2) as i64;
+    Sample {
+        at_ms: now,
+        unix_ms: (t4 + offset) as u64,
+        delay_ms: if rtt > server_hold {
+            (rtt - server_hold) as u32
+        } else {
+            0
+        },
+    }
+}
+
+/// Converts the NTP timestamp at the start of `b` to Unix milliseconds.
+/// Seconds values with the top bit clear are taken to be from after the NTP
+/// era rolls over in 2036.  Times before the Unix epoch give `None`.
+fn ntp_to_unix_ms(b: &[u8]) -> Option<u64> {
+    let seconds = be32(b) as u64;
+    let fraction = be32(&b[4..]) as u64;
+    let seconds = if seconds & 0x8000_0000 == 0 {
+        seconds + (1 << 32)
+    } else {
+        seconds
+    };
+    seconds.checked_sub(NTP_TO_UNIX_S)
+        .map(|s| s * 1000 + ((fraction * 1000) >> 32))
+}
+
+fn be32(b: &[u8]) -> u32 {
+    ((b[0] as u32) << 24)
+        | ((b[1] as u32) << 16)
+        | ((b[2] as u32) << 8)
+        | (b[3] as u32)
+}
+
+fn put_be32(b: &mut [u8], v: u32) {
+    b[0] = (v >> 24) as u8;
+    b[1] = (v >> 16) as u8;
+    b[2] = (v >> 8) as u8;
+    b[3] = v as u8;
+}
+
+/// Error beyond which `PtpServo` steps the clock rather than steering it.
+const STEP_THRESHOLD_MS : i64 = 128;
+/// Shortest time between samples over which `PtpServo` will estimate the
+/// clock's rate error; closer samples only correct its phase.
+const MIN_RATE_INTERVAL_MS : u32 = 10_000;
+/// Largest rate correction the servos apply, in parts per billion.
+const MAX_TRIM_PPB : i32 = 500_000;
+
+/// Limits a rate correction to `MAX_TRIM_PPB` either way.
+fn clamp_trim(trim: i64) -> i32 {
+    let max = MAX_TRIM_PPB as i64;
+    if trim > max {
+        MAX_TRIM_PPB
+    } else if trim < -max {
+        -MAX_TRIM_PPB
+    } else {
+        trim as i32
+    }
+}
+
+/// Keeps the Ethernet MAC's PTP clock on Unix time (seconds since 1970),
+/// for applications that use it to timestamp frames but have no PTP master
+/// to follow.
+///
+/// The first sample, or any that finds the clock far out, sets the clock
+/// outright.  After that, each sample steps out the error, and trims the
+/// addend by the rate at which the error built up since the previous one.
+pub struct PtpServo {
+    base_addend: u32,
+    trim_ppb: i32,
+    /// Monotonic time of the last correction.
+    last_ms: Option<u32>,
+}
+
+impl PtpServo {
+    /// Creates a servo for the PTP clock, started with `inc`.
+    pub fn new(inc: &ClockIncrement) -> PtpServo {
+        PtpServo {
+            base_addend: inc.addend,
+            trim_ppb: 0,
+            last_ms: None,
+        }
+    }
+
+    /// The rate correction currently applied, in parts per billion.
+    pub fn trim_ppb(&self) -> i32 {
+        self.trim_ppb
+    }
+}
+
+impl Disciplined for PtpServo {
+    fn discipline(&mut self, sample: &Sample) {
+        let now_ms = time::now_ms();
+        let clock = PTP.now();
+        let unix_ms = sample.unix_ms_at(now_ms);
+        let clock_ms = clock.seconds as u64 * 1000
+            + (clock.subsec_nanos() / 1_000_000) as u64;
+        let error_ms = unix_ms as i64 - clock_ms as i64;
+
+        let last_ms = match self.last_ms {
+            Some(t) if error_ms.abs() <= STEP_THRESHOLD_MS => t,
+            _ => {
+                PTP.set_time(Timestamp::from_nanos(
+                        (unix_ms / 1000) as u32,
+                        (unix_ms % 1000) as u32 * 1_000_000));
+                self.last_ms = Some(now_ms);
+                return
+            },
+        };
+
+        let elapsed_ms = now_ms.wrapping_sub(last_ms);
+        if elapsed_ms >= MIN_RATE_INTERVAL_MS {
+            // Correct half the apparent rate error, as each estimate is
+            // only good to a few milliseconds.
+            let ppb = error_ms * 1_000_000_000 / elapsed_ms as i64 / 2;
+            self.trim_ppb = clamp_trim(self.trim_ppb as i64 + ppb);
+            PTP.set_addend(scaled_addend(self.base_addend, self.trim_ppb));
+        }
+
+        let magnitude = error_ms.abs() as u32;
+        PTP.adjust(TimeOffset {
+            negative: error_ms < 0,
+            seconds: 0,
+            subseconds: nanos_to_subseconds(magnitude * 1_000_000),
+        });
+        self.last_ms = Some(now_ms);
+    }
+}
+
+/// Keeps the RTC on Unix time, so that the time is known from the moment
+/// of a reset rather than from the first SNTP reply.  The RTC must have
+/// been started (`Rtc::start`).
+///
+/// Like `PtpServo`, the first sample, or any that finds the RTC far out,
+/// sets it outright.  After that, each sample shifts out the error, and
+/// trims the RTC's calibration by the rate at which the error built up
+/// since the previous one.  The RTC only resolves 1/256 second, so smaller
+/// errors are left to the rate correction.
+pub struct RtcServo {
+    trim_ppb: i32,
+    /// Monotonic time of the last correction.
+    last_ms: Option<u32>,
+}
+
+impl RtcServo {
+    pub fn new() -> RtcServo {
+        RtcServo {
+            trim_ppb: 0,
+            last_ms: None,
+        }
+    }
+
+    /// The rate correction currently applied, in parts per billion.
+    pub fn trim_ppb(&self) -> i32 {
+        self.trim_ppb
+    }
+}
+
+impl Disciplined for RtcServo {
+    fn discipline(&mut self, sample: &Sample) {
+        let now_ms = time::now_ms();
+        let unix_ms = sample.unix_ms_at(now_ms);
+        let error_ms = RTC.now_unix_ms()
+            .map(|rtc_ms| unix_ms as i64 - rtc_ms as i64);
+
+        let (last_ms, error_ms) = match (self.last_ms, error_ms) {
+            (Some(t), Some(e)) if e.abs() <= STEP_THRESHOLD_MS => (t, e),
+            _ => {
+                // A time outside the RTC's calendar can only come from a
+                // confused server; leave the RTC alone.
+                if RTC.set_unix_ms(unix_ms).is_ok() {
+                    self.last_ms = Some(now_ms)
+                }
+                return
+            },
+        };
+
+        let elapsed_ms = now_ms.wrapping_sub(last_ms);
+        if elapsed_ms >= MIN_RATE_INTERVAL_MS {
+            // As for `PtpServo`, correct half the apparent rate error.
+            let ppb = error_ms * 1_000_000_000 / elapsed_ms as i64 / 2;
+            let trim = clamp_trim(self.trim_ppb as i64 + ppb);
+            self.trim_ppb = RTC.set_rate_ppb(trim);
+        }
+
+        // Within the step threshold, so well within a second.
+        let _ = RTC.shift_ms(error_ms as i32);
+        self.last_ms = Some(now_ms);
+    }
+}
+
+#[cfg(all(test, feature = "host-test"))]
+mod tests {
+    use time::{now_ms, SoftTimer, TimerId, TimerWheel};
+    use super::*;
+
+    const SERVER : Ipv4Address = [192, 168, 1, 1];
+    /// 2023-08-01T00:00:00Z, on both scales.
+    const NTP_S : u32 = 3_899_836_800;
+    const UNIX_MS : u64 = 1_690_848_000_000;
+
+    fn nothing(_: TimerId) {}
+
+    fn expire(wheel: &TimerWheel) {
+        wheel.tick(now_ms().wrapping_add(MAX_INTERVAL_S * 1000 + 1));
+        wheel.dispatch()
+    }
+
+    fn query(c: &mut SntpClient) -> Vec<u8> {
+        let mut buf = vec![0; MESSAGE_LEN];
+        assert_eq!(Some(SERVER), c.poll(&mut buf));
+        buf
+    }
+
+    /// Builds a server's answer to `query`, received at `t2` and sent at
+    /// `t3` (each NTP seconds and fraction).
+    fn answer(query: &[u8], first: u8, stratum: u8,
+              t2: (u32, u32), t3: (u32, u32)) -> Vec<u8> {
+        let mut m = vec![0; MESSAGE_LEN];
+        m[0] = first;
+        m[STRATUM] = stratum;
+        m[ORIGINATE..ORIGINATE + 8]
+            .copy_from_slice(&query[TRANSMIT..TRANSMIT + 8]);
+        put_be32(&mut m[RECEIVE..], t2.0);
+        put_be32(&mut m[RECEIVE + 4..], t2.1);
+        put_be32(&mut m[TRANSMIT..], t3.0);
+        put_be32(&mut m[TRANSMIT + 4..], t3.1);
+        m
+    }
+
+    /// No leap warning, version 4, server mode.
+    const SERVER_HEADER : u8 = VERSION << 3 | MODE_SERVER;
+
+    #[test]
+    fn converts_ntp_timestamps() {
+        let mut b = [0; 8];
+        put_be32(&mut b, NTP_TO_UNIX_S as u32);
+        assert_eq!(Some(0), ntp_to_unix_ms(&b));
+        put_be32(&mut b, NTP_S);
+        put_be32(&mut b[4..], 0x8000_0000);
+        assert_eq!(Some(UNIX_MS + 500), ntp_to_unix_ms(&b));
+        // Before 1970.
+        put_be32(&mut b, 0x8000_0000);
+        assert_eq!(None, ntp_to_unix_ms(&b));
+        // Just into the second era, in 2036.
+        put_be32(&mut b, 0);
+        put_be32(&mut b[4..], 0);
+        assert_eq!(Some(((1 << 32) - NTP_TO_UNIX_S) * 1000),
+                   ntp_to_unix_ms(&b));
+    }
+
+    #[test]
+    fn offset_allows_for_delay_and_server_hold() {
+        // Out in 45ms, held for 10ms, back in 45ms: the server's clock read
+        // UNIX_MS + 50 as the reply left, 45ms before it arrived.
+        let s = make_sample(1000, 1100, UNIX_MS + 40, UNIX_MS + 50);
+        assert_eq!(1100, s.at_ms);
+        assert_eq!(UNIX_MS + 95, s.unix_ms);
+        assert_eq!(90, s.delay_ms);
+        assert_eq!(UNIX_MS + 1095, s.unix_ms_at(2100));
+
+        // The same, with the monotonic clock wrapping while the query was
+        // out.
+        let s = make_sample(!0 - 99, 0, UNIX_MS + 40, UNIX_MS + 50);
+        assert_eq!(0, s.at_ms);
+        assert_eq!(UNIX_MS + 95, s.unix_ms);
+        assert_eq!(90, s.delay_ms);
+
+        // A server claiming to hold the query longer than it was out
+        // doesn't make the delay negative.
+        let s = make_sample(1000, 1010, UNIX_MS, UNIX_MS + 30);
+        assert_eq!(0, s.delay_ms);
+        assert_eq!(UNIX_MS + 20, s.unix_ms);
+    }
+
+    #[test]
+    fn takes_a_sample_from_a_good_answer() {
+        static TIMERS : [SoftTimer; 1] = [SoftTimer::new()];
+        let wheel = TimerWheel::new(&TIMERS);
+        let mut c = SntpClient::new(SERVER, 1024, 1, &wheel,
+                                    wheel.claim().unwrap(), nothing);
+        assert_eq!(None, c.now_unix_ms());
+
+        let q = query(&mut c);
+        assert_eq!(VERSION << 3 | MODE_CLIENT, q[0]);
+        assert!(c.poll(&mut [0; MESSAGE_LEN]).is_none());
+
+        let a = answer(&q, SERVER_HEADER, 2,
+                       (NTP_S, 0), (NTP_S, 0x4000_0000));
+        // The monotonic clock doesn't move in tests, so there's no delay,
+        // and the time is halfway through the server's hold.
+        let s = c.receive(&a).unwrap();
+        assert_eq!(UNIX_MS + 125, s.unix_ms);
+        assert_eq!(0, s.delay_ms);
+        assert_eq!(Some(UNIX_MS + 125), c.now_unix_ms());
+
+        // The same answer again isn't a second sample.
+        assert!(c.receive(&a).is_none());
+
+        // The next query waits for the polling interval.
+        assert!(c.poll(&mut [0; MESSAGE_LEN]).is_none());
+        expire(&wheel);
+        let q2 = query(&mut c);
+        assert!(q[TRANSMIT..] != q2[TRANSMIT..]);
+    }
+
+    #[test]
+    fn ignores_answers_to_other_queries() {
+        static TIMERS : [SoftTimer; 1] = [SoftTimer::new()];
+        let wheel = TimerWheel::new(&TIMERS);
+        let mut c = SntpClient::new(SERVER, 1024, 1, &wheel,
+                                    wheel.claim().unwrap(), nothing);
+        let q = query(&mut c);
+        let good = answer(&q, SERVER_HEADER, 2, (NTP_S, 0), (NTP_S, 0));
+
+        let mut forged = good.clone();
+        forged[ORIGINATE + 7] ^= 1;
+        let mut from_client = good.clone();
+        from_client[0] = VERSION << 3 | MODE_CLIENT;
+        for m in &[forged, from_client, good[..MESSAGE_LEN - 1].to_vec()] {
+            assert!(c.receive(m).is_none());
+        }
+        assert_eq!(0, c.stats().rejected);
+        assert!(c.receive(&good).is_some());
+    }
+
+    #[test]
+    fn rejects_unsynchronized_or_nonsensical_answers() {
+        static TIMERS : [SoftTimer; 1] = [SoftTimer::new()];
+        let wheel = TimerWheel::new(&TIMERS);
+        let mut c = SntpClient::new(SERVER, 1024, 1, &wheel,
+                                    wheel.claim().unwrap(), nothing);
+        let t = (NTP_S, 0);
+        let cases : &[(u8, u8, (u32, u32), (u32, u32))] = &[
+            // Leap indicator saying the clock is unsynchronized.
+            (LEAP_ALARM << 6 | SERVER_HEADER, 2, t, t),
+            // Kiss-o'-death.
+            (SERVER_HEADER, 0, t, t),
+            (SERVER_HEADER, 16, t, t),
+            // Answered before it was received.
+            (SERVER_HEADER, 2, (NTP_S, 1 << 31), t),
+            // Before 1970.
+            (SERVER_HEADER, 2, (0x8000_0000, 0), t),
+        ];
+        for (i, &(first, stratum, t2, t3)) in cases.iter().enumerate() {
+            let q = query(&mut c);
+            assert!(c.receive(&answer(&q, first, stratum, t2, t3))
+                        .is_none());
+            assert_eq!(i + 1, c.stats().rejected);
+            // Back off before asking again.
+            assert!(c.poll(&mut [0; MESSAGE_LEN]).is_none());
+            expire(&wheel);
+        }
+        assert!(c.last_sample().is_none());
+    }
+
+    #[test]
+    fn retries_after_a_timeout() {
+        static TIMERS : [SoftTimer; 1] = [SoftTimer::new()];
+        let wheel = TimerWheel::new(&TIMERS);
+        let mut c = SntpClient::new(SERVER, 1024, 1, &wheel,
+                                    wheel.claim().unwrap(), nothing);
+        let q = query(&mut c);
+        expire(&wheel);
+        assert!(c.poll(&mut [0; MESSAGE_LEN]).is_none());
+        assert_eq!(1, c.stats().timeouts);
+
+        // A late answer is no good.
+        assert!(c.receive(&answer(&q, SERVER_HEADER, 2, (NTP_S, 0),
+                                  (NTP_S, 0))).is_none());
+        expire(&wheel);
+        let _ = query(&mut c);
+    }
+}
@@ -0,0 +1,65 @@
+//! Small, fast, deterministic pseudo-random number generation.
+//!
+//! This is *not* suitable for cryptography.  It's intended for things like
+//! retry jitter, where what matters is that identical devices don't behave
+//! identically, and that runs are reproducible given the seed.
+
+/// Marsaglia's xorshift32 generator.  Period 2^32 - 1; the state is never
+/// zero.
+#[derive(Copy, Clone)]
+pub struct XorShift32 {
+    state: u32,
+}
+
+/// Substituted for a zero seed, which would otherwise get the generator stuck.
+const ZERO_SEED_REPLACEMENT : u32 = 0x9e3779b9;
+
+impl XorShift32 {
+    /// Creates a generator from a seed.  Any seed is acceptable.
+    pub fn new(seed: u32) -> XorShift32 {
+        XorShift32 {
+            state: if seed == 0 { ZERO_SEED_REPLACEMENT } else { seed },
+        }
+    }
+
+    /// Creates a generator by folding together several words of seed material
+    /// -- for example, the device's unique ID from
+    /// `stm32f4::flash::FLASH.read_unique_id()`.
+    pub fn from_words(words: &[u32]) -> XorShift32 {
+        let mut seed = 0u32;
+        for &w in words {
+            // Rotate-and-multiply so that word order matters and all bits
+            // contribute.
+            seed = (seed.rotate_left(5) ^ w).wrapping_mul(0x9e3779b9);
+        }
+        XorShift32::new(seed)
+    }
+
+    /// Produces the next 32-bit value.
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// Produces a value uniformly distributed (to within a bias of at most
+    /// `bound` / 2^32) in `0..bound`.  Returns 0 if `bound` is 0.
+    pub fn next_below(&mut self, bound: u32) -> u32 {
+        // Multiply-shift avoids both division and the worst of modulo bias.
+        (((self.next_u32() as u64) * (bound as u64)) >> 32) as u32
+    }
+
+    /// Produces a value in `lo..=hi`.  If `hi < lo` the two are swapped.
+    pub fn next_in_range(&mut self, lo: u32, hi: u32) -> u32 {
+        let (lo, hi) = if hi < lo { (hi, lo) } else { (lo, hi) };
+        let span = hi - lo;
+        if span == !0 {
+            self.next_u32()
+        } else {
+            lo + self.next_below(span + 1)
+        }
+    }
+}
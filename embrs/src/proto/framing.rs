@@ -0,0 +1,475 @@
+//! Packet framing for byte streams: SLIP and COBS.
+//!
+//! Both turn a packet into bytes that can't contain the frame delimiter, so
+//! that a receiver can find packet boundaries in a stream -- and recover
+//! after noise or a dropped byte -- without any length fields to trust.
+//!
+//! - SLIP (RFC 1055) ends each packet with 0xC0, and escapes any 0xC0 or
+//!   0xDB in the data as two bytes.  Overhead depends on the data: at worst
+//!   it doubles.
+//! - COBS ends each packet with 0x00, and replaces the zeros in the data
+//!   with the distance to the next one.  Overhead is at most one byte in
+//!   254, plus the delimiter.
+//!
+//! The encoders are iterators over the encoded bytes, so they can feed a
+//! serial port directly, with no buffer:
+//!
+//!     for b in CobsEncoder::new(&packet) {
+//!         try!(port.write(b));
+//!     }
+//!
+//! The decoders take one byte at a time into a buffer the caller provides,
+//! and hand back each packet as it completes:
+//!
+//!     let mut buf = [0; 256];
+//!     let mut dec = CobsDecoder::new(&mut buf);
+//!     // ...
+//!     match dec.push(try!(port.read())) {
+//!         Ok(Some(packet)) => ...,
+//!         Ok(None) => (),
+//!         Err(e) => ...,  // the packet is lost; carry on
+//!     }
+//!
+//! Neither framing checks the contents.  For that, `append_checksum` and
+//! `strip_checksum` add and check a trailer, computed by any `Checksum` --
+//! including the STM32F4's CRC unit, through `HwCrc32`.
+
+use crc::{crc16_xmodem, crc32, crc32_mpeg2};
+#[cfg(not(feature = "arch:armv6-m"))]
+use stm32f4::crc::CrcUnit;
+
+/// Ways that decoding can fail.  In each case the packet is discarded, and
+/// the decoder carries on with the next.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum FramingError {
+    /// The packet didn't fit in the buffer.
+    Overflow,
+    /// SLIP: an escape byte was followed by something other than an escape
+    /// code.
+    BadEscape,
+    /// COBS: the packet ended in the middle of a block.
+    Truncated,
+}
+
+/// Runs `encoder` into `out`, returning the number of bytes written, or
+/// `Overflow` if they don't fit.
+pub fn encode_into<I>(encoder: I, out: &mut [u8])
+    -> Result<usize, FramingError>
+    where I: Iterator<Item = u8> {
+    let mut n = 0;
+    for b in encoder {
+        if n == out.len() {
+            return Err(FramingError::Overflow)
+        }
+        out[n] = b;
+        n += 1
+    }
+    Ok(n)
+}
+
+/*****************************************************************************
+ * SLIP
+ */
+
+pub const SLIP_END : u8 = 0xC0;
+pub const SLIP_ESC : u8 = 0xDB;
+/// Follows `SLIP_ESC` in place of an `END` byte in the data.
+pub const SLIP_ESC_END : u8 = 0xDC;
+/// Follows `SLIP_ESC` in place of an `ESC` byte in the data.
+pub const SLIP_ESC_ESC : u8 = 0xDD;
+
+/// Yields the SLIP encoding of a packet: an `END` (which flushes any noise
+/// at the receiver), the escaped data, and a final `END`.
+pub struct SlipEncoder<'a> {
+    packet: &'a [u8],
+    pos: usize,
+    started: bool,
+    /// Second byte of an escape sequence, waiting to be yielded.
+    pending: Option<u8>,
+    done: bool,
+}
+
+impl<'a> SlipEncoder<'a> {
+    pub fn new(packet: &'a [u8]) -> SlipEncoder<'a> {
+        SlipEncoder {
+            packet: packet,
+            pos: 0,
+            started: false,
+            pending: None,
+            done: false,
+        }
+    }
+}
+
+impl<'a> Iterator for SlipEncoder<'a> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if !self.started {
+            self.started = true;
+            return Some(SLIP_END)
+        }
+        if let Some(b) = self.pending.take() {
+            return Some(b)
+        }
+        if self.pos == self.packet.len() {
+            if self.done {
+                return None
+            }
+            self.done = true;
+            return Some(SLIP_END)
+        }
+        let b = self.packet[self.pos];
+        self.pos += 1;
+        match b {
+            SLIP_END => {
+                self.pending = Some(SLIP_ESC_END);
+                Some(SLIP_ESC)
+            },
+            SLIP_ESC => {
+                self.pending = Some(SLIP_ESC_ESC);
+                Some(SLIP_ESC)
+            },
+            _ => Some(b),
+        }
+    }
+}
+
+/// Reassembles SLIP packets.  Empty packets -- from the back-to-back `END`
+/// bytes between packets -- are skipped.
+pub struct SlipDecoder<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+    escaped: bool,
+    /// Set after an error, until the end of the packet.
+    discarding: bool,
+}
+
+impl<'b> SlipDecoder<'b> {
+    pub fn new(buf: &'b mut [u8]) -> SlipDecoder<'b> {
+        SlipDecoder {
+            buf: buf,
+            len: 0,
+            escaped: false,
+            discarding: false,
+        }
+    }
+
+    /// Drops any partial packet.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.escaped = false;
+        self.discarding = false
+    }
+
+    /// Adds a received byte, returning the packet it completes, if any.  An
+    /// error is returned once, at the byte where the packet went wrong.
+    pub fn push(&mut self, byte: u8) -> Result<Option<&[u8]>, FramingError> {
+        if byte == SLIP_END {
+            let len = self.len;
+            let good = !self.discarding && !self.escaped;
+            self.reset();
+            return Ok(if good && len > 0 {
+                Some(&self.buf[..len])
+            } else {
+                None
+            })
+        }
+        if self.discarding {
+            return Ok(None)
+        }
+
+        let b = if self.escaped {
+            self.escaped = false;
+            match byte {
+                SLIP_ESC_END => SLIP_END,
+                SLIP_ESC_ESC => SLIP_ESC,
+                _ => return self.fail(FramingError::BadEscape),
+            }
+        } else if byte == SLIP_ESC {
+            self.escaped = true;
+            return Ok(None)
+        } else {
+            byte
+        };
+
+        if self.len == self.buf.len() {
+            return self.fail(FramingError::Overflow)
+        }
+        self.buf[self.len] = b;
+        self.len += 1;
+        Ok(None)
+    }
+
+    fn fail(&mut self, e: FramingError)
+        -> Result<Option<&[u8]>, FramingError> {
+        self.discarding = true;
+        Err(e)
+    }
+}
+
+/*****************************************************************************
+ * COBS
+ */
+
+/// Largest number of data bytes in a COBS block.
+const COBS_BLOCK : usize = 254;
+
+/// Largest encoding of an `n`-byte packet, including the delimiter.
+pub fn cobs_max_encoded_len(n: usize) -> usize {
+    n + n / COBS_BLOCK + 2
+}
+
+/// Yields the COBS encoding of a packet, followed by a zero delimiter.
+pub struct CobsEncoder<'a> {
+    packet: &'a [u8],
+    pos: usize,
+    /// Data bytes left in the current block.
+    run: usize,
+    /// Whether the current block ends at a zero in the data, to be skipped.
+    skip_zero: bool,
+    /// Whether the current block is the last.
+    last: bool,
+    done: bool,
+}
+
+impl<'a> CobsEncoder<'a> {
+    pub fn new(packet: &'a [u8]) -> CobsEncoder<'a> {
+        CobsEncoder {
+            packet: packet,
+            pos: 0,
+            run: 0,
+            skip_zero: false,
+            last: false,
+            done: false,
+        }
+    }
+}
+
+impl<'a> Iterator for CobsEncoder<'a> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.run > 0 {
+            let b = self.packet[self.pos];
+            self.pos += 1;
+            self.run -= 1;
+            if self.run == 0 && self.skip_zero {
+                self.pos += 1
+            }
+            return Some(b)
+        }
+        if self.last {
+            if self.done {
+                return None
+            }
+            self.done = true;
+            return Some(0)
+        }
+
+        // Start a block: its code is one more than the number of data bytes
+        // before the next zero, or 0xFF for a full block with no zero.
+        let rest = &self.packet[self.pos..];
+        let n = rest.iter().take(COBS_BLOCK).take_while(|&&b| b != 0).count();
+        self.run = n;
+        self.skip_zero = n < rest.len() && n < COBS_BLOCK;
+        self.last = n == rest.len() && n < COBS_BLOCK;
+        if n == 0 && self.skip_zero {
+            self.pos += 1
+        }
+        Some(n as u8 + 1)
+    }
+}
+
+/// Reassembles COBS packets.  Empty packets -- from back-to-back delimiters
+/// -- are skipped.
+pub struct CobsDecoder<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+    /// Code of the current block, or 0 before the first.
+    code: u8,
+    /// Data bytes left in the current block.
+    run: u8,
+    /// Set after an error, until the end of the packet.
+    discarding: bool,
+}
+
+impl<'b> CobsDecoder<'b> {
+    pub fn new(buf: &'b mut [u8]) -> CobsDecoder<'b> {
+        CobsDecoder {
+            buf: buf,
+            len: 0,
+            code: 0,
+            run: 0,
+            discarding: false,
+        }
+    }
+
+    /// Drops any partial packet.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.code = 0;
+        self.run = 0;
+        self.discarding = false
+    }
+
+    /// Adds a received byte, returning the packet it completes, if any.  An
+    /// error is returned once, at the byte where the packet went wrong.
+    pub fn push(&mut self, byte: u8) -> Result<Option<&[u8]>, FramingError> {
+        if byte == 0 {
+            let len = self.len;
+            let (discarding, empty, truncated) =
+                (self.discarding, self.code == 0, self.run != 0);
+            self.reset();
+            if discarding || empty {
+                return Ok(None)
+            }
+            if truncated {
+                return Err(FramingError::Truncated)
+            }
+            return Ok(Some(&self.buf[..len]))
+        }
+        if self.discarding {
+            return Ok(None)
+        }
+
+        if self.run == 0 {
+            // A new block.  The zero that ended the last one, unless it was
+            // full, goes in first.
+            if self.code != 0 && self.code != 0xFF && !self.append(0) {
+                return self.overflow()
+            }
+            self.code = byte;
+            self.run = byte - 1;
+            return Ok(None)
+        }
+
+        self.run -= 1;
+        if !self.append(byte) {
+            return self.overflow()
+        }
+        Ok(None)
+    }
+
+    fn append(&mut self, b: u8) -> bool {
+        if self.len == self.buf.len() {
+            return false
+        }
+        self.buf[self.len] = b;
+        self.len += 1;
+        true
+    }
+
+    fn overflow(&mut self) -> Result<Option<&[u8]>, FramingError> {
+        self.discarding = true;
+        Err(FramingError::Overflow)
+    }
+}
+
+/*****************************************************************************
+ * Checksum trailers
+ */
+
+/// A checksum for packet trailers.
+pub trait Checksum {
+    /// Bytes the checksum occupies, at most 4.
+    fn size(&self) -> usize;
+
+    /// Computes the checksum of `data`, writing it to `out[..self.size()]`,
+    /// least significant byte first.
+    fn compute(&self, data: &[u8], out: &mut [u8]);
+}
+
+/// CRC-16, as `crc::crc16_xmodem`.
+pub struct Crc16;
+
+impl Checksum for Crc16 {
+    fn size(&self) -> usize {
+        2
+    }
+
+    fn compute(&self, data: &[u8], out: &mut [u8]) {
+        put_le(crc16_xmodem(data) as u32, &mut out[..2])
+    }
+}
+
+/// CRC-32, as used by Ethernet; see `crc::crc32`.
+pub struct Crc32;
+
+impl Checksum for Crc32 {
+    fn size(&self) -> usize {
+        4
+    }
+
+    fn compute(&self, data: &[u8], out: &mut [u8]) {
+        put_le(crc32(data), &mut out[..4])
+    }
+}
+
+/// CRC-32/MPEG-2, computed in software; see `crc::crc32_mpeg2`.  This is
+/// the checksum to use at the other end of a link using `HwCrc32`.
+pub struct Crc32Mpeg2;
+
+impl Checksum for Crc32Mpeg2 {
+    fn size(&self) -> usize {
+        4
+    }
+
+    fn compute(&self, data: &[u8], out: &mut [u8]) {
+        put_le(crc32_mpeg2(data), &mut out[..4])
+    }
+}
+
+/// CRC-32/MPEG-2, computed by the STM32F4's CRC unit, whose clock must be
+/// enabled.  The unit is shared, so this mustn't be used from more than
+/// one context at a time.
+#[cfg(not(feature = "arch:armv6-m"))]
+pub struct HwCrc32(pub &'static CrcUnit);
+
+#[cfg(not(feature = "arch:armv6-m"))]
+impl Checksum for HwCrc32 {
+    fn size(&self) -> usize {
+        4
+    }
+
+    fn compute(&self, data: &[u8], out: &mut [u8]) {
+        put_le(self.0.compute(data), &mut out[..4])
+    }
+}
+
+fn put_le(v: u32, out: &mut [u8]) {
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = (v >> (8 * i)) as u8
+    }
+}
+
+/// Appends the checksum of `buf[..len]` to it, returning the new length, or
+/// `None` if there's no room.
+pub fn append_checksum<C: Checksum>(checksum: &C, buf: &mut [u8], len: usize)
+    -> Option<usize> {
+    let end = len + checksum.size();
+    if end > buf.len() {
+        return None
+    }
+    let (data, trailer) = buf[..end].split_at_mut(len);
+    checksum.compute(data, trailer);
+    Some(end)
+}
+
+/// Checks the checksum trailing `packet`, returning the data before it if
+/// it matches.
+pub fn strip_checksum<'p, C: Checksum>(checksum: &C, packet: &'p [u8])
+    -> Option<&'p [u8]> {
+    let n = checksum.size();
+    if packet.len() < n {
+        return None
+    }
+    let (data, trailer) = packet.split_at(packet.len() - n);
+    let mut expected = [0; 4];
+    checksum.compute(data, &mut expected[..n]);
+    if &expected[..n] == trailer {
+        Some(data)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,366 @@
+//! MAVLink v1 and v2 framing.
+//!
+//! This handles the envelope -- start byte, header, checksum, and (in v2)
+//! signature -- and leaves the payloads to the application, so it works
+//! with any dialect.  The one thing it needs to know about the messages is
+//! each one's `CRC_EXTRA`, a checksum of the message definition that is
+//! folded into the frame checksum so that mismatched definitions are
+//! caught.  That comes from a table the application supplies, generated
+//! along with its message definitions:
+//!
+//!     static MESSAGES: [MessageInfo; 2] = [
+//!         MessageInfo { id: 0, crc_extra: 50 },     // HEARTBEAT
+//!         MessageInfo { id: 33, crc_extra: 104 },   // GLOBAL_POSITION_INT
+//!     ];
+//!
+//!     let mut parser = Parser::new(&MESSAGES);
+//!     while let Ok(b) = port.try_read() {
+//!         if let Some(frame) = parser.push(b) {
+//!             // ...frame.header.msgid, frame.payload...
+//!         }
+//!     }
+//!
+//!     let mut out = Serializer::new(Version::V2, 1, 1);
+//!     let mut buf = [0; MAX_FRAME_LEN];
+//!     let n = try!(out.encode(&MESSAGES[0], &heartbeat, &mut buf));
+//!     try!(port.write_all(&buf[..n]));
+//!
+//! Frames whose message isn't in the table can't be checked, and are
+//! dropped.  Signed v2 frames are accepted, with their signature available
+//! for the application to check; this module doesn't verify or produce
+//! signatures.
+
+use core::cmp;
+
+use crc::{crc16_mcrf4xx, crc16_mcrf4xx_continue};
+
+/// Start byte of a v1 frame.
+pub const STX_V1 : u8 = 0xFE;
+/// Start byte of a v2 frame.
+pub const STX_V2 : u8 = 0xFD;
+
+/// Bytes in a v1 header, including the start byte.
+const HEADER_LEN_V1 : usize = 6;
+/// Bytes in a v2 header, including the start byte.
+const HEADER_LEN_V2 : usize = 10;
+const CHECKSUM_LEN : usize = 2;
+const SIGNATURE_LEN : usize = 13;
+
+/// Longest payload.
+pub const MAX_PAYLOAD_LEN : usize = 255;
+/// Longest frame: a signed v2 frame with the longest payload.
+pub const MAX_FRAME_LEN : usize =
+    HEADER_LEN_V2 + MAX_PAYLOAD_LEN + CHECKSUM_LEN + SIGNATURE_LEN;
+
+/// v2 incompatibility flag: the frame is signed.
+pub const IFLAG_SIGNED : u8 = 0x01;
+
+/// Protocol versions.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+/// What the framing needs to know about a message type.
+#[derive(Copy, Clone, Debug)]
+pub struct MessageInfo {
+    pub id: u32,
+    /// The `CRC_EXTRA` byte generated from the message definition.
+    pub crc_extra: u8,
+}
+
+/// Finds message `id` in `table`.
+pub fn lookup(table: &[MessageInfo], id: u32) -> Option<&MessageInfo> {
+    table.iter().find(|m| m.id == id)
+}
+
+/// Ways that encoding a frame can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum MavlinkError {
+    /// The output buffer can't hold the frame.
+    BufferTooSmall,
+    /// The payload is longer than `MAX_PAYLOAD_LEN`.
+    PayloadTooLong,
+    /// The message ID doesn't fit the version: v1 IDs are 8 bits, v2 IDs
+    /// 24 bits.
+    IdTooLarge,
+}
+
+/// A frame's header fields.
+#[derive(Copy, Clone, Debug)]
+pub struct Header {
+    pub version: Version,
+    /// Incompatibility and compatibility flags; always 0 for v1.
+    pub incompat_flags: u8,
+    pub compat_flags: u8,
+    /// Sequence number, incremented by the sender for each frame; gaps mean
+    /// lost frames.
+    pub seq: u8,
+    /// Sending system and component.
+    pub sysid: u8,
+    pub compid: u8,
+    pub msgid: u32,
+}
+
+/// A received frame.
+#[derive(Copy, Clone, Debug)]
+pub struct Frame<'a> {
+    pub header: Header,
+    /// The payload as sent.  v2 senders drop trailing zero bytes, so this
+    /// may be shorter than the message; see `copy_payload`.
+    pub payload: &'a [u8],
+    /// The signature, for signed frames.
+    pub signature: Option<&'a [u8]>,
+}
+
+impl<'a> Frame<'a> {
+    /// Copies the payload into `out`, zero-filling whatever is left, which
+    /// restores any zeros a v2 sender dropped.  Returns the number of
+    /// payload bytes copied; any beyond `out.len()` are ignored, as newer
+    /// versions of a message may have added fields.
+    pub fn copy_payload(&self, out: &mut [u8]) -> usize {
+        let n = cmp::min(out.len(), self.payload.len());
+        out[..n].copy_from_slice(&self.payload[..n]);
+        for b in out[n..].iter_mut() {
+            *b = 0
+        }
+        n
+    }
+}
+
+/// Counts of frames the parser has dropped.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Stats {
+    /// Frames that failed the checksum.
+    pub bad_checksums: usize,
+    /// Frames for messages not in the table.
+    pub unknown_messages: usize,
+    /// v2 frames with incompatibility flags we don't understand.
+    pub unsupported: usize,
+}
+
+/// Reassembles frames from a byte stream.
+pub struct Parser<'t> {
+    table: &'t [MessageInfo],
+    buf: [u8; MAX_FRAME_LEN],
+    /// Bytes of the current frame in `buf`; 0 when hunting for a start
+    /// byte.
+    len: usize,
+    /// Length of the current frame, once its header is in.
+    frame_len: usize,
+    stats: Stats,
+}
+
+impl<'t> Parser<'t> {
+    /// Creates a parser accepting the messages in `table`.
+    pub fn new(table: &'t [MessageInfo]) -> Parser<'t> {
+        Parser {
+            table: table,
+            buf: [0; MAX_FRAME_LEN],
+            len: 0,
+            frame_len: 0,
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Drops any partial frame.
+    pub fn reset(&mut self) {
+        self.len = 0
+    }
+
+    /// Adds a received byte.  Returns the frame it completes, if that frame
+    /// is intact and its message is in the table.
+    ///
+    /// After a bad frame, the parser hunts for the next start byte from the
+    /// end of that frame, not from within it; a frame following a
+    /// corrupted length byte may be lost.
+    pub fn push(&mut self, byte: u8) -> Option<Frame> {
+        if self.len == 0 {
+            if byte != STX_V1 && byte != STX_V2 {
+                return None
+            }
+            self.frame_len = 0;
+        }
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        let v2 = self.buf[0] == STX_V2;
+        let header_len = if v2 { HEADER_LEN_V2 } else { HEADER_LEN_V1 };
+        if self.len == header_len {
+            let payload_len = self.buf[1] as usize;
+            let signed = v2 && self.buf[2] & IFLAG_SIGNED != 0;
+            self.frame_len = header_len + payload_len + CHECKSUM_LEN
+                + if signed { SIGNATURE_LEN } else { 0 };
+        }
+        if self.frame_len == 0 || self.len < self.frame_len {
+            return None
+        }
+
+        self.len = 0;
+        self.check_frame()
+    }
+
+    /// Checks the complete frame in `buf`.
+    fn check_frame(&mut self) -> Option<Frame> {
+        let buf = &self.buf[..self.frame_len];
+        let payload_len = buf[1] as usize;
+        let header = if buf[0] == STX_V2 {
+            Header {
+                version: Version::V2,
+                incompat_flags: buf[2],
+                compat_flags: buf[3],
+                seq: buf[4],
+                sysid: buf[5],
+                compid: buf[6],
+                msgid: buf[7] as u32
+                    | (buf[8] as u32) << 8
+                    | (buf[9] as u32) << 16,
+            }
+        } else {
+            Header {
+                version: Version::V1,
+                incompat_flags: 0,
+                compat_flags: 0,
+                seq: buf[2],
+                sysid: buf[3],
+                compid: buf[4],
+                msgid: buf[5] as u32,
+            }
+        };
+        let header_len = match header.version {
+            Version::V1 => HEADER_LEN_V1,
+            Version::V2 => HEADER_LEN_V2,
+        };
+
+        if header.incompat_flags & !IFLAG_SIGNED != 0 {
+            self.stats.unsupported += 1;
+            return None
+        }
+        let info = match lookup(self.table, header.msgid) {
+            Some(i) => *i,
+            None => {
+                self.stats.unknown_messages += 1;
+                return None
+            },
+        };
+
+        let body_end = header_len + payload_len;
+        let crc = crc16_mcrf4xx_continue(crc16_mcrf4xx(&buf[1..body_end]),
+                                         &[info.crc_extra]);
+        let sent = buf[body_end] as u16 | (buf[body_end + 1] as u16) << 8;
+        if crc != sent {
+            self.stats.bad_checksums += 1;
+            return None
+        }
+
+        let sig_start = body_end + CHECKSUM_LEN;
+        Some(Frame {
+            header: header,
+            payload: &buf[header_len..body_end],
+            signature: if sig_start < buf.len() {
+                Some(&buf[sig_start..])
+            } else {
+                None
+            },
+        })
+    }
+}
+
+/// Builds frames from one system and component, numbering them.
+pub struct Serializer {
+    version: Version,
+    sysid: u8,
+    compid: u8,
+    seq: u8,
+}
+
+impl Serializer {
+    pub fn new(version: Version, sysid: u8, compid: u8) -> Serializer {
+        Serializer {
+            version: version,
+            sysid: sysid,
+            compid: compid,
+            seq: 0,
+        }
+    }
+
+    pub fn set_version(&mut self, version: Version) {
+        self.version = version
+    }
+
+    /// Sequence number the next frame will carry.
+    pub fn next_seq(&self) -> u8 {
+        self.seq
+    }
+
+    /// Writes a frame carrying `payload` as message `msg` into `out`,
+    /// returning its length.  For v2, trailing zero bytes of the payload are
+    /// dropped, as the protocol requires.  The sequence number only
+    /// advances if the frame is built.
+    pub fn encode(&mut self, msg: &MessageInfo, payload: &[u8],
+                  out: &mut [u8])
+        -> Result<usize, MavlinkError> {
+        if payload.len() > MAX_PAYLOAD_LEN {
+            return Err(MavlinkError::PayloadTooLong)
+        }
+        let (payload, header_len) = match self.version {
+            Version::V1 => {
+                if msg.id > 0xFF {
+                    return Err(MavlinkError::IdTooLarge)
+                }
+                (payload, HEADER_LEN_V1)
+            },
+            Version::V2 => {
+                if msg.id > 0xFF_FFFF {
+                    return Err(MavlinkError::IdTooLarge)
+                }
+                // At least one byte is always sent.
+                let mut n = payload.len();
+                while n > 1 && payload[n - 1] == 0 {
+                    n -= 1
+                }
+                (&payload[..n], HEADER_LEN_V2)
+            },
+        };
+        let body_end = header_len + payload.len();
+        let frame_len = body_end + CHECKSUM_LEN;
+        if out.len() < frame_len {
+            return Err(MavlinkError::BufferTooSmall)
+        }
+
+        out[1] = payload.len() as u8;
+        match self.version {
+            Version::V1 => {
+                out[0] = STX_V1;
+                out[2] = self.seq;
+                out[3] = self.sysid;
+                out[4] = self.compid;
+                out[5] = msg.id as u8;
+            },
+            Version::V2 => {
+                out[0] = STX_V2;
+                out[2] = 0;
+                out[3] = 0;
+                out[4] = self.seq;
+                out[5] = self.sysid;
+                out[6] = self.compid;
+                out[7] = msg.id as u8;
+                out[8] = (msg.id >> 8) as u8;
+                out[9] = (msg.id >> 16) as u8;
+            },
+        }
+        out[header_len..body_end].copy_from_slice(payload);
+        let crc = crc16_mcrf4xx_continue(crc16_mcrf4xx(&out[1..body_end]),
+                                         &[msg.crc_extra]);
+        out[body_end] = crc as u8;
+        out[body_end + 1] = (crc >> 8) as u8;
+
+        self.seq = self.seq.wrapping_add(1);
+        Ok(frame_len)
+    }
+}
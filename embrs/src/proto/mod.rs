@@ -0,0 +1,10 @@
+//! Wire protocols, independent of the link that carries them.
+//!
+//! These work on bytes, in fixed buffers, so that they can sit on top of a
+//! USART, a USB serial port, or anything else that moves bytes -- typically
+//! through the `hal` serial traits.
+
+pub mod framing;
+pub mod mavlink;
+#[cfg(not(feature = "arch:armv6-m"))]
+pub mod modbus;
@@ -0,0 +1,1009 @@
+//! Modbus RTU, as slave or master, over an RS-485 serial port.
+//!
+//! An RTU frame is a slave address, a function code, data, and a CRC-16,
+//! sent as one burst of characters; frames are separated by at least 3.5
+//! character times of silence.  `RtuPort` handles that layer, over any
+//! serial port with the `hal` traits: it sends frames with the
+//! transceiver's driver enabled (through a `DriverEnable`), and finds the
+//! ends of received frames by timing the silence (through a `GapTimer`),
+//! since at higher baud rates the gap is far shorter than a SysTick.
+//!
+//! On the STM32F4, `usart::Rs485` is a `DriverEnable`, and `tim::GapTick`
+//! makes a `GapTimer` of a timer:
+//!
+//!     static TICK: Tick = Tick::new(&TIM7);
+//!     let rs485 = Rs485::new(&USART2, de_pin);
+//!     let gap = GapTick::new(&TICK, clock::frozen());
+//!     let port = RtuPort::new(&USART2, &rs485, &gap, 19200);
+//!
+//! The USART must already be configured for the link (commonly 8E1), and
+//! its interrupt routed to a handler calling `Rs485::handle_interrupt`, so
+//! that DE is released after each frame.  The timer's clock must be
+//! enabled; its interrupt isn't used.
+//!
+//! A `Slave` answers requests from the registers of a `RegisterMap` that the
+//! application implements.  A `Master` sends requests and waits, with a
+//! timeout, for the answers.  Both are polled: call `Slave::poll` often --
+//! at least once a character time, unless the USART's receive is buffered
+//! -- and the master's requests block until they finish.  Timeouts use
+//! `time::now_ms`.
+//!
+//! Modbus expects one master per line, but where there are more, their
+//! frames can collide, and both go unanswered.  `Master::set_retries` has
+//! unanswered requests sent again after a randomized delay (see `backoff`),
+//! waiting for the line to fall quiet first, so that masters don't keep
+//! colliding.
+
+use backoff::Backoff;
+use crc::crc16_modbus;
+use hal::{NbError, SerialRead, SerialWrite};
+use time;
+
+/// Address that every slave obeys, and none answers.
+pub const BROADCAST : u8 = 0;
+
+/// Longest frame, including address and CRC.
+pub const MAX_FRAME_LEN : usize = 256;
+/// Bytes of CRC at the end of each frame.
+const CRC_LEN : usize = 2;
+
+// Function codes.
+pub const READ_COILS : u8 = 0x01;
+pub const READ_DISCRETE_INPUTS : u8 = 0x02;
+pub const READ_HOLDING_REGISTERS : u8 = 0x03;
+pub const READ_INPUT_REGISTERS : u8 = 0x04;
+pub const WRITE_SINGLE_COIL : u8 = 0x05;
+pub const WRITE_SINGLE_REGISTER : u8 = 0x06;
+pub const WRITE_MULTIPLE_COILS : u8 = 0x0F;
+pub const WRITE_MULTIPLE_REGISTERS : u8 = 0x10;
+
+/// Set in the function code of an exception response.
+const EXCEPTION_FLAG : u8 = 0x80;
+
+/// Most registers one request can read or write, so that the answer fits
+/// in a frame.
+pub const MAX_REGISTERS : usize = 123;
+/// Most coils or inputs one request can read or write.
+pub const MAX_BITS : usize = 1968;
+
+/// Exception codes, returned by a slave that can't carry out a request.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Exception {
+    IllegalFunction,
+    IllegalDataAddress,
+    IllegalDataValue,
+    ServerDeviceFailure,
+    /// Any other code.
+    Other(u8),
+}
+
+impl Exception {
+    pub fn code(self) -> u8 {
+        match self {
+            Exception::IllegalFunction => 1,
+            Exception::IllegalDataAddress => 2,
+            Exception::IllegalDataValue => 3,
+            Exception::ServerDeviceFailure => 4,
+            Exception::Other(c) => c,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Exception {
+        match code {
+            1 => Exception::IllegalFunction,
+            2 => Exception::IllegalDataAddress,
+            3 => Exception::IllegalDataValue,
+            4 => Exception::ServerDeviceFailure,
+            c => Exception::Other(c),
+        }
+    }
+}
+
+/// Ways that a master's request can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ModbusError {
+    /// No valid answer arrived in time.
+    Timeout,
+    /// The slave answered with an exception.
+    Exception(Exception),
+    /// The answer didn't match the request.
+    BadResponse,
+    /// Too many (or no) registers or coils for one request.
+    BadCount,
+    /// A read addressed to `BROADCAST`, which no slave would answer.
+    BroadcastRead,
+}
+
+/*****************************************************************************
+ * Framing.
+ */
+
+/// Returns the inter-frame gap, in seconds, at `baud`: 3.5 characters of
+/// eleven bits, or, above 19200 baud, a fixed 1.75ms.
+pub fn frame_gap(baud: u32) -> f32 {
+    if baud > 19200 {
+        0.00175
+    } else {
+        3.5 * 11. / baud as f32
+    }
+}
+
+/// Control of an RS-485 transceiver's driver, around each frame sent.
+pub trait DriverEnable {
+    /// Enables the driver, before the first byte of a frame is written.
+    fn begin_transmit(&self);
+    /// Called once the last byte of a frame has been written.  The driver
+    /// must stay enabled until that byte has left the wire.
+    fn end_transmit(&self);
+}
+
+/// A one-shot timer for the silence that ends a frame.
+pub trait GapTimer {
+    /// Starts, or restarts, timing a gap of `seconds`.
+    fn start_gap(&self, seconds: f32);
+    /// Checks whether the gap last started has elapsed.
+    fn is_expired(&self) -> bool;
+}
+
+/// Sends and receives RTU frames over `serial`.
+pub struct RtuPort<'a, S: 'a, D: 'a, T: 'a> {
+    serial: &'a S,
+    de: &'a D,
+    timer: &'a T,
+    gap: f32,
+    buf: [u8; MAX_FRAME_LEN],
+    len: usize,
+    /// Whether the frame being received has been damaged: a byte lost, or
+    /// received with an error.
+    damaged: bool,
+    /// Number of frames dropped as damaged or failing their CRC.
+    bad_frames: usize,
+}
+
+impl<'a, S, D, T> RtuPort<'a, S, D, T>
+    where S: SerialRead + SerialWrite, D: DriverEnable, T: GapTimer {
+    /// Creates a port on `serial` at `baud` (which must match the port's
+    /// setting), with the driver controlled by `de` and frame gaps timed by
+    /// `timer`.
+    pub fn new(serial: &'a S, de: &'a D, timer: &'a T, baud: u32)
+        -> RtuPort<'a, S, D, T> {
+        RtuPort {
+            serial: serial,
+            de: de,
+            timer: timer,
+            gap: frame_gap(baud),
+            buf: [0; MAX_FRAME_LEN],
+            len: 0,
+            damaged: false,
+            bad_frames: 0,
+        }
+    }
+
+    /// Number of received frames dropped as damaged or corrupt.
+    pub fn bad_frames(&self) -> usize {
+        self.bad_frames
+    }
+
+    /// Checks whether a frame is partly received: bytes have arrived, but
+    /// not yet the gap that ends them.  Only meaningful after `poll`.
+    pub fn is_receiving(&self) -> bool {
+        self.len != 0
+    }
+
+    /// Drops any partly received frame.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.damaged = false
+    }
+
+    /// Sends `frame` -- address and PDU -- with its CRC, blocking until the
+    /// last byte is in the serial port.  The driver is released when it has
+    /// gone.
+    pub fn send(&mut self, frame: &[u8]) {
+        let crc = crc16_modbus(frame);
+        self.de.begin_transmit();
+        for &b in frame.iter().chain([crc as u8, (crc >> 8) as u8].iter()) {
+            // A frame is sent whole or not at all; there's nothing useful
+            // to do about a failure partway.
+            let _ = self.serial.write(b);
+        }
+        self.de.end_transmit()
+    }
+
+    /// Collects received bytes.  Once a frame has been followed by the
+    /// inter-frame gap, returns its length if its CRC is good; `frame` then
+    /// gives its contents until the next call.
+    pub fn poll(&mut self) -> Option<usize> {
+        loop {
+            match self.serial.try_read() {
+                Ok(b) => {
+                    if self.len < MAX_FRAME_LEN {
+                        self.buf[self.len] = b
+                    } else {
+                        self.damaged = true
+                    }
+                    self.len += 1;
+                    self.restart_gap()
+                },
+                Err(NbError::Other(_)) => {
+                    self.damaged = true;
+                    self.len += 1;
+                    self.restart_gap()
+                },
+                Err(NbError::WouldBlock) => break,
+            }
+        }
+
+        if self.len == 0 || !self.timer.is_expired() {
+            return None
+        }
+        let len = self.len;
+        let damaged = self.damaged;
+        self.reset();
+        if damaged || len < 1 + 1 + CRC_LEN {
+            self.bad_frames += 1;
+            return None
+        }
+        let n = len - CRC_LEN;
+        let crc = self.buf[n] as u16 | (self.buf[n + 1] as u16) << 8;
+        if crc16_modbus(&self.buf[..n]) != crc {
+            self.bad_frames += 1;
+            return None
+        }
+        Some(n)
+    }
+
+    /// The frame last returned by `poll`, without its CRC.
+    pub fn frame(&self, len: usize) -> &[u8] {
+        &self.buf[..len]
+    }
+
+    fn restart_gap(&self) {
+        self.timer.start_gap(self.gap)
+    }
+}
+
+/*****************************************************************************
+ * Slave.
+ */
+
+/// The data a slave exposes.  Each method handles one item; those left
+/// unimplemented report `IllegalDataAddress`.
+pub trait RegisterMap {
+    fn read_coil(&mut self, _addr: u16) -> Result<bool, Exception> {
+        Err(Exception::IllegalDataAddress)
+    }
+
+    fn write_coil(&mut self, _addr: u16, _value: bool)
+        -> Result<(), Exception> {
+        Err(Exception::IllegalDataAddress)
+    }
+
+    fn read_discrete_input(&mut self, _addr: u16) -> Result<bool, Exception> {
+        Err(Exception::IllegalDataAddress)
+    }
+
+    fn read_holding_register(&mut self, _addr: u16)
+        -> Result<u16, Exception> {
+        Err(Exception::IllegalDataAddress)
+    }
+
+    fn write_holding_register(&mut self, _addr: u16, _value: u16)
+        -> Result<(), Exception> {
+        Err(Exception::IllegalDataAddress)
+    }
+
+    fn read_input_register(&mut self, _addr: u16) -> Result<u16, Exception> {
+        Err(Exception::IllegalDataAddress)
+    }
+}
+
+fn get16(b: &[u8]) -> u16 {
+    (b[0] as u16) << 8 | b[1] as u16
+}
+
+fn put16(b: &mut [u8], v: u16) {
+    b[0] = (v >> 8) as u8;
+    b[1] = v as u8
+}
+
+/// Carries out `request` (address and PDU) against `map`, writing the
+/// response (address and PDU) to `out`, and returning its length.
+pub fn handle_request<M: RegisterMap>(map: &mut M,
+                                      request: &[u8],
+                                      out: &mut [u8; MAX_FRAME_LEN])
+    -> usize {
+    out[0] = request[0];
+    out[1] = request[1];
+    match execute(map, &request[1..], &mut out[1..]) {
+        Ok(n) => 1 + n,
+        Err(e) => {
+            out[1] = request[1] | EXCEPTION_FLAG;
+            out[2] = e.code();
+            3
+        },
+    }
+}
+
+/// Carries out a request PDU, writing the response PDU to `out`.
+fn execute<M: RegisterMap>(map: &mut M, pdu: &[u8], out: &mut [u8])
+    -> Result<usize, Exception> {
+    let function = pdu[0];
+    let data = &pdu[1..];
+    match function {
+        READ_COILS | READ_DISCRETE_INPUTS => {
+            let (addr, count) = try!(read_request(data, MAX_BITS));
+            let bytes = (count + 7) / 8;
+            out[1] = bytes as u8;
+            for b in out[2..2 + bytes].iter_mut() {
+                *b = 0
+            }
+            for i in 0..count {
+                let a = addr.wrapping_add(i as u16);
+                let on = if function == READ_COILS {
+                    try!(map.read_coil(a))
+                } else {
+                    try!(map.read_discrete_input(a))
+                };
+                if on {
+                    out[2 + i / 8] |= 1 << (i % 8)
+                }
+            }
+            Ok(2 + bytes)
+        },
+        READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+            let (addr, count) = try!(read_request(data, MAX_REGISTERS));
+            out[1] = (count * 2) as u8;
+            for i in 0..count {
+                let a = addr.wrapping_add(i as u16);
+                let v = if function == READ_HOLDING_REGISTERS {
+                    try!(map.read_holding_register(a))
+                } else {
+                    try!(map.read_input_register(a))
+                };
+                put16(&mut out[2 + 2 * i..], v)
+            }
+            Ok(2 + 2 * count)
+        },
+        WRITE_SINGLE_COIL => {
+            if data.len() != 4 {
+                return Err(Exception::IllegalDataValue)
+            }
+            let on = match get16(&data[2..]) {
+                0xFF00 => true,
+                0x0000 => false,
+                _ => return Err(Exception::IllegalDataValue),
+            };
+            try!(map.write_coil(get16(data), on));
+            out[1..5].copy_from_slice(data);
+            Ok(5)
+        },
+        WRITE_SINGLE_REGISTER => {
+            if data.len() != 4 {
+                return Err(Exception::IllegalDataValue)
+            }
+            try!(map.write_holding_register(get16(data), get16(&data[2..])));
+            out[1..5].copy_from_slice(data);
+            Ok(5)
+        },
+        WRITE_MULTIPLE_COILS | WRITE_MULTIPLE_REGISTERS => {
+            let coils = function == WRITE_MULTIPLE_COILS;
+            if data.len() < 5 {
+                return Err(Exception::IllegalDataValue)
+            }
+            let addr = get16(data);
+            let count = get16(&data[2..]) as usize;
+            let bytes = data[4] as usize;
+            let (max, expected) = if coils {
+                (MAX_BITS, (count + 7) / 8)
+            } else {
+                (MAX_REGISTERS, count * 2)
+            };
+            if count == 0 || count > max || bytes != expected
+                || data.len() != 5 + bytes {
+                return Err(Exception::IllegalDataValue)
+            }
+            let values = &data[5..];
+            for i in 0..count {
+                let a = addr.wrapping_add(i as u16);
+                if coils {
+                    let on = values[i / 8] & (1 << (i % 8)) != 0;
+                    try!(map.write_coil(a, on))
+                } else {
+                    let v = get16(&values[2 * i..]);
+                    try!(map.write_holding_register(a, v))
+                }
+            }
+            out[1..5].copy_from_slice(&data[..4]);
+            Ok(5)
+        },
+        _ => Err(Exception::IllegalFunction),
+    }
+}
+
+/// Parses the address and count of a read request.
+fn read_request(data: &[u8], max: usize) -> Result<(u16, usize), Exception> {
+    if data.len() != 4 {
+        return Err(Exception::IllegalDataValue)
+    }
+    let count = get16(&data[2..]) as usize;
+    if count == 0 || count > max {
+        return Err(Exception::IllegalDataValue)
+    }
+    Ok((get16(data), count))
+}
+
+/// A Modbus slave.
+pub struct Slave<'a, S: 'a, D: 'a, T: 'a> {
+    port: RtuPort<'a, S, D, T>,
+    address: u8,
+    response: [u8; MAX_FRAME_LEN],
+}
+
+impl<'a, S, D, T> Slave<'a, S, D, T>
+    where S: SerialRead + SerialWrite, D: DriverEnable, T: GapTimer {
+    /// Creates a slave answering to `address` (1-247) on `port`.
+    pub fn new(port: RtuPort<'a, S, D, T>, address: u8)
+        -> Slave<'a, S, D, T> {
+        Slave {
+            port: port,
+            address: address,
+            response: [0; MAX_FRAME_LEN],
+        }
+    }
+
+    pub fn port(&self) -> &RtuPort<'a, S, D, T> {
+        &self.port
+    }
+
+    /// Handles any request that has arrived, against `map`, and sends the
+    /// answer.  Returns `true` if a request for this slave was handled.
+    pub fn poll<M: RegisterMap>(&mut self, map: &mut M) -> bool {
+        let len = match self.port.poll() {
+            Some(n) => n,
+            None => return false,
+        };
+        let (n, broadcast) = {
+            let request = self.port.frame(len);
+            let to = request[0];
+            if to != self.address && to != BROADCAST {
+                return false
+            }
+            (handle_request(map, request, &mut self.response),
+             to == BROADCAST)
+        };
+        if !broadcast {
+            self.port.send(&self.response[..n])
+        }
+        true
+    }
+}
+
+/*****************************************************************************
+ * Master.
+ */
+
+/// A Modbus master.
+pub struct Master<'a, S: 'a, D: 'a, T: 'a> {
+    port: RtuPort<'a, S, D, T>,
+    timeout_ms: u32,
+    request: [u8; MAX_FRAME_LEN],
+    /// Retries of unanswered requests, and the delays before them.
+    retries: u32,
+    backoff: Option<Backoff>,
+}
+
+impl<'a, S, D, T> Master<'a, S, D, T>
+    where S: SerialRead + SerialWrite, D: DriverEnable, T: GapTimer {
+    /// Creates a master on `port`, waiting up to `timeout_ms` for each
+    /// answer.
+    pub fn new(port: RtuPort<'a, S, D, T>, timeout_ms: u32)
+        -> Master<'a, S, D, T> {
+        Master {
+            port: port,
+            timeout_ms: timeout_ms,
+            request: [0; MAX_FRAME_LEN],
+            retries: 0,
+            backoff: None,
+        }
+    }
+
+    pub fn port(&self) -> &RtuPort<'a, S, D, T> {
+        &self.port
+    }
+
+    pub fn set_timeout_ms(&mut self, timeout_ms: u32) {
+        self.timeout_ms = timeout_ms
+    }
+
+    /// Sends requests that time out again, up to `retries` more times,
+    /// waiting a delay from `backoff`, in milliseconds, before each.  Every
+    /// request then waits for any frame already on the line to end before
+    /// it's sent.
+    pub fn set_retries(&mut self, retries: u32, backoff: Backoff) {
+        self.retries = retries;
+        self.backoff = Some(backoff)
+    }
+
+    /// Reads `out.len()` holding registers of `slave`, from `addr`.
+    pub fn read_holding_registers(&mut self, slave: u8, addr: u16,
+                                  out: &mut [u16])
+        -> Result<(), ModbusError> {
+        self.read_registers(slave, READ_HOLDING_REGISTERS, addr, out)
+    }
+
+    /// Reads `out.len()` input registers of `slave`, from `addr`.
+    pub fn read_input_registers(&mut self, slave: u8, addr: u16,
+                                out: &mut [u16])
+        -> Result<(), ModbusError> {
+        self.read_registers(slave, READ_INPUT_REGISTERS, addr, out)
+    }
+
+    /// Reads `out.len()` coils of `slave`, from `addr`.
+    pub fn read_coils(&mut self, slave: u8, addr: u16, out: &mut [bool])
+        -> Result<(), ModbusError> {
+        self.read_bits(slave, READ_COILS, addr, out)
+    }
+
+    /// Reads `out.len()` discrete inputs of `slave`, from `addr`.
+    pub fn read_discrete_inputs(&mut self, slave: u8, addr: u16,
+                                out: &mut [bool])
+        -> Result<(), ModbusError> {
+        self.read_bits(slave, READ_DISCRETE_INPUTS, addr, out)
+    }
+
+    pub fn write_single_coil(&mut self, slave: u8, addr: u16, value: bool)
+        -> Result<(), ModbusError> {
+        self.set_header(slave, WRITE_SINGLE_COIL, addr,
+                        if value { 0xFF00 } else { 0 });
+        let _ = try!(self.transact(6, 5));
+        Ok(())
+    }
+
+    pub fn write_single_register(&mut self, slave: u8, addr: u16,
+                                 value: u16)
+        -> Result<(), ModbusError> {
+        self.set_header(slave, WRITE_SINGLE_REGISTER, addr, value);
+        let _ = try!(self.transact(6, 5));
+        Ok(())
+    }
+
+    /// Writes `values` to the holding registers of `slave`, from `addr`.
+    pub fn write_multiple_registers(&mut self, slave: u8, addr: u16,
+                                    values: &[u16])
+        -> Result<(), ModbusError> {
+        let count = values.len();
+        if count == 0 || count > MAX_REGISTERS {
+            return Err(ModbusError::BadCount)
+        }
+        self.set_header(slave, WRITE_MULTIPLE_REGISTERS, addr, count as u16);
+        self.request[6] = (count * 2) as u8;
+        for (i, &v) in values.iter().enumerate() {
+            put16(&mut self.request[7 + 2 * i..], v)
+        }
+        let _ = try!(self.transact(7 + 2 * count, 5));
+        Ok(())
+    }
+
+    /// Writes `values` to the coils of `slave`, from `addr`.
+    pub fn write_multiple_coils(&mut self, slave: u8, addr: u16,
+                                values: &[bool])
+        -> Result<(), ModbusError> {
+        let count = values.len();
+        if count == 0 || count > MAX_BITS {
+            return Err(ModbusError::BadCount)
+        }
+        let bytes = (count + 7) / 8;
+        self.set_header(slave, WRITE_MULTIPLE_COILS, addr, count as u16);
+        self.request[6] = bytes as u8;
+        for b in self.request[7..7 + bytes].iter_mut() {
+            *b = 0
+        }
+        for (i, &on) in values.iter().enumerate() {
+            if on {
+                self.request[7 + i / 8] |= 1 << (i % 8)
+            }
+        }
+        let _ = try!(self.transact(7 + bytes, 5));
+        Ok(())
+    }
+
+    fn read_registers(&mut self, slave: u8, function: u8, addr: u16,
+                      out: &mut [u16])
+        -> Result<(), ModbusError> {
+        if slave == BROADCAST {
+            return Err(ModbusError::BroadcastRead)
+        }
+        let count = out.len();
+        if count == 0 || count > MAX_REGISTERS {
+            return Err(ModbusError::BadCount)
+        }
+        self.set_header(slave, function, addr, count as u16);
+        let len = try!(self.transact(6, 2 + 2 * count));
+        let pdu = &self.port.frame(len)[1..];
+        if pdu[1] as usize != 2 * count {
+            return Err(ModbusError::BadResponse)
+        }
+        for (i, v) in out.iter_mut().enumerate() {
+            *v = get16(&pdu[2 + 2 * i..])
+        }
+        Ok(())
+    }
+
+    fn read_bits(&mut self, slave: u8, function: u8, addr: u16,
+                 out: &mut [bool])
+        -> Result<(), ModbusError> {
+        if slave == BROADCAST {
+            return Err(ModbusError::BroadcastRead)
+        }
+        let count = out.len();
+        if count == 0 || count > MAX_BITS {
+            return Err(ModbusError::BadCount)
+        }
+        let bytes = (count + 7) / 8;
+        self.set_header(slave, function, addr, count as u16);
+        let len = try!(self.transact(6, 2 + bytes));
+        let pdu = &self.port.frame(len)[1..];
+        if pdu[1] as usize != bytes {
+            return Err(ModbusError::BadResponse)
+        }
+        for (i, v) in out.iter_mut().enumerate() {
+            *v = pdu[2 + i / 8] & (1 << (i % 8)) != 0
+        }
+        Ok(())
+    }
+
+    /// Fills in the address, function, and two 16-bit fields common to most
+    /// requests.
+    fn set_header(&mut self, slave: u8, function: u8, a: u16, b: u16) {
+        self.request[0] = slave;
+        self.request[1] = function;
+        put16(&mut self.request[2..], a);
+        put16(&mut self.request[4..], b)
+    }
+
+    /// Sends the first `len` bytes of `request`, and waits for an answer
+    /// whose PDU is `expected` bytes long, retrying as `set_retries` says.
+    /// Returns the length of the answer frame, which is left in the port.
+    /// Broadcasts get no answer, and return 0 as soon as they're sent.
+    fn transact(&mut self, len: usize, expected: usize)
+        -> Result<usize, ModbusError> {
+        let mut retries = self.retries;
+        let mut delay = 0;
+        loop {
+            if self.backoff.is_some() {
+                self.listen(delay)
+            }
+            match self.exchange(len, expected) {
+                Err(ModbusError::Timeout) if retries > 0 => {
+                    retries -= 1;
+                    if let Some(ref mut b) = self.backoff {
+                        delay = b.next_delay()
+                    }
+                },
+                r => {
+                    if let Some(ref mut b) = self.backoff {
+                        b.reset()
+                    }
+                    return r
+                },
+            }
+        }
+    }
+
+    /// Waits `ms`, and then for any frame on the line to end -- though no
+    /// longer than the timeout -- discarding whatever arrives.
+    fn listen(&mut self, ms: u32) {
+        let start = time::now_ms();
+        let limit = ms.saturating_add(self.timeout_ms);
+        loop {
+            let _ = self.port.poll();
+            let elapsed = time::now_ms().wrapping_sub(start);
+            if (elapsed >= ms && !self.port.is_receiving()) || elapsed > limit {
+                return
+            }
+        }
+    }
+
+    /// Sends the request and waits for its answer, once.
+    fn exchange(&mut self, len: usize, expected: usize)
+        -> Result<usize, ModbusError> {
+        let slave = self.request[0];
+        let function = self.request[1];
+        self.port.reset();
+        self.port.send(&self.request[..len]);
+        if slave == BROADCAST {
+            return Ok(0)
+        }
+
+        let start = time::now_ms();
+        let n;
+        loop {
+            if let Some(len) = self.port.poll() {
+                // Frames from anyone else are ignored.
+                if self.port.frame(len)[0] == slave {
+                    n = len;
+                    break
+                }
+            }
+            if time::now_ms().wrapping_sub(start) > self.timeout_ms {
+                return Err(ModbusError::Timeout)
+            }
+        }
+
+        let pdu = &self.port.frame(n)[1..];
+        if pdu[0] == function | EXCEPTION_FLAG && pdu.len() == 2 {
+            return Err(ModbusError::Exception(Exception::from_code(pdu[1])))
+        }
+        if pdu[0] != function || pdu.len() != expected {
+            return Err(ModbusError::BadResponse)
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(all(test, feature = "host-test"))]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::collections::VecDeque;
+
+    use crc::crc16_modbus;
+    use hal::{NbError, NbResult, SerialRead, SerialWrite};
+
+    use super::*;
+
+    /// Sixteen coils, ten holding registers, and four input registers;
+    /// discrete inputs are left unimplemented.
+    struct Map {
+        coils: [bool; 16],
+        holding: [u16; 10],
+    }
+
+    impl Map {
+        fn new() -> Map {
+            let mut holding = [0; 10];
+            for (i, r) in holding.iter_mut().enumerate() {
+                *r = 0x1000 + i as u16
+            }
+            let mut coils = [false; 16];
+            for &i in &[0, 2, 3, 9] {
+                coils[i] = true
+            }
+            Map { coils: coils, holding: holding }
+        }
+    }
+
+    impl RegisterMap for Map {
+        fn read_coil(&mut self, addr: u16) -> Result<bool, Exception> {
+            self.coils.get(addr as usize).cloned()
+                .ok_or(Exception::IllegalDataAddress)
+        }
+
+        fn write_coil(&mut self, addr: u16, value: bool)
+            -> Result<(), Exception> {
+            let c = try!(self.coils.get_mut(addr as usize)
+                         .ok_or(Exception::IllegalDataAddress));
+            *c = value;
+            Ok(())
+        }
+
+        fn read_holding_register(&mut self, addr: u16)
+            -> Result<u16, Exception> {
+            self.holding.get(addr as usize).cloned()
+                .ok_or(Exception::IllegalDataAddress)
+        }
+
+        fn write_holding_register(&mut self, addr: u16, value: u16)
+            -> Result<(), Exception> {
+            // Stands in for hardware refusing a value.
+            if value == 0xDEAD {
+                return Err(Exception::ServerDeviceFailure)
+            }
+            let r = try!(self.holding.get_mut(addr as usize)
+                         .ok_or(Exception::IllegalDataAddress));
+            *r = value;
+            Ok(())
+        }
+
+        fn read_input_register(&mut self, addr: u16)
+            -> Result<u16, Exception> {
+            if addr < 4 {
+                Ok(0x4000 + addr)
+            } else {
+                Err(Exception::IllegalDataAddress)
+            }
+        }
+    }
+
+    fn handle(map: &mut Map, request: &[u8]) -> Vec<u8> {
+        let mut out = [0; MAX_FRAME_LEN];
+        let n = handle_request(map, request, &mut out);
+        out[..n].to_vec()
+    }
+
+    fn with_crc(frame: &[u8]) -> Vec<u8> {
+        let crc = crc16_modbus(frame);
+        let mut v = frame.to_vec();
+        v.push(crc as u8);
+        v.push((crc >> 8) as u8);
+        v
+    }
+
+    #[test]
+    fn crc_matches_reference_frame() {
+        // Read ten holding registers from slave 1.
+        assert_eq!(0xCDC5,
+                   crc16_modbus(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x0A]));
+    }
+
+    #[test]
+    fn reads_registers() {
+        let mut map = Map::new();
+        assert_eq!(vec![0x11, 0x03, 0x04, 0x10, 0x01, 0x10, 0x02],
+                   handle(&mut map, &[0x11, 0x03, 0x00, 0x01, 0x00, 0x02]));
+        assert_eq!(vec![0x11, 0x04, 0x02, 0x40, 0x03],
+                   handle(&mut map, &[0x11, 0x04, 0x00, 0x03, 0x00, 0x01]));
+    }
+
+    #[test]
+    fn reads_coils_packed_lsb_first() {
+        let mut map = Map::new();
+        assert_eq!(vec![0x11, 0x01, 0x02, 0x0D, 0x02],
+                   handle(&mut map, &[0x11, 0x01, 0x00, 0x00, 0x00, 0x0A]));
+    }
+
+    #[test]
+    fn writes_echo_the_request() {
+        let mut map = Map::new();
+        let req = [0x11, 0x05, 0x00, 0x01, 0xFF, 0x00];
+        assert_eq!(req.to_vec(), handle(&mut map, &req));
+        assert!(map.coils[1]);
+
+        let req = [0x11, 0x06, 0x00, 0x04, 0xAB, 0xCD];
+        assert_eq!(req.to_vec(), handle(&mut map, &req));
+        assert_eq!(0xABCD, map.holding[4]);
+
+        let req = [0x11, 0x10, 0x00, 0x01, 0x00, 0x02, 0x04,
+                   0x00, 0x0A, 0x01, 0x02];
+        assert_eq!(req[..6].to_vec(), handle(&mut map, &req));
+        assert_eq!([0x000A, 0x0102], map.holding[1..3]);
+
+        let req = [0x11, 0x0F, 0x00, 0x00, 0x00, 0x0A, 0x02, 0xCD, 0x01];
+        assert_eq!(req[..6].to_vec(), handle(&mut map, &req));
+        assert_eq!([true, false, true, true, false, false, true, true,
+                    true, false],
+                   map.coils[..10]);
+    }
+
+    #[test]
+    fn exceptions() {
+        let cases: &[(&[u8], u8, u8)] = &[
+            // Unknown function.
+            (&[0x11, 0x07], 0x87, 1),
+            // Past the end of the map.
+            (&[0x11, 0x03, 0x00, 0x09, 0x00, 0x02], 0x83, 2),
+            (&[0x11, 0x06, 0x00, 0x0A, 0x00, 0x01], 0x86, 2),
+            // Discrete inputs aren't implemented.
+            (&[0x11, 0x02, 0x00, 0x00, 0x00, 0x01], 0x82, 2),
+            // Counts of zero, and too many.
+            (&[0x11, 0x03, 0x00, 0x00, 0x00, 0x00], 0x83, 3),
+            (&[0x11, 0x04, 0x00, 0x00, 0x00, 0x7C], 0x84, 3),
+            (&[0x11, 0x01, 0x00, 0x00, 0x07, 0xB1], 0x81, 3),
+            // Truncated.
+            (&[0x11, 0x03, 0x00, 0x00, 0x00], 0x83, 3),
+            (&[0x11, 0x10, 0x00, 0x00], 0x90, 3),
+            // A coil value other than on or off.
+            (&[0x11, 0x05, 0x00, 0x01, 0x12, 0x34], 0x85, 3),
+            // Byte count disagreeing with the register count, or with the
+            // data that follows.
+            (&[0x11, 0x10, 0x00, 0x00, 0x00, 0x01, 0x04, 0x00, 0x01],
+             0x90, 3),
+            (&[0x11, 0x10, 0x00, 0x00, 0x00, 0x01, 0x02, 0x00], 0x90, 3),
+            (&[0x11, 0x0F, 0x00, 0x00, 0x00, 0x09, 0x01, 0xFF], 0x8F, 3),
+            // Refused by the map.
+            (&[0x11, 0x06, 0x00, 0x00, 0xDE, 0xAD], 0x86, 4),
+        ];
+        for &(req, function, code) in cases {
+            let mut map = Map::new();
+            assert_eq!(vec![0x11, function, code], handle(&mut map, req),
+                       "{:?}", req);
+        }
+    }
+
+    /// Both ends of a serial line: bytes to be received, and bytes sent.
+    struct Line {
+        rx: RefCell<VecDeque<u8>>,
+        tx: RefCell<Vec<u8>>,
+    }
+
+    impl SerialRead for Line {
+        type Error = ();
+
+        fn try_read(&self) -> NbResult<u8, ()> {
+            self.rx.borrow_mut().pop_front().ok_or(NbError::WouldBlock)
+        }
+    }
+
+    impl SerialWrite for Line {
+        type Error = ();
+
+        fn try_write(&self, byte: u8) -> NbResult<(), ()> {
+            self.tx.borrow_mut().push(byte);
+            Ok(())
+        }
+
+        fn try_flush(&self) -> NbResult<(), ()> {
+            Ok(())
+        }
+    }
+
+    /// Counts the frames sent.
+    struct Driver(Cell<usize>);
+
+    impl DriverEnable for Driver {
+        fn begin_transmit(&self) {}
+
+        fn end_transmit(&self) {
+            self.0.set(self.0.get() + 1)
+        }
+    }
+
+    /// A gap that's always over, so that whatever has been received is a
+    /// frame.
+    struct NoGap;
+
+    impl GapTimer for NoGap {
+        fn start_gap(&self, _seconds: f32) {}
+
+        fn is_expired(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn slave_answers_framed_requests() {
+        let line = Line {
+            rx: RefCell::new(VecDeque::new()),
+            tx: RefCell::new(Vec::new()),
+        };
+        let de = Driver(Cell::new(0));
+        let gap = NoGap;
+        let mut slave = Slave::new(RtuPort::new(&line, &de, &gap, 19200),
+                                   0x01);
+        let mut map = Map::new();
+
+        // Read ten holding registers, as sent on the wire.
+        line.rx.borrow_mut().extend(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x0A,
+                                      0xC5, 0xCD]);
+        assert!(slave.poll(&mut map));
+        let mut expected = vec![0x01, 0x03, 20];
+        for r in &map.holding {
+            expected.push((r >> 8) as u8);
+            expected.push(*r as u8);
+        }
+        assert_eq!(with_crc(&expected), *line.tx.borrow());
+        assert_eq!(1, de.0.get());
+
+        // A damaged CRC is dropped unanswered.
+        line.tx.borrow_mut().clear();
+        line.rx.borrow_mut().extend(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x0A,
+                                      0xC5, 0xCE]);
+        assert!(!slave.poll(&mut map));
+        assert_eq!(1, slave.port().bad_frames());
+
+        // So are requests for other slaves.
+        line.rx.borrow_mut()
+            .extend(with_crc(&[0x02, 0x06, 0x00, 0x00, 0x00, 0x01]));
+        assert!(!slave.poll(&mut map));
+        assert_eq!(0x1000, map.holding[0]);
+
+        // Broadcasts are carried out, and not answered.
+        line.rx.borrow_mut()
+            .extend(with_crc(&[BROADCAST, 0x06, 0x00, 0x00, 0x00, 0x01]));
+        assert!(slave.poll(&mut map));
+        assert_eq!(1, map.holding[0]);
+
+        assert!(line.tx.borrow().is_empty());
+        assert_eq!(1, de.0.get());
+    }
+}
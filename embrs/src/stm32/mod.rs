@@ -0,0 +1,17 @@
+//! Peripheral definitions common to more than one STM32 family.
+//!
+//! ST reuses peripheral blocks across its families, sometimes unchanged.
+//! Where the register layouts and encodings match, they're defined here
+//! once, and the family modules (`stm32f1`, `stm32f4`) re-export them, so
+//! that neither family reaches into the other.
+
+pub mod rcc;
+pub mod usart;
+
+/// Checks the register block layouts of the shared peripherals (see
+/// `register_layout!`).
+#[cfg(all(test, feature = "host-test"))]
+#[test]
+fn check_layouts() {
+    usart::check_layout();
+}
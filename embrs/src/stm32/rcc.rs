@@ -0,0 +1,67 @@
+//! RCC definitions shared by the STM32F1 and STM32F4, whose bus prescaler
+//! and clock switch encodings match.
+
+bit_enums! {
+    /// Prescaler options for the APB clocks (relative to the AHB clock).
+    pub bit_enum ApbPrescaler {
+        Div2  = 0b00,
+        Div4  = 0b01,
+        Div8  = 0b10,
+        Div16 = 0b11,
+    }
+
+    /// Prescaler options for the AHB clocks (relative to the system clock).
+    pub bit_enum AhbPrescaler {
+        Div2   = 0b000,
+        Div4   = 0b001,
+        Div8   = 0b010,
+        Div16  = 0b011,
+        Div64  = 0b100,
+        Div128 = 0b101,
+        Div256 = 0b110,
+        Div512 = 0b111,
+    }
+
+    /// Clocks that can be used as the system clock source.
+    pub bit_enum ClockSwitch {
+        Hsi = 0b00,
+        Hse = 0b01,
+        Pll = 0b10,
+    }
+}
+
+pub trait ClockDivisor {
+    fn to_divisor(self) -> u32;
+}
+
+impl ClockDivisor for ApbPrescaler {
+    fn to_divisor(self) -> u32 {
+        match self {
+            ApbPrescaler::Div2  =>  2,
+            ApbPrescaler::Div4  =>  4,
+            ApbPrescaler::Div8  =>  8,
+            ApbPrescaler::Div16 => 16,
+        }
+    }
+}
+
+impl<T: ClockDivisor> ClockDivisor for Option<T> {
+    fn to_divisor(self) -> u32 {
+        self.map(|v| v.to_divisor()).unwrap_or(1)
+    }
+}
+
+impl ClockDivisor for AhbPrescaler {
+    fn to_divisor(self) -> u32 {
+        match self {
+            AhbPrescaler::Div2   =>   2,
+            AhbPrescaler::Div4   =>   4,
+            AhbPrescaler::Div8   =>   8,
+            AhbPrescaler::Div16  =>  16,
+            AhbPrescaler::Div64  =>  64,
+            AhbPrescaler::Div128 => 128,
+            AhbPrescaler::Div256 => 256,
+            AhbPrescaler::Div512 => 512,
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! General Purpose I/O (GPIO) support for the STM32F1.
+//!
+//! Unlike the F4, the F1 packs each pin's entire configuration into a single
+//! four-bit field (split across the `CRL` and `CRH` registers), and has no
+//! per-pin alternate function selection: peripherals claim their pins when
+//! enabled, subject to the AFIO remap registers.
+
+#![allow(trivial_numeric_casts)]  // required for bitflags :-(
+
+use arm_m::reg::{mmio, AtomicReg, Reg, RoReg, WoReg};
+use hal::{DigitalInput, DigitalOutput};
+
+/// A GPIO port's memory mapped registers.
+#[repr(C, packed)]
+pub struct GpioPort {
+    pub crl:  Reg<u32>,
+    pub crh:  Reg<u32>,
+    pub idr:  RoReg<u32>,
+    pub odr:  Reg<u32>,
+    pub bsrr: WoReg<u32>,
+    pub brr:  WoReg<u32>,
+    pub lckr: Reg<u32>,
+}
+
+register_layout! {
+    fn check_layout: GpioPort [0x1C] {
+        crl @ 0x00,
+        crh @ 0x04,
+        idr @ 0x08,
+        odr @ 0x0C,
+        bsrr @ 0x10,
+        brr @ 0x14,
+        lckr @ 0x18,
+    }
+}
+
+/// Maximum output speeds.  These control output slew rate.
+#[derive(Clone, Copy)]
+pub enum Speed {
+    Max10MHz = 0b01,
+    Max2MHz  = 0b10,
+    Max50MHz = 0b11,
+}
+
+/// Complete configuration of a pin.
+#[derive(Clone, Copy)]
+pub enum Config {
+    /// Analog mode for use with the ADC.
+    Analog,
+    /// High-impedance digital input.
+    Floating,
+    /// Digital input with internal pull-up resistor.
+    PullUp,
+    /// Digital input with internal pull-down resistor.
+    PullDown,
+    /// Digital output driven both high and low.
+    PushPull(Speed),
+    /// Digital output only driven low.
+    OpenDrain(Speed),
+    /// Peripheral output driven both high and low.
+    AltPushPull(Speed),
+    /// Peripheral output only driven low.
+    AltOpenDrain(Speed),
+}
+
+impl Config {
+    /// Encodes the configuration as the `CNF:MODE` field value.
+    fn to_bits(self) -> u32 {
+        match self {
+            Config::Analog => 0b0000,
+            Config::Floating => 0b0100,
+            Config::PullUp | Config::PullDown => 0b1000,
+            Config::PushPull(s) => 0b0000 | s as u32,
+            Config::OpenDrain(s) => 0b0100 | s as u32,
+            Config::AltPushPull(s) => 0b1000 | s as u32,
+            Config::AltOpenDrain(s) => 0b1100 | s as u32,
+        }
+    }
+}
+
+bitflags! {
+    /// Names a group of pins on a single GPIO port.
+    pub flags PinMask: u16 {
+        const P0 = 1 << 0,
+        const P1 = 1 << 1,
+        const P2 = 1 << 2,
+        const P3 = 1 << 3,
+        const P4 = 1 << 4,
+        const P5 = 1 << 5,
+        const P6 = 1 << 6,
+        const P7 = 1 << 7,
+        const P8 = 1 << 8,
+        const P9 = 1 << 9,
+        const P10 = 1 << 10,
+        const P11 = 1 << 11,
+        const P12 = 1 << 12,
+        const P13 = 1 << 13,
+        const P14 = 1 << 14,
+        const P15 = 1 << 15,
+    }
+}
+
+impl GpioPort {
+    /// Applies `config` to the pins selected by `pins`.
+    ///
+    /// The configuration registers can't be updated atomically as a whole, so
+    /// this is atomic with respect to other GPIO operations only for pins that
+    /// fall in the same half of the port.
+    pub fn set_config(&self, pins: PinMask, config: Config) {
+        // Same multiply trick as the F4's alternate function update: spread
+        // one bit per pin into the low bit of each four-bit field.
+        fn do_update(bits: u32, cfg: u32, reg: &Reg<u32>) {
+            let mut places = 0u32;
+            for i in 0..8 {
+                places |= (bits & (1 << i)) << (3 * i);
+            }
+            if places != 0 {
+                reg.atomic_nand_and_or(0b1111 * places, cfg * places)
+            }
+        }
+
+        let bits = pins.bits() as u32;
+        do_update(bits & 0xFF, config.to_bits(), &self.crl);
+        do_update((bits >> 8) & 0xFF, config.to_bits(), &self.crh);
+
+        // In input-with-pull mode, the output register selects the direction
+        // of the pull.
+        match config {
+            Config::PullUp => self.set(pins),
+            Config::PullDown => self.clear(pins),
+            _ => (),
+        }
+    }
+
+    /// Reads the state of pins selected by `pins`.  The returned `PinMask`
+    /// contains those pins that were observed as logic high.
+    #[inline]
+    pub fn get(&self, pins: PinMask) -> PinMask {
+        PinMask::from_bits_truncate(
+            (self.idr.get() as u16) & pins.bits())
+    }
+
+    /// Sets pins selected by `pins` to logic high.
+    #[inline]
+    pub fn set(&self, pins: PinMask) {
+        self.bsrr.set(pins.bits() as u32)
+    }
+
+    /// Clears pins selected by `pins` to logic low.
+    #[inline]
+    pub fn clear(&self, pins: PinMask) {
+        self.brr.set(pins.bits() as u32)
+    }
+}
+
+macro_rules! static_gpio {
+    ($name:ident, $addr:expr) => {
+        #[inline]
+        pub fn $name() -> &'static GpioPort {
+            unsafe { mmio($addr) }
+        }
+    };
+}
+
+static_gpio!(gpioa, 0x40010800);
+static_gpio!(gpiob, 0x40010c00);
+static_gpio!(gpioc, 0x40011000);
+static_gpio!(gpiod, 0x40011400);
+static_gpio!(gpioe, 0x40011800);
+
+/// Names a group of pins on a particular port, as in the STM32F4 GPIO driver.
+#[derive(Copy, Clone)]
+pub struct Pins {
+    /// Accessor for the GPIO port, e.g. `gpioc`.
+    pub port: fn() -> &'static GpioPort,
+    /// Pin(s) on `port`.
+    pub pins: PinMask,
+}
+
+impl Pins {
+    /// Applies `config` to the pins.
+    pub fn configure(&self, config: Config) {
+        (self.port)().set_config(self.pins, config)
+    }
+}
+
+impl DigitalOutput for Pins {
+    fn set_high(&self) {
+        (self.port)().set(self.pins)
+    }
+
+    fn set_low(&self) {
+        (self.port)().clear(self.pins)
+    }
+}
+
+impl DigitalInput for Pins {
+    fn is_high(&self) -> bool {
+        (self.port)().get(self.pins) == self.pins
+    }
+
+    fn is_low(&self) -> bool {
+        (self.port)().get(self.pins).is_empty()
+    }
+}
@@ -0,0 +1,22 @@
+//! Support for the STM32F1 series of SoCs (currently the STM32F103
+//! "performance line").
+//!
+//! Several ST peripheral blocks are shared with the STM32F4, and where their
+//! register layouts match, this module reuses the definitions in `stm32`
+//! rather than duplicating them.
+//!
+//! Not all F1 parts have every peripheral declared here; check the datasheet
+//! for the part in use.
+
+pub mod gpio;
+pub mod rcc;
+pub mod usart;
+
+/// Checks the register block layouts of the STM32F1 peripherals (see
+/// `register_layout!`).
+#[cfg(all(test, feature = "host-test"))]
+#[test]
+fn check_layouts() {
+    gpio::check_layout();
+    rcc::check_layout();
+}
@@ -0,0 +1,421 @@
+//! Reset and Clock Control (RCC) support for the STM32F1.
+//!
+//! The F1 RCC differs from the F4's mostly in its PLL, which has a single
+//! integer multiplier (2-16) applied to either HSI/2 or the HSE (optionally
+//! halved), and feeds both the system clock and -- through a fixed /1 or /1.5
+//! prescaler -- the USB peripheral.
+//!
+//! The bus prescaler and clock switch encodings match the F4's, so those types
+//! come from `stm32::rcc`.
+
+use arm_m::dwt::DWT;
+use arm_m::reg::{mmio, AtomicReg, Reg};
+
+pub use stm32::rcc::{AhbPrescaler, ApbPrescaler, ClockSwitch};
+
+use stm32::rcc::ClockDivisor;
+
+/// The RCC's hardware register layout.
+#[repr(C, packed)]
+struct Registers {
+    cr:        Reg<u32>,
+    cfgr:      Reg<u32>,
+    _cir:      Reg<u32>,
+    _apb2rstr: Reg<u32>,
+    _apb1rstr: Reg<u32>,
+    ahbenr:    Reg<u32>,
+    apb2enr:   Reg<u32>,
+    apb1enr:   Reg<u32>,
+    _bdcr:     Reg<u32>,
+    _csr:      Reg<u32>,
+}
+
+register_layout! {
+    fn check_layout: Registers [0x28] {
+        cr @ 0x00,
+        cfgr @ 0x04,
+        ahbenr @ 0x14,
+        apb2enr @ 0x18,
+        apb1enr @ 0x1C,
+    }
+}
+
+const RCC_ADDRESS : usize = 0x40021000;
+
+/// The Flash access control register.  The F1's Flash interface is otherwise
+/// unsupported, but its wait states must be set along with the clocks.
+const FLASH_ACR_ADDRESS : usize = 0x40022000;
+
+/// At startup, before the RCC has been reconfigured, the STM32F1 runs from its
+/// 8MHz HSI.
+pub const BOOT_CLOCK_HZ : u32 = 8_000_000;
+
+/// Upper bound on the time `configure_clocks` waits for the HSE to start, or
+/// for the PLL to lock, in CPU cycles at `BOOT_CLOCK_HZ`: 100ms.
+pub const STARTUP_TIMEOUT_CYCLES : u32 = BOOT_CLOCK_HZ / 10;
+
+bit_wrappers! {
+    /// Wrapper for the Clock Control Register bits.
+    pub struct Cr(pub u32);
+    /// Wrapper for the Clock Configuration Register bits.
+    pub struct Cfgr(pub u32);
+}
+
+impl Cr {
+    bitfield_accessors! {
+        /// Ready flag for the PLL.
+        pub total [25] get_pllrdy / with_pllrdy: bool,
+        /// Turns the PLL on/off.
+        pub total [24] get_pllon / with_pllon: bool,
+        /// Turns the Clock Security System (CSS) on/off.
+        pub total [19] get_csson / with_csson: bool,
+        /// When `true`, bypasses the HSE oscillator, using the external clock
+        /// signal directly.
+        pub total [18] get_hsebyp / with_hsebyp: bool,
+        /// Ready flag for the HSE oscillator.
+        pub total [17] get_hserdy / with_hserdy: bool,
+        /// Turns the HSE oscillator on/off.
+        pub total [16] get_hseon / with_hseon: bool,
+        /// Internal HSI calibration bits, set by hardware at startup.
+        pub total [15:8] get_hsical / with_hsical: u8,
+        /// HSI trim adjusts the frequency of the HSI oscillator.
+        pub total [7:3] get_hsitrim / with_hsitrim: u32,
+        /// Ready flag for the HSI oscillator.
+        pub total [1] get_hsirdy / with_hsirdy: bool,
+        /// Turns the HSI oscillator on/off.
+        pub total [0] get_hsion / with_hsion: bool,
+    }
+}
+
+impl Cfgr {
+    bitfield_accessors! {
+        /// Controls the clock output on the MCO pin.
+        pub       [26:24] get_mco / with_mco: Mco,
+        /// Selects the USB clock prescaler.
+        pub total [22]    get_usbpre / with_usbpre: UsbPrescaler,
+        /// Raw PLL multiplier field; see `get_pll_multiplier` and
+        /// `with_pll_multiplier`.
+        pub total [21:18] get_pllmul / with_pllmul: u32,
+        /// Divides the HSE by two before it reaches the PLL.
+        pub total [17]    get_pllxtpre / with_pllxtpre: bool,
+        /// Input clock for the PLL.
+        pub total [16]    get_pllsrc / with_pllsrc: PllSource,
+        /// Prescaler deriving the ADC clock from APB2.
+        pub total [15:14] get_adcpre / with_adcpre: AdcPrescaler,
+        /// Raw enable for the APB2 prescaler; see `get_ppre2` and `with_ppre2`.
+        pub total [13]    get_ppre2_en / with_ppre2_en: bool,
+        /// Raw divisor for the APB2 prescaler; see `get_ppre2` and
+        /// `with_ppre2`.
+        pub total [12:11] get_ppre2_div / with_ppre2_div: ApbPrescaler,
+        /// Raw enable for the APB1 prescaler; see `get_ppre1` and `with_ppre1`.
+        pub total [10]    get_ppre1_en / with_ppre1_en: bool,
+        /// Raw divisor for the APB1 prescaler; see `get_ppre1` and
+        /// `with_ppre1`.
+        pub total [ 9: 8] get_ppre1_div / with_ppre1_div: ApbPrescaler,
+        /// Raw enable for the AHB prescaler; see `get_hpre` and `with_hpre`.
+        pub total [ 7]    get_hpre_en / with_hpre_en: bool,
+        /// Raw divisor for the AHB prescaler; see `get_hpre` and `with_hpre`.
+        pub total [ 6: 4] get_hpre_div / with_hpre_div: AhbPrescaler,
+        /// Reads as the currently selected system clock source.
+        pub       [ 3: 2] get_sws / with_sws: ClockSwitch,
+        /// Selects the system clock source.  Selections written to `Cfgr` do
+        /// not take effect immediately; monitor by re-reading and checking
+        /// `get_sws`.
+        pub       [ 1: 0] get_sw / with_sw: ClockSwitch,
+    }
+
+    en_option_accessors! {
+        /// Selects the (optional) prescaler used to derive the APB2 clock from
+        /// the AHB clock.
+        enable get_ppre2_en / with_ppre2_en
+        value get_ppre2_div / with_ppre2_div : ApbPrescaler
+        as get_ppre2 / with_ppre2;
+
+        /// Selects the (optional) prescaler used to derive the APB1 clock from
+        /// the AHB clock.
+        enable get_ppre1_en / with_ppre1_en
+        value get_ppre1_div / with_ppre1_div : ApbPrescaler
+        as get_ppre1 / with_ppre1;
+
+        /// Selects the (optional) prescaler used to derive the AHB clock from
+        /// the system clock.
+        enable get_hpre_en / with_hpre_en
+        value get_hpre_div / with_hpre_div : AhbPrescaler
+        as get_hpre / with_hpre;
+    }
+
+    /// Gets the PLL multiplier, 2-16.
+    pub fn get_pll_multiplier(self) -> u32 {
+        // Field values 0b1110 and 0b1111 both mean x16.
+        let m = self.get_pllmul() + 2;
+        if m > 16 { 16 } else { m }
+    }
+
+    /// Sets the PLL multiplier, which must be in the range 2-16.
+    pub fn with_pll_multiplier(self, m: u32) -> Self {
+        assert!(m >= 2 && m <= 16);
+        self.with_pllmul(m - 2)
+    }
+}
+
+bit_enums! {
+    /// Clocks that can be output on the MCO pin.
+    pub bit_enum Mco {
+        None = 0b000,
+        Sysclk = 0b100,
+        Hsi = 0b101,
+        Hse = 0b110,
+        PllDiv2 = 0b111,
+    }
+
+    /// Options for the USB clock prescaler.
+    pub bit_enum UsbPrescaler {
+        /// USB clock is the PLL output divided by 1.5 (e.g. 72MHz to 48MHz).
+        Div1_5 = 0,
+        /// USB clock is the PLL output (which must be 48MHz).
+        Div1 = 1,
+    }
+
+    /// Options for the PLL source clock.
+    pub bit_enum PllSource {
+        /// The HSI, divided by two.
+        HsiDiv2 = 0,
+        /// The HSE, optionally divided by two (see `Cfgr::with_pllxtpre`).
+        Hse = 1,
+    }
+
+    /// Options for the ADC clock prescaler.
+    pub bit_enum AdcPrescaler {
+        Div2 = 0b00,
+        Div4 = 0b01,
+        Div6 = 0b10,
+        Div8 = 0b11,
+    }
+}
+
+/// A clock configuration using the HSE crystal oscillator and the PLL.
+pub struct ClockConfig {
+    /// Frequency of external crystal.  This is used to compute clock speeds,
+    /// but does not affect clock settings.
+    pub crystal_hz: f32,
+    /// Whether the crystal frequency is halved before reaching the PLL.
+    pub crystal_div2: bool,
+    /// PLL multiplier, 2-16.  The product must not exceed 72MHz.
+    pub pll_multiplier: u32,
+    /// Prescaler used to derive the USB clock, which must come out at 48MHz
+    /// if USB is used.
+    pub usb_prescaler: UsbPrescaler,
+
+    /// Optional divisor used to derive the AHB clock from the system clock.
+    pub ahb_divisor: Option<AhbPrescaler>,
+    /// Optional divisor used to derive the APB1 clock (36MHz max) from the
+    /// AHB clock.
+    pub apb1_divisor: Option<ApbPrescaler>,
+    /// Optional divisor used to derive the APB2 clock from the AHB clock.
+    pub apb2_divisor: Option<ApbPrescaler>,
+
+    /// Number of wait states desired for Flash accesses: 0 up to 24MHz, 1 up
+    /// to 48MHz, 2 beyond.
+    pub flash_latency: u32,
+}
+
+/// The internal clock speeds implied by a `ClockConfig`.
+#[derive(Copy, Clone)]
+pub struct ClockSpeeds {
+    pub cpu: f32,
+    pub ahb: f32,
+    pub apb1: f32,
+    pub apb2: f32,
+    pub usb: f32,
+}
+
+impl ClockConfig {
+    pub fn compute_speeds(&self) -> ClockSpeeds {
+        let pll_in_hz = if self.crystal_div2 {
+            self.crystal_hz / 2.
+        } else {
+            self.crystal_hz
+        };
+        let cpu = pll_in_hz * (self.pll_multiplier as f32);
+        let ahb = cpu / (self.ahb_divisor.to_divisor() as f32);
+        ClockSpeeds {
+            cpu: cpu,
+            ahb: ahb,
+            apb1: ahb / (self.apb1_divisor.to_divisor() as f32),
+            apb2: ahb / (self.apb2_divisor.to_divisor() as f32),
+            usb: match self.usb_prescaler {
+                UsbPrescaler::Div1_5 => cpu / 1.5,
+                UsbPrescaler::Div1 => cpu,
+            },
+        }
+    }
+}
+
+/// Reasons `configure_clocks` can fail.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum ClockError {
+    /// The HSE crystal oscillator never reported ready.
+    HseTimeout,
+    /// The PLL never reported lock.
+    PllTimeout,
+}
+
+/// Names the F1's peripherals, for the purposes of clock control.  Each value
+/// encodes the enable register and bit index.
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[repr(u32)]
+pub enum Peripheral {
+    // AHBENR
+    Dma1   = (0 << 8) | 0,
+    Sram   = (0 << 8) | 2,
+    Flitf  = (0 << 8) | 4,
+    Crc    = (0 << 8) | 6,
+
+    // APB2ENR
+    Afio   = (1 << 8) | 0,
+    GpioA  = (1 << 8) | 2,
+    GpioB  = (1 << 8) | 3,
+    GpioC  = (1 << 8) | 4,
+    GpioD  = (1 << 8) | 5,
+    GpioE  = (1 << 8) | 6,
+    Adc1   = (1 << 8) | 9,
+    Adc2   = (1 << 8) | 10,
+    Tim1   = (1 << 8) | 11,
+    Spi1   = (1 << 8) | 12,
+    Usart1 = (1 << 8) | 14,
+
+    // APB1ENR
+    Tim2   = (2 << 8) | 0,
+    Tim3   = (2 << 8) | 1,
+    Tim4   = (2 << 8) | 2,
+    Wwdg   = (2 << 8) | 11,
+    Spi2   = (2 << 8) | 14,
+    Usart2 = (2 << 8) | 17,
+    Usart3 = (2 << 8) | 18,
+    I2c1   = (2 << 8) | 21,
+    I2c2   = (2 << 8) | 22,
+    Usb    = (2 << 8) | 23,
+    Can1   = (2 << 8) | 25,
+    Bkp    = (2 << 8) | 27,
+    Pwr    = (2 << 8) | 28,
+}
+
+impl Peripheral {
+    /// Gets the clock speed for this peripheral, given the current speeds.
+    pub fn get_clock(self, speeds: &ClockSpeeds) -> f32 {
+        match (self as u32) >> 8 {
+            0 => speeds.ahb,
+            1 => speeds.apb2,
+            _ => speeds.apb1,
+        }
+    }
+}
+
+/// RCC driver.
+pub struct Rcc;
+
+impl Rcc {
+    fn reg(&self) -> &'static Registers {
+        unsafe { mmio(RCC_ADDRESS) }
+    }
+
+    fn flash_acr(&self) -> &'static Reg<u32> {
+        unsafe { mmio(FLASH_ACR_ADDRESS) }
+    }
+
+    /// Enables clock to peripheral `p`.
+    pub fn enable_clock(&self, p: Peripheral) {
+        let reg = match (p as u32) >> 8 {
+            0 => &self.reg().ahbenr,
+            1 => &self.reg().apb2enr,
+            _ => &self.reg().apb1enr,
+        };
+        reg.atomic_or(1 << ((p as u32) & 0x1F))
+    }
+
+    pub fn read_cr(&self) -> Cr {
+        Cr(self.reg().cr.get())
+    }
+
+    pub fn write_cr(&self, v: Cr) {
+        self.reg().cr.set(v.0)
+    }
+
+    pub fn update_cr<F: FnOnce(Cr) -> Cr>(&self, f: F) {
+        self.write_cr(f(self.read_cr()))
+    }
+
+    pub fn read_cfgr(&self) -> Cfgr {
+        Cfgr(self.reg().cfgr.get())
+    }
+
+    pub fn write_cfgr(&self, v: Cfgr) {
+        self.reg().cfgr.set(v.0)
+    }
+
+    pub fn update_cfgr<F: FnOnce(Cfgr) -> Cfgr>(&self, f: F) {
+        self.write_cfgr(f(self.read_cfgr()))
+    }
+
+    /// Reconfigures the RCC to the given `ClockConfig`, by way of the HSI,
+    /// and sets the Flash wait states to match.
+    ///
+    /// If the HSE fails to start, or the PLL fails to lock, within
+    /// `STARTUP_TIMEOUT_CYCLES`, the system is left running from the HSI with
+    /// the PLL and HSE off, and an error is returned.
+    pub fn configure_clocks(&self, cfg: &ClockConfig)
+        -> Result<(), ClockError> {
+        DWT.enable_cycle_counter();
+
+        // Switch to the HSI while messing with the PLL.
+        self.update_cr(|v| v.with_hsion(true));
+        while !self.read_cr().get_hsirdy() {}
+        self.update_cfgr(|v| v.with_sw(ClockSwitch::Hsi));
+        while self.read_cfgr().get_sws() != Ok(ClockSwitch::Hsi) {}
+
+        self.update_cr(|v| v.with_pllon(false));
+        while self.read_cr().get_pllrdy() {}
+
+        // Slow the buses and Flash before speeding up.
+        self.update_cfgr(|v| v.with_hpre(cfg.ahb_divisor)
+                         .with_ppre1(cfg.apb1_divisor)
+                         .with_ppre2(cfg.apb2_divisor)
+                         .with_usbpre(cfg.usb_prescaler));
+        self.flash_acr().atomic_nand_and_or(0b111, cfg.flash_latency & 0b111);
+
+        self.update_cr(|v| v.with_hseon(true));
+        if !self.wait_ready(|cr| cr.get_hserdy()) {
+            self.update_cr(|v| v.with_hseon(false));
+            return Err(ClockError::HseTimeout)
+        }
+
+        self.update_cfgr(|v| v.with_pllsrc(PllSource::Hse)
+                         .with_pllxtpre(cfg.crystal_div2)
+                         .with_pll_multiplier(cfg.pll_multiplier));
+
+        self.update_cr(|v| v.with_pllon(true));
+        if !self.wait_ready(|cr| cr.get_pllrdy()) {
+            self.update_cr(|v| v.with_pllon(false).with_hseon(false));
+            return Err(ClockError::PllTimeout)
+        }
+
+        self.update_cfgr(|v| v.with_sw(ClockSwitch::Pll));
+        while self.read_cfgr().get_sws() != Ok(ClockSwitch::Pll) {}
+        Ok(())
+    }
+
+    /// Waits up to `STARTUP_TIMEOUT_CYCLES` for `ready` to hold.
+    fn wait_ready<F: Fn(Cr) -> bool>(&self, ready: F) -> bool {
+        let start = DWT.read_cycle_count();
+        while !ready(self.read_cr()) {
+            let elapsed = DWT.read_cycle_count().wrapping_sub(start);
+            if elapsed > STARTUP_TIMEOUT_CYCLES {
+                return false
+            }
+        }
+        true
+    }
+}
+
+/// Shared instance of the `Rcc` driver.
+pub static RCC: Rcc = Rcc;
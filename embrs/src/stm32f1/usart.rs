@@ -0,0 +1,140 @@
+//! Universal Synchronous/Asychronous Receiver/Transmitter (USART) support for
+//! the STM32F1.
+//!
+//! The F1's USARTs are the same block as the F4's, minus 8x oversampling
+//! (`OVER8`) and one-bit sampling (`ONEBIT`), which are reserved here.  The
+//! register layout and bit wrappers come from `stm32::usart`.
+//!
+//! Pins are claimed by the USART when it's enabled; configure TX as
+//! `Config::AltPushPull` and RX as an input.
+
+use arm_m::reg::{mmio, Reg};
+use hal::{NbError, NbResult, SerialRead, SerialWrite};
+use super::rcc::{ClockSpeeds, Peripheral, RCC};
+
+pub use stm32::usart::{Brr, Cr1, Cr2, Cr3, Dr, Registers, SerialError, Sr};
+pub use stm32::usart::{Parity, StopBits, WordLength};
+
+pub struct Usart {
+    reg: *const Registers,
+    /// Name of this USART in the RCC.
+    peripheral: Peripheral,
+}
+
+macro_rules! reg_accessors {
+    ($reg:ident, $ty:ident, $read:ident, $write:ident, $update:ident) => {
+        pub fn $write(&self, v: $ty) {
+            self.reg().$reg.set(v.0)
+        }
+
+        pub fn $read(&self) -> $ty {
+            $ty(self.reg().$reg.get())
+        }
+
+        pub fn $update<F: FnOnce($ty) -> $ty>(&self, f: F) {
+            self.$write(f(self.$read()))
+        }
+    };
+}
+
+impl Usart {
+    fn reg(&self) -> &Registers {
+        unsafe { mmio(self.reg as usize) }
+    }
+
+    reg_accessors!(sr, Sr, read_sr, write_sr, update_sr);
+    reg_accessors!(dr, Dr, read_dr, write_dr, update_dr);
+    reg_accessors!(cr1, Cr1, read_cr1, write_cr1, update_cr1);
+    reg_accessors!(cr2, Cr2, read_cr2, write_cr2, update_cr2);
+    reg_accessors!(cr3, Cr3, read_cr3, write_cr3, update_cr3);
+    reg_accessors!(brr, Brr, read_brr, write_brr, update_brr);
+
+    /// Enables this USART's clock in the RCC.
+    pub fn enable_clock(&self) {
+        RCC.enable_clock(self.peripheral)
+    }
+
+    /// Sets the baud rate, rounding to the nearest achievable rate (16x
+    /// oversampling is the only option on the F1).
+    pub fn set_baud(&self, speeds: &ClockSpeeds, baud: u32) {
+        let clock = self.peripheral.get_clock(speeds) as u32;
+        // USARTDIV in 12.4 fixed point is simply clock / baud.
+        let div = (clock + baud / 2) / baud;
+        self.write_brr(Brr::default()
+                       .with_mantissa(div >> 4)
+                       .with_fraction(div & 0xF))
+    }
+
+    pub fn send8(&self, v: u8) {
+        self.write_dr(Dr::default().with_data(v))
+    }
+
+    pub fn recv8(&self) -> u8 {
+        self.read_dr().get_data()
+    }
+}
+
+impl SerialWrite for Usart {
+    type Error = SerialError;
+
+    fn try_write(&self, byte: u8) -> NbResult<(), SerialError> {
+        if self.read_sr().get_txe() {
+            self.send8(byte);
+            Ok(())
+        } else {
+            Err(NbError::WouldBlock)
+        }
+    }
+
+    fn try_flush(&self) -> NbResult<(), SerialError> {
+        if self.read_sr().get_tc() {
+            Ok(())
+        } else {
+            Err(NbError::WouldBlock)
+        }
+    }
+}
+
+impl SerialRead for Usart {
+    type Error = SerialError;
+
+    fn try_read(&self) -> NbResult<u8, SerialError> {
+        let sr = self.read_sr();
+        let err = if sr.get_ore() {
+            Some(SerialError::Overrun)
+        } else if sr.get_fe() {
+            Some(SerialError::Framing)
+        } else if sr.get_nf() {
+            Some(SerialError::Noise)
+        } else if sr.get_pe() {
+            Some(SerialError::Parity)
+        } else {
+            None
+        };
+
+        match err {
+            Some(e) => {
+                // Reading DR after SR clears the error flags.
+                let _ = self.recv8();
+                Err(NbError::Other(e))
+            },
+            None if sr.get_rxne() => Ok(self.recv8()),
+            None => Err(NbError::WouldBlock),
+        }
+    }
+}
+
+unsafe impl Sync for Usart {}
+
+macro_rules! static_usart {
+    ($name:ident, $addr:expr, $periph:ident) => {
+        pub static $name: Usart = Usart {
+            reg: $addr as *const Registers,
+            peripheral: Peripheral::$periph,
+        };
+    };
+}
+
+static_usart!(USART1, 0x40013800, Usart1);
+static_usart!(USART2, 0x40004400, Usart2);
+static_usart!(USART3, 0x40004800, Usart3);
@@ -0,0 +1,544 @@
+//! Analog-to-Digital Converter (ADC) support.
+//!
+//! This provides the register layer for the three ADCs and their common
+//! control block, a simple blocking single-conversion helper, a threshold
+//! alarm facility built on the analog watchdog, and calibrated readings of
+//! the internal channels: the temperature sensor, VDDA, and VBAT.
+
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use arm_m::reg::{mmio, Reg, RoReg};
+
+
+/*******************************************************************************
+ * Peripheral register layouts.
+ */
+
+/// Register layout of a single ADC.
+#[repr(C, packed)]
+pub struct Adc {
+    /// Status register.
+    pub sr:    Reg<Sr>,
+    /// Control register 1.
+    pub cr1:   Reg<Cr1>,
+    /// Control register 2.
+    pub cr2:   Reg<Cr2>,
+    /// Sample time registers SMPR1 and SMPR2, in that order.  SMPR1 covers
+    /// channels 10-18, SMPR2 channels 0-9.
+    pub smpr:  [Reg<u32>; 2],
+    /// Injected channel data offset registers JOFR1-JOFR4.
+    pub jofr:  [Reg<u32>; 4],
+    /// Analog watchdog high threshold register.
+    pub htr:   Reg<Tr>,
+    /// Analog watchdog low threshold register.
+    pub ltr:   Reg<Tr>,
+    /// Regular sequence registers SQR1-SQR3, in that order.
+    pub sqr:   [Reg<u32>; 3],
+    /// Injected sequence register.
+    pub jsqr:  Reg<u32>,
+    /// Injected data registers JDR1-JDR4.
+    pub jdr:   [Reg<u32>; 4],
+    /// Regular data register.
+    pub dr:    Reg<u32>,
+}
+
+register_layout! {
+    fn check_adc_layout: Adc [0x50] {
+        sr @ 0x00,
+        cr1 @ 0x04,
+        cr2 @ 0x08,
+        smpr @ 0x0C,
+        jofr @ 0x14,
+        htr @ 0x24,
+        ltr @ 0x28,
+        sqr @ 0x2C,
+        jsqr @ 0x38,
+        jdr @ 0x3C,
+        dr @ 0x4C,
+    }
+}
+
+/// Register layout of the ADC common control block.
+#[repr(C, packed)]
+pub struct AdcCommon {
+    /// Common status register, mirroring the status bits of all three ADCs.
+    pub csr:   Reg<u32>,
+    /// Common control register.
+    pub ccr:   Reg<Ccr>,
+    /// Common regular data register for dual and triple modes.
+    pub cdr:   Reg<u32>,
+}
+
+register_layout! {
+    fn check_common_layout: AdcCommon [0x0C] {
+        csr @ 0x00,
+        ccr @ 0x04,
+        cdr @ 0x08,
+    }
+}
+
+/// Produces a shared reference to ADC1.
+#[inline]
+pub fn adc1() -> &'static Adc {
+    unsafe {
+        mmio(0x40012000)
+    }
+}
+
+/// Produces a shared reference to ADC2.
+#[inline]
+pub fn adc2() -> &'static Adc {
+    unsafe {
+        mmio(0x40012100)
+    }
+}
+
+/// Produces a shared reference to ADC3.
+#[inline]
+pub fn adc3() -> &'static Adc {
+    unsafe {
+        mmio(0x40012200)
+    }
+}
+
+/// Produces a shared reference to the ADC common control block.
+#[inline]
+pub fn adc_common() -> &'static AdcCommon {
+    unsafe {
+        mmio(0x40012300)
+    }
+}
+
+/// Names the three ADCs.
+#[derive(Eq, PartialEq, Copy, Clone)]
+pub enum AdcIndex {
+    Adc1, Adc2, Adc3,
+}
+
+impl AdcIndex {
+    /// Produces a shared reference to the named ADC's registers.
+    pub fn get(self) -> &'static Adc {
+        match self {
+            AdcIndex::Adc1 => adc1(),
+            AdcIndex::Adc2 => adc2(),
+            AdcIndex::Adc3 => adc3(),
+        }
+    }
+}
+
+/// Internal channel connected to the temperature sensor (ADC1 only).
+#[cfg(not(feature = "soc_family:stm32f4[23]"))]
+pub const CHANNEL_TEMPERATURE : u32 = 16;
+/// Internal channel connected to the temperature sensor (ADC1 only).  On
+/// these parts it's shared with VBAT, and reads the sensor only while
+/// `Ccr::get_vbate` is clear.
+#[cfg(feature = "soc_family:stm32f4[23]")]
+pub const CHANNEL_TEMPERATURE : u32 = 18;
+/// Internal channel connected to the internal reference voltage (ADC1 only).
+pub const CHANNEL_VREFINT : u32 = 17;
+/// Internal channel connected to VBAT, through a divider of `VBAT_DIVIDER`
+/// (ADC1 only).
+pub const CHANNEL_VBAT : u32 = 18;
+
+/// Ratio of VBAT to the voltage seen on `CHANNEL_VBAT`.
+#[cfg(not(feature = "soc_family:stm32f4[23]"))]
+pub const VBAT_DIVIDER : u32 = 2;
+/// Ratio of VBAT to the voltage seen on `CHANNEL_VBAT`.
+#[cfg(feature = "soc_family:stm32f4[23]")]
+pub const VBAT_DIVIDER : u32 = 4;
+
+/// Largest value produced by a 12-bit conversion.
+pub const FULL_SCALE : u32 = 4095;
+
+
+/*******************************************************************************
+ * Register types.
+ */
+
+bit_wrappers! {
+    /// Status register type.
+    pub struct Sr(pub u32);
+    /// Control register 1 type.
+    pub struct Cr1(pub u32);
+    /// Control register 2 type.
+    pub struct Cr2(pub u32);
+    /// Watchdog threshold register type, used for both HTR and LTR.
+    pub struct Tr(pub u32);
+    /// Common control register type.
+    pub struct Ccr(pub u32);
+}
+
+impl Sr {
+    bitfield_accessors! {
+        /// Overrun.
+        pub total [5] get_ovr / with_ovr: bool,
+        /// Regular channel conversion started.
+        pub total [4] get_strt / with_strt: bool,
+        /// Injected channel conversion started.
+        pub total [3] get_jstrt / with_jstrt: bool,
+        /// Injected channel end of conversion.
+        pub total [2] get_jeoc / with_jeoc: bool,
+        /// Regular channel end of conversion.
+        pub total [1] get_eoc / with_eoc: bool,
+        /// Analog watchdog event.
+        pub total [0] get_awd / with_awd: bool,
+    }
+}
+
+impl Cr1 {
+    bitfield_accessors! {
+        /// Enables the overrun interrupt.
+        pub total [26] get_ovrie / with_ovrie: bool,
+        /// Conversion resolution.
+        pub total [25:24] get_res / with_res: Resolution,
+        /// Enables the analog watchdog on regular channels.
+        pub total [23] get_awden / with_awden: bool,
+        /// Enables the analog watchdog on injected channels.
+        pub total [22] get_jawden / with_jawden: bool,
+        /// Number of channels converted per discontinuous-mode trigger, minus
+        /// one.
+        pub total [15:13] get_discnum / with_discnum: u32,
+        /// Enables discontinuous mode on injected channels.
+        pub total [12] get_jdiscen / with_jdiscen: bool,
+        /// Enables discontinuous mode on regular channels.
+        pub total [11] get_discen / with_discen: bool,
+        /// Enables automatic injected group conversion.
+        pub total [10] get_jauto / with_jauto: bool,
+        /// Restricts the analog watchdog to the channel selected by `AWDCH`.
+        pub total [9] get_awdsgl / with_awdsgl: bool,
+        /// Enables scan mode.
+        pub total [8] get_scan / with_scan: bool,
+        /// Enables the injected end-of-conversion interrupt.
+        pub total [7] get_jeocie / with_jeocie: bool,
+        /// Enables the analog watchdog interrupt.
+        pub total [6] get_awdie / with_awdie: bool,
+        /// Enables the regular end-of-conversion interrupt.
+        pub total [5] get_eocie / with_eocie: bool,
+        /// Channel watched by the analog watchdog when `AWDSGL` is set.
+        pub total [4:0] get_awdch / with_awdch: u32,
+    }
+}
+
+impl Cr2 {
+    bitfield_accessors! {
+        /// Starts conversion of regular channels.
+        pub total [30] get_swstart / with_swstart: bool,
+        /// External trigger enable for regular channels.
+        pub total [29:28] get_exten / with_exten: TriggerEdge,
+        /// External trigger selection for regular channels.
+        pub total [27:24] get_extsel / with_extsel: u32,
+        /// Starts conversion of injected channels.
+        pub total [22] get_jswstart / with_jswstart: bool,
+        /// External trigger enable for injected channels.
+        pub total [21:20] get_jexten / with_jexten: TriggerEdge,
+        /// External trigger selection for injected channels.
+        pub total [19:16] get_jextsel / with_jextsel: u32,
+        /// Data alignment.
+        pub total [11] get_align / with_align: Align,
+        /// End-of-conversion selection: set to flag EOC after each conversion
+        /// rather than each sequence.
+        pub total [10] get_eocs / with_eocs: bool,
+        /// Keeps issuing DMA requests after the last transfer.
+        pub total [9] get_dds / with_dds: bool,
+        /// Enables DMA.
+        pub total [8] get_dma / with_dma: bool,
+        /// Enables continuous conversion.
+        pub total [1] get_cont / with_cont: bool,
+        /// Powers the ADC on.
+        pub total [0] get_adon / with_adon: bool,
+    }
+}
+
+impl Tr {
+    bitfield_accessors! {
+        /// Threshold, compared against the 12-bit conversion result.
+        pub total [11:0] get_threshold / with_threshold: u32,
+    }
+}
+
+impl Ccr {
+    bitfield_accessors! {
+        /// Enables the temperature sensor and VREFINT channels.
+        pub total [23] get_tsvrefe / with_tsvrefe: bool,
+        /// Enables the VBAT channel.
+        pub total [22] get_vbate / with_vbate: bool,
+        /// Prescaler deriving the ADC clock from APB2.
+        pub total [17:16] get_adcpre / with_adcpre: AdcPrescaler,
+        /// Multi-ADC mode selection; zero for independent mode.
+        pub total [4:0] get_multi / with_multi: u32,
+    }
+}
+
+bit_enums! {
+    /// Conversion resolutions.
+    pub bit_enum Resolution {
+        Bits12 = 0b00,
+        Bits10 = 0b01,
+        Bits8 = 0b10,
+        Bits6 = 0b11,
+    }
+
+    /// External trigger edge selection.
+    pub bit_enum TriggerEdge {
+        Disabled = 0b00,
+        Rising = 0b01,
+        Falling = 0b10,
+        Both = 0b11,
+    }
+
+    /// Data alignment options.
+    pub bit_enum Align {
+        Right = 0,
+        Left = 1,
+    }
+
+    /// Prescaler options for the ADC clock, relative to APB2.
+    pub bit_enum AdcPrescaler {
+        Div2 = 0b00,
+        Div4 = 0b01,
+        Div6 = 0b10,
+        Div8 = 0b11,
+    }
+
+    /// Sampling times, in ADC clock cycles.
+    pub bit_enum SampleTime {
+        Cycles3 = 0b000,
+        Cycles15 = 0b001,
+        Cycles28 = 0b010,
+        Cycles56 = 0b011,
+        Cycles84 = 0b100,
+        Cycles112 = 0b101,
+        Cycles144 = 0b110,
+        Cycles480 = 0b111,
+    }
+}
+
+
+/*******************************************************************************
+ * ADC supplementary operations.
+ */
+
+/// The SR flags, all cleared by writing zero; the other bits are reserved.
+const SR_FLAGS : u32 = 0x3F;
+
+impl Adc {
+    /// Clears the status flags set in `flags`.  The flags are
+    /// clear-on-write-zero, so this doesn't disturb flags set by the hardware
+    /// concurrently, as a read-modify-write would.
+    pub fn clear_sr(&self, flags: Sr) {
+        self.sr.set(Sr(SR_FLAGS & !flags.0))
+    }
+
+    /// Sets the sampling time for `channel` (0-18).
+    pub fn set_sample_time(&self, channel: u32, t: SampleTime) {
+        let (reg, shift) = if channel >= 10 {
+            (&self.smpr[0], (channel - 10) * 3)
+        } else {
+            (&self.smpr[1], channel * 3)
+        };
+        reg.update(|v| (v & !(0b111 << shift)) | ((t as u32) << shift))
+    }
+
+    /// Powers the ADC on, if it isn't already.  Applications should wait for
+    /// the stabilization time (a few microseconds) before relying on results.
+    pub fn power_on(&self) {
+        self.cr2.update(|v| v.with_adon(true))
+    }
+
+    /// Performs a single conversion of `channel` (0-18) and waits for the
+    /// result.
+    ///
+    /// This replaces the regular sequence with just `channel`, and turns off
+    /// scan and continuous modes, so don't use it on an ADC that's part of a
+    /// running conversion pipeline.
+    pub fn convert_blocking(&self, channel: u32, t: SampleTime) -> u16 {
+        self.set_sample_time(channel, t);
+        // Sequence length 1 (L = 0), SQ1 = channel.
+        self.sqr[0].update(|v| v & !(0xF << 20));
+        self.sqr[2].update(|v| (v & !0x1F) | channel);
+        self.cr1.update(|v| v.with_scan(false));
+        self.cr2.update(|v| v.with_cont(false).with_eocs(true));
+
+        self.clear_sr(Sr::default().with_eoc(true));
+        self.cr2.update(|v| v.with_swstart(true));
+        while !self.sr.get().get_eoc() {}
+
+        self.dr.get() as u16
+    }
+}
+
+
+/*******************************************************************************
+ * Internal channels and calibration.
+ */
+
+/// Address of the factory VREFINT calibration value.
+const VREFINT_CAL_ADDRESS : usize = 0x1fff7a2a;
+
+/// VDDA at which VREFINT_CAL was measured, in millivolts.
+const VREFINT_CAL_VDDA_MV : u32 = 3300;
+
+/// Reads the factory calibration value: the raw 12-bit conversion of VREFINT
+/// with VDDA at 3.3V and 30C.
+pub fn read_vrefint_cal() -> u16 {
+    let cal: &RoReg<u16> = unsafe { mmio(VREFINT_CAL_ADDRESS) };
+    cal.get()
+}
+
+/// Addresses of the factory temperature sensor calibration values: raw
+/// conversions at `TS_CAL1_CENTI_C` and `TS_CAL2_CENTI_C`, with VDDA at
+/// `VREFINT_CAL_VDDA_MV`.
+const TS_CAL1_ADDRESS : usize = 0x1fff7a2c;
+const TS_CAL2_ADDRESS : usize = 0x1fff7a2e;
+
+/// Temperatures at which the sensor was calibrated, in hundredths of a
+/// degree Celsius.
+const TS_CAL1_CENTI_C : i32 = 30_00;
+const TS_CAL2_CENTI_C : i32 = 110_00;
+
+/// Reads the factory temperature sensor calibration values, at 30C and 110C.
+pub fn read_ts_cal() -> (u16, u16) {
+    let cal1: &RoReg<u16> = unsafe { mmio(TS_CAL1_ADDRESS) };
+    let cal2: &RoReg<u16> = unsafe { mmio(TS_CAL2_ADDRESS) };
+    (cal1.get(), cal2.get())
+}
+
+/// Measures the actual analog supply voltage VDDA, in millivolts, by
+/// converting VREFINT on ADC1 and comparing against the factory calibration.
+///
+/// This enables the internal channels, and clobbers ADC1's regular sequence
+/// (see `Adc::convert_blocking`).  ADC1 must be clocked and powered on.
+pub fn read_vdda() -> u32 {
+    adc_common().ccr.update(|v| v.with_tsvrefe(true));
+    let raw = adc1().convert_blocking(CHANNEL_VREFINT, SampleTime::Cycles480);
+    if raw == 0 {
+        return 0
+    }
+    VREFINT_CAL_VDDA_MV * (read_vrefint_cal() as u32) / (raw as u32)
+}
+
+/// Measures the die temperature, in hundredths of a degree Celsius, using
+/// the factory calibration.  VDDA is measured first (see `read_vdda`), so
+/// the reading doesn't depend on the supply.
+///
+/// The sensor is good for spotting changes; its absolute accuracy, even
+/// calibrated, is a few degrees.  Requirements are as for `read_vdda`.
+pub fn read_temperature() -> i32 {
+    let vdda = read_vdda() as i32;
+    // Where VBAT shares the sensor's channel, it has to be off.
+    adc_common().ccr.update(|v| v.with_vbate(false).with_tsvrefe(true));
+    let raw = adc1().convert_blocking(CHANNEL_TEMPERATURE,
+                                      SampleTime::Cycles480) as i32;
+    let (cal1, cal2) = read_ts_cal();
+    let (cal1, cal2) = (cal1 as i32, cal2 as i32);
+    if cal2 == cal1 {
+        return 0
+    }
+    // Scale the reading to the calibration supply, then interpolate.
+    let raw = raw * vdda / VREFINT_CAL_VDDA_MV as i32;
+    TS_CAL1_CENTI_C
+        + (raw - cal1) * (TS_CAL2_CENTI_C - TS_CAL1_CENTI_C) / (cal2 - cal1)
+}
+
+/// Measures the backup supply voltage VBAT, in millivolts.  The VBAT channel
+/// is turned off again afterwards, since its divider draws current from the
+/// battery.  Requirements are as for `read_vdda`.
+pub fn read_vbat() -> u32 {
+    let vdda = read_vdda();
+    adc_common().ccr.update(|v| v.with_vbate(true));
+    let raw = adc1().convert_blocking(CHANNEL_VBAT, SampleTime::Cycles480);
+    adc_common().ccr.update(|v| v.with_vbate(false));
+    counts_to_mv(raw as u32, vdda) * VBAT_DIVIDER
+}
+
+/// Converts a voltage in millivolts to 12-bit conversion counts, given the
+/// analog supply voltage `vdda_mv`.  Saturates at full scale.
+pub fn mv_to_counts(mv: u32, vdda_mv: u32) -> u32 {
+    if mv >= vdda_mv {
+        FULL_SCALE
+    } else {
+        (mv * FULL_SCALE + vdda_mv / 2) / vdda_mv
+    }
+}
+
+/// Converts 12-bit conversion counts to millivolts, given the analog supply
+/// voltage `vdda_mv`.
+pub fn counts_to_mv(counts: u32, vdda_mv: u32) -> u32 {
+    (counts * vdda_mv + FULL_SCALE / 2) / FULL_SCALE
+}
+
+
+/*******************************************************************************
+ * Threshold alarms.
+ */
+
+/// Function called when a threshold alarm fires, with the ADC that raised it
+/// and the conversion result that crossed the threshold.
+pub type AlarmHandler = fn(AdcIndex, u16);
+
+/// Registered alarm handlers for ADC1-3, stored as addresses (or zero).
+static ALARM_HANDLERS : [AtomicUsize; 3] = [
+    ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT,
+];
+
+impl AdcIndex {
+    /// Arms a one-shot threshold alarm: `handler` is called from
+    /// `adc_alarm_isr` the first time a regular conversion of `channel` lands
+    /// outside `low_mv..=high_mv`, after which the alarm is disarmed.
+    ///
+    /// Millivolts are converted to counts using `vdda_mv` (see
+    /// `read_vdda`).
+    ///
+    /// This touches only the analog watchdog settings.  The alarm can only
+    /// fire when `channel` is actually being converted, so something else must
+    /// be running a conversion sequence that includes it.  The application
+    /// must also enable the `Interrupt::Adc` IRQ and install `adc_alarm_isr`.
+    pub fn arm_threshold_alarm(self, channel: u32,
+                               low_mv: u32, high_mv: u32, vdda_mv: u32,
+                               handler: AlarmHandler) {
+        let adc = self.get();
+        ALARM_HANDLERS[self as usize].store(handler as usize, Ordering::Release);
+
+        adc.ltr.set(Tr::default()
+                    .with_threshold(mv_to_counts(low_mv, vdda_mv)));
+        adc.htr.set(Tr::default()
+                    .with_threshold(mv_to_counts(high_mv, vdda_mv)));
+        adc.clear_sr(Sr::default().with_awd(true));
+        adc.cr1.update(|v| v.with_awdch(channel)
+                       .with_awdsgl(true)
+                       .with_awden(true)
+                       .with_awdie(true));
+    }
+
+    /// Disarms this ADC's threshold alarm.
+    pub fn disarm_threshold_alarm(self) {
+        self.get().cr1.update(|v| v.with_awden(false).with_awdie(false));
+        ALARM_HANDLERS[self as usize].store(0, Ordering::Release);
+    }
+}
+
+/// Interrupt handler for threshold alarms.  Install this as the ADC vector
+/// (`InterruptTable::adc`); it checks all three ADCs, since they share the
+/// interrupt.
+pub extern "C" fn adc_alarm_isr() {
+    for &i in [AdcIndex::Adc1, AdcIndex::Adc2, AdcIndex::Adc3].iter() {
+        let adc = i.get();
+        let cr1 = adc.cr1.get();
+        if !(cr1.get_awdie() && adc.sr.get().get_awd()) {
+            continue
+        }
+
+        let value = adc.dr.get() as u16;
+        adc.cr1.set(cr1.with_awden(false).with_awdie(false));
+        adc.clear_sr(Sr::default().with_awd(true));
+
+        let h = ALARM_HANDLERS[i as usize].swap(0, Ordering::AcqRel);
+        if h != 0 {
+            let handler: AlarmHandler = unsafe { mem::transmute(h) };
+            handler(i, value)
+        }
+    }
+}
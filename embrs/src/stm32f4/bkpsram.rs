@@ -0,0 +1,93 @@
+//! Backup SRAM support.
+//!
+//! The STM32F4 has 4KiB of SRAM in the backup domain, which keeps its
+//! contents through standby and, with the backup regulator on, while only
+//! VBAT is powered.  It's small, but handy for settings or logs that must
+//! outlive a reset, and as a `BlockDevice` it makes a tiny (eight block) disk.
+//!
+//! Before use, enable the `Pwr` and `BackupSram` clocks and call
+//! `BackupSram::enable`.
+
+use core::ptr;
+
+use hal::{BlockDevice, BLOCK_SIZE};
+use super::pwr::PWR;
+
+const BKPSRAM_ADDRESS : usize = 0x40024000;
+
+/// Size of the backup SRAM, in bytes.
+pub const BKPSRAM_SIZE : usize = 4096;
+
+/// Error from accessing a range outside the backup SRAM.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct OutOfRange;
+
+/// Backup SRAM driver.
+pub struct BackupSram;
+
+impl BackupSram {
+    /// Allows writes to the backup domain and, if `retain_on_vbat`, turns on
+    /// the backup regulator.
+    pub fn enable(&self, retain_on_vbat: bool) {
+        PWR.set_backup_access(true);
+        if retain_on_vbat {
+            PWR.enable_backup_regulator()
+        }
+    }
+
+    fn check(&self, offset: usize, len: usize) -> Result<(), OutOfRange> {
+        if offset > BKPSRAM_SIZE || len > BKPSRAM_SIZE - offset {
+            Err(OutOfRange)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Copies backup SRAM from `offset` into `buf`.
+    pub fn read(&self, offset: usize, buf: &mut [u8])
+        -> Result<(), OutOfRange> {
+        try!(self.check(offset, buf.len()));
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = unsafe {
+                ptr::read_volatile((BKPSRAM_ADDRESS + offset + i) as *const u8)
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies `data` into backup SRAM at `offset`.
+    pub fn write(&self, offset: usize, data: &[u8]) -> Result<(), OutOfRange> {
+        try!(self.check(offset, data.len()));
+        for (i, &b) in data.iter().enumerate() {
+            unsafe {
+                ptr::write_volatile((BKPSRAM_ADDRESS + offset + i) as *mut u8,
+                                    b)
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for BackupSram {
+    type Error = OutOfRange;
+
+    fn block_count(&self) -> u32 {
+        (BKPSRAM_SIZE / BLOCK_SIZE) as u32
+    }
+
+    fn read_blocks(&self, block: u32, buf: &mut [u8])
+        -> Result<(), OutOfRange> {
+        self.read(block as usize * BLOCK_SIZE, buf)
+    }
+
+    fn write_blocks(&self, block: u32, data: &[u8]) -> Result<(), OutOfRange> {
+        self.write(block as usize * BLOCK_SIZE, data)
+    }
+
+    fn erase_blocks(&self, block: u32, count: u32) -> Result<(), OutOfRange> {
+        self.check(block as usize * BLOCK_SIZE, count as usize * BLOCK_SIZE)
+    }
+}
+
+/// Shared instance of the `BackupSram` driver.
+pub static BKPSRAM: BackupSram = BackupSram;
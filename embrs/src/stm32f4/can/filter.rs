@@ -0,0 +1,302 @@
+//! Receive filter banks.
+//!
+//! The two CAN controllers share 28 filter banks, which live in CAN1's
+//! register block.  Banks below the split point (`CAN2SB`) belong to CAN1,
+//! the rest to CAN2.  Each bank holds, depending on its mode, one to four
+//! filters: a 32-bit mask, two 32-bit identifiers, two 16-bit masks, or four
+//! 16-bit identifiers.  A frame passing any filter lands in that filter's
+//! receive FIFO, tagged with its *filter match index*, which counts filters
+//! in bank order, separately for each FIFO.
+//!
+//! `FilterBuilder` hides all of that.  Describe what to accept, and it picks
+//! bank modes, packs filters into banks, sets the split, and reports each
+//! filter's match index:
+//!
+//!     let mut filters = FilterBuilder::new();
+//!     let status = try!(filters.add(CanIndex::Can1, Fifo::Fifo0,
+//!                                   Filter::Exact(status_id.into())));
+//!     let _ = try!(filters.add(CanIndex::Can2, Fifo::Fifo1, Filter::All));
+//!     filters.apply();
+//!
+//! The filter registers are only reachable through CAN1, so its clock must
+//! be enabled even if only CAN2 is in use.
+
+use arm_m::reg::{mmio, Reg, ReservedReg};
+use super::{CanIndex, Fifo};
+use super::frame::{ExtendedId, Id, StandardId};
+
+#[repr(C, packed)]
+struct Registers {
+    fmr:        Reg<u32>,
+    fm1r:       Reg<u32>,
+    _reserved0: ReservedReg,
+    fs1r:       Reg<u32>,
+    _reserved1: ReservedReg,
+    ffa1r:      Reg<u32>,
+    _reserved2: ReservedReg,
+    fa1r:       Reg<u32>,
+    _reserved3: [ReservedReg; 8],
+    bank:       [[Reg<u32>; 2]; BANK_COUNT],
+}
+
+register_layout! {
+    fn check_layout: Registers [0x120] {
+        fmr @ 0x00,
+        fm1r @ 0x04,
+        fs1r @ 0x0C,
+        ffa1r @ 0x14,
+        fa1r @ 0x1C,
+        bank @ 0x40,
+    }
+}
+
+/// The filter block, at offset 0x200 in CAN1.
+const FILTER_ADDRESS : usize = 0x40006600;
+
+/// Number of filter banks, shared between the controllers.
+pub const BANK_COUNT : usize = 28;
+
+bit_wrappers! {
+    /// Wrapper for the Filter Master Register bits.
+    pub struct Fmr(pub u32);
+}
+
+impl Fmr {
+    bitfield_accessors! {
+        /// First bank belonging to CAN2.
+        pub total [13:8] get_can2sb / with_can2sb: u32,
+        /// Filter initialization mode: set while banks are reconfigured.
+        pub total [0] get_finit / with_finit: bool,
+    }
+}
+
+/// What a filter accepts.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Filter {
+    /// Data frames with exactly this identifier.
+    Exact(Id),
+    /// Data and remote frames whose identifiers (of the same format as `id`)
+    /// match `id` in the bits set in `mask`.
+    Masked { id: Id, mask: u32 },
+    /// Everything.
+    All,
+}
+
+/// Ways that building filters can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum FilterError {
+    /// All `BANK_COUNT` banks are in use.
+    OutOfBanks,
+}
+
+/// Bank modes, from the bank's point of view.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum BankMode {
+    Mask32,
+    List32,
+    Mask16,
+    List16,
+}
+
+impl BankMode {
+    /// Number of filters (and filter match indices) a bank holds.
+    fn slots(self) -> usize {
+        match self {
+            BankMode::Mask32 => 1,
+            BankMode::List32 | BankMode::Mask16 => 2,
+            BankMode::List16 => 4,
+        }
+    }
+
+    fn is_32_bit(self) -> bool {
+        self == BankMode::Mask32 || self == BankMode::List32
+    }
+
+    fn is_list(self) -> bool {
+        self == BankMode::List32 || self == BankMode::List16
+    }
+}
+
+/// A bank under construction.
+#[derive(Copy, Clone, Debug)]
+struct Bank {
+    owner: CanIndex,
+    fifo: Fifo,
+    mode: BankMode,
+    /// Filters in the bank, as their register encoding: a whole register for
+    /// 32-bit modes, a half for 16-bit.
+    words: [u32; 4],
+    used: usize,
+}
+
+/// A set of receive filters for both controllers; see the module docs.
+pub struct FilterBuilder {
+    banks: [Bank; BANK_COUNT],
+    count: usize,
+}
+
+/// `IDE` bit in the 32-bit filter register encoding.  (The `RTR` bit,
+/// below it, is left clear: list entries accept only data frames, and masks
+/// ignore it.)
+const IDE32 : u32 = 1 << 2;
+/// `IDE` bit in the 16-bit encoding.
+const IDE16 : u32 = 1 << 3;
+
+fn ext32(id: ExtendedId) -> u32 {
+    (id.as_raw() << 3) | IDE32
+}
+
+fn std16(id: StandardId) -> u32 {
+    (id.as_raw() as u32) << 5
+}
+
+impl FilterBuilder {
+    /// Makes an empty set, which accepts nothing on either controller.
+    pub fn new() -> FilterBuilder {
+        FilterBuilder {
+            banks: [Bank {
+                owner: CanIndex::Can1,
+                fifo: Fifo::Fifo0,
+                mode: BankMode::Mask32,
+                words: [0; 4],
+                used: 0,
+            }; BANK_COUNT],
+            count: 0,
+        }
+    }
+
+    /// Adds `filter` to `can`'s filters, delivering matching frames to
+    /// `fifo`, and returns the filter match index that frames it accepts will
+    /// carry.
+    pub fn add(&mut self, can: CanIndex, fifo: Fifo, filter: Filter)
+        -> Result<u8, FilterError> {
+        match filter {
+            Filter::Exact(Id::Standard(id)) =>
+                self.place(can, fifo, BankMode::List16, std16(id)),
+            Filter::Exact(Id::Extended(id)) =>
+                self.place(can, fifo, BankMode::List32, ext32(id)),
+            Filter::Masked { id: Id::Standard(id), mask } => {
+                // IDE must match (be clear); RTR is ignored.
+                let mask = ((mask & 0x7FF) << 5) | IDE16;
+                self.place(can, fifo, BankMode::Mask16,
+                           (mask << 16) | std16(id))
+            },
+            Filter::Masked { id: Id::Extended(id), mask } => {
+                // For 32-bit masks, the two words go in separate registers;
+                // `place` can't pack them, so fill the bank directly.
+                let mask = ((mask & 0x1FFF_FFFF) << 3) | IDE32;
+                self.new_bank(can, fifo, BankMode::Mask32, [ext32(id), mask])
+            },
+            Filter::All =>
+                self.new_bank(can, fifo, BankMode::Mask32, [0, 0]),
+        }
+    }
+
+    /// Puts one filter `word` in a bank of the given kind with room, or a
+    /// new one.
+    fn place(&mut self, can: CanIndex, fifo: Fifo, mode: BankMode, word: u32)
+        -> Result<u8, FilterError> {
+        for i in 0..self.count {
+            let b = self.banks[i];
+            if b.owner == can && b.fifo == fifo && b.mode == mode
+                    && b.used < mode.slots() {
+                self.banks[i].words[b.used] = word;
+                self.banks[i].used = b.used + 1;
+                return Ok((self.index_base(i) + b.used) as u8)
+            }
+        }
+        self.new_bank(can, fifo, mode, [word, 0])
+    }
+
+    /// Starts a new bank with its first filter in `words`.
+    fn new_bank(&mut self, can: CanIndex, fifo: Fifo, mode: BankMode,
+                words: [u32; 2]) -> Result<u8, FilterError> {
+        if self.count == BANK_COUNT {
+            return Err(FilterError::OutOfBanks)
+        }
+        let i = self.count;
+        self.banks[i] = Bank {
+            owner: can,
+            fifo: fifo,
+            mode: mode,
+            words: [words[0], words[1], 0, 0],
+            used: 1,
+        };
+        self.count += 1;
+        Ok(self.index_base(i) as u8)
+    }
+
+    /// Filter match index of the first filter in bank `i`.  Banks keep their
+    /// relative order when `apply` splits them between the controllers, so
+    /// this counts the slots of the earlier banks with the same owner and
+    /// FIFO.
+    fn index_base(&self, i: usize) -> usize {
+        let b = &self.banks[i];
+        self.banks[..i].iter()
+            .filter(|e| e.owner == b.owner && e.fifo == b.fifo)
+            .fold(0, |n, e| n + e.mode.slots())
+    }
+
+    /// Register values for bank `b`.  Unused list entries repeat the first
+    /// one, so they match nothing new.
+    fn registers(b: &Bank) -> [u32; 2] {
+        let mut w = b.words;
+        if b.mode.is_list() {
+            for j in b.used..b.mode.slots() {
+                w[j] = w[0]
+            }
+        }
+        match b.mode {
+            BankMode::Mask32 => [w[0], w[1]],
+            BankMode::List32 => [w[0], w[1]],
+            BankMode::Mask16 => {
+                // A second mask, if absent, repeats the first.
+                let second = if b.used > 1 { w[1] } else { w[0] };
+                [w[0], second]
+            },
+            BankMode::List16 => [w[0] | (w[1] << 16), w[2] | (w[3] << 16)],
+        }
+    }
+
+    /// Loads the filters into the hardware, replacing all existing filters
+    /// on both controllers.  CAN1's banks come first; CAN2 gets the rest.
+    ///
+    /// Frames arriving while this runs are not received.
+    pub fn apply(&self) {
+        let reg: &Registers = unsafe { mmio(FILTER_ADDRESS) };
+
+        let banks = &self.banks[..self.count];
+        let can1 = banks.iter().filter(|b| b.owner == CanIndex::Can1);
+        let can2 = banks.iter().filter(|b| b.owner == CanIndex::Can2);
+        let can2sb = banks.iter()
+            .filter(|b| b.owner == CanIndex::Can1)
+            .count() as u32;
+
+        reg.fmr.update(|v| Fmr(v).with_finit(true).0);
+        reg.fa1r.set(0);
+
+        let (mut fm1r, mut fs1r, mut ffa1r, mut fa1r) = (0, 0, 0, 0);
+        for (n, b) in can1.chain(can2).enumerate() {
+            let bit = 1 << n;
+            if b.mode.is_list() {
+                fm1r |= bit
+            }
+            if b.mode.is_32_bit() {
+                fs1r |= bit
+            }
+            if b.fifo == Fifo::Fifo1 {
+                ffa1r |= bit
+            }
+            fa1r |= bit;
+            let r = FilterBuilder::registers(b);
+            reg.bank[n][0].set(r[0]);
+            reg.bank[n][1].set(r[1]);
+        }
+        reg.fm1r.set(fm1r);
+        reg.fs1r.set(fs1r);
+        reg.ffa1r.set(ffa1r);
+        reg.fa1r.set(fa1r);
+
+        reg.fmr.update(|v| Fmr(v).with_can2sb(can2sb).with_finit(false).0)
+    }
+}
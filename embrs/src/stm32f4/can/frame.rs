@@ -0,0 +1,223 @@
+//! CAN identifiers and frames.
+
+use core::cmp::Ordering;
+
+/// Largest standard (11-bit) identifier.
+pub const MAX_STANDARD_ID : u16 = 0x7FF;
+/// Largest extended (29-bit) identifier.
+pub const MAX_EXTENDED_ID : u32 = 0x1FFF_FFFF;
+
+/// Most data bytes a frame can carry.
+pub const MAX_DATA_LEN : usize = 8;
+
+/// An 11-bit identifier.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug)]
+pub struct StandardId(u16);
+
+impl StandardId {
+    /// Wraps `raw`, if it fits in 11 bits.
+    pub fn new(raw: u16) -> Option<StandardId> {
+        if raw <= MAX_STANDARD_ID {
+            Some(StandardId(raw))
+        } else {
+            None
+        }
+    }
+
+    pub fn as_raw(self) -> u16 {
+        self.0
+    }
+}
+
+/// A 29-bit identifier.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug)]
+pub struct ExtendedId(u32);
+
+impl ExtendedId {
+    /// Wraps `raw`, if it fits in 29 bits.
+    pub fn new(raw: u32) -> Option<ExtendedId> {
+        if raw <= MAX_EXTENDED_ID {
+            Some(ExtendedId(raw))
+        } else {
+            None
+        }
+    }
+
+    pub fn as_raw(self) -> u32 {
+        self.0
+    }
+
+    /// The top 11 bits, which are sent (and arbitrate) where a standard
+    /// identifier would be.
+    pub fn base(self) -> u16 {
+        (self.0 >> 18) as u16
+    }
+}
+
+/// A frame identifier of either format.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Id {
+    Standard(StandardId),
+    Extended(ExtendedId),
+}
+
+impl Id {
+    /// Orders identifiers by bus priority: the identifier that wins
+    /// arbitration (the numerically lower, on the wire) comes first.
+    ///
+    /// Arbitration compares the 11 base bits first; where those tie, a
+    /// standard data frame beats an extended one (its IDE bit is dominant).
+    pub fn priority_cmp(&self, other: &Id) -> Ordering {
+        match self.arbitration_key().cmp(&other.arbitration_key()) {
+            Ordering::Equal => match (*self, *other) {
+                (Id::Extended(a), Id::Extended(b)) => a.cmp(&b),
+                _ => Ordering::Equal,
+            },
+            o => o,
+        }
+    }
+
+    /// Base identifier bits and IDE bit, in transmission order.
+    fn arbitration_key(&self) -> u32 {
+        match *self {
+            Id::Standard(s) => (s.as_raw() as u32) << 1,
+            Id::Extended(e) => ((e.base() as u32) << 1) | 1,
+        }
+    }
+}
+
+impl From<StandardId> for Id {
+    fn from(id: StandardId) -> Id {
+        Id::Standard(id)
+    }
+}
+
+impl From<ExtendedId> for Id {
+    fn from(id: ExtendedId) -> Id {
+        Id::Extended(id)
+    }
+}
+
+/// A CAN 2.0 frame: data, or a remote request for data.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct CanFrame {
+    id: Id,
+    remote: bool,
+    dlc: u8,
+    data: [u8; MAX_DATA_LEN],
+}
+
+impl CanFrame {
+    /// Makes a data frame carrying `data`, if it's no more than
+    /// `MAX_DATA_LEN` bytes.
+    pub fn new_data<I: Into<Id>>(id: I, data: &[u8]) -> Option<CanFrame> {
+        if data.len() > MAX_DATA_LEN {
+            return None
+        }
+        let mut f = CanFrame {
+            id: id.into(),
+            remote: false,
+            dlc: data.len() as u8,
+            data: [0; MAX_DATA_LEN],
+        };
+        f.data[..data.len()].copy_from_slice(data);
+        Some(f)
+    }
+
+    /// Makes a remote frame requesting `dlc` bytes, if `dlc` is no more than
+    /// `MAX_DATA_LEN`.
+    pub fn new_remote<I: Into<Id>>(id: I, dlc: u8) -> Option<CanFrame> {
+        if dlc as usize > MAX_DATA_LEN {
+            return None
+        }
+        Some(CanFrame {
+            id: id.into(),
+            remote: true,
+            dlc: dlc,
+            data: [0; MAX_DATA_LEN],
+        })
+    }
+
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    pub fn is_remote(&self) -> bool {
+        self.remote
+    }
+
+    /// The data length code: the number of data bytes, or for a remote
+    /// frame, the number requested.
+    pub fn dlc(&self) -> u8 {
+        self.dlc
+    }
+
+    /// The data carried; empty for remote frames.
+    pub fn data(&self) -> &[u8] {
+        if self.remote {
+            &[]
+        } else {
+            &self.data[..self.dlc as usize]
+        }
+    }
+
+    /// Orders frames by bus priority, as `Id::priority_cmp`, with data
+    /// frames beating remote frames for the same identifier.
+    pub fn priority_cmp(&self, other: &CanFrame) -> Ordering {
+        match self.id.priority_cmp(&other.id) {
+            Ordering::Equal => self.remote.cmp(&other.remote),
+            o => o,
+        }
+    }
+
+    /// Encodes the frame into mailbox register values: the identifier
+    /// register (without `TXRQ`), the length register, and the two data
+    /// registers.
+    pub fn to_mailbox(&self) -> [u32; 4] {
+        let rtr = if self.remote { MAILBOX_RTR } else { 0 };
+        let ir = match self.id {
+            Id::Standard(s) => (s.as_raw() as u32) << 21,
+            Id::Extended(e) => (e.as_raw() << 3) | MAILBOX_IDE,
+        } | rtr;
+        let d = &self.data;
+        [
+            ir,
+            self.dlc as u32,
+            (d[0] as u32) | (d[1] as u32) << 8
+                | (d[2] as u32) << 16 | (d[3] as u32) << 24,
+            (d[4] as u32) | (d[5] as u32) << 8
+                | (d[6] as u32) << 16 | (d[7] as u32) << 24,
+        ]
+    }
+
+    /// Decodes mailbox register values, as read from a receive FIFO.  Data
+    /// length codes above eight (which the standard allows) are treated as
+    /// eight.
+    pub fn from_mailbox(regs: [u32; 4]) -> CanFrame {
+        let ir = regs[0];
+        let id = if (ir & MAILBOX_IDE) != 0 {
+            Id::Extended(ExtendedId(ir >> 3))
+        } else {
+            Id::Standard(StandardId((ir >> 21) as u16))
+        };
+        let mut dlc = (regs[1] & 0xF) as u8;
+        if dlc as usize > MAX_DATA_LEN {
+            dlc = MAX_DATA_LEN as u8
+        }
+        let mut data = [0; MAX_DATA_LEN];
+        for i in 0..4 {
+            data[i] = (regs[2] >> (i * 8)) as u8;
+            data[i + 4] = (regs[3] >> (i * 8)) as u8;
+        }
+        CanFrame {
+            id: id,
+            remote: (ir & MAILBOX_RTR) != 0,
+            dlc: dlc,
+            data: data,
+        }
+    }
+}
+
+/// `IDE` and `RTR` bits of a mailbox identifier register.
+const MAILBOX_IDE : u32 = 1 << 2;
+const MAILBOX_RTR : u32 = 1 << 1;
@@ -0,0 +1,629 @@
+//! Controller Area Network (bxCAN) support.
+//!
+//! The STM32F4 has two CAN controllers.  CAN1 is the master: it owns the
+//! receive filters (see `filter`), which CAN2 can only use through it, so
+//! using CAN2 means enabling both clocks.
+//!
+//! Bringing up a controller goes like this:
+//!
+//! 1. Enable its clock, and route its pins with `Can::configure_pins`.
+//! 2. `configure` it, with bit timing computed by `BitTiming::compute`.  This
+//!    leaves it in initialization mode, off the bus.
+//! 3. Set up receive filters with a `filter::FilterBuilder`.  Until you do,
+//!    nothing is received.
+//! 4. `start` it, which waits for it to synchronize to the bus.
+//!
+//! Frames (see `frame`) are then sent through the three transmit mailboxes
+//! with `try_transmit`, and collected from the two receive FIFOs with
+//! `try_receive`.  For more than three frames in flight, `tx` queues them in
+//! priority order behind the mailboxes.
+
+#![allow(trivial_numeric_casts)]  // for bitflags :-(
+
+use arm_m::reg::{mmio, Reg, ReservedReg};
+use clock;
+use hal::{NbError, NbResult};
+use super::gpio::{self, Pins};
+use super::rcc::{ApbPeripheral, ClockSpeeds, RCC};
+
+pub mod filter;
+pub mod frame;
+pub mod tx;
+
+pub use self::frame::CanFrame;
+
+#[repr(C, packed)]
+struct Registers {
+    mcr:       Reg<u32>,
+    msr:       Reg<u32>,
+    tsr:       Reg<u32>,
+    rfr:       [Reg<u32>; 2],
+    ier:       Reg<u32>,
+    esr:       Reg<u32>,
+    btr:       Reg<u32>,
+    _reserved: [ReservedReg; 88],
+    tx:        [Mailbox; 3],
+    rx:        [Mailbox; 2],
+}
+
+/// A transmit mailbox or receive FIFO output mailbox: the identifier, length
+/// and timestamp, and data registers.
+#[repr(C, packed)]
+struct Mailbox {
+    ir:  Reg<u32>,
+    dtr: Reg<u32>,
+    dlr: Reg<u32>,
+    dhr: Reg<u32>,
+}
+
+register_layout! {
+    fn check_layout: Registers [0x1D0] {
+        mcr @ 0x000,
+        msr @ 0x004,
+        tsr @ 0x008,
+        rfr @ 0x00C,
+        ier @ 0x014,
+        esr @ 0x018,
+        btr @ 0x01C,
+        tx @ 0x180,
+        rx @ 0x1B0,
+    }
+}
+
+bit_wrappers! {
+    /// Wrapper for the Master Control Register bits.
+    pub struct Mcr(pub u32);
+    /// Wrapper for the Master Status Register bits.
+    pub struct Msr(pub u32);
+    /// Wrapper for the Transmit Status Register bits.
+    pub struct Tsr(pub u32);
+    /// Wrapper for the Receive FIFO Register bits.
+    pub struct Rfr(pub u32);
+    /// Wrapper for the Error Status Register bits.
+    pub struct Esr(pub u32);
+    /// Wrapper for the Bit Timing Register bits.
+    pub struct Btr(pub u32);
+}
+
+impl Mcr {
+    bitfield_accessors! {
+        /// Freezes the controller while the core is halted by a debugger.
+        pub total [16] get_dbf / with_dbf: bool,
+        /// Resets the controller (self-clearing).
+        pub total [15] get_reset / with_reset: bool,
+        /// Time triggered communication mode.
+        pub total [7] get_ttcm / with_ttcm: bool,
+        /// Automatic bus-off management: recover from bus-off without
+        /// software intervention.
+        pub total [6] get_abom / with_abom: bool,
+        /// Automatic wakeup on bus activity.
+        pub total [5] get_awum / with_awum: bool,
+        /// No automatic retransmission.
+        pub total [4] get_nart / with_nart: bool,
+        /// Receive FIFO locked mode: when full, drop new frames rather than
+        /// overwriting the last.
+        pub total [3] get_rflm / with_rflm: bool,
+        /// Transmit FIFO priority: send mailboxes in request order rather
+        /// than identifier order.
+        pub total [2] get_txfp / with_txfp: bool,
+        /// Requests sleep mode.
+        pub total [1] get_sleep / with_sleep: bool,
+        /// Requests initialization mode.
+        pub total [0] get_inrq / with_inrq: bool,
+    }
+}
+
+impl Msr {
+    bitfield_accessors! {
+        /// Current level of the CAN RX pin.
+        pub total [11] get_rx / with_rx: bool,
+        pub total [10] get_samp / with_samp: bool,
+        /// Receiving.
+        pub total [9] get_rxm / with_rxm: bool,
+        /// Transmitting.
+        pub total [8] get_txm / with_txm: bool,
+        pub total [4] get_slaki / with_slaki: bool,
+        pub total [3] get_wkui / with_wkui: bool,
+        pub total [2] get_erri / with_erri: bool,
+        /// In sleep mode.
+        pub total [1] get_slak / with_slak: bool,
+        /// In initialization mode.
+        pub total [0] get_inak / with_inak: bool,
+    }
+}
+
+impl Tsr {
+    bitfield_accessors! {
+        /// Number of the next empty mailbox, or (when all are pending) of
+        /// the lowest-priority one.
+        pub total [25:24] get_code / with_code: u32,
+    }
+
+    /// Checks whether transmit mailbox `mb` is empty.
+    pub fn is_empty(&self, mb: usize) -> bool {
+        (self.0 & (1 << (26 + mb))) != 0
+    }
+
+    /// Checks whether mailbox `mb` has lost arbitration (it's still pending,
+    /// unless automatic retransmission is off).
+    pub fn is_arbitration_lost(&self, mb: usize) -> bool {
+        (self.0 & (TSR_ALST << (mb * 8))) != 0
+    }
+
+    /// Checks whether mailbox `mb`'s last transmission failed with an error.
+    pub fn is_transmit_error(&self, mb: usize) -> bool {
+        (self.0 & (TSR_TERR << (mb * 8))) != 0
+    }
+
+    /// Checks whether mailbox `mb`'s last transmission succeeded.  Meaningful
+    /// once `is_request_complete`.
+    pub fn is_transmit_ok(&self, mb: usize) -> bool {
+        (self.0 & (TSR_TXOK << (mb * 8))) != 0
+    }
+
+    /// Checks whether mailbox `mb`'s last request (transmission or abort)
+    /// has completed.
+    pub fn is_request_complete(&self, mb: usize) -> bool {
+        (self.0 & (TSR_RQCP << (mb * 8))) != 0
+    }
+}
+
+/// Per-mailbox TSR bits, for mailbox 0; mailbox *n*'s are shifted up by
+/// `8 * n`.
+const TSR_RQCP : u32 = 1 << 0;
+const TSR_TXOK : u32 = 1 << 1;
+const TSR_ALST : u32 = 1 << 2;
+const TSR_TERR : u32 = 1 << 3;
+const TSR_ABRQ : u32 = 1 << 7;
+
+/// `TXRQ` bit in a transmit mailbox identifier register.
+const TIR_TXRQ : u32 = 1 << 0;
+
+impl Rfr {
+    bitfield_accessors! {
+        /// Releases the output mailbox (set to dequeue a frame).
+        pub total [5] get_rfom / with_rfom: bool,
+        /// A frame was lost to a full FIFO (write one to clear).
+        pub total [4] get_fovr / with_fovr: bool,
+        /// The FIFO is full (write one to clear).
+        pub total [3] get_full / with_full: bool,
+        /// Number of frames pending.
+        pub total [1:0] get_fmp / with_fmp: u32,
+    }
+}
+
+bit_enums! {
+    /// Last error codes.
+    pub bit_enum LastError {
+        None = 0b000,
+        Stuff = 0b001,
+        Form = 0b010,
+        Acknowledgment = 0b011,
+        BitRecessive = 0b100,
+        BitDominant = 0b101,
+        Crc = 0b110,
+        /// Set by software, so that a fresh error is recognizable.
+        SetBySoftware = 0b111,
+    }
+}
+
+impl Esr {
+    bitfield_accessors! {
+        /// Receive error counter.
+        pub total [31:24] get_rec / with_rec: u32,
+        /// Transmit error counter.
+        pub total [23:16] get_tec / with_tec: u32,
+        pub total [6:4] get_lec / with_lec: LastError,
+        /// Bus-off: the transmit error counter passed 255.
+        pub total [2] get_boff / with_boff: bool,
+        /// Error passive: an error counter passed 127.
+        pub total [1] get_epvf / with_epvf: bool,
+        /// Error warning: an error counter reached 96.
+        pub total [0] get_ewgf / with_ewgf: bool,
+    }
+}
+
+impl Btr {
+    bitfield_accessors! {
+        /// Silent mode: receive, but never drive the bus.
+        pub total [31] get_silm / with_silm: bool,
+        /// Loopback mode: receive our own transmissions.
+        pub total [30] get_lbkm / with_lbkm: bool,
+        /// Resynchronization jump width, in time quanta, minus one.
+        pub total [25:24] get_sjw / with_sjw: u32,
+        /// Time segment 2, in time quanta, minus one.
+        pub total [22:20] get_ts2 / with_ts2: u32,
+        /// Time segment 1 (including propagation), in time quanta, minus one.
+        pub total [19:16] get_ts1 / with_ts1: u32,
+        /// Baud rate prescaler, minus one.
+        pub total [9:0] get_brp / with_brp: u32,
+    }
+}
+
+bitflags! {
+    /// Interrupt sources, as in the Interrupt Enable Register.
+    pub flags Interrupts: u32 {
+        const SLEEP = 1 << 17,
+        const WAKEUP = 1 << 16,
+        /// Any of the error conditions enabled below.
+        const ERROR = 1 << 15,
+        const LAST_ERROR_CODE = 1 << 11,
+        const BUS_OFF = 1 << 10,
+        const ERROR_PASSIVE = 1 << 9,
+        const ERROR_WARNING = 1 << 8,
+        const FIFO1_OVERRUN = 1 << 6,
+        const FIFO1_FULL = 1 << 5,
+        const FIFO1_PENDING = 1 << 4,
+        const FIFO0_OVERRUN = 1 << 3,
+        const FIFO0_FULL = 1 << 2,
+        const FIFO0_PENDING = 1 << 1,
+        /// A transmit mailbox has become empty.
+        const TX_MAILBOX_EMPTY = 1 << 0,
+    }
+}
+
+/// The two CAN controllers.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum CanIndex {
+    Can1,
+    Can2,
+}
+
+/// The two receive FIFOs of each controller.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Fifo {
+    Fifo0 = 0,
+    Fifo1 = 1,
+}
+
+/// Test modes.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum TestMode {
+    /// Normal operation.
+    None,
+    /// Transmissions are received, as well as sent.
+    Loopback,
+    /// Listen without acknowledging or sending anything.
+    Silent,
+    /// Transmissions are received, and nothing reaches the bus: a self test.
+    SilentLoopback,
+}
+
+/// Ways that bit timing computation can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum BitrateError {
+    /// No prescaler and segment lengths give exactly the requested rate from
+    /// this clock.
+    Unachievable,
+}
+
+/// Bit timing, in time quanta.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct BitTiming {
+    /// Peripheral clocks per time quantum (1-1024).
+    pub prescaler: u32,
+    /// Quanta before the sample point, not counting the sync quantum
+    /// (1-16).
+    pub ts1: u32,
+    /// Quanta after the sample point (1-8).
+    pub ts2: u32,
+    /// Resynchronization jump width (1-4).
+    pub sjw: u32,
+}
+
+/// Sample point that `BitTiming::compute` aims for, in tenths of a percent
+/// of the bit time.  This is the CANopen recommendation.
+pub const TARGET_SAMPLE_POINT : u32 = 875;
+
+impl BitTiming {
+    /// Finds timing giving exactly `bitrate` bits per second from a
+    /// peripheral clock of `clock_hz`, with the sample point as near as
+    /// possible to `TARGET_SAMPLE_POINT`.  Where there's a choice, more quanta
+    /// per bit win, since they allow finer placement of the sample point.
+    pub fn compute(clock_hz: u32, bitrate: u32)
+        -> Result<BitTiming, BitrateError> {
+        if bitrate == 0 {
+            return Err(BitrateError::Unachievable)
+        }
+        let mut best: Option<(u32, BitTiming)> = None;
+        // One sync quantum, plus up to 16 + 8.
+        for quanta in (8..26).rev() {
+            let per_bit = bitrate * quanta;
+            if clock_hz % per_bit != 0 {
+                continue
+            }
+            let prescaler = clock_hz / per_bit;
+            if prescaler == 0 || prescaler > 1024 {
+                continue
+            }
+            // Quanta up to and including the sample point.
+            let before = (quanta * TARGET_SAMPLE_POINT + 500) / 1000;
+            let mut ts2 = quanta - before;
+            if ts2 < 1 {
+                ts2 = 1
+            } else if ts2 > 8 {
+                ts2 = 8
+            }
+            let ts1 = quanta - 1 - ts2;
+            if ts1 < 1 || ts1 > 16 {
+                continue
+            }
+            let sample = (quanta - ts2) * 1000 / quanta;
+            let error = if sample > TARGET_SAMPLE_POINT {
+                sample - TARGET_SAMPLE_POINT
+            } else {
+                TARGET_SAMPLE_POINT - sample
+            };
+            let better = match best {
+                Some((e, _)) => error < e,
+                None => true,
+            };
+            if better {
+                best = Some((error, BitTiming {
+                    prescaler: prescaler,
+                    ts1: ts1,
+                    ts2: ts2,
+                    sjw: if ts2 < 4 { ts2 } else { 4 },
+                }))
+            }
+        }
+        best.map(|(_, t)| t).ok_or(BitrateError::Unachievable)
+    }
+
+    fn to_btr(&self) -> Btr {
+        Btr::default()
+            .with_brp(self.prescaler - 1)
+            .with_ts1(self.ts1 - 1)
+            .with_ts2(self.ts2 - 1)
+            .with_sjw(self.sjw - 1)
+    }
+}
+
+/// Controller options.
+#[derive(Copy, Clone, Debug)]
+pub struct CanConfig {
+    pub timing: BitTiming,
+    pub test_mode: TestMode,
+    /// Recover from bus-off automatically, after 128 occurrences of 11
+    /// recessive bits.  Otherwise, software must `stop` and `start` the
+    /// controller.
+    pub auto_bus_off_recovery: bool,
+    /// Retry transmissions that lose arbitration or fail, until they
+    /// succeed.
+    pub auto_retransmit: bool,
+    /// Send pending mailboxes in the order they were filled, rather than by
+    /// identifier.
+    pub tx_fifo_order: bool,
+    /// When a receive FIFO is full, keep the old frames and drop new ones.
+    pub rx_fifo_locked: bool,
+}
+
+/// Ways that receiving can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum RxError {
+    /// Frames were lost because the FIFO filled up.  The FIFO's contents are
+    /// still there to be received.
+    Overrun,
+}
+
+/// A received frame and where it came from.
+#[derive(Copy, Clone, Debug)]
+pub struct Received {
+    pub frame: CanFrame,
+    /// Filter match index of the filter that accepted it (see `filter`).
+    pub filter: u8,
+    /// Bus bit time at which the frame's start arrived, if time triggered
+    /// communication mode is on.
+    pub time: u16,
+}
+
+/// Number of transmit mailboxes.
+pub const TX_MAILBOXES : usize = 3;
+
+/// CAN controller driver.
+pub struct Can {
+    reg: *const Registers,
+    /// Name of this controller in the RCC.
+    peripheral: ApbPeripheral,
+}
+
+unsafe impl Sync for Can {}
+
+macro_rules! reg_accessors {
+    ($name:ident, $ty:ident, $read:ident, $write:ident, $update:ident) => {
+        pub fn $read(&self) -> $ty {
+            $ty(self.reg().$name.get())
+        }
+
+        pub fn $write(&self, v: $ty) {
+            self.reg().$name.set(v.0)
+        }
+
+        pub fn $update<F: FnOnce($ty) -> $ty>(&self, f: F) {
+            self.$write(f(self.$read()))
+        }
+    };
+}
+
+impl Can {
+    fn reg(&self) -> &Registers {
+        unsafe { mmio(self.reg as usize) }
+    }
+
+    reg_accessors!(mcr, Mcr, read_mcr, write_mcr, update_mcr);
+    reg_accessors!(btr, Btr, read_btr, write_btr, update_btr);
+
+    pub fn read_msr(&self) -> Msr {
+        Msr(self.reg().msr.get())
+    }
+
+    pub fn read_tsr(&self) -> Tsr {
+        Tsr(self.reg().tsr.get())
+    }
+
+    pub fn read_rfr(&self, fifo: Fifo) -> Rfr {
+        Rfr(self.reg().rfr[fifo as usize].get())
+    }
+
+    pub fn read_esr(&self) -> Esr {
+        Esr(self.reg().esr.get())
+    }
+
+    /// Enables this controller's clock in the RCC.
+    pub fn enable_clock(&self) {
+        RCC.enable_clock(self.peripheral)
+    }
+
+    /// Routes `pins` (RX and TX, which must be on the same port) to this
+    /// controller.  They must support it: see the datasheet.
+    pub fn configure_pins(&self, pins: &Pins) {
+        pins.configure_alternate(gpio::Function::AF9, gpio::Pull::Up)
+    }
+
+    /// Puts the controller in initialization mode, off the bus, and waits
+    /// for it to get there.  Pending transmissions are abandoned, once any
+    /// frame in progress finishes.
+    pub fn stop(&self) {
+        self.update_mcr(|v| v.with_sleep(false).with_inrq(true));
+        while !self.read_msr().get_inak() {}
+    }
+
+    /// Leaves initialization mode and waits for the controller to
+    /// synchronize to the bus: 11 consecutive recessive bits.  With nothing
+    /// connected, this can take forever; in silent loopback mode it doesn't
+    /// need a bus at all.
+    pub fn start(&self) {
+        self.update_mcr(|v| v.with_inrq(false));
+        while self.read_msr().get_inak() {}
+    }
+
+    /// Configures the controller, leaving it stopped (see `stop`).
+    pub fn configure(&self, config: &CanConfig) {
+        self.stop();
+        self.update_mcr(|v| v
+                        .with_abom(config.auto_bus_off_recovery)
+                        .with_nart(!config.auto_retransmit)
+                        .with_txfp(config.tx_fifo_order)
+                        .with_rflm(config.rx_fifo_locked)
+                        .with_ttcm(false)
+                        .with_dbf(true));
+        let (silent, loopback) = match config.test_mode {
+            TestMode::None => (false, false),
+            TestMode::Loopback => (false, true),
+            TestMode::Silent => (true, false),
+            TestMode::SilentLoopback => (true, true),
+        };
+        self.write_btr(config.timing.to_btr()
+                       .with_silm(silent)
+                       .with_lbkm(loopback))
+    }
+
+    /// Computes bit timing for `bitrate` from the current clock speeds.
+    pub fn compute_timing(&self, speeds: &ClockSpeeds, bitrate: u32)
+        -> Result<BitTiming, BitrateError> {
+        BitTiming::compute(speeds.get_clock_for(self.peripheral) as u32,
+                           bitrate)
+    }
+
+    /// Computes bit timing using the clock speeds recorded by
+    /// `clock::freeze`.  See `compute_timing`.
+    ///
+    /// # Panics
+    ///
+    /// If the clock speeds have not been frozen.
+    pub fn compute_timing_frozen(&self, bitrate: u32)
+        -> Result<BitTiming, BitrateError> {
+        self.compute_timing(clock::frozen(), bitrate)
+    }
+
+    /// Acknowledges the error interrupt (`ERRI`), leaving the other status
+    /// bits alone.
+    pub fn clear_error_interrupt(&self) {
+        self.reg().msr.set(Msr::default().with_erri(true).0)
+    }
+
+    /// Enables the interrupt sources in `i`, leaving others alone.
+    pub fn enable_interrupts(&self, i: Interrupts) {
+        self.reg().ier.update(|v| v | i.bits())
+    }
+
+    /// Disables the interrupt sources in `i`, leaving others alone.
+    pub fn disable_interrupts(&self, i: Interrupts) {
+        self.reg().ier.update(|v| v & !i.bits())
+    }
+
+    /// Puts `frame` in an empty transmit mailbox, and returns the mailbox's
+    /// number.  If all are full, we'd block.
+    pub fn try_transmit(&self, frame: &CanFrame) -> NbResult<usize, ()> {
+        let tsr = self.read_tsr();
+        let mb = tsr.get_code() as usize;
+        if mb >= TX_MAILBOXES || !tsr.is_empty(mb) {
+            return Err(NbError::WouldBlock)
+        }
+        self.load_mailbox(mb, frame);
+        Ok(mb)
+    }
+
+    /// Loads `frame` into transmit mailbox `mb`, which must be empty, and
+    /// requests its transmission.
+    pub fn load_mailbox(&self, mb: usize, frame: &CanFrame) {
+        let m = &self.reg().tx[mb];
+        let r = frame.to_mailbox();
+        m.dtr.set(r[1]);
+        m.dlr.set(r[2]);
+        m.dhr.set(r[3]);
+        m.ir.set(r[0] | TIR_TXRQ)
+    }
+
+    /// Requests that mailbox `mb`'s transmission be abandoned.  A frame
+    /// already on the wire is finished; check `Tsr::is_transmit_ok` once the
+    /// request completes to see whether it went.
+    pub fn abort(&self, mb: usize) {
+        self.reg().tsr.set(TSR_ABRQ << (mb * 8))
+    }
+
+    /// Clears mailbox `mb`'s completion status (`RQCP`, and with it `TXOK`,
+    /// `ALST` and `TERR`).
+    pub fn clear_request_complete(&self, mb: usize) {
+        self.reg().tsr.set(TSR_RQCP << (mb * 8))
+    }
+
+    /// Takes the oldest frame from `fifo`, if there is one.
+    ///
+    /// An overrun is reported once, and cleared; the frames that survived it
+    /// are received by later calls.
+    pub fn try_receive(&self, fifo: Fifo) -> NbResult<Received, RxError> {
+        let rfr = &self.reg().rfr[fifo as usize];
+        let status = Rfr(rfr.get());
+        if status.get_fovr() {
+            rfr.set(Rfr::default().with_fovr(true).0);
+            return Err(NbError::Other(RxError::Overrun))
+        }
+        if status.get_fmp() == 0 {
+            return Err(NbError::WouldBlock)
+        }
+
+        let m = &self.reg().rx[fifo as usize];
+        let dtr = m.dtr.get();
+        let frame = CanFrame::from_mailbox(
+            [m.ir.get(), dtr, m.dlr.get(), m.dhr.get()]);
+        rfr.set(Rfr::default().with_rfom(true).0);
+        Ok(Received {
+            frame: frame,
+            filter: (dtr >> 8) as u8,
+            time: (dtr >> 16) as u16,
+        })
+    }
+}
+
+/// Shared instance of the driver for CAN1.
+pub static CAN1: Can = Can {
+    reg: 0x40006400 as *const Registers,
+    peripheral: ApbPeripheral::Can1,
+};
+
+/// Shared instance of the driver for CAN2.
+pub static CAN2: Can = Can {
+    reg: 0x40006800 as *const Registers,
+    peripheral: ApbPeripheral::Can2,
+};
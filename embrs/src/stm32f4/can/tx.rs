@@ -0,0 +1,570 @@
+//! Interrupt-driven transmission.
+//!
+//! The controller has only three transmit mailboxes, and among pending
+//! mailboxes it sends the highest-priority identifier first.  A `CanTx` puts
+//! a software queue in front of them, kept in priority order, so that the
+//! mailboxes always hold the most urgent frames: when a frame is queued that
+//! beats everything in the mailboxes, the least urgent mailbox is aborted and
+//! its frame goes back in the queue.
+//!
+//! To use one, configure the controller with `tx_fifo_order` off, install
+//! the controller's `_tx_isr` and `_sce_isr` handlers, enable those
+//! interrupts in the NVIC, and call `enable`:
+//!
+//!     CAN1_TX.enable();
+//!     NVIC.enable_irq(Interrupt::Can1Tx);
+//!     NVIC.enable_irq(Interrupt::Can1Sce);
+//!
+//!     if let Err(frame) = CAN1_TX.send(frame) {
+//!         // queue full
+//!     }
+//!
+//! With automatic retransmission on, the hardware retries frames that lose
+//! arbitration or hit errors until they get through.  With it off, `CanTx`
+//! retries them itself, up to a limit (see `set_max_retries`), so that a
+//! frame isn't lost to one unlucky attempt.
+//!
+//! The status change and error (SCE) handler counts bus-off events.  If
+//! automatic bus-off recovery is off, and a recovery timer has been set up
+//! (see `set_recovery_timer`), it also takes the controller off the bus and
+//! schedules its return after a randomized, growing wait, so that nodes
+//! knocked off together don't all come back together:
+//!
+//!     let timer = WHEEL.claim().unwrap();
+//!     CAN1_TX.set_recovery_timer(&WHEEL, timer, can1_recover, seed);
+//!
+//! Without a recovery timer, the controller stays bus-off until the
+//! application `stop`s and `start`s it.
+
+use core::cmp::Ordering as CmpOrdering;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT,
+                         ATOMIC_USIZE_INIT};
+
+use backoff::{Backoff, Jitter};
+use prng::XorShift32;
+use sync::IrqCell;
+use time::wheel::{TimerCallback, TimerId, TimerWheel};
+use super::{Can, Interrupts, CAN1, CAN2, TX_MAILBOXES};
+use super::frame::{CanFrame, Id};
+use super::super::irq::Interrupt;
+
+/// Number of frames the software queue holds, beyond the mailboxes.
+pub const TX_QUEUE_LEN : usize = 16;
+
+/// Default for `set_max_retries`.
+pub const DEFAULT_MAX_RETRIES : u8 = 3;
+
+/// Ceiling of the wait before the first bus-off recovery, in milliseconds.
+/// It doubles with each bus-off, up to `RECOVERY_MAX_MS`, until a frame gets
+/// through.
+const RECOVERY_FIRST_MS : u32 = 10;
+
+/// Longest wait before a bus-off recovery, in milliseconds.
+const RECOVERY_MAX_MS : u32 = 2000;
+
+/// A frame waiting to go, and how many times it's failed.
+#[derive(Copy, Clone, Debug)]
+struct Pending {
+    frame: CanFrame,
+    failures: u8,
+}
+
+/// State owned by the transmit interrupt.
+struct TxState {
+    /// Queued frames, most urgent first; the first `len` are valid.
+    queue: [Option<Pending>; TX_QUEUE_LEN],
+    len: usize,
+    /// What each mailbox holds, if anything.
+    mailbox: [Option<Pending>; TX_MAILBOXES],
+    /// Bits of the mailboxes we've asked to abort.
+    aborting: u8,
+    /// Bits of the aborting mailboxes whose frames should be discarded,
+    /// rather than requeued, if the abort works.
+    discard: u8,
+    max_retries: u8,
+}
+
+impl TxState {
+    const fn new() -> TxState {
+        TxState {
+            queue: [None; TX_QUEUE_LEN],
+            len: 0,
+            mailbox: [None; TX_MAILBOXES],
+            aborting: 0,
+            discard: 0,
+            max_retries: DEFAULT_MAX_RETRIES,
+        }
+    }
+
+    /// Queues `p` behind any frames of equal priority.  If the queue is
+    /// full, the least urgent frame -- `p` or another -- is returned.
+    fn insert(&mut self, p: Pending) -> Option<Pending> {
+        let mut i = self.len;
+        while i > 0 && self.queue[i - 1].unwrap().frame
+                .priority_cmp(&p.frame) == CmpOrdering::Greater {
+            i -= 1
+        }
+
+        let mut dropped = None;
+        if self.len == TX_QUEUE_LEN {
+            if i == TX_QUEUE_LEN {
+                return Some(p)
+            }
+            dropped = self.queue[TX_QUEUE_LEN - 1];
+            self.len -= 1
+        }
+
+        let mut j = self.len;
+        while j > i {
+            self.queue[j] = self.queue[j - 1];
+            j -= 1
+        }
+        self.queue[i] = Some(p);
+        self.len += 1;
+        dropped
+    }
+
+    /// Removes the most urgent queued frame.
+    fn pop(&mut self) -> Option<Pending> {
+        if self.len == 0 {
+            return None
+        }
+        let p = self.queue[0];
+        for i in 1..self.len {
+            self.queue[i - 1] = self.queue[i]
+        }
+        self.len -= 1;
+        self.queue[self.len] = None;
+        p
+    }
+
+    /// Removes queued frames with identifier `id`, returning how many there
+    /// were.
+    fn remove(&mut self, id: Id) -> usize {
+        let mut kept = 0;
+        for i in 0..self.len {
+            let p = self.queue[i];
+            if p.unwrap().frame.id() != id {
+                self.queue[kept] = p;
+                kept += 1
+            }
+        }
+        let removed = self.len - kept;
+        for i in kept..self.len {
+            self.queue[i] = None
+        }
+        self.len = kept;
+        removed
+    }
+}
+
+/// The timer that ends a bus-off, and how long it waits.
+struct RecoveryTimer {
+    wheel: &'static TimerWheel,
+    timer: TimerId,
+    callback: TimerCallback,
+    backoff: Backoff,
+}
+
+/// State owned by the status change and error interrupt.
+struct Recovery {
+    timer: Option<RecoveryTimer>,
+    /// Set from taking the controller off the bus until `recover` puts it
+    /// back.
+    pending: bool,
+}
+
+/// Counts of transmission events, since `enable`.  These wrap.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct TxStats {
+    /// Frames sent successfully.
+    pub sent: usize,
+    /// Attempts that lost arbitration.
+    pub arbitration_lost: usize,
+    /// Attempts that failed with a bus error.
+    pub errors: usize,
+    /// Frames given up on after `max_retries` failures.
+    pub failed: usize,
+    /// Frames aborted, whether by `cancel` and `abort_all` or to make room
+    /// for a more urgent frame.  (The latter are requeued.)
+    pub aborted: usize,
+    /// Frames discarded because the queue was full when they were requeued.
+    pub dropped: usize,
+    /// Times the controller went bus-off.
+    pub bus_off: usize,
+}
+
+/// An interrupt-driven transmit queue for one controller; see the module
+/// docs.
+pub struct CanTx {
+    can: &'static Can,
+    state: IrqCell<TxState>,
+    recovery: IrqCell<Recovery>,
+    sent: AtomicUsize,
+    arbitration_lost: AtomicUsize,
+    errors: AtomicUsize,
+    failed: AtomicUsize,
+    aborted: AtomicUsize,
+    dropped: AtomicUsize,
+    bus_off: AtomicUsize,
+    /// Set while the controller is known to be bus-off, so each event is
+    /// counted once.
+    in_bus_off: AtomicBool,
+}
+
+fn bump(counter: &AtomicUsize) {
+    let _ = counter.fetch_add(1, Ordering::Relaxed);
+}
+
+impl CanTx {
+    /// Creates a queue for `can`, whose transmit interrupt is `irq` and
+    /// status change and error interrupt is `sce_irq`.
+    pub const fn new(can: &'static Can, irq: Interrupt, sce_irq: Interrupt)
+        -> CanTx {
+        CanTx {
+            can: can,
+            state: IrqCell::new(irq as u32, TxState::new()),
+            recovery: IrqCell::new(sce_irq as u32, Recovery {
+                timer: None,
+                pending: false,
+            }),
+            sent: ATOMIC_USIZE_INIT,
+            arbitration_lost: ATOMIC_USIZE_INIT,
+            errors: ATOMIC_USIZE_INIT,
+            failed: ATOMIC_USIZE_INIT,
+            aborted: ATOMIC_USIZE_INIT,
+            dropped: ATOMIC_USIZE_INIT,
+            bus_off: ATOMIC_USIZE_INIT,
+            in_bus_off: ATOMIC_BOOL_INIT,
+        }
+    }
+
+    /// Enables the controller's transmit and bus-off interrupts.  The
+    /// mailboxes must be empty, and the NVIC interrupts still need enabling.
+    pub fn enable(&self) {
+        for c in &[&self.sent, &self.arbitration_lost, &self.errors,
+                   &self.failed, &self.aborted, &self.dropped, &self.bus_off] {
+            c.store(0, Ordering::Relaxed)
+        }
+        self.in_bus_off.store(false, Ordering::Relaxed);
+        self.can.enable_interrupts(Interrupts::TX_MAILBOX_EMPTY
+                                   | Interrupts::ERROR
+                                   | Interrupts::BUS_OFF)
+    }
+
+    /// Sets how many times a frame may fail, when automatic retransmission
+    /// is off, before it's discarded.  Zero means no retries.
+    pub fn set_max_retries(&self, n: u8) {
+        self.state.lock(|s| s.max_retries = n)
+    }
+
+    /// Arranges for the controller to rejoin the bus after going bus-off,
+    /// when automatic bus-off recovery is off.  `timer` must be a slot
+    /// claimed from `wheel`, and `callback` must call `recover` --
+    /// `can1_recover` and `can2_recover` do, for the shared queues.  `seed`
+    /// should differ between devices.
+    ///
+    /// `recover` uses state owned by the status change and error interrupt,
+    /// so the wheel's dispatch priority must be lower than that interrupt's.
+    pub fn set_recovery_timer(&self,
+                              wheel: &'static TimerWheel,
+                              timer: TimerId,
+                              callback: TimerCallback,
+                              seed: u32) {
+        self.recovery.lock(|r| {
+            r.timer = Some(RecoveryTimer {
+                wheel: wheel,
+                timer: timer,
+                callback: callback,
+                backoff: Backoff::new(XorShift32::new(seed),
+                                      RECOVERY_FIRST_MS, RECOVERY_MAX_MS,
+                                      Jitter::Equal),
+            })
+        })
+    }
+
+    /// Ends a bus-off scheduled by the status change and error handler,
+    /// letting the controller rejoin the bus once it's seen 128 occurrences
+    /// of 11 recessive bits.  Call this from the recovery timer's callback.
+    pub fn recover(&self) {
+        self.recovery.lock(|r| {
+            if !r.pending {
+                return
+            }
+            if !self.can.read_msr().get_inak() {
+                // Not in initialization mode yet; look again later.
+                Self::schedule_recovery(r);
+                return
+            }
+            r.pending = false;
+            self.can.update_mcr(|v| v.with_inrq(false))
+        })
+    }
+
+    /// Starts the recovery timer for the next backoff delay.
+    fn schedule_recovery(r: &mut Recovery) {
+        if let Some(ref mut t) = r.timer {
+            let delay = t.backoff.next_delay();
+            // The timer was claimed for us, so this can't fail.
+            let _ = t.wheel.start_one_shot(t.timer, delay, t.callback);
+        }
+    }
+
+    /// Queues `frame` for transmission, or hands it back if the queue is
+    /// full.
+    pub fn send(&self, frame: CanFrame) -> Result<(), CanFrame> {
+        self.state.lock(|s| {
+            if s.len == TX_QUEUE_LEN {
+                return Err(frame)
+            }
+            let _ = s.insert(Pending { frame: frame, failures: 0 });
+            self.refill(s);
+            Ok(())
+        })
+    }
+
+    /// Number of frames queued or in mailboxes.
+    pub fn pending(&self) -> usize {
+        self.state.lock(|s| {
+            s.len + s.mailbox.iter().filter(|m| m.is_some()).count()
+        })
+    }
+
+    /// Checks whether everything sent has gone (or been given up on).
+    pub fn is_idle(&self) -> bool {
+        self.pending() == 0
+    }
+
+    /// Discards queued frames with identifier `id`, and aborts any in
+    /// mailboxes, returning the number discarded from the queue.  A frame
+    /// already on the wire is still sent.
+    pub fn cancel(&self, id: Id) -> usize {
+        self.state.lock(|s| {
+            let removed = s.remove(id);
+            for mb in 0..TX_MAILBOXES {
+                if let Some(p) = s.mailbox[mb] {
+                    if p.frame.id() == id {
+                        self.abort_mailbox(s, mb, true)
+                    }
+                }
+            }
+            for _ in 0..removed {
+                bump(&self.aborted)
+            }
+            removed
+        })
+    }
+
+    /// Discards the queue and aborts all mailboxes.
+    pub fn abort_all(&self) {
+        self.state.lock(|s| {
+            while let Some(_) = s.pop() {
+                bump(&self.aborted)
+            }
+            for mb in 0..TX_MAILBOXES {
+                if s.mailbox[mb].is_some() {
+                    self.abort_mailbox(s, mb, true)
+                }
+            }
+        })
+    }
+
+    /// Gets the event counts.
+    pub fn stats(&self) -> TxStats {
+        TxStats {
+            sent: self.sent.load(Ordering::Relaxed),
+            arbitration_lost: self.arbitration_lost.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            aborted: self.aborted.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            bus_off: self.bus_off.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Requests that mailbox `mb` be aborted; its frame is requeued when the
+    /// abort completes, unless `discard`.
+    fn abort_mailbox(&self, s: &mut TxState, mb: usize, discard: bool) {
+        let bit = 1 << mb;
+        if discard {
+            s.discard |= bit
+        }
+        if (s.aborting & bit) == 0 {
+            s.aborting |= bit;
+            self.can.abort(mb)
+        }
+    }
+
+    /// Moves frames from the queue into empty mailboxes; then, if the most
+    /// urgent queued frame still beats a mailbox, evicts the least urgent
+    /// mailbox to make way for it.
+    fn refill(&self, s: &mut TxState) {
+        for mb in 0..TX_MAILBOXES {
+            if s.mailbox[mb].is_none() {
+                match s.pop() {
+                    Some(p) => {
+                        self.can.load_mailbox(mb, &p.frame);
+                        s.mailbox[mb] = Some(p)
+                    },
+                    None => return,
+                }
+            }
+        }
+
+        let head = match s.queue[0] {
+            Some(p) => p.frame,
+            None => return,
+        };
+        let mut victim: Option<(usize, CanFrame)> = None;
+        for mb in 0..TX_MAILBOXES {
+            if (s.aborting & (1 << mb)) != 0 {
+                // Room is already on the way.
+                return
+            }
+            let f = s.mailbox[mb].unwrap().frame;
+            let worse = match victim {
+                Some((_, v)) => f.priority_cmp(&v) == CmpOrdering::Greater,
+                None => true,
+            };
+            if worse {
+                victim = Some((mb, f))
+            }
+        }
+        if let Some((mb, f)) = victim {
+            if head.priority_cmp(&f) == CmpOrdering::Less {
+                self.abort_mailbox(s, mb, false)
+            }
+        }
+    }
+
+    /// Handles the transmit interrupt: retires finished mailboxes and
+    /// refills them.
+    pub fn on_tx_interrupt(&self) {
+        self.state.lock(|s| {
+            let tsr = self.can.read_tsr();
+            let retransmit = !self.can.read_mcr().get_nart();
+            for mb in 0..TX_MAILBOXES {
+                if !tsr.is_request_complete(mb) {
+                    continue
+                }
+                self.can.clear_request_complete(mb);
+                let bit = 1 << mb;
+                let aborting = (s.aborting & bit) != 0;
+                let discard = (s.discard & bit) != 0;
+                s.aborting &= !bit;
+                s.discard &= !bit;
+
+                let p = match s.mailbox[mb].take() {
+                    Some(p) => p,
+                    None => continue,
+                };
+
+                if tsr.is_transmit_ok(mb) {
+                    bump(&self.sent);
+                    self.in_bus_off.store(false, Ordering::Relaxed);
+                    continue
+                }
+                if tsr.is_arbitration_lost(mb) {
+                    bump(&self.arbitration_lost)
+                }
+                if tsr.is_transmit_error(mb) {
+                    bump(&self.errors)
+                }
+
+                let retry = if aborting {
+                    bump(&self.aborted);
+                    if discard {
+                        None
+                    } else {
+                        Some(p)
+                    }
+                } else if retransmit || p.failures >= s.max_retries {
+                    // With automatic retransmission, completion without
+                    // success means the controller gave up (say, on leaving
+                    // bus-off); don't fight it.
+                    bump(&self.failed);
+                    None
+                } else {
+                    Some(Pending { frame: p.frame, failures: p.failures + 1 })
+                };
+                if let Some(p) = retry {
+                    if s.insert(p).is_some() {
+                        bump(&self.dropped)
+                    }
+                }
+            }
+            self.refill(s)
+        })
+    }
+
+    /// Handles the status change and error interrupt: notes bus-off, and if
+    /// the hardware won't recover by itself, schedules recovery.
+    pub fn on_sce_interrupt(&self) {
+        self.can.clear_error_interrupt();
+        if !self.can.read_esr().get_boff() {
+            return
+        }
+        self.recovery.lock(|r| {
+            if !self.in_bus_off.swap(true, Ordering::Relaxed) {
+                bump(&self.bus_off);
+                // A frame has got through since the last bus-off, so the
+                // bus is presumably healthy again: start the waits over.
+                if let Some(ref mut t) = r.timer {
+                    t.backoff.reset()
+                }
+            }
+            if self.can.read_mcr().get_abom() || r.timer.is_none()
+                    || r.pending {
+                return
+            }
+            // Ask for initialization mode, but don't wait for it here; by
+            // the time the timer fires, it will have been reached, and
+            // leaving it starts the controller's own wait for a quiet bus.
+            self.can.update_mcr(|v| v.with_sleep(false).with_inrq(true));
+            r.pending = true;
+            Self::schedule_recovery(r)
+        })
+    }
+}
+
+/// Shared transmit queue for CAN1.
+pub static CAN1_TX: CanTx = CanTx::new(&CAN1, Interrupt::Can1Tx,
+                                      Interrupt::Can1Sce);
+
+/// Shared transmit queue for CAN2.
+pub static CAN2_TX: CanTx = CanTx::new(&CAN2, Interrupt::Can2Tx,
+                                      Interrupt::Can2Sce);
+
+/// Transmit interrupt handler for CAN1.  Install this as the CAN1 TX vector
+/// (`InterruptTable::can1_tx`).
+pub extern "C" fn can1_tx_isr() {
+    CAN1_TX.on_tx_interrupt()
+}
+
+/// Status change and error interrupt handler for CAN1.  Install this as the
+/// CAN1 SCE vector (`InterruptTable::can1_sce`).
+pub extern "C" fn can1_sce_isr() {
+    CAN1_TX.on_sce_interrupt()
+}
+
+/// Bus-off recovery timer callback for CAN1; see `set_recovery_timer`.
+pub fn can1_recover(_: TimerId) {
+    CAN1_TX.recover()
+}
+
+/// Transmit interrupt handler for CAN2.  Install this as the CAN2 TX vector
+/// (`InterruptTable::can2_tx`).
+pub extern "C" fn can2_tx_isr() {
+    CAN2_TX.on_tx_interrupt()
+}
+
+/// Status change and error interrupt handler for CAN2.  Install this as the
+/// CAN2 SCE vector (`InterruptTable::can2_sce`).
+pub extern "C" fn can2_sce_isr() {
+    CAN2_TX.on_sce_interrupt()
+}
+
+/// Bus-off recovery timer callback for CAN2; see `set_recovery_timer`.
+pub fn can2_recover(_: TimerId) {
+    CAN2_TX.recover()
+}
@@ -0,0 +1,116 @@
+//! Placing statics in the 64 KiB Core Coupled Memory.
+//!
+//! CCM is attached directly to the CPU's data bus, so it's fast and doesn't
+//! contend with DMA for the bus matrix -- but for the same reason, DMA can't
+//! reach it at all.  That makes it a good home for the stack (which is where
+//! `layout.ld` puts it) and for large, CPU-only data such as lookup tables and
+//! filter state, and a bad home for anything handed to a peripheral.
+//!
+//! Statics are placed in CCM with `ccm_static!`, which wraps them in `Ccm`:
+//!
+//!     ccm_static! {
+//!         static COEFFS: [f32; 256] = [0.; 256];
+//!         zeroed static mut HISTORY: [f32; 4096] = [0.; 4096];
+//!     }
+//!
+//! Plain statics go in `.ccm_data` and are initialized from Flash at startup,
+//! like `.data`.  Those marked `zeroed` go in `.ccm_bss` and are simply
+//! cleared; their initializer *must* be all zeroes, or they won't match it.
+//!
+//! `Ccm` deliberately doesn't implement `Deref`: getting at the contents takes
+//! an explicit `get` (or `get_mut`), so a CCM buffer can't slip into a DMA
+//! API unnoticed.  As a second line of defence, `dma::mem_copy` and the DMA
+//! paths of the USART, SPI slave and I2C drivers check addresses with
+//! `contains`, and reject CCM buffers as `Inaccessible`.
+
+/// First address of CCM.
+pub const CCM_START : usize = 0x1000_0000;
+
+/// Address just past the end of CCM.
+pub const CCM_END : usize = 0x1001_0000;
+
+/// Checks whether any of the `len` bytes at `addr` lie in CCM.
+pub fn contains(addr: usize, len: usize) -> bool {
+    addr < CCM_END && addr + len > CCM_START
+}
+
+/// A value placed in CCM by `ccm_static!`.
+pub struct Ccm<T>(T);
+
+impl<T> Ccm<T> {
+    pub const fn new(value: T) -> Ccm<T> {
+        Ccm(value)
+    }
+
+    /// Gets a reference to the contents, which must not be used for DMA.
+    pub fn get(&self) -> &T {
+        &self.0
+    }
+
+    /// Gets a mutable reference to the contents, which must not be used for
+    /// DMA.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+/// Declares statics that live in CCM; see the module docs.
+#[macro_export]
+macro_rules! ccm_static {
+    () => {};
+    (@place $section:expr, [$($vis:tt)*], [$($m:tt)*],
+     $name:ident, $ty:ty, $init:expr) => {
+        $($m)*
+        #[link_section = $section]
+        $($vis)* $name : $crate::stm32f4::ccm::Ccm<$ty> =
+            $crate::stm32f4::ccm::Ccm::new($init);
+    };
+    ($(#[$m:meta])* zeroed pub static mut $name:ident : $ty:ty = $init:expr;
+     $($rest:tt)*) => {
+        ccm_static!(@place ".ccm_bss", [pub static mut], [$(#[$m])*],
+                    $name, $ty, $init);
+        ccm_static!($($rest)*);
+    };
+    ($(#[$m:meta])* zeroed pub static $name:ident : $ty:ty = $init:expr;
+     $($rest:tt)*) => {
+        ccm_static!(@place ".ccm_bss", [pub static], [$(#[$m])*],
+                    $name, $ty, $init);
+        ccm_static!($($rest)*);
+    };
+    ($(#[$m:meta])* zeroed static mut $name:ident : $ty:ty = $init:expr;
+     $($rest:tt)*) => {
+        ccm_static!(@place ".ccm_bss", [static mut], [$(#[$m])*],
+                    $name, $ty, $init);
+        ccm_static!($($rest)*);
+    };
+    ($(#[$m:meta])* zeroed static $name:ident : $ty:ty = $init:expr;
+     $($rest:tt)*) => {
+        ccm_static!(@place ".ccm_bss", [static], [$(#[$m])*],
+                    $name, $ty, $init);
+        ccm_static!($($rest)*);
+    };
+    ($(#[$m:meta])* pub static mut $name:ident : $ty:ty = $init:expr;
+     $($rest:tt)*) => {
+        ccm_static!(@place ".ccm_data", [pub static mut], [$(#[$m])*],
+                    $name, $ty, $init);
+        ccm_static!($($rest)*);
+    };
+    ($(#[$m:meta])* pub static $name:ident : $ty:ty = $init:expr;
+     $($rest:tt)*) => {
+        ccm_static!(@place ".ccm_data", [pub static], [$(#[$m])*],
+                    $name, $ty, $init);
+        ccm_static!($($rest)*);
+    };
+    ($(#[$m:meta])* static mut $name:ident : $ty:ty = $init:expr;
+     $($rest:tt)*) => {
+        ccm_static!(@place ".ccm_data", [static mut], [$(#[$m])*],
+                    $name, $ty, $init);
+        ccm_static!($($rest)*);
+    };
+    ($(#[$m:meta])* static $name:ident : $ty:ty = $init:expr;
+     $($rest:tt)*) => {
+        ccm_static!(@place ".ccm_data", [static], [$(#[$m])*],
+                    $name, $ty, $init);
+        ccm_static!($($rest)*);
+    };
+}
@@ -0,0 +1,166 @@
+//! MCU debug component (DBGMCU) support.
+//!
+//! DBGMCU identifies the device and revision, and controls how peripherals
+//! behave while the core is halted by a debugger -- most usefully, it can
+//! freeze timers and watchdogs so that stepping through code doesn't cause
+//! spurious timeouts or resets.
+
+#![allow(trivial_numeric_casts)]  // for bitflags :-(
+
+use arm_m::reg::{mmio, AtomicReg, Reg};
+
+#[repr(C, packed)]
+struct Registers {
+    idcode: Reg<u32>,
+    cr:     Reg<u32>,
+    apb1fz: Reg<u32>,
+    apb2fz: Reg<u32>,
+}
+
+register_layout! {
+    fn check_layout: Registers [0x10] {
+        idcode @ 0x00,
+        cr @ 0x04,
+        apb1fz @ 0x08,
+        apb2fz @ 0x0C,
+    }
+}
+
+const DBGMCU_ADDRESS : usize = 0xe0042000;
+
+bit_wrappers! {
+    /// Wrapper for the ID Code Register bits.
+    pub struct Idcode(pub u32);
+    /// Wrapper for the Configuration Register bits.
+    pub struct Cr(pub u32);
+}
+
+impl Idcode {
+    bitfield_accessors! {
+        /// Silicon revision.  See the errata sheet for the mapping to
+        /// revision letters.
+        pub total [31:16] get_rev_id / with_rev_id: u16,
+        /// Device family identifier; 0x413 for the STM32F405/407/415/417.
+        pub total [11: 0] get_dev_id / with_dev_id: u32,
+    }
+}
+
+impl Cr {
+    bitfield_accessors! {
+        /// Selects the trace pin assignment.
+        pub total [7:6] get_trace_mode / with_trace_mode: TraceMode,
+        /// Enables the trace pins.
+        pub total [5] get_trace_ioen / with_trace_ioen: bool,
+        /// Keeps the debug connection alive in Standby mode.
+        pub total [2] get_dbg_standby / with_dbg_standby: bool,
+        /// Keeps the debug connection alive in Stop mode.
+        pub total [1] get_dbg_stop / with_dbg_stop: bool,
+        /// Keeps the debug connection alive in Sleep mode.
+        pub total [0] get_dbg_sleep / with_dbg_sleep: bool,
+    }
+}
+
+bit_enums! {
+    /// Trace pin assignments.
+    pub bit_enum TraceMode {
+        Asynchronous = 0b00,
+        Synchronous1 = 0b01,
+        Synchronous2 = 0b10,
+        Synchronous4 = 0b11,
+    }
+}
+
+bitflags! {
+    /// APB1 peripherals that can be frozen while the core is halted.
+    pub flags Apb1Freeze: u32 {
+        const TIM2 = 1 << 0,
+        const TIM3 = 1 << 1,
+        const TIM4 = 1 << 2,
+        const TIM5 = 1 << 3,
+        const TIM6 = 1 << 4,
+        const TIM7 = 1 << 5,
+        const TIM12 = 1 << 6,
+        const TIM13 = 1 << 7,
+        const TIM14 = 1 << 8,
+        const RTC = 1 << 10,
+        const WWDG = 1 << 11,
+        const IWDG = 1 << 12,
+        /// Freezes the I2C1 SMBus timeout.
+        const I2C1_SMBUS_TIMEOUT = 1 << 21,
+        /// Freezes the I2C2 SMBus timeout.
+        const I2C2_SMBUS_TIMEOUT = 1 << 22,
+        /// Freezes the I2C3 SMBus timeout.
+        const I2C3_SMBUS_TIMEOUT = 1 << 23,
+        const CAN1 = 1 << 25,
+        const CAN2 = 1 << 26,
+    }
+}
+
+bitflags! {
+    /// APB2 peripherals that can be frozen while the core is halted.
+    pub flags Apb2Freeze: u32 {
+        const TIM1 = 1 << 0,
+        const TIM8 = 1 << 1,
+        const TIM9 = 1 << 16,
+        const TIM10 = 1 << 17,
+        const TIM11 = 1 << 18,
+    }
+}
+
+/// DBGMCU driver.
+pub struct Dbgmcu;
+
+impl Dbgmcu {
+    fn reg(&self) -> &'static Registers {
+        unsafe { mmio(DBGMCU_ADDRESS) }
+    }
+
+    pub fn read_idcode(&self) -> Idcode {
+        Idcode(self.reg().idcode.get())
+    }
+
+    pub fn read_cr(&self) -> Cr {
+        Cr(self.reg().cr.get())
+    }
+
+    pub fn write_cr(&self, v: Cr) {
+        self.reg().cr.set(v.0)
+    }
+
+    pub fn update_cr<F: FnOnce(Cr) -> Cr>(&self, f: F) {
+        self.write_cr(f(self.read_cr()))
+    }
+
+    /// Reads the set of APB1 peripherals currently frozen under debug.
+    pub fn get_apb1_freeze(&self) -> Apb1Freeze {
+        Apb1Freeze::from_bits_truncate(self.reg().apb1fz.get())
+    }
+
+    /// Freezes (if `frozen`) or unfreezes the APB1 peripherals in `p` while
+    /// the core is halted.  Others are unaffected.
+    pub fn set_apb1_freeze(&self, p: Apb1Freeze, frozen: bool) {
+        if frozen {
+            self.reg().apb1fz.atomic_or(p.bits())
+        } else {
+            self.reg().apb1fz.atomic_nand(p.bits())
+        }
+    }
+
+    /// Reads the set of APB2 peripherals currently frozen under debug.
+    pub fn get_apb2_freeze(&self) -> Apb2Freeze {
+        Apb2Freeze::from_bits_truncate(self.reg().apb2fz.get())
+    }
+
+    /// Freezes (if `frozen`) or unfreezes the APB2 peripherals in `p` while
+    /// the core is halted.  Others are unaffected.
+    pub fn set_apb2_freeze(&self, p: Apb2Freeze, frozen: bool) {
+        if frozen {
+            self.reg().apb2fz.atomic_or(p.bits())
+        } else {
+            self.reg().apb2fz.atomic_nand(p.bits())
+        }
+    }
+}
+
+/// Shared instance of the `Dbgmcu` driver.
+pub static DBGMCU: Dbgmcu = Dbgmcu;
@@ -0,0 +1,334 @@
+//! Double-buffered circular DMA, for continuous streams like audio or ADC
+//! sampling.
+//!
+//! In double-buffer mode the stream alternates between two memory buffers,
+//! switching each time one is full (or empty, for memory-to-peripheral) and
+//! raising Transfer Complete.  The buffer the stream isn't using -- the one
+//! *not* named by the current target (CT) bit -- belongs to the application
+//! until the next switch.
+//!
+//! The application gets at that buffer either from the stream's interrupt,
+//! through `handle_interrupt`, or with interrupts masked, through
+//! `with_idle_buffer`; a buffer is never lent to both at once.
+
+use core::marker::PhantomData;
+use core::mem;
+use core::slice;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use arm_m;
+
+use super::super::ccm;
+use super::{Cr, Direction, DmaWord, InterruptFlags, Ndtr, Request, Target};
+use super::{HALF_TRANSFER, TRANSFER_COMPLETE, TRANSFER_ERROR};
+
+/// Reasons `DoubleBuffer::start` can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum DoubleBufferError {
+    /// The stream is already running.
+    Busy,
+    /// The two buffers differ in length.
+    LengthMismatch,
+    /// The buffers are empty, or longer than the 65535 transfers NDTR can
+    /// count.
+    BadLength,
+    /// A buffer is in memory that DMA can't access (see `ccm`).
+    Inaccessible,
+    /// The stream isn't running, so there's no buffer to lend.
+    Stopped,
+    /// A buffer is already lent out, to an enclosing `with_idle_buffer` or
+    /// `handle_interrupt` callback.
+    InUse,
+    /// The stream switched buffers while `with_idle_buffer` was using the
+    /// idle one, so the stream has already started on it.  Whatever the
+    /// closure did stands, but its result is lost.
+    Overrun,
+}
+
+/// Events delivered to `DoubleBuffer::handle_interrupt`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Event {
+    /// The first half of the buffer the stream is working on is done; the
+    /// accompanying slice is that half.
+    HalfTransfer(Target),
+    /// The stream has finished with a buffer and switched to the other; the
+    /// accompanying slice is the finished buffer.
+    TransferComplete(Target),
+    /// A bus error stopped the stream.  No slice is delivered (it's empty).
+    Error,
+}
+
+/// Driver for a DMA stream running in double-buffer mode, moving elements of
+/// type `T` (`u8`, `u16`, or `u32`, matching the peripheral's data register).
+pub struct DoubleBuffer<T: DmaWord> {
+    request: Request,
+    /// Addresses of the two buffers; zero when stopped.
+    buf: [AtomicUsize; 2],
+    /// Length of each buffer, in elements.
+    len: AtomicUsize,
+    /// Set while a buffer is lent out.
+    lent: AtomicBool,
+    _marker: PhantomData<T>,
+}
+
+impl<T: DmaWord> DoubleBuffer<T> {
+    /// Creates a driver for the stream serving `request`.
+    pub const fn new(request: Request) -> DoubleBuffer<T> {
+        DoubleBuffer {
+            request: request,
+            buf: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            len: AtomicUsize::new(0),
+            lent: AtomicBool::new(false),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Checks whether the stream is running.
+    pub fn is_running(&self) -> bool {
+        self.request.route().get_stream().is_enabled()
+    }
+
+    /// Starts streaming between the peripheral register at `peripheral` and
+    /// the two buffers, beginning with `buf0`, in direction `dir` (which
+    /// can't be memory-to-memory).  Runs until `stop`.  The buffers can't
+    /// be in CCM, which DMA can't reach.
+    ///
+    /// The stream's interrupt must be routed to a handler that calls
+    /// `handle_interrupt`.
+    pub fn start(&self,
+                 peripheral: *const (),
+                 dir: Direction,
+                 buf0: &'static mut [T],
+                 buf1: &'static mut [T])
+        -> Result<(), DoubleBufferError> {
+        if self.is_running() {
+            return Err(DoubleBufferError::Busy)
+        }
+        if buf0.len() != buf1.len() {
+            return Err(DoubleBufferError::LengthMismatch)
+        }
+        let len = buf0.len();
+        if len == 0 || len > 0xFFFF {
+            return Err(DoubleBufferError::BadLength)
+        }
+        let bytes = len * mem::size_of::<T>();
+        if ccm::contains(buf0.as_ptr() as usize, bytes)
+            || ccm::contains(buf1.as_ptr() as usize, bytes) {
+            return Err(DoubleBufferError::Inaccessible)
+        }
+
+        self.buf[0].store(buf0.as_ptr() as usize, Ordering::Relaxed);
+        self.buf[1].store(buf1.as_ptr() as usize, Ordering::Relaxed);
+        self.len.store(len, Ordering::Release);
+
+        let route = self.request.route();
+        let stream = route.get_stream();
+        route.clear_interrupt_flags(InterruptFlags::all());
+        stream.par.set(peripheral);
+        stream.mar[0].set(buf0.as_ptr() as *const ());
+        stream.mar[1].set(buf1.as_ptr() as *const ());
+        stream.ndtr.set(Ndtr::default().with_ndt(len as u16));
+        stream.cr.set(Cr::default()
+                      .with_chsel(route.get_channel())
+                      .with_dir(dir)
+                      .with_msize(T::transfer_size())
+                      .with_psize(T::transfer_size())
+                      .with_minc(true)
+                      .with_dbm(true)
+                      .with_circ(true)
+                      .with_ct(Target::Memory0)
+                      .with_htie(true)
+                      .with_tcie(true)
+                      .with_teie(true));
+        stream.cr.update(|v| v.with_en(true));
+        Ok(())
+    }
+
+    /// Stops the stream and forgets the buffers.
+    pub fn stop(&self) {
+        let _ = self.request.route().get_stream().abort();
+        self.len.store(0, Ordering::Release);
+    }
+
+    /// Gets the buffer the stream is currently using.
+    pub fn current_target(&self) -> Target {
+        self.request.route().get_stream().cr.get().get_ct()
+    }
+
+    /// Makes a slice of buffer `t`.
+    ///
+    /// The caller must hold `lent`, and only lend out a buffer (or part of
+    /// one) that the stream isn't using.
+    unsafe fn buffer(&self, t: Target, len: usize) -> &mut [T] {
+        let p = self.buf[t as usize].load(Ordering::Relaxed) as *mut T;
+        slice::from_raw_parts_mut(p, len)
+    }
+
+    /// Claims `lent`, returning `false` if a buffer is already out.  Called
+    /// only with interrupts masked, or from the stream's interrupt, which
+    /// therefore can't interrupt one another.
+    fn lend(&self) -> bool {
+        !self.lent.compare_and_swap(false, true, Ordering::Acquire)
+    }
+
+    /// Runs `f` on the buffer the stream is not using, with interrupts
+    /// masked so that the stream's interrupt can't hand out the same buffer
+    /// meanwhile.  The stream itself keeps going, so `f` should be quick: if
+    /// the stream switches to the buffer before `f` returns, the result is
+    /// `Overrun`.
+    pub fn with_idle_buffer<R, F>(&self, f: F)
+        -> Result<R, DoubleBufferError>
+        where F: FnOnce(&mut [T]) -> R {
+        arm_m::without_interrupts(|| {
+            let len = self.len.load(Ordering::Acquire);
+            if len == 0 {
+                return Err(DoubleBufferError::Stopped)
+            }
+            if !self.lend() {
+                return Err(DoubleBufferError::InUse)
+            }
+            let current = self.current_target();
+            let idle = match current {
+                Target::Memory0 => Target::Memory1,
+                Target::Memory1 => Target::Memory0,
+            };
+            let r = f(unsafe { self.buffer(idle, len) });
+            let switched = self.current_target() != current;
+            self.lent.store(false, Ordering::Release);
+            if switched {
+                Err(DoubleBufferError::Overrun)
+            } else {
+                Ok(r)
+            }
+        })
+    }
+
+    /// To be called from the stream's interrupt handler.  Acknowledges the
+    /// interrupt and passes each event that occurred to `callback`, along
+    /// with the part of the buffers that just became safe to touch.  Returns
+    /// `true` if anything happened.
+    pub fn handle_interrupt<F>(&self, mut callback: F) -> bool
+        where F: FnMut(Event, &mut [T]) {
+        let route = self.request.route();
+        let flags = match route.get_interrupt_flags() {
+            Ok(f) => f & (HALF_TRANSFER | TRANSFER_COMPLETE | TRANSFER_ERROR),
+            Err(_) => return false,
+        };
+        route.clear_interrupt_flags(flags);
+
+        let len = self.len.load(Ordering::Acquire);
+        if flags.is_empty() || len == 0 {
+            return false
+        }
+
+        if flags.contains(TRANSFER_ERROR) {
+            // The hardware has already disabled the stream.
+            self.len.store(0, Ordering::Release);
+            callback(Event::Error, &mut []);
+            return true
+        }
+
+        if !self.lend() {
+            // Called from within a `with_idle_buffer` closure; the events
+            // are lost.
+            return true
+        }
+        let current = self.current_target();
+        if flags.contains(TRANSFER_COMPLETE) {
+            // CT has already flipped, so the finished buffer is the other.
+            let done = match current {
+                Target::Memory0 => Target::Memory1,
+                Target::Memory1 => Target::Memory0,
+            };
+            callback(Event::TransferComplete(done),
+                     unsafe { self.buffer(done, len) });
+        }
+        if flags.contains(HALF_TRANSFER) {
+            // The stream has moved on to the second half.
+            callback(Event::HalfTransfer(current),
+                     unsafe { &mut self.buffer(current, len)[.. len / 2] });
+        }
+        self.lent.store(false, Ordering::Release);
+        true
+    }
+}
+
+#[cfg(all(test, feature = "host-test"))]
+mod tests {
+    use arm_m::sim;
+    use super::{DoubleBuffer, DoubleBufferError, Event};
+    use super::super::{Direction, InterruptFlags, Ir, Request, StreamIndex};
+    use super::super::{Target, HALF_TRANSFER, TRANSFER_COMPLETE};
+
+    /// DMA1's LISR, which holds the flags of stream 3 (serving SPI2 RX).
+    const DMA1_LISR : usize = 0x40026000;
+    /// SPI2's data register.
+    const SPI2_DR : usize = 0x4000380C;
+
+    fn start(db: &DoubleBuffer<u16>,
+             buf0: &'static mut [u16],
+             buf1: &'static mut [u16]) {
+        sim::reset();
+        assert!(db.start(SPI2_DR as *const (), Direction::PeripheralToMemory,
+                         buf0, buf1).is_ok());
+    }
+
+    fn raise(flags: InterruptFlags) {
+        let s = StreamIndex::S3;
+        sim::poke(DMA1_LISR, Ir(0).with_rs(s.get_rs_index(), flags).0)
+    }
+
+    #[test]
+    fn idle_buffer_is_lent_and_returned() {
+        static mut BUF0: [u16; 8] = [0; 8];
+        static mut BUF1: [u16; 8] = [0; 8];
+        let db = DoubleBuffer::new(Request::Spi2Rx);
+        start(&db, unsafe { &mut BUF0 }, unsafe { &mut BUF1 });
+
+        // CT reads as zero, so the stream is on buffer 0.
+        let r = db.with_idle_buffer(|b| {
+            assert_eq!(b.as_ptr(), unsafe { BUF1.as_ptr() });
+            assert_eq!(b.len(), 8);
+            // No second loan while the first is out.
+            assert!(db.with_idle_buffer(|_| ())
+                    == Err(DoubleBufferError::InUse));
+            b[0] = 42;
+        });
+        assert!(r == Ok(()));
+        assert_eq!(unsafe { BUF1[0] }, 42);
+        // Returned: it can be lent again.
+        assert!(db.with_idle_buffer(|_| ()) == Ok(()));
+    }
+
+    #[test]
+    fn interrupt_lends_finished_buffer() {
+        static mut BUF0: [u16; 8] = [0; 8];
+        static mut BUF1: [u16; 8] = [0; 8];
+        let db = DoubleBuffer::new(Request::Spi2Rx);
+        start(&db, unsafe { &mut BUF0 }, unsafe { &mut BUF1 });
+
+        raise(TRANSFER_COMPLETE | HALF_TRANSFER);
+        let mut events = 0;
+        assert!(db.handle_interrupt(|e, b| {
+            match e {
+                Event::TransferComplete(Target::Memory1) =>
+                    assert_eq!(b.len(), 8),
+                Event::HalfTransfer(Target::Memory0) =>
+                    assert_eq!(b.len(), 4),
+                _ => panic!("unexpected event"),
+            }
+            events += 1
+        }));
+        assert_eq!(events, 2);
+        // The loan ended with the handler.
+        assert!(db.with_idle_buffer(|_| ()) == Ok(()));
+    }
+
+    #[test]
+    fn stopped_stream_lends_nothing() {
+        sim::reset();
+        let db = DoubleBuffer::<u16>::new(Request::Spi2Rx);
+        assert!(db.with_idle_buffer(|_| ())
+                == Err(DoubleBufferError::Stopped));
+    }
+}
@@ -0,0 +1,191 @@
+//! Memory-to-memory copies using DMA2 (DMA1 can't do them).
+//!
+//! For large buffers, such as framebuffers, this is faster than a CPU copy,
+//! and the asynchronous form frees the CPU for other work meanwhile.  The
+//! widest transfer size (and, when everything is 16-byte aligned, bursts)
+//! that the buffers' alignment allows is used automatically.
+//!
+//! DMA can't reach the CCM RAM at `0x1000_0000`; buffers there are rejected.
+
+use super::super::ccm;
+use super::{dma2, Burst, Channel, Cr, Direction, DmaRoute, Fcr, FifoThreshold};
+use super::{InterruptFlags, Ndtr, StreamIndex, TransferSize};
+use super::{FIFO_ERROR, TRANSFER_COMPLETE, TRANSFER_ERROR};
+
+/// Reasons a copy can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum MemCopyError {
+    /// The stream is already busy.
+    Busy,
+    /// Source and destination differ in length.
+    LengthMismatch,
+    /// A buffer is in memory that DMA can't access.
+    Inaccessible,
+    /// The copy needs more transfers than one DMA operation can do (65535);
+    /// only applies to `start`.
+    TooLong,
+    /// The DMA controller reported a bus or FIFO error.
+    Transfer,
+}
+
+/// Largest number of transfers in one DMA operation.
+const MAX_TRANSFERS : usize = 0xFFFF;
+
+/// Bytes moved by one burst (a full FIFO) when bursting.
+const BURST_BYTES : usize = 16;
+
+fn is_accessible(addr: usize, len: usize) -> bool {
+    !ccm::contains(addr, len)
+}
+
+/// Picks transfer size and burst for a copy, given the OR of both addresses
+/// and the length (so that a single alignment test covers all three).
+fn plan(bits: usize) -> (TransferSize, usize, Burst) {
+    if bits % BURST_BYTES == 0 {
+        (TransferSize::Word, 4, Burst::Incr4)
+    } else if bits % 4 == 0 {
+        (TransferSize::Word, 4, Burst::Single)
+    } else if bits % 2 == 0 {
+        (TransferSize::HalfWord, 2, Burst::Single)
+    } else {
+        (TransferSize::Byte, 1, Burst::Single)
+    }
+}
+
+/// Driver for memory-to-memory copies on one stream of DMA2.
+pub struct MemCopy {
+    stream: StreamIndex,
+}
+
+impl MemCopy {
+    /// Creates a driver using DMA2 stream `stream`, which must not be used
+    /// for anything else meanwhile.
+    pub const fn new(stream: StreamIndex) -> MemCopy {
+        MemCopy {
+            stream: stream,
+        }
+    }
+
+    fn route(&self) -> DmaRoute {
+        DmaRoute {
+            dma: dma2,
+            stream: self.stream,
+            // Ignored for memory-to-memory transfers.
+            channel: Channel::Ch0,
+        }
+    }
+
+    /// Checks whether a copy is in progress.
+    pub fn is_busy(&self) -> bool {
+        self.route().get_stream().is_enabled()
+    }
+
+    /// Starts copying `src` into `dst`, returning immediately; use `poll` or
+    /// `wait` to find out when it's done.
+    pub fn start(&self, dst: &'static mut [u8], src: &'static [u8])
+        -> Result<(), MemCopyError> {
+        unsafe { self.start_raw(dst.as_mut_ptr() as usize,
+                                src.as_ptr() as usize,
+                                src.len(),
+                                dst.len()) }
+    }
+
+    unsafe fn start_raw(&self, dst: usize, src: usize, len: usize,
+                        dst_len: usize)
+        -> Result<(), MemCopyError> {
+        if len != dst_len {
+            return Err(MemCopyError::LengthMismatch)
+        }
+        if !is_accessible(dst, len) || !is_accessible(src, len) {
+            return Err(MemCopyError::Inaccessible)
+        }
+        if self.is_busy() {
+            return Err(MemCopyError::Busy)
+        }
+
+        let (size, unit, burst) = plan(dst | src | len);
+        let count = len / unit;
+        if count > MAX_TRANSFERS {
+            return Err(MemCopyError::TooLong)
+        }
+        if count == 0 {
+            return Ok(())
+        }
+
+        let route = self.route();
+        let stream = route.get_stream();
+        route.clear_interrupt_flags(InterruptFlags::all());
+        // In memory-to-memory mode the "peripheral" side is the source.
+        stream.par.set(src as *const ());
+        stream.mar[0].set(dst as *const ());
+        stream.ndtr.set(Ndtr::default().with_ndt(count as u16));
+        // Memory-to-memory requires the FIFO.  Bursts of four words fill it
+        // exactly, so they need the full threshold.
+        stream.fcr.set(Fcr::default()
+                       .with_dmdis(true)
+                       .with_fth(if burst == Burst::Single {
+                           FifoThreshold::At50
+                       } else {
+                           FifoThreshold::At100
+                       }));
+        stream.cr.set(Cr::default()
+                      .with_dir(Direction::MemoryToMemory)
+                      .with_msize(size)
+                      .with_psize(size)
+                      .with_mburst(burst)
+                      .with_pburst(burst)
+                      .with_minc(true)
+                      .with_pinc(true));
+        stream.cr.update(|v| v.with_en(true));
+        Ok(())
+    }
+
+    /// Checks whether the copy started by `start` has finished.
+    pub fn poll(&self) -> Result<bool, MemCopyError> {
+        let route = self.route();
+        let flags = route.get_interrupt_flags().unwrap_or(TRANSFER_ERROR);
+        if flags.intersects(TRANSFER_ERROR | FIFO_ERROR) {
+            let _ = route.get_stream().abort();
+            Err(MemCopyError::Transfer)
+        } else {
+            Ok(flags.contains(TRANSFER_COMPLETE) || !self.is_busy())
+        }
+    }
+
+    /// Waits for the copy started by `start` to finish.
+    pub fn wait(&self) -> Result<(), MemCopyError> {
+        while !try!(self.poll()) {}
+        Ok(())
+    }
+
+    /// Copies `src` into `dst`, waiting until it's done.  Copies longer than
+    /// a single DMA operation allows are done in pieces.
+    pub fn copy(&self, dst: &mut [u8], src: &[u8])
+        -> Result<(), MemCopyError> {
+        if dst.len() != src.len() {
+            return Err(MemCopyError::LengthMismatch)
+        }
+        let (_, unit, _) = plan(dst.as_ptr() as usize
+                                | src.as_ptr() as usize
+                                | src.len());
+        // Keep each piece a whole number of bursts.
+        let piece = (MAX_TRANSFERS * unit) & !(BURST_BYTES - 1);
+        for (d, s) in dst.chunks_mut(piece).zip(src.chunks(piece)) {
+            // The borrows of `dst` and `src` outlive the transfer, because we
+            // wait for it.
+            try!(unsafe {
+                self.start_raw(d.as_mut_ptr() as usize, s.as_ptr() as usize,
+                               s.len(), d.len())
+            });
+            try!(self.wait());
+        }
+        Ok(())
+    }
+}
+
+/// Copies `src` into `dst` using DMA2 stream `stream`, waiting until it's
+/// done.  See `MemCopy` for details and an asynchronous version.
+pub fn mem_copy(stream: StreamIndex, dst: &mut [u8], src: &[u8])
+    -> Result<(), MemCopyError> {
+    MemCopy::new(stream).copy(dst, src)
+}
@@ -3,9 +3,16 @@
 #![allow(trivial_numeric_casts)]  // for bitflags :-(
 
 use core::mem;
-use arm_m::reg::Reg;
+use arm_m::reg::{mmio, Reg, RoReg, WoReg};
 use bits;
 
+pub mod double_buffer;
+pub mod mem_copy;
+pub mod request;
+
+pub use self::double_buffer::DoubleBuffer;
+pub use self::mem_copy::{mem_copy, MemCopy};
+pub use self::request::Request;
 
 /*******************************************************************************
  * Peripheral register layouts.
@@ -16,11 +23,11 @@ use bits;
 pub struct Dma {
     /// Interrupt status registers, described in the Reference Manual as LISR
     /// and HISR, but represented here as an array (in that order).
-    pub isr:  [Reg<Ir>; 2],
+    pub isr:  [RoReg<Ir>; 2],
 
     /// Interrupt flag clear registers, described in the Reference Manual as
     /// LIFCR and HIFCR, but represented here as an array (in that order).
-    pub ifcr: [Reg<Ir>; 2],
+    pub ifcr: [WoReg<Ir>; 2],
 
     /// Control registers for the eight hardware DMA streams.
     pub stream: [Stream; 8],
@@ -52,11 +59,33 @@ pub struct Stream {
     pub fcr:  Reg<Fcr>,
 }
 
+// The address registers are modeled as pointers, so the layout only matches on
+// 32-bit targets; these aren't checked by 64-bit `host-test` builds.
+#[cfg(target_pointer_width = "32")]
+register_layout! {
+    fn check_dma_layout: Dma [0xD0] {
+        isr @ 0x00,
+        ifcr @ 0x08,
+        stream @ 0x10,
+    }
+}
+
+#[cfg(target_pointer_width = "32")]
+register_layout! {
+    fn check_stream_layout: Stream [0x18] {
+        cr @ 0x00,
+        ndtr @ 0x04,
+        par @ 0x08,
+        mar @ 0x0C,
+        fcr @ 0x14,
+    }
+}
+
 /// Produces a shared reference to DMA1.
 #[inline]
 pub fn dma1() -> &'static Dma {
     unsafe {
-        &*(0x40026000 as *const Dma)
+        mmio(0x40026000)
     }
 }
 
@@ -64,7 +93,7 @@ pub fn dma1() -> &'static Dma {
 #[inline]
 pub fn dma2() -> &'static Dma {
     unsafe {
-        &*(0x40026400 as *const Dma)
+        mmio(0x40026400)
     }
 }
 
@@ -346,10 +375,11 @@ impl Dma {
     /// Clears a set of interrupt flags for a particular stream.
     ///
     /// This winds up writing one of the two `ifcr` registers, but is more
-    /// convenient than doing it by hand.
+    /// convenient than doing it by hand.  They're write-one-to-clear, so
+    /// other streams' flags, written as zero, are left alone.
     pub fn clear_interrupt_flags(&self, s: StreamIndex, flags: InterruptFlags) {
         self.ifcr[s.get_ir_index()]
-            .update(|v| v.with_rs(s.get_rs_index(), flags))
+            .set(Ir(0).with_rs(s.get_rs_index(), flags))
     }
 
     /// Reads the current set of interrupt flags for a particular stream.
@@ -370,7 +400,124 @@ pub enum StreamIndex {
     S0, S1, S2, S3, S4, S5, S6, S7
 }
 
+/// Names the DMA controller, stream, and channel used to serve a particular
+/// peripheral request.  Which combinations are valid is given by the request
+/// mapping tables in the Reference Manual, so routes can't be built by hand:
+/// get them from a `Request`.
+#[derive(Copy, Clone)]
+pub struct DmaRoute {
+    /// Accessor for the controller, `dma1` or `dma2`.
+    dma: fn() -> &'static Dma,
+    /// Stream on that controller.
+    stream: StreamIndex,
+    /// Request channel selected on that stream.
+    channel: Channel,
+}
+
+impl DmaRoute {
+    /// Gets the stream's index on its controller.
+    #[inline]
+    pub fn get_stream_index(&self) -> StreamIndex {
+        self.stream
+    }
+
+    /// Gets the request channel to select on the stream (`Cr::with_chsel`).
+    #[inline]
+    pub fn get_channel(&self) -> Channel {
+        self.channel
+    }
+
+    /// Produces a shared reference to the route's controller.
+    #[inline]
+    pub fn get_dma(&self) -> &'static Dma {
+        (self.dma)()
+    }
+
+    /// Produces a shared reference to the route's stream registers.
+    #[inline]
+    pub fn get_stream(&self) -> &'static Stream {
+        &self.get_dma().stream[self.stream as usize]
+    }
+
+    /// Reads the interrupt flags for the route's stream.
+    pub fn get_interrupt_flags(&self) -> bits::BitsResult<InterruptFlags> {
+        self.get_dma().get_interrupt_flags(self.stream)
+    }
+
+    /// Clears interrupt flags for the route's stream.
+    pub fn clear_interrupt_flags(&self, flags: InterruptFlags) {
+        self.get_dma().clear_interrupt_flags(self.stream, flags)
+    }
+}
+
+impl Stream {
+    /// Checks whether the stream is enabled (i.e. a transfer is in progress).
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.cr.get().get_en()
+    }
+
+    /// Disables the stream and waits for any in-flight transfer to wind down.
+    /// Prefer `abort`, which also cleans up the interrupt flags.
+    pub fn disable(&self) {
+        self.cr.update(|v| v.with_en(false));
+        while self.is_enabled() {}
+    }
+
+    /// Reads the number of transfers left to do (NDTR).  After a transfer
+    /// stops early, this tells how far it got.
+    #[inline]
+    pub fn remaining(&self) -> u16 {
+        self.ndtr.get().get_ndt()
+    }
+
+    /// Stops the stream safely: clears EN, waits for the current transfer to
+    /// finish (EN only reads back as zero once it has, and the stream can't
+    /// be reconfigured before then), then clears the stream's interrupt
+    /// flags so that stale flags don't confuse the next user or block
+    /// re-enabling.  Returns the number of transfers that were left undone.
+    pub fn abort(&self) -> u16 {
+        self.disable();
+        let (dma, index) = self.locate();
+        dma.clear_interrupt_flags(index, InterruptFlags::all());
+        self.remaining()
+    }
+
+    /// Finds the controller this stream belongs to, and its index there.
+    fn locate(&self) -> (&'static Dma, StreamIndex) {
+        let addr = self as *const Stream as usize;
+        for &dma in &[dma1(), dma2()] {
+            let first = &dma.stream[0] as *const Stream as usize;
+            let end = first + mem::size_of::<[Stream; 8]>();
+            if addr >= first && addr < end {
+                let i = (addr - first) / mem::size_of::<Stream>();
+                return (dma, StreamIndex::from_index(i))
+            }
+        }
+        unreachable!()
+    }
+}
+
 impl StreamIndex {
+    /// Converts a stream number (0-7) into a `StreamIndex`.
+    ///
+    /// # Panics
+    ///
+    /// If `i` is out of range.
+    pub fn from_index(i: usize) -> StreamIndex {
+        match i {
+            0 => StreamIndex::S0,
+            1 => StreamIndex::S1,
+            2 => StreamIndex::S2,
+            3 => StreamIndex::S3,
+            4 => StreamIndex::S4,
+            5 => StreamIndex::S5,
+            6 => StreamIndex::S6,
+            7 => StreamIndex::S7,
+            _ => panic!("bad DMA stream"),
+        }
+    }
+
     /// Converts a stream index into the corresponding index into the interrupt
     /// register arrays `isr` and `ifcr`.
     pub fn get_ir_index(self) -> usize {
@@ -385,3 +532,21 @@ impl StreamIndex {
         }
     }
 }
+
+/// Element types that DMA can move in a single transfer.
+pub trait DmaWord: Copy {
+    /// The transfer size matching this type.
+    fn transfer_size() -> TransferSize;
+}
+
+impl DmaWord for u8 {
+    fn transfer_size() -> TransferSize { TransferSize::Byte }
+}
+
+impl DmaWord for u16 {
+    fn transfer_size() -> TransferSize { TransferSize::HalfWord }
+}
+
+impl DmaWord for u32 {
+    fn transfer_size() -> TransferSize { TransferSize::Word }
+}
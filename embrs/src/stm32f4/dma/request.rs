@@ -0,0 +1,169 @@
+//! The DMA request mapping: which stream and channel of which controller
+//! serves each peripheral's requests (RM0090 tables 42 and 43).
+//!
+//! Each `Request` names one cell of those tables, so anything built from a
+//! `Request` is routed correctly by construction.  Requests are named after
+//! the peripheral signal, as in the Reference Manual; where a signal can be
+//! served by two streams, both are available, suffixed with the stream
+//! number (e.g. `Usart1RxS2` and `Usart1RxS5`).  Where the Reference Manual
+//! lists several timer signals sharing a cell, the name lists them all.
+//!
+//! Only peripherals present on the STM32F40x/41x are included.
+
+use super::{dma1, dma2, Channel, DmaRoute, StreamIndex};
+
+macro_rules! request_table {
+    ($($name:ident => $dma:ident, $stream:ident, $channel:ident;)*) => {
+        /// Names a peripheral DMA request line, as served by a particular
+        /// stream.
+        #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+        pub enum Request {
+            $($name,)*
+        }
+
+        impl Request {
+            /// Gets the controller, stream, and channel serving this request.
+            pub fn route(self) -> DmaRoute {
+                match self {
+                    $(
+                        Request::$name => DmaRoute {
+                            dma: $dma,
+                            stream: StreamIndex::$stream,
+                            channel: Channel::$channel,
+                        },
+                    )*
+                }
+            }
+        }
+    };
+}
+
+request_table! {
+    // DMA1, channel 0.
+    Spi3RxS0 => dma1, S0, Ch0;
+    Spi3RxS2 => dma1, S2, Ch0;
+    Spi2Rx => dma1, S3, Ch0;
+    Spi2Tx => dma1, S4, Ch0;
+    Spi3TxS5 => dma1, S5, Ch0;
+    Spi3TxS7 => dma1, S7, Ch0;
+
+    // DMA1, channel 1.
+    I2c1RxS0 => dma1, S0, Ch1;
+    Tim7UpS2 => dma1, S2, Ch1;
+    Tim7UpS4 => dma1, S4, Ch1;
+    I2c1RxS5 => dma1, S5, Ch1;
+    I2c1TxS6 => dma1, S6, Ch1;
+    I2c1TxS7 => dma1, S7, Ch1;
+
+    // DMA1, channel 2.
+    Tim4Ch1 => dma1, S0, Ch2;
+    I2s3ExtRxS2 => dma1, S2, Ch2;
+    Tim4Ch2 => dma1, S3, Ch2;
+    I2s2ExtTx => dma1, S4, Ch2;
+    I2s3ExtTx => dma1, S5, Ch2;
+    Tim4Up => dma1, S6, Ch2;
+    Tim4Ch3 => dma1, S7, Ch2;
+
+    // DMA1, channel 3.
+    I2s3ExtRxS0 => dma1, S0, Ch3;
+    Tim2UpCh3 => dma1, S1, Ch3;
+    I2c3Rx => dma1, S2, Ch3;
+    I2s2ExtRx => dma1, S3, Ch3;
+    I2c3Tx => dma1, S4, Ch3;
+    Tim2Ch1 => dma1, S5, Ch3;
+    Tim2Ch2Ch4 => dma1, S6, Ch3;
+    Tim2UpCh4 => dma1, S7, Ch3;
+
+    // DMA1, channel 4.
+    Uart5Rx => dma1, S0, Ch4;
+    Usart3Rx => dma1, S1, Ch4;
+    Uart4Rx => dma1, S2, Ch4;
+    Usart3TxS3 => dma1, S3, Ch4;
+    Uart4Tx => dma1, S4, Ch4;
+    Usart2Rx => dma1, S5, Ch4;
+    Usart2Tx => dma1, S6, Ch4;
+    Uart5Tx => dma1, S7, Ch4;
+
+    // DMA1, channel 5.
+    Tim3Ch4Up => dma1, S2, Ch5;
+    Tim3Ch1Trig => dma1, S4, Ch5;
+    Tim3Ch2 => dma1, S5, Ch5;
+    Tim3Ch3 => dma1, S7, Ch5;
+
+    // DMA1, channel 6.
+    Tim5Ch3Up => dma1, S0, Ch6;
+    Tim5Ch4TrigS1 => dma1, S1, Ch6;
+    Tim5Ch1 => dma1, S2, Ch6;
+    Tim5Ch4TrigS3 => dma1, S3, Ch6;
+    Tim5Ch2 => dma1, S4, Ch6;
+    Tim5Up => dma1, S6, Ch6;
+
+    // DMA1, channel 7.
+    Tim6Up => dma1, S1, Ch7;
+    I2c2RxS2 => dma1, S2, Ch7;
+    I2c2RxS3 => dma1, S3, Ch7;
+    Usart3TxS4 => dma1, S4, Ch7;
+    Dac1 => dma1, S5, Ch7;
+    Dac2 => dma1, S6, Ch7;
+    I2c2Tx => dma1, S7, Ch7;
+
+    // DMA2, channel 0.
+    Adc1S0 => dma2, S0, Ch0;
+    Tim8Ch1Ch2Ch3 => dma2, S2, Ch0;
+    Adc1S4 => dma2, S4, Ch0;
+    Tim1Ch1Ch2Ch3 => dma2, S6, Ch0;
+
+    // DMA2, channel 1.
+    DcmiS1 => dma2, S1, Ch1;
+    Adc2S2 => dma2, S2, Ch1;
+    Adc2S3 => dma2, S3, Ch1;
+    DcmiS7 => dma2, S7, Ch1;
+
+    // DMA2, channel 2.
+    Adc3S0 => dma2, S0, Ch2;
+    Adc3S1 => dma2, S1, Ch2;
+    CrypOut => dma2, S5, Ch2;
+    CrypIn => dma2, S6, Ch2;
+    HashIn => dma2, S7, Ch2;
+
+    // DMA2, channel 3.
+    Spi1RxS0 => dma2, S0, Ch3;
+    Spi1RxS2 => dma2, S2, Ch3;
+    Spi1TxS3 => dma2, S3, Ch3;
+    Spi1TxS5 => dma2, S5, Ch3;
+
+    // DMA2, channel 4.
+    Usart1RxS2 => dma2, S2, Ch4;
+    SdioS3 => dma2, S3, Ch4;
+    Usart1RxS5 => dma2, S5, Ch4;
+    SdioS6 => dma2, S6, Ch4;
+    Usart1Tx => dma2, S7, Ch4;
+
+    // DMA2, channel 5.
+    Usart6RxS1 => dma2, S1, Ch5;
+    Usart6RxS2 => dma2, S2, Ch5;
+    Usart6TxS6 => dma2, S6, Ch5;
+    Usart6TxS7 => dma2, S7, Ch5;
+
+    // DMA2, channel 6.
+    Tim1Trig => dma2, S0, Ch6;
+    Tim1Ch1S1 => dma2, S1, Ch6;
+    Tim1Ch2 => dma2, S2, Ch6;
+    Tim1Ch1S3 => dma2, S3, Ch6;
+    Tim1Ch4TrigCom => dma2, S4, Ch6;
+    Tim1Up => dma2, S5, Ch6;
+    Tim1Ch3 => dma2, S6, Ch6;
+
+    // DMA2, channel 7.
+    Tim8Up => dma2, S1, Ch7;
+    Tim8Ch1 => dma2, S2, Ch7;
+    Tim8Ch2 => dma2, S3, Ch7;
+    Tim8Ch3 => dma2, S4, Ch7;
+    Tim8Ch4TrigCom => dma2, S7, Ch7;
+}
+
+impl From<Request> for DmaRoute {
+    fn from(r: Request) -> DmaRoute {
+        r.route()
+    }
+}
@@ -0,0 +1,57 @@
+//! Workarounds for known STM32F4 silicon errata.
+//!
+//! Each workaround is a named function here, called by the affected driver at
+//! the point where the erratum bites.  Each can be compiled out individually
+//! by disabling the corresponding `erratum:*` Cargo feature (they are all on
+//! by default), in which case the function becomes a no-op.
+//!
+//! The `ERRATA` table lists every workaround this crate knows about and
+//! whether it was compiled in, so that applications can report it at runtime.
+//!
+//! Erratum numbers refer to ST's ES0182 (STM32F405/407/415/417 errata sheet).
+
+use core::iter::Filter;
+use core::slice::Iter;
+
+use arm_m;
+
+/// Describes a known erratum and whether its workaround is compiled in.
+pub struct Erratum {
+    /// Cargo feature name controlling the workaround.
+    pub feature: &'static str,
+    /// Section number in ST's errata sheet.
+    pub section: &'static str,
+    /// One-line summary of the problem.
+    pub summary: &'static str,
+    /// Whether the workaround was compiled in.
+    pub applied: bool,
+}
+
+/// Every erratum workaround known to this crate.
+pub static ERRATA : [Erratum; 1] = [
+    Erratum {
+        feature: "erratum:rcc_enable_delay",
+        section: "2.1.13",
+        summary: "Delay after an RCC peripheral clock enabling",
+        applied: cfg!(feature = "erratum:rcc_enable_delay"),
+    },
+];
+
+/// Iterates over the errata whose workarounds were compiled in.
+pub fn applied() -> Filter<Iter<'static, Erratum>, fn(&&Erratum) -> bool> {
+    fn is_applied(e: &&Erratum) -> bool { e.applied }
+    ERRATA.iter().filter(is_applied as fn(&&Erratum) -> bool)
+}
+
+/// ES0182 2.1.13: after a peripheral clock is enabled in the RCC, there is a
+/// delay of a couple of bus cycles before the peripheral's registers respond.
+/// Accesses issued in that window are silently dropped.
+///
+/// Call this after any write to an `xxxENR` register.  It issues a `DSB` to
+/// ensure the write has completed before we go on.
+#[inline]
+pub fn rcc_enable_delay() {
+    if cfg!(feature = "erratum:rcc_enable_delay") {
+        arm_m::data_synchronization_barrier()
+    }
+}
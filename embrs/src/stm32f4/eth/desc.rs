@@ -0,0 +1,355 @@
+//! Ethernet DMA descriptors.
+//!
+//! Frames move between memory and the MAC through rings of descriptors, each
+//! naming a buffer and carrying the frame's status.  A descriptor belongs
+//! either to software or to the DMA engine, as recorded in its OWN bit:
+//! software fills one in and hands it over; the engine processes it and hands
+//! it back.  Neither side may touch a descriptor the other owns.
+//!
+//! A ring is an array of descriptors laid end to end, whose last descriptor
+//! is created with `new_last` so that the engine wraps around:
+//!
+//!     static TX_RING: [TxDescriptor; 2] = [
+//!         TxDescriptor::new(),
+//!         TxDescriptor::new_last(),
+//!     ];
+//!
+//! Descriptors here are in the enhanced format (see `Dmabmr::get_edfe`), with
+//! one buffer each.
+
+use core::cell::UnsafeCell;
+use core::ptr;
+
+use arm_m;
+use super::ptp::Timestamp;
+
+/// Largest buffer a descriptor can describe.
+pub const MAX_BUFFER_LEN : usize = 0x1FFF;
+
+bit_wrappers! {
+    /// Wrapper for the first word of a transmit descriptor, TDES0: control
+    /// bits set by software, and status written back by the DMA engine.
+    pub struct Tdes0(pub u32);
+    /// Wrapper for the first word of a receive descriptor, RDES0: status
+    /// written back by the DMA engine.
+    pub struct Rdes0(pub u32);
+    /// Wrapper for a receive descriptor's extended status word, RDES4.
+    pub struct Rdes4(pub u32);
+}
+
+impl Tdes0 {
+    bitfield_accessors! {
+        pub total [31] get_own / with_own: bool,
+        /// Raise `TRANSMIT` when this frame is sent.
+        pub total [30] get_ic / with_ic: bool,
+        /// Last segment of the frame.
+        pub total [29] get_ls / with_ls: bool,
+        /// First segment of the frame.
+        pub total [28] get_fs / with_fs: bool,
+        /// Don't append a CRC.
+        pub total [27] get_dc / with_dc: bool,
+        /// Don't pad short frames.
+        pub total [26] get_dp / with_dp: bool,
+        /// Capture a transmit timestamp.
+        pub total [25] get_ttse / with_ttse: bool,
+        pub total [23:22] get_cic / with_cic: ChecksumInsertion,
+        /// End of ring.
+        pub total [21] get_ter / with_ter: bool,
+        /// A timestamp was captured (status).
+        pub total [17] get_ttss / with_ttss: bool,
+        /// IP header error (status).
+        pub total [16] get_ihe / with_ihe: bool,
+        /// Error summary (status).
+        pub total [15] get_es / with_es: bool,
+        pub total [14] get_jt / with_jt: bool,
+        pub total [13] get_ff / with_ff: bool,
+        /// IP payload error (status).
+        pub total [12] get_ipe / with_ipe: bool,
+        pub total [11] get_lca / with_lca: bool,
+        pub total [10] get_nc / with_nc: bool,
+        pub total [9] get_lco / with_lco: bool,
+        pub total [8] get_ec / with_ec: bool,
+        pub total [7] get_vf / with_vf: bool,
+        /// Collision count (status).
+        pub total [6:3] get_cc / with_cc: u32,
+        pub total [2] get_ed / with_ed: bool,
+        pub total [1] get_uf / with_uf: bool,
+        pub total [0] get_db / with_db: bool,
+    }
+}
+
+impl Rdes0 {
+    bitfield_accessors! {
+        pub total [31] get_own / with_own: bool,
+        /// Destination address filter failed (only seen when the filter is
+        /// set to pass failures through).
+        pub total [30] get_afm / with_afm: bool,
+        /// Frame length, including the CRC.
+        pub total [29:16] get_fl / with_fl: u32,
+        /// Error summary.
+        pub total [15] get_es / with_es: bool,
+        pub total [14] get_de / with_de: bool,
+        pub total [13] get_saf / with_saf: bool,
+        pub total [12] get_le / with_le: bool,
+        pub total [11] get_oe / with_oe: bool,
+        pub total [10] get_vlan / with_vlan: bool,
+        pub total [9] get_fs / with_fs: bool,
+        pub total [8] get_ls / with_ls: bool,
+        /// With timestamping enabled, a timestamp was captured.  (Otherwise,
+        /// an IP header checksum error.)
+        pub total [7] get_tsv / with_tsv: bool,
+        pub total [6] get_lco / with_lco: bool,
+        pub total [5] get_ft / with_ft: bool,
+        pub total [4] get_rwt / with_rwt: bool,
+        pub total [3] get_re / with_re: bool,
+        pub total [2] get_dribble / with_dribble: bool,
+        pub total [1] get_ce / with_ce: bool,
+        /// The extended status word (`Rdes4`) is valid.
+        pub total [0] get_esa / with_esa: bool,
+    }
+}
+
+impl Rdes4 {
+    bitfield_accessors! {
+        /// The frame is PTP version 2 (else version 1).
+        pub total [13] get_ptpv / with_ptpv: bool,
+        /// The PTP message came over Ethernet (else over UDP).
+        pub total [12] get_ptpft / with_ptpft: bool,
+        pub total [11:8] get_pmt / with_pmt: u32,
+        pub total [7] get_ipv6pr / with_ipv6pr: bool,
+        pub total [6] get_ipv4pr / with_ipv4pr: bool,
+        pub total [5] get_ipcb / with_ipcb: bool,
+        pub total [4] get_ippe / with_ippe: bool,
+        pub total [3] get_iphe / with_iphe: bool,
+        pub total [2:0] get_ippt / with_ippt: u32,
+    }
+}
+
+bit_enums! {
+    /// Checksums the MAC can compute and insert into transmitted frames.
+    /// Anything but `None` requires transmit store-and-forward.
+    pub bit_enum ChecksumInsertion {
+        None = 0b00,
+        IpHeader = 0b01,
+        /// IP header and TCP/UDP/ICMP payload, with the pseudo-header
+        /// checksum supplied by software in the frame.
+        IpHeaderAndPayload = 0b10,
+        /// IP header and TCP/UDP/ICMP payload, pseudo-header included.
+        Full = 0b11,
+    }
+}
+
+/// Per-frame transmit options.
+#[derive(Copy, Clone, Debug)]
+pub struct TxOptions {
+    /// Raise `TRANSMIT` when the frame has been sent.
+    pub interrupt: bool,
+    /// Capture the time the frame was sent (see `TxDescriptor::timestamp`).
+    pub timestamp: bool,
+    pub checksum: ChecksumInsertion,
+}
+
+/// Options for an ordinary frame.
+pub const DEFAULT_TX_OPTIONS : TxOptions = TxOptions {
+    interrupt: true,
+    timestamp: false,
+    checksum: ChecksumInsertion::None,
+};
+
+const OWN : u32 = 1 << 31;
+const TDES0_TER : u32 = 1 << 21;
+const RDES1_RER : u32 = 1 << 15;
+
+#[inline]
+fn get(word: &UnsafeCell<u32>) -> u32 {
+    unsafe { ptr::read_volatile(word.get()) }
+}
+
+#[inline]
+fn put(word: &UnsafeCell<u32>, v: u32) {
+    unsafe { ptr::write_volatile(word.get(), v) }
+}
+
+/// A transmit descriptor (enhanced format).
+#[repr(C)]
+pub struct TxDescriptor {
+    tdes0: UnsafeCell<u32>,
+    tdes1: UnsafeCell<u32>,
+    buf1: UnsafeCell<u32>,
+    _buf2: UnsafeCell<u32>,
+    _reserved: [UnsafeCell<u32>; 2],
+    ts_lo: UnsafeCell<u32>,
+    ts_hi: UnsafeCell<u32>,
+}
+
+unsafe impl Sync for TxDescriptor {}
+
+impl TxDescriptor {
+    const fn with_tdes0(tdes0: u32) -> TxDescriptor {
+        TxDescriptor {
+            tdes0: UnsafeCell::new(tdes0),
+            tdes1: UnsafeCell::new(0),
+            buf1: UnsafeCell::new(0),
+            _buf2: UnsafeCell::new(0),
+            _reserved: [UnsafeCell::new(0), UnsafeCell::new(0)],
+            ts_lo: UnsafeCell::new(0),
+            ts_hi: UnsafeCell::new(0),
+        }
+    }
+
+    /// Creates an idle descriptor, owned by software.
+    pub const fn new() -> TxDescriptor {
+        TxDescriptor::with_tdes0(0)
+    }
+
+    /// Creates an idle descriptor that ends its ring.
+    pub const fn new_last() -> TxDescriptor {
+        TxDescriptor::with_tdes0(TDES0_TER)
+    }
+
+    pub fn is_owned_by_dma(&self) -> bool {
+        get(&self.tdes0) & OWN != 0
+    }
+
+    /// Reads the control and status word.  Status is only meaningful once the
+    /// DMA engine has handed the descriptor back.
+    pub fn status(&self) -> Tdes0 {
+        Tdes0(get(&self.tdes0))
+    }
+
+    /// Hands `frame` (destination address through payload, without CRC) to
+    /// the DMA engine for transmission.  Follow up with `Eth::resume_tx`.
+    ///
+    /// # Panics
+    ///
+    /// If the DMA engine owns the descriptor, or the frame is longer than
+    /// `MAX_BUFFER_LEN`.
+    pub fn submit(&self, frame: &'static [u8], opts: &TxOptions) {
+        assert!(!self.is_owned_by_dma());
+        assert!(frame.len() <= MAX_BUFFER_LEN);
+
+        put(&self.buf1, frame.as_ptr() as u32);
+        put(&self.tdes1, frame.len() as u32);
+
+        let tdes0 = Tdes0(get(&self.tdes0) & TDES0_TER)
+            .with_fs(true)
+            .with_ls(true)
+            .with_ic(opts.interrupt)
+            .with_ttse(opts.timestamp)
+            .with_cic(opts.checksum);
+        // The buffer and length must land before ownership passes.
+        arm_m::data_memory_barrier();
+        put(&self.tdes0, tdes0.with_own(true).0)
+    }
+
+    /// Gets the time the frame was sent, if it asked for a timestamp and
+    /// the DMA engine has handed the descriptor back.
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        let s = self.status();
+        if s.get_own() || !s.get_ttss() {
+            return None
+        }
+        Some(Timestamp {
+            seconds: get(&self.ts_hi),
+            subseconds: get(&self.ts_lo),
+        })
+    }
+}
+
+/// A receive descriptor (enhanced format).
+#[repr(C)]
+pub struct RxDescriptor {
+    rdes0: UnsafeCell<u32>,
+    rdes1: UnsafeCell<u32>,
+    buf1: UnsafeCell<u32>,
+    _buf2: UnsafeCell<u32>,
+    rdes4: UnsafeCell<u32>,
+    _reserved: UnsafeCell<u32>,
+    ts_lo: UnsafeCell<u32>,
+    ts_hi: UnsafeCell<u32>,
+}
+
+unsafe impl Sync for RxDescriptor {}
+
+impl RxDescriptor {
+    const fn with_rdes1(rdes1: u32) -> RxDescriptor {
+        RxDescriptor {
+            rdes0: UnsafeCell::new(0),
+            rdes1: UnsafeCell::new(rdes1),
+            buf1: UnsafeCell::new(0),
+            _buf2: UnsafeCell::new(0),
+            rdes4: UnsafeCell::new(0),
+            _reserved: UnsafeCell::new(0),
+            ts_lo: UnsafeCell::new(0),
+            ts_hi: UnsafeCell::new(0),
+        }
+    }
+
+    /// Creates an unarmed descriptor, owned by software.
+    pub const fn new() -> RxDescriptor {
+        RxDescriptor::with_rdes1(0)
+    }
+
+    /// Creates an unarmed descriptor that ends its ring.
+    pub const fn new_last() -> RxDescriptor {
+        RxDescriptor::with_rdes1(RDES1_RER)
+    }
+
+    pub fn is_owned_by_dma(&self) -> bool {
+        get(&self.rdes0) & OWN != 0
+    }
+
+    /// Reads the status word.  Only meaningful once the DMA engine has handed
+    /// the descriptor back.
+    pub fn status(&self) -> Rdes0 {
+        Rdes0(get(&self.rdes0))
+    }
+
+    /// Reads the extended status word, if `status().get_esa()`.
+    pub fn extended_status(&self) -> Rdes4 {
+        Rdes4(get(&self.rdes4))
+    }
+
+    /// Length of the received frame, including its CRC.  A frame is only
+    /// complete in one buffer if `status()` shows both `fs` and `ls`.
+    pub fn frame_len(&self) -> usize {
+        self.status().get_fl() as usize
+    }
+
+    /// Hands `buf` to the DMA engine to receive into.  Only a multiple of
+    /// four bytes is used.  Follow up with `Eth::resume_rx` if the receive
+    /// process had run out of buffers.
+    ///
+    /// # Panics
+    ///
+    /// If the DMA engine owns the descriptor.
+    pub fn arm(&self, buf: &'static mut [u8]) {
+        assert!(!self.is_owned_by_dma());
+
+        let len = if buf.len() > MAX_BUFFER_LEN {
+            MAX_BUFFER_LEN
+        } else {
+            buf.len()
+        };
+        let len = len & !3;
+
+        put(&self.buf1, buf.as_mut_ptr() as u32);
+        put(&self.rdes1, (get(&self.rdes1) & RDES1_RER) | len as u32);
+        arm_m::data_memory_barrier();
+        put(&self.rdes0, OWN)
+    }
+
+    /// Gets the time the frame was received, if timestamping was enabled for
+    /// frames of its kind (see `ptp`) and the DMA engine has handed the
+    /// descriptor back.
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        let s = self.status();
+        if s.get_own() || !s.get_ls() || !s.get_tsv() {
+            return None
+        }
+        Some(Timestamp {
+            seconds: get(&self.ts_hi),
+            subseconds: get(&self.ts_lo),
+        })
+    }
+}
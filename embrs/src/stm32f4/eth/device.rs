@@ -0,0 +1,242 @@
+//! The Ethernet MAC as a `net::NetDevice`.
+//!
+//! `EthDevice` owns a pair of descriptor rings and a buffer for each
+//! descriptor, and walks the rings on behalf of an IP stack:
+//!
+//!     static TX_RING: [TxDescriptor; 4] = [ ... ];
+//!     static RX_RING: [RxDescriptor; 4] = [ ... ];
+//!     static mut TX_BUFS: [Buffer; 4] = [[0; BUFFER_LEN]; 4];
+//!     static mut RX_BUFS: [Buffer; 4] = [[0; BUFFER_LEN]; 4];
+//!
+//!     let mut dev = EthDevice::new(&TX_RING, unsafe { &mut TX_BUFS },
+//!                                  &RX_RING, unsafe { &mut RX_BUFS },
+//!                                  &mac, true);
+//!     ETH.start(&TX_RING, &RX_RING, &DEFAULT_DMA_CONFIG);
+//!
+//! Each buffer holds a whole frame, so frames are never split across
+//! descriptors.  Checksum offload requires `tx_store_forward` in the
+//! `DmaConfig`.
+
+use core::slice;
+
+use net::{Capabilities, ChecksumCapabilities, NetDevice, NetError, Offload,
+          RxToken, TxToken};
+use super::ETH;
+use super::desc::{ChecksumInsertion, RxDescriptor, TxDescriptor, TxOptions};
+
+/// Bytes in each frame buffer: a maximum-length frame with CRC, rounded up.
+pub const BUFFER_LEN : usize = 1536;
+/// Longest frame, without CRC.
+pub const MTU : usize = 1514;
+/// Bytes of CRC at the end of each received frame.
+const CRC_LEN : usize = 4;
+
+pub type Buffer = [u8; BUFFER_LEN];
+
+/// The Ethernet MAC's rings and buffers.
+pub struct EthDevice {
+    tx_ring: &'static [TxDescriptor],
+    tx_bufs: &'static mut [Buffer],
+    tx_next: usize,
+    rx_ring: &'static [RxDescriptor],
+    rx_bufs: &'static mut [Buffer],
+    rx_next: usize,
+    mac: [u8; 6],
+    offload: bool,
+}
+
+impl EthDevice {
+    /// Takes over the rings and their buffers (one per descriptor), arms
+    /// the receive ring, and sets the MAC's address to `mac`.  With
+    /// `offload`, the MAC checks and inserts IPv4, TCP, UDP and ICMP
+    /// checksums.  Call this after `Eth::reset`, and before `Eth::start`.
+    ///
+    /// # Panics
+    ///
+    /// If a ring and its buffers differ in number.
+    pub fn new(tx_ring: &'static [TxDescriptor],
+               tx_bufs: &'static mut [Buffer],
+               rx_ring: &'static [RxDescriptor],
+               rx_bufs: &'static mut [Buffer],
+               mac: &[u8; 6],
+               offload: bool)
+        -> EthDevice {
+        assert!(tx_ring.len() == tx_bufs.len());
+        assert!(rx_ring.len() == rx_bufs.len());
+
+        for (desc, buf) in rx_ring.iter().zip(rx_bufs.iter_mut()) {
+            arm(desc, buf)
+        }
+        ETH.set_mac_address(mac);
+        ETH.update_maccr(|v| v.with_ipco(offload));
+
+        EthDevice {
+            tx_ring: tx_ring,
+            tx_bufs: tx_bufs,
+            tx_next: 0,
+            rx_ring: rx_ring,
+            rx_bufs: rx_bufs,
+            rx_next: 0,
+            mac: *mac,
+            offload: offload,
+        }
+    }
+
+    fn tx_options(&self) -> TxOptions {
+        TxOptions {
+            interrupt: true,
+            timestamp: false,
+            checksum: if self.offload {
+                ChecksumInsertion::Full
+            } else {
+                ChecksumInsertion::None
+            },
+        }
+    }
+
+    /// Checks whether the frame in software-owned `desc` should be dropped:
+    /// damaged, split across buffers, or failing a checksum we offload.
+    fn is_bad(&self, desc: &RxDescriptor) -> bool {
+        let s = desc.status();
+        if s.get_es() || !s.get_fs() || !s.get_ls()
+            || desc.frame_len() < CRC_LEN {
+            return true
+        }
+        if self.offload && s.get_esa() {
+            let x = desc.extended_status();
+            return x.get_iphe() || x.get_ippe()
+        }
+        false
+    }
+}
+
+/// Hands `buf` to `desc` for reception.
+fn arm(desc: &RxDescriptor, buf: &mut Buffer) {
+    // The DMA engine only writes the buffer while it owns the descriptor,
+    // and we only read it while we do.
+    let buf = unsafe { slice::from_raw_parts_mut(buf.as_mut_ptr(), buf.len()) };
+    desc.arm(buf);
+    ETH.resume_rx()
+}
+
+/// A received frame, in its buffer.  The buffer goes back to the DMA engine
+/// when the token is dropped.
+pub struct EthRxToken<'a> {
+    desc: &'static RxDescriptor,
+    buf: &'a mut Buffer,
+    len: usize,
+}
+
+impl<'a> RxToken for EthRxToken<'a> {
+    fn consume<R, F: FnOnce(&[u8]) -> R>(self, f: F) -> R {
+        f(&self.buf[..self.len])
+    }
+}
+
+impl<'a> Drop for EthRxToken<'a> {
+    fn drop(&mut self) {
+        arm(self.desc, self.buf)
+    }
+}
+
+/// The right to send a frame from the next transmit buffer.
+pub struct EthTxToken<'a> {
+    ring: &'static [TxDescriptor],
+    bufs: &'a mut [Buffer],
+    next: &'a mut usize,
+    opts: TxOptions,
+}
+
+impl<'a> TxToken for EthTxToken<'a> {
+    fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F)
+        -> Result<R, NetError> {
+        if len > MTU {
+            return Err(NetError::FrameTooLong)
+        }
+        let EthTxToken { ring, bufs, next, opts } = self;
+        let i = *next;
+        let desc = &ring[i];
+        // A token handed out with a receive token doesn't promise a free
+        // buffer.
+        if desc.is_owned_by_dma() {
+            return Err(NetError::Exhausted)
+        }
+
+        let r = f(&mut bufs[i][..len]);
+        // As with receive buffers, we don't touch this one again until the
+        // DMA engine has given back the descriptor.
+        let frame = unsafe { slice::from_raw_parts(bufs[i].as_ptr(), len) };
+        desc.submit(frame, &opts);
+        ETH.resume_tx();
+        *next = (i + 1) % ring.len();
+        Ok(r)
+    }
+}
+
+impl<'a> NetDevice<'a> for EthDevice {
+    type Rx = EthRxToken<'a>;
+    type Tx = EthTxToken<'a>;
+
+    fn receive(&'a mut self) -> Option<(EthRxToken<'a>, EthTxToken<'a>)> {
+        let ring = self.rx_ring;
+        let i;
+        loop {
+            let n = self.rx_next;
+            if ring[n].is_owned_by_dma() {
+                return None
+            }
+            self.rx_next = (n + 1) % ring.len();
+            if self.is_bad(&ring[n]) {
+                arm(&ring[n], &mut self.rx_bufs[n]);
+                continue
+            }
+            i = n;
+            break
+        }
+
+        let opts = self.tx_options();
+        let rx = EthRxToken {
+            desc: &ring[i],
+            buf: &mut self.rx_bufs[i],
+            len: ring[i].frame_len() - CRC_LEN,
+        };
+        let tx = EthTxToken {
+            ring: self.tx_ring,
+            bufs: &mut *self.tx_bufs,
+            next: &mut self.tx_next,
+            opts: opts,
+        };
+        Some((rx, tx))
+    }
+
+    fn transmit(&'a mut self) -> Option<EthTxToken<'a>> {
+        if self.tx_ring[self.tx_next].is_owned_by_dma() {
+            return None
+        }
+        let opts = self.tx_options();
+        Some(EthTxToken {
+            ring: self.tx_ring,
+            bufs: &mut *self.tx_bufs,
+            next: &mut self.tx_next,
+            opts: opts,
+        })
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let offload = if self.offload { Offload::Both } else { Offload::None };
+        Capabilities {
+            mtu: MTU,
+            max_burst_size: Some(self.tx_ring.len()),
+            checksum: ChecksumCapabilities {
+                ipv4: offload,
+                udp: offload,
+                tcp: offload,
+                icmpv4: offload,
+            },
+        }
+    }
+
+    fn mac_address(&self) -> [u8; 6] {
+        self.mac
+    }
+}
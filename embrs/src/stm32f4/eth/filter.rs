@@ -0,0 +1,240 @@
+//! Receive address filtering.
+//!
+//! The MAC decides which received frames reach memory by destination
+//! address, in up to three ways at once:
+//!
+//! - Our own address, set by `Eth::set_mac_address`.
+//! - Three more *perfect* filters, each matching one address exactly, or
+//!   with some bytes ignored, and optionally on the source address instead.
+//! - A 64-bin hash of addresses, for the multicast groups a protocol stack
+//!   has joined.  Hashing lets some unwanted groups through, so software must
+//!   still check; but it's a great deal better than being promiscuous.
+//!
+//! `FilterConfig` says which of these apply to unicast and multicast frames.
+//! Broadcasts pass unless blocked.
+
+use super::{address_words, Eth};
+
+bit_wrappers! {
+    /// Wrapper for the MAC Frame Filter Register bits.
+    pub struct Macffr(pub u32);
+}
+
+impl Macffr {
+    bitfield_accessors! {
+        /// Passes all frames, recording the filter result in each
+        /// descriptor (`Rdes0::get_afm`).
+        pub total [31] get_ra / with_ra: bool,
+        /// With hash filtering, also passes perfect filter matches.
+        pub total [10] get_hpf / with_hpf: bool,
+        /// Source address filtering.
+        pub total [9] get_saf / with_saf: bool,
+        pub total [8] get_saif / with_saif: bool,
+        pub total [7:6] get_pcf / with_pcf: u32,
+        /// Blocks broadcast frames.
+        pub total [5] get_bfd / with_bfd: bool,
+        /// Passes all multicast frames.
+        pub total [4] get_pam / with_pam: bool,
+        pub total [3] get_daif / with_daif: bool,
+        /// Filters multicast destinations by hash.
+        pub total [2] get_hm / with_hm: bool,
+        /// Filters unicast destinations by hash.
+        pub total [1] get_hu / with_hu: bool,
+        /// Passes everything, without recording filter results.
+        pub total [0] get_pm / with_pm: bool,
+    }
+}
+
+/// How destination addresses of one kind (unicast or multicast) are
+/// matched.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum AddressMatch {
+    /// Against the perfect filters only.
+    Perfect,
+    /// Against the hash table only.
+    Hash,
+    /// Against either.
+    HashOrPerfect,
+}
+
+/// Receive filter settings.
+#[derive(Copy, Clone, Debug)]
+pub struct FilterConfig {
+    /// Passes every frame, whatever the rest of this says.
+    pub promiscuous: bool,
+    /// Passes every multicast frame.
+    pub all_multicast: bool,
+    pub block_broadcast: bool,
+    pub unicast: AddressMatch,
+    pub multicast: AddressMatch,
+}
+
+/// Filtering for a host in no multicast groups: only frames to our address,
+/// and broadcasts.
+pub const DEFAULT_FILTER_CONFIG : FilterConfig = FilterConfig {
+    promiscuous: false,
+    all_multicast: false,
+    block_broadcast: false,
+    unicast: AddressMatch::Perfect,
+    multicast: AddressMatch::Perfect,
+};
+
+/// The perfect filters beyond our own address.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum PerfectSlot {
+    Slot1 = 1,
+    Slot2 = 2,
+    Slot3 = 3,
+}
+
+/// Settings for one perfect filter.
+#[derive(Copy, Clone, Debug)]
+pub struct PerfectFilter {
+    pub address: [u8; 6],
+    /// Matches the frame's source address, rather than its destination.
+    /// Source matching also requires `Macffr::get_saf`.
+    pub source: bool,
+    /// Bytes of `address` to ignore: bit *n* for `address[n]`.
+    pub ignore_bytes: u8,
+}
+
+/// A multicast hash table.  Each address hashes to one of 64 bins; the MAC
+/// passes frames whose destination falls in a set bin.
+///
+/// Bins can be shared, so addresses can't be removed one at a time.  To
+/// leave a group, `clear` the table and insert the groups that remain.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct HashTable {
+    pub bins: u64,
+}
+
+/// Computes the hash bin (0-63) for `addr`: the upper six bits of the
+/// bit-reversed, complemented Ethernet CRC of the address.
+pub fn hash_bin(addr: &[u8; 6]) -> u32 {
+    let mut crc = !0u32;
+    for &b in addr {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    // Reversing the word and taking the top six bits is the same as reversing
+    // the bottom six.
+    let crc = !crc;
+    let mut bin = 0;
+    for i in 0..6 {
+        bin |= ((crc >> i) & 1) << (5 - i);
+    }
+    bin
+}
+
+impl HashTable {
+    pub const fn new() -> HashTable {
+        HashTable { bins: 0 }
+    }
+
+    pub fn insert(&mut self, addr: &[u8; 6]) {
+        self.bins |= 1u64 << hash_bin(addr)
+    }
+
+    /// Checks whether frames to `addr` would pass.  (So might frames to
+    /// other addresses in the same bin.)
+    pub fn contains(&self, addr: &[u8; 6]) -> bool {
+        self.bins & (1u64 << hash_bin(addr)) != 0
+    }
+
+    pub fn clear(&mut self) {
+        self.bins = 0
+    }
+}
+
+/// VLAN tag filter settings.
+#[derive(Copy, Clone, Debug)]
+pub struct VlanFilter {
+    /// The tag control information to match.
+    pub tag: u16,
+    /// Compares only the 12-bit VLAN identifier, ignoring priority and CFI.
+    pub vid_only: bool,
+}
+
+/// Address enable bit in MACAxHR.
+const MACA_AE : u32 = 1 << 31;
+/// Source address bit in MACAxHR.
+const MACA_SA : u32 = 1 << 30;
+/// Position of the byte mask in MACAxHR.
+const MACA_MBC_SHIFT : u32 = 24;
+
+/// VLAN tag comparison bit in MACVLANTR: 12-bit if set.
+const MACVLANTR_VLANTC : u32 = 1 << 16;
+
+impl Eth {
+    pub fn read_macffr(&self) -> Macffr {
+        Macffr(self.mac().macffr.get())
+    }
+
+    pub fn write_macffr(&self, v: Macffr) {
+        self.mac().macffr.set(v.0)
+    }
+
+    pub fn update_macffr<F: FnOnce(Macffr) -> Macffr>(&self, f: F) {
+        self.write_macffr(f(self.read_macffr()))
+    }
+
+    /// Applies `cfg` to the frame filter.  Source address filtering and
+    /// control frame settings are left alone.
+    pub fn set_filter(&self, cfg: &FilterConfig) {
+        // HPF applies to both kinds at once; it's set if either wants it,
+        // which for the other kind only matters if it's hashed.
+        let hpf = cfg.unicast == AddressMatch::HashOrPerfect
+            || cfg.multicast == AddressMatch::HashOrPerfect;
+        self.update_macffr(|v| v.with_pm(cfg.promiscuous)
+                                .with_pam(cfg.all_multicast)
+                                .with_bfd(cfg.block_broadcast)
+                                .with_hu(cfg.unicast != AddressMatch::Perfect)
+                                .with_hm(cfg.multicast != AddressMatch::Perfect)
+                                .with_hpf(hpf))
+    }
+
+    /// Sets up perfect filter `slot`, or disables it.
+    pub fn set_perfect_filter(&self,
+                              slot: PerfectSlot,
+                              filter: Option<&PerfectFilter>) {
+        let regs = &self.mac().maca[slot as usize];
+        // Both halves take effect when the low register is written.  Latch
+        // the filter disabled first, so that no frame sees a mixture of old
+        // and new settings.
+        regs.hr.set(0);
+        regs.lr.set(0);
+        if let Some(f) = filter {
+            let (hi, lo) = address_words(&f.address);
+            let sa = if f.source { MACA_SA } else { 0 };
+            let mbc = ((f.ignore_bytes & 0x3F) as u32) << MACA_MBC_SHIFT;
+            regs.hr.set(MACA_AE | sa | mbc | hi);
+            regs.lr.set(lo)
+        }
+    }
+
+    /// Loads the multicast (or unicast) hash table.
+    pub fn set_hash_table(&self, table: &HashTable) {
+        self.mac().machthr.set((table.bins >> 32) as u32);
+        self.mac().machtlr.set(table.bins as u32)
+    }
+
+    /// Sets up VLAN tag filtering, or disables it.  With it enabled, tagged
+    /// frames whose tag doesn't match are dropped (unless the filter passes
+    /// everything); untagged frames are unaffected.
+    pub fn set_vlan_filter(&self, filter: Option<&VlanFilter>) {
+        let v = match filter {
+            None => 0,
+            Some(f) => {
+                let vlantc = if f.vid_only { MACVLANTR_VLANTC } else { 0 };
+                vlantc | f.tag as u32
+            },
+        };
+        self.mac().macvlantr.set(v)
+    }
+}
@@ -0,0 +1,633 @@
+//! Ethernet MAC support.
+//!
+//! The Ethernet peripheral has three parts, each with its own register block:
+//!
+//! - The MAC proper, which frames and filters traffic (see `filter`) and
+//!   talks to the PHY's management interface (MDIO).
+//! - A dedicated DMA engine, which moves frames between the MAC's FIFOs and
+//!   rings of descriptors in RAM (see `desc`).
+//! - The IEEE 1588 timestamping unit (see `ptp`).
+//!
+//! Bringing up the interface goes roughly like this:
+//!
+//! 1. Select MII or RMII with `Syscfg::set_phy_interface`, before enabling
+//!    the Ethernet clocks.
+//! 2. Enable the `Ethernet`, `EthernetTx`, and `EthernetRx` clocks (and
+//!    `EthernetPtp`, for timestamping), and route the pins with
+//!    `Eth::configure_pins`.
+//! 3. `ETH.reset()`, which needs the PHY to be supplying its clocks.
+//! 4. Configure the PHY over MDIO, and mirror its negotiated speed and duplex
+//!    with `ETH.set_link`.
+//! 5. Build descriptor rings, and hand them over with `ETH.start`.
+//!
+//! The driver doesn't own the rings: applications (or network stacks) walk
+//! them directly, which is where the interesting policy lives.  `device`
+//! does that for IP stacks using the `net::NetDevice` interface.
+//!
+//! The driver uses the *enhanced* (eight-word) descriptor format throughout,
+//! since that's the one that carries frame timestamps.
+
+#![allow(trivial_numeric_casts)]  // for bitflags :-(
+
+use arm_m::reg::{mmio, AtomicReg, Reg, ReservedReg};
+use bits;
+use clock;
+use super::gpio::{self, Pins};
+use super::rcc::ClockSpeeds;
+
+pub mod desc;
+pub mod device;
+pub mod filter;
+pub mod ptp;
+
+use self::desc::{RxDescriptor, TxDescriptor};
+
+/*******************************************************************************
+ * Register layouts.
+ */
+
+#[repr(C, packed)]
+struct MacRegisters {
+    maccr:      Reg<u32>,
+    macffr:     Reg<u32>,
+    machthr:    Reg<u32>,
+    machtlr:    Reg<u32>,
+    macmiiar:   Reg<u32>,
+    macmiidr:   Reg<u32>,
+    // Flow control and power management aren't supported yet.
+    #[allow(dead_code)]
+    macfcr:     Reg<u32>,
+    macvlantr:  Reg<u32>,
+    _reserved0: [ReservedReg; 2],
+    #[allow(dead_code)]
+    macrwuffr:  Reg<u32>,
+    #[allow(dead_code)]
+    macpmtcsr:  Reg<u32>,
+    _reserved1: ReservedReg,
+    macdbgr:    Reg<u32>,
+    macsr:      Reg<u32>,
+    macimr:     Reg<u32>,
+    /// MAC address registers MACA0HR/MACA0LR through MACA3HR/MACA3LR.
+    maca:       [MacAddressRegs; 4],
+}
+
+#[repr(C, packed)]
+struct MacAddressRegs {
+    hr: Reg<u32>,
+    lr: Reg<u32>,
+}
+
+register_layout! {
+    fn check_mac_layout: MacRegisters [0x60] {
+        maccr @ 0x00,
+        macffr @ 0x04,
+        machthr @ 0x08,
+        machtlr @ 0x0C,
+        macmiiar @ 0x10,
+        macmiidr @ 0x14,
+        macfcr @ 0x18,
+        macvlantr @ 0x1C,
+        macrwuffr @ 0x28,
+        macpmtcsr @ 0x2C,
+        macdbgr @ 0x34,
+        macsr @ 0x38,
+        macimr @ 0x3C,
+        maca @ 0x40,
+    }
+}
+
+#[repr(C, packed)]
+struct DmaRegisters {
+    dmabmr:    Reg<u32>,
+    dmatpdr:   Reg<u32>,
+    dmarpdr:   Reg<u32>,
+    dmardlar:  Reg<u32>,
+    dmatdlar:  Reg<u32>,
+    dmasr:     Reg<u32>,
+    dmaomr:    Reg<u32>,
+    dmaier:    Reg<u32>,
+    dmamfbocr: Reg<u32>,
+    dmarswtr:  Reg<u32>,
+    _reserved: [ReservedReg; 8],
+    dmachtdr:  Reg<u32>,
+    dmachrdr:  Reg<u32>,
+    dmachtbar: Reg<u32>,
+    dmachrbar: Reg<u32>,
+}
+
+register_layout! {
+    fn check_dma_layout: DmaRegisters [0x58] {
+        dmabmr @ 0x00,
+        dmatpdr @ 0x04,
+        dmarpdr @ 0x08,
+        dmardlar @ 0x0C,
+        dmatdlar @ 0x10,
+        dmasr @ 0x14,
+        dmaomr @ 0x18,
+        dmaier @ 0x1C,
+        dmamfbocr @ 0x20,
+        dmarswtr @ 0x24,
+        dmachtdr @ 0x48,
+        dmachrdr @ 0x4C,
+        dmachtbar @ 0x50,
+        dmachrbar @ 0x54,
+    }
+}
+
+const ETH_MAC_ADDRESS : usize = 0x40028000;
+const ETH_DMA_ADDRESS : usize = 0x40029000;
+
+/*******************************************************************************
+ * Register contents.
+ */
+
+bit_wrappers! {
+    /// Wrapper for the MAC Configuration Register bits.
+    pub struct Maccr(pub u32);
+    /// Wrapper for the MAC MII Address Register bits.
+    pub struct Macmiiar(pub u32);
+    /// Wrapper for the MAC Status Register bits.
+    pub struct Macsr(pub u32);
+    /// Wrapper for the DMA Bus Mode Register bits.
+    pub struct Dmabmr(pub u32);
+    /// Wrapper for the DMA Operation Mode Register bits.
+    pub struct Dmaomr(pub u32);
+    /// Wrapper for the DMA Status Register bits.
+    pub struct Dmasr(pub u32);
+}
+
+impl Maccr {
+    bitfield_accessors! {
+        /// Disables the receive watchdog, which cuts off frames over 2048
+        /// bytes.
+        pub total [23] get_wd / with_wd: bool,
+        /// Disables the transmit jabber timer.
+        pub total [22] get_jd / with_jd: bool,
+        /// Interframe gap, in units of 8 bit times below 96.
+        pub total [19:17] get_ifg / with_ifg: u32,
+        /// Ignores carrier sense during half-duplex transmission.
+        pub total [16] get_csd / with_csd: bool,
+        pub total [14] get_fes / with_fes: LinkSpeed,
+        /// Disables reception of our own frames in half duplex.
+        pub total [13] get_rod / with_rod: bool,
+        pub total [12] get_lm / with_lm: bool,
+        pub total [11] get_dm / with_dm: Duplex,
+        /// Checks IPv4 header and TCP/UDP/ICMP checksums on receipt.
+        pub total [10] get_ipco / with_ipco: bool,
+        pub total [9] get_rd / with_rd: bool,
+        /// Strips padding and FCS from short (non-VLAN) received frames.
+        pub total [7] get_apcs / with_apcs: bool,
+        pub total [6:5] get_bl / with_bl: u32,
+        pub total [4] get_dc / with_dc: bool,
+        pub total [3] get_te / with_te: bool,
+        pub total [2] get_re / with_re: bool,
+    }
+}
+
+impl Macmiiar {
+    bitfield_accessors! {
+        pub total [15:11] get_pa / with_pa: u32,
+        pub total [10:6] get_mr / with_mr: u32,
+        pub [4:2] get_cr / with_cr: MdcDivider,
+        pub total [1] get_mw / with_mw: bool,
+        pub total [0] get_mb / with_mb: bool,
+    }
+}
+
+impl Macsr {
+    bitfield_accessors! {
+        /// Time stamp trigger status; see `ptp`.
+        pub total [9] get_tsts / with_tsts: bool,
+        pub total [6] get_mmcts / with_mmcts: bool,
+        pub total [5] get_mmcrs / with_mmcrs: bool,
+        pub total [4] get_mmcs / with_mmcs: bool,
+        pub total [3] get_pmts / with_pmts: bool,
+    }
+}
+
+impl Dmabmr {
+    bitfield_accessors! {
+        /// Mixed burst.
+        pub total [26] get_mb / with_mb: bool,
+        /// Address-aligned beats.
+        pub total [25] get_aab / with_aab: bool,
+        /// Multiplies both burst lengths by 4.
+        pub total [24] get_fpm / with_fpm: bool,
+        /// Use separate PBL: RDP for RX and PBL for TX.
+        pub total [23] get_usp / with_usp: bool,
+        pub total [22:17] get_rdp / with_rdp: u32,
+        /// Fixed burst.
+        pub total [16] get_fb / with_fb: bool,
+        /// RX:TX priority ratio, minus one, when `da` is clear.
+        pub total [15:14] get_pm / with_pm: u32,
+        /// Programmable burst length (beats), 1-32 in powers of two.
+        pub total [13:8] get_pbl / with_pbl: u32,
+        /// Enhanced (eight-word) descriptor format.
+        pub total [7] get_edfe / with_edfe: bool,
+        /// Descriptor skip length, in words, between ring entries.
+        pub total [6:2] get_dsl / with_dsl: u32,
+        /// DMA arbitration: round robin if clear, RX first if set.
+        pub total [1] get_da / with_da: bool,
+        /// Software reset.  Self-clearing.
+        pub total [0] get_sr / with_sr: bool,
+    }
+}
+
+impl Dmaomr {
+    bitfield_accessors! {
+        /// Disables dropping of frames with TCP/IP checksum errors.
+        pub total [26] get_dtcefd / with_dtcefd: bool,
+        /// Receive store and forward.
+        pub total [25] get_rsf / with_rsf: bool,
+        /// Disables flushing of received frames when no descriptors are free.
+        pub total [24] get_dfrf / with_dfrf: bool,
+        /// Transmit store and forward.  Required for TX checksum offload.
+        pub total [21] get_tsf / with_tsf: bool,
+        /// Flushes the transmit FIFO.  Self-clearing.
+        pub total [20] get_ftf / with_ftf: bool,
+        pub total [16:14] get_ttc / with_ttc: u32,
+        /// Starts transmission.
+        pub total [13] get_st / with_st: bool,
+        /// Forwards frames with errors.
+        pub total [7] get_fef / with_fef: bool,
+        /// Forwards undersized good frames.
+        pub total [6] get_fugf / with_fugf: bool,
+        pub total [4:3] get_rtc / with_rtc: u32,
+        /// Operates on a second frame while the first's status is pending.
+        pub total [2] get_osf / with_osf: bool,
+        /// Starts reception.
+        pub total [1] get_sr / with_sr: bool,
+    }
+}
+
+impl Dmasr {
+    bitfield_accessors! {
+        /// Time stamp trigger status (mirrors `Macsr::get_tsts`).
+        pub total [29] get_tsts / with_tsts: bool,
+        pub total [28] get_pmts / with_pmts: bool,
+        pub total [27] get_mmcs / with_mmcs: bool,
+        /// Error bits for a fatal bus error.
+        pub total [25:23] get_ebs / with_ebs: u32,
+        /// Transmit process state.
+        pub total [22:20] get_tps / with_tps: u32,
+        /// Receive process state.
+        pub total [19:17] get_rps / with_rps: u32,
+        pub [16:0] get_flags / with_flags: DmaInterrupts,
+    }
+}
+
+bit_enums! {
+    /// Link speeds supported by the MAC.
+    pub bit_enum LinkSpeed {
+        Mbps10 = 0,
+        Mbps100 = 1,
+    }
+
+    pub bit_enum Duplex {
+        Half = 0,
+        Full = 1,
+    }
+
+    /// Dividers from HCLK to the MDIO clock, MDC, which must not exceed
+    /// 2.5MHz.  Each is named for the HCLK range it serves.
+    pub bit_enum MdcDivider {
+        /// 60-100MHz, divide by 42.
+        Hclk60to100 = 0b000,
+        /// 100-150MHz, divide by 62.
+        Hclk100to150 = 0b001,
+        /// 20-35MHz, divide by 16.
+        Hclk20to35 = 0b010,
+        /// 35-60MHz, divide by 26.
+        Hclk35to60 = 0b011,
+        /// 150-180MHz, divide by 102.
+        Hclk150to180 = 0b100,
+    }
+}
+
+bitflags! {
+    /// Normal and abnormal DMA events, as reported in DMASR and enabled in
+    /// DMAIER (at the same positions).  Events are grouped under the two
+    /// summary bits, which must be enabled for their members to interrupt.
+    pub flags DmaInterrupts: u32 {
+        const TRANSMIT = 1 << 0,
+        const TRANSMIT_STOPPED = 1 << 1,
+        const TRANSMIT_BUFFER_UNAVAILABLE = 1 << 2,
+        const TRANSMIT_JABBER_TIMEOUT = 1 << 3,
+        const RECEIVE_OVERFLOW = 1 << 4,
+        const TRANSMIT_UNDERFLOW = 1 << 5,
+        const RECEIVE = 1 << 6,
+        const RECEIVE_BUFFER_UNAVAILABLE = 1 << 7,
+        const RECEIVE_STOPPED = 1 << 8,
+        const RECEIVE_WATCHDOG_TIMEOUT = 1 << 9,
+        const EARLY_TRANSMIT = 1 << 10,
+        const FATAL_BUS_ERROR = 1 << 13,
+        const EARLY_RECEIVE = 1 << 14,
+        const ABNORMAL_SUMMARY = 1 << 15,
+        const NORMAL_SUMMARY = 1 << 16,
+    }
+}
+
+impl bits::FromBits for DmaInterrupts {
+    fn from_bits(bits: u32) -> bits::BitsResult<Self> {
+        DmaInterrupts::from_bits(bits).ok_or(bits::BadBits(bits))
+    }
+}
+
+impl bits::IntoBits for DmaInterrupts {
+    fn into_bits(self) -> u32 {
+        self.bits()
+    }
+}
+
+impl MdcDivider {
+    /// Picks the divider for an HCLK of `hclk` Hz.
+    pub fn for_hclk(hclk: f32) -> MdcDivider {
+        if hclk < 35_000_000. {
+            MdcDivider::Hclk20to35
+        } else if hclk < 60_000_000. {
+            MdcDivider::Hclk35to60
+        } else if hclk < 100_000_000. {
+            MdcDivider::Hclk60to100
+        } else if hclk < 150_000_000. {
+            MdcDivider::Hclk100to150
+        } else {
+            MdcDivider::Hclk150to180
+        }
+    }
+}
+
+/// Settings for the DMA engine that depend on the application's buffers and
+/// bus traffic, rather than on the link.
+#[derive(Copy, Clone, Debug)]
+pub struct DmaConfig {
+    /// Burst length, in beats: 1, 2, 4, 8, 16, or 32.
+    pub burst_len: u32,
+    /// Buffers frames entirely in the FIFO before transmitting, rather than
+    /// starting at a threshold.  Needed for checksum insertion.
+    pub tx_store_forward: bool,
+    /// Buffers frames entirely in the FIFO before handing them to the DMA,
+    /// so that frames failing the CRC check never reach memory.
+    pub rx_store_forward: bool,
+}
+
+/// A configuration suitable for most applications.
+pub const DEFAULT_DMA_CONFIG : DmaConfig = DmaConfig {
+    burst_len: 32,
+    tx_store_forward: true,
+    rx_store_forward: true,
+};
+
+/// Ethernet driver.
+pub struct Eth;
+
+macro_rules! reg_accessors {
+    ($block:ident, $name:ident, $ty:ident, $read:ident, $write:ident,
+     $update:ident) => {
+        pub fn $read(&self) -> $ty {
+            $ty(self.$block().$name.get())
+        }
+
+        pub fn $write(&self, v: $ty) {
+            self.$block().$name.set(v.0)
+        }
+
+        pub fn $update<F: FnOnce($ty) -> $ty>(&self, f: F) {
+            self.$write(f(self.$read()))
+        }
+    };
+}
+
+impl Eth {
+    fn mac(&self) -> &'static MacRegisters {
+        unsafe { mmio(ETH_MAC_ADDRESS) }
+    }
+
+    fn dma(&self) -> &'static DmaRegisters {
+        unsafe { mmio(ETH_DMA_ADDRESS) }
+    }
+
+    reg_accessors!(mac, maccr, Maccr, read_maccr, write_maccr, update_maccr);
+    reg_accessors!(dma, dmabmr, Dmabmr, read_dmabmr, write_dmabmr,
+                   update_dmabmr);
+    reg_accessors!(dma, dmaomr, Dmaomr, read_dmaomr, write_dmaomr,
+                   update_dmaomr);
+
+    pub fn read_macsr(&self) -> Macsr {
+        Macsr(self.mac().macsr.get())
+    }
+
+    pub fn read_dmasr(&self) -> Dmasr {
+        Dmasr(self.dma().dmasr.get())
+    }
+
+    /// Routes `pins` to the MAC's MII/RMII and MDIO signals (alternate
+    /// function 11), at the highest slew rate, as the interface requires.
+    ///
+    /// Which pins carry which signals is part-specific; see the datasheet.
+    pub fn configure_pins(&self, pins: &Pins) {
+        (pins.port)().set_speed(pins.pins, gpio::Speed::VeryHigh);
+        pins.configure_alternate(gpio::Function::AF11, gpio::Pull::None)
+    }
+
+    /// Resets the MAC, DMA, and PTP blocks, and waits for the reset to
+    /// finish.
+    ///
+    /// The reset is clocked by the PHY, so this hangs if the PHY isn't
+    /// running (or the PHY interface was selected wrong).
+    pub fn reset(&self) {
+        self.update_dmabmr(|v| v.with_sr(true));
+        while self.read_dmabmr().get_sr() {}
+    }
+
+    /// Sets the MDIO clock divider for an HCLK of `speeds.ahb`.
+    pub fn set_mdc_clock(&self, speeds: &ClockSpeeds) {
+        let div = MdcDivider::for_hclk(speeds.ahb);
+        self.mac().macmiiar.update(|v| Macmiiar(v).with_cr(div).0)
+    }
+
+    /// Sets the MDIO clock divider using the clock speeds recorded by
+    /// `clock::freeze`.  See `set_mdc_clock`.
+    ///
+    /// # Panics
+    ///
+    /// If the clock speeds have not been frozen.
+    pub fn set_mdc_clock_frozen(&self) {
+        self.set_mdc_clock(clock::frozen())
+    }
+
+    fn mdio_wait(&self) {
+        while Macmiiar(self.mac().macmiiar.get()).get_mb() {}
+    }
+
+    /// Reads PHY register `reg` (0-31) of the PHY at address `phy` (0-31)
+    /// over MDIO.
+    pub fn mdio_read(&self, phy: u32, reg: u32) -> u16 {
+        self.mdio_wait();
+        self.mac().macmiiar.update(|v| Macmiiar(v)
+                                   .with_pa(phy & 0x1F)
+                                   .with_mr(reg & 0x1F)
+                                   .with_mw(false)
+                                   .with_mb(true)
+                                   .0);
+        self.mdio_wait();
+        self.mac().macmiidr.get() as u16
+    }
+
+    /// Writes `value` to PHY register `reg` (0-31) of the PHY at address
+    /// `phy` (0-31) over MDIO, and waits for the write to finish.
+    pub fn mdio_write(&self, phy: u32, reg: u32, value: u16) {
+        self.mdio_wait();
+        self.mac().macmiidr.set(value as u32);
+        self.mac().macmiiar.update(|v| Macmiiar(v)
+                                   .with_pa(phy & 0x1F)
+                                   .with_mr(reg & 0x1F)
+                                   .with_mw(true)
+                                   .with_mb(true)
+                                   .0);
+        self.mdio_wait()
+    }
+
+    /// Sets the station address: the source address for transmitted frames,
+    /// and the first perfect destination filter.  (For the others, see
+    /// `filter`.)
+    pub fn set_mac_address(&self, addr: &[u8; 6]) {
+        let (hi, lo) = address_words(addr);
+        // The address is latched when the low register is written.
+        self.mac().maca[0].hr.set(hi | (1 << 31));
+        self.mac().maca[0].lr.set(lo)
+    }
+
+    /// Matches the MAC's speed and duplex to those the PHY negotiated.  Only
+    /// change these with the MAC stopped.
+    pub fn set_link(&self, speed: LinkSpeed, duplex: Duplex) {
+        self.update_maccr(|v| v.with_fes(speed).with_dm(duplex))
+    }
+
+    /// Points the DMA engine at descriptor rings `tx` and `rx`, and starts
+    /// the MAC and DMA.  Each ring's last descriptor must be marked as such
+    /// (see `desc`), and the rings must stay put until `stop`.
+    ///
+    /// Receive descriptors should be armed before this is called, or the
+    /// receive process will immediately suspend (`RECEIVE_BUFFER_UNAVAILABLE`)
+    /// and need `resume_rx`.
+    pub fn start(&self,
+                 tx: &'static [TxDescriptor],
+                 rx: &'static [RxDescriptor],
+                 cfg: &DmaConfig) {
+        let pbl = cfg.burst_len;
+        self.write_dmabmr(Dmabmr::default()
+                          .with_aab(true)
+                          .with_usp(true)
+                          .with_pbl(pbl)
+                          .with_rdp(pbl)
+                          .with_edfe(true));
+
+        self.dma().dmatdlar.set(tx.as_ptr() as u32);
+        self.dma().dmardlar.set(rx.as_ptr() as u32);
+
+        self.update_maccr(|v| v.with_te(true).with_re(true));
+
+        self.update_dmaomr(|v| v.with_ftf(true));
+        while self.read_dmaomr().get_ftf() {}
+
+        self.update_dmaomr(|v| v.with_tsf(cfg.tx_store_forward)
+                                .with_rsf(cfg.rx_store_forward)
+                                .with_osf(true)
+                                .with_st(true)
+                                .with_sr(true))
+    }
+
+    /// Stops the DMA and MAC.  Frames in flight are abandoned.
+    pub fn stop(&self) {
+        self.update_dmaomr(|v| v.with_st(false));
+        self.update_maccr(|v| v.with_te(false).with_re(false));
+        self.update_dmaomr(|v| v.with_ftf(true).with_sr(false));
+    }
+
+    /// Tells the DMA engine that transmit descriptors have been handed to it,
+    /// waking the transmit process if it had suspended for lack of work.
+    pub fn resume_tx(&self) {
+        self.dma().dmatpdr.set(0)
+    }
+
+    /// Tells the DMA engine that receive descriptors have been handed back to
+    /// it, waking the receive process if it had suspended for lack of
+    /// buffers.
+    pub fn resume_rx(&self) {
+        self.dma().dmarpdr.set(0)
+    }
+
+    /// Enables interrupts for `events`.  The relevant summary bits
+    /// (`NORMAL_SUMMARY`, `ABNORMAL_SUMMARY`) must be included for any of
+    /// their members to interrupt.
+    pub fn enable_dma_interrupts(&self, events: DmaInterrupts) {
+        self.dma().dmaier.atomic_or(events.bits())
+    }
+
+    pub fn disable_dma_interrupts(&self, events: DmaInterrupts) {
+        self.dma().dmaier.atomic_nand(events.bits())
+    }
+
+    /// Clears the status bits for `events`.  Summary bits clear only along
+    /// with all their members.
+    pub fn clear_dma_status(&self, events: DmaInterrupts) {
+        // DMASR is write-one-to-clear.
+        self.dma().dmasr.set(events.bits())
+    }
+
+    /// Address of the transmit descriptor the DMA engine is working on.
+    pub fn current_tx_descriptor(&self) -> u32 {
+        self.dma().dmachtdr.get()
+    }
+
+    /// Address of the receive descriptor the DMA engine is working on.
+    pub fn current_rx_descriptor(&self) -> u32 {
+        self.dma().dmachrdr.get()
+    }
+
+    /// Address of the transmit buffer the DMA engine is reading.
+    pub fn current_tx_buffer(&self) -> u32 {
+        self.dma().dmachtbar.get()
+    }
+
+    /// Address of the receive buffer the DMA engine is writing.
+    pub fn current_rx_buffer(&self) -> u32 {
+        self.dma().dmachrbar.get()
+    }
+
+    /// Sets the receive interrupt watchdog: with store-and-forward receive,
+    /// the `RECEIVE` event is raised `count` * 256 HCLK cycles after a frame
+    /// arrives, rather than immediately, unless the descriptor asked for
+    /// interrupts to be suppressed.  Zero disables the watchdog.
+    pub fn set_rx_watchdog(&self, count: u8) {
+        self.dma().dmarswtr.set(count as u32)
+    }
+
+    /// Reads the MAC Debug Register, which shows the state of the FIFOs and
+    /// their controllers.  The layout is in the Reference Manual.
+    pub fn read_debug(&self) -> u32 {
+        self.mac().macdbgr.get()
+    }
+
+    /// Counts of receive frames lost so far, as (missed by the controller
+    /// for lack of descriptors, lost to FIFO overflow).  Reading clears the
+    /// counts.
+    pub fn take_missed_frames(&self) -> (u32, u32) {
+        let v = self.dma().dmamfbocr.get();
+        (v & 0xFFFF, (v >> 17) & 0x7FF)
+    }
+}
+
+/// Packs a MAC address into the (high, low) register words, as MACAxHR and
+/// MACAxLR want it: first octet in the low byte of the low word.
+fn address_words(addr: &[u8; 6]) -> (u32, u32) {
+    let lo = (addr[0] as u32)
+        | ((addr[1] as u32) << 8)
+        | ((addr[2] as u32) << 16)
+        | ((addr[3] as u32) << 24);
+    let hi = (addr[4] as u32) | ((addr[5] as u32) << 8);
+    (hi, lo)
+}
+
+/// Shared instance of the `Eth` driver.
+pub static ETH: Eth = Eth;
@@ -0,0 +1,383 @@
+//! IEEE 1588 Precision Time Protocol (PTP) timestamping support.
+//!
+//! The MAC keeps a system time -- seconds, plus a 31-bit binary fraction --
+//! and captures it as selected frames cross the MII, recording the result in
+//! the frame's descriptor (`TxDescriptor::timestamp`,
+//! `RxDescriptor::timestamp`).  It doesn't run PTP itself: the message
+//! exchange and the servo that steers the clock are the application's.  This
+//! module supplies the knobs the servo turns:
+//!
+//! - `Ptp::start` sets the clock running from HCLK using the fine update
+//!   method, in which an accumulator adds the *addend* register once per HCLK
+//!   cycle and ticks the clock on overflow.  Nominal values come from
+//!   `ClockIncrement::compute`.
+//! - `Ptp::set_addend` trims the clock's rate; `scaled_addend` computes an
+//!   addend corrected by some parts per billion.
+//! - `Ptp::adjust` steps the clock by a `TimeOffset`.
+//! - `Ptp::set_target_time` raises an interrupt at a given time.
+//!
+//! The `EthernetPtp` clock must be enabled, and the Ethernet DMA set up with
+//! enhanced descriptors, as `Eth::start` does.
+
+use arm_m::reg::{mmio, read_pair_coherent, Reg, ReservedReg};
+use clock;
+use super::ETH;
+use super::super::rcc::ClockSpeeds;
+
+#[repr(C, packed)]
+struct Registers {
+    ptptscr:   Reg<u32>,
+    ptpssir:   Reg<u32>,
+    ptptshr:   Reg<u32>,
+    ptptslr:   Reg<u32>,
+    ptptshur:  Reg<u32>,
+    ptptslur:  Reg<u32>,
+    ptptsar:   Reg<u32>,
+    ptptthr:   Reg<u32>,
+    ptpttlr:   Reg<u32>,
+    _reserved: ReservedReg,
+    ptptssr:   Reg<u32>,
+    ptpppscr:  Reg<u32>,
+}
+
+register_layout! {
+    fn check_layout: Registers [0x30] {
+        ptptscr @ 0x00,
+        ptpssir @ 0x04,
+        ptptshr @ 0x08,
+        ptptslr @ 0x0C,
+        ptptshur @ 0x10,
+        ptptslur @ 0x14,
+        ptptsar @ 0x18,
+        ptptthr @ 0x1C,
+        ptpttlr @ 0x20,
+        ptptssr @ 0x28,
+        ptpppscr @ 0x2C,
+    }
+}
+
+const PTP_ADDRESS : usize = 0x40028700;
+
+/// Time stamp trigger interrupt mask bit in MACIMR.
+const MACIMR_TSTIM : u32 = 1 << 9;
+
+/// Sign bit of the subseconds field in PTPTSLUR: set to subtract.
+const SUBTRACT : u32 = 1 << 31;
+
+bit_wrappers! {
+    /// Wrapper for the PTP Time Stamp Control Register bits.
+    pub struct Ptptscr(pub u32);
+}
+
+impl Ptptscr {
+    bitfield_accessors! {
+        /// Filters PTP frames by destination MAC address.
+        pub total [18] get_tspffmae / with_tspffmae: bool,
+        pub total [17:16] get_tscnt / with_tscnt: ClockNode,
+        /// Snapshot master (rather than slave) messages only.
+        pub total [15] get_tssmrme / with_tssmrme: bool,
+        /// Snapshot event messages only.
+        pub total [14] get_tsseme / with_tsseme: bool,
+        pub total [13] get_tssipv4fe / with_tssipv4fe: bool,
+        pub total [12] get_tssipv6fe / with_tssipv6fe: bool,
+        /// Snapshot PTP carried directly over Ethernet.
+        pub total [11] get_tssptpoefe / with_tssptpoefe: bool,
+        /// Parse PTP version 2 (else version 1).
+        pub total [10] get_tsptppsv2e / with_tsptppsv2e: bool,
+        /// Subsecond rollover at 999,999,999 (else at 2^31 - 1).
+        pub total [9] get_tsssr / with_tsssr: bool,
+        /// Snapshot all received frames.
+        pub total [8] get_tssarfe / with_tssarfe: bool,
+        /// Latches the addend register.  Self-clearing.
+        pub total [5] get_ttsaru / with_ttsaru: bool,
+        /// Enables the target time interrupt.  Cleared when it fires.
+        pub total [4] get_tsite / with_tsite: bool,
+        /// Adds or subtracts the update registers.  Self-clearing.
+        pub total [3] get_tsstu / with_tsstu: bool,
+        /// Loads the update registers as the time.  Self-clearing.
+        pub total [2] get_tssti / with_tssti: bool,
+        /// Fine update (else coarse).
+        pub total [1] get_tsfcu / with_tsfcu: bool,
+        /// Enables timestamping.
+        pub total [0] get_tse / with_tse: bool,
+    }
+}
+
+bit_enums! {
+    /// The PTP clock role, which decides which messages are timestamped.
+    pub bit_enum ClockNode {
+        Ordinary = 0b00,
+        Boundary = 0b01,
+        EndToEndTransparent = 0b10,
+        PeerToPeerTransparent = 0b11,
+    }
+}
+
+/// A reading of the PTP system time.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Timestamp {
+    pub seconds: u32,
+    /// Fraction of a second, in units of 2^-31 s (about 0.466ns).
+    pub subseconds: u32,
+}
+
+/// Subsecond units in one second.
+pub const SUBSECONDS_PER_SECOND : u32 = 1 << 31;
+
+/// Converts nanoseconds (below one second) to subsecond units.
+pub fn nanos_to_subseconds(nanos: u32) -> u32 {
+    // 2^31 / 10^9, as 32.32 fixed point.
+    (((nanos as u64) * 9_223_372_037) >> 32) as u32
+}
+
+/// Converts subsecond units to nanoseconds, truncating.
+pub fn subseconds_to_nanos(subseconds: u32) -> u32 {
+    (((subseconds as u64) * 1_000_000_000) >> 31) as u32
+}
+
+impl Timestamp {
+    pub fn from_nanos(seconds: u32, nanos: u32) -> Timestamp {
+        Timestamp {
+            seconds: seconds,
+            subseconds: nanos_to_subseconds(nanos),
+        }
+    }
+
+    /// The fraction of a second, in nanoseconds.
+    pub fn subsec_nanos(&self) -> u32 {
+        subseconds_to_nanos(self.subseconds)
+    }
+}
+
+/// A step to apply to the system time with `Ptp::adjust`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct TimeOffset {
+    /// Subtract, rather than add.
+    pub negative: bool,
+    pub seconds: u32,
+    /// Fraction of a second, in units of 2^-31 s; below
+    /// `SUBSECONDS_PER_SECOND`.
+    pub subseconds: u32,
+}
+
+/// Settings that make the system time count seconds from HCLK.
+#[derive(Copy, Clone, Debug)]
+pub struct ClockIncrement {
+    /// Amount added to the subseconds on each tick.
+    pub subsecond_increment: u8,
+    /// Nominal addend, for exactly `subsecond_increment` units per tick.
+    pub addend: u32,
+}
+
+impl ClockIncrement {
+    /// Computes the increment and nominal addend for an HCLK of `hclk` Hz,
+    /// ticking as fast as possible for the best resolution.
+    ///
+    /// Each tick adds `subsecond_increment`, so ticks must come at
+    /// 2^31 / `subsecond_increment` Hz -- which must be below HCLK, since the
+    /// accumulator overflows at most once per cycle.
+    pub fn compute(hclk: f32) -> ClockIncrement {
+        let hclk = hclk as u64;
+        let inc = SUBSECONDS_PER_SECOND as u64 / hclk + 1;
+        // The addend is 2^32 * tick_hz / hclk, with tick_hz being
+        // SUBSECONDS_PER_SECOND / inc.  That's rarely a whole number, so
+        // fold its division into this one; done in f32, the rounding would
+        // cost tens of ppb before the servo even starts.
+        let addend = ((SUBSECONDS_PER_SECOND as u64) << 32) / (inc * hclk);
+        ClockIncrement {
+            subsecond_increment: inc as u8,
+            addend: addend as u32,
+        }
+    }
+}
+
+/// Adjusts `base` (a nominal addend) to make the clock run faster by `ppb`
+/// parts per billion, or slower if `ppb` is negative.
+pub fn scaled_addend(base: u32, ppb: i32) -> u32 {
+    // The correction is small enough that f32 resolves it to well under one
+    // unit, though `base` itself would not survive the trip.
+    let delta = (base as f32 * (ppb as f32 / 1e9)) as i64;
+    let r = base as i64 + delta;
+    if r < 0 {
+        0
+    } else if r > 0xFFFF_FFFF {
+        0xFFFF_FFFF
+    } else {
+        r as u32
+    }
+}
+
+/// Which frames get timestamped.  Transmitted frames are timestamped if
+/// their descriptors ask (`TxOptions::timestamp`); these settings apply to
+/// received frames.
+#[derive(Copy, Clone, Debug)]
+pub struct SnapshotConfig {
+    /// Timestamp every received frame, ignoring the rest of this.
+    pub all_frames: bool,
+    /// Parse PTP version 2 messages (else version 1).
+    pub ptp_v2: bool,
+    pub over_ethernet: bool,
+    pub over_ipv4: bool,
+    pub over_ipv6: bool,
+    /// Only event messages (Sync, Delay_Req, and so on).
+    pub event_only: bool,
+    /// With `event_only`, master messages rather than slave messages.
+    pub master_only: bool,
+    pub node: ClockNode,
+}
+
+/// What a PTPv2 ordinary clock acting as a slave needs timestamped.
+pub const SLAVE_SNAPSHOT_CONFIG : SnapshotConfig = SnapshotConfig {
+    all_frames: false,
+    ptp_v2: true,
+    over_ethernet: true,
+    over_ipv4: true,
+    over_ipv6: false,
+    event_only: true,
+    master_only: false,
+    node: ClockNode::Ordinary,
+};
+
+/// PTP timestamping driver.
+pub struct Ptp;
+
+impl Ptp {
+    fn reg(&self) -> &'static Registers {
+        unsafe { mmio(PTP_ADDRESS) }
+    }
+
+    pub fn read_ptptscr(&self) -> Ptptscr {
+        Ptptscr(self.reg().ptptscr.get())
+    }
+
+    pub fn write_ptptscr(&self, v: Ptptscr) {
+        self.reg().ptptscr.set(v.0)
+    }
+
+    pub fn update_ptptscr<F: FnOnce(Ptptscr) -> Ptptscr>(&self, f: F) {
+        self.write_ptptscr(f(self.read_ptptscr()))
+    }
+
+    /// Starts the system time at `at`, ticking as `inc` says.  (Use
+    /// `ClockIncrement::compute`, and keep the result: its addend is the
+    /// base for later rate trims.)
+    pub fn start(&self, inc: &ClockIncrement, at: Timestamp) {
+        // The trigger interrupt stays masked until a target time is set.
+        ETH.mac().macimr.update(|v| v | MACIMR_TSTIM);
+
+        self.update_ptptscr(|v| v.with_tse(true).with_tsssr(false));
+        self.reg().ptpssir.set(inc.subsecond_increment as u32);
+        self.set_addend(inc.addend);
+        self.update_ptptscr(|v| v.with_tsfcu(true));
+        self.set_time(at)
+    }
+
+    /// Starts the system time at `at`, ticking from the HCLK speed in
+    /// `speeds`.  Returns the increment used.
+    pub fn start_with_clock(&self, speeds: &ClockSpeeds, at: Timestamp)
+        -> ClockIncrement {
+        let inc = ClockIncrement::compute(speeds.ahb);
+        self.start(&inc, at);
+        inc
+    }
+
+    /// Starts the system time at `at`, ticking from the HCLK speed recorded
+    /// by `clock::freeze`.  See `start_with_clock`.
+    ///
+    /// # Panics
+    ///
+    /// If the clock speeds have not been frozen.
+    pub fn start_frozen(&self, at: Timestamp) -> ClockIncrement {
+        self.start_with_clock(clock::frozen(), at)
+    }
+
+    /// Stops timestamping, and the system time with it.
+    pub fn stop(&self) {
+        self.update_ptptscr(|v| v.with_tse(false))
+    }
+
+    /// Reads the system time.
+    pub fn now(&self) -> Timestamp {
+        let t = read_pair_coherent(&self.reg().ptptshr, &self.reg().ptptslr);
+        Timestamp {
+            seconds: (t >> 32) as u32,
+            subseconds: t as u32 & !SUBTRACT,
+        }
+    }
+
+    /// Replaces the system time with `at`.
+    pub fn set_time(&self, at: Timestamp) {
+        while self.read_ptptscr().get_tssti() {}
+        self.reg().ptptshur.set(at.seconds);
+        self.reg().ptptslur.set(at.subseconds & !SUBTRACT);
+        self.update_ptptscr(|v| v.with_tssti(true));
+        while self.read_ptptscr().get_tssti() {}
+    }
+
+    /// Steps the system time by `offset`.
+    pub fn adjust(&self, offset: TimeOffset) {
+        while self.read_ptptscr().get_tsstu() {}
+        let sign = if offset.negative { SUBTRACT } else { 0 };
+        self.reg().ptptshur.set(offset.seconds);
+        self.reg().ptptslur.set(sign | (offset.subseconds & !SUBTRACT));
+        self.update_ptptscr(|v| v.with_tsstu(true));
+        while self.read_ptptscr().get_tsstu() {}
+    }
+
+    /// Replaces the addend, trimming the clock's rate.  A larger addend runs
+    /// faster.
+    pub fn set_addend(&self, addend: u32) {
+        while self.read_ptptscr().get_ttsaru() {}
+        self.reg().ptptsar.set(addend);
+        self.update_ptptscr(|v| v.with_ttsaru(true))
+    }
+
+    pub fn read_addend(&self) -> u32 {
+        self.reg().ptptsar.get()
+    }
+
+    /// Chooses which received frames are timestamped.
+    pub fn set_snapshot(&self, cfg: &SnapshotConfig) {
+        self.update_ptptscr(|v| v.with_tssarfe(cfg.all_frames)
+                                 .with_tsptppsv2e(cfg.ptp_v2)
+                                 .with_tssptpoefe(cfg.over_ethernet)
+                                 .with_tssipv4fe(cfg.over_ipv4)
+                                 .with_tssipv6fe(cfg.over_ipv6)
+                                 .with_tsseme(cfg.event_only)
+                                 .with_tssmrme(cfg.master_only)
+                                 .with_tscnt(cfg.node))
+    }
+
+    /// Arms the target time interrupt to fire when the system time reaches
+    /// `at`.  It arrives as the Ethernet interrupt, with `Macsr::get_tsts`
+    /// set; the handler should call `take_target_reached`.  The interrupt
+    /// fires once per call.
+    pub fn set_target_time(&self, at: Timestamp) {
+        self.reg().ptptthr.set(at.seconds);
+        self.reg().ptpttlr.set(at.subseconds & !SUBTRACT);
+        self.update_ptptscr(|v| v.with_tsite(true));
+        ETH.mac().macimr.update(|v| v & !MACIMR_TSTIM)
+    }
+
+    /// Disarms the target time interrupt.
+    pub fn cancel_target_time(&self) {
+        ETH.mac().macimr.update(|v| v | MACIMR_TSTIM);
+        self.update_ptptscr(|v| v.with_tsite(false))
+    }
+
+    /// Checks (and clears) whether the target time has been reached.  Also
+    /// clears `Macsr::get_tsts`.
+    pub fn take_target_reached(&self) -> bool {
+        // PTPTSSR clears on read.
+        self.reg().ptptssr.get() & (1 << 1) != 0
+    }
+
+    /// Sets the PPS output to 2^`log2_hz` Hz (0-15).  At 0, the output is a
+    /// pulse each second; faster rates give a square wave.
+    pub fn set_pps_frequency(&self, log2_hz: u32) {
+        self.reg().ptpppscr.set(log2_hz & 0xF)
+    }
+}
+
+/// Shared instance of the `Ptp` driver.
+pub static PTP: Ptp = Ptp;
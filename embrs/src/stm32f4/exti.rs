@@ -0,0 +1,154 @@
+//! External interrupt/event controller (EXTI) support.
+//!
+//! EXTI lines 0-15 follow GPIO pins 0-15 of whichever port SYSCFG routes to
+//! them (see `Syscfg::route_exti`); the rest carry internal events such as the
+//! PVD, RTC alarm, and USB wakeup.  Lines are named here by bitmask, bit *n*
+//! for line *n*, so that a GPIO `PinMask` converts directly.
+//!
+//! Lines 0-4 have interrupt vectors of their own; 5-9 and 10-15 share
+//! `Exti95` and `Exti1510`, whose handlers must check `pending` to see which
+//! line fired.
+
+use arm_m::reg::{mmio, AtomicReg, Reg};
+use super::gpio::{self, GpioPort, PinMask, Pins};
+use super::syscfg::{ExtiPort, SYSCFG};
+
+#[repr(C, packed)]
+struct Registers {
+    imr:   Reg<u32>,
+    emr:   Reg<u32>,
+    rtsr:  Reg<u32>,
+    ftsr:  Reg<u32>,
+    swier: Reg<u32>,
+    pr:    Reg<u32>,
+}
+
+register_layout! {
+    fn check_layout: Registers [0x18] {
+        imr @ 0x00,
+        emr @ 0x04,
+        rtsr @ 0x08,
+        ftsr @ 0x0C,
+        swier @ 0x10,
+        pr @ 0x14,
+    }
+}
+
+const EXTI_ADDRESS : usize = 0x40013C00;
+
+/// Mask of the lines implemented (0-22).
+const ALL_LINES : u32 = (1 << 23) - 1;
+
+/// Signal edges that can trigger a line.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Trigger {
+    Rising,
+    Falling,
+    Both,
+}
+
+/// EXTI driver.
+pub struct Exti;
+
+impl Exti {
+    fn reg(&self) -> &'static Registers {
+        unsafe { mmio(EXTI_ADDRESS) }
+    }
+
+    /// Selects which edges trigger `lines`.
+    pub fn set_trigger(&self, lines: u32, trigger: Trigger) {
+        let lines = lines & ALL_LINES;
+        let (rising, falling) = match trigger {
+            Trigger::Rising => (true, false),
+            Trigger::Falling => (false, true),
+            Trigger::Both => (true, true),
+        };
+        if rising {
+            self.reg().rtsr.atomic_or(lines)
+        } else {
+            self.reg().rtsr.atomic_nand(lines)
+        }
+        if falling {
+            self.reg().ftsr.atomic_or(lines)
+        } else {
+            self.reg().ftsr.atomic_nand(lines)
+        }
+    }
+
+    /// Unmasks the interrupt request for `lines`.  The corresponding NVIC
+    /// interrupt must also be enabled.
+    pub fn enable_interrupt(&self, lines: u32) {
+        self.reg().imr.atomic_or(lines & ALL_LINES)
+    }
+
+    pub fn disable_interrupt(&self, lines: u32) {
+        self.reg().imr.atomic_nand(lines & ALL_LINES)
+    }
+
+    /// Unmasks the event request for `lines`, which wakes the processor from
+    /// `wait_for_event` without taking an interrupt.
+    pub fn enable_event(&self, lines: u32) {
+        self.reg().emr.atomic_or(lines & ALL_LINES)
+    }
+
+    pub fn disable_event(&self, lines: u32) {
+        self.reg().emr.atomic_nand(lines & ALL_LINES)
+    }
+
+    /// Returns the subset of `lines` with a trigger pending.
+    pub fn pending(&self, lines: u32) -> u32 {
+        self.reg().pr.get() & lines
+    }
+
+    /// Clears pending triggers on `lines`.  (The register is
+    /// write-one-to-clear, so other lines are unaffected.)
+    pub fn clear_pending(&self, lines: u32) {
+        self.reg().pr.set(lines & ALL_LINES)
+    }
+
+    /// Triggers `lines` from software, as if their signals had changed.
+    pub fn trigger(&self, lines: u32) {
+        self.reg().swier.set(lines & ALL_LINES)
+    }
+
+    /// Routes the EXTI lines for `pins` from their port, and sets them to
+    /// trigger on `trigger`.  The pins should already be configured as
+    /// inputs, and the SYSCFG clock enabled.  Returns the line mask.
+    pub fn configure_pins(&self, pins: &Pins, trigger: Trigger) -> u32 {
+        SYSCFG.route_exti(pins.pins, exti_port(pins.port));
+        let lines = lines_for(pins.pins);
+        self.set_trigger(lines, trigger);
+        lines
+    }
+}
+
+/// Converts GPIO pins to the corresponding EXTI line mask.
+pub fn lines_for(pins: PinMask) -> u32 {
+    pins.bits() as u32
+}
+
+/// Finds the SYSCFG selector for a GPIO port accessor.
+///
+/// # Panics
+///
+/// If `port` isn't one of the `gpio` port accessors.
+pub fn exti_port(port: fn() -> &'static GpioPort) -> ExtiPort {
+    let ports: [(fn() -> &'static GpioPort, ExtiPort); 9] = [
+        (gpio::gpioa, ExtiPort::PA),
+        (gpio::gpiob, ExtiPort::PB),
+        (gpio::gpioc, ExtiPort::PC),
+        (gpio::gpiod, ExtiPort::PD),
+        (gpio::gpioe, ExtiPort::PE),
+        (gpio::gpiof, ExtiPort::PF),
+        (gpio::gpiog, ExtiPort::PG),
+        (gpio::gpioh, ExtiPort::PH),
+        (gpio::gpioi, ExtiPort::PI),
+    ];
+    match ports.iter().find(|&&(p, _)| p == port) {
+        Some(&(_, e)) => e,
+        None => panic!("unknown GPIO port"),
+    }
+}
+
+/// Shared instance of the `Exti` driver.
+pub static EXTI: Exti = Exti;
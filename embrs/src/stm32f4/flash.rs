@@ -1,14 +1,66 @@
-use arm_m::reg::Reg;
+//! Flash interface support, plus access to the factory-programmed device
+//! information and one-time-programmable (OTP) area in system memory.
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+use arm_m::reg::{mmio, Reg, RoReg};
+use super::power_marker::{self, Phase};
 
 #[repr(C, packed)]
 struct Registers {
-    acr: Reg<u32>,
+    acr:     Reg<u32>,
+    keyr:    Reg<u32>,
+    optkeyr: Reg<u32>,
+    sr:      Reg<u32>,
+    cr:      Reg<u32>,
+    optcr:   Reg<u32>,
+}
+
+register_layout! {
+    fn check_layout: Registers [0x18] {
+        acr @ 0x00,
+        keyr @ 0x04,
+        optkeyr @ 0x08,
+        sr @ 0x0C,
+        cr @ 0x10,
+        optcr @ 0x14,
+    }
 }
 
 const FLASH_ADDRESS : usize = 0x40023c00;
 
+/// Keys written, in order, to `KEYR` to unlock `CR`.
+const KEYR_KEYS : [u32; 2] = [0x45670123, 0xcdef89ab];
+/// Keys written, in order, to `OPTKEYR` to unlock `OPTCR`.
+const OPTKEYR_KEYS : [u32; 2] = [0x08192a3b, 0x4c5d6e7f];
+
+/// RDP byte value for read protection level 0.
+const RDP_LEVEL0 : u8 = 0xAA;
+/// RDP byte value for read protection level 2.  Any value other than this and
+/// `RDP_LEVEL0` selects level 1.
+const RDP_LEVEL2 : u8 = 0xCC;
+
+/// Address of the 96-bit unique device ID.
+const UNIQUE_ID_ADDRESS : usize = 0x1fff7a10;
+/// Address of the 16-bit Flash size register, in kiB.
+const FLASH_SIZE_ADDRESS : usize = 0x1fff7a22;
+/// Base address of the OTP data blocks.
+const OTP_ADDRESS : usize = 0x1fff7800;
+/// Base address of the OTP lock bytes, one per block.
+const OTP_LOCK_ADDRESS : usize = 0x1fff7a00;
+
+/// Size of each OTP data block in bytes.
+pub const OTP_BLOCK_SIZE : usize = 32;
+
 bit_wrappers! {
     pub struct Acr(pub u32);
+    /// Wrapper for the Flash Status Register bits.
+    pub struct Sr(pub u32);
+    /// Wrapper for the Flash Control Register bits.
+    pub struct Cr(pub u32);
+    /// Wrapper for the Flash Option Control Register bits.
+    pub struct Optcr(pub u32);
 }
 
 impl Acr {
@@ -22,13 +74,203 @@ impl Acr {
     }
 }
 
+impl Sr {
+    bitfield_accessors! {
+        /// Set while a Flash operation is in progress.
+        pub total [16] get_bsy / with_bsy: bool,
+        /// Programming sequence error.
+        pub total [7] get_pgserr / with_pgserr: bool,
+        /// Programming parallelism error.
+        pub total [6] get_pgperr / with_pgperr: bool,
+        /// Programming alignment error.
+        pub total [5] get_pgaerr / with_pgaerr: bool,
+        /// Write protection error.
+        pub total [4] get_wrperr / with_wrperr: bool,
+        /// Operation error.
+        pub total [1] get_operr / with_operr: bool,
+        /// End of operation.
+        pub total [0] get_eop / with_eop: bool,
+    }
+}
+
+impl Cr {
+    bitfield_accessors! {
+        /// Locks `CR`; cleared only by the `KEYR` unlock sequence.
+        pub total [31] get_lock / with_lock: bool,
+        /// Enables the error interrupt.
+        pub total [25] get_errie / with_errie: bool,
+        /// Enables the end-of-operation interrupt.
+        pub total [24] get_eopie / with_eopie: bool,
+        /// Starts an erase operation.
+        pub total [16] get_strt / with_strt: bool,
+        /// Program size, which must agree with the supply voltage.
+        pub total [9:8] get_psize / with_psize: ProgramSize,
+        /// Sector number for sector erase.
+        pub total [6:3] get_snb / with_snb: u32,
+        /// Selects mass erase.
+        pub total [2] get_mer / with_mer: bool,
+        /// Selects sector erase.
+        pub total [1] get_ser / with_ser: bool,
+        /// Selects programming.
+        pub total [0] get_pg / with_pg: bool,
+    }
+}
+
+impl Optcr {
+    bitfield_accessors! {
+        /// Write protection for sectors 0-11, one bit per sector.  Note that
+        /// the sense is inverted: a *clear* bit protects the sector.
+        pub total [27:16] get_nwrp / with_nwrp: u32,
+        /// Raw read protection byte; see `get_rdp_level` and `with_rdp_level`.
+        pub total [15:8] get_rdp / with_rdp: u8,
+        /// When clear, entering Standby mode generates a reset.
+        pub total [7] get_nrst_stdby / with_nrst_stdby: bool,
+        /// When clear, entering Stop mode generates a reset.
+        pub total [6] get_nrst_stop / with_nrst_stop: bool,
+        /// When set, the independent watchdog is started by software;
+        /// when clear, it starts automatically at reset.
+        pub total [5] get_wdg_sw / with_wdg_sw: bool,
+        /// Brown-out reset threshold.
+        pub total [3:2] get_bor_lev / with_bor_lev: BorLevel,
+        /// Starts an option byte programming operation.
+        pub total [1] get_optstrt / with_optstrt: bool,
+        /// Locks `OPTCR`; cleared only by the `OPTKEYR` unlock sequence.
+        pub total [0] get_optlock / with_optlock: bool,
+    }
+
+    /// Interprets the RDP byte as a read protection level.
+    pub fn get_rdp_level(self) -> RdpLevel {
+        match self.get_rdp() {
+            RDP_LEVEL0 => RdpLevel::Level0,
+            RDP_LEVEL2 => RdpLevel::Level2,
+            _ => RdpLevel::Level1,
+        }
+    }
+
+    /// Sets the RDP byte to the canonical value for a read protection level.
+    pub fn with_rdp_level(self, v: RdpLevel) -> Self {
+        self.with_rdp(match v {
+            RdpLevel::Level0 => RDP_LEVEL0,
+            RdpLevel::Level1 => 0x55,
+            RdpLevel::Level2 => RDP_LEVEL2,
+        })
+    }
+
+    /// Checks whether Flash sector `sector` (0-11) is write protected.
+    pub fn get_write_protected(self, sector: u32) -> bool {
+        (self.get_nwrp() & (1 << sector)) == 0
+    }
+
+    /// Sets or clears write protection for Flash sector `sector` (0-11).
+    pub fn with_write_protected(self, sector: u32, protected: bool) -> Self {
+        let nwrp = self.get_nwrp();
+        self.with_nwrp(if protected {
+            nwrp & !(1 << sector)
+        } else {
+            nwrp | (1 << sector)
+        })
+    }
+}
+
+/// Read protection levels.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum RdpLevel {
+    /// No read protection.
+    Level0,
+    /// Debug and boot-from-RAM access to Flash is blocked.  Reverting to level
+    /// 0 triggers a mass erase.
+    Level1,
+    /// Debug is permanently disabled and the option bytes are frozen.  This
+    /// cannot be undone.
+    Level2,
+}
+
+bit_enums! {
+    /// Brown-out reset threshold levels.  See the datasheet for voltages.
+    pub bit_enum BorLevel {
+        Level3 = 0b00,
+        Level2 = 0b01,
+        Level1 = 0b10,
+        Off = 0b11,
+    }
+
+    /// Width of each programming operation.
+    pub bit_enum ProgramSize {
+        X8  = 0b00,
+        X16 = 0b01,
+        X32 = 0b10,
+        X64 = 0b11,
+    }
+}
+
+/// Names the sixteen 32-byte OTP blocks.  Using an enum avoids range checks.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum OtpBlock {
+    B0 = 0, B1, B2, B3, B4, B5, B6, B7,
+    B8, B9, B10, B11, B12, B13, B14, B15,
+}
+
+impl OtpBlock {
+    fn address(self) -> usize {
+        OTP_ADDRESS + (self as usize) * OTP_BLOCK_SIZE
+    }
+
+    fn lock_address(self) -> usize {
+        OTP_LOCK_ADDRESS + (self as usize)
+    }
+}
+
+/// Ways that Flash programming can fail.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum FlashError {
+    /// The write would run off the end of the target region.
+    OutOfRange,
+    /// The target OTP block has been locked.
+    OtpLocked,
+    /// Some target byte has already been programmed, and OTP bytes can only
+    /// be written once.
+    AlreadyProgrammed,
+    /// The hardware reported a programming sequence error.
+    Sequence,
+    /// The hardware reported a parallelism error.
+    Parallelism,
+    /// The hardware reported an alignment error.
+    Alignment,
+    /// The hardware reported a write protection error.
+    WriteProtected,
+    /// The hardware reported an operation error.
+    Operation,
+    /// The requested change can't be undone, and the caller didn't say that
+    /// was okay.
+    Irreversible,
+    /// An erase request doesn't cover whole sectors, or a `FlashBlocks`
+    /// request whole blocks.
+    Unaligned,
+}
+
+/// Token proving that the option bytes have been unlocked for programming.
+/// Obtained from `Flash::unlock_options`; the option bytes are locked again
+/// when the token is dropped.  Only one exists at a time.
+pub struct OptionUnlock {
+    _private: (),
+}
+
+impl Drop for OptionUnlock {
+    fn drop(&mut self) {
+        FLASH.update_optcr(|v| v.with_optlock(true));
+        OPTIONS_UNLOCKED.store(false, Ordering::Release)
+    }
+}
+
+/// Set while an `OptionUnlock` exists, so that dropping one can't relock
+/// the option bytes under another.
+static OPTIONS_UNLOCKED : AtomicBool = ATOMIC_BOOL_INIT;
+
 pub struct Flash;
 
 impl Flash {
     fn reg(&self) -> &Registers {
-        unsafe {
-            &*(FLASH_ADDRESS as *const Registers)
-        }
+        unsafe { mmio(FLASH_ADDRESS) }
     }
 
     pub fn read_acr(&self) -> Acr {
@@ -42,6 +284,284 @@ impl Flash {
     pub fn update_acr<F: FnOnce(Acr) -> Acr>(&self, f: F) {
         self.write_acr(f(self.read_acr()))
     }
+
+    pub fn read_sr(&self) -> Sr {
+        Sr(self.reg().sr.get())
+    }
+
+    pub fn write_sr(&self, v: Sr) {
+        self.reg().sr.set(v.0)
+    }
+
+    pub fn read_cr(&self) -> Cr {
+        Cr(self.reg().cr.get())
+    }
+
+    pub fn write_cr(&self, v: Cr) {
+        self.reg().cr.set(v.0)
+    }
+
+    pub fn update_cr<F: FnOnce(Cr) -> Cr>(&self, f: F) {
+        self.write_cr(f(self.read_cr()))
+    }
+
+    pub fn read_optcr(&self) -> Optcr {
+        Optcr(self.reg().optcr.get())
+    }
+
+    fn update_optcr<F: FnOnce(Optcr) -> Optcr>(&self, f: F) {
+        self.reg().optcr.set(f(self.read_optcr()).0)
+    }
+
+    /// Unlocks the option bytes for programming.  They remain unlocked until
+    /// the returned token is dropped.  Returns `None` if a token is already
+    /// out.
+    pub fn unlock_options(&self) -> Option<OptionUnlock> {
+        if OPTIONS_UNLOCKED.compare_and_swap(false, true, Ordering::Acquire) {
+            return None
+        }
+        if self.read_optcr().get_optlock() {
+            for &k in OPTKEYR_KEYS.iter() {
+                self.reg().optkeyr.set(k)
+            }
+        }
+        Some(OptionUnlock { _private: () })
+    }
+
+    /// Programs the option bytes with the result of applying `f` to their
+    /// current contents, and waits for programming to complete.
+    ///
+    /// Setting read protection level 2 permanently disables debug access and
+    /// freezes the option bytes, so it is refused (with `Irreversible`) unless
+    /// `permanent` is `true`.
+    ///
+    /// New settings are not fully applied until the next reset.  Lowering read
+    /// protection from level 1 to level 0 mass-erases the Flash, including the
+    /// code calling this function.
+    pub fn program_options<F>(&self, _unlock: &OptionUnlock, permanent: bool,
+                              f: F)
+        -> Result<(), FlashError>
+        where F: FnOnce(Optcr) -> Optcr
+    {
+        let current = self.read_optcr();
+        let new = f(current).with_optlock(false).with_optstrt(false);
+
+        if new.get_rdp_level() == RdpLevel::Level2
+            && current.get_rdp_level() != RdpLevel::Level2
+            && !permanent {
+            return Err(FlashError::Irreversible)
+        }
+
+        try!(self.wait_idle());
+        power_marker::mark(Phase::FlashWrite, || {
+            self.reg().optcr.set(new.0);
+            self.reg().optcr.set(new.with_optstrt(true).0);
+            self.wait_idle()
+        })
+    }
+
+    /// Reads the 96-bit unique device ID, least significant word first.
+    pub fn read_unique_id(&self) -> [u32; 3] {
+        let id: &[RoReg<u32>; 3] = unsafe { mmio(UNIQUE_ID_ADDRESS) };
+        [id[0].get(), id[1].get(), id[2].get()]
+    }
+
+    /// Reads the size of the on-chip Flash, in kiB.
+    pub fn read_flash_size_kib(&self) -> u16 {
+        let size: &RoReg<u16> = unsafe { mmio(FLASH_SIZE_ADDRESS) };
+        size.get()
+    }
+
+    /// Reads the contents of an OTP block.
+    pub fn read_otp(&self, block: OtpBlock) -> [u8; OTP_BLOCK_SIZE] {
+        let mut out = [0; OTP_BLOCK_SIZE];
+        let data: &[RoReg<u8>; OTP_BLOCK_SIZE] =
+            unsafe { mmio(block.address()) };
+        for (b, r) in out.iter_mut().zip(data.iter()) {
+            *b = r.get();
+        }
+        out
+    }
+
+    /// Checks whether an OTP block has been locked against further
+    /// programming.
+    pub fn is_otp_locked(&self, block: OtpBlock) -> bool {
+        let lock: &RoReg<u8> = unsafe { mmio(block.lock_address()) };
+        lock.get() != 0xFF
+    }
+
+    /// Permanently programs `data` into OTP block `block`, starting at byte
+    /// `offset`.
+    ///
+    /// OTP bytes can be written exactly once, so this refuses (with
+    /// `AlreadyProgrammed`) to touch any byte that doesn't still read as
+    /// `0xFF`; nothing is written unless the whole range is blank.  Locked
+    /// blocks are likewise refused.
+    ///
+    /// Programming uses byte-wide accesses, which are valid at any supply
+    /// voltage.  This busy-waits for each byte, and must not be called while
+    /// code is being fetched from a Flash bank that another party is erasing.
+    pub fn program_otp(&self, block: OtpBlock, offset: usize, data: &[u8])
+        -> Result<(), FlashError> {
+        if offset > OTP_BLOCK_SIZE || data.len() > OTP_BLOCK_SIZE - offset {
+            return Err(FlashError::OutOfRange)
+        }
+        if self.is_otp_locked(block) {
+            return Err(FlashError::OtpLocked)
+        }
+        let current = self.read_otp(block);
+        if current[offset .. offset + data.len()].iter().any(|&b| b != 0xFF) {
+            return Err(FlashError::AlreadyProgrammed)
+        }
+
+        unsafe {
+            self.program_bytes(block.address() + offset, data)
+        }
+    }
+
+    /// Permanently locks OTP block `block` against programming.  Locking an
+    /// already-locked block is a no-op.
+    pub fn lock_otp(&self, block: OtpBlock) -> Result<(), FlashError> {
+        if self.is_otp_locked(block) {
+            return Ok(())
+        }
+
+        unsafe {
+            self.program_bytes(block.lock_address(), &[0])
+        }
+    }
+
+    /// Programs `data` into Flash starting at `address`, one byte at a time.
+    ///
+    /// The Flash controller is unlocked for the duration and re-locked before
+    /// return, even on failure.  The operation is bracketed by the `FlashWrite`
+    /// power marker.
+    ///
+    /// # Safety
+    ///
+    /// This will happily overwrite program code or constant data, and can
+    /// only clear bits; callers are responsible for choosing a target range
+    /// that is erased and not otherwise in use.
+    pub unsafe fn program_bytes(&self, address: usize, data: &[u8])
+        -> Result<(), FlashError> {
+        power_marker::mark(Phase::FlashWrite, || {
+            self.unlock();
+            let result = self.program_bytes_unlocked(address, data);
+            self.lock();
+            result
+        })
+    }
+
+    unsafe fn program_bytes_unlocked(&self, address: usize, data: &[u8])
+        -> Result<(), FlashError> {
+        try!(self.wait_idle());
+        self.update_cr(|v| v.with_psize(ProgramSize::X8).with_pg(true));
+
+        let mut result = Ok(());
+        for (i, &b) in data.iter().enumerate() {
+            ptr::write_volatile((address + i) as *mut u8, b);
+            result = self.wait_idle();
+            if result.is_err() { break }
+        }
+
+        self.update_cr(|v| v.with_pg(false));
+        result
+    }
+
+    /// Erases Flash sector `sector` (0-11), waiting for it to finish -- which
+    /// can take a couple of seconds for the larger sectors.  Like
+    /// `program_bytes`, this unlocks and re-locks the controller and is
+    /// bracketed by the `FlashWrite` power marker.
+    ///
+    /// # Safety
+    ///
+    /// As for `start_erase_sector`.
+    pub unsafe fn erase_sector(&self, sector: u32) -> Result<(), FlashError> {
+        power_marker::mark(Phase::FlashWrite, || {
+            self.unlock();
+            let result = self.wait_idle().and_then(|_| {
+                self.start_erase_sector(sector);
+                self.wait_idle()
+            });
+            self.end_erase();
+            self.lock();
+            result
+        })
+    }
+
+    /// Begins erasing Flash sector `sector` (0-11) and returns without waiting
+    /// for it to finish.  `CR` must already be unlocked.  Completion can be
+    /// detected using `is_busy`, after which `take_error` reports the outcome
+    /// and `end_erase` must be called.
+    ///
+    /// Erasing uses 32-bit parallelism, which requires a supply of at least
+    /// 2.7V.
+    ///
+    /// # Safety
+    ///
+    /// The caller is responsible for ensuring that nothing in the sector is
+    /// still needed -- in particular, not the code doing the erasing.
+    pub unsafe fn start_erase_sector(&self, sector: u32) {
+        self.update_cr(|v| v.with_pg(false)
+                       .with_psize(ProgramSize::X32)
+                       .with_snb(sector)
+                       .with_ser(true));
+        self.update_cr(|v| v.with_strt(true))
+    }
+
+    /// Leaves sector erase mode after `start_erase_sector`.
+    pub fn end_erase(&self) {
+        self.update_cr(|v| v.with_ser(false))
+    }
+
+    /// Returns `true` while a program or erase operation is in progress.
+    #[inline]
+    pub fn is_busy(&self) -> bool {
+        self.read_sr().get_bsy()
+    }
+
+    /// Unlocks `CR` for writing, if it is locked.
+    pub fn unlock(&self) {
+        if self.read_cr().get_lock() {
+            for &k in KEYR_KEYS.iter() {
+                self.reg().keyr.set(k)
+            }
+        }
+    }
+
+    /// Locks `CR` against writes until the next `unlock`.
+    pub fn lock(&self) {
+        self.update_cr(|v| v.with_lock(true))
+    }
+
+    /// Waits for any ongoing operation to finish, then checks for and clears
+    /// any error flags.
+    pub fn wait_idle(&self) -> Result<(), FlashError> {
+        while self.read_sr().get_bsy() {}
+        self.take_error()
+    }
+
+    /// Checks for and clears any error flags.
+    pub fn take_error(&self) -> Result<(), FlashError> {
+        let sr = self.read_sr();
+        // Error and EOP flags are write-one-to-clear; writing back what we
+        // read clears exactly the flags we've seen.
+        self.write_sr(sr.with_bsy(false));
+
+        if sr.get_pgserr() {
+            Err(FlashError::Sequence)
+        } else if sr.get_pgperr() {
+            Err(FlashError::Parallelism)
+        } else if sr.get_pgaerr() {
+            Err(FlashError::Alignment)
+        } else if sr.get_wrperr() {
+            Err(FlashError::WriteProtected)
+        } else if sr.get_operr() {
+            Err(FlashError::Operation)
+        } else {
+            Ok(())
+        }
+    }
 }
 
 pub static FLASH : Flash = Flash;
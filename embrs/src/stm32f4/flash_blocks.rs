@@ -0,0 +1,107 @@
+//! Flash sectors as a `BlockDevice`.
+//!
+//! `FlashBlocks` presents a run of the 128KiB sectors (5-11, on parts with a
+//! single 1MiB bank) as 512-byte blocks.  Flash has to be erased a whole
+//! sector at a time, and programming can only clear bits, so this isn't a
+//! drop-in disk: `erase_blocks` only accepts whole sectors, and
+//! `write_blocks` only works on erased blocks.  Filesystems that rewrite
+//! blocks in place (FAT, notably) need a translation layer on top.
+
+use core::slice;
+
+use hal::{BlockDevice, BLOCK_SIZE};
+use super::flash::{FlashError, FLASH};
+
+/// The first of the uniform 128KiB sectors.
+const FIRST_LARGE_SECTOR : u32 = 5;
+/// The last sector on single-bank parts.
+const LAST_SECTOR : u32 = 11;
+/// Address of `FIRST_LARGE_SECTOR`.
+const LARGE_SECTORS_ADDRESS : usize = 0x08020000;
+const LARGE_SECTOR_SIZE : usize = 128 * 1024;
+
+/// Blocks in each sector.
+pub const BLOCKS_PER_SECTOR : u32 = (LARGE_SECTOR_SIZE / BLOCK_SIZE) as u32;
+
+/// A run of Flash sectors used as block storage.
+pub struct FlashBlocks {
+    first_sector: u32,
+    sector_count: u32,
+}
+
+impl FlashBlocks {
+    /// Uses `sector_count` sectors, starting at `first_sector`.
+    ///
+    /// # Panics
+    ///
+    /// If the sectors aren't all among sectors 5-11.
+    ///
+    /// # Safety
+    ///
+    /// The sectors must hold nothing else -- in particular, no code.
+    pub unsafe fn new(first_sector: u32, sector_count: u32) -> FlashBlocks {
+        assert!(first_sector >= FIRST_LARGE_SECTOR
+                && sector_count <= LAST_SECTOR + 1 - first_sector);
+        FlashBlocks {
+            first_sector: first_sector,
+            sector_count: sector_count,
+        }
+    }
+
+    fn address(&self, block: u32) -> usize {
+        LARGE_SECTORS_ADDRESS
+            + (self.first_sector - FIRST_LARGE_SECTOR) as usize
+                * LARGE_SECTOR_SIZE
+            + block as usize * BLOCK_SIZE
+    }
+
+    /// Checks that `len` bytes from `block` are whole blocks within the
+    /// device.
+    fn check(&self, block: u32, len: usize) -> Result<(), FlashError> {
+        if len % BLOCK_SIZE != 0 {
+            return Err(FlashError::Unaligned)
+        }
+        let count = (len / BLOCK_SIZE) as u32;
+        let total = self.block_count();
+        if block > total || count > total - block {
+            Err(FlashError::OutOfRange)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl BlockDevice for FlashBlocks {
+    type Error = FlashError;
+
+    fn block_count(&self) -> u32 {
+        self.sector_count * BLOCKS_PER_SECTOR
+    }
+
+    fn read_blocks(&self, block: u32, buf: &mut [u8])
+        -> Result<(), FlashError> {
+        try!(self.check(block, buf.len()));
+        let src = unsafe {
+            slice::from_raw_parts(self.address(block) as *const u8, buf.len())
+        };
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn write_blocks(&self, block: u32, data: &[u8]) -> Result<(), FlashError> {
+        try!(self.check(block, data.len()));
+        unsafe { FLASH.program_bytes(self.address(block), data) }
+    }
+
+    fn erase_blocks(&self, block: u32, count: u32) -> Result<(), FlashError> {
+        try!(self.check(block, count as usize * BLOCK_SIZE));
+        if block % BLOCKS_PER_SECTOR != 0 || count % BLOCKS_PER_SECTOR != 0 {
+            return Err(FlashError::Unaligned)
+        }
+        let first = self.first_sector + block / BLOCKS_PER_SECTOR;
+        for sector in first..(first + count / BLOCKS_PER_SECTOR) {
+            try!(unsafe { FLASH.erase_sector(sector) })
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,104 @@
+//! Flash sectors as `NorFlash`.
+//!
+//! `FlashSectors` presents a run of equally-sized sectors -- the 16KiB
+//! sectors 0-3, the 64KiB sector 4, or some of the 128KiB sectors 5-11 on
+//! parts with a single 1MiB bank -- as a byte-addressed `NorFlash` region,
+//! for the wear-levelling layers in `storage`.  The small sectors are the
+//! usual home for settings, since erasing them is quick; linker scripts
+//! that put the vector table in sector 0 leave sectors 1-3 free for them.
+
+use core::slice;
+
+use hal::NorFlash;
+use super::flash::{FlashError, FLASH};
+
+/// The last sector on single-bank parts.
+const LAST_SECTOR : u32 = 11;
+/// Address of sector 0.
+const FLASH_BASE : usize = 0x08000000;
+
+/// Returns the address and size of `sector`.
+fn sector_extent(sector: u32) -> (usize, usize) {
+    match sector {
+        0...3 => (FLASH_BASE + sector as usize * 0x4000, 0x4000),
+        4 => (FLASH_BASE + 0x10000, 0x10000),
+        _ => (FLASH_BASE + (sector as usize - 4) * 0x20000, 0x20000),
+    }
+}
+
+/// A run of equally-sized Flash sectors.
+pub struct FlashSectors {
+    first_sector: u32,
+    sector_count: u32,
+    address: usize,
+    sector_size: usize,
+}
+
+impl FlashSectors {
+    /// Uses `sector_count` sectors, starting at `first_sector`.
+    ///
+    /// # Panics
+    ///
+    /// If the sectors run past sector 11, or aren't all the same size.
+    ///
+    /// # Safety
+    ///
+    /// The sectors must hold nothing else -- in particular, no code.
+    pub unsafe fn new(first_sector: u32, sector_count: u32) -> FlashSectors {
+        assert!(sector_count > 0
+                && first_sector <= LAST_SECTOR
+                && sector_count <= LAST_SECTOR + 1 - first_sector);
+        let (address, size) = sector_extent(first_sector);
+        let (_, last_size) = sector_extent(first_sector + sector_count - 1);
+        assert!(size == last_size);
+        FlashSectors {
+            first_sector: first_sector,
+            sector_count: sector_count,
+            address: address,
+            sector_size: size,
+        }
+    }
+
+    fn check(&self, offset: usize, len: usize) -> Result<(), FlashError> {
+        let total = self.sector_size * self.sector_count as usize;
+        if offset > total || len > total - offset {
+            Err(FlashError::OutOfRange)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl NorFlash for FlashSectors {
+    type Error = FlashError;
+
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sector_count(&self) -> u32 {
+        self.sector_count
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), FlashError> {
+        try!(self.check(offset, buf.len()));
+        let src = unsafe {
+            slice::from_raw_parts((self.address + offset) as *const u8,
+                                  buf.len())
+        };
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn program(&self, offset: usize, data: &[u8]) -> Result<(), FlashError> {
+        try!(self.check(offset, data.len()));
+        unsafe { FLASH.program_bytes(self.address + offset, data) }
+    }
+
+    fn erase_sector(&self, sector: u32) -> Result<(), FlashError> {
+        if sector >= self.sector_count {
+            return Err(FlashError::OutOfRange)
+        }
+        unsafe { FLASH.erase_sector(self.first_sector + sector) }
+    }
+}
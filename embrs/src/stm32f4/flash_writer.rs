@@ -0,0 +1,179 @@
+//! Cooperative, time-budgeted Flash programming and erasing.
+//!
+//! `FLASH.program_bytes` busy-waits until the whole job is done, which is fine
+//! at startup but not in a system with real-time work to do.  `FlashWriter`
+//! instead breaks each job into slices: every call to `poll` does as much as
+//! it can within a cycle budget (measured with the DWT cycle counter) and then
+//! returns, leaving the rest for the next call.  The application calls `poll`
+//! periodically -- from its main loop, or a low-priority timer handler --
+//! until the job completes.
+//!
+//! Note that the STM32F4's Flash stalls *instruction fetches* while an
+//! operation is in progress.  The budget bounds how long `poll` itself runs,
+//! but interrupt handlers will only run promptly during a sector erase if they
+//! (and the vector table) execute from RAM.
+//!
+//! While a job is in progress, `FlashWriter` assumes it owns the Flash
+//! controller; don't mix it with direct calls to the `Flash` driver.
+
+use core::ptr;
+
+use arm_m::dwt::DWT;
+use super::flash::{FLASH, FlashError, ProgramSize};
+use super::iwdg::IWDG;
+use super::power_marker::{self, Phase};
+
+/// The job in progress.
+enum Job<'a> {
+    Idle,
+    Program {
+        address: usize,
+        data: &'a [u32],
+        /// Index into `data` of the next word to program.
+        next: usize,
+    },
+    Erase,
+}
+
+/// Result of a call to `FlashWriter::poll`.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum Status {
+    /// There was nothing to do.
+    Idle,
+    /// The job is in progress; call `poll` again.
+    Busy,
+    /// The job has finished, with the given result.  The writer is now idle.
+    Done(Result<(), FlashError>),
+}
+
+/// Error produced when a job is submitted while another is in progress.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct WriterBusy;
+
+/// Cooperative Flash writer.  See the module docs.
+pub struct FlashWriter<'a> {
+    job: Job<'a>,
+}
+
+impl<'a> FlashWriter<'a> {
+    /// Creates an idle writer.
+    pub const fn new() -> FlashWriter<'a> {
+        FlashWriter { job: Job::Idle }
+    }
+
+    /// Checks whether a job is in progress.
+    pub fn is_idle(&self) -> bool {
+        match self.job {
+            Job::Idle => true,
+            _ => false,
+        }
+    }
+
+    /// Submits a job to program `data` into Flash starting at `address`, which
+    /// must be word-aligned.  No work is done until `poll`.
+    ///
+    /// Programming uses 32-bit parallelism, which requires a supply of at
+    /// least 2.7V.
+    ///
+    /// # Safety
+    ///
+    /// As for `Flash::program_bytes`: the target range must be erased and not
+    /// otherwise in use.
+    pub unsafe fn program(&mut self, address: usize, data: &'a [u32])
+        -> Result<(), WriterBusy> {
+        if !self.is_idle() {
+            return Err(WriterBusy)
+        }
+        self.job = Job::Program {
+            address: address,
+            data: data,
+            next: 0,
+        };
+
+        power_marker::begin(Phase::FlashWrite);
+        FLASH.unlock();
+        FLASH.update_cr(|v| v.with_psize(ProgramSize::X32).with_pg(true));
+        Ok(())
+    }
+
+    /// Submits a job to erase Flash sector `sector` (0-11) and starts it.  A
+    /// sector erase takes up to a couple of seconds; `poll` reports when it's
+    /// done.
+    ///
+    /// # Safety
+    ///
+    /// As for `Flash::start_erase_sector`: nothing in the sector may still be
+    /// needed.
+    pub unsafe fn erase_sector(&mut self, sector: u32)
+        -> Result<(), WriterBusy> {
+        if !self.is_idle() {
+            return Err(WriterBusy)
+        }
+        self.job = Job::Erase;
+
+        power_marker::begin(Phase::FlashWrite);
+        FLASH.unlock();
+        FLASH.start_erase_sector(sector);
+        Ok(())
+    }
+
+    /// Advances the current job, spending no more than roughly
+    /// `budget_cycles` CPU cycles.  (A single word program, once started, is
+    /// not interrupted, so the budget can be overrun by one word's worth.)
+    ///
+    /// The independent watchdog is fed while waiting on the hardware, since a
+    /// sector erase can outlast any reasonable watchdog timeout.
+    pub fn poll(&mut self, budget_cycles: u32) -> Status {
+        if self.is_idle() {
+            return Status::Idle
+        }
+
+        DWT.enable_cycle_counter();
+        let start = DWT.read_cycle_count();
+        let elapsed = || DWT.read_cycle_count().wrapping_sub(start);
+
+        loop {
+            if FLASH.is_busy() {
+                IWDG.feed();
+                if elapsed() > budget_cycles {
+                    return Status::Busy
+                }
+                continue
+            }
+
+            let result = FLASH.take_error();
+            if result.is_ok() {
+                if let Job::Program { address, data, ref mut next } = self.job {
+                    if *next < data.len() {
+                        if elapsed() > budget_cycles {
+                            return Status::Busy
+                        }
+                        unsafe {
+                            ptr::write_volatile(
+                                (address + *next * 4) as *mut u32,
+                                data[*next]);
+                        }
+                        *next += 1;
+                        continue
+                    }
+                }
+            }
+
+            return Status::Done(self.finish(result))
+        }
+    }
+
+    /// Wraps up the current job, returning to idle.
+    fn finish(&mut self, result: Result<(), FlashError>)
+        -> Result<(), FlashError> {
+        match self.job {
+            Job::Program { .. } => FLASH.update_cr(|v| v.with_pg(false)),
+            Job::Erase => FLASH.end_erase(),
+            Job::Idle => (),
+        }
+        FLASH.lock();
+        power_marker::end(Phase::FlashWrite);
+        self.job = Job::Idle;
+        result
+    }
+}
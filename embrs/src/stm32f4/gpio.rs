@@ -6,7 +6,8 @@
 
 #![allow(trivial_numeric_casts)]  // required for bitflags :-(
 
-use arm_m::reg::{AtomicReg,Reg};
+use arm_m::reg::{mmio, AtomicReg, Reg, RoReg, WoReg};
+use hal::{DigitalInOut, DigitalInput, DigitalOutput};
 
 /// A GPIO port's memory mapped registers.
 #[repr(C, packed)]
@@ -15,14 +16,29 @@ pub struct GpioPort {
     pub otyper:  Reg<u32>,
     pub ospeedr: Reg<u32>,
     pub pupdr:   Reg<u32>,
-    pub idr:     Reg<u32>,
+    pub idr:     RoReg<u32>,
     pub odr:     Reg<u32>,
-    pub bsrr:    Reg<u32>,
+    pub bsrr:    WoReg<u32>,
     pub lckr:    Reg<u32>,
     pub afrl:    Reg<u32>,
     pub afrh:    Reg<u32>,
 }
 
+register_layout! {
+    fn check_layout: GpioPort [0x28] {
+        moder @ 0x00,
+        otyper @ 0x04,
+        ospeedr @ 0x08,
+        pupdr @ 0x0C,
+        idr @ 0x10,
+        odr @ 0x14,
+        bsrr @ 0x18,
+        lckr @ 0x1C,
+        afrl @ 0x20,
+        afrh @ 0x24,
+    }
+}
+
 /// Possible modes of a GPIO pin.
 #[derive(Clone, Copy)]
 pub enum Mode {
@@ -213,12 +229,93 @@ macro_rules! static_gpio {
     ($name:ident, $addr:expr) => {
         #[inline]
         pub fn $name() -> &'static GpioPort {
-            unsafe {
-                &*($addr as *const GpioPort)
-            }
+            unsafe { mmio($addr) }
         }
     };
 }
 
 static_gpio!(gpioa, 0x40020000);
+static_gpio!(gpiob, 0x40020400);
+static_gpio!(gpioc, 0x40020800);
 static_gpio!(gpiod, 0x40020c00);
+static_gpio!(gpioe, 0x40021000);
+static_gpio!(gpiof, 0x40021400);
+static_gpio!(gpiog, 0x40021800);
+static_gpio!(gpioh, 0x40021c00);
+static_gpio!(gpioi, 0x40022000);
+
+/// Names a group of pins on a particular port, for drivers that need to be
+/// told which pin(s) to use.  Because it holds only a function pointer and a
+/// mask, it can be built in a `static`:
+///
+///     static LED: Pins = Pins { port: gpiod, pins: P12 };
+#[derive(Copy, Clone)]
+pub struct Pins {
+    /// Accessor for the GPIO port, e.g. `gpiod`.
+    pub port: fn() -> &'static GpioPort,
+    /// Pin(s) on `port`.
+    pub pins: PinMask,
+}
+
+impl Pins {
+    /// Sets the pins to logic high.
+    #[inline]
+    pub fn set(&self) {
+        (self.port)().set(self.pins)
+    }
+
+    /// Clears the pins to logic low.
+    #[inline]
+    pub fn clear(&self) {
+        (self.port)().clear(self.pins)
+    }
+
+    /// Reads the pins; the result contains those observed as logic high.
+    #[inline]
+    pub fn get(&self) -> PinMask {
+        (self.port)().get(self.pins)
+    }
+
+    /// Configures the pins as push-pull digital outputs.
+    pub fn configure_output(&self) {
+        let port = (self.port)();
+        port.set_output_type(self.pins, OutputType::PushPull);
+        port.set_mode(self.pins, Mode::Gpio)
+    }
+
+    /// Routes the pins to alternate function `af`, with the given pull
+    /// configuration.
+    pub fn configure_alternate(&self, af: Function, pull: Pull) {
+        let port = (self.port)();
+        port.set_pull(self.pins, pull);
+        port.set_alternate_function(self.pins, af);
+        port.set_mode(self.pins, Mode::Alternate)
+    }
+}
+
+impl DigitalOutput for Pins {
+    fn set_high(&self) {
+        self.set()
+    }
+
+    fn set_low(&self) {
+        self.clear()
+    }
+}
+
+impl DigitalInput for Pins {
+    fn is_high(&self) -> bool {
+        self.get() == self.pins
+    }
+
+    fn is_low(&self) -> bool {
+        self.get().is_empty()
+    }
+}
+
+impl DigitalInOut for Pins {
+    fn set_output_enabled(&self, enabled: bool) {
+        let mode = if enabled { Mode::Gpio } else { Mode::Input };
+        (self.port)().set_mode(self.pins, mode)
+    }
+}
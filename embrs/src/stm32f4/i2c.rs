@@ -0,0 +1,1100 @@
+//! Inter-Integrated Circuit (I2C) master support.
+//!
+//! The F4's I2C block reports progress as events in SR1 and SR2, and several
+//! of them must be answered at exactly the right moment -- particularly when
+//! reading, where the ACK, POS and STOP bits have to be set before the last
+//! bytes arrive, by rules that differ for one, two, and more bytes.  `I2c`
+//! hides that behind transactions: a sequence of write and read segments,
+//! each starting with a (repeated) start and the device address, with a stop
+//! only at the end.  Reading a register is then
+//!
+//!     I2C1.transaction(addr, &mut [Segment::Write(&[reg]),
+//!                                  Segment::Read(&mut value)])
+//!
+//! `DmaI2c` does the same, moving the data of longer segments by DMA.
+//!
+//! Both implement `hal::I2cBus`.
+//!
+//! A device that stops answering -- or holds the bus -- can't hang the
+//! caller: each step of a transaction gives up after `TIMEOUT_MS` without
+//! progress, reporting `I2cError::Stuck`.  This is timed with the DWT cycle
+//! counter, which `configure` starts.
+//!
+//! A device reset or interrupted partway through a byte can be left holding
+//! SDA low, waiting for clocks that never come.  `clear_bus` frees it by
+//! clocking SCL by hand.  `transaction_with_recovery` does that when the
+//! bus is stuck, and retries after lost arbitration and bus errors too,
+//! waiting a randomized delay (see `backoff`) first so that masters that
+//! collided once don't collide again.
+//!
+//! # SMBus
+//!
+//! `configure_smbus` switches the block into SMBus mode, as host or device.
+//! With PEC enabled, the final segment of every transaction carries a packet
+//! error code: appended to a final write, or received (and checked by the
+//! hardware) after a final read.  The hardware also enforces the SMBus
+//! clock-low timeouts, reporting `I2cError::Timeout`, and watches the
+//! SMBALERT# pin (SMBA), which is routed like SCL and SDA by
+//! `configure_pins`.
+//!
+//! As a device, the block answers at its own address (and, with address
+//! resolution enabled, at the SMBus device default address) and the host
+//! drives each transaction.  Its events arrive on the I2C's interrupts,
+//! whose handlers pass them to `handle_device_event`, which in turn calls
+//! the application's `SmbusDevice` to take or supply each byte.  The
+//! application implements the protocol on top, including the ARP commands
+//! if it resolves addresses, ending with `set_device_address`.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use arm_m::dwt::DWT;
+use arm_m::reg::{mmio, Reg};
+use backoff::Backoff;
+use clock;
+use hal::{DelayUs, I2cBus};
+use super::ccm;
+use super::dma::{self, Request};
+use super::gpio::{self, Pins};
+use super::rcc::{ApbPeripheral, ClockSpeeds, RCC};
+
+#[repr(C, packed)]
+struct Registers {
+    cr1:   Reg<u32>,
+    cr2:   Reg<u32>,
+    oar1:  Reg<u32>,
+    oar2:  Reg<u32>,
+    dr:    Reg<u32>,
+    sr1:   Reg<u32>,
+    sr2:   Reg<u32>,
+    ccr:   Reg<u32>,
+    trise: Reg<u32>,
+    fltr:  Reg<u32>,
+}
+
+register_layout! {
+    fn check_layout: Registers [0x28] {
+        cr1 @ 0x00,
+        cr2 @ 0x04,
+        oar1 @ 0x08,
+        oar2 @ 0x0C,
+        dr @ 0x10,
+        sr1 @ 0x14,
+        sr2 @ 0x18,
+        ccr @ 0x1C,
+        trise @ 0x20,
+        fltr @ 0x24,
+    }
+}
+
+bit_wrappers! {
+    pub struct Cr1(pub u32);
+    pub struct Cr2(pub u32);
+    pub struct Sr1(pub u32);
+    pub struct Sr2(pub u32);
+    pub struct Ccr(pub u32);
+}
+
+impl Cr1 {
+    bitfield_accessors! {
+        pub total [15] get_swrst / with_swrst: bool,
+        /// SMBus device: drive SMBA low.
+        pub total [13] get_alert / with_alert: bool,
+        /// Transfer (or check) PEC after the current byte.
+        pub total [12] get_pec / with_pec: bool,
+        /// For a two-byte read: ACK applies to the next byte, not the
+        /// current one.
+        pub total [11] get_pos / with_pos: bool,
+        pub total [10] get_ack / with_ack: bool,
+        pub total [ 9] get_stop / with_stop: bool,
+        pub total [ 8] get_start / with_start: bool,
+        pub total [ 7] get_nostretch / with_nostretch: bool,
+        pub total [ 6] get_engc / with_engc: bool,
+        pub total [ 5] get_enpec / with_enpec: bool,
+        /// Respond to the SMBus device default address (ARP).
+        pub total [ 4] get_enarp / with_enarp: bool,
+        /// SMBus host (rather than device).
+        pub total [ 3] get_smbtype / with_smbtype: bool,
+        pub total [ 1] get_smbus / with_smbus: bool,
+        pub total [ 0] get_pe / with_pe: bool,
+    }
+}
+
+impl Cr2 {
+    bitfield_accessors! {
+        /// With DMA, NACK the byte the DMA transfer ends on.
+        pub total [12] get_last / with_last: bool,
+        pub total [11] get_dmaen / with_dmaen: bool,
+        pub total [10] get_itbufen / with_itbufen: bool,
+        pub total [ 9] get_itevten / with_itevten: bool,
+        pub total [ 8] get_iterren / with_iterren: bool,
+        /// Peripheral clock frequency, in MHz.
+        pub total [5:0] get_freq / with_freq: u32,
+    }
+}
+
+impl Sr1 {
+    bitfield_accessors! {
+        /// SMBus host: a device pulled SMBA low.
+        pub total [15] get_smbalert / with_smbalert: bool,
+        /// SCL held low past an SMBus limit.
+        pub total [14] get_timeout / with_timeout: bool,
+        pub total [12] get_pecerr / with_pecerr: bool,
+        pub total [11] get_ovr / with_ovr: bool,
+        /// Acknowledge failure: the device NACKed.
+        pub total [10] get_af / with_af: bool,
+        pub total [ 9] get_arlo / with_arlo: bool,
+        pub total [ 8] get_berr / with_berr: bool,
+        pub total [ 7] get_txe / with_txe: bool,
+        pub total [ 6] get_rxne / with_rxne: bool,
+        pub total [ 4] get_stopf / with_stopf: bool,
+        pub total [ 2] get_btf / with_btf: bool,
+        pub total [ 1] get_addr / with_addr: bool,
+        pub total [ 0] get_sb / with_sb: bool,
+    }
+}
+
+impl Sr2 {
+    bitfield_accessors! {
+        /// PEC computed so far, when ENPEC is set.
+        pub total [15:8] get_pec / with_pec: u32,
+        pub total [6] get_smbhost / with_smbhost: bool,
+        pub total [5] get_smbdefault / with_smbdefault: bool,
+        pub total [2] get_tra / with_tra: bool,
+        pub total [1] get_busy / with_busy: bool,
+        pub total [0] get_msl / with_msl: bool,
+    }
+}
+
+impl Ccr {
+    bitfield_accessors! {
+        /// Fast mode.
+        pub total [15] get_fs / with_fs: bool,
+        pub total [14] get_duty / with_duty: bool,
+        pub total [11:0] get_ccr / with_ccr: u32,
+    }
+}
+
+/// SR1 error flags, cleared by writing zero: TIMEOUT, PECERR, OVR, AF, ARLO
+/// and BERR.
+const SR1_ERRORS : u32 =
+    (1 << 14) | (1 << 12) | (1 << 11) | (1 << 10) | (1 << 9) | (1 << 8);
+
+/// SR1's SMBALERT flag, also cleared by writing zero.
+const SR1_SMBALERT : u32 = 1 << 15;
+
+/// SR1's acknowledge failure flag, likewise.
+const SR1_AF : u32 = 1 << 10;
+
+/// OAR1 bit 14, which the Reference Manual says must be kept set.
+const OAR1_RESERVED_ONE : u32 = 1 << 14;
+
+/// Address a host reads to learn which device is asserting SMBALERT#.
+pub const SMBUS_ALERT_RESPONSE : u8 = 0x0C;
+
+/// Bus speeds.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum I2cSpeed {
+    /// 100 kHz.
+    Standard,
+    /// 400 kHz.
+    Fast,
+}
+
+/// Which end of an SMBus we are.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SmbusRole {
+    Host,
+    /// A device answering at the given seven-bit address.
+    Device(u8),
+}
+
+/// SMBus settings for `I2c::configure_smbus`.
+#[derive(Copy, Clone, Debug)]
+pub struct SmbusConfig {
+    pub role: SmbusRole,
+    /// Send and check packet error codes.
+    pub pec: bool,
+    /// Device only: answer the SMBus device default address, for address
+    /// resolution.
+    pub arp: bool,
+}
+
+/// The application's side of an SMBus device, driven by
+/// `I2c::handle_device_event`.  Each call happens in the I2C's interrupt,
+/// with the bus stretched until it returns.
+pub trait SmbusDevice {
+    /// The host has addressed us, to write to us or, if `read`, to read.
+    /// `arp` says it used the SMBus device default address.  A repeated
+    /// start calls this again.
+    fn start(&mut self, read: bool, arp: bool);
+
+    /// The host has sent `byte`.
+    fn receive(&mut self, byte: u8);
+
+    /// The host wants another byte.  Return `None` after the last: with PEC
+    /// enabled, the PEC goes next, and otherwise the host gets 0xFF.
+    fn transmit(&mut self) -> Option<u8>;
+
+    /// The host has ended the transaction.  With PEC enabled, `pec_ok` says
+    /// whether the last byte written by the host was the PEC of the message;
+    /// it's always true for messages that end with a read.
+    fn stop(&mut self, pec_ok: bool);
+}
+
+/// One part of a transaction.
+pub enum Segment<'a> {
+    /// Send these bytes.  An empty write just addresses the device, which
+    /// is a way to check that it's there.
+    Write(&'a [u8]),
+    /// Receive enough bytes to fill this buffer, which must not be empty.
+    Read(&'a mut [u8]),
+}
+
+/// Ways that I2C operations can fail.  After any of them, a stop has been
+/// sent (if we still had the bus) and the peripheral is ready for another
+/// transaction.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum I2cError {
+    /// The device didn't acknowledge its address or a byte we sent.
+    Nack,
+    /// Another master won the bus.
+    ArbitrationLost,
+    /// A start or stop appeared somewhere it shouldn't.
+    Bus,
+    /// A read segment was empty.
+    EmptyRead,
+    /// SMBus: a device (or we) held SCL low too long.  The hardware has
+    /// already sent a stop.
+    Timeout,
+    /// SMBus: the received PEC didn't match.
+    Pec,
+    /// The peripheral clock is outside the 2-50 MHz the block supports (or,
+    /// for fast mode, below 4 MHz).
+    BadClock,
+    /// A DMA transfer failed.
+    Dma,
+    /// A segment moved by DMA was longer than 65535 bytes, the most one
+    /// transfer can move.
+    TooLong,
+    /// A segment to be moved by DMA is in memory that DMA can't access
+    /// (see `ccm`).
+    Inaccessible,
+    /// The bus made no progress for `TIMEOUT_MS`: a device is holding it,
+    /// or has stopped responding.
+    Stuck,
+}
+
+/// How long a transaction waits for the next event (or, under DMA, the next
+/// byte) before giving up.  This matches the SMBus limit on how long a
+/// device may stretch the clock in one message.
+pub const TIMEOUT_MS : u32 = 25;
+
+/// Shortest segment `DmaI2c` moves by DMA; shorter ones are polled, which is
+/// cheaper than setting up a stream.
+pub const DMA_THRESHOLD : usize = 4;
+
+/// Half a period of the clock `clear_bus` sends: 100 kHz.
+const CLEAR_HALF_PERIOD_US : u32 = 5;
+
+/// How `transaction_with_recovery` recovers from a failed attempt.
+pub struct BusRecovery<'a, D: 'a> {
+    scl: Pins,
+    sda: Pins,
+    delay: &'a D,
+    backoff: Backoff,
+    retries: u32,
+}
+
+impl<'a, D: DelayUs + 'a> BusRecovery<'a, D> {
+    /// Sets up recovery for a bus on pins `scl` and `sda`, trying each
+    /// transaction up to `retries` more times.  Before each retry it waits
+    /// a delay from `backoff`, in microseconds, timed by `delay`.
+    pub fn new(scl: Pins, sda: Pins, delay: &'a D, backoff: Backoff,
+               retries: u32)
+        -> BusRecovery<'a, D> {
+        BusRecovery {
+            scl: scl,
+            sda: sda,
+            delay: delay,
+            backoff: backoff,
+            retries: retries,
+        }
+    }
+}
+
+/// I2C master driver.
+pub struct I2c {
+    reg: *const Registers,
+    /// Name of this I2C in the RCC.
+    peripheral: ApbPeripheral,
+    /// `TIMEOUT_MS` in CPU cycles, set by `configure`.
+    timeout_cycles: AtomicUsize,
+    /// SMBus device: the message so far, ending with the last byte
+    /// received, carries a correct PEC.
+    device_pec: AtomicBool,
+}
+
+macro_rules! reg_accessors {
+    ($name:ident, $ty:ident, $read:ident, $write:ident, $update:ident) => {
+        pub fn $write(&self, v: $ty) {
+            self.reg().$name.set(v.0)
+        }
+
+        pub fn $read(&self) -> $ty {
+            $ty(self.reg().$name.get())
+        }
+
+        pub fn $update<F: FnOnce($ty) -> $ty>(&self, f: F) {
+            self.$write(f(self.$read()))
+        }
+    };
+}
+
+/// DMA requests for one transaction.
+#[derive(Copy, Clone)]
+struct DmaPair {
+    tx: Request,
+    rx: Request,
+}
+
+impl I2c {
+    fn reg(&self) -> &Registers {
+        unsafe {
+            mmio(self.reg as usize)
+        }
+    }
+
+    reg_accessors!(cr1, Cr1, read_cr1, write_cr1, update_cr1);
+    reg_accessors!(cr2, Cr2, read_cr2, write_cr2, update_cr2);
+    reg_accessors!(ccr, Ccr, read_ccr, write_ccr, update_ccr);
+
+    pub fn read_sr1(&self) -> Sr1 {
+        Sr1(self.reg().sr1.get())
+    }
+
+    pub fn read_sr2(&self) -> Sr2 {
+        Sr2(self.reg().sr2.get())
+    }
+
+    /// Address of the data register, for use as a DMA peripheral address.
+    pub fn dr_address(&self) -> *const () {
+        &self.reg().dr as *const Reg<u32> as *const ()
+    }
+
+    /// Enables this I2C's clock in the RCC.
+    pub fn enable_clock(&self) {
+        RCC.enable_clock(self.peripheral)
+    }
+
+    /// Routes `pins` (SCL and SDA) to this I2C, as open-drain outputs with
+    /// pull ups.  The internal pull ups are weak; most buses want external
+    /// ones too.
+    pub fn configure_pins(&self, pins: &Pins) {
+        ((pins.port)()).set_output_type(pins.pins, gpio::OutputType::OpenDrain);
+        pins.configure_alternate(gpio::Function::AF4, gpio::Pull::Up)
+    }
+
+    /// Configures the bus speed, given the current clock speeds, and enables
+    /// the peripheral.  This also starts the DWT cycle counter, for timeouts.
+    pub fn configure(&self, speeds: &ClockSpeeds, speed: I2cSpeed)
+        -> Result<(), I2cError> {
+        let clk = speeds.get_clock_for(self.peripheral) as u32;
+        let mhz = clk / 1000000;
+        if mhz < 2 || mhz > 50 {
+            return Err(I2cError::BadClock)
+        }
+
+        // SCL high and low times are each CCR peripheral clocks in standard
+        // mode; in fast mode (with DUTY clear), low is twice high.
+        let (ccr, trise) = match speed {
+            I2cSpeed::Standard => {
+                let c = clk / (2 * 100000);
+                // 1000 ns maximum rise time.
+                (if c < 4 { 4 } else { c }, mhz + 1)
+            },
+            I2cSpeed::Fast => {
+                if mhz < 4 {
+                    return Err(I2cError::BadClock)
+                }
+                let c = (clk + 3 * 400000 - 1) / (3 * 400000);
+                // 300 ns maximum rise time.
+                (if c < 1 { 1 } else { c }, mhz * 300 / 1000 + 1)
+            },
+        };
+
+        self.update_cr1(|v| v.with_pe(false));
+        self.write_cr2(Cr2::default().with_freq(mhz));
+        self.write_ccr(Ccr::default()
+                       .with_fs(speed == I2cSpeed::Fast)
+                       .with_ccr(ccr));
+        self.reg().trise.set(trise);
+        self.update_cr1(|v| v.with_pe(true));
+
+        let cycles = speeds.cpu * (TIMEOUT_MS as f32 / 1000.);
+        self.timeout_cycles.store(cycles as usize, Ordering::Relaxed);
+        DWT.enable_cycle_counter();
+        Ok(())
+    }
+
+    /// Configures the bus speed using the clock speeds recorded by
+    /// `clock::freeze`.  See `configure`.
+    ///
+    /// # Panics
+    ///
+    /// If the clock speeds have not been frozen.
+    pub fn configure_frozen(&self, speed: I2cSpeed) -> Result<(), I2cError> {
+        self.configure(clock::frozen(), speed)
+    }
+
+    /// Configures the bus for SMBus, which runs at standard speed, given the
+    /// current clock speeds, and enables the peripheral.  A device starts
+    /// acknowledging its address at once, but handles nothing until
+    /// `enable_device_interrupts`.
+    pub fn configure_smbus(&self, speeds: &ClockSpeeds, config: &SmbusConfig)
+        -> Result<(), I2cError> {
+        try!(self.configure(speeds, I2cSpeed::Standard));
+        self.update_cr1(|v| v.with_pe(false));
+        let host = config.role == SmbusRole::Host;
+        if let SmbusRole::Device(address) = config.role {
+            self.set_device_address(address)
+        }
+        self.update_cr1(|v| v.with_smbus(true)
+                        .with_smbtype(host)
+                        .with_enpec(config.pec)
+                        .with_enarp(config.arp && !host)
+                        .with_alert(false));
+        self.update_cr1(|v| v.with_pe(true));
+        if !host {
+            // ACK can only be set while the peripheral is enabled.
+            self.update_cr1(|v| v.with_ack(true))
+        }
+        Ok(())
+    }
+
+    /// Configures the bus for SMBus using the clock speeds recorded by
+    /// `clock::freeze`.  See `configure_smbus`.
+    ///
+    /// # Panics
+    ///
+    /// If the clock speeds have not been frozen.
+    pub fn configure_smbus_frozen(&self, config: &SmbusConfig)
+        -> Result<(), I2cError> {
+        self.configure_smbus(clock::frozen(), config)
+    }
+
+    /// Checks whether PEC is enabled.
+    pub fn is_pec_enabled(&self) -> bool {
+        self.read_cr1().get_enpec()
+    }
+
+    /// SMBus device: asserts (or releases) SMBALERT#, asking the host to
+    /// find out what we want.
+    pub fn set_alert(&self, asserted: bool) {
+        self.update_cr1(|v| v.with_alert(asserted))
+    }
+
+    /// SMBus device: answers at seven-bit `address` from now on, as after
+    /// an ARP Assign Address command.
+    pub fn set_device_address(&self, address: u8) {
+        self.reg().oar1.set(OAR1_RESERVED_ONE
+                            | ((address as u32 & 0x7F) << 1))
+    }
+
+    /// SMBus device: enables the event and error interrupts (e.g.
+    /// `InterruptTable::i2c1_ev` and `i2c1_er`), whose handlers should call
+    /// `handle_device_event`.
+    pub fn enable_device_interrupts(&self) {
+        self.update_cr2(|v| v.with_itevten(true).with_iterren(true))
+    }
+
+    /// SMBus device: handles whatever the host has done since the last
+    /// call, passing it on to `device`.  Bus errors are reported after the
+    /// events that came before them; the host is expected to start over.
+    pub fn handle_device_event<D: SmbusDevice>(&self, device: &mut D)
+        -> Result<(), I2cError> {
+        let sr1 = self.read_sr1();
+        if sr1.get_af() {
+            // The host NACKed our last byte: the normal end of a read,
+            // which doesn't set STOPF.
+            self.reg().sr1.set(!SR1_AF);
+            self.update_cr2(|v| v.with_itbufen(false));
+            device.stop(true)
+        }
+        if sr1.get_addr() {
+            // Reading SR2 after SR1 clears ADDR.
+            let sr2 = self.read_sr2();
+            self.device_pec.store(true, Ordering::Relaxed);
+            self.update_cr2(|v| v.with_itbufen(true));
+            device.start(sr2.get_tra(), sr2.get_smbdefault())
+        }
+        if sr1.get_rxne() {
+            let b = self.reg().dr.get() as u8;
+            // A message followed by its PEC has a PEC of zero.
+            let pec_ok = self.read_sr2().get_pec() == 0;
+            self.device_pec.store(pec_ok, Ordering::Relaxed);
+            device.receive(b)
+        }
+        if sr1.get_txe() && !sr1.get_af() && self.read_sr2().get_tra() {
+            match device.transmit() {
+                Some(b) => self.reg().dr.set(b as u32),
+                None if self.is_pec_enabled() => {
+                    // The PEC follows the byte in progress; there's
+                    // nothing more to ask for.
+                    self.update_cr1(|v| v.with_pec(true));
+                    self.update_cr2(|v| v.with_itbufen(false))
+                },
+                None => self.reg().dr.set(0xFF),
+            }
+        }
+        if sr1.get_stopf() {
+            // STOPF is cleared by reading SR1, then writing CR1.
+            self.update_cr1(|v| v);
+            self.update_cr2(|v| v.with_itbufen(false));
+            device.stop(!self.is_pec_enabled()
+                        || self.device_pec.load(Ordering::Relaxed))
+        }
+        self.check_errors(Sr1(sr1.0 & !SR1_AF))
+    }
+
+    /// SMBus host: enables an interrupt on SMBALERT#, which arrives on this
+    /// I2C's error vector (e.g. `InterruptTable::i2c1_er`).  The handler
+    /// should call `take_alert`.
+    ///
+    /// Other bus errors share the vector; the transaction that caused them
+    /// reports and clears them.
+    pub fn enable_alert_interrupt(&self) {
+        self.update_cr2(|v| v.with_iterren(true))
+    }
+
+    pub fn disable_alert_interrupt(&self) {
+        self.update_cr2(|v| v.with_iterren(false))
+    }
+
+    /// SMBus host: checks whether a device has asserted SMBALERT# since the
+    /// last call, clearing the flag.
+    pub fn take_alert(&self) -> bool {
+        if self.read_sr1().get_smbalert() {
+            self.reg().sr1.set(!SR1_SMBALERT);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// SMBus host: reads the Alert Response Address, returning the address
+    /// of the device asserting SMBALERT#.  If several are, the lowest
+    /// address wins and the others keep SMBALERT# asserted.
+    pub fn alert_response(&self) -> Result<u8, I2cError> {
+        let mut a = [0];
+        try!(self.transaction(SMBUS_ALERT_RESPONSE,
+                              &mut [Segment::Read(&mut a)]));
+        Ok(a[0] >> 1)
+    }
+
+    /// SMBus Read Word: sends `command`, then reads a little-endian word.
+    pub fn read_word(&self, address: u8, command: u8)
+        -> Result<u16, I2cError> {
+        let mut w = [0; 2];
+        try!(self.transaction(address, &mut [Segment::Write(&[command]),
+                                             Segment::Read(&mut w)]));
+        Ok(w[0] as u16 | (w[1] as u16) << 8)
+    }
+
+    /// SMBus Write Word: sends `command` and a little-endian word.
+    pub fn write_word(&self, address: u8, command: u8, value: u16)
+        -> Result<(), I2cError> {
+        let b = [command, value as u8, (value >> 8) as u8];
+        self.transaction(address, &mut [Segment::Write(&b)])
+    }
+
+    /// Runs `segments` against the device at seven-bit `address`, each
+    /// starting with a (repeated) start and the address, and the last ending
+    /// with a stop.
+    pub fn transaction(&self, address: u8, segments: &mut [Segment])
+        -> Result<(), I2cError> {
+        self.run(address, segments, None)
+    }
+
+    /// Runs `segments` as `transaction` does, but tries again, as
+    /// `recovery` allows, after failures that another attempt might cure:
+    /// lost arbitration, bus errors, SMBus timeouts, and a stuck bus, which
+    /// is cleared first with `clear_bus`.
+    pub fn transaction_with_recovery<D: DelayUs>(&self,
+                                                 address: u8,
+                                                 segments: &mut [Segment],
+                                                 recovery: &mut BusRecovery<D>)
+        -> Result<(), I2cError> {
+        self.run_with_recovery(address, segments, None, recovery)
+    }
+
+    fn run_with_recovery<D: DelayUs>(&self,
+                                     address: u8,
+                                     segments: &mut [Segment],
+                                     dma: Option<DmaPair>,
+                                     recovery: &mut BusRecovery<D>)
+        -> Result<(), I2cError> {
+        let mut retries = recovery.retries;
+        loop {
+            match self.run(address, segments, dma) {
+                Err(e) if retries > 0 && is_transient(e) => {
+                    retries -= 1;
+                    if e == I2cError::Stuck {
+                        // If it won't clear, the retry will say so.
+                        let _ = self.clear_bus(&recovery.scl, &recovery.sda,
+                                               recovery.delay);
+                    }
+                    let us = recovery.backoff.next_delay();
+                    recovery.delay.delay_us(us)
+                },
+                r => {
+                    recovery.backoff.reset();
+                    return r
+                },
+            }
+        }
+    }
+
+    /// Frees a bus whose SDA is held low by a device waiting for clocks:
+    /// takes the pins `scl` and `sda` from the I2C, clocks SCL until the
+    /// device lets go of SDA (nine clocks at most, enough to finish a byte
+    /// and its acknowledge), sends a stop, and hands the pins back.  Reports
+    /// `Stuck` if SDA is still low.
+    ///
+    /// The pins must be the ones given to `configure_pins`.  The peripheral
+    /// is disabled meanwhile, which also resets its view of the bus.
+    pub fn clear_bus<D: DelayUs>(&self, scl: &Pins, sda: &Pins, delay: &D)
+        -> Result<(), I2cError> {
+        self.update_cr1(|v| v.with_pe(false));
+        for p in &[scl, sda] {
+            // Released (high) before they're taken from the I2C.
+            p.set();
+            ((p.port)()).set_output_type(p.pins, gpio::OutputType::OpenDrain);
+            ((p.port)()).set_mode(p.pins, gpio::Mode::Gpio)
+        }
+
+        for _ in 0..9 {
+            if !sda.get().is_empty() {
+                break
+            }
+            scl.clear();
+            delay.delay_us(CLEAR_HALF_PERIOD_US);
+            scl.set();
+            delay.delay_us(CLEAR_HALF_PERIOD_US)
+        }
+        // Stop: SDA rising while SCL is high.
+        scl.clear();
+        delay.delay_us(CLEAR_HALF_PERIOD_US);
+        sda.clear();
+        delay.delay_us(CLEAR_HALF_PERIOD_US);
+        scl.set();
+        delay.delay_us(CLEAR_HALF_PERIOD_US);
+        sda.set();
+        delay.delay_us(CLEAR_HALF_PERIOD_US);
+        let released = !sda.get().is_empty();
+
+        for p in &[scl, sda] {
+            p.configure_alternate(gpio::Function::AF4, gpio::Pull::Up)
+        }
+        self.update_cr1(|v| v.with_pe(true));
+        if released {
+            Ok(())
+        } else {
+            Err(I2cError::Stuck)
+        }
+    }
+
+    fn run(&self, address: u8, segments: &mut [Segment],
+           dma: Option<DmaPair>) -> Result<(), I2cError> {
+        let r = self.run_segments(address, segments, dma);
+        if r.is_err() {
+            self.recover(dma)
+        }
+        r
+    }
+
+    fn run_segments(&self, address: u8, segments: &mut [Segment],
+                    dma: Option<DmaPair>) -> Result<(), I2cError> {
+        for s in segments.iter() {
+            let (addr, len) = match *s {
+                Segment::Read(ref buf) if buf.is_empty() =>
+                    return Err(I2cError::EmptyRead),
+                Segment::Read(ref buf) => (buf.as_ptr() as usize, buf.len()),
+                Segment::Write(bytes) => (bytes.as_ptr() as usize, bytes.len()),
+            };
+            if dma.is_some() && len >= DMA_THRESHOLD {
+                if len > u16::max_value() as usize {
+                    return Err(I2cError::TooLong)
+                }
+                if ccm::contains(addr, len) {
+                    return Err(I2cError::Inaccessible)
+                }
+            }
+        }
+
+        // Wait out any stop still being sent from last time.
+        let start = DWT.read_cycle_count();
+        while self.read_cr1().get_stop() {
+            try!(self.check_stuck(start))
+        }
+
+        let n = segments.len();
+        let pec = self.is_pec_enabled();
+        // Set when a read segment has already requested the next start.
+        let mut started = false;
+        for (i, s) in segments.iter_mut().enumerate() {
+            let last = i + 1 == n;
+            match *s {
+                Segment::Write(bytes) => {
+                    try!(self.address(address, false, started));
+                    try!(self.write_segment(bytes, last, last && pec, dma));
+                    started = false
+                },
+                Segment::Read(ref mut buf) => {
+                    // ACK until told otherwise.
+                    self.update_cr1(|v| v.with_ack(true).with_pos(false));
+                    try!(self.address(address, true, started));
+                    try!(self.read_segment(buf, last, last && pec, dma));
+                    started = !last
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a start (unless one is already on its way) and the address,
+    /// and waits for the device to acknowledge.  `ADDR` is left set, holding
+    /// the bus, since the read sequences need to clear it at the right
+    /// moment.
+    fn address(&self, address: u8, read: bool, started: bool)
+        -> Result<(), I2cError> {
+        if !started {
+            self.update_cr1(|v| v.with_start(true))
+        }
+        try!(self.wait(|sr1| sr1.get_sb()));
+        self.reg().dr.set(((address as u32) << 1) | read as u32);
+        self.wait(|sr1| sr1.get_addr())
+    }
+
+    /// Clears `ADDR`, which takes reading SR1 and then SR2, and releases
+    /// the clock.
+    fn clear_addr(&self) {
+        let _ = self.read_sr1();
+        let _ = self.read_sr2();
+    }
+
+    /// Ends a segment: a stop if it's the last, otherwise a repeated start.
+    fn end_segment(&self, last: bool) {
+        if last {
+            self.update_cr1(|v| v.with_stop(true))
+        } else {
+            self.update_cr1(|v| v.with_start(true))
+        }
+    }
+
+    /// Sends `bytes`, followed by the PEC if `pec`.
+    fn write_segment(&self, bytes: &[u8], last: bool, pec: bool,
+                     dma: Option<DmaPair>) -> Result<(), I2cError> {
+        match dma {
+            Some(d) if bytes.len() >= DMA_THRESHOLD => {
+                // With LAST, the hardware sends the PEC after the DMA
+                // transfer by itself.
+                self.setup_stream(d.tx, bytes.as_ptr(), bytes.len(),
+                                  dma::Direction::MemoryToPeripheral);
+                self.update_cr2(|v| v.with_dmaen(true).with_last(pec));
+                self.clear_addr();
+                try!(self.wait_dma(d.tx));
+                self.update_cr2(|v| v.with_dmaen(false).with_last(false))
+            },
+            _ => {
+                self.clear_addr();
+                for &b in bytes {
+                    try!(self.wait(|sr1| sr1.get_txe()));
+                    self.reg().dr.set(b as u32)
+                }
+                if pec {
+                    // PEC goes out once the last byte has left DR.
+                    try!(self.wait(|sr1| sr1.get_txe()));
+                    self.update_cr1(|v| v.with_pec(true))
+                }
+            },
+        }
+        // An address-only write never shifts a byte out, so BTF never sets.
+        try!(self.wait(|sr1| sr1.get_btf()
+                       || (bytes.is_empty() && sr1.get_txe())));
+        if last {
+            self.end_segment(true)
+        }
+        Ok(())
+    }
+
+    /// Fills `buf`, then receives and checks the PEC if `pec`.
+    fn read_segment(&self, buf: &mut [u8], last: bool, pec: bool,
+                    dma: Option<DmaPair>) -> Result<(), I2cError> {
+        let n = buf.len();
+        // Bytes on the wire, counting the PEC, which is checked by the
+        // hardware and then dropped.
+        let m = n + pec as usize;
+        let ptr = buf.as_ptr();
+        let mut put = |i: usize, b: u32| if i < n { buf[i] = b as u8 };
+        match dma {
+            Some(d) if n >= DMA_THRESHOLD => {
+                // LAST makes the hardware NACK the final byte itself -- or,
+                // with PEC, receive and check the PEC after it.
+                self.setup_stream(d.rx, ptr, n,
+                                  dma::Direction::PeripheralToMemory);
+                self.update_cr2(|v| v.with_dmaen(true).with_last(true));
+                self.clear_addr();
+                try!(self.wait_dma(d.rx));
+                if pec {
+                    try!(self.wait(|sr1| sr1.get_rxne()));
+                    let _ = self.reg().dr.get();
+                }
+                self.end_segment(last);
+                self.update_cr2(|v| v.with_dmaen(false).with_last(false));
+            },
+            _ if m == 1 => {
+                self.update_cr1(|v| v.with_ack(false));
+                self.clear_addr();
+                self.end_segment(last);
+                try!(self.wait(|sr1| sr1.get_rxne()));
+                put(0, self.reg().dr.get())
+            },
+            _ if m == 2 => {
+                // NACK the second byte, not the first.
+                self.update_cr1(|v| v.with_ack(false).with_pos(true)
+                                .with_pec(pec));
+                self.clear_addr();
+                // Both bytes in hand, the bus stretched.
+                try!(self.wait(|sr1| sr1.get_btf()));
+                self.end_segment(last);
+                put(0, self.reg().dr.get());
+                put(1, self.reg().dr.get());
+                self.update_cr1(|v| v.with_pos(false))
+            },
+            _ => {
+                self.clear_addr();
+                for i in 0..m - 3 {
+                    try!(self.wait(|sr1| sr1.get_rxne()));
+                    put(i, self.reg().dr.get())
+                }
+                // Byte m-3 in DR, m-2 in the shift register: NACK the next
+                // (and, with PEC, check it).
+                try!(self.wait(|sr1| sr1.get_btf()));
+                self.update_cr1(|v| v.with_ack(false).with_pec(pec));
+                put(m - 3, self.reg().dr.get());
+                try!(self.wait(|sr1| sr1.get_btf()));
+                self.end_segment(last);
+                put(m - 2, self.reg().dr.get());
+                try!(self.wait(|sr1| sr1.get_rxne()));
+                put(m - 1, self.reg().dr.get())
+            },
+        }
+        // A PEC mismatch shows up once the PEC byte is in.
+        self.check_errors(self.read_sr1())
+    }
+
+    /// Spins until `ready`, an error, or a timeout.
+    fn wait<F: Fn(Sr1) -> bool>(&self, ready: F) -> Result<(), I2cError> {
+        let start = DWT.read_cycle_count();
+        loop {
+            let sr1 = self.read_sr1();
+            try!(self.check_errors(sr1));
+            if ready(sr1) {
+                return Ok(())
+            }
+            try!(self.check_stuck(start))
+        }
+    }
+
+    /// Reports `Stuck` if `TIMEOUT_MS` has passed since the cycle count
+    /// `start`.
+    fn check_stuck(&self, start: u32) -> Result<(), I2cError> {
+        let limit = self.timeout_cycles.load(Ordering::Relaxed) as u32;
+        if DWT.read_cycle_count().wrapping_sub(start) > limit {
+            Err(I2cError::Stuck)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Reports (and clears) the error flags in `sr1`.
+    fn check_errors(&self, sr1: Sr1) -> Result<(), I2cError> {
+        if (sr1.0 & SR1_ERRORS) == 0 {
+            return Ok(())
+        }
+        self.reg().sr1.set(!SR1_ERRORS);
+        if sr1.get_timeout() {
+            Err(I2cError::Timeout)
+        } else if sr1.get_pecerr() {
+            Err(I2cError::Pec)
+        } else if sr1.get_arlo() {
+            Err(I2cError::ArbitrationLost)
+        } else if sr1.get_berr() {
+            Err(I2cError::Bus)
+        } else {
+            // OVR can't happen with clock stretching; report it as the NACK
+            // that usually accompanies it.
+            Err(I2cError::Nack)
+        }
+    }
+
+    /// Cleans up after an error: releases the bus, if we have it, and
+    /// resets the per-transaction settings.
+    fn recover(&self, dma: Option<DmaPair>) {
+        if self.read_sr2().get_msl() {
+            self.update_cr1(|v| v.with_stop(true))
+        }
+        self.update_cr1(|v| v.with_ack(false).with_pos(false).with_pec(false));
+        self.update_cr2(|v| v.with_dmaen(false).with_last(false));
+        if let Some(d) = dma {
+            let _ = d.tx.route().get_stream().abort();
+            let _ = d.rx.route().get_stream().abort();
+        }
+        self.reg().sr1.set(!SR1_ERRORS)
+    }
+
+    fn setup_stream(&self, req: Request, buf: *const u8, len: usize,
+                    dir: dma::Direction) {
+        let route = req.route();
+        let stream = route.get_stream();
+        route.clear_interrupt_flags(dma::InterruptFlags::all());
+        stream.par.set(self.dr_address());
+        stream.mar[0].set(buf as *const ());
+        stream.ndtr.set(dma::Ndtr::default().with_ndt(len as u16));
+        stream.cr.set(dma::Cr::default()
+                      .with_chsel(route.get_channel())
+                      .with_dir(dir)
+                      .with_msize(dma::TransferSize::Byte)
+                      .with_psize(dma::TransferSize::Byte)
+                      .with_minc(true));
+        stream.cr.update(|v| v.with_en(true))
+    }
+
+    /// Waits for the stream serving `req` to finish, watching for I2C
+    /// errors meanwhile.  The timeout restarts with each byte moved.
+    fn wait_dma(&self, req: Request) -> Result<(), I2cError> {
+        let route = req.route();
+        let stream = route.get_stream();
+        let mut start = DWT.read_cycle_count();
+        let mut remaining = stream.remaining();
+        loop {
+            try!(self.check_errors(self.read_sr1()));
+            match route.get_interrupt_flags() {
+                Ok(f) if f.intersects(dma::TRANSFER_ERROR) =>
+                    return Err(I2cError::Dma),
+                Ok(f) if f.intersects(dma::TRANSFER_COMPLETE) => {
+                    route.clear_interrupt_flags(dma::TRANSFER_COMPLETE);
+                    return Ok(())
+                },
+                _ => (),
+            }
+            let r = stream.remaining();
+            if r != remaining {
+                remaining = r;
+                start = DWT.read_cycle_count()
+            } else {
+                try!(self.check_stuck(start))
+            }
+        }
+    }
+}
+
+impl I2cBus for I2c {
+    type Error = I2cError;
+
+    fn write(&self, address: u8, bytes: &[u8]) -> Result<(), I2cError> {
+        self.transaction(address, &mut [Segment::Write(bytes)])
+    }
+
+    fn read(&self, address: u8, buffer: &mut [u8]) -> Result<(), I2cError> {
+        self.transaction(address, &mut [Segment::Read(buffer)])
+    }
+
+    fn write_read(&self, address: u8, bytes: &[u8], buffer: &mut [u8])
+        -> Result<(), I2cError> {
+        self.transaction(address, &mut [Segment::Write(bytes),
+                                        Segment::Read(buffer)])
+    }
+}
+
+/// An I2C master that moves segments of `DMA_THRESHOLD` bytes or more by
+/// DMA.  Transactions still block until they're done, but the CPU only
+/// handles the events around each segment, not every byte.
+pub struct DmaI2c<'a> {
+    i2c: &'a I2c,
+    dma: DmaPair,
+}
+
+impl<'a> DmaI2c<'a> {
+    /// Creates a DMA driver for `i2c`, using the given DMA requests for
+    /// transmit and receive -- e.g. `Request::I2c1TxS6` and
+    /// `Request::I2c1RxS0` for I2C1.
+    pub const fn new(i2c: &'a I2c, tx: Request, rx: Request) -> DmaI2c<'a> {
+        DmaI2c {
+            i2c: i2c,
+            dma: DmaPair { tx: tx, rx: rx },
+        }
+    }
+
+    /// As `I2c::transaction`.
+    pub fn transaction(&self, address: u8, segments: &mut [Segment])
+        -> Result<(), I2cError> {
+        self.i2c.run(address, segments, Some(self.dma))
+    }
+
+    /// As `I2c::transaction_with_recovery`.
+    pub fn transaction_with_recovery<D: DelayUs>(&self,
+                                                 address: u8,
+                                                 segments: &mut [Segment],
+                                                 recovery: &mut BusRecovery<D>)
+        -> Result<(), I2cError> {
+        self.i2c.run_with_recovery(address, segments, Some(self.dma),
+                                   recovery)
+    }
+}
+
+impl<'a> I2cBus for DmaI2c<'a> {
+    type Error = I2cError;
+
+    fn write(&self, address: u8, bytes: &[u8]) -> Result<(), I2cError> {
+        self.transaction(address, &mut [Segment::Write(bytes)])
+    }
+
+    fn read(&self, address: u8, buffer: &mut [u8]) -> Result<(), I2cError> {
+        self.transaction(address, &mut [Segment::Read(buffer)])
+    }
+
+    fn write_read(&self, address: u8, bytes: &[u8], buffer: &mut [u8])
+        -> Result<(), I2cError> {
+        self.transaction(address, &mut [Segment::Write(bytes),
+                                        Segment::Read(buffer)])
+    }
+}
+
+/// Checks whether another attempt might succeed where one failed with `e`.
+fn is_transient(e: I2cError) -> bool {
+    match e {
+        I2cError::ArbitrationLost
+            | I2cError::Bus
+            | I2cError::Timeout
+            | I2cError::Stuck => true,
+        _ => false,
+    }
+}
+
+unsafe impl Sync for I2c {}
+
+macro_rules! static_i2c {
+    ($name:ident, $addr:expr, $periph:ident) => {
+        pub static $name: I2c = I2c {
+            reg: $addr as *const Registers,
+            peripheral: ApbPeripheral::$periph,
+            timeout_cycles: AtomicUsize::new(0),
+            device_pec: AtomicBool::new(true),
+        };
+    };
+}
+
+static_i2c!(I2C1, 0x40005400, I2c1);
+static_i2c!(I2C2, 0x40005800, I2c2);
+static_i2c!(I2C3, 0x40005C00, I2c3);
@@ -5,8 +5,10 @@
 //! - `struct InterruptTable` for modeling the vendor-specific vector table.
 //! - `trait NvicExt` to extend the NVIC with operations using STM32F4-specific
 //!   vector numbers and widths.
+//! - `trait ScbExt` to do the same for the system exceptions.
 
 use arm_m::nvic;
+use arm_m::scb::{Scb, SystemException, SCB};
 
 /// Re-export the type used for interrupt vectors on ARMv7-M.
 pub use arm_m::exc::Handler;
@@ -317,10 +319,20 @@ pub enum Interrupt {
 
 const PRIO_SHIFT : u32 = 4;
 
+/// Number of priority bits implemented by the STM32F4.
+pub const PRIO_BITS : u32 = 8 - PRIO_SHIFT;
+
 /// Enumeration of the STM32F4 interrupt priority values.  The STM32F4 only
 /// implements four bits of priority, or 16 levels.  This enumeration acts like
 /// a four-bit integer to avoid needing to range check priority values at
 /// runtime.
+///
+/// Lower values are more urgent.  The four bits are divided between
+/// *preemption priority* (the high bits), which decides whether one handler
+/// can interrupt another, and *subpriority* (the low bits), which only orders
+/// pending interrupts of equal preemption priority.  The split is set with
+/// `set_priority_grouping`; at reset, all four bits are preemption priority.
+/// `Priority::compose` builds a value from the two parts.
 #[derive(Clone, Copy)]
 pub enum Priority {
     P0 = 0, P1, P2, P3, P4, P5, P6, P7,
@@ -401,8 +413,62 @@ impl NvicExt for nvic::Nvic {
     }
 
     fn get_priority(&self, irq: Interrupt) -> Priority {
+        Priority::from_raw(self.get_priority_raw(irq as u32))
+    }
+}
+
+/// Divides the implemented priority bits so that the top `preempt_bits` (0-4)
+/// are preemption priority, and the rest subpriority.
+///
+/// # Panics
+///
+/// If `preempt_bits` exceeds `PRIO_BITS`.
+pub fn set_priority_grouping(preempt_bits: u32) {
+    assert!(preempt_bits <= PRIO_BITS);
+    // PRIGROUP names the highest subpriority bit of the full eight-bit field.
+    SCB.set_priority_grouping_raw(7 - preempt_bits)
+}
+
+/// Returns the number of implemented priority bits currently used for
+/// preemption priority.
+pub fn get_priority_grouping() -> u32 {
+    let prigroup = SCB.get_priority_grouping_raw();
+    if prigroup < PRIO_SHIFT {
+        PRIO_BITS
+    } else {
+        7 - prigroup
+    }
+}
+
+impl Priority {
+    /// Builds a priority from preemption priority and subpriority, according
+    /// to the current grouping (see `set_priority_grouping`).
+    ///
+    /// # Panics
+    ///
+    /// If either part is out of range for the current grouping.
+    pub fn compose(preempt: u32, sub: u32) -> Priority {
+        let sub_bits = PRIO_BITS - get_priority_grouping();
+        assert!(preempt < (1 << (PRIO_BITS - sub_bits)));
+        assert!(sub < (1 << sub_bits));
+        Priority::from_raw((((preempt << sub_bits) | sub) << PRIO_SHIFT) as u8)
+    }
+
+    /// Extracts the preemption priority under the current grouping.
+    pub fn preempt_level(self) -> u32 {
+        (self as u32) >> (PRIO_BITS - get_priority_grouping())
+    }
+
+    /// Extracts the subpriority under the current grouping.
+    pub fn sub_level(self) -> u32 {
+        let sub_bits = PRIO_BITS - get_priority_grouping();
+        (self as u32) & ((1 << sub_bits) - 1)
+    }
+
+    /// Converts a raw eight-bit priority as read from the hardware.
+    fn from_raw(raw: u8) -> Priority {
         // We're relying on the compiler to recognize this silliness.
-        match self.get_priority_raw(irq as u32) >> PRIO_SHIFT {
+        match raw >> PRIO_SHIFT {
             0 => Priority::P0,
             1 => Priority::P1,
             2 => Priority::P2,
@@ -422,5 +488,33 @@ impl NvicExt for nvic::Nvic {
             _ => unreachable!(),
         }
     }
+}
+
+/// Priorities for system exceptions use the same four implemented bits as
+/// interrupts, and share a single priority space with them.
+pub type ExceptionPriority = Priority;
+
+/// Extension trait for `arm_m::Scb` adding typed access to system exception
+/// priorities.
+pub trait ScbExt {
+    /// Sets the priority of a system exception.  The same preemption caveats
+    /// apply as for `NvicExt::set_priority`.
+    fn set_exception_priority(&self, exc: SystemException,
+                              priority: ExceptionPriority);
+
+    /// Reads the priority of a system exception.
+    fn get_exception_priority(&self, exc: SystemException)
+        -> ExceptionPriority;
+}
+
+impl ScbExt for Scb {
+    fn set_exception_priority(&self, exc: SystemException,
+                              priority: ExceptionPriority) {
+        self.set_priority_raw(exc, (priority as u8) << PRIO_SHIFT)
+    }
 
+    fn get_exception_priority(&self, exc: SystemException)
+        -> ExceptionPriority {
+        Priority::from_raw(self.get_priority_raw(exc))
+    }
 }
@@ -0,0 +1,115 @@
+//! Independent Watchdog (IWDG) support.
+//!
+//! The IWDG runs from the 32kHz LSI oscillator, so it keeps working even when
+//! the main clock tree has been misconfigured.  Once started it cannot be
+//! stopped except by reset.
+
+use arm_m::reg::{mmio, Reg};
+use hal::Watchdog;
+
+#[repr(C, packed)]
+struct Registers {
+    kr:  Reg<u32>,
+    pr:  Reg<u32>,
+    rlr: Reg<u32>,
+    sr:  Reg<u32>,
+}
+
+register_layout! {
+    fn check_layout: Registers [0x10] {
+        kr @ 0x00,
+        pr @ 0x04,
+        rlr @ 0x08,
+        sr @ 0x0C,
+    }
+}
+
+const IWDG_ADDRESS : usize = 0x40003000;
+
+/// Key written to `KR` to reload the counter ("feed" the watchdog).
+const KEY_RELOAD : u32 = 0xAAAA;
+/// Key written to `KR` to unlock `PR` and `RLR` for writing.
+const KEY_UNLOCK : u32 = 0x5555;
+/// Key written to `KR` to start the watchdog.
+const KEY_START : u32 = 0xCCCC;
+
+bit_wrappers! {
+    /// Wrapper for the Status Register bits.
+    pub struct Sr(pub u32);
+}
+
+impl Sr {
+    bitfield_accessors! {
+        /// Set while a reload value update is in progress.
+        pub total [1] get_rvu / with_rvu: bool,
+        /// Set while a prescaler update is in progress.
+        pub total [0] get_pvu / with_pvu: bool,
+    }
+}
+
+bit_enums! {
+    /// Prescaler options for deriving the watchdog counter clock from the LSI.
+    pub bit_enum Prescaler {
+        Div4   = 0b000,
+        Div8   = 0b001,
+        Div16  = 0b010,
+        Div32  = 0b011,
+        Div64  = 0b100,
+        Div128 = 0b101,
+        Div256 = 0b110,
+    }
+}
+
+/// IWDG driver.
+pub struct Iwdg;
+
+impl Iwdg {
+    fn reg(&self) -> &'static Registers {
+        unsafe { mmio(IWDG_ADDRESS) }
+    }
+
+    pub fn read_sr(&self) -> Sr {
+        Sr(self.reg().sr.get())
+    }
+
+    /// Sets the prescaler and reload value, waiting for the (slow, LSI-clocked)
+    /// hardware to accept each.  The counter is reloaded afterwards, so the new
+    /// timeout applies from this point.
+    ///
+    /// Only the low 12 bits of `reload` are significant.
+    pub fn configure(&self, prescaler: Prescaler, reload: u32) {
+        self.reg().kr.set(KEY_UNLOCK);
+
+        while self.read_sr().get_pvu() {}
+        self.reg().pr.set(prescaler as u32);
+
+        while self.read_sr().get_rvu() {}
+        self.reg().rlr.set(reload & 0xFFF);
+
+        self.feed()
+    }
+
+    /// Starts the watchdog.  This also starts the LSI oscillator if it is not
+    /// already running.  There is no going back.
+    pub fn start(&self) {
+        self.reg().kr.set(KEY_START)
+    }
+
+    /// Reloads the watchdog counter, postponing reset.
+    ///
+    /// This is a single store and is safe to call from any context, including
+    /// fault handlers, whether or not the watchdog has been started.
+    #[inline]
+    pub fn feed(&self) {
+        self.reg().kr.set(KEY_RELOAD)
+    }
+}
+
+impl Watchdog for Iwdg {
+    fn feed(&self) {
+        Iwdg::feed(self)
+    }
+}
+
+/// Shared instance of the `Iwdg` driver.
+pub static IWDG: Iwdg = Iwdg;
@@ -1,8 +1,69 @@
 //! Support for the STM32F4 series of SoCs.
 
+pub mod adc;
+pub mod bkpsram;
+pub mod can;
+#[macro_use]
+pub mod ccm;
+pub mod crc;
+pub mod dbgmcu;
 pub mod dma;
+pub mod errata;
+pub mod eth;
+pub mod exti;
 pub mod flash;
+pub mod flash_blocks;
+pub mod flash_sectors;
+pub mod flash_writer;
 pub mod gpio;
+pub mod i2c;
 pub mod irq;
+pub mod iwdg;
+pub mod otg_fs;
+pub mod power_marker;
+pub mod pwr;
 pub mod rcc;
+pub mod rtc;
+pub mod spi;
+pub mod syscfg;
+pub mod tim;
 pub mod usart;
+
+/// Checks the register block layouts of the STM32F4 peripherals against the
+/// Reference Manual (see `register_layout!`).
+#[cfg(all(test, feature = "host-test"))]
+#[test]
+fn check_layouts() {
+    adc::check_adc_layout();
+    adc::check_common_layout();
+    can::check_layout();
+    can::filter::check_layout();
+    dbgmcu::check_layout();
+    #[cfg(target_pointer_width = "32")]
+    fn check_dma() {
+        dma::check_dma_layout();
+        dma::check_stream_layout();
+    }
+    #[cfg(not(target_pointer_width = "32"))]
+    fn check_dma() {}
+    check_dma();
+    eth::check_mac_layout();
+    eth::check_dma_layout();
+    eth::ptp::check_layout();
+    exti::check_layout();
+    flash::check_layout();
+    gpio::check_layout();
+    i2c::check_layout();
+    iwdg::check_layout();
+    otg_fs::check_layout();
+    otg_fs::device::check_layout();
+    otg_fs::device::check_endpoint_layout();
+    otg_fs::host::check_layout();
+    otg_fs::host::check_channel_layout();
+    pwr::check_layout();
+    rcc::raw::check_layout();
+    rtc::check_layout();
+    spi::check_layout();
+    syscfg::check_layout();
+    tim::check_layout();
+}
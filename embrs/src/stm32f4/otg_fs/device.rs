@@ -0,0 +1,643 @@
+//! OTG_FS device mode.
+//!
+//! The device side of the controller has four endpoints in each direction.
+//! Endpoint 0 carries control transfers; the others can be bulk, interrupt,
+//! or isochronous, as the host configures them from our descriptors.
+//!
+//! Like the host driver, this one polls rather than taking interrupts of its
+//! own: the application calls `Device::poll` regularly (from its main loop,
+//! or from the OTG_FS interrupt with the core interrupts it wants enabled).
+//! `poll` drains the shared receive FIFO into a one-packet buffer per OUT
+//! endpoint -- each endpoint is only armed for another packet once its
+//! buffer is empty, so the host is NAKed meanwhile -- and reports bus resets
+//! and control requests as `Event`s.
+//!
+//! The application answers each control request itself, since only it knows
+//! the descriptors: `control_in` to send a response, `control_ack` to finish
+//! a request without one, or `control_stall` to refuse it.  Standard
+//! requests need a little more: `set_address` for `SET_ADDRESS`, and
+//! `configure_endpoint` (or `deconfigure_endpoints`) for
+//! `SET_CONFIGURATION` and `SET_INTERFACE`.
+//!
+//! The class drivers in `usb` reach the other endpoints through
+//! `Endpoints`, which implements `usb::msc::BulkEndpoints` and
+//! `usb::ecm::EcmEndpoints`.
+//!
+//! Typical startup:
+//!
+//! 1. `OtgFs::configure_pins` and enable the `UsbOtgFs` clock.  The AHB must
+//!    run at 32MHz or more.
+//! 2. `Device::init`, then `Device::connect(true)`.
+//! 3. Call `Device::poll`, handling each `Event`, forever.
+
+use core::cell::{Cell, UnsafeCell};
+use core::cmp;
+
+use arm_m::reg::{mmio, Reg, ReservedReg};
+use hal::{DelayUs, NbError, NbResult};
+use usb::{EndpointDescriptor, SetupPacket, DIR_IN};
+use usb::ecm::EcmEndpoints;
+use usb::msc::BulkEndpoints;
+use super::{CoreInterrupts, DevicePacketStatus, Mode, ALL_TX_FIFOS};
+use super::{ENUMERATION_DONE, FIFO_WORDS, USB_RESET, OTG_FS};
+
+#[repr(C, packed)]
+struct Registers {
+    dcfg:       Reg<u32>,
+    dctl:       Reg<u32>,
+    #[allow(dead_code)]
+    dsts:       Reg<u32>,
+    _reserved0: ReservedReg,
+    diepmsk:    Reg<u32>,
+    doepmsk:    Reg<u32>,
+    #[allow(dead_code)]
+    daint:      Reg<u32>,
+    daintmsk:   Reg<u32>,
+}
+
+register_layout! {
+    fn check_layout: Registers [0x20] {
+        dcfg @ 0x00,
+        dctl @ 0x04,
+        dsts @ 0x08,
+        diepmsk @ 0x10,
+        doepmsk @ 0x14,
+        daint @ 0x18,
+        daintmsk @ 0x1C,
+    }
+}
+
+/// Registers of one endpoint, IN or OUT.  OUT endpoints have no FIFO
+/// status.
+#[repr(C, packed)]
+struct EndpointRegisters {
+    ctl:        Reg<u32>,
+    _reserved0: ReservedReg,
+    int:        Reg<u32>,
+    _reserved1: ReservedReg,
+    tsiz:       Reg<u32>,
+    _reserved2: ReservedReg,
+    #[allow(dead_code)]
+    txfsts:     Reg<u32>,
+    _reserved3: ReservedReg,
+}
+
+register_layout! {
+    fn check_endpoint_layout: EndpointRegisters [0x20] {
+        ctl @ 0x00,
+        int @ 0x08,
+        tsiz @ 0x10,
+        txfsts @ 0x18,
+    }
+}
+
+const DEVICE_ADDRESS : usize = 0x50000800;
+const IN_ENDPOINT_ADDRESS : usize = 0x50000900;
+const OUT_ENDPOINT_ADDRESS : usize = 0x50000B00;
+
+/// Number of endpoints in each direction, counting endpoint 0.
+pub const ENDPOINT_COUNT : usize = 4;
+
+/// Largest packet on any endpoint: the full speed bulk maximum.
+pub const MAX_PACKET : usize = 64;
+
+/// Longest control data stage `Device` buffers, in either direction.
+/// Longer requests are stalled, and longer responses truncated.
+pub const CONTROL_BUFFER_LEN : usize = 256;
+
+// FIFO RAM split: receive, then one transmit FIFO per IN endpoint.  The
+// bulk and interrupt endpoints get room for a few maximum-size packets.
+const RX_FIFO_WORDS : u32 = 128;
+const TX0_FIFO_WORDS : u32 = MAX_PACKET as u32 / 4;
+const TX_FIFO_WORDS : u32 =
+    (FIFO_WORDS - RX_FIFO_WORDS - TX0_FIFO_WORDS) / 3;
+
+// DCFG bits.
+/// Device speed: full speed, with the internal PHY.
+const DCFG_DSPD_FULL : u32 = 0b11;
+const DCFG_DAD_SHIFT : u32 = 4;
+const DCFG_DAD_MASK : u32 = 0x7F << DCFG_DAD_SHIFT;
+
+// DCTL bits.
+const DCTL_SDIS : u32 = 1 << 1;
+const DCTL_CGINAK : u32 = 1 << 8;
+
+/// Turnaround time, in PHY clocks, for an AHB of 32MHz or more.
+const TURNAROUND : u32 = 6;
+
+bit_wrappers! {
+    /// Wrapper for the Device Endpoint Control Register bits, IN or OUT.
+    pub struct Depctl(pub u32);
+    /// Wrapper for the Device Endpoint Transfer Size Register bits, IN or
+    /// OUT.
+    pub struct Deptsiz(pub u32);
+}
+
+impl Depctl {
+    bitfield_accessors! {
+        pub total [31] get_epena / with_epena: bool,
+        pub total [30] get_epdis / with_epdis: bool,
+        /// Sets the data toggle to DATA0.  Write only.
+        pub total [28] get_sd0pid / with_sd0pid: bool,
+        pub total [27] get_snak / with_snak: bool,
+        pub total [26] get_cnak / with_cnak: bool,
+        /// Transmit FIFO number.  IN endpoints only.
+        pub total [25:22] get_txfnum / with_txfnum: u32,
+        pub total [21] get_stall / with_stall: bool,
+        /// Endpoint type, as in `usb::EndpointType`.
+        pub total [19:18] get_eptyp / with_eptyp: u32,
+        pub total [17] get_naksts / with_naksts: bool,
+        /// Endpoint active in the current configuration.
+        pub total [15] get_usbaep / with_usbaep: bool,
+        /// Maximum packet size.  For endpoint 0 this is a two-bit code,
+        /// where 0 means 64 bytes.
+        pub total [10:0] get_mpsiz / with_mpsiz: u32,
+    }
+}
+
+impl Deptsiz {
+    bitfield_accessors! {
+        /// OUT endpoint 0 only: SETUP packets to accept back-to-back.
+        pub total [30:29] get_stupcnt / with_stupcnt: u32,
+        pub total [28:19] get_pktcnt / with_pktcnt: u32,
+        pub total [18:0] get_xfrsiz / with_xfrsiz: u32,
+    }
+}
+
+/// Things `Device::poll` reports.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Event {
+    /// The host reset the bus.  The address is back to 0, the endpoints
+    /// other than 0 are deconfigured, and any class state should be reset.
+    Reset,
+    /// A control request arrived on endpoint 0.  Its OUT data stage, if
+    /// any, is in `Device::control_data`.  Answer with `control_in`,
+    /// `control_ack`, or `control_stall`.
+    Setup(SetupPacket),
+}
+
+/// Ways an endpoint operation can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum EndpointError {
+    /// The host hasn't configured the endpoint, or has reset the bus since.
+    NotConfigured,
+}
+
+/// Progress of the control transfer on endpoint 0.
+#[derive(Copy, Clone, Debug)]
+enum Control {
+    Idle,
+    /// Receiving the OUT data stage of `setup`; `received` bytes so far.
+    DataOut { setup: SetupPacket, received: usize },
+    /// Sending the IN data stage: `sent` bytes of `len` so far, and a
+    /// zero-length packet to end it if `zlp`.
+    DataIn { sent: usize, len: usize, zlp: bool },
+}
+
+/// Marks an empty OUT endpoint buffer.
+const NO_PACKET : usize = !0;
+
+/// OTG_FS device mode driver.
+///
+/// This holds the packet buffers, so it isn't `Sync`: it belongs to
+/// whichever context calls `poll`, along with the class drivers.
+pub struct Device {
+    /// The last packet received on each OUT endpoint, waiting to be taken.
+    out_buf: UnsafeCell<[[u8; MAX_PACKET]; ENDPOINT_COUNT]>,
+    /// Length of each `out_buf` entry, or `NO_PACKET`.
+    out_len: [Cell<usize>; ENDPOINT_COUNT],
+    /// The last SETUP packet, as received.
+    setup: Cell<[u8; 8]>,
+    control: Cell<Control>,
+    /// Endpoint 0's data stage, in either direction.
+    control_buf: UnsafeCell<[u8; CONTROL_BUFFER_LEN]>,
+    /// Length of the OUT data stage in `control_buf`.
+    control_len: Cell<usize>,
+}
+
+impl Default for Device {
+    fn default() -> Device {
+        Device::new()
+    }
+}
+
+impl Device {
+    pub fn new() -> Device {
+        Device {
+            out_buf: UnsafeCell::new([[0; MAX_PACKET]; ENDPOINT_COUNT]),
+            out_len: [Cell::new(NO_PACKET), Cell::new(NO_PACKET),
+                      Cell::new(NO_PACKET), Cell::new(NO_PACKET)],
+            setup: Cell::new([0; 8]),
+            control: Cell::new(Control::Idle),
+            control_buf: UnsafeCell::new([0; CONTROL_BUFFER_LEN]),
+            control_len: Cell::new(0),
+        }
+    }
+
+    fn reg(&self) -> &'static Registers {
+        unsafe { mmio(DEVICE_ADDRESS) }
+    }
+
+    fn in_ep(&self, n: usize) -> &'static EndpointRegisters {
+        let regs: &'static [EndpointRegisters; ENDPOINT_COUNT] =
+            unsafe { mmio(IN_ENDPOINT_ADDRESS) };
+        &regs[n]
+    }
+
+    fn out_ep(&self, n: usize) -> &'static EndpointRegisters {
+        let regs: &'static [EndpointRegisters; ENDPOINT_COUNT] =
+            unsafe { mmio(OUT_ENDPOINT_ADDRESS) };
+        &regs[n]
+    }
+
+    /// Puts the controller in device mode and sets up its FIFOs, without
+    /// connecting to the bus.  If `vbus_sensing`, the controller watches
+    /// VBUS (on PA9) and only connects while the host powers it.
+    pub fn init<D: DelayUs>(&self, delay: &D, vbus_sensing: bool) {
+        OTG_FS.set_global_interrupt(false);
+        OTG_FS.update_gusbcfg(|v| v.with_physel(true));
+        OTG_FS.reset_core();
+        OTG_FS.update_gccfg(|v| v.with_pwrdwn(true)
+                                 .with_novbussens(!vbus_sensing)
+                                 .with_vbusasen(false)
+                                 .with_vbusbsen(vbus_sensing));
+        OTG_FS.force_mode(Mode::Device, delay);
+        OTG_FS.update_gusbcfg(|v| v.with_trdt(TURNAROUND));
+        OTG_FS.ungate_clocks();
+
+        self.reg().dctl.set(DCTL_SDIS);
+        self.reg().dcfg.set(DCFG_DSPD_FULL);
+
+        OTG_FS.set_rx_fifo_size(RX_FIFO_WORDS);
+        OTG_FS.set_tx_fifo(0, RX_FIFO_WORDS, TX0_FIFO_WORDS);
+        for n in 1..ENDPOINT_COUNT as u32 {
+            OTG_FS.set_tx_fifo(n,
+                               RX_FIFO_WORDS + TX0_FIFO_WORDS
+                                   + (n - 1) * TX_FIFO_WORDS,
+                               TX_FIFO_WORDS);
+        }
+
+        self.reg().diepmsk.set(0);
+        self.reg().doepmsk.set(0);
+        self.reg().daintmsk.set(0);
+        self.bus_reset();
+        OTG_FS.clear_interrupts(CoreInterrupts::all());
+    }
+
+    /// Connects to the bus (pulling up D+) or disconnects.
+    pub fn connect(&self, on: bool) {
+        if on {
+            self.reg().dctl.update(|v| v & !DCTL_SDIS)
+        } else {
+            self.reg().dctl.update(|v| v | DCTL_SDIS)
+        }
+    }
+
+    /// Forgets everything a host set up: the address, the endpoints, and
+    /// any packets in flight.
+    fn bus_reset(&self) {
+        for n in 0..ENDPOINT_COUNT {
+            self.out_len[n].set(NO_PACKET);
+        }
+        self.control.set(Control::Idle);
+        self.reg().dcfg.update(|v| v & !DCFG_DAD_MASK);
+        self.deconfigure_endpoints();
+        OTG_FS.flush_tx_fifo(ALL_TX_FIFOS);
+        OTG_FS.flush_rx_fifo();
+        for n in 0..ENDPOINT_COUNT {
+            self.in_ep(n).int.set(!0);
+            self.out_ep(n).int.set(!0);
+        }
+        self.expect_setup()
+    }
+
+    /// Readies endpoint 0 for the next SETUP packets, which the controller
+    /// accepts whether or not the endpoint is enabled.
+    fn expect_setup(&self) {
+        self.out_ep(0).tsiz.set(Deptsiz(0).with_stupcnt(3)
+                                          .with_pktcnt(1)
+                                          .with_xfrsiz(3 * 8)
+                                          .0)
+    }
+
+    /// Handles whatever the controller has to report, returning the first
+    /// event, if any.  Call this until it returns `None` to catch up.
+    pub fn poll(&self) -> Option<Event> {
+        let ints = OTG_FS.read_interrupts();
+        if ints.contains(USB_RESET) {
+            OTG_FS.clear_interrupts(USB_RESET);
+            self.bus_reset();
+            return Some(Event::Reset)
+        }
+        if ints.contains(ENUMERATION_DONE) {
+            OTG_FS.clear_interrupts(ENUMERATION_DONE);
+            // Endpoint 0 takes 64-byte packets, the full speed maximum.
+            self.in_ep(0).ctl.set(Depctl(0).with_mpsiz(0).0);
+            self.reg().dctl.update(|v| v | DCTL_CGINAK);
+        }
+
+        self.continue_control_in();
+
+        while let Some(st) = OTG_FS.pop_rx_status() {
+            let n = st.get_chnum() as usize;
+            let len = st.get_bcnt() as usize;
+            match st.get_device_pktsts() {
+                Ok(DevicePacketStatus::SetupData) => {
+                    let mut b = [0; 8];
+                    let _ = OTG_FS.read_packet(&mut b, len);
+                    self.setup.set(b)
+                },
+                Ok(DevicePacketStatus::SetupComplete) => {
+                    self.out_ep(0).int.set(!0);
+                    if let Some(e) = self.setup_done() {
+                        return Some(e)
+                    }
+                },
+                Ok(DevicePacketStatus::OutData) if n == 0 => {
+                    if let Some(e) = self.control_out_data(len) {
+                        return Some(e)
+                    }
+                },
+                Ok(DevicePacketStatus::OutData) if n < ENDPOINT_COUNT => {
+                    let bufs = unsafe { &mut *self.out_buf.get() };
+                    let got = OTG_FS.read_packet(&mut bufs[n], len);
+                    self.out_len[n].set(got)
+                },
+                _ => {
+                    let _ = OTG_FS.read_packet(&mut [], len);
+                },
+            }
+        }
+        None
+    }
+
+    /// Starts on a newly received SETUP packet: collects its OUT data
+    /// stage, if it has one, or reports it.
+    fn setup_done(&self) -> Option<Event> {
+        let setup = SetupPacket::from_bytes(&self.setup.get());
+        self.expect_setup();
+        self.control_len.set(0);
+        if setup.is_in() || setup.length == 0 {
+            self.control.set(Control::Idle);
+            return Some(Event::Setup(setup))
+        }
+        if setup.length as usize > CONTROL_BUFFER_LEN {
+            self.control.set(Control::Idle);
+            self.control_stall();
+            return None
+        }
+        self.control.set(Control::DataOut { setup: setup, received: 0 });
+        self.arm_out(0);
+        None
+    }
+
+    /// Takes a `len`-byte packet for endpoint 0 from the receive FIFO.
+    fn control_out_data(&self, len: usize) -> Option<Event> {
+        match self.control.get() {
+            Control::DataOut { setup, received } => {
+                let buf = unsafe { &mut *self.control_buf.get() };
+                let end = setup.length as usize;
+                let received = received
+                    + OTG_FS.read_packet(&mut buf[received..end], len);
+                if received == end || len < MAX_PACKET {
+                    self.control.set(Control::Idle);
+                    self.control_len.set(received);
+                    Some(Event::Setup(setup))
+                } else {
+                    self.control.set(Control::DataOut {
+                        setup: setup,
+                        received: received,
+                    });
+                    self.arm_out(0);
+                    None
+                }
+            },
+            // The status stage of an IN request, which is empty.
+            _ => {
+                let _ = OTG_FS.read_packet(&mut [], len);
+                None
+            },
+        }
+    }
+
+    /// The OUT data stage of the control request last reported.
+    pub fn control_data(&self) -> &[u8] {
+        let buf = unsafe { &*self.control_buf.get() };
+        &buf[..self.control_len.get()]
+    }
+
+    /// Answers the control request `setup` with `data`, which is truncated
+    /// to the length the host asked for (and to `CONTROL_BUFFER_LEN`).  The
+    /// data goes out over the following calls to `poll`.
+    pub fn control_in(&self, setup: &SetupPacket, data: &[u8]) {
+        let len = cmp::min(cmp::min(data.len(), setup.length as usize),
+                           CONTROL_BUFFER_LEN);
+        let buf = unsafe { &mut *self.control_buf.get() };
+        buf[..len].copy_from_slice(&data[..len]);
+        // A response shorter than asked for must end with a short packet.
+        let zlp = len < setup.length as usize && len % MAX_PACKET == 0;
+        self.control.set(Control::DataIn { sent: 0, len: len, zlp: zlp });
+        // The host ends the transfer with an empty OUT packet.
+        self.arm_out(0);
+        self.continue_control_in()
+    }
+
+    /// Sends the next packet of a control IN data stage, if there's one to
+    /// send and room to send it.
+    fn continue_control_in(&self) {
+        if let Control::DataIn { sent, len, zlp } = self.control.get() {
+            if Depctl(self.in_ep(0).ctl.get()).get_epena() {
+                return
+            }
+            let n = cmp::min(MAX_PACKET, len - sent);
+            let buf = unsafe { &*self.control_buf.get() };
+            self.send_packet(0, &buf[sent..sent + n]);
+            let sent = sent + n;
+            self.control.set(if n < MAX_PACKET || (sent == len && !zlp) {
+                Control::Idle
+            } else {
+                Control::DataIn { sent: sent, len: len, zlp: zlp }
+            })
+        }
+    }
+
+    /// Completes a control request that has no IN data stage, by sending
+    /// the empty status packet.
+    pub fn control_ack(&self) {
+        self.send_packet(0, &[])
+    }
+
+    /// Refuses the control request in progress.  The stall lasts until the
+    /// next SETUP packet.
+    pub fn control_stall(&self) {
+        self.in_ep(0).ctl.update(|v| Depctl(v).with_stall(true).0);
+        self.out_ep(0).ctl.update(|v| Depctl(v).with_stall(true).0)
+    }
+
+    /// Takes on `address`, as `SET_ADDRESS` asks.  Call this before
+    /// `control_ack`: the controller keeps using address 0 until the status
+    /// stage is done.
+    pub fn set_address(&self, address: u8) {
+        self.reg().dcfg.update(|v| (v & !DCFG_DAD_MASK)
+                               | ((address as u32 & 0x7F) << DCFG_DAD_SHIFT))
+    }
+
+    /// Activates the endpoint described by `ep`, with its data toggle reset,
+    /// as part of `SET_CONFIGURATION` or `SET_INTERFACE`.  IN endpoint *n*
+    /// uses transmit FIFO *n*.
+    ///
+    /// # Panics
+    ///
+    /// If the endpoint is number 0 or doesn't exist, or takes packets larger
+    /// than `MAX_PACKET`.
+    pub fn configure_endpoint(&self, ep: &EndpointDescriptor) {
+        let n = ep.number() as usize;
+        assert!(n > 0 && n < ENDPOINT_COUNT
+                && ep.max_packet_size as usize <= MAX_PACKET);
+        let ctl = Depctl(0).with_usbaep(true)
+                           .with_eptyp(ep.kind as u32)
+                           .with_mpsiz(ep.max_packet_size as u32)
+                           .with_sd0pid(true)
+                           .with_snak(true);
+        if ep.is_in() {
+            OTG_FS.flush_tx_fifo(n as u32);
+            self.in_ep(n).ctl.set(ctl.with_txfnum(n as u32).0)
+        } else {
+            self.out_len[n].set(NO_PACKET);
+            self.out_ep(n).ctl.set(ctl.0);
+            self.arm_out(n)
+        }
+    }
+
+    /// Deactivates every endpoint but 0, as for a change of configuration
+    /// or alternate setting; follow up with `configure_endpoint` for those
+    /// the new one uses.
+    pub fn deconfigure_endpoints(&self) {
+        for n in 1..ENDPOINT_COUNT {
+            for ep in &[self.in_ep(n), self.out_ep(n)] {
+                let ctl = Depctl(ep.ctl.get());
+                ep.ctl.set(Depctl(0).with_snak(true)
+                                    .with_epdis(ctl.get_epena())
+                                    .0)
+            }
+            self.out_len[n].set(NO_PACKET);
+        }
+    }
+
+    /// Clears a halt on endpoint `address` (including its direction bit),
+    /// and resets its data toggle, as `CLEAR_FEATURE(ENDPOINT_HALT)` asks.
+    pub fn clear_halt(&self, address: u8) {
+        let n = (address & 0xF) as usize;
+        if n >= ENDPOINT_COUNT {
+            return
+        }
+        let ep = if address & DIR_IN != 0 {
+            self.in_ep(n)
+        } else {
+            self.out_ep(n)
+        };
+        ep.ctl.update(|v| Depctl(v).with_stall(false).with_sd0pid(true).0)
+    }
+
+    /// Returns the endpoints `ep_in`, `ep_out`, and `ep_notify` (numbers,
+    /// without direction bits) for use by a class driver.
+    pub fn endpoints(&self, ep_in: u8, ep_out: u8, ep_notify: u8)
+        -> Endpoints {
+        Endpoints {
+            device: self,
+            ep_in: ep_in as usize,
+            ep_out: ep_out as usize,
+            ep_notify: ep_notify as usize,
+        }
+    }
+
+    /// Arms OUT endpoint `n` to receive one packet.
+    fn arm_out(&self, n: usize) {
+        let ep = self.out_ep(n);
+        let tsiz = Deptsiz(ep.tsiz.get()).with_pktcnt(1)
+                                         .with_xfrsiz(MAX_PACKET as u32);
+        ep.tsiz.set(tsiz.0);
+        ep.ctl.update(|v| Depctl(v).with_cnak(true).with_epena(true).0)
+    }
+
+    /// Queues one packet on IN endpoint `n`, whose previous packet must be
+    /// gone.
+    fn send_packet(&self, n: usize, data: &[u8]) {
+        let ep = self.in_ep(n);
+        ep.tsiz.set(Deptsiz(0).with_pktcnt(1)
+                              .with_xfrsiz(data.len() as u32)
+                              .0);
+        ep.ctl.update(|v| Depctl(v).with_cnak(true).with_epena(true).0);
+        if !data.is_empty() {
+            OTG_FS.write_packet(n as u32, data)
+        }
+    }
+
+    /// Checks that IN endpoint `n` is configured and free, and sends `data`.
+    fn try_send_on(&self, n: usize, data: &[u8])
+        -> NbResult<(), EndpointError> {
+        let ctl = Depctl(self.in_ep(n).ctl.get());
+        if !ctl.get_usbaep() {
+            return Err(NbError::Other(EndpointError::NotConfigured))
+        }
+        if ctl.get_epena() || ctl.get_stall() {
+            return Err(NbError::WouldBlock)
+        }
+        self.send_packet(n, data);
+        Ok(())
+    }
+}
+
+/// Endpoints of a `Device`, as used by a class driver: a bulk pair, plus
+/// (for `EcmEndpoints`) an interrupt IN endpoint for notifications.
+pub struct Endpoints<'a> {
+    device: &'a Device,
+    ep_in: usize,
+    ep_out: usize,
+    ep_notify: usize,
+}
+
+impl<'a> BulkEndpoints for Endpoints<'a> {
+    type Error = EndpointError;
+
+    fn max_packet(&self) -> usize {
+        MAX_PACKET
+    }
+
+    fn try_receive(&self, buf: &mut [u8]) -> NbResult<usize, EndpointError> {
+        let d = self.device;
+        let n = self.ep_out;
+        if !Depctl(d.out_ep(n).ctl.get()).get_usbaep() {
+            return Err(NbError::Other(EndpointError::NotConfigured))
+        }
+        let len = d.out_len[n].get();
+        if len == NO_PACKET {
+            return Err(NbError::WouldBlock)
+        }
+        let bufs = unsafe { &*d.out_buf.get() };
+        buf[..len].copy_from_slice(&bufs[n][..len]);
+        d.out_len[n].set(NO_PACKET);
+        d.arm_out(n);
+        Ok(len)
+    }
+
+    fn try_send(&self, data: &[u8]) -> NbResult<(), EndpointError> {
+        self.device.try_send_on(self.ep_in, data)
+    }
+
+    fn stall_in(&self) {
+        self.device.in_ep(self.ep_in).ctl
+            .update(|v| Depctl(v).with_stall(true).0)
+    }
+
+    fn stall_out(&self) {
+        self.device.out_ep(self.ep_out).ctl
+            .update(|v| Depctl(v).with_stall(true).0)
+    }
+}
+
+impl<'a> EcmEndpoints for Endpoints<'a> {
+    fn try_notify(&self, data: &[u8]) -> NbResult<(), EndpointError> {
+        self.device.try_send_on(self.ep_notify, data)
+    }
+}
@@ -0,0 +1,795 @@
+//! OTG_FS host mode.
+//!
+//! The host side of the controller talks to one device on its port through
+//! eight *channels*.  A channel is programmed with the device address,
+//! endpoint, and transfer type, then enabled to run a transfer; it reports
+//! completion, NAK, STALL, or an error in its interrupt register.
+//!
+//! This driver moves one packet per channel enable and polls for the result,
+//! which keeps the FIFO handling simple (it never has to split a packet across
+//! FIFO refills) at some cost in throughput.  Endpoint state lives in `Pipe`s,
+//! which aren't tied to a channel: any claimed `Channel` can run a transfer on
+//! any pipe, so a driver can get by with one channel for all its endpoints.
+//!
+//! Typical startup:
+//!
+//! 1. `OtgFs::configure_pins` and enable the `UsbOtgFs` clock.
+//! 2. `Host::init`, then `Host::set_port_power(true)` (if the board switches
+//!    VBUS, turn that on too).
+//! 3. Wait for `Host::is_device_connected`.
+//! 4. `Host::reset_port` to learn the device's speed.
+//! 5. `Host::enumerate` to address it, then `Host::read_configuration` and
+//!    `Host::set_configuration`.
+
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT};
+
+use arm_m::reg::{mmio, Reg, ReservedReg};
+use hal::DelayUs;
+use sync;
+use usb::{self, DeviceDescriptor, EndpointDescriptor, EndpointType};
+use usb::SetupPacket;
+use super::{CoreInterrupts, Mode, PacketStatus, ALL_TX_FIFOS, FIFO_WORDS};
+use super::OTG_FS;
+
+#[repr(C, packed)]
+struct Registers {
+    hcfg:       Reg<u32>,
+    hfir:       Reg<u32>,
+    hfnum:      Reg<u32>,
+    _reserved0: ReservedReg,
+    hptxsts:    Reg<u32>,
+    haint:      Reg<u32>,
+    haintmsk:   Reg<u32>,
+    _reserved1: [ReservedReg; 9],
+    hprt:       Reg<u32>,
+}
+
+register_layout! {
+    fn check_layout: Registers [0x44] {
+        hcfg @ 0x00,
+        hfir @ 0x04,
+        hfnum @ 0x08,
+        hptxsts @ 0x10,
+        haint @ 0x14,
+        haintmsk @ 0x18,
+        hprt @ 0x40,
+    }
+}
+
+#[repr(C, packed)]
+struct ChannelRegisters {
+    hcchar:     Reg<u32>,
+    _reserved0: ReservedReg,
+    hcint:      Reg<u32>,
+    hcintmsk:   Reg<u32>,
+    hctsiz:     Reg<u32>,
+    _reserved1: [ReservedReg; 3],
+}
+
+register_layout! {
+    fn check_channel_layout: ChannelRegisters [0x20] {
+        hcchar @ 0x00,
+        hcint @ 0x08,
+        hcintmsk @ 0x0C,
+        hctsiz @ 0x10,
+    }
+}
+
+const HOST_ADDRESS : usize = 0x50000400;
+const CHANNEL_ADDRESS : usize = 0x50000500;
+
+/// Number of host channels.
+pub const CHANNEL_COUNT : u32 = 8;
+
+// FIFO RAM split: receive, then non-periodic, then periodic transmit.  Each
+// holds a few maximum-size (64-byte) packets.
+const RX_FIFO_WORDS : u32 = 128;
+const NPTX_FIFO_WORDS : u32 = 96;
+const PTX_FIFO_WORDS : u32 = FIFO_WORDS - RX_FIFO_WORDS - NPTX_FIFO_WORDS;
+
+/// How many times control and bulk transactions are retried when the device
+/// NAKs before giving up with `TransferError::Nak`.  Mass storage devices in
+/// particular can NAK for a long while during writes.
+pub const NAK_LIMIT : u32 = 1_000_000;
+
+bit_wrappers! {
+    /// Wrapper for the Host Port Control and Status Register bits.
+    pub struct Hprt(pub u32);
+    /// Wrapper for the Host Channel Characteristics Register bits.
+    pub struct Hcchar(pub u32);
+    /// Wrapper for the Host Channel Transfer Size Register bits.
+    pub struct Hctsiz(pub u32);
+}
+
+impl Hprt {
+    bitfield_accessors! {
+        /// Port speed: 1 for full speed, 2 for low speed.
+        pub total [18:17] get_pspd / with_pspd: u32,
+        pub total [12] get_ppwr / with_ppwr: bool,
+        /// Line status: bit 0 is D+, bit 1 D-.
+        pub total [11:10] get_plsts / with_plsts: u32,
+        pub total [8] get_prst / with_prst: bool,
+        pub total [7] get_psusp / with_psusp: bool,
+        pub total [6] get_pres / with_pres: bool,
+        /// Overcurrent change.  Write 1 to clear.
+        pub total [5] get_pocchng / with_pocchng: bool,
+        pub total [4] get_poca / with_poca: bool,
+        /// Enable change.  Write 1 to clear.
+        pub total [3] get_penchng / with_penchng: bool,
+        /// Port enabled.  Write 1 to *disable*.
+        pub total [2] get_pena / with_pena: bool,
+        /// Connection detected.  Write 1 to clear.
+        pub total [1] get_pcdet / with_pcdet: bool,
+        /// A device is connected.
+        pub total [0] get_pcsts / with_pcsts: bool,
+    }
+}
+
+/// HPRT bits that clear (or, for PENA, disable the port) when written with 1.
+const HPRT_W1C : u32 = (1 << 5) | (1 << 3) | (1 << 2) | (1 << 1);
+
+impl Hcchar {
+    bitfield_accessors! {
+        pub total [31] get_chena / with_chena: bool,
+        pub total [30] get_chdis / with_chdis: bool,
+        /// For periodic transfers: run in odd frames.
+        pub total [29] get_oddfrm / with_oddfrm: bool,
+        /// Device address.
+        pub total [28:22] get_dad / with_dad: u32,
+        pub total [21:20] get_mcnt / with_mcnt: u32,
+        /// Endpoint type, as in `usb::EndpointType`.
+        pub total [19:18] get_eptyp / with_eptyp: u32,
+        pub total [17] get_lsdev / with_lsdev: bool,
+        /// Endpoint direction: set for IN.
+        pub total [15] get_epdir / with_epdir: bool,
+        pub total [14:11] get_epnum / with_epnum: u32,
+        pub total [10:0] get_mpsiz / with_mpsiz: u32,
+    }
+}
+
+impl Hctsiz {
+    bitfield_accessors! {
+        pub total [30:29] get_dpid / with_dpid: Pid,
+        pub total [28:19] get_pktcnt / with_pktcnt: u32,
+        pub total [18:0] get_xfrsiz / with_xfrsiz: u32,
+    }
+}
+
+bit_enums! {
+    /// Packet IDs a channel can send, or expects.
+    pub bit_enum Pid {
+        Data0 = 0b00,
+        Data2 = 0b01,
+        Data1 = 0b10,
+        Setup = 0b11,
+    }
+}
+
+bitflags! {
+    /// Channel events, as reported in HCINT.
+    pub flags ChannelInterrupts: u32 {
+        const TRANSFER_COMPLETE = 1 << 0,
+        const HALTED = 1 << 1,
+        const STALL = 1 << 3,
+        const NAK = 1 << 4,
+        const ACK = 1 << 5,
+        const TRANSACTION_ERROR = 1 << 7,
+        const BABBLE = 1 << 8,
+        const FRAME_OVERRUN = 1 << 9,
+        const DATA_TOGGLE_ERROR = 1 << 10,
+    }
+}
+
+/// Speed of the device on the port.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum DeviceSpeed {
+    Full,
+    Low,
+}
+
+/// Ways a transfer can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum TransferError {
+    /// The device wasn't ready.  For interrupt endpoints this just means
+    /// there's nothing to report yet.
+    Nak,
+    /// The endpoint is halted, or the device rejected a control request.
+    Stall,
+    /// CRC error, timeout, or bit stuffing error.
+    Transaction,
+    /// The device sent more than it should have.
+    Babble,
+    DataToggle,
+    FrameOverrun,
+    /// There's no device on the port.
+    Disconnected,
+}
+
+/// Ways enumeration can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum EnumError {
+    Transfer(TransferError),
+    /// The device sent a malformed descriptor.
+    BadDescriptor,
+    /// The configuration doesn't fit in the buffer provided.
+    BufferTooSmall,
+}
+
+impl From<TransferError> for EnumError {
+    fn from(e: TransferError) -> EnumError {
+        EnumError::Transfer(e)
+    }
+}
+
+/// The state of one endpoint on one device.
+#[derive(Copy, Clone, Debug)]
+pub struct Pipe {
+    pub address: u8,
+    /// Endpoint number, without the direction bit.
+    pub endpoint: u8,
+    pub kind: EndpointType,
+    /// Direction, for non-control endpoints.
+    pub is_in: bool,
+    pub max_packet: u16,
+    pub low_speed: bool,
+    /// Data toggle for the next packet on a bulk or interrupt endpoint:
+    /// DATA1 if set.  Reset this when the endpoint's halt is cleared.
+    pub toggle: bool,
+}
+
+impl Pipe {
+    /// The default control pipe of the device at `address`.
+    pub fn control(address: u8, max_packet: u16, low_speed: bool) -> Pipe {
+        Pipe {
+            address: address,
+            endpoint: 0,
+            kind: EndpointType::Control,
+            is_in: false,
+            max_packet: max_packet,
+            low_speed: low_speed,
+            toggle: false,
+        }
+    }
+
+    /// A pipe to the endpoint described by `ep`, on the device at `address`.
+    pub fn for_endpoint(address: u8, ep: &EndpointDescriptor, low_speed: bool)
+        -> Pipe {
+        Pipe {
+            address: address,
+            endpoint: ep.number(),
+            kind: ep.kind,
+            is_in: ep.is_in(),
+            max_packet: ep.max_packet_size,
+            low_speed: low_speed,
+            toggle: false,
+        }
+    }
+
+    fn is_periodic(&self) -> bool {
+        self.kind == EndpointType::Interrupt
+            || self.kind == EndpointType::Isochronous
+    }
+
+    fn data_pid(&self) -> Pid {
+        data_pid(self.toggle)
+    }
+}
+
+fn data_pid(toggle: bool) -> Pid {
+    if toggle { Pid::Data1 } else { Pid::Data0 }
+}
+
+/// An enumerated device.
+#[derive(Copy, Clone, Debug)]
+pub struct Device {
+    pub address: u8,
+    pub speed: DeviceSpeed,
+    pub descriptor: DeviceDescriptor,
+    /// The device's default control pipe.
+    pub control: Pipe,
+}
+
+/// Bitmask of claimed channels.
+static CHANNELS_IN_USE : AtomicUsize = ATOMIC_USIZE_INIT;
+
+const ALL_CHANNELS : usize = (1 << CHANNEL_COUNT) - 1;
+
+/// A claimed host channel.  Dropping it releases the channel.
+pub struct Channel {
+    index: u32,
+}
+
+impl Channel {
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        let bit = 1 << self.index;
+        let _ = sync::fetch_update(&CHANNELS_IN_USE, |v| Some(v & !bit));
+    }
+}
+
+/// OTG_FS host mode driver.
+pub struct Host;
+
+impl Host {
+    fn reg(&self) -> &'static Registers {
+        unsafe { mmio(HOST_ADDRESS) }
+    }
+
+    fn channel(&self, index: u32) -> &'static ChannelRegisters {
+        let regs: &'static [ChannelRegisters; 8] =
+            unsafe { mmio(CHANNEL_ADDRESS) };
+        &regs[index as usize]
+    }
+
+    pub fn read_hprt(&self) -> Hprt {
+        Hprt(self.reg().hprt.get())
+    }
+
+    /// Updates HPRT without disturbing the write-1-to-clear bits.
+    pub fn update_hprt<F: FnOnce(Hprt) -> Hprt>(&self, f: F) {
+        let v = f(self.read_hprt());
+        self.reg().hprt.set(v.0 & !HPRT_W1C)
+    }
+
+    /// Puts the controller in host mode and sets up its FIFOs.  The port is
+    /// left unpowered.
+    pub fn init<D: DelayUs>(&self, delay: &D) {
+        OTG_FS.set_global_interrupt(false);
+        OTG_FS.update_gusbcfg(|v| v.with_physel(true));
+        OTG_FS.reset_core();
+        // Hosts supply VBUS rather than sensing it.
+        OTG_FS.update_gccfg(|v| v.with_pwrdwn(true)
+                                 .with_novbussens(true)
+                                 .with_vbusasen(false)
+                                 .with_vbusbsen(false));
+        OTG_FS.force_mode(Mode::Host, delay);
+        OTG_FS.ungate_clocks();
+        let _ = self.set_clock(DeviceSpeed::Full);
+
+        OTG_FS.set_rx_fifo_size(RX_FIFO_WORDS);
+        OTG_FS.set_tx_fifo(0, RX_FIFO_WORDS, NPTX_FIFO_WORDS);
+        OTG_FS.set_periodic_tx_fifo(RX_FIFO_WORDS + NPTX_FIFO_WORDS,
+                                    PTX_FIFO_WORDS);
+        OTG_FS.flush_tx_fifo(ALL_TX_FIFOS);
+        OTG_FS.flush_rx_fifo();
+
+        for i in 0..CHANNEL_COUNT {
+            let c = self.channel(i);
+            c.hcintmsk.set(0);
+            c.hcint.set(!0);
+        }
+        self.reg().haintmsk.set(0);
+        OTG_FS.clear_interrupts(CoreInterrupts::all());
+    }
+
+    /// Switches the PHY clock for talking to a device of `speed`.  Returns
+    /// `true` if the setting changed, in which case the port must be reset.
+    fn set_clock(&self, speed: DeviceSpeed) -> bool {
+        // FSLSPCS: 1 for 48MHz, 2 for 6MHz.  HFIR: PHY clocks per frame.
+        let (fslspcs, frame) = match speed {
+            DeviceSpeed::Full => (1, 48000),
+            DeviceSpeed::Low => (2, 6000),
+        };
+        let changed = self.reg().hcfg.get() & 3 != fslspcs;
+        self.reg().hcfg.update(|v| (v & !3) | fslspcs);
+        self.reg().hfir.set(frame);
+        changed
+    }
+
+    /// Turns port power on or off.  This drives the controller's own notion
+    /// of port power; boards that switch VBUS with a GPIO must do that too.
+    pub fn set_port_power(&self, on: bool) {
+        self.update_hprt(|v| v.with_ppwr(on))
+    }
+
+    pub fn is_device_connected(&self) -> bool {
+        self.read_hprt().get_pcsts()
+    }
+
+    /// Returns the port change events (connect, enable change, overcurrent
+    /// change) that have happened since the last call, and clears them.
+    pub fn take_port_changes(&self) -> Hprt {
+        let v = self.read_hprt();
+        // Of the W1C bits, write back only the change flags -- not PENA.
+        self.reg().hprt.set(v.0 & HPRT_W1C & !(1 << 2));
+        v
+    }
+
+    /// Resets the device on the port and returns its speed.  A device must be
+    /// reset before it's enumerated.
+    pub fn reset_port<D: DelayUs>(&self, delay: &D)
+        -> Result<DeviceSpeed, TransferError> {
+        loop {
+            if !self.is_device_connected() {
+                return Err(TransferError::Disconnected)
+            }
+            self.update_hprt(|v| v.with_prst(true));
+            // USB requires at least 10ms of reset.
+            delay.delay_ms(15);
+            self.update_hprt(|v| v.with_prst(false));
+            // ...and 10ms of recovery afterwards.
+            delay.delay_ms(20);
+
+            let hprt = self.read_hprt();
+            if !hprt.get_pena() {
+                return Err(TransferError::Disconnected)
+            }
+            let speed = if hprt.get_pspd() == 2 {
+                DeviceSpeed::Low
+            } else {
+                DeviceSpeed::Full
+            };
+            // A PHY clock change only takes effect after another reset.
+            if !self.set_clock(speed) {
+                return Ok(speed)
+            }
+        }
+    }
+
+    /// Reads the current (micro)frame number.
+    pub fn frame_number(&self) -> u32 {
+        self.reg().hfnum.get() & 0xFFFF
+    }
+
+    /// Returns a bitmask of the channels with events pending, whether or not
+    /// they're enabled as interrupts.
+    pub fn pending_channels(&self) -> u32 {
+        self.reg().haint.get() & 0xFFFF
+    }
+
+    /// Claims an idle channel, if there is one.
+    pub fn claim_channel(&self) -> Option<Channel> {
+        let r = sync::fetch_update(&CHANNELS_IN_USE, |v| {
+            if v == ALL_CHANNELS {
+                None
+            } else {
+                Some(v | (1 << (!v).trailing_zeros()))
+            }
+        });
+        match r {
+            Ok(old) => Some(Channel { index: (!old).trailing_zeros() }),
+            Err(_) => None,
+        }
+    }
+
+    /// Pops one entry from the receive FIFO, storing its data at
+    /// `buf[*received..]` if it's for channel `ch`.  Data for other channels
+    /// (there shouldn't be any) is discarded.
+    fn service_rx(&self, ch: u32, buf: &mut [u8], received: &mut usize) {
+        if let Some(st) = OTG_FS.pop_rx_status() {
+            if let Ok(PacketStatus::InData) = st.get_pktsts() {
+                let len = st.get_bcnt() as usize;
+                if st.get_chnum() == ch {
+                    *received += OTG_FS.read_packet(&mut buf[*received..],
+                                                    len);
+                } else {
+                    let _ = OTG_FS.read_packet(&mut [], len);
+                }
+            }
+        }
+    }
+
+    /// Stops channel `ch` if it's still running, and clears its events.
+    fn halt(&self, ch: u32, buf: &mut [u8], received: &mut usize) {
+        let c = self.channel(ch);
+        let hcchar = Hcchar(c.hcchar.get());
+        if hcchar.get_chena() {
+            c.hcchar.set(hcchar.with_chdis(true).with_chena(true).0);
+            // Halted IN channels post a status to the receive FIFO, which has
+            // to be drained before the halt completes.
+            while c.hcint.get() & HALTED.bits() == 0 {
+                self.service_rx(ch, buf, received)
+            }
+        }
+        c.hcint.set(!0)
+    }
+
+    /// Runs one transaction: sends `out`, or receives up to one packet into
+    /// `buf`.  Returns the number of bytes moved.  Received bytes that don't
+    /// fit in `buf` are lost.
+    fn transact(&self,
+                ch: &Channel,
+                pipe: &Pipe,
+                is_in: bool,
+                pid: Pid,
+                out: &[u8],
+                buf: &mut [u8]) -> Result<usize, TransferError> {
+        let c = self.channel(ch.index);
+        let len = if is_in { pipe.max_packet as u32 } else { out.len() as u32 };
+        let periodic = pipe.is_periodic();
+        // Periodic transfers are scheduled for the next frame.
+        let odd = periodic && self.frame_number() & 1 == 0;
+
+        c.hcint.set(!0);
+        c.hctsiz.set(Hctsiz(0).with_xfrsiz(len)
+                              .with_pktcnt(1)
+                              .with_dpid(pid)
+                              .0);
+        c.hcchar.set(Hcchar(0).with_mpsiz(pipe.max_packet as u32)
+                              .with_epnum(pipe.endpoint as u32)
+                              .with_epdir(is_in)
+                              .with_lsdev(pipe.low_speed)
+                              .with_eptyp(pipe.kind as u32)
+                              .with_mcnt(1)
+                              .with_dad(pipe.address as u32)
+                              .with_oddfrm(odd)
+                              .with_chena(true)
+                              .0);
+
+        if !is_in && !out.is_empty() {
+            let words = (out.len() as u32 + 3) / 4;
+            if periodic {
+                while self.reg().hptxsts.get() & 0xFFFF < words {}
+            } else {
+                while OTG_FS.read_hnptxsts().get_nptxfsav() < words {}
+            }
+            OTG_FS.write_packet(ch.index, out)
+        }
+
+        let mut received = 0;
+        loop {
+            self.service_rx(ch.index, buf, &mut received);
+
+            let events = ChannelInterrupts::from_bits_truncate(c.hcint.get());
+            let result = if events.contains(TRANSFER_COMPLETE) {
+                Ok(if is_in { received } else { out.len() })
+            } else if events.contains(STALL) {
+                Err(TransferError::Stall)
+            } else if events.contains(NAK) {
+                Err(TransferError::Nak)
+            } else if events.contains(TRANSACTION_ERROR) {
+                Err(TransferError::Transaction)
+            } else if events.contains(BABBLE) {
+                Err(TransferError::Babble)
+            } else if events.contains(DATA_TOGGLE_ERROR) {
+                Err(TransferError::DataToggle)
+            } else if events.contains(FRAME_OVERRUN) {
+                Err(TransferError::FrameOverrun)
+            } else if !self.is_device_connected() {
+                Err(TransferError::Disconnected)
+            } else {
+                continue
+            };
+            self.halt(ch.index, buf, &mut received);
+            return result
+        }
+    }
+
+    /// Like `transact`, but retries NAKed transactions up to `NAK_LIMIT`
+    /// times.
+    fn transact_retry(&self,
+                      ch: &Channel,
+                      pipe: &Pipe,
+                      is_in: bool,
+                      pid: Pid,
+                      out: &[u8],
+                      buf: &mut [u8]) -> Result<usize, TransferError> {
+        let mut naks = 0;
+        loop {
+            match self.transact(ch, pipe, is_in, pid, out, &mut *buf) {
+                Err(TransferError::Nak) if naks < NAK_LIMIT => naks += 1,
+                r => return r,
+            }
+        }
+    }
+
+    /// Performs a control transfer with an IN (or no) data stage, returning
+    /// the number of bytes received.
+    pub fn control_in(&self,
+                      ch: &Channel,
+                      pipe: &Pipe,
+                      setup: &SetupPacket,
+                      buf: &mut [u8]) -> Result<usize, TransferError> {
+        let _ = try!(self.transact_retry(ch, pipe, false, Pid::Setup,
+                                         &setup.to_bytes(), &mut []));
+
+        let want = if (setup.length as usize) < buf.len() {
+            setup.length as usize
+        } else {
+            buf.len()
+        };
+        let mut total = 0;
+        let mut toggle = true;
+        while total < want {
+            let n = try!(self.transact_retry(ch, pipe, true, data_pid(toggle),
+                                             &[], &mut buf[total..want]));
+            toggle = !toggle;
+            total += n;
+            if n < pipe.max_packet as usize {
+                break
+            }
+        }
+
+        // Status stage: an empty OUT packet.
+        let _ = try!(self.transact_retry(ch, pipe, false, Pid::Data1,
+                                         &[], &mut []));
+        Ok(total)
+    }
+
+    /// Performs a control transfer with an OUT (or no) data stage.
+    pub fn control_out(&self,
+                       ch: &Channel,
+                       pipe: &Pipe,
+                       setup: &SetupPacket,
+                       data: &[u8]) -> Result<(), TransferError> {
+        let _ = try!(self.transact_retry(ch, pipe, false, Pid::Setup,
+                                         &setup.to_bytes(), &mut []));
+
+        let mut toggle = true;
+        for chunk in data.chunks(pipe.max_packet as usize) {
+            let _ = try!(self.transact_retry(ch, pipe, false,
+                                             data_pid(toggle),
+                                             chunk, &mut []));
+            toggle = !toggle;
+        }
+
+        // Status stage: an empty IN packet.
+        let _ = try!(self.transact_retry(ch, pipe, true, Pid::Data1,
+                                         &[], &mut []));
+        Ok(())
+    }
+
+    /// Receives a bulk transfer into `buf`, returning its length.  The
+    /// transfer ends with a short packet or when `buf` is full; `buf` should
+    /// be a multiple of the pipe's packet size.
+    pub fn bulk_in(&self, ch: &Channel, pipe: &mut Pipe, buf: &mut [u8])
+        -> Result<usize, TransferError> {
+        let mut total = 0;
+        loop {
+            let pid = pipe.data_pid();
+            let n = try!(self.transact_retry(ch, pipe, true, pid,
+                                             &[], &mut buf[total..]));
+            pipe.toggle = !pipe.toggle;
+            total += n;
+            if n < pipe.max_packet as usize || total == buf.len() {
+                return Ok(total)
+            }
+        }
+    }
+
+    /// Sends `data` as a bulk transfer.  An empty `data` sends a zero-length
+    /// packet.
+    pub fn bulk_out(&self, ch: &Channel, pipe: &mut Pipe, data: &[u8])
+        -> Result<(), TransferError> {
+        if data.is_empty() {
+            return self.send_packet(ch, pipe, &[], true)
+        }
+        for chunk in data.chunks(pipe.max_packet as usize) {
+            try!(self.send_packet(ch, pipe, chunk, true))
+        }
+        Ok(())
+    }
+
+    /// Polls an interrupt IN endpoint once, returning the length of the
+    /// report received.  `TransferError::Nak` means the device has nothing to
+    /// report.
+    pub fn interrupt_in(&self, ch: &Channel, pipe: &mut Pipe, buf: &mut [u8])
+        -> Result<usize, TransferError> {
+        let pid = pipe.data_pid();
+        let n = try!(self.transact(ch, pipe, true, pid, &[], buf));
+        pipe.toggle = !pipe.toggle;
+        Ok(n)
+    }
+
+    /// Sends one packet to an interrupt OUT endpoint.
+    pub fn interrupt_out(&self, ch: &Channel, pipe: &mut Pipe, data: &[u8])
+        -> Result<(), TransferError> {
+        self.send_packet(ch, pipe, data, false)
+    }
+
+    fn send_packet(&self,
+                   ch: &Channel,
+                   pipe: &mut Pipe,
+                   data: &[u8],
+                   retry: bool) -> Result<(), TransferError> {
+        let pid = pipe.data_pid();
+        let _ = try!(if retry {
+            self.transact_retry(ch, pipe, false, pid, data, &mut [])
+        } else {
+            self.transact(ch, pipe, false, pid, data, &mut [])
+        });
+        pipe.toggle = !pipe.toggle;
+        Ok(())
+    }
+
+    /// Assigns `address` to the freshly reset device on the port, and reads
+    /// its device descriptor.
+    pub fn enumerate<D: DelayUs>(&self,
+                                 ch: &Channel,
+                                 delay: &D,
+                                 speed: DeviceSpeed,
+                                 address: u8) -> Result<Device, EnumError> {
+        let low_speed = speed == DeviceSpeed::Low;
+        // Every device accepts 8-byte packets on endpoint 0, and the first
+        // eight bytes of the descriptor tell us the real limit.
+        let mut pipe = Pipe::control(0, 8, low_speed);
+        let mut buf = [0; usb::DEVICE_DESCRIPTOR_LEN];
+        let n = try!(self.control_in(
+                ch, &pipe,
+                &SetupPacket::get_descriptor(usb::DESC_DEVICE, 0, 8),
+                &mut buf));
+        if n < 8 {
+            return Err(EnumError::BadDescriptor)
+        }
+        pipe.max_packet = buf[7] as u16;
+
+        try!(self.control_out(ch, &pipe,
+                              &SetupPacket::set_address(address), &[]));
+        // Devices get 2ms to start answering at their new address.
+        delay.delay_ms(2);
+        pipe.address = address;
+
+        let n = try!(self.control_in(
+                ch, &pipe,
+                &SetupPacket::get_descriptor(usb::DESC_DEVICE, 0,
+                                             buf.len() as u16),
+                &mut buf));
+        match DeviceDescriptor::parse(&buf[..n]) {
+            Some(d) => Ok(Device {
+                address: address,
+                speed: speed,
+                descriptor: d,
+                control: pipe,
+            }),
+            None => Err(EnumError::BadDescriptor),
+        }
+    }
+
+    /// Reads configuration descriptor `index` (not the configuration value),
+    /// and all the descriptors that follow it, into `buf`.  Returns the total
+    /// length; walk the result with `usb::Descriptors`.
+    pub fn read_configuration(&self,
+                              ch: &Channel,
+                              device: &Device,
+                              index: u8,
+                              buf: &mut [u8]) -> Result<usize, EnumError> {
+        let mut header = [0; 9];
+        let n = try!(self.control_in(
+                ch, &device.control,
+                &SetupPacket::get_descriptor(usb::DESC_CONFIGURATION, index,
+                                             header.len() as u16),
+                &mut header));
+        if n < header.len() || header[1] != usb::DESC_CONFIGURATION {
+            return Err(EnumError::BadDescriptor)
+        }
+        let total = (header[2] as usize) | ((header[3] as usize) << 8);
+        if total > buf.len() {
+            return Err(EnumError::BufferTooSmall)
+        }
+
+        let n = try!(self.control_in(
+                ch, &device.control,
+                &SetupPacket::get_descriptor(usb::DESC_CONFIGURATION, index,
+                                             total as u16),
+                &mut buf[..total]));
+        if n < total {
+            return Err(EnumError::BadDescriptor)
+        }
+        Ok(total)
+    }
+
+    /// Selects configuration `value` (from `ConfigurationDescriptor::value`).
+    pub fn set_configuration(&self, ch: &Channel, device: &Device, value: u8)
+        -> Result<(), TransferError> {
+        self.control_out(ch, &device.control,
+                         &SetupPacket::set_configuration(value), &[])
+    }
+
+    /// Clears a halt on the endpoint behind `pipe`, and resets its toggle.
+    pub fn clear_halt(&self, ch: &Channel, device: &Device, pipe: &mut Pipe)
+        -> Result<(), TransferError> {
+        let ep = pipe.endpoint | if pipe.is_in { usb::DIR_IN } else { 0 };
+        try!(self.control_out(ch, &device.control,
+                              &SetupPacket::clear_halt(ep), &[]));
+        pipe.toggle = false;
+        Ok(())
+    }
+}
+
+/// Shared instance of the host mode driver.
+pub static HOST: Host = Host;
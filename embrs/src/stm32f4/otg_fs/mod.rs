@@ -0,0 +1,423 @@
+//! USB On-The-Go Full Speed (OTG_FS) controller support.
+//!
+//! The controller can act as a USB device or as a host; this module covers
+//! the core shared by both modes -- reset, mode selection, and the packet
+//! FIFOs -- and `host` and `device` build the two modes on top of it.
+//!
+//! The FS controller has no DMA, so all data passes through the FIFOs by
+//! programmed I/O ("slave mode" in the Reference Manual).  There's 1.25KiB of
+//! FIFO RAM, divided between one shared receive FIFO and the transmit FIFOs
+//! by `set_rx_fifo_size` and `set_tx_fifo`.
+//!
+//! Before use, enable the `UsbOtgFs` clock (which needs the 48MHz PLL
+//! output; see `Rcc::pll48_running`) and route the pins with
+//! `OtgFs::configure_pins`.
+
+#![allow(trivial_numeric_casts)]  // for bitflags :-(
+
+use arm_m::reg::{mmio, AtomicReg, Reg, ReservedReg};
+use bits;
+use hal::DelayUs;
+use super::gpio::{self, Pins};
+
+pub mod device;
+pub mod host;
+
+#[repr(C, packed)]
+struct Registers {
+    gotgctl:    Reg<u32>,
+    gotgint:    Reg<u32>,
+    gahbcfg:    Reg<u32>,
+    gusbcfg:    Reg<u32>,
+    grstctl:    Reg<u32>,
+    gintsts:    Reg<u32>,
+    gintmsk:    Reg<u32>,
+    grxstsr:    Reg<u32>,
+    grxstsp:    Reg<u32>,
+    grxfsiz:    Reg<u32>,
+    /// Host non-periodic transmit FIFO size, or in device mode, endpoint 0
+    /// transmit FIFO size (DIEPTXF0).
+    hnptxfsiz:  Reg<u32>,
+    hnptxsts:   Reg<u32>,
+    _reserved0: [ReservedReg; 2],
+    gccfg:      Reg<u32>,
+    cid:        Reg<u32>,
+    _reserved1: [ReservedReg; 48],
+    hptxfsiz:   Reg<u32>,
+    /// Device IN endpoint transmit FIFO sizes DIEPTXF1 - DIEPTXF3.
+    dieptxf:    [Reg<u32>; 3],
+}
+
+register_layout! {
+    fn check_layout: Registers [0x110] {
+        gotgctl @ 0x00,
+        gotgint @ 0x04,
+        gahbcfg @ 0x08,
+        gusbcfg @ 0x0C,
+        grstctl @ 0x10,
+        gintsts @ 0x14,
+        gintmsk @ 0x18,
+        grxstsr @ 0x1C,
+        grxstsp @ 0x20,
+        grxfsiz @ 0x24,
+        hnptxfsiz @ 0x28,
+        hnptxsts @ 0x2C,
+        gccfg @ 0x38,
+        cid @ 0x3C,
+        hptxfsiz @ 0x100,
+        dieptxf @ 0x104,
+    }
+}
+
+const OTG_FS_ADDRESS : usize = 0x50000000;
+/// Offset of the power and clock gating control register.
+const PCGCCTL_OFFSET : usize = 0xE00;
+/// Offset of the first FIFO window; window *n* is at `(n + 1) * 0x1000`.
+const FIFO_OFFSET : usize = 0x1000;
+
+/// Total FIFO RAM, in words.
+pub const FIFO_WORDS : u32 = 320;
+
+/// `flush_tx_fifo` argument that flushes every transmit FIFO.
+pub const ALL_TX_FIFOS : u32 = 0x10;
+
+bit_wrappers! {
+    /// Wrapper for the USB Configuration Register bits.
+    pub struct Gusbcfg(pub u32);
+    /// Wrapper for the General Core Configuration Register bits.
+    pub struct Gccfg(pub u32);
+    /// Wrapper for a receive status entry, as popped from GRXSTSP.
+    pub struct Grxstsp(pub u32);
+    /// Wrapper for the Non-Periodic Transmit FIFO/Queue Status Register bits.
+    pub struct Hnptxsts(pub u32);
+}
+
+impl Gusbcfg {
+    bitfield_accessors! {
+        pub total [30] get_fdmod / with_fdmod: bool,
+        pub total [29] get_fhmod / with_fhmod: bool,
+        /// Turnaround time, in PHY clocks.
+        pub total [13:10] get_trdt / with_trdt: u32,
+        pub total [9] get_hnpcap / with_hnpcap: bool,
+        pub total [8] get_srpcap / with_srpcap: bool,
+        /// Full speed serial transceiver select.  Always set.
+        pub total [6] get_physel / with_physel: bool,
+        pub total [2:0] get_tocal / with_tocal: u32,
+    }
+}
+
+impl Gccfg {
+    bitfield_accessors! {
+        /// Disables VBUS sensing, for devices that aren't bus powered.
+        pub total [21] get_novbussens / with_novbussens: bool,
+        pub total [20] get_sofouten / with_sofouten: bool,
+        pub total [19] get_vbusbsen / with_vbusbsen: bool,
+        pub total [18] get_vbusasen / with_vbusasen: bool,
+        /// Powers up the transceiver (despite the name).
+        pub total [16] get_pwrdwn / with_pwrdwn: bool,
+    }
+}
+
+impl Grxstsp {
+    bitfield_accessors! {
+        pub [20:17] get_pktsts / with_pktsts: PacketStatus,
+        /// The same field, as the device mode reads it.
+        pub [20:17] get_device_pktsts / with_device_pktsts:
+            DevicePacketStatus,
+        pub total [16:15] get_dpid / with_dpid: u32,
+        /// Byte count of the data that follows in the FIFO.
+        pub total [14:4] get_bcnt / with_bcnt: u32,
+        /// Channel (host) or endpoint (device) number.
+        pub total [3:0] get_chnum / with_chnum: u32,
+    }
+}
+
+impl Hnptxsts {
+    bitfield_accessors! {
+        pub total [30:24] get_nptxqtop / with_nptxqtop: u32,
+        /// Free request queue entries.
+        pub total [23:16] get_nptqxsav / with_nptqxsav: u32,
+        /// Free FIFO space, in words.
+        pub total [15:0] get_nptxfsav / with_nptxfsav: u32,
+    }
+}
+
+bit_enums! {
+    /// Kinds of receive FIFO entry, in host mode.
+    pub bit_enum PacketStatus {
+        /// A data packet follows.
+        InData = 0b0010,
+        /// An IN transfer finished.
+        InComplete = 0b0011,
+        DataToggleError = 0b0101,
+        ChannelHalted = 0b0111,
+    }
+
+    /// Kinds of receive FIFO entry, in device mode.
+    pub bit_enum DevicePacketStatus {
+        GlobalOutNak = 0b0001,
+        /// An OUT data packet follows.
+        OutData = 0b0010,
+        /// An OUT transfer finished.
+        OutComplete = 0b0011,
+        /// The SETUP stage of a control transfer finished.
+        SetupComplete = 0b0100,
+        /// The eight bytes of a SETUP packet follow.
+        SetupData = 0b0110,
+    }
+}
+
+bitflags! {
+    /// Core interrupt sources, as reported in GINTSTS and enabled in GINTMSK.
+    pub flags CoreInterrupts: u32 {
+        const MODE_MISMATCH = 1 << 1,
+        const OTG = 1 << 2,
+        const START_OF_FRAME = 1 << 3,
+        const RX_FIFO_NONEMPTY = 1 << 4,
+        const NONPERIODIC_TX_FIFO_EMPTY = 1 << 5,
+        const EARLY_SUSPEND = 1 << 10,
+        const SUSPEND = 1 << 11,
+        const USB_RESET = 1 << 12,
+        const ENUMERATION_DONE = 1 << 13,
+        const IN_ENDPOINT = 1 << 18,
+        const OUT_ENDPOINT = 1 << 19,
+        const HOST_PORT = 1 << 24,
+        const HOST_CHANNEL = 1 << 25,
+        const PERIODIC_TX_FIFO_EMPTY = 1 << 26,
+        const CONNECTOR_ID_CHANGE = 1 << 28,
+        const DISCONNECT = 1 << 29,
+        const SESSION_REQUEST = 1 << 30,
+        const RESUME = 1 << 31,
+    }
+}
+
+impl bits::FromBits for CoreInterrupts {
+    fn from_bits(bits: u32) -> bits::BitsResult<Self> {
+        CoreInterrupts::from_bits(bits).ok_or(bits::BadBits(bits))
+    }
+}
+
+impl bits::IntoBits for CoreInterrupts {
+    fn into_bits(self) -> u32 {
+        self.bits()
+    }
+}
+
+/// The role the controller takes.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Mode {
+    Host,
+    Device,
+}
+
+/// GAHBCFG global interrupt enable.
+const GAHBCFG_GINT : u32 = 1 << 0;
+/// GINTSTS current mode bit: set in host mode.
+const GINTSTS_CMOD : u32 = 1 << 0;
+
+// GRSTCTL bits.
+const GRSTCTL_AHBIDL : u32 = 1 << 31;
+const GRSTCTL_TXFNUM_SHIFT : u32 = 6;
+const GRSTCTL_TXFFLSH : u32 = 1 << 5;
+const GRSTCTL_RXFFLSH : u32 = 1 << 4;
+const GRSTCTL_CSRST : u32 = 1 << 0;
+
+/// OTG_FS core driver.
+pub struct OtgFs;
+
+impl OtgFs {
+    fn reg(&self) -> &'static Registers {
+        unsafe { mmio(OTG_FS_ADDRESS) }
+    }
+
+    fn pcgcctl(&self) -> &'static Reg<u32> {
+        unsafe { mmio(OTG_FS_ADDRESS + PCGCCTL_OFFSET) }
+    }
+
+    fn fifo(&self, n: u32) -> &'static Reg<u32> {
+        unsafe { mmio(OTG_FS_ADDRESS + FIFO_OFFSET * (n as usize + 1)) }
+    }
+
+    pub fn read_gusbcfg(&self) -> Gusbcfg {
+        Gusbcfg(self.reg().gusbcfg.get())
+    }
+
+    pub fn write_gusbcfg(&self, v: Gusbcfg) {
+        self.reg().gusbcfg.set(v.0)
+    }
+
+    pub fn update_gusbcfg<F: FnOnce(Gusbcfg) -> Gusbcfg>(&self, f: F) {
+        self.write_gusbcfg(f(self.read_gusbcfg()))
+    }
+
+    pub fn read_gccfg(&self) -> Gccfg {
+        Gccfg(self.reg().gccfg.get())
+    }
+
+    pub fn write_gccfg(&self, v: Gccfg) {
+        self.reg().gccfg.set(v.0)
+    }
+
+    pub fn update_gccfg<F: FnOnce(Gccfg) -> Gccfg>(&self, f: F) {
+        self.write_gccfg(f(self.read_gccfg()))
+    }
+
+    pub fn read_hnptxsts(&self) -> Hnptxsts {
+        Hnptxsts(self.reg().hnptxsts.get())
+    }
+
+    /// Routes `pins` to the controller's DM, DP, ID, VBUS, and SOF signals
+    /// (alternate function 10).
+    ///
+    /// Which pins carry which signals is part-specific; see the datasheet.
+    pub fn configure_pins(&self, pins: &Pins) {
+        (pins.port)().set_speed(pins.pins, gpio::Speed::VeryHigh);
+        pins.configure_alternate(gpio::Function::AF10, gpio::Pull::None)
+    }
+
+    /// Resets the core's state machines, leaving configuration alone.
+    pub fn reset_core(&self) {
+        while self.reg().grstctl.get() & GRSTCTL_AHBIDL == 0 {}
+        self.reg().grstctl.set(GRSTCTL_CSRST);
+        while self.reg().grstctl.get() & GRSTCTL_CSRST != 0 {}
+        while self.reg().grstctl.get() & GRSTCTL_AHBIDL == 0 {}
+    }
+
+    /// Forces the controller into `mode`, ignoring the ID pin, and waits the
+    /// 25ms the change takes.
+    pub fn force_mode<D: DelayUs>(&self, mode: Mode, delay: &D) {
+        self.update_gusbcfg(|v| v.with_fhmod(mode == Mode::Host)
+                                 .with_fdmod(mode == Mode::Device));
+        delay.delay_ms(25)
+    }
+
+    /// Reads the mode the controller is actually in.
+    pub fn current_mode(&self) -> Mode {
+        if self.reg().gintsts.get() & GINTSTS_CMOD != 0 {
+            Mode::Host
+        } else {
+            Mode::Device
+        }
+    }
+
+    /// Ungates the PHY clock, after a suspend or at startup.
+    pub fn ungate_clocks(&self) {
+        self.pcgcctl().set(0)
+    }
+
+    /// Enables or disables the controller's interrupt output as a whole.
+    pub fn set_global_interrupt(&self, enabled: bool) {
+        if enabled {
+            self.reg().gahbcfg.atomic_or(GAHBCFG_GINT)
+        } else {
+            self.reg().gahbcfg.atomic_nand(GAHBCFG_GINT)
+        }
+    }
+
+    /// Reads the pending core interrupts (whether or not they're enabled).
+    pub fn read_interrupts(&self) -> CoreInterrupts {
+        CoreInterrupts::from_bits_truncate(self.reg().gintsts.get())
+    }
+
+    /// Clears `flags`.  Only the latched events can be cleared this way; the
+    /// others (such as `RX_FIFO_NONEMPTY`) reflect current state.
+    pub fn clear_interrupts(&self, flags: CoreInterrupts) {
+        self.reg().gintsts.set(flags.bits())
+    }
+
+    pub fn enable_interrupts(&self, flags: CoreInterrupts) {
+        self.reg().gintmsk.atomic_or(flags.bits())
+    }
+
+    pub fn disable_interrupts(&self, flags: CoreInterrupts) {
+        self.reg().gintmsk.atomic_nand(flags.bits())
+    }
+
+    /// Sets the size of the receive FIFO, which starts at FIFO RAM offset 0,
+    /// in words.  It must hold at least one maximum-size packet plus status,
+    /// and more to receive back-to-back packets.
+    pub fn set_rx_fifo_size(&self, words: u32) {
+        self.reg().grxfsiz.set(words & 0xFFFF)
+    }
+
+    /// Places transmit FIFO `n` at word offset `start` in FIFO RAM, `words`
+    /// long.  In host mode, FIFO 0 is the non-periodic FIFO (control and
+    /// bulk); in device mode, FIFO *n* serves IN endpoint *n*.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is not 0-3.
+    pub fn set_tx_fifo(&self, n: u32, start: u32, words: u32) {
+        let v = (words << 16) | (start & 0xFFFF);
+        match n {
+            0 => self.reg().hnptxfsiz.set(v),
+            1 | 2 | 3 => self.reg().dieptxf[n as usize - 1].set(v),
+            _ => panic!("no such FIFO"),
+        }
+    }
+
+    /// Places the host periodic (interrupt and isochronous) transmit FIFO at
+    /// word offset `start`, `words` long.
+    pub fn set_periodic_tx_fifo(&self, start: u32, words: u32) {
+        self.reg().hptxfsiz.set((words << 16) | (start & 0xFFFF))
+    }
+
+    /// Discards the contents of transmit FIFO `n`, or all of them if `n` is
+    /// `ALL_TX_FIFOS`.
+    pub fn flush_tx_fifo(&self, n: u32) {
+        self.reg().grstctl.set(GRSTCTL_TXFFLSH
+                               | ((n & 0x1F) << GRSTCTL_TXFNUM_SHIFT));
+        while self.reg().grstctl.get() & GRSTCTL_TXFFLSH != 0 {}
+    }
+
+    /// Discards the contents of the receive FIFO.
+    pub fn flush_rx_fifo(&self) {
+        self.reg().grstctl.set(GRSTCTL_RXFFLSH);
+        while self.reg().grstctl.get() & GRSTCTL_RXFFLSH != 0 {}
+    }
+
+    /// Pops the next receive FIFO entry's status, if there is one.  Any data
+    /// it describes must then be read with `read_packet`.
+    pub fn pop_rx_status(&self) -> Option<Grxstsp> {
+        if self.read_interrupts().contains(RX_FIFO_NONEMPTY) {
+            Some(Grxstsp(self.reg().grxstsp.get()))
+        } else {
+            None
+        }
+    }
+
+    /// Reads a `len`-byte packet from the receive FIFO into `buf`, returning
+    /// the number of bytes stored.  The whole packet is popped even if `buf`
+    /// is too short for it.
+    pub fn read_packet(&self, buf: &mut [u8], len: usize) -> usize {
+        let fifo = self.fifo(0);
+        let stored = if len < buf.len() { len } else { buf.len() };
+        let mut i = 0;
+        while i < len {
+            let word = fifo.get();
+            for j in 0..4 {
+                if i + j < stored {
+                    buf[i + j] = (word >> (8 * j)) as u8
+                }
+            }
+            i += 4;
+        }
+        stored
+    }
+
+    /// Pushes `data` into transmit FIFO `n` (in host mode, the FIFO serving
+    /// channel `n`).  The caller must have checked that there's room.
+    pub fn write_packet(&self, n: u32, data: &[u8]) {
+        let fifo = self.fifo(n);
+        for chunk in data.chunks(4) {
+            let mut word = 0;
+            for (j, &b) in chunk.iter().enumerate() {
+                word |= (b as u32) << (8 * j);
+            }
+            fifo.set(word)
+        }
+    }
+}
+
+/// Shared instance of the `OtgFs` driver.
+pub static OTG_FS: OtgFs = OtgFs;
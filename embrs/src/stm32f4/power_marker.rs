@@ -0,0 +1,102 @@
+//! Power profiling markers.
+//!
+//! When measuring run-mode current (e.g. across the Discovery board's IDD
+//! jumper), it helps to know what the firmware was doing at each point in the
+//! trace.  This module lets the application designate a GPIO pin for each of
+//! a handful of firmware phases; the pin is driven high for the duration of
+//! the phase, so a scope or logic analyzer channel can be lined up against the
+//! current measurement.
+//!
+//! Phases with no designated pin cost an atomic load and a branch.
+//!
+//! Designating a pin looks like this:
+//!
+//! ```
+//! static SLEEP_MARKER: Pins = Pins {
+//!     port: gpio::gpiod,
+//!     pins: gpio::P14,
+//! };
+//!
+//! // ... after configuring PD14 as a push-pull output:
+//! power_marker::designate(Phase::Sleep, Some(&SLEEP_MARKER));
+//! ```
+
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use arm_m;
+use super::gpio::Pins;
+
+/// Firmware phases that can be marked.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum Phase {
+    /// The processor is asleep waiting for an interrupt.
+    Sleep = 0,
+    /// The Flash controller is busy programming or erasing.
+    FlashWrite = 1,
+    /// A burst of peripheral activity, as defined by the application.
+    PeripheralBurst = 2,
+    /// Reserved for the application's own use.
+    User = 3,
+}
+
+const PHASE_COUNT : usize = 4;
+
+/// Designated markers, stored as `&'static Pins` addresses (or zero).
+static MARKERS : [AtomicUsize; PHASE_COUNT] = [
+    ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT,
+];
+
+/// Designates `marker` to be driven during `phase`, or removes the designation
+/// if `marker` is `None`.  The pins must be configured as outputs by the
+/// application.  This can be called at any time; if it races with the start or
+/// end of the phase, the old pin may be left high.
+pub fn designate(phase: Phase, marker: Option<&'static Pins>) {
+    let addr = marker.map(|m| m as *const Pins as usize).unwrap_or(0);
+    MARKERS[phase as usize].store(addr, Ordering::Release)
+}
+
+fn marker(phase: Phase) -> Option<&'static Pins> {
+    let addr = MARKERS[phase as usize].load(Ordering::Acquire);
+    if addr == 0 {
+        None
+    } else {
+        Some(unsafe { &*(addr as *const Pins) })
+    }
+}
+
+/// Signals the start of `phase` by driving its marker pin(s) high.
+#[inline]
+pub fn begin(phase: Phase) {
+    if let Some(m) = marker(phase) {
+        m.set()
+    }
+}
+
+/// Signals the end of `phase` by driving its marker pin(s) low.
+#[inline]
+pub fn end(phase: Phase) {
+    if let Some(m) = marker(phase) {
+        m.clear()
+    }
+}
+
+/// Runs `body` bracketed by markers for `phase`.
+#[inline]
+pub fn mark<R, F: FnOnce() -> R>(phase: Phase, body: F) -> R {
+    begin(phase);
+    let r = body();
+    end(phase);
+    r
+}
+
+/// Waits for an interrupt, marking the `Sleep` phase.
+///
+/// Note that the marker is lowered after the interrupt handler(s) have run,
+/// not when the processor wakes.
+#[inline]
+pub fn wait_for_interrupt() {
+    mark(Phase::Sleep, arm_m::wait_for_interrupt)
+}
@@ -0,0 +1,197 @@
+//! Power controller (PWR) support.
+//!
+//! Before use, enable the `Pwr` clock.
+//!
+//! # Supply monitoring
+//!
+//! The programmable voltage detector (PVD) compares VDD against a threshold
+//! (`PvdLevel`), and can interrupt through EXTI line 16 when the supply falls
+//! below it -- in time, with a large enough hold-up capacitor, for the
+//! application to save state to Flash or backup SRAM:
+//!
+//!     fn power_failing() {
+//!         save_state_to_backup_sram()
+//!     }
+//!
+//!     PWR.set_pvd_handler(PvdLevel::Mv2700, power_failing);
+//!     NVIC.enable_irq(Interrupt::Pvd);
+//!
+//! The application must install `pvd_isr` as the PVD vector
+//! (`InterruptTable::pvd`).
+
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use arm_m::reg::{mmio, Reg};
+use super::exti::{Trigger, EXTI};
+
+#[repr(C, packed)]
+struct Registers {
+    cr:  Reg<u32>,
+    csr: Reg<u32>,
+}
+
+register_layout! {
+    fn check_layout: Registers [0x8] {
+        cr @ 0x00,
+        csr @ 0x04,
+    }
+}
+
+const PWR_ADDRESS : usize = 0x40007000;
+
+bit_wrappers! {
+    /// Wrapper for the Power Control Register bits.
+    pub struct Cr(pub u32);
+    /// Wrapper for the Power Control/Status Register bits.
+    pub struct Csr(pub u32);
+}
+
+impl Cr {
+    bitfield_accessors! {
+        /// Regulator voltage scaling: set for scale 1 (full speed).
+        pub total [14] get_vos / with_vos: bool,
+        pub total [9] get_fpds / with_fpds: bool,
+        /// Disables write protection on the backup domain (RTC, backup
+        /// registers, and backup SRAM).
+        pub total [8] get_dbp / with_dbp: bool,
+        /// PVD threshold.
+        pub total [7:5] get_pls / with_pls: PvdLevel,
+        /// Enables the PVD.
+        pub total [4] get_pvde / with_pvde: bool,
+        pub total [3] get_csbf / with_csbf: bool,
+        pub total [2] get_cwuf / with_cwuf: bool,
+        pub total [1] get_pdds / with_pdds: bool,
+        pub total [0] get_lpds / with_lpds: bool,
+    }
+}
+
+impl Csr {
+    bitfield_accessors! {
+        pub total [14] get_vosrdy / with_vosrdy: bool,
+        /// Keeps the backup SRAM powered from VBAT.
+        pub total [9] get_bre / with_bre: bool,
+        pub total [8] get_ewup / with_ewup: bool,
+        /// Set when the backup regulator is ready.
+        pub total [3] get_brr / with_brr: bool,
+        /// PVD output: set while VDD is below the PVD threshold.
+        pub total [2] get_pvdo / with_pvdo: bool,
+        pub total [1] get_sbf / with_sbf: bool,
+        pub total [0] get_wuf / with_wuf: bool,
+    }
+}
+
+bit_enums! {
+    /// PVD thresholds, in millivolts.  These are the falling thresholds;
+    /// rising thresholds are about 100mV higher.
+    pub bit_enum PvdLevel {
+        Mv2000 = 0b000,
+        Mv2100 = 0b001,
+        Mv2300 = 0b010,
+        Mv2500 = 0b011,
+        Mv2600 = 0b100,
+        Mv2700 = 0b101,
+        Mv2800 = 0b110,
+        Mv2900 = 0b111,
+    }
+}
+
+/// The EXTI line carrying the PVD output.
+pub const PVD_EXTI_LINE : u32 = 1 << 16;
+
+/// Function called from `pvd_isr` when the supply falls below the threshold.
+pub type PvdHandler = fn();
+
+/// The registered `PvdHandler`, stored as an address (or zero).
+static PVD_HANDLER : AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// PWR driver.
+pub struct Pwr;
+
+impl Pwr {
+    fn reg(&self) -> &'static Registers {
+        unsafe { mmio(PWR_ADDRESS) }
+    }
+
+    pub fn read_cr(&self) -> Cr {
+        Cr(self.reg().cr.get())
+    }
+
+    pub fn write_cr(&self, v: Cr) {
+        self.reg().cr.set(v.0)
+    }
+
+    pub fn update_cr<F: FnOnce(Cr) -> Cr>(&self, f: F) {
+        self.write_cr(f(self.read_cr()))
+    }
+
+    pub fn read_csr(&self) -> Csr {
+        Csr(self.reg().csr.get())
+    }
+
+    pub fn write_csr(&self, v: Csr) {
+        self.reg().csr.set(v.0)
+    }
+
+    pub fn update_csr<F: FnOnce(Csr) -> Csr>(&self, f: F) {
+        self.write_csr(f(self.read_csr()))
+    }
+
+    /// Allows or forbids writes to the backup domain.
+    pub fn set_backup_access(&self, allowed: bool) {
+        self.update_cr(|v| v.with_dbp(allowed))
+    }
+
+    /// Turns on the backup regulator, so the backup SRAM survives on VBAT,
+    /// and waits for it to come up.  Requires backup access.
+    pub fn enable_backup_regulator(&self) {
+        self.update_csr(|v| v.with_bre(true));
+        while !self.read_csr().get_brr() {}
+    }
+
+    /// Turns on the PVD, comparing VDD against `level`.  This alone doesn't
+    /// interrupt; see `set_pvd_handler`.
+    pub fn enable_pvd(&self, level: PvdLevel) {
+        self.update_cr(|v| v.with_pls(level));
+        self.update_cr(|v| v.with_pvde(true))
+    }
+
+    /// Turns off the PVD, and its interrupt.
+    pub fn disable_pvd(&self) {
+        EXTI.disable_interrupt(PVD_EXTI_LINE);
+        self.update_cr(|v| v.with_pvde(false));
+        PVD_HANDLER.store(0, Ordering::Release)
+    }
+
+    /// Checks whether VDD is below the PVD threshold.  Meaningful only while
+    /// the PVD is enabled.
+    pub fn is_supply_low(&self) -> bool {
+        self.read_csr().get_pvdo()
+    }
+
+    /// Turns on the PVD at `level`, and arranges for `handler` to be called
+    /// from `pvd_isr` each time the supply falls below it.  The application
+    /// must also enable the `Interrupt::Pvd` IRQ.
+    pub fn set_pvd_handler(&self, level: PvdLevel, handler: PvdHandler) {
+        PVD_HANDLER.store(handler as usize, Ordering::Release);
+        self.enable_pvd(level);
+        // PVDO rises as the supply falls.
+        EXTI.set_trigger(PVD_EXTI_LINE, Trigger::Rising);
+        EXTI.clear_pending(PVD_EXTI_LINE);
+        EXTI.enable_interrupt(PVD_EXTI_LINE)
+    }
+}
+
+/// Shared instance of the `Pwr` driver.
+pub static PWR: Pwr = Pwr;
+
+/// Interrupt handler for the PVD.  Install this as the PVD vector
+/// (`InterruptTable::pvd`).
+pub extern "C" fn pvd_isr() {
+    EXTI.clear_pending(PVD_EXTI_LINE);
+    let h = PVD_HANDLER.load(Ordering::Acquire);
+    if h != 0 {
+        let handler: PvdHandler = unsafe { mem::transmute(h) };
+        handler()
+    }
+}
@@ -3,22 +3,181 @@
 //! This module provides a higher-level driver with some useful algorithms.  For
 //! more direct access to the hardware, see the `raw` submodule.
 
-use arm_m;
-use arm_m::reg::AtomicReg;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use arm_m::dwt::DWT;
+use arm_m::reg::{mmio, AtomicReg};
+use super::errata;
 use super::flash::FLASH;
 
 pub mod raw;
 pub use self::raw::{AhbPrescaler, ApbPrescaler, Cr, Cfgr, Pllcfgr};
+pub use self::raw::{Bdcr, Plli2scfgr, RtcSel};
 pub use self::raw::Pllp as SysPrescaler;
+pub use self::raw::{ClockSwitch, PllSource};
 
 use self::raw::ClockDivisor;
 
 /// At startup, before the RCC has been reconfigured, the STM32F4 runs at 16MHz.
 pub const BOOT_CLOCK_HZ : u32 = 16_000_000;
 
+/// Upper bound on the time `configure_clocks` waits for the HSE to start, or
+/// for the PLL to lock, in CPU cycles at `BOOT_CLOCK_HZ`: 100ms.  Crystals
+/// typically start within a few milliseconds, and the PLL locks in well under
+/// one.
+pub const STARTUP_TIMEOUT_CYCLES : u32 = BOOT_CLOCK_HZ / 10;
+
+/// Clock speeds in the degraded profile that `configure_clocks` falls back to
+/// on failure: the 16MHz HSI drives the system clock and all buses directly,
+/// and the PLL (and thus the 48MHz clock) is off.
+pub const DEGRADED_SPEEDS : ClockSpeeds = ClockSpeeds {
+    cpu: BOOT_CLOCK_HZ as f32,
+    ahb: BOOT_CLOCK_HZ as f32,
+    apb1: BOOT_CLOCK_HZ as f32,
+    apb2: BOOT_CLOCK_HZ as f32,
+    pll48: 0.,
+};
+
+/// Reasons `configure_clocks` can fail.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum ClockError {
+    /// The HSE crystal oscillator never reported ready.
+    HseTimeout = 1,
+    /// The PLL never reported lock.
+    PllTimeout = 2,
+}
+
 /// RCC driver.
 pub struct Rcc;
 
+/// Steps of the `configure_clocks` sequence.  Recorded in `RccState` so that a
+/// system stuck waiting on an oscillator can be diagnosed from a debugger, and
+/// so that higher layers can tell whether the clock tree has been set up.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum InitPhase {
+    /// Clocks are as they were at reset (or as the driver last observed them
+    /// with `refresh_state`); the driver has not reconfigured anything.
+    Reset = 0,
+    /// Waiting for the HSI to be ready and selected as the system clock.
+    SwitchingToHsi = 1,
+    /// Stopping the PLL and adjusting bus prescalers and Flash wait states.
+    StoppingPll = 2,
+    /// Waiting for the HSE crystal oscillator to start.
+    WaitingForHse = 3,
+    /// Waiting for the PLL to lock.
+    WaitingForPll = 4,
+    /// Waiting for the switch to the PLL as system clock.
+    SwitchingToPll = 5,
+    /// `configure_clocks` has completed.
+    Configured = 6,
+    /// `configure_clocks` failed, and the clocks are in the degraded profile
+    /// described by `DEGRADED_SPEEDS`.  `RccState::failure` says why.
+    Degraded = 7,
+}
+
+/// The RCC driver's record of the clock tree, maintained by the driver's
+/// methods so that it can be consulted without decoding registers.
+///
+/// This reflects only changes made through the `Rcc` driver.  Code that pokes
+/// the registers directly (e.g. through `update_cr`) should call
+/// `Rcc::refresh_state` afterwards.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct RccState {
+    /// The internal 16MHz oscillator is on and ready.
+    pub hsi_ready: bool,
+    /// The external crystal oscillator is on and ready.
+    pub hse_ready: bool,
+    /// The main PLL is locked.
+    pub pll_locked: bool,
+    /// Input selected for the main PLL.
+    pub pll_source: PllSource,
+    /// Active system clock source.
+    pub sysclk: ClockSwitch,
+    /// Progress through `configure_clocks`.
+    pub phase: InitPhase,
+    /// Cause of the most recent `configure_clocks` failure, if any.  This
+    /// survives later successful configuration, for diagnostic purposes.
+    pub failure: Option<ClockError>,
+}
+
+/// State of the RCC after reset.
+pub const RESET_STATE : RccState = RccState {
+    hsi_ready: true,
+    hse_ready: false,
+    pll_locked: false,
+    pll_source: PllSource::Hsi,
+    sysclk: ClockSwitch::Hsi,
+    phase: InitPhase::Reset,
+    failure: None,
+};
+
+impl RccState {
+    /// Checks whether the 48MHz clock used by USB OTG FS, SDIO, and the RNG
+    /// is running.  Whether it's actually *at* 48MHz depends on the PLL
+    /// configuration; see `ClockSpeeds::pll48`.
+    pub fn pll48_running(&self) -> bool {
+        self.pll_locked
+    }
+
+    /// Checks whether the system clock is derived from the HSE crystal, either
+    /// directly or through the PLL.
+    pub fn is_crystal_timed(&self) -> bool {
+        match self.sysclk {
+            ClockSwitch::Hse => true,
+            ClockSwitch::Pll => self.pll_source == PllSource::Hse,
+            ClockSwitch::Hsi => false,
+        }
+    }
+
+    fn pack(&self) -> usize {
+        (self.hsi_ready as usize)
+            | ((self.hse_ready as usize) << 1)
+            | ((self.pll_locked as usize) << 2)
+            | ((self.pll_source as usize) << 3)
+            | ((self.sysclk as usize) << 4)
+            | ((self.phase as usize) << 8)
+            | (self.failure.map(|e| e as usize).unwrap_or(0) << 12)
+    }
+
+    fn unpack(bits: usize) -> RccState {
+        RccState {
+            hsi_ready: bits & 1 != 0,
+            hse_ready: bits & (1 << 1) != 0,
+            pll_locked: bits & (1 << 2) != 0,
+            pll_source: if bits & (1 << 3) != 0 {
+                PllSource::Hse
+            } else {
+                PllSource::Hsi
+            },
+            sysclk: match (bits >> 4) & 0b11 {
+                0b01 => ClockSwitch::Hse,
+                0b10 => ClockSwitch::Pll,
+                _ => ClockSwitch::Hsi,
+            },
+            phase: match (bits >> 8) & 0xF {
+                1 => InitPhase::SwitchingToHsi,
+                2 => InitPhase::StoppingPll,
+                3 => InitPhase::WaitingForHse,
+                4 => InitPhase::WaitingForPll,
+                5 => InitPhase::SwitchingToPll,
+                6 => InitPhase::Configured,
+                7 => InitPhase::Degraded,
+                _ => InitPhase::Reset,
+            },
+            failure: match (bits >> 12) & 0b11 {
+                1 => Some(ClockError::HseTimeout),
+                2 => Some(ClockError::PllTimeout),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Packed `RccState`, shared by all users of the `Rcc` driver.  (The driver is
+/// a unit struct, so the state can't live inside it.)
+/// Initialized to `RESET_STATE`, packed: HSI ready, everything else zero.
+static STATE : AtomicUsize = AtomicUsize::new(1);
+
 /// A clock configuration when using the High Speed External (HSE) crystal
 /// oscillator and internal PLL.
 pub struct ClockConfig {
@@ -57,6 +216,7 @@ pub struct ClockConfig {
 /// Packages up the various internal clock speeds, which can be computed from a
 /// `ClockConfig`.  (We compute them all at once because they're
 /// interdependent.)
+#[derive(Copy, Clone)]
 pub struct ClockSpeeds {
     pub cpu: f32,
     pub ahb: f32,
@@ -69,6 +229,14 @@ impl ClockSpeeds {
     pub fn get_clock_for<P: PeripheralName>(&self, p: P) -> f32 {
         p.get_clock(self)
     }
+
+    /// Gets the kernel clock for timer `p`.  Timers don't simply use their
+    /// APB clock: when the APB prescaler is anything other than 1, the RCC
+    /// feeds the timers at twice the APB frequency (RM0090 section 6.2).
+    pub fn get_timer_clock_for(&self, p: ApbPeripheral) -> f32 {
+        let apb = self.get_clock_for(p);
+        if apb < self.ahb { apb * 2. } else { apb }
+    }
 }
 
 impl ClockConfig {
@@ -99,21 +267,28 @@ pub trait PeripheralName {
     /// Check the STM32F4 Reference Manual.
     fn enable_clock(self, rcc: &Rcc);
 
+    /// Alters the RCC to pulse the named peripheral's reset, returning its
+    /// registers to their reset values.
+    ///
+    /// # Panics
+    ///
+    /// If the named peripheral has no reset control in the RCC's `AxBxRSTR`
+    /// registers.
+    fn reset(self, rcc: &Rcc);
+
     /// Gets the clock speed for this peripheral, given the current speeds.
     fn get_clock(self, speeds: &ClockSpeeds) -> f32;
 }
 
 impl Rcc {
     fn reg(&self) -> &raw::Registers {
-        unsafe {
-            &*(raw::RCC_ADDRESS as *const raw::Registers)
-        }
+        unsafe { mmio(raw::RCC_ADDRESS) }
     }
 
     /// Enables clock to peripheral `p` if that clock can be controlled.
     ///
-    /// The implementation uses barriers to ensure that the clock is enabled
-    /// before return.  This works around ST's erratum 2.1.13.
+    /// The implementation ensures that the clock is enabled before return,
+    /// working around ST's erratum 2.1.13 (see `errata::rcc_enable_delay`).
     ///
     /// # Panics
     ///
@@ -124,7 +299,46 @@ impl Rcc {
         // re-dispatch for bus-specific behavior
         p.enable_clock(self);
         // ensure the write took effect.
-        arm_m::data_synchronization_barrier();
+        errata::rcc_enable_delay();
+    }
+
+    /// Resets a peripheral, returning its registers (and any data buffered
+    /// in it) to their reset state.  Its clock is left alone.
+    ///
+    /// # Panics
+    ///
+    /// If `p` has no reset control.  Check the STM32F4 Reference Manual.
+    pub fn reset<P: PeripheralName>(&self, p: P) {
+        p.reset(self)
+    }
+
+    /// Returns the driver's record of the clock tree.
+    pub fn state(&self) -> RccState {
+        RccState::unpack(STATE.load(Ordering::Acquire))
+    }
+
+    fn update_state<F: FnOnce(RccState) -> RccState>(&self, f: F) {
+        // Only the thread running `configure_clocks` or `refresh_state`
+        // writes the state, so a plain read-modify-write suffices.
+        let new = f(self.state());
+        STATE.store(new.pack(), Ordering::Release)
+    }
+
+    /// Re-derives the driver's record of the clock tree from the hardware,
+    /// e.g. after a bootloader has changed the clocks or after direct register
+    /// manipulation.  The `phase` is left unchanged.
+    pub fn refresh_state(&self) {
+        let cr = self.read_cr();
+        let cfgr = self.read_cfgr();
+        let pllcfgr = self.read_pllcfgr();
+        self.update_state(|s| RccState {
+            hsi_ready: cr.get_hsirdy(),
+            hse_ready: cr.get_hserdy(),
+            pll_locked: cr.get_pllrdy(),
+            pll_source: pllcfgr.get_pllsrc(),
+            sysclk: cfgr.get_sws().unwrap_or(ClockSwitch::Hsi),
+            .. s
+        })
     }
 
     pub fn read_cr(&self) -> Cr {
@@ -163,6 +377,70 @@ impl Rcc {
         self.write_pllcfgr(f(self.read_pllcfgr()))
     }
 
+    pub fn read_plli2scfgr(&self) -> Plli2scfgr {
+        Plli2scfgr(self.reg().plli2scfgr.get())
+    }
+
+    pub fn write_plli2scfgr(&self, v: Plli2scfgr) {
+        self.reg().plli2scfgr.set(v.0)
+    }
+
+    pub fn update_plli2scfgr<F>(&self, f: F)
+        where F: FnOnce(Plli2scfgr) -> Plli2scfgr {
+        self.write_plli2scfgr(f(self.read_plli2scfgr()))
+    }
+
+    pub fn read_bdcr(&self) -> Bdcr {
+        Bdcr(self.reg().bdcr.get())
+    }
+
+    pub fn write_bdcr(&self, v: Bdcr) {
+        self.reg().bdcr.set(v.0)
+    }
+
+    /// Alters the BDCR.  Like the rest of the backup domain, it ignores
+    /// writes unless backup access is enabled (`Pwr::set_backup_access`).
+    pub fn update_bdcr<F: FnOnce(Bdcr) -> Bdcr>(&self, f: F) {
+        self.write_bdcr(f(self.read_bdcr()))
+    }
+
+    /// Starts the 32.768 kHz LSE oscillator -- or, with `bypass`, accepts
+    /// an external clock on OSC32_IN -- and waits for it.  Crystals can take
+    /// a couple of seconds.  Requires backup access.
+    pub fn start_lse(&self, bypass: bool) {
+        if self.read_bdcr().get_lserdy() {
+            return
+        }
+        self.update_bdcr(|v| v.with_lsebyp(bypass));
+        self.update_bdcr(|v| v.with_lseon(true));
+        while !self.read_bdcr().get_lserdy() {}
+    }
+
+    /// Starts the PLLI2S, which feeds the I2S peripherals, multiplying the
+    /// main PLL's input clock (after its `pllm` prescaler) by `n` and
+    /// dividing by `r`.  The I2S clock source is switched to the PLLI2S.
+    ///
+    /// On the Discovery board, with 1 MHz into the PLLs, no one setting
+    /// suits both audio families.  With the master clock output on,
+    /// `n = 258, r = 3` gives 86 MHz for 48 kHz, and `n = 271, r = 2` gives
+    /// 135.5 MHz for 44.1 kHz, each within 0.02%.
+    pub fn configure_plli2s(&self, n: u32, r: u32) -> Result<(), ClockError> {
+        DWT.enable_cycle_counter();
+        self.update_cr(|v| v.with_plli2son(false));
+        while self.read_cr().get_plli2srdy() {}
+        self.update_plli2scfgr(|v| v.with_plli2sn(n).with_plli2sr(r));
+        self.update_cfgr(|v| v.with_i2ssrc(raw::I2sSrc::Plli2s));
+        self.update_cr(|v| v.with_plli2son(true));
+        // The timeout is in cycles at the boot clock; the CPU may well be
+        // running faster, which only shortens it.  The PLL locks in well
+        // under a millisecond either way.
+        if self.wait_ready(|cr| cr.get_plli2srdy()) {
+            Ok(())
+        } else {
+            Err(ClockError::PllTimeout)
+        }
+    }
+
     /// Reconfigures the RCC to the given `ClockConfig`.
     ///
     /// This is done via a two-step process, where we first switch to the 16MHz
@@ -172,18 +450,38 @@ impl Rcc {
     /// switching algorithm could likely perform better.
     ///
     /// Note that this method also reconfigures the number of Flash wait states.
-    pub fn configure_clocks(&self, cfg: &ClockConfig) {
+    ///
+    /// This method never hangs.  If the HSE fails to start, or the PLL fails
+    /// to lock, within `STARTUP_TIMEOUT_CYCLES`, it leaves the system in a
+    /// degraded profile (see `DEGRADED_SPEEDS`), records the failure in
+    /// `state()`, and returns an error.  The Flash wait states are left as
+    /// configured by `cfg`, which is more than the HSI requires.
+    pub fn configure_clocks(&self, cfg: &ClockConfig)
+        -> Result<(), ClockError> {
+        DWT.enable_cycle_counter();
+
         // Switch to the internal 16MHz oscillator while messing with the PLL.
         // First, ensure the HSI is enabled.
+        self.update_state(|s| RccState {
+            phase: InitPhase::SwitchingToHsi,
+            .. s
+        });
         self.update_cr(|v| v.with_hsion(true));
         while !self.read_cr().get_hsirdy() {}
         // Do the switch.
-        self.update_cfgr(|v| v.with_sw(raw::ClockSwitch::Hsi));
-        while self.read_cfgr().get_sws() != Ok(raw::ClockSwitch::Hsi) {}
+        self.update_cfgr(|v| v.with_sw(ClockSwitch::Hsi));
+        while self.read_cfgr().get_sws() != Ok(ClockSwitch::Hsi) {}
+        self.update_state(|s| RccState {
+            hsi_ready: true,
+            sysclk: ClockSwitch::Hsi,
+            phase: InitPhase::StoppingPll,
+            .. s
+        });
 
         // Turn off the PLL so we can reconfigure it safely.
         self.update_cr(|v| v.with_pllon(false));
         while self.read_cr().get_pllrdy() {}
+        self.update_state(|s| RccState { pll_locked: false, .. s });
 
         // Apply divisors to both buses and Flash before increasing clock
         // frequency.  (Doing it in the other order may temporarily drive things
@@ -196,23 +494,80 @@ impl Rcc {
         FLASH.update_acr(|v| v.with_latency(cfg.flash_latency));
 
         // Switch on the external crystal oscillator.
+        self.update_state(|s| RccState {
+            phase: InitPhase::WaitingForHse,
+            .. s
+        });
         self.update_cr(|v| v.with_hseon(true));
-        while !self.read_cr().get_hserdy() {}
+        if !self.wait_ready(|cr| cr.get_hserdy()) {
+            return Err(self.degrade(ClockError::HseTimeout))
+        }
+        self.update_state(|s| RccState { hse_ready: true, .. s });
 
         // Configure the PLL.
         self.update_pllcfgr(|v| v.with_pllm(cfg.crystal_divisor)
                             .with_plln(cfg.vco_multiplier)
                             .with_pllp(cfg.general_divisor)
                             .with_pllq(cfg.pll48_divisor)
-                            .with_pllsrc(raw::PllSource::Hse));
+                            .with_pllsrc(PllSource::Hse));
 
         // Turn on the PLL.
+        self.update_state(|s| RccState {
+            pll_source: PllSource::Hse,
+            phase: InitPhase::WaitingForPll,
+            .. s
+        });
         self.update_cr(|v| v.with_pllon(true));
-        while !self.read_cr().get_pllrdy() {}
+        if !self.wait_ready(|cr| cr.get_pllrdy()) {
+            return Err(self.degrade(ClockError::PllTimeout))
+        }
+        self.update_state(|s| RccState {
+            pll_locked: true,
+            phase: InitPhase::SwitchingToPll,
+            .. s
+        });
 
         // Select the PLL as our clock source.
-        self.update_cfgr(|v| v.with_sw(raw::ClockSwitch::Pll));
-        while self.read_cfgr().get_sws() != Ok(raw::ClockSwitch::Pll) {}
+        self.update_cfgr(|v| v.with_sw(ClockSwitch::Pll));
+        while self.read_cfgr().get_sws() != Ok(ClockSwitch::Pll) {}
+        self.update_state(|s| RccState {
+            sysclk: ClockSwitch::Pll,
+            phase: InitPhase::Configured,
+            .. s
+        });
+        Ok(())
+    }
+
+    /// Waits up to `STARTUP_TIMEOUT_CYCLES` for `ready` to hold.  Must be
+    /// called while running from the HSI.
+    fn wait_ready<F: Fn(Cr) -> bool>(&self, ready: F) -> bool {
+        let start = DWT.read_cycle_count();
+        while !ready(self.read_cr()) {
+            let elapsed = DWT.read_cycle_count().wrapping_sub(start);
+            if elapsed > STARTUP_TIMEOUT_CYCLES {
+                return false
+            }
+        }
+        true
+    }
+
+    /// Puts the clocks into the degraded profile after a failure during
+    /// `configure_clocks`, which has already switched to the HSI.  Returns
+    /// `err` for convenience.
+    fn degrade(&self, err: ClockError) -> ClockError {
+        self.update_cr(|v| v.with_pllon(false).with_hseon(false));
+        self.update_cfgr(|v| v.with_hpre(None)
+                         .with_ppre1(None)
+                         .with_ppre2(None));
+        let cr = self.read_cr();
+        self.update_state(|s| RccState {
+            hse_ready: cr.get_hserdy(),
+            pll_locked: cr.get_pllrdy(),
+            phase: InitPhase::Degraded,
+            failure: Some(err),
+            .. s
+        });
+        err
     }
 }
 
@@ -346,6 +701,18 @@ impl PeripheralName for AhbPeripheral {
             .atomic_or(1 << self.get_bit_index())
     }
 
+    fn reset(self, rcc: &Rcc) {
+        if !self.has_rst() {
+            panic!("cannot reset AHB{} idx {}",
+                   (self.get_bus() as u32) + 1,
+                   self.get_bit_index())
+        }
+
+        let rstr = &rcc.reg().ahb_rstr[self.get_bus() as usize];
+        rstr.atomic_or(1 << self.get_bit_index());
+        rstr.atomic_nand(1 << self.get_bit_index())
+    }
+
     fn get_clock(self, speeds: &ClockSpeeds) -> f32 {
         speeds.ahb
     }
@@ -442,6 +809,18 @@ impl PeripheralName for ApbPeripheral {
             .apb_enr[self.get_bus() as usize]
             .atomic_or(1 << self.get_bit_index())
     }
+
+    fn reset(self, rcc: &Rcc) {
+        if !self.has_rst() {
+            panic!("cannot reset APB{} idx {}",
+                   (self.get_bus() as u32) + 1,
+                   self.get_bit_index())
+        }
+
+        let rstr = &rcc.reg().apb_rstr[self.get_bus() as usize];
+        rstr.atomic_or(1 << self.get_bit_index());
+        rstr.atomic_nand(1 << self.get_bit_index())
+    }
     fn get_clock(self, speeds: &ClockSpeeds) -> f32 {
         match self.get_bus() {
             ApbBus::Apb1 => speeds.apb1,
@@ -452,3 +831,106 @@ impl PeripheralName for ApbPeripheral {
 
 /// Shared instance of the `Rcc` driver.
 pub static RCC: Rcc = Rcc;
+
+#[cfg(all(test, feature = "host-test"))]
+mod tests {
+    use arm_m::sim::{self, AccessKind};
+    use super::*;
+
+    const RCC_CR : usize = raw::RCC_ADDRESS;
+    const RCC_PLLCFGR : usize = raw::RCC_ADDRESS + 0x04;
+    const RCC_CFGR : usize = raw::RCC_ADDRESS + 0x08;
+    const FLASH_ACR : usize = 0x40023C00;
+
+    const CLOCKS : ClockConfig = ClockConfig {
+        crystal_hz: 8_000_000.,
+        crystal_divisor: 4,
+        vco_multiplier: 168,
+        general_divisor: SysPrescaler::Div2,
+        pll48_divisor: 7,
+        ahb_divisor: None,
+        apb1_divisor: Some(ApbPrescaler::Div4),
+        apb2_divisor: Some(ApbPrescaler::Div2),
+        flash_latency: 5,
+    };
+
+    /// Makes each oscillator and the PLL report ready as soon as it's
+    /// switched on, as (healthy) hardware would shortly afterwards.
+    fn cr_hook(_: usize, v: u32) -> u32 {
+        let on = Cr(v);
+        on.with_hsirdy(on.get_hsion())
+            .with_hserdy(on.get_hseon())
+            .with_pllrdy(on.get_pllon())
+            .0
+    }
+
+    /// Makes the switch status follow the switch.
+    fn cfgr_hook(_: usize, v: u32) -> u32 {
+        (v & !0b1100) | ((v & 0b11) << 2)
+    }
+
+    #[test]
+    fn compute_speeds() {
+        let s = CLOCKS.compute_speeds();
+        assert_eq!(s.cpu, 168_000_000.);
+        assert_eq!(s.ahb, 168_000_000.);
+        assert_eq!(s.apb1, 42_000_000.);
+        assert_eq!(s.apb2, 84_000_000.);
+        assert_eq!(s.pll48, 48_000_000.);
+    }
+
+    #[test]
+    fn register_fields() {
+        // The bitfield accessors against the RM0090 bit positions.
+        assert_eq!(Cr(0).with_pllon(true).0, 1 << 24);
+        assert_eq!(Cr(0).with_hserdy(true).0, 1 << 17);
+        assert_eq!(Pllcfgr(0).with_plln(0x1FF).0, 0x1FF << 6);
+        assert_eq!(Pllcfgr(0).with_pllq(0xF).0, 0xF << 24);
+        assert_eq!(Pllcfgr(!0).with_pllm(0).0, !0x3F);
+        assert!(Cfgr(0b1000).get_sws() == Ok(ClockSwitch::Pll));
+        // SWS = 0b11 is reserved.
+        assert!(Cfgr(0b1100).get_sws().is_err());
+    }
+
+    #[test]
+    fn configure_clocks_sequence() {
+        sim::reset();
+        sim::set_write_hook(RCC_CR, cr_hook);
+        sim::set_write_hook(RCC_CFGR, cfgr_hook);
+
+        assert!(RCC.configure_clocks(&CLOCKS).is_ok());
+
+        let pll = Pllcfgr(sim::peek(RCC_PLLCFGR));
+        assert_eq!(pll.get_pllm(), 4);
+        assert_eq!(pll.get_plln(), 168);
+        assert!(pll.get_pllp() == SysPrescaler::Div2);
+        assert_eq!(pll.get_pllq(), 7);
+        assert!(pll.get_pllsrc() == PllSource::Hse);
+
+        let cfgr = Cfgr(sim::peek(RCC_CFGR));
+        assert!(cfgr.get_sws() == Ok(ClockSwitch::Pll));
+        let cr = Cr(sim::peek(RCC_CR));
+        assert!(cr.get_hseon() && cr.get_pllon());
+        assert_eq!(sim::peek(FLASH_ACR) & 7, 5);
+
+        let state = RCC.state();
+        assert!(state.phase == InitPhase::Configured);
+        assert!(state.sysclk == ClockSwitch::Pll);
+        assert!(state.pll_locked);
+
+        // The Flash wait states must go up, and the PLL be configured,
+        // before the PLL is switched on.
+        let writes: Vec<_> = sim::take_log().into_iter()
+            .filter(|a| a.kind == AccessKind::Write)
+            .collect();
+        let position = |f: &Fn(&sim::Access) -> bool| {
+            writes.iter().position(|a| f(a)).unwrap()
+        };
+        let acr = position(&|a| a.address == FLASH_ACR);
+        let pllcfgr = position(&|a| a.address == RCC_PLLCFGR);
+        let pllon = position(&|a| a.address == RCC_CR
+                             && Cr(a.value as u32).get_pllon());
+        assert!(acr < pllon);
+        assert!(pllcfgr < pllon);
+    }
+}
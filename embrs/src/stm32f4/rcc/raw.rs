@@ -1,6 +1,8 @@
 //! Reset and Clock Control (RCC) raw register interface.
 
-use arm_m::reg::Reg;
+use arm_m::reg::{Reg, ReservedReg};
+
+pub use stm32::rcc::{AhbPrescaler, ApbPrescaler, ClockDivisor, ClockSwitch};
 
 /// The RCC's hardware register layout.
 #[repr(C, packed)]
@@ -13,39 +15,39 @@ pub struct Registers {
     ///
     /// Note that they are numbered from zero in this array.
     pub ahb_rstr:      [Reg<u32>; 3],
-    pub _reserved_1c:  Reg<u32>,
+    pub _reserved_1c:  ReservedReg,
     /// APB peripheral reset registers APB1RSTR - APB2RSTR.
     ///
     /// Note that they are numbered from zero in this array.
     pub apb_rstr:      [Reg<u32>; 2],
-    pub _reserved_28:  Reg<u32>,
-    pub _reserved_2c:  Reg<u32>,
+    pub _reserved_28:  ReservedReg,
+    pub _reserved_2c:  ReservedReg,
     /// AHB clock enable registers AHB1ENR - AHB3ENR.
     ///
     /// Note that they are numbered from zero in this array.
     pub ahb_enr:       [Reg<u32>; 3],
-    pub _reserved_3c:  Reg<u32>,
+    pub _reserved_3c:  ReservedReg,
     /// APB clock enable registers APB1ENR - APB2ENR.
     ///
     /// Note that they are numbered from zero in this array.
     pub apb_enr:       [Reg<u32>; 2],
-    pub _reserved_48:  Reg<u32>,
-    pub _reserved_4c:  Reg<u32>,
+    pub _reserved_48:  ReservedReg,
+    pub _reserved_4c:  ReservedReg,
     /// AHB low power clock enable registers AHB1LPENR - AHB3LPENR.
     ///
     /// Note that they are numbered from zero in this array.
     pub ahb_lpenr:     [Reg<u32>; 3],
-    pub _reserved_5c:  Reg<u32>,
+    pub _reserved_5c:  ReservedReg,
     /// APB low power clock enable registers APB1LPENR - APB2LPENR.
     ///
     /// Note that they are numbered from zero in this array.
     pub apb_lpenr:     [Reg<u32>; 2],
-    pub _reserved_68:  Reg<u32>,
-    pub _reserved_6c:  Reg<u32>,
+    pub _reserved_68:  ReservedReg,
+    pub _reserved_6c:  ReservedReg,
     pub bdcr:          Reg<u32>,
     pub csr:           Reg<u32>,
-    pub _reserved_78:  Reg<u32>,
-    pub _reserved_7c:  Reg<u32>,
+    pub _reserved_78:  ReservedReg,
+    pub _reserved_7c:  ReservedReg,
     pub sscgr:         Reg<u32>,
     pub plli2scfgr:    Reg<u32>,
     #[cfg(feature = "soc_family:stm32f4[23]")]
@@ -54,6 +56,30 @@ pub struct Registers {
     pub dckcfgr:       Reg<u32>,
 }
 
+#[cfg(not(feature = "soc_family:stm32f4[23]"))]
+const REGISTERS_SIZE : usize = 0x88;
+#[cfg(feature = "soc_family:stm32f4[23]")]
+const REGISTERS_SIZE : usize = 0x90;
+
+register_layout! {
+    fn check_layout: Registers [REGISTERS_SIZE] {
+        cr @ 0x00,
+        pllcfgr @ 0x04,
+        cfgr @ 0x08,
+        cir @ 0x0C,
+        ahb_rstr @ 0x10,
+        apb_rstr @ 0x20,
+        ahb_enr @ 0x30,
+        apb_enr @ 0x40,
+        ahb_lpenr @ 0x50,
+        apb_lpenr @ 0x60,
+        bdcr @ 0x70,
+        csr @ 0x74,
+        sscgr @ 0x80,
+        plli2scfgr @ 0x84,
+    }
+}
+
 bit_wrappers! {
     /// Wrapper for the Clock Control Register bits.
     pub struct Cr(pub u32);
@@ -61,15 +87,17 @@ bit_wrappers! {
     pub struct Cfgr(pub u32);
     /// Wrapper for the PLL Configuration Register bits.
     pub struct Pllcfgr(pub u32);
+    /// Wrapper for the PLLI2S Configuration Register bits.
+    pub struct Plli2scfgr(pub u32);
+    /// Wrapper for the Backup Domain Control Register bits.
+    pub struct Bdcr(pub u32);
 }
 
 impl Cr {
     bitfield_accessors! {
         /// Ready flag for the PLLI2S.
-        #[cfg(feature = "soc_family:stm32f4[23]")]
         pub total [27] get_plli2srdy / with_plli2srdy: bool,
         /// Turns the PLLI2S on/off.
-        #[cfg(feature = "soc_family:stm32f4[23]")]
         pub total [26] get_plli2son / with_plli2son: bool,
         /// Ready flag for the main PLL.
         pub total [25] get_pllrdy / with_pllrdy: bool,
@@ -95,40 +123,6 @@ impl Cr {
     }
 }
 
-/// Wraps up a pattern we use repeatedly below, where we turn an enable flag and
-/// a prescaler selection into an optional prescaler.
-macro_rules! en_option_accessors {
-    () => {};
-    (
-        $(#[$m:meta])*
-        enable $get_en:ident / $with_en:ident
-        value $get_div:ident / $with_div:ident : $ty:ty
-        as $get_opt:ident / $with_opt:ident;
-
-        $($rest:tt)*
-    ) => {
-        $(#[$m])*
-        pub fn $get_opt(self) -> Option<$ty> {
-            if self.$get_en() {
-                Some(self.$get_div())
-            } else {
-                None
-            }
-        }
-
-        $(#[$m])*
-        pub fn $with_opt(self, v: Option<$ty>) -> Self {
-            if let Some(wrapped) = v {
-                self.$with_div(wrapped).$with_en(true)
-            } else {
-                self.$with_en(false)
-            }
-        }
-
-        en_option_accessors!{$($rest)*}
-    };
-}
-
 impl Cfgr {
     bitfield_accessors! {
         /// Controls the clock output on the MCO2 pin.
@@ -260,69 +254,6 @@ bit_enums! {
     }
 
     // TODO model RTCPRE
-
-    /// Prescaler options for the APB clocks (relative to the AHB clock).
-    pub bit_enum ApbPrescaler {
-        Div2  = 0b00,
-        Div4  = 0b01,
-        Div8  = 0b10,
-        Div16 = 0b11,
-    }
-
-    /// Prescaler options for the AHB clocks (relative to the system clock).
-    pub bit_enum AhbPrescaler {
-        Div2   = 0b000,
-        Div4   = 0b001,
-        Div8   = 0b010,
-        Div16  = 0b011,
-        Div64  = 0b100,
-        Div128 = 0b101,
-        Div256 = 0b110,
-        Div512 = 0b111,
-    }
-
-    /// Clocks that can be used as the system clock source.
-    pub bit_enum ClockSwitch {
-        Hsi = 0b00,
-        Hse = 0b01,
-        Pll = 0b10,
-    }
-}
-
-pub trait ClockDivisor {
-    fn to_divisor(self) -> u32;
-}
-
-impl ClockDivisor for ApbPrescaler {
-    fn to_divisor(self) -> u32 {
-        match self {
-            ApbPrescaler::Div2  =>  2,
-            ApbPrescaler::Div4  =>  4,
-            ApbPrescaler::Div8  =>  8,
-            ApbPrescaler::Div16 => 16,
-        }
-    }
-}
-
-impl<T: ClockDivisor> ClockDivisor for Option<T> {
-    fn to_divisor(self) -> u32 {
-        self.map(|v| v.to_divisor()).unwrap_or(1)
-    }
-}
-
-impl ClockDivisor for AhbPrescaler {
-    fn to_divisor(self) -> u32 {
-        match self {
-            AhbPrescaler::Div2   =>   2,
-            AhbPrescaler::Div4   =>   4,
-            AhbPrescaler::Div8   =>   8,
-            AhbPrescaler::Div16  =>  16,
-            AhbPrescaler::Div64  =>  64,
-            AhbPrescaler::Div128 => 128,
-            AhbPrescaler::Div256 => 256,
-            AhbPrescaler::Div512 => 512,
-        }
-    }
 }
 
 impl Pllcfgr {
@@ -352,6 +283,51 @@ impl Pllcfgr {
     }
 }
 
+impl Plli2scfgr {
+    bitfield_accessors! {
+        /// Prescaler for the I2S clock.
+        ///
+        /// Derives the I2S clock from the PLLI2S VCO frequency; valid values
+        /// are 2 through 7.
+        pub total [30:28] get_plli2sr / with_plli2sr: u32,
+        /// Multiplication factor for the PLLI2S VCO.
+        ///
+        /// The PLLI2S shares the main PLL's input (including the `pllm`
+        /// prescaler); valid values are 50 through 432.
+        pub total [14: 6] get_plli2sn / with_plli2sn: u32,
+    }
+}
+
+impl Bdcr {
+    bitfield_accessors! {
+        /// Resets the whole backup domain, including the RTC.
+        pub total [16] get_bdrst / with_bdrst: bool,
+        /// Turns the RTC clock on/off.
+        pub total [15] get_rtcen / with_rtcen: bool,
+        /// Selects the RTC clock.  Once chosen, this can only be changed by
+        /// resetting the backup domain.
+        pub total [ 9: 8] get_rtcsel / with_rtcsel: RtcSel,
+        /// When `true`, bypasses the LSE oscillator, using an external
+        /// clock signal instead.
+        pub total [ 2] get_lsebyp / with_lsebyp: bool,
+        /// Ready flag for the LSE oscillator.
+        pub total [ 1] get_lserdy / with_lserdy: bool,
+        /// Turns the LSE oscillator on/off.
+        pub total [ 0] get_lseon / with_lseon: bool,
+    }
+}
+
+bit_enums! {
+    /// Clocks that can drive the RTC.
+    pub bit_enum RtcSel {
+        NoClock = 0b00,
+        Lse = 0b01,
+        Lsi = 0b10,
+        /// HSE divided by RTCPRE.
+        Hse = 0b11,
+    }
+}
+
 bit_enums! {
     /// Options for the PLL source clock.
     pub bit_enum PllSource {
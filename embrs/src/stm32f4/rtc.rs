@@ -0,0 +1,487 @@
+//! Real-time clock (RTC) support.
+//!
+//! The RTC keeps the date and time in the backup domain, so -- run from the
+//! 32.768 kHz LSE, with a battery on VBAT -- it carries on through resets,
+//! standby, and loss of main power.  This driver keeps it on UTC and speaks
+//! Unix time, in milliseconds since 1970, like `net::sntp`; `RtcServo` there
+//! keeps it in step with an NTP server.
+//!
+//! Before use, enable the `Pwr` clock and call `Rtc::start`:
+//!
+//!     RCC.enable_clock(Pwr);
+//!     RTC.start();
+//!     if let Some(ms) = RTC.now_unix_ms() {
+//!         // ...
+//!     }
+//!
+//! The calendar covers 2001 through 2099.  (The hardware reports a year of
+//! 2000 as the calendar not having been set.)
+
+use arm_m::reg::{mmio, Reg, ReservedReg, RoReg, WoReg};
+use super::pwr::PWR;
+use super::rcc::{RtcSel, RCC};
+
+#[repr(C, packed)]
+struct Registers {
+    tr:       Reg<u32>,
+    dr:       Reg<u32>,
+    // The wakeup timer, alarms, timestamps and tamper detection aren't
+    // supported yet; the calendar stays in its reset 24-hour format.
+    #[allow(dead_code)]
+    cr:       Reg<u32>,
+    isr:      Reg<u32>,
+    prer:     Reg<u32>,
+    #[allow(dead_code)]
+    wutr:     Reg<u32>,
+    #[allow(dead_code)]
+    calibr:   Reg<u32>,
+    #[allow(dead_code)]
+    alrmar:   Reg<u32>,
+    #[allow(dead_code)]
+    alrmbr:   Reg<u32>,
+    wpr:      WoReg<u32>,
+    ssr:      RoReg<u32>,
+    shiftr:   WoReg<u32>,
+    #[allow(dead_code)]
+    tstr:     RoReg<u32>,
+    #[allow(dead_code)]
+    tsdr:     RoReg<u32>,
+    #[allow(dead_code)]
+    tsssr:    RoReg<u32>,
+    calr:     Reg<u32>,
+    #[allow(dead_code)]
+    tafcr:    Reg<u32>,
+    #[allow(dead_code)]
+    alrmassr: Reg<u32>,
+    #[allow(dead_code)]
+    alrmbssr: Reg<u32>,
+    _reserved_4c: ReservedReg,
+    /// Backup registers BKP0R - BKP19R.
+    #[allow(dead_code)]
+    bkpr:     [Reg<u32>; 20],
+}
+
+register_layout! {
+    fn check_layout: Registers [0xA0] {
+        tr @ 0x00,
+        dr @ 0x04,
+        cr @ 0x08,
+        isr @ 0x0C,
+        prer @ 0x10,
+        wutr @ 0x14,
+        calibr @ 0x18,
+        alrmar @ 0x1C,
+        alrmbr @ 0x20,
+        wpr @ 0x24,
+        ssr @ 0x28,
+        shiftr @ 0x2C,
+        tstr @ 0x30,
+        tsdr @ 0x34,
+        tsssr @ 0x38,
+        calr @ 0x3C,
+        tafcr @ 0x40,
+        alrmassr @ 0x44,
+        alrmbssr @ 0x48,
+        bkpr @ 0x50,
+    }
+}
+
+const RTC_ADDRESS : usize = 0x40002800;
+
+/// Keys written to `WPR`, in order, to unlock the registers for writing.
+const KEY_UNLOCK : [u32; 2] = [0xCA, 0x53];
+/// Any other value locks them again.
+const KEY_LOCK : u32 = 0xFF;
+
+/// Prescalers dividing the LSE down to 1 Hz: the asynchronous one as large
+/// as possible to save power, and the synchronous one setting the
+/// resolution of the subsecond counter, 1/256 s.
+const PREDIV_A : u32 = 127;
+const PREDIV_S : u32 = 255;
+
+/// Unix time of 2001-01-01, the start of the calendar's range.
+const FIRST_UNIX_S : u64 = 978_307_200;
+/// Unix time of 2100-01-01, just past its end.
+const END_UNIX_S : u64 = 4_102_444_800;
+
+/// Largest rate correction, in pulses of the 32.768 kHz clock added (or,
+/// if negative, masked) per 2^20.
+const MAX_CAL_PULSES : i64 = 512;
+const MIN_CAL_PULSES : i64 = -511;
+
+bit_wrappers! {
+    /// Wrapper for the Time Register bits.
+    pub struct Tr(pub u32);
+    /// Wrapper for the Date Register bits.
+    pub struct Dr(pub u32);
+    /// Wrapper for the Initialization and Status Register bits.
+    pub struct Isr(pub u32);
+    /// Wrapper for the Prescaler Register bits.
+    pub struct Prer(pub u32);
+    /// Wrapper for the Shift Control Register bits.
+    pub struct Shiftr(pub u32);
+    /// Wrapper for the Calibration Register bits.
+    pub struct Calr(pub u32);
+}
+
+impl Tr {
+    bitfield_accessors! {
+        /// Set for PM, in 12-hour format.
+        pub total [22] get_pm / with_pm: bool,
+        /// Hours, in BCD.
+        pub total [21:16] get_hours / with_hours: u32,
+        /// Minutes, in BCD.
+        pub total [14: 8] get_minutes / with_minutes: u32,
+        /// Seconds, in BCD.
+        pub total [ 6: 0] get_seconds / with_seconds: u32,
+    }
+}
+
+impl Dr {
+    bitfield_accessors! {
+        /// Year within the century, in BCD.
+        pub total [23:16] get_year / with_year: u32,
+        /// Day of the week, 1 (Monday) to 7.
+        pub total [15:13] get_weekday / with_weekday: u32,
+        /// Month, 1 to 12, in BCD.
+        pub total [12: 8] get_month / with_month: u32,
+        /// Day of the month, in BCD.
+        pub total [ 5: 0] get_day / with_day: u32,
+    }
+}
+
+impl Isr {
+    bitfield_accessors! {
+        /// Set while a calibration update is pending.
+        pub total [16] get_recalpf / with_recalpf: bool,
+        /// Requests initialization mode, which stops the calendar so that
+        /// it can be set.
+        pub total [ 7] get_init / with_init: bool,
+        /// Set once initialization mode has been entered.
+        pub total [ 6] get_initf / with_initf: bool,
+        /// Set when the shadow registers are in step with the calendar.
+        pub total [ 5] get_rsf / with_rsf: bool,
+        /// Set once the calendar has been set (to a year other than 2000).
+        pub total [ 4] get_inits / with_inits: bool,
+        /// Set while a shift operation is pending.
+        pub total [ 3] get_shpf / with_shpf: bool,
+    }
+}
+
+impl Prer {
+    bitfield_accessors! {
+        /// Asynchronous prescaler factor, less one.
+        pub total [22:16] get_prediv_a / with_prediv_a: u32,
+        /// Synchronous prescaler factor, less one.
+        pub total [14: 0] get_prediv_s / with_prediv_s: u32,
+    }
+}
+
+impl Shiftr {
+    bitfield_accessors! {
+        /// Adds a second to the clock.
+        pub total [31] get_add1s / with_add1s: bool,
+        /// Subtracts this many subsecond counts from the clock.
+        pub total [14: 0] get_subfs / with_subfs: u32,
+    }
+}
+
+impl Calr {
+    bitfield_accessors! {
+        /// Adds 512 pulses per 2^20, speeding the clock by 488.5 ppm.
+        pub total [15] get_calp / with_calp: bool,
+        /// Masks this many pulses per 2^20, slowing the clock by 0.954 ppm
+        /// each.
+        pub total [ 8: 0] get_calm / with_calm: u32,
+    }
+}
+
+/// Error from asking for a time or adjustment outside what the RTC can
+/// represent.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct OutOfRange;
+
+/// RTC driver.
+pub struct Rtc;
+
+impl Rtc {
+    fn reg(&self) -> &'static Registers {
+        unsafe { mmio(RTC_ADDRESS) }
+    }
+
+    pub fn read_isr(&self) -> Isr {
+        Isr(self.reg().isr.get())
+    }
+
+    /// Writes the ISR.  Its flags are cleared by writing zero and
+    /// unaffected by writing one, so there is no `update_isr`: start from
+    /// `Isr(!0)` and clear the flags meant to be cleared.
+    pub fn write_isr(&self, v: Isr) {
+        self.reg().isr.set(v.0)
+    }
+
+    fn unlock(&self) {
+        for &k in &KEY_UNLOCK {
+            self.reg().wpr.set(k)
+        }
+    }
+
+    fn lock(&self) {
+        self.reg().wpr.set(KEY_LOCK)
+    }
+
+    /// Runs the RTC from the LSE, starting the LSE if needed.  If the RTC
+    /// was already running (say, on VBAT while the rest of the system was
+    /// off), it keeps its time.
+    pub fn start(&self) {
+        PWR.set_backup_access(true);
+        RCC.start_lse(false);
+        if RCC.read_bdcr().get_rtcen() {
+            return
+        }
+        RCC.update_bdcr(|v| v.with_rtcsel(RtcSel::Lse));
+        RCC.update_bdcr(|v| v.with_rtcen(true))
+    }
+
+    /// Checks whether the calendar has been set since the backup domain
+    /// last lost power.
+    pub fn is_set(&self) -> bool {
+        self.read_isr().get_inits()
+    }
+
+    /// Sets the clock to `unix_ms`, milliseconds since 1970.
+    pub fn set_unix_ms(&self, unix_ms: u64) -> Result<(), OutOfRange> {
+        let seconds = unix_ms / 1000;
+        if seconds < FIRST_UNIX_S || seconds >= END_UNIX_S {
+            return Err(OutOfRange)
+        }
+        let (tr, dr) = to_calendar(seconds as u32);
+
+        self.unlock();
+        self.write_isr(Isr(!0).with_init(true));
+        while !self.read_isr().get_initf() {}
+        // The prescalers have to be written separately, synchronous first.
+        let prer = Prer(0).with_prediv_s(PREDIV_S);
+        self.reg().prer.set(prer.0);
+        self.reg().prer.set(prer.with_prediv_a(PREDIV_A).0);
+        self.reg().tr.set(tr.0);
+        self.reg().dr.set(dr.0);
+        // Leaving initialization mode restarts the calendar at the top of
+        // the second.
+        self.write_isr(Isr(!0).with_init(false));
+        self.lock();
+
+        self.shift_ms((unix_ms % 1000) as i32)
+    }
+
+    /// Reads the clock, in milliseconds since 1970, if it has been set.
+    pub fn now_unix_ms(&self) -> Option<u64> {
+        if !self.is_set() {
+            return None
+        }
+        while !self.read_isr().get_rsf() {}
+        // Reading SSR freezes TR and DR until DR is read, so the three
+        // agree.
+        let ss = self.reg().ssr.get() & 0xFFFF;
+        let tr = Tr(self.reg().tr.get());
+        let dr = Dr(self.reg().dr.get());
+        let seconds = from_calendar(tr, dr) as i64;
+        // After a shift, the subsecond count can exceed PREDIV_S, leaving
+        // the clock a little before the second it shows.
+        let sub_ms = (PREDIV_S as i64 - ss as i64) * 1000
+            / (PREDIV_S as i64 + 1);
+        Some((seconds * 1000 + sub_ms) as u64)
+    }
+
+    /// Moves the clock forward (or, if negative, back) by `ms`, which must
+    /// be less than a second, to the nearest 1/256 second.
+    pub fn shift_ms(&self, ms: i32) -> Result<(), OutOfRange> {
+        if ms <= -1000 || ms >= 1000 {
+            return Err(OutOfRange)
+        }
+        let counts = |ms: i32| (ms as u32 * (PREDIV_S + 1) + 500) / 1000;
+        let shift = if ms > 0 {
+            // Add a second and take back the difference.
+            Shiftr(0).with_add1s(true).with_subfs(counts(1000 - ms))
+        } else {
+            Shiftr(0).with_subfs(counts(-ms))
+        };
+        if shift.get_subfs() == 0 && !shift.get_add1s() {
+            return Ok(())
+        }
+        while self.read_isr().get_shpf() {}
+        self.unlock();
+        self.reg().shiftr.set(shift.0);
+        self.lock();
+        Ok(())
+    }
+
+    /// Trims the clock's rate by `ppb` parts per billion (faster if
+    /// positive), within about +488/-487 ppm, in steps of about 0.954
+    /// ppm.  Returns the correction actually applied.
+    pub fn set_rate_ppb(&self, ppb: i32) -> i32 {
+        let pulses = ppb as i64 * (1 << 20) / 1_000_000_000;
+        let pulses = if pulses > MAX_CAL_PULSES {
+            MAX_CAL_PULSES
+        } else if pulses < MIN_CAL_PULSES {
+            MIN_CAL_PULSES
+        } else {
+            pulses
+        };
+        let calr = if pulses > 0 {
+            Calr(0).with_calp(true).with_calm((512 - pulses) as u32)
+        } else {
+            Calr(0).with_calm(-pulses as u32)
+        };
+        while self.read_isr().get_recalpf() {}
+        self.unlock();
+        self.reg().calr.set(calr.0);
+        self.lock();
+        (pulses * 1_000_000_000 / (1 << 20)) as i32
+    }
+}
+
+/// Shared instance of the `Rtc` driver.
+pub static RTC: Rtc = Rtc;
+
+fn bcd(v: u32) -> u32 {
+    (v / 10) << 4 | v % 10
+}
+
+fn from_bcd(v: u32) -> u32 {
+    (v >> 4) * 10 + (v & 0xF)
+}
+
+/// Converts Unix seconds, within the calendar's range, to register values.
+fn to_calendar(seconds: u32) -> (Tr, Dr) {
+    let days = seconds / 86_400;
+    let time = seconds % 86_400;
+    let (year, month, day) = civil_from_days(days);
+    // 1970-01-01 was a Thursday.
+    let weekday = (days + 3) % 7 + 1;
+    let tr = Tr(0)
+        .with_hours(bcd(time / 3600))
+        .with_minutes(bcd(time / 60 % 60))
+        .with_seconds(bcd(time % 60));
+    let dr = Dr(0)
+        .with_year(bcd(year - 2000))
+        .with_weekday(weekday)
+        .with_month(bcd(month))
+        .with_day(bcd(day));
+    (tr, dr)
+}
+
+/// Converts register values, in 24-hour format, to Unix seconds.
+fn from_calendar(tr: Tr, dr: Dr) -> u32 {
+    let days = days_from_civil(from_bcd(dr.get_year()) + 2000,
+                               from_bcd(dr.get_month()),
+                               from_bcd(dr.get_day()));
+    days * 86_400
+        + from_bcd(tr.get_hours()) * 3600
+        + from_bcd(tr.get_minutes()) * 60
+        + from_bcd(tr.get_seconds())
+}
+
+// The date conversions are Howard Hinnant's, restricted to dates after
+// 1970: March-based years put the leap day last, and 400-year eras repeat.
+
+/// Days from 1970-01-01 to the given date.
+fn days_from_civil(year: u32, month: u32, day: u32) -> u32 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// The date (year, month, day) `days` after 1970-01-01.
+fn civil_from_days(days: u32) -> (u32, u32, u32) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(all(test, feature = "host-test"))]
+mod tests {
+    use arm_m::sim;
+    use super::*;
+
+    const RTC_TR : usize = 0x40002800;
+    const RTC_DR : usize = 0x40002804;
+    const RTC_ISR : usize = 0x4000280C;
+    const RTC_SSR : usize = 0x40002828;
+    const RTC_SHIFTR : usize = 0x4000282C;
+
+    /// 2026-10-17 12:34:56 UTC, a Saturday.
+    const SAMPLE_S : u32 = 1_792_240_496;
+
+    /// Acts like the hardware: initialization mode is entered at once, and
+    /// the calendar is set and in sync.
+    fn isr_hook(_: usize, v: u32) -> u32 {
+        let init = v & (1 << 7);
+        init | init >> 1 | 1 << 5 | 1 << 4
+    }
+
+    #[test]
+    fn calendar_round_trips() {
+        let (tr, dr) = to_calendar(SAMPLE_S);
+        assert_eq!(tr.0, 0x12_34_56);
+        assert_eq!(dr.0, 0x26_00_00 | 6 << 13 | 0x10 << 8 | 0x17);
+        assert_eq!(from_calendar(tr, dr), SAMPLE_S);
+
+        // Leap day, end of the range.
+        let s = 3_981_398_399;
+        let (tr, dr) = to_calendar(s);
+        assert_eq!(dr.get_month(), 0x02);
+        assert_eq!(dr.get_day(), 0x29);
+        assert_eq!(dr.get_weekday(), 3);
+        assert_eq!(from_calendar(tr, dr), s);
+
+        let first = FIRST_UNIX_S as u32;
+        let (tr, dr) = to_calendar(first);
+        assert_eq!(dr.0, 0x01_00_00 | 1 << 13 | 0x01 << 8 | 0x01);
+        assert_eq!(from_calendar(tr, dr), first);
+    }
+
+    #[test]
+    fn set_writes_calendar_and_shift() {
+        sim::reset();
+        sim::set_write_hook(RTC_ISR, isr_hook);
+        let ms = SAMPLE_S as u64 * 1000 + 250;
+        assert_eq!(RTC.set_unix_ms(ms), Ok(()));
+        assert_eq!(sim::peek(RTC_TR), 0x12_34_56);
+        assert_eq!(Dr(sim::peek(RTC_DR)).get_day(), 0x17);
+        // A quarter second ahead: add one, take back three quarters.
+        let shift = Shiftr(sim::peek(RTC_SHIFTR));
+        assert!(shift.get_add1s());
+        assert_eq!(shift.get_subfs(), 192);
+
+        assert_eq!(RTC.set_unix_ms(FIRST_UNIX_S * 1000 - 1),
+                   Err(OutOfRange));
+        assert_eq!(RTC.set_unix_ms(END_UNIX_S * 1000), Err(OutOfRange));
+    }
+
+    #[test]
+    fn now_reads_subseconds() {
+        sim::reset();
+        assert_eq!(RTC.now_unix_ms(), None);
+
+        sim::poke(RTC_ISR, 1 << 5 | 1 << 4);
+        sim::poke(RTC_TR, 0x12_34_56);
+        sim::poke(RTC_DR, 0x26_00_00 | 6 << 13 | 0x10 << 8 | 0x17);
+        sim::poke(RTC_SSR, PREDIV_S - 128);
+        assert_eq!(RTC.now_unix_ms(), Some(SAMPLE_S as u64 * 1000 + 500));
+        // Held back by a shift: a quarter second before the one shown.
+        sim::poke(RTC_SSR, PREDIV_S + 64);
+        assert_eq!(RTC.now_unix_ms(), Some(SAMPLE_S as u64 * 1000 - 250));
+    }
+}
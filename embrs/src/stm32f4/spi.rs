@@ -0,0 +1,806 @@
+//! Serial Peripheral Interface (SPI) support.
+//!
+//! As master, `Spi` implements `hal::SpiTransfer`, polling a word at a time;
+//! configure it with `configure_master`.
+//!
+//! As slave, the master decides when words move, so the slave has to be
+//! ready in advance: `configure_slave`, then `preload` the first word before
+//! the master starts clocking, or use `SlaveDma`, which keeps the transmit
+//! register fed by DMA for whole fixed-size frames and uses an EXTI
+//! interrupt on NSS to find where each transaction ends.
+//!
+//! Either way, the SPI can use the Motorola frame format (the usual one) or
+//! TI's synchronous serial format, and can append a hardware CRC to each
+//! transfer and check the one received; see `FrameFormat` and the `crc`
+//! configuration fields.
+//!
+//! The SPIs with I2S support (SPI2 and SPI3) can instead run as I2S audio
+//! interfaces; see `configure_i2s`.  I2S is clocked from the PLLI2S (see
+//! `Rcc::configure_plli2s`), not the bus clock, and usually fed by DMA.
+
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use arm_m::reg::{mmio, Reg};
+use bits::FromBitsTotal;
+use clock;
+use hal::SpiTransfer;
+use super::ccm;
+use super::dma::{self, Request};
+use super::exti::{Trigger, EXTI};
+use super::gpio::{self, Pins};
+use super::rcc::{ApbPeripheral, ClockSpeeds, RCC};
+
+#[repr(C, packed)]
+struct Registers {
+    cr1:     Reg<u32>,
+    cr2:     Reg<u32>,
+    sr:      Reg<u32>,
+    dr:      Reg<u32>,
+    crcpr:   Reg<u32>,
+    rxcrcr:  Reg<u32>,
+    txcrcr:  Reg<u32>,
+    i2scfgr: Reg<u32>,
+    i2spr:   Reg<u32>,
+}
+
+register_layout! {
+    fn check_layout: Registers [0x24] {
+        cr1 @ 0x00,
+        cr2 @ 0x04,
+        sr @ 0x08,
+        dr @ 0x0C,
+        crcpr @ 0x10,
+        rxcrcr @ 0x14,
+        txcrcr @ 0x18,
+        i2scfgr @ 0x1C,
+        i2spr @ 0x20,
+    }
+}
+
+bit_wrappers! {
+    pub struct Cr1(pub u32);
+    pub struct Cr2(pub u32);
+    pub struct Sr(pub u32);
+    pub struct I2scfgr(pub u32);
+    pub struct I2spr(pub u32);
+}
+
+impl Cr1 {
+    bitfield_accessors! {
+        pub total [15] get_bidimode / with_bidimode: bool,
+        pub total [14] get_bidioe / with_bidioe: bool,
+        /// Hardware CRC calculation.  Only change this while disabled.
+        pub total [13] get_crcen / with_crcen: bool,
+        /// Send the CRC after the current word.
+        pub total [12] get_crcnext / with_crcnext: bool,
+        pub total [11] get_dff / with_dff: DataFrame,
+        pub total [10] get_rxonly / with_rxonly: bool,
+        /// Software slave management: NSS comes from `SSI`, not the pin.
+        pub total [ 9] get_ssm / with_ssm: bool,
+        /// Internal slave select, used in place of NSS when `SSM` is set.
+        pub total [ 8] get_ssi / with_ssi: bool,
+        pub total [ 7] get_lsbfirst / with_lsbfirst: bool,
+        pub total [ 6] get_spe / with_spe: bool,
+        pub total [5:3] get_br / with_br: BaudDivisor,
+        pub total [ 2] get_mstr / with_mstr: bool,
+        pub total [ 1] get_cpol / with_cpol: bool,
+        pub total [ 0] get_cpha / with_cpha: bool,
+    }
+}
+
+impl Cr2 {
+    bitfield_accessors! {
+        pub total [7] get_txeie / with_txeie: bool,
+        pub total [6] get_rxneie / with_rxneie: bool,
+        pub total [5] get_errie / with_errie: bool,
+        pub total [4] get_frf / with_frf: FrameFormat,
+        /// As master, drive NSS low while enabled.
+        pub total [2] get_ssoe / with_ssoe: bool,
+        pub total [1] get_txdmaen / with_txdmaen: bool,
+        pub total [0] get_rxdmaen / with_rxdmaen: bool,
+    }
+}
+
+impl Sr {
+    bitfield_accessors! {
+        /// In TI mode, a frame pulse arrived mid-word (cleared by reading
+        /// SR).
+        pub total [8] get_fre / with_fre: bool,
+        pub total [7] get_bsy / with_bsy: bool,
+        pub total [6] get_ovr / with_ovr: bool,
+        pub total [5] get_modf / with_modf: bool,
+        /// The received CRC didn't match (write zero to clear).
+        pub total [4] get_crcerr / with_crcerr: bool,
+        /// As slave, a word was due out before one was written.
+        pub total [3] get_udr / with_udr: bool,
+        pub total [1] get_txe / with_txe: bool,
+        pub total [0] get_rxne / with_rxne: bool,
+    }
+}
+
+impl I2scfgr {
+    bitfield_accessors! {
+        /// I2S rather than SPI.
+        pub total [11] get_i2smod / with_i2smod: bool,
+        /// Enables the I2S (SPE is ignored in I2S mode).
+        pub total [10] get_i2se / with_i2se: bool,
+        pub total [9:8] get_i2scfg / with_i2scfg: I2sMode,
+        /// For PCM: long frame sync rather than short.
+        pub total [7] get_pcmsync / with_pcmsync: bool,
+        pub total [5:4] get_i2sstd / with_i2sstd: I2sStandard,
+        pub total [3] get_ckpol / with_ckpol: bool,
+        pub total [2:1] get_datlen / with_datlen: I2sDataLength,
+        /// 32-bit channels rather than 16.
+        pub total [0] get_chlen / with_chlen: bool,
+    }
+}
+
+impl I2spr {
+    bitfield_accessors! {
+        /// Output the master clock (256 times the sample rate).
+        pub total [9] get_mckoe / with_mckoe: bool,
+        /// Adds one to the prescaler `2 * i2sdiv`.
+        pub total [8] get_odd / with_odd: bool,
+        pub total [7:0] get_i2sdiv / with_i2sdiv: u32,
+    }
+}
+
+bit_enums! {
+    pub bit_enum FrameFormat {
+        /// Motorola format: the usual SPI, with NSS held low for the
+        /// transaction.
+        Motorola = 0,
+        /// TI synchronous serial format: a one-clock frame pulse on NSS
+        /// before each word, data changing on rising SCK edges.  Clock mode,
+        /// bit order and NSS management settings are ignored.
+        Ti = 1,
+    }
+
+    pub bit_enum I2sMode {
+        SlaveTx = 0b00,
+        SlaveRx = 0b01,
+        MasterTx = 0b10,
+        MasterRx = 0b11,
+    }
+
+    pub bit_enum I2sStandard {
+        /// The original Philips I2S: data one clock after the word select
+        /// edge.
+        Philips = 0b00,
+        /// Left justified.
+        Msb = 0b01,
+        /// Right justified.
+        Lsb = 0b10,
+        Pcm = 0b11,
+    }
+
+    pub bit_enum I2sDataLength {
+        Bits16 = 0b00,
+        Bits24 = 0b01,
+        Bits32 = 0b10,
+    }
+
+    pub bit_enum DataFrame {
+        Bits8 = 0,
+        Bits16 = 1,
+    }
+
+    /// Dividers from the peripheral clock to the master's SCK.
+    pub bit_enum BaudDivisor {
+        Div2 = 0b000,
+        Div4 = 0b001,
+        Div8 = 0b010,
+        Div16 = 0b011,
+        Div32 = 0b100,
+        Div64 = 0b101,
+        Div128 = 0b110,
+        Div256 = 0b111,
+    }
+}
+
+/// The four combinations of clock polarity and phase.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SpiMode {
+    /// Clock idles low; data sampled on the rising edge.
+    Mode0,
+    /// Clock idles low; data sampled on the falling edge.
+    Mode1,
+    /// Clock idles high; data sampled on the falling edge.
+    Mode2,
+    /// Clock idles high; data sampled on the rising edge.
+    Mode3,
+}
+
+impl SpiMode {
+    /// Gets the `(CPOL, CPHA)` bits.
+    fn bits(self) -> (bool, bool) {
+        match self {
+            SpiMode::Mode0 => (false, false),
+            SpiMode::Mode1 => (false, true),
+            SpiMode::Mode2 => (true, false),
+            SpiMode::Mode3 => (true, true),
+        }
+    }
+}
+
+/// How a slave learns that it's selected.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Nss {
+    /// From the NSS pin, which must be routed to the SPI.
+    Hardware,
+    /// From software, through `Spi::select`.  The NSS pin is free for other
+    /// uses -- such as watching with EXTI.
+    Software,
+}
+
+/// Master options.
+#[derive(Copy, Clone, Debug)]
+pub struct MasterConfig {
+    pub mode: SpiMode,
+    /// Fastest acceptable SCK frequency.  The result may be slower, by up to
+    /// half.
+    pub max_clock_hz: u32,
+    pub frame: DataFrame,
+    pub lsb_first: bool,
+    pub format: FrameFormat,
+    /// CRC polynomial, if a CRC should follow each transfer.
+    pub crc: Option<u16>,
+}
+
+/// Slave options.
+#[derive(Copy, Clone, Debug)]
+pub struct SlaveConfig {
+    pub mode: SpiMode,
+    pub nss: Nss,
+    pub frame: DataFrame,
+    pub lsb_first: bool,
+    pub format: FrameFormat,
+    /// CRC polynomial, if a CRC should follow each transfer.
+    pub crc: Option<u16>,
+}
+
+/// I2S options.
+#[derive(Copy, Clone, Debug)]
+pub struct I2sConfig {
+    pub mode: I2sMode,
+    pub standard: I2sStandard,
+    /// Bits of data per sample.
+    pub data: I2sDataLength,
+    /// Use 32-bit channels even for 16-bit data (which is implied for
+    /// longer data).
+    pub extended: bool,
+    /// Frames per second, for masters.
+    pub sample_rate: u32,
+    /// As master, output the master clock, at 256 times the sample rate, on
+    /// the MCK pin.  Most codecs need it.
+    pub mclk_output: bool,
+    pub clock_idle_high: bool,
+}
+
+/// The CRC-8 polynomial x^8 + x^2 + x + 1 (CRC-8-CCITT), without its top
+/// term, as `crc` fields take it.
+pub const CRC8_CCITT : u16 = 0x07;
+/// The CRC-16 polynomial x^16 + x^12 + x^5 + 1 (CRC-16-CCITT).
+pub const CRC16_CCITT : u16 = 0x1021;
+/// The polynomial the CRC unit resets to.
+pub const CRC_RESET_POLYNOMIAL : u16 = 0x07;
+
+/// Ways that SPI operations can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SpiError {
+    /// A word was received before the last was read, and lost.
+    Overrun,
+    /// As master, NSS was pulled low by someone else.
+    ModeFault,
+    /// The peripheral clock can't be divided down far enough.
+    TooFast,
+    /// A DMA transfer is already in progress.
+    Busy,
+    /// The CRC received at the end of a transfer didn't match the data.
+    Crc,
+    /// In TI mode, a frame pulse arrived in the middle of a word.
+    FrameFormat,
+    /// The I2S clock can't be divided to give the requested sample rate.
+    SampleRate,
+    /// DMA buffers were empty, longer than 65535 bytes, or of different
+    /// lengths.
+    Length,
+    /// A DMA buffer is in memory that DMA can't access (see `ccm`).
+    Inaccessible,
+}
+
+/// SPI driver.
+pub struct Spi {
+    reg: *const Registers,
+    /// Alternate function that routes this SPI's signals to pins.
+    af: gpio::Function,
+    /// Name of this SPI in the RCC.
+    peripheral: ApbPeripheral,
+}
+
+macro_rules! reg_accessors {
+    ($name:ident, $ty:ident, $read:ident, $write:ident, $update:ident) => {
+        pub fn $write(&self, v: $ty) {
+            self.reg().$name.set(v.0)
+        }
+
+        pub fn $read(&self) -> $ty {
+            $ty(self.reg().$name.get())
+        }
+
+        pub fn $update<F: FnOnce($ty) -> $ty>(&self, f: F) {
+            self.$write(f(self.$read()))
+        }
+    };
+}
+
+impl Spi {
+    fn reg(&self) -> &Registers {
+        unsafe {
+            mmio(self.reg as usize)
+        }
+    }
+
+    reg_accessors!(cr1, Cr1, read_cr1, write_cr1, update_cr1);
+    reg_accessors!(cr2, Cr2, read_cr2, write_cr2, update_cr2);
+    reg_accessors!(i2scfgr, I2scfgr, read_i2scfgr, write_i2scfgr,
+                   update_i2scfgr);
+    reg_accessors!(i2spr, I2spr, read_i2spr, write_i2spr, update_i2spr);
+
+    pub fn read_sr(&self) -> Sr {
+        Sr(self.reg().sr.get())
+    }
+
+    /// Address of the data register, for use as a DMA peripheral address.
+    pub fn dr_address(&self) -> *const () {
+        &self.reg().dr as *const Reg<u32> as *const ()
+    }
+
+    /// Enables this SPI's clock in the RCC.
+    pub fn enable_clock(&self) {
+        RCC.enable_clock(self.peripheral)
+    }
+
+    /// Resets this SPI, discarding its configuration and any buffered data.
+    pub fn reset(&self) {
+        RCC.reset(self.peripheral)
+    }
+
+    /// Routes `pins` to this SPI's signals (SCK, MISO, MOSI, NSS --
+    /// whichever the pins carry).
+    ///
+    /// Which pins carry which signals is part-specific; see the datasheet.
+    pub fn configure_pins(&self, pins: &Pins) {
+        pins.configure_alternate(self.af, gpio::Pull::None)
+    }
+
+    /// Configures the SPI as master, given the current clock speeds, and
+    /// enables it.  NSS is managed in software, and kept high; use a GPIO
+    /// for chip select.  Returns the actual SCK frequency.
+    pub fn configure_master(&self, speeds: &ClockSpeeds,
+                            config: &MasterConfig) -> Result<u32, SpiError> {
+        let clk = speeds.get_clock_for(self.peripheral) as u32;
+        let mut br = 0;
+        while br < 8 && (clk >> (br + 1)) > config.max_clock_hz {
+            br += 1
+        }
+        if br == 8 {
+            return Err(SpiError::TooFast)
+        }
+        let (cpol, cpha) = config.mode.bits();
+
+        self.apply(Cr1::default()
+                   .with_cpol(cpol)
+                   .with_cpha(cpha)
+                   .with_mstr(true)
+                   .with_br(BaudDivisor::from_bits_total(br))
+                   .with_lsbfirst(config.lsb_first)
+                   .with_ssm(true)
+                   .with_ssi(true)
+                   .with_dff(config.frame),
+                   config.format,
+                   config.crc);
+        Ok(clk >> (br + 1))
+    }
+
+    /// Configures the SPI as master using the clock speeds recorded by
+    /// `clock::freeze`.  See `configure_master`.
+    ///
+    /// # Panics
+    ///
+    /// If the clock speeds have not been frozen.
+    pub fn configure_master_frozen(&self, config: &MasterConfig)
+        -> Result<u32, SpiError> {
+        self.configure_master(clock::frozen(), config)
+    }
+
+    /// Configures the SPI as slave, and enables it.  With `Nss::Software`,
+    /// the slave starts deselected.
+    pub fn configure_slave(&self, config: &SlaveConfig) {
+        let (cpol, cpha) = config.mode.bits();
+        let software = config.nss == Nss::Software;
+
+        self.apply(Cr1::default()
+                   .with_cpol(cpol)
+                   .with_cpha(cpha)
+                   .with_lsbfirst(config.lsb_first)
+                   .with_ssm(software)
+                   .with_ssi(software)
+                   .with_dff(config.frame),
+                   config.format,
+                   config.crc)
+    }
+
+    /// Disables the SPI, loads `cr1` with the frame format and CRC settings,
+    /// and reenables it.
+    fn apply(&self, cr1: Cr1, format: FrameFormat, crc: Option<u16>) {
+        self.update_cr1(|v| v.with_spe(false));
+        self.write_cr2(Cr2::default().with_frf(format));
+        self.reg().crcpr.set(crc.unwrap_or(CRC_RESET_POLYNOMIAL) as u32);
+        self.write_cr1(cr1.with_crcen(crc.is_some()));
+        self.update_cr1(|v| v.with_spe(true))
+    }
+
+    /// Configures the SPI as an I2S interface, leaving it disabled (see
+    /// `set_i2s_enabled`).  `i2s_clock_hz` is the frequency of the I2S
+    /// clock, usually the PLLI2S output.  Returns the actual sample rate,
+    /// for masters; the prescaler is an integer, so it may be a little off.
+    pub fn configure_i2s(&self, i2s_clock_hz: u32, config: &I2sConfig)
+        -> Result<u32, SpiError> {
+        let wide = config.extended || config.data != I2sDataLength::Bits16;
+        let master = config.mode == I2sMode::MasterTx
+            || config.mode == I2sMode::MasterRx;
+
+        let mut pr = I2spr::default().with_i2sdiv(2);
+        let mut rate = 0;
+        if master {
+            // Bit clocks per frame -- or, with the master clock out, MCK
+            // cycles, which are fixed at 256.
+            let per_frame = if config.mclk_output {
+                256
+            } else if wide {
+                64
+            } else {
+                32
+            };
+            let f = config.sample_rate * per_frame;
+            let d = (i2s_clock_hz + f / 2) / f;
+            if d < 4 || d > 511 {
+                return Err(SpiError::SampleRate)
+            }
+            pr = pr.with_i2sdiv(d / 2)
+                .with_odd(d & 1 != 0)
+                .with_mckoe(config.mclk_output);
+            rate = i2s_clock_hz / (per_frame * d);
+        }
+
+        self.write_i2scfgr(I2scfgr::default());
+        self.write_i2spr(pr);
+        self.write_i2scfgr(I2scfgr::default()
+                           .with_i2smod(true)
+                           .with_i2scfg(config.mode)
+                           .with_i2sstd(config.standard)
+                           .with_ckpol(config.clock_idle_high)
+                           .with_datlen(config.data)
+                           .with_chlen(wide));
+        Ok(rate)
+    }
+
+    /// Starts or stops the I2S.
+    pub fn set_i2s_enabled(&self, enabled: bool) {
+        self.update_i2scfgr(|v| v.with_i2se(enabled))
+    }
+
+    /// Checks whether a CRC follows each transfer.
+    pub fn is_crc_enabled(&self) -> bool {
+        self.read_cr1().get_crcen()
+    }
+
+    /// Restarts the CRC calculation, for the start of a transfer.  The
+    /// transmit and receive CRC registers are only cleared by turning the
+    /// CRC unit off and on, which needs the SPI disabled, so this waits for
+    /// any transfer in progress to finish.
+    pub fn reset_crc(&self) {
+        while self.is_busy() {}
+        self.update_cr1(|v| v.with_spe(false));
+        self.update_cr1(|v| v.with_crcen(false));
+        self.update_cr1(|v| v.with_crcen(true));
+        self.update_cr1(|v| v.with_spe(true))
+    }
+
+    /// Reads the CRC of the data received so far.
+    pub fn read_rx_crc(&self) -> u16 {
+        self.reg().rxcrcr.get() as u16
+    }
+
+    /// Reads the CRC of the data sent so far.
+    pub fn read_tx_crc(&self) -> u16 {
+        self.reg().txcrcr.get() as u16
+    }
+
+    /// With software NSS management, selects or deselects the slave.
+    pub fn select(&self, selected: bool) {
+        // SSI stands in for the NSS pin, which is active low.
+        self.update_cr1(|v| v.with_ssi(!selected))
+    }
+
+    /// As slave, loads the first word to send, so that it's ready when the
+    /// master starts clocking.  Without this, the master receives whatever
+    /// was last loaded -- or, if nothing, an underrun.
+    pub fn preload(&self, word: u16) {
+        self.reg().dr.set(word as u32)
+    }
+
+    /// Checks whether the SPI is mid-transfer.
+    pub fn is_busy(&self) -> bool {
+        self.read_sr().get_bsy()
+    }
+
+    /// Checks for and clears errors.  Overrun is cleared by reading DR then
+    /// SR, mode fault by reading SR then writing CR1, TI frame errors by
+    /// reading SR, and CRC errors by writing zero.
+    pub fn check_errors(&self) -> Result<(), SpiError> {
+        let sr = self.read_sr();
+        if sr.get_crcerr() {
+            self.reg().sr.set(Sr(!0).with_crcerr(false).0);
+            Err(SpiError::Crc)
+        } else if sr.get_fre() {
+            Err(SpiError::FrameFormat)
+        } else if sr.get_ovr() {
+            let _ = self.reg().dr.get();
+            let _ = self.read_sr();
+            Err(SpiError::Overrun)
+        } else if sr.get_modf() {
+            self.update_cr1(|v| v);
+            Err(SpiError::ModeFault)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Exchanges one word, as master.  If `last`, and CRCs are on, the CRC
+    /// is sent next.
+    fn exchange(&self, word: u16, last: bool) -> Result<u16, SpiError> {
+        while !self.read_sr().get_txe() {
+            try!(self.check_errors())
+        }
+        self.reg().dr.set(word as u32);
+        if last {
+            self.update_cr1(|v| v.with_crcnext(true))
+        }
+        while !self.read_sr().get_rxne() {
+            try!(self.check_errors())
+        }
+        Ok(self.reg().dr.get() as u16)
+    }
+
+    /// Finishes a transfer's CRC phase: takes the received CRC, which the
+    /// hardware has already checked, and reports whether it matched.
+    fn finish_crc(&self) -> Result<(), SpiError> {
+        while !self.read_sr().get_rxne() {
+            try!(self.check_errors())
+        }
+        let _ = self.reg().dr.get();
+        self.check_errors()
+    }
+}
+
+impl SpiTransfer for Spi {
+    type Error = SpiError;
+
+    fn transfer(&self, words: &mut [u8]) -> Result<(), SpiError> {
+        let crc = self.is_crc_enabled() && !words.is_empty();
+        if crc {
+            self.reset_crc()
+        }
+        let n = words.len();
+        for (i, w) in words.iter_mut().enumerate() {
+            *w = try!(self.exchange(*w as u16, crc && i + 1 == n)) as u8
+        }
+        if crc { self.finish_crc() } else { Ok(()) }
+    }
+
+    fn write(&self, words: &[u8]) -> Result<(), SpiError> {
+        let crc = self.is_crc_enabled() && !words.is_empty();
+        if crc {
+            self.reset_crc()
+        }
+        let n = words.len();
+        for (i, &w) in words.iter().enumerate() {
+            let _ = try!(self.exchange(w as u16, crc && i + 1 == n));
+        }
+        if crc { self.finish_crc() } else { Ok(()) }
+    }
+}
+
+/// DMA-driven slave transfers of fixed-size frames.
+///
+/// Each transaction exchanges up to one frame: `start` loads a frame to send
+/// and a buffer to receive into, and the master clocks as much of it as it
+/// likes.  The transaction ends when the master deasserts NSS, which we
+/// watch with an EXTI interrupt; `handle_nss_interrupt` then hands what
+/// arrived to a callback, and the next `start` readies another frame.
+///
+/// With CRCs on, the hardware sends the CRC after the last byte of `tx`,
+/// and checks the one that follows `rx`; a mismatch is reported when the
+/// transaction ends.
+///
+/// The SPI should be configured as slave with 8-bit frames.  With
+/// `Nss::Hardware`, the NSS pin should be routed to both the SPI and EXTI;
+/// with `Nss::Software`, this selects the slave itself at `start`.
+///
+/// The application must route the NSS pin's EXTI interrupt to
+/// `handle_nss_interrupt`.
+pub struct SlaveDma<'a> {
+    spi: &'a Spi,
+    config: SlaveConfig,
+    tx: Request,
+    rx: Request,
+    /// Length of the frame in progress; zero when idle.
+    len: AtomicUsize,
+    /// Address of the receive buffer of the frame in progress.
+    rx_buf: AtomicUsize,
+    /// EXTI line mask for NSS; zero until `watch_nss`.
+    nss_line: AtomicUsize,
+}
+
+impl<'a> SlaveDma<'a> {
+    /// Creates a DMA slave driver for `spi`, using the given DMA requests
+    /// for transmit and receive -- e.g. `Request::Spi2Tx` and
+    /// `Request::Spi2Rx` for SPI2.  `config` is reapplied after each
+    /// transaction, which resets the SPI to discard any unsent data.
+    pub const fn new(spi: &'a Spi, config: SlaveConfig, tx: Request,
+                     rx: Request) -> SlaveDma<'a> {
+        SlaveDma {
+            spi: spi,
+            config: config,
+            tx: tx,
+            rx: rx,
+            len: AtomicUsize::new(0),
+            rx_buf: AtomicUsize::new(0),
+            nss_line: AtomicUsize::new(0),
+        }
+    }
+
+    /// Arranges to be interrupted when `nss` (a single pin, configured as
+    /// an input or routed to the SPI) goes high, ending a transaction.  The
+    /// SYSCFG clock must be enabled.
+    pub fn watch_nss(&self, nss: &Pins) {
+        let line = EXTI.configure_pins(nss, Trigger::Rising);
+        EXTI.clear_pending(line);
+        self.nss_line.store(line as usize, Ordering::Relaxed);
+        EXTI.enable_interrupt(line)
+    }
+
+    /// Checks whether a transaction is set up or in progress.
+    pub fn is_busy(&self) -> bool {
+        self.len.load(Ordering::Acquire) != 0
+    }
+
+    /// Readies a transaction: `tx` will be sent while `rx`, which must be
+    /// the same length, is filled.  Nothing moves until the master starts
+    /// clocking.  The buffers must be non-empty and at most 65535 bytes,
+    /// the most one DMA transfer can move.
+    pub fn start(&self, tx: &'static [u8], rx: &'static mut [u8])
+        -> Result<(), SpiError> {
+        if tx.len() != rx.len() || tx.is_empty()
+            || rx.len() > u16::max_value() as usize {
+            return Err(SpiError::Length)
+        }
+        if ccm::contains(tx.as_ptr() as usize, tx.len())
+            || ccm::contains(rx.as_ptr() as usize, rx.len()) {
+            return Err(SpiError::Inaccessible)
+        }
+        if self.is_busy() {
+            return Err(SpiError::Busy)
+        }
+        self.rx_buf.store(rx.as_ptr() as usize, Ordering::Relaxed);
+        self.len.store(rx.len(), Ordering::Release);
+
+        // Receive first, so that nothing can arrive unheard.
+        self.setup_stream(self.rx, rx.as_ptr(), rx.len(),
+                          dma::Direction::PeripheralToMemory);
+        self.spi.update_cr2(|v| v.with_rxdmaen(true));
+        // Enabling transmit DMA fills the data register at once: that's the
+        // preload.
+        self.setup_stream(self.tx, tx.as_ptr(), tx.len(),
+                          dma::Direction::MemoryToPeripheral);
+        self.spi.update_cr2(|v| v.with_txdmaen(true));
+
+        if self.config.nss == Nss::Software {
+            self.spi.select(true)
+        }
+        Ok(())
+    }
+
+    fn setup_stream(&self, req: Request, buf: *const u8, len: usize,
+                    dir: dma::Direction) {
+        let route = req.route();
+        let stream = route.get_stream();
+        route.clear_interrupt_flags(dma::InterruptFlags::all());
+        stream.par.set(self.spi.dr_address());
+        stream.mar[0].set(buf as *const ());
+        stream.ndtr.set(dma::Ndtr::default().with_ndt(len as u16));
+        stream.cr.set(dma::Cr::default()
+                      .with_chsel(route.get_channel())
+                      .with_dir(dir)
+                      .with_msize(dma::TransferSize::Byte)
+                      .with_psize(dma::TransferSize::Byte)
+                      .with_minc(true));
+        stream.cr.update(|v| v.with_en(true));
+    }
+
+    /// Ends the transaction in progress, passing the bytes received to
+    /// `received` and returning their number (which is also, give or take
+    /// the preloaded byte, the number sent).  The SPI is reset and
+    /// reconfigured, discarding whatever the master didn't clock out.
+    ///
+    /// A CRC mismatch, or a TI frame format error, is reported instead, and
+    /// the data isn't delivered.
+    pub fn finish<F: FnOnce(&[u8])>(&self, received: F)
+        -> Result<usize, SpiError> {
+        let len = self.len.load(Ordering::Acquire);
+        if len == 0 {
+            return Ok(0)
+        }
+        let sr = self.spi.read_sr();
+        if self.config.nss == Nss::Software {
+            self.spi.select(false)
+        }
+        let _ = self.tx.route().get_stream().abort();
+        let remaining = self.rx.route().get_stream().abort() as usize;
+
+        self.spi.reset();
+        self.spi.configure_slave(&self.config);
+        let result = if sr.get_crcerr() {
+            Err(SpiError::Crc)
+        } else if sr.get_fre() {
+            Err(SpiError::FrameFormat)
+        } else {
+            // Both streams are stopped, so the buffer is ours again until
+            // the next `start`, which `len` being nonzero holds off.
+            let n = len - remaining;
+            received(unsafe {
+                slice::from_raw_parts(
+                    self.rx_buf.load(Ordering::Relaxed) as *const u8, n)
+            });
+            Ok(n)
+        };
+        self.len.store(0, Ordering::Release);
+        result
+    }
+
+    /// To be called from the NSS pin's EXTI interrupt handler.  If NSS has
+    /// been deasserted, ends the transaction, delivering the data to
+    /// `received` and returning the outcome, as from `finish`.
+    pub fn handle_nss_interrupt<F: FnOnce(&[u8])>(&self, received: F)
+        -> Option<Result<usize, SpiError>> {
+        let line = self.nss_line.load(Ordering::Relaxed) as u32;
+        if line == 0 || EXTI.pending(line) == 0 {
+            return None
+        }
+        EXTI.clear_pending(line);
+        if self.is_busy() {
+            Some(self.finish(received))
+        } else {
+            None
+        }
+    }
+}
+
+unsafe impl Sync for Spi {}
+
+macro_rules! static_spi {
+    ($name:ident, $addr:expr, $af:ident, $periph:ident) => {
+        pub static $name: Spi = Spi {
+            reg: $addr as *const Registers,
+            af: gpio::Function::$af,
+            peripheral: ApbPeripheral::$periph,
+        };
+    };
+}
+
+static_spi!(SPI1, 0x40013000, AF5, Spi1);
+static_spi!(SPI2, 0x40003800, AF5, Spi2);
+static_spi!(SPI3, 0x40003C00, AF6, Spi3);
@@ -0,0 +1,201 @@
+//! System Configuration Controller (SYSCFG) support.
+//!
+//! SYSCFG holds a grab-bag of settings: what appears at address 0, which GPIO
+//! port drives each EXTI line, the Ethernet PHY interface, and the I/O
+//! compensation cell.
+//!
+//! Its clock must be enabled (`ApbPeripheral::Syscfg`) before use.
+
+use arm_m::reg::{mmio, AtomicReg, Reg, ReservedReg};
+use bits::FromBits;
+use super::gpio::PinMask;
+
+#[repr(C, packed)]
+struct Registers {
+    memrmp:    Reg<u32>,
+    pmc:       Reg<u32>,
+    /// External interrupt configuration registers EXTICR1 - EXTICR4.
+    ///
+    /// Note that they are numbered from zero in this array.
+    exticr:    [Reg<u32>; 4],
+    _reserved: [ReservedReg; 2],
+    cmpcr:     Reg<u32>,
+}
+
+register_layout! {
+    fn check_layout: Registers [0x24] {
+        memrmp @ 0x00,
+        pmc @ 0x04,
+        exticr @ 0x08,
+        cmpcr @ 0x20,
+    }
+}
+
+const SYSCFG_ADDRESS : usize = 0x40013800;
+
+bit_wrappers! {
+    /// Wrapper for the Memory Remap Register bits.
+    pub struct Memrmp(pub u32);
+    /// Wrapper for the Peripheral Mode Configuration Register bits.
+    pub struct Pmc(pub u32);
+    /// Wrapper for the Compensation Cell Control Register bits.
+    pub struct Cmpcr(pub u32);
+}
+
+impl Memrmp {
+    bitfield_accessors! {
+        /// Selects the memory aliased at address 0.  Initialized from the
+        /// BOOT pins at reset.
+        pub [1:0] get_mem_mode / with_mem_mode: MemMode,
+    }
+}
+
+impl Pmc {
+    bitfield_accessors! {
+        /// Selects the Ethernet PHY interface.  Must be set while the Ethernet
+        /// MAC is held in reset and before its clock is enabled.
+        pub total [23] get_mii_rmii_sel / with_mii_rmii_sel: PhyInterface,
+    }
+}
+
+impl Cmpcr {
+    bitfield_accessors! {
+        /// Set once the compensation cell is ready (read-only).
+        pub total [8] get_ready / with_ready: bool,
+        /// Powers up the compensation cell.
+        pub total [0] get_cmp_pd / with_cmp_pd: bool,
+    }
+}
+
+bit_enums! {
+    /// Memories that can be mapped at address 0.
+    pub bit_enum MemMode {
+        MainFlash = 0b00,
+        SystemFlash = 0b01,
+        Fsmc = 0b10,
+        Sram1 = 0b11,
+    }
+
+    /// Ethernet PHY interface options.
+    pub bit_enum PhyInterface {
+        Mii = 0,
+        Rmii = 1,
+    }
+
+    /// GPIO ports that can be selected as the source of an EXTI line.
+    pub bit_enum ExtiPort {
+        PA = 0b0000,
+        PB = 0b0001,
+        PC = 0b0010,
+        PD = 0b0011,
+        PE = 0b0100,
+        PF = 0b0101,
+        PG = 0b0110,
+        PH = 0b0111,
+        PI = 0b1000,
+    }
+}
+
+/// SYSCFG driver.
+pub struct Syscfg;
+
+impl Syscfg {
+    fn reg(&self) -> &'static Registers {
+        unsafe { mmio(SYSCFG_ADDRESS) }
+    }
+
+    pub fn read_memrmp(&self) -> Memrmp {
+        Memrmp(self.reg().memrmp.get())
+    }
+
+    pub fn write_memrmp(&self, v: Memrmp) {
+        self.reg().memrmp.set(v.0)
+    }
+
+    pub fn update_memrmp<F: FnOnce(Memrmp) -> Memrmp>(&self, f: F) {
+        self.write_memrmp(f(self.read_memrmp()))
+    }
+
+    pub fn read_pmc(&self) -> Pmc {
+        Pmc(self.reg().pmc.get())
+    }
+
+    pub fn write_pmc(&self, v: Pmc) {
+        self.reg().pmc.set(v.0)
+    }
+
+    pub fn update_pmc<F: FnOnce(Pmc) -> Pmc>(&self, f: F) {
+        self.write_pmc(f(self.read_pmc()))
+    }
+
+    pub fn read_cmpcr(&self) -> Cmpcr {
+        Cmpcr(self.reg().cmpcr.get())
+    }
+
+    pub fn write_cmpcr(&self, v: Cmpcr) {
+        self.reg().cmpcr.set(v.0)
+    }
+
+    pub fn update_cmpcr<F: FnOnce(Cmpcr) -> Cmpcr>(&self, f: F) {
+        self.write_cmpcr(f(self.read_cmpcr()))
+    }
+
+    /// Selects the memory that appears at address 0.
+    ///
+    /// Changing this while executing from the aliased region is a great way to
+    /// crash.
+    pub fn set_mem_mode(&self, mode: MemMode) {
+        self.update_memrmp(|v| v.with_mem_mode(mode))
+    }
+
+    /// Selects the Ethernet PHY interface.
+    pub fn set_phy_interface(&self, phy: PhyInterface) {
+        self.update_pmc(|v| v.with_mii_rmii_sel(phy))
+    }
+
+    /// Routes the EXTI lines for `pins` from GPIO port `port`.  EXTI line *n*
+    /// serves pin *n* of exactly one port at a time, so this steals the
+    /// selected lines from whatever port they were routed from before.
+    pub fn route_exti(&self, pins: PinMask, port: ExtiPort) {
+        let pins = pins.bits() as u32;
+        let port = port as u32;
+
+        for (i, reg) in self.reg().exticr.iter().enumerate() {
+            // Each EXTICR holds four 4-bit fields for four consecutive lines.
+            // See gpio::set_alternate_function for the multiplication trick.
+            let bits = (pins >> (i * 4)) & 0xF;
+            let mut places = 0u32;
+            for j in 0..4 {
+                places |= (bits & (1 << j)) << (3 * j);
+            }
+
+            if places != 0 {
+                reg.atomic_nand_and_or(0b1111 * places, port * places)
+            }
+        }
+    }
+
+    /// Reads the port currently routed to EXTI line `line` (0-15).  Returns
+    /// `None` if the field holds one of the reserved encodings.
+    pub fn get_exti_route(&self, line: u32) -> Option<ExtiPort> {
+        let reg = self.reg().exticr[(line / 4) as usize].get();
+        let field = (reg >> ((line % 4) * 4)) & 0xF;
+        ExtiPort::from_bits(field).ok()
+    }
+
+    /// Powers up the I/O compensation cell, which reduces slew rate for I/O
+    /// speeds above 50MHz, and waits for it to become ready.  This requires a
+    /// supply of at least 2.4V.
+    pub fn enable_compensation_cell(&self) {
+        self.update_cmpcr(|v| v.with_cmp_pd(true));
+        while !self.read_cmpcr().get_ready() {}
+    }
+
+    /// Powers down the I/O compensation cell.
+    pub fn disable_compensation_cell(&self) {
+        self.update_cmpcr(|v| v.with_cmp_pd(false))
+    }
+}
+
+/// Shared instance of the `Syscfg` driver.
+pub static SYSCFG: Syscfg = Syscfg;
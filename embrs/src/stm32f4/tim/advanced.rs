@@ -0,0 +1,247 @@
+//! Advanced-control timer (TIM1/TIM8) features: complementary outputs, the
+//! dead-time generator, and the break input.
+//!
+//! A typical half-bridge setup looks like this:
+//!
+//!     let pwm = ComplementaryPwm::new(&TIM1, Channel::Ch1).unwrap();
+//!     let dt = DeadTime::compute(TIM1.get_clock(&speeds), 200.).unwrap();
+//!     TIM1.set_dead_time(dt).unwrap();
+//!     TIM1.configure_break(&BreakConfig::default()).unwrap();
+//!     pwm.pwm().configure(&speeds, 20_000., 0.5, PwmMode::Mode1).unwrap();
+//!     pwm.enable();
+//!
+//! Dead time, break, and idle states should all be set before the outputs
+//! are first enabled, and can then be frozen with `Timer::lock`.
+
+use super::{BreakPolarity, Channel, ClockDivision, Kind, LockLevel, Polarity,
+            Sr, Timer};
+use super::pwm::Pwm;
+
+/// Ways that advanced timer configuration can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum AdvancedError {
+    /// The timer isn't TIM1 or TIM8.
+    NotAdvanced,
+    /// The channel has no complementary output (channel 4 doesn't).
+    NoSuchChannel,
+    /// The requested dead time exceeds what the generator can produce at
+    /// this timer clock.
+    DeadTimeTooLong,
+    /// The break input is still asserted, so the outputs can't be
+    /// re-enabled.
+    BreakActive,
+}
+
+/// A computed dead-time generator setting.
+#[derive(Copy, Clone, Debug)]
+pub struct DeadTime {
+    /// Clock division for the dead-time (and input filter) sampling clock.
+    pub ckd: ClockDivision,
+    /// Encoded value for the BDTR `DTG` field.
+    pub dtg: u32,
+    /// Dead time this setting actually produces, which is never less than
+    /// the request.
+    pub actual_ns: f32,
+}
+
+impl DeadTime {
+    /// Computes the setting giving at least `ns` nanoseconds of dead time
+    /// from a timer clock of `clock_hz` (see `Timer::get_clock`).
+    ///
+    /// The finest sampling clock that can reach `ns` is used.  Note that
+    /// `ckd` also slows the sampling clock of the timer's input filters.
+    pub fn compute(clock_hz: f32, ns: f32) -> Result<DeadTime, AdvancedError> {
+        let divs = [ClockDivision::Div1, ClockDivision::Div2,
+                    ClockDivision::Div4];
+        for (i, &ckd) in divs.iter().enumerate() {
+            let tick_ns = 1e9 * ((1 << i) as f32) / clock_hz;
+            let r = ns / tick_ns;
+            let mut ticks = r as u32;
+            if (ticks as f32) < r {
+                ticks += 1
+            }
+            if let Some((dtg, actual)) = encode_dtg(ticks) {
+                return Ok(DeadTime {
+                    ckd: ckd,
+                    dtg: dtg,
+                    actual_ns: (actual as f32) * tick_ns,
+                })
+            }
+        }
+        Err(AdvancedError::DeadTimeTooLong)
+    }
+}
+
+/// Encodes a dead time of at least `ticks` sampling clocks into the
+/// piecewise-linear `DTG` format (RM0090 section 17.4.18), returning the
+/// encoding and the number of ticks it actually represents.
+fn encode_dtg(ticks: u32) -> Option<(u32, u32)> {
+    if ticks <= 127 {
+        Some((ticks, ticks))
+    } else if ticks <= 254 {
+        let m = (ticks + 1) / 2;
+        Some((0b1000_0000 | (m - 64), m * 2))
+    } else if ticks <= 504 {
+        let m = (ticks + 7) / 8;
+        Some((0b1100_0000 | (m - 32), m * 8))
+    } else if ticks <= 1008 {
+        let m = (ticks + 15) / 16;
+        Some((0b1110_0000 | (m - 32), m * 16))
+    } else {
+        None
+    }
+}
+
+/// Break input and off-state configuration.
+#[derive(Copy, Clone, Debug)]
+pub struct BreakConfig {
+    /// Enables the break input.  When it's asserted, the hardware clears the
+    /// main output enable, forcing all outputs to their idle states.
+    pub enable: bool,
+    pub polarity: BreakPolarity,
+    /// Re-enables the outputs automatically at the next update event after
+    /// the break input deasserts.  Motor drives usually want this off, so
+    /// that a fault latches until software calls `recover_from_break`.
+    pub automatic_output: bool,
+    /// See `Bdtr::with_ossr`.
+    pub off_state_run: bool,
+    /// See `Bdtr::with_ossi`.
+    pub off_state_idle: bool,
+}
+
+impl Default for BreakConfig {
+    fn default() -> BreakConfig {
+        BreakConfig {
+            enable: true,
+            polarity: BreakPolarity::ActiveLow,
+            automatic_output: false,
+            off_state_run: true,
+            off_state_idle: true,
+        }
+    }
+}
+
+impl Timer {
+    fn check_advanced(&self) -> Result<(), AdvancedError> {
+        if self.kind == Kind::Advanced {
+            Ok(())
+        } else {
+            Err(AdvancedError::NotAdvanced)
+        }
+    }
+
+    /// Programs the dead-time generator, which delays the rising edge of
+    /// each output of a complementary pair so that both are never on at
+    /// once.  Ignored if BDTR has been locked.
+    pub fn set_dead_time(&self, dt: DeadTime) -> Result<(), AdvancedError> {
+        try!(self.check_advanced());
+        self.update_cr1(|v| v.with_ckd(dt.ckd));
+        self.update_bdtr(|v| v.with_dtg(dt.dtg));
+        Ok(())
+    }
+
+    /// Configures the break input and off states.  Ignored if BDTR has been
+    /// locked.
+    pub fn configure_break(&self, cfg: &BreakConfig)
+        -> Result<(), AdvancedError> {
+        try!(self.check_advanced());
+        self.update_bdtr(|v| v.with_bke(cfg.enable)
+                         .with_bkp(cfg.polarity)
+                         .with_aoe(cfg.automatic_output)
+                         .with_ossr(cfg.off_state_run)
+                         .with_ossi(cfg.off_state_idle));
+        // Changing the polarity can itself produce a spurious break.
+        self.clear_sr(Sr::default().with_bif(true));
+        Ok(())
+    }
+
+    /// Checks whether a break has occurred since the flag was last cleared.
+    pub fn break_occurred(&self) -> bool {
+        self.read_sr().get_bif()
+    }
+
+    /// Re-enables the outputs after a break, if the break input has been
+    /// released.  The break flag remains set while the input is asserted, so
+    /// we clear it and check that it stays clear.
+    pub fn recover_from_break(&self) -> Result<(), AdvancedError> {
+        try!(self.check_advanced());
+        self.clear_sr(Sr::default().with_bif(true));
+        if self.read_sr().get_bif() {
+            return Err(AdvancedError::BreakActive)
+        }
+        self.set_main_output(true);
+        Ok(())
+    }
+
+    /// Write-protects the safety-related settings at `level`, until reset.
+    /// This can only be done once; later calls have no effect.
+    pub fn lock(&self, level: LockLevel) -> Result<(), AdvancedError> {
+        try!(self.check_advanced());
+        self.update_bdtr(|v| v.with_lock(level));
+        Ok(())
+    }
+}
+
+/// A PWM output with its complementary output, on channels 1-3 of an
+/// advanced timer.  Use `pwm` to set frequency and duty cycle, which apply
+/// to the pair; the complementary output is the inverse of the main one,
+/// less dead time.
+#[derive(Copy, Clone)]
+pub struct ComplementaryPwm {
+    pwm: Pwm,
+}
+
+impl ComplementaryPwm {
+    pub fn new(timer: &'static Timer, channel: Channel)
+        -> Result<ComplementaryPwm, AdvancedError> {
+        try!(timer.check_advanced());
+        if channel == Channel::Ch4 {
+            return Err(AdvancedError::NoSuchChannel)
+        }
+        Pwm::new(timer, channel)
+            .map(|pwm| ComplementaryPwm { pwm: pwm })
+            .map_err(|_| AdvancedError::NoSuchChannel)
+    }
+
+    /// Gets the main channel's PWM driver.
+    pub fn pwm(&self) -> &Pwm {
+        &self.pwm
+    }
+
+    /// Sets the polarity of both outputs, and their levels while the main
+    /// output enable is clear (e.g. after a break).  Must precede `lock` at
+    /// levels that protect these settings.
+    pub fn configure_outputs(&self, main: Polarity, complementary: Polarity,
+                             idle_main: bool, idle_complementary: bool) {
+        let timer = self.pwm.timer();
+        let ch = self.pwm.channel();
+        timer.update_ccer(|v| {
+            let c = v.get_channel(ch)
+                .with_ccp(main)
+                .with_ccnp(complementary);
+            v.with_channel(ch, c)
+        });
+        timer.update_cr2(|v| v.with_ois(ch, idle_main)
+                         .with_oisn(ch, idle_complementary))
+    }
+
+    /// Enables both outputs and the main output enable.
+    pub fn enable(&self) {
+        let ch = self.pwm.channel();
+        self.pwm.timer().update_ccer(|v| {
+            let c = v.get_channel(ch).with_cce(true).with_ccne(true);
+            v.with_channel(ch, c)
+        });
+        self.pwm.timer().set_main_output(true)
+    }
+
+    /// Disables both outputs.  The main output enable is shared and is left
+    /// alone.
+    pub fn disable(&self) {
+        let ch = self.pwm.channel();
+        self.pwm.timer().update_ccer(|v| {
+            let c = v.get_channel(ch).with_cce(false).with_ccne(false);
+            v.with_channel(ch, c)
+        })
+    }
+}
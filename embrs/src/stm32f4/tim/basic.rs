@@ -0,0 +1,136 @@
+//! Periodic ticks and one-shot delays on the basic timers, TIM6 and TIM7.
+//!
+//! The basic timers have no channels or pins; they just count and raise
+//! update events.  That makes them a cheap source of periodic interrupts
+//! (leaving SysTick to an operating system, say) and, through TRGO, of
+//! conversion triggers for the DAC.
+//!
+//! Nothing here actually requires a basic timer, so any timer can be used if
+//! TIM6 and TIM7 are busy.
+
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use proto::modbus::GapTimer;
+use super::super::rcc::ClockSpeeds;
+use super::{MasterMode, Sr, Timebase, TimebaseError, Timer};
+
+/// A tick or delay source built on a timer's update event.
+pub struct Tick {
+    timer: &'static Timer,
+    /// Number of update events handled so far (modulo the word size).
+    count: AtomicUsize,
+}
+
+impl Tick {
+    pub const fn new(timer: &'static Timer) -> Tick {
+        Tick {
+            timer: timer,
+            count: ATOMIC_USIZE_INIT,
+        }
+    }
+
+    /// Sets up the counter to produce update events at (close to) `hz`,
+    /// without starting it.
+    fn prepare(&self, speeds: &ClockSpeeds, hz: f32, one_shot: bool)
+        -> Result<Timebase, TimebaseError> {
+        let tb = try!(Timebase::compute(self.timer.get_clock(speeds),
+                                        hz,
+                                        self.timer.counter_max()));
+        let t = self.timer;
+        t.stop();
+        t.set_timebase(tb);
+        t.update_cr1(|v| v.with_opm(one_shot).with_urs(true));
+        t.load_preloads();
+        t.clear_sr(Sr::default().with_uif(true));
+        Ok(tb)
+    }
+
+    /// Starts periodic update events at (close to) `hz`, each of which raises
+    /// the timer's interrupt.  The timer's clock must already be enabled.
+    /// The chosen timebase is returned.
+    pub fn start_periodic(&self, speeds: &ClockSpeeds, hz: f32)
+        -> Result<Timebase, TimebaseError> {
+        let tb = try!(self.prepare(speeds, hz, false));
+        self.timer.update_dier(|v| v.with_uie(true));
+        self.timer.start();
+        Ok(tb)
+    }
+
+    /// Starts a one-shot delay of (about) `seconds`, after which the counter
+    /// stops and the update event fires once.  Completion can be detected
+    /// by polling `is_expired` or, if `interrupt` is set, in the timer's
+    /// interrupt.
+    pub fn start_one_shot(&self, speeds: &ClockSpeeds, seconds: f32,
+                          interrupt: bool)
+        -> Result<Timebase, TimebaseError> {
+        if !(seconds > 0.) {
+            return Err(TimebaseError::TooFast)
+        }
+        let tb = try!(self.prepare(speeds, 1. / seconds, true));
+        self.timer.update_dier(|v| v.with_uie(interrupt));
+        self.timer.start();
+        Ok(tb)
+    }
+
+    /// Checks whether a one-shot delay has elapsed.
+    pub fn is_expired(&self) -> bool {
+        self.timer.read_sr().get_uif()
+    }
+
+    /// Stops the counter and disables its interrupt.
+    pub fn stop(&self) {
+        self.timer.update_dier(|v| v.with_uie(false));
+        self.timer.stop()
+    }
+
+    /// Emits each update event on TRGO, e.g. to pace DAC conversions.  This
+    /// takes effect whether or not the update interrupt is used.
+    pub fn enable_trgo(&self) {
+        self.timer.set_master_mode(MasterMode::Update)
+    }
+
+    /// Handles the timer's interrupt, returning `true` if an update event
+    /// (a tick, or a one-shot expiring) had occurred.
+    pub fn handle_interrupt(&self) -> bool {
+        if !self.timer.read_sr().get_uif() {
+            return false
+        }
+        self.timer.clear_sr(Sr::default().with_uif(true));
+        let c = self.count.load(Ordering::Relaxed);
+        self.count.store(c.wrapping_add(1), Ordering::Relaxed);
+        true
+    }
+
+    /// Number of update events handled by `handle_interrupt`, wrapping at the
+    /// word size.
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+/// A `Tick` paired with the clock speeds it runs at, for timing the gaps
+/// between Modbus frames.
+pub struct GapTick<'a> {
+    tick: &'a Tick,
+    speeds: &'a ClockSpeeds,
+}
+
+impl<'a> GapTick<'a> {
+    pub fn new(tick: &'a Tick, speeds: &'a ClockSpeeds) -> GapTick<'a> {
+        GapTick {
+            tick: tick,
+            speeds: speeds,
+        }
+    }
+}
+
+impl<'a> GapTimer for GapTick<'a> {
+    fn start_gap(&self, seconds: f32) {
+        // Frame gaps are well within any timer's range.
+        let _ = self.tick.start_one_shot(self.speeds, seconds, false);
+    }
+
+    fn is_expired(&self) -> bool {
+        self.tick.is_expired()
+    }
+}
@@ -0,0 +1,283 @@
+//! Input capture, for measuring frequency and pulse width.
+//!
+//! On each selected edge of its input, a capture channel latches the counter
+//! into its CCR.  This driver collects those captures in its interrupt
+//! handler, extends them to 32-bit timestamps by counting counter overflows,
+//! and queues them in a small FIFO for the application to collect with `pop`.
+//! Differences between timestamps can then be converted to real units with
+//! `tick_hz` and the helpers at the bottom of this module.
+//!
+//! Because overflows are counted by the capture driver, only one `Capture`
+//! should be active per timer; it takes over the timer's update interrupt.
+
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use super::super::rcc::ClockSpeeds;
+use super::{CaptureCompareSelection, Channel, IcPrescaler, Polarity, Sr,
+            Timer};
+
+/// Number of captures the FIFO can hold.
+pub const FIFO_LEN: usize = 8;
+
+/// Which input edges trigger a capture.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Edge {
+    Rising,
+    Falling,
+    /// Both edges, for measuring pulse widths.
+    Both,
+}
+
+/// Input capture configuration.
+#[derive(Copy, Clone, Debug)]
+pub struct CaptureConfig {
+    pub edge: Edge,
+    /// Capture only every Nth edge, to measure fast signals without flooding
+    /// the interrupt.
+    pub prescaler: IcPrescaler,
+    /// Input filter, encoded as for `IcConfig::with_icf` (0-15).
+    pub filter: u32,
+    /// Timer prescaler: the counter ticks at the timer clock divided by
+    /// `timer_prescaler + 1`.  This trades resolution for the time between
+    /// overflows.
+    pub timer_prescaler: u32,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> CaptureConfig {
+        CaptureConfig {
+            edge: Edge::Rising,
+            prescaler: IcPrescaler::Div1,
+            filter: 0,
+            timer_prescaler: 0,
+        }
+    }
+}
+
+/// An input capture channel and its FIFO.
+pub struct Capture {
+    timer: &'static Timer,
+    channel: Channel,
+    /// Number of counter overflows so far (modulo the word size).
+    wraps: AtomicUsize,
+    /// Extended timestamps, written by `handle_interrupt`.
+    fifo: [AtomicUsize; FIFO_LEN],
+    /// Number of timestamps ever written; only `handle_interrupt` advances
+    /// this.
+    head: AtomicUsize,
+    /// Number of timestamps ever read; only `pop` advances this.
+    tail: AtomicUsize,
+    /// Number of captures lost, either to a full FIFO or because the hardware
+    /// captured again before we read CCR.
+    dropped: AtomicUsize,
+}
+
+impl Capture {
+    /// Creates a capture driver for `channel` of `timer` (checked by
+    /// `configure`).
+    pub const fn new(timer: &'static Timer, channel: Channel) -> Capture {
+        Capture {
+            timer: timer,
+            channel: channel,
+            wraps: ATOMIC_USIZE_INIT,
+            fifo: [
+                ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+            ],
+            head: ATOMIC_USIZE_INIT,
+            tail: ATOMIC_USIZE_INIT,
+            dropped: ATOMIC_USIZE_INIT,
+        }
+    }
+
+    /// Configures the timer to free-run and the channel to capture per `cfg`,
+    /// enables the capture and update interrupts, and starts the counter.
+    /// The timer's clock must already be enabled.
+    ///
+    /// # Panics
+    ///
+    /// If the timer lacks this channel.
+    pub fn configure(&self, cfg: &CaptureConfig) {
+        assert!(self.timer.has_channel(self.channel));
+        let t = self.timer;
+        let ch = self.channel;
+
+        t.stop();
+        t.update_ccer(|v| {
+            let c = v.get_channel(ch).with_cce(false);
+            v.with_channel(ch, c)
+        });
+        t.update_ccmr(ch, |v| {
+            let ic = v.get_ic(ch)
+                .with_ccs(CaptureCompareSelection::InputDirect)
+                .with_icf(cfg.filter)
+                .with_icpsc(cfg.prescaler);
+            v.with_ic(ch, ic)
+        });
+        // Edge selection uses the CCxP/CCxNP pair: 00 rising, 01 falling,
+        // 11 both.
+        let (p, np) = match cfg.edge {
+            Edge::Rising => (Polarity::ActiveHigh, Polarity::ActiveHigh),
+            Edge::Falling => (Polarity::ActiveLow, Polarity::ActiveHigh),
+            Edge::Both => (Polarity::ActiveLow, Polarity::ActiveLow),
+        };
+        t.update_ccer(|v| {
+            let c = v.get_channel(ch)
+                .with_ccp(p)
+                .with_ccnp(np)
+                .with_cce(true);
+            v.with_channel(ch, c)
+        });
+
+        t.set_prescaler(cfg.timer_prescaler);
+        t.set_auto_reload(t.counter_max());
+        t.update_cr1(|v| v.with_urs(true));
+        t.load_preloads();
+
+        self.wraps.store(0, Ordering::Relaxed);
+        self.tail.store(self.head.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.dropped.store(0, Ordering::Relaxed);
+
+        t.clear_sr(Sr::default()
+                   .with_uif(true)
+                   .with_ccif(ch, true)
+                   .with_ccof(ch, true));
+        t.update_dier(|v| v.with_uie(true).with_ccie(ch, true));
+        t.start()
+    }
+
+    /// Disables capture and the interrupts, and stops the counter.
+    pub fn stop(&self) {
+        let ch = self.channel;
+        self.timer.update_dier(|v| v.with_uie(false).with_ccie(ch, false));
+        self.timer.update_ccer(|v| {
+            let c = v.get_channel(ch).with_cce(false);
+            v.with_channel(ch, c)
+        });
+        self.timer.stop()
+    }
+
+    /// Number of counts between overflows, or zero for a 32-bit counter
+    /// (whose captures need no extension).
+    fn period(&self) -> u32 {
+        self.timer.counter_max().wrapping_add(1)
+    }
+
+    /// Handles the timer's interrupt, collecting any capture and accounting
+    /// for overflows.
+    pub fn handle_interrupt(&self) {
+        let ch = self.channel;
+        let sr = self.timer.read_sr();
+        let mut wraps = self.wraps.load(Ordering::Relaxed) as u32;
+
+        if sr.get_ccif(ch) {
+            // Reading CCR clears the capture flag.
+            let ccr = self.timer.get_compare(ch);
+            // If an overflow is pending too, it may have happened before or
+            // after the capture.  A capture from early in the counter's
+            // range came after it.
+            let w = if sr.get_uif() && ccr < self.timer.counter_max() / 2 {
+                wraps.wrapping_add(1)
+            } else {
+                wraps
+            };
+            self.push(w.wrapping_mul(self.period()).wrapping_add(ccr));
+
+            if sr.get_ccof(ch) {
+                self.timer.clear_sr(Sr::default().with_ccof(ch, true));
+                let _ = self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        if sr.get_uif() {
+            self.timer.clear_sr(Sr::default().with_uif(true));
+            wraps = wraps.wrapping_add(1);
+            self.wraps.store(wraps as usize, Ordering::Relaxed)
+        }
+    }
+
+    fn push(&self, timestamp: u32) {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) >= FIFO_LEN {
+            let _ = self.dropped.fetch_add(1, Ordering::Relaxed);
+            return
+        }
+        self.fifo[head % FIFO_LEN].store(timestamp as usize, Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release)
+    }
+
+    /// Takes the oldest capture timestamp from the FIFO, if any.  Timestamps
+    /// are in counter ticks and wrap at 2^32; use wrapping subtraction to
+    /// compare them.
+    pub fn pop(&self) -> Option<u32> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if head == tail {
+            return None
+        }
+        let t = self.fifo[tail % FIFO_LEN].load(Ordering::Relaxed) as u32;
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(t)
+    }
+
+    /// Number of captures lost since `configure`.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Rate at which the counter ticks, i.e. the units of the timestamps.
+    pub fn tick_hz(&self, speeds: &ClockSpeeds) -> f32 {
+        self.timer.get_clock(speeds) / (self.timer.get_prescaler() + 1) as f32
+    }
+
+    /// Converts the difference between two successive captures to the
+    /// frequency of the input signal, allowing for the capture prescaler.
+    /// This assumes single-edge capture; with `Edge::Both`, see
+    /// `PulseFigures`.
+    pub fn frequency(&self, speeds: &ClockSpeeds, delta: u32) -> f32 {
+        if delta == 0 {
+            return 0.
+        }
+        let edges = match self.timer.read_ccmr(self.channel)
+            .get_ic(self.channel).get_icpsc() {
+            IcPrescaler::Div1 => 1,
+            IcPrescaler::Div2 => 2,
+            IcPrescaler::Div4 => 4,
+            IcPrescaler::Div8 => 8,
+        };
+        self.tick_hz(speeds) * (edges as f32) / (delta as f32)
+    }
+}
+
+/// Figures for one cycle of a pulse train, computed from three successive
+/// captures taken with `Edge::Both`: the start of a pulse, its end, and the
+/// start of the next pulse.
+#[derive(Copy, Clone, Debug)]
+pub struct PulseFigures {
+    pub frequency_hz: f32,
+    /// Fraction of the period occupied by the pulse, from 0 to 1.
+    pub duty: f32,
+    pub width_s: f32,
+}
+
+impl PulseFigures {
+    /// Computes the figures from timestamps in ticks of `tick_hz` (see
+    /// `Capture::tick_hz`).  Returns `None` if the timestamps describe an
+    /// empty period.
+    pub fn from_edges(tick_hz: f32, start: u32, end: u32, next_start: u32)
+        -> Option<PulseFigures> {
+        let period = next_start.wrapping_sub(start);
+        let width = end.wrapping_sub(start);
+        if period == 0 || width > period {
+            return None
+        }
+        Some(PulseFigures {
+            frequency_hz: tick_hz / (period as f32),
+            duty: (width as f32) / (period as f32),
+            width_s: (width as f32) / tick_hz,
+        })
+    }
+}
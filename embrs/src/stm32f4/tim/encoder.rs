@@ -0,0 +1,240 @@
+//! Quadrature encoder interface.
+//!
+//! In encoder mode the timer counts the edges of a quadrature signal on TI1
+//! and TI2 (channels 1 and 2), up or down depending on their phase.  The
+//! counter is only 16 bits wide on most timers, so this driver extends it in
+//! software by counting overflows and underflows in the update interrupt.
+//! The application must route that interrupt to `Encoder::handle_interrupt`.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{CaptureCompareSelection, Channel, Direction, IcPrescaler,
+            Polarity, SlaveMode, Sr, Timer};
+
+/// Which edges the counter counts.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum EncoderMode {
+    /// Count edges of TI1 only: two counts per quadrature cycle.
+    Ti1,
+    /// Count edges of TI2 only: two counts per quadrature cycle.
+    Ti2,
+    /// Count edges of both inputs: four counts per quadrature cycle.
+    Both,
+}
+
+/// Encoder configuration.
+#[derive(Copy, Clone, Debug)]
+pub struct EncoderConfig {
+    pub mode: EncoderMode,
+    /// Input filter applied to both inputs, encoded as for
+    /// `IcConfig::with_icf` (0-15).
+    pub filter: u32,
+    /// Inverts TI1, reversing the direction of count.
+    pub invert_ti1: bool,
+    /// Inverts TI2, reversing the direction of count.
+    pub invert_ti2: bool,
+}
+
+impl Default for EncoderConfig {
+    fn default() -> EncoderConfig {
+        EncoderConfig {
+            mode: EncoderMode::Both,
+            filter: 0,
+            invert_ti1: false,
+            invert_ti2: false,
+        }
+    }
+}
+
+/// A quadrature encoder on channels 1 and 2 of a timer.
+pub struct Encoder {
+    timer: &'static Timer,
+    /// Net number of counter overflows (positive) and underflows (negative),
+    /// in two's complement.
+    wraps: AtomicUsize,
+}
+
+impl Encoder {
+    /// Creates an encoder driver for `timer`, which must have at least two
+    /// channels (this is checked by `configure`).
+    pub const fn new(timer: &'static Timer) -> Encoder {
+        Encoder {
+            timer: timer,
+            wraps: AtomicUsize::new(0),
+        }
+    }
+
+    /// Configures the timer for encoder mode, zeroes the position, enables
+    /// the update interrupt, and starts counting.  The timer's clock must
+    /// already be enabled.
+    ///
+    /// # Panics
+    ///
+    /// If the timer lacks channel 2.
+    pub fn configure(&self, cfg: &EncoderConfig) {
+        assert!(self.timer.has_channel(Channel::Ch2));
+        let t = self.timer;
+
+        t.stop();
+        t.update_smcr(|v| v.with_sms(SlaveMode::Disabled));
+        for &(ch, invert) in &[(Channel::Ch1, cfg.invert_ti1),
+                               (Channel::Ch2, cfg.invert_ti2)] {
+            t.update_ccmr(ch, |v| {
+                let ic = v.get_ic(ch)
+                    .with_ccs(CaptureCompareSelection::InputDirect)
+                    .with_icf(cfg.filter)
+                    .with_icpsc(IcPrescaler::Div1);
+                v.with_ic(ch, ic)
+            });
+            t.update_ccer(|v| {
+                let c = v.get_channel(ch)
+                    .with_ccp(if invert {
+                        Polarity::ActiveLow
+                    } else {
+                        Polarity::ActiveHigh
+                    })
+                    .with_ccnp(Polarity::ActiveHigh);
+                v.with_channel(ch, c)
+            });
+        }
+        t.update_smcr(|v| v.with_sms(match cfg.mode {
+            EncoderMode::Ti1 => SlaveMode::Encoder1,
+            EncoderMode::Ti2 => SlaveMode::Encoder2,
+            EncoderMode::Both => SlaveMode::Encoder3,
+        }));
+
+        t.set_prescaler(0);
+        t.set_auto_reload(t.counter_max());
+        // Only genuine overflow/underflow should count as a wrap.
+        t.update_cr1(|v| v.with_urs(true));
+        t.load_preloads();
+        self.reset();
+        t.clear_sr(Sr::default().with_uif(true));
+        t.update_dier(|v| v.with_uie(true));
+        t.start()
+    }
+
+    /// Sets the position to zero.
+    pub fn reset(&self) {
+        self.timer.set_counter(0);
+        self.wraps.store(0, Ordering::Relaxed)
+    }
+
+    /// Number of counts between wraps of the hardware counter, or zero for a
+    /// 32-bit counter, whose wraps match those of the `i32` position.
+    fn period(&self) -> u32 {
+        self.timer.counter_max().wrapping_add(1)
+    }
+
+    /// Decides whether a wrap that left the counter at `cnt` was an underflow
+    /// (counter reloaded near the top) rather than an overflow (counter
+    /// cleared).  This assumes the counter has moved less than half its range
+    /// since the wrap, which holds unless the interrupt is very late.
+    fn is_underflow(&self, cnt: u32) -> bool {
+        cnt > self.timer.counter_max() / 2
+    }
+
+    /// Handles the timer's update interrupt, accounting for counter wraps.
+    pub fn handle_interrupt(&self) {
+        if !self.timer.read_sr().get_uif() {
+            return
+        }
+        self.timer.clear_sr(Sr::default().with_uif(true));
+
+        let delta = if self.is_underflow(self.timer.get_counter()) {
+            !0
+        } else {
+            1
+        };
+        let w = self.wraps.load(Ordering::Relaxed);
+        self.wraps.store(w.wrapping_add(delta), Ordering::Relaxed)
+    }
+
+    /// Gets the extended position, in counts.  This wraps (as an `i32`) after
+    /// 2^31 counts in either direction.
+    ///
+    /// If a wrap has happened but not been handled yet -- say, because this
+    /// is called with interrupts masked -- it is accounted for here.
+    pub fn position(&self) -> i32 {
+        // A wrap between reading the counter and reading UIF would pair a
+        // count from before the wrap with the flag from after it, so UIF is
+        // read on both sides of the counter, and the lot retried if it (or
+        // the handled wrap count) changed meanwhile.
+        let (mut wraps, mut cnt, mut pending);
+        loop {
+            wraps = self.wraps.load(Ordering::Relaxed);
+            pending = self.timer.read_sr().get_uif();
+            cnt = self.timer.get_counter();
+            if self.timer.read_sr().get_uif() == pending
+                && self.wraps.load(Ordering::Relaxed) == wraps {
+                break
+            }
+        }
+
+        let wraps = wraps as u32;
+        let wraps = if !pending {
+            wraps
+        } else if self.is_underflow(cnt) {
+            wraps.wrapping_sub(1)
+        } else {
+            wraps.wrapping_add(1)
+        };
+        wraps.wrapping_mul(self.period()).wrapping_add(cnt) as i32
+    }
+
+    /// Reads the direction the counter last moved in.
+    pub fn is_counting_down(&self) -> bool {
+        self.timer.read_cr1().get_dir() == Direction::Down
+    }
+
+    /// Disables the update interrupt and stops counting.
+    pub fn stop(&self) {
+        self.timer.update_dier(|v| v.with_uie(false));
+        self.timer.stop()
+    }
+}
+
+/// Estimates velocity from successive positions, smoothed with a
+/// first-order low-pass filter.
+///
+/// Call `update` at regular-ish intervals (e.g. from a control loop) with the
+/// current position and the time since the previous call.
+#[derive(Copy, Clone, Debug)]
+pub struct VelocityEstimator {
+    last: i32,
+    rate: f32,
+    alpha: f32,
+}
+
+impl VelocityEstimator {
+    /// Creates an estimator starting at `position`.  `alpha` (0 to 1) weights
+    /// each new sample against the history: 1 disables smoothing, smaller
+    /// values trade responsiveness for noise rejection.
+    pub const fn new(position: i32, alpha: f32) -> VelocityEstimator {
+        VelocityEstimator {
+            last: position,
+            rate: 0.,
+            alpha: alpha,
+        }
+    }
+
+    /// Feeds in a new `position`, taken `dt` seconds after the last, and
+    /// returns the updated estimate in counts per second.  A non-positive
+    /// `dt` leaves the estimate unchanged.
+    pub fn update(&mut self, position: i32, dt: f32) -> f32 {
+        // Differences are taken with wrapping arithmetic, so passing through
+        // the i32 wrap point is harmless.
+        let delta = position.wrapping_sub(self.last);
+        self.last = position;
+        if dt > 0. {
+            let sample = delta as f32 / dt;
+            self.rate += self.alpha * (sample - self.rate);
+        }
+        self.rate
+    }
+
+    /// Gets the current estimate, in counts per second.
+    pub fn rate(&self) -> f32 {
+        self.rate
+    }
+}
@@ -0,0 +1,875 @@
+//! Timer (TIM) register layer.
+//!
+//! The STM32F4 timers come in three families that share one register layout:
+//!
+//! - The advanced-control timers TIM1 and TIM8, which add complementary
+//!   outputs, a repetition counter, and the break/dead-time register (BDTR).
+//! - The general-purpose timers TIM2-5 and TIM9-14, with between one and four
+//!   capture/compare channels.  TIM2 and TIM5 have 32-bit counters.
+//! - The basic timers TIM6 and TIM7, which only count.
+//!
+//! Registers a timer doesn't implement read as zero and ignore writes, so the
+//! `Timer` driver doesn't try to stop you from using them; it does record
+//! which family each timer belongs to, for the higher-level drivers.
+
+use arm_m::reg::{mmio, Reg, WoReg};
+use super::gpio::{self, Pins};
+use super::rcc::{ApbPeripheral, ClockSpeeds, RCC};
+
+pub mod advanced;
+pub mod basic;
+pub mod capture;
+pub mod encoder;
+pub mod pwm;
+pub mod trigger;
+
+pub use self::advanced::ComplementaryPwm;
+pub use self::basic::{GapTick, Tick};
+pub use self::capture::Capture;
+pub use self::encoder::Encoder;
+pub use self::pwm::Pwm;
+
+/*******************************************************************************
+ * Peripheral register layout.
+ */
+
+#[repr(C, packed)]
+pub struct Registers {
+    pub cr1:  Reg<Cr1>,
+    pub cr2:  Reg<Cr2>,
+    pub smcr: Reg<Smcr>,
+    pub dier: Reg<Dier>,
+    pub sr:   Reg<Sr>,
+    pub egr:  WoReg<Egr>,
+    /// Capture/compare mode registers CCMR1 and CCMR2, which configure
+    /// channels 1-2 and 3-4 respectively.
+    pub ccmr: [Reg<Ccmr>; 2],
+    pub ccer: Reg<Ccer>,
+    pub cnt:  Reg<u32>,
+    pub psc:  Reg<u32>,
+    pub arr:  Reg<u32>,
+    pub rcr:  Reg<u32>,
+    /// Capture/compare registers CCR1-CCR4.
+    pub ccr:  [Reg<u32>; 4],
+    pub bdtr: Reg<Bdtr>,
+    /// DMA control register, which sets up burst transfers through `dmar`.
+    pub dcr:  Reg<Dcr>,
+    /// DMA address for full transfer: each access is redirected to the
+    /// next register of the burst described by `dcr`.
+    pub dmar: Reg<u32>,
+    /// Option register, implemented only on TIM2, TIM5 and TIM11.
+    pub or:   Reg<u32>,
+}
+
+/// Byte offset of `ccr[0]` in the register block, for `set_dma_burst`.
+pub const CCR1_OFFSET : usize = 0x34;
+
+register_layout! {
+    fn check_layout: Registers [0x54] {
+        cr1 @ 0x00,
+        cr2 @ 0x04,
+        smcr @ 0x08,
+        dier @ 0x0C,
+        sr @ 0x10,
+        egr @ 0x14,
+        ccmr @ 0x18,
+        ccer @ 0x20,
+        cnt @ 0x24,
+        psc @ 0x28,
+        arr @ 0x2C,
+        rcr @ 0x30,
+        ccr @ 0x34,
+        bdtr @ 0x44,
+        dcr @ 0x48,
+        dmar @ 0x4C,
+        or @ 0x50,
+    }
+}
+
+bit_wrappers! {
+    pub struct Cr1(pub u32);
+    pub struct Cr2(pub u32);
+    pub struct Smcr(pub u32);
+    pub struct Dier(pub u32);
+    pub struct Sr(pub u32);
+    pub struct Egr(pub u32);
+    /// Capture/compare mode register.  Each register holds two eight-bit
+    /// halves, one per channel, whose meaning depends on whether the channel
+    /// is an output (`OcConfig`) or an input.
+    pub struct Ccmr(pub u32);
+    /// One channel's half of a `Ccmr`, in output compare mode.
+    pub struct OcConfig(pub u32);
+    /// One channel's half of a `Ccmr`, in input capture mode.
+    pub struct IcConfig(pub u32);
+    /// Capture/compare enable register.  Like `Ccmr`, this is an array of
+    /// per-channel fields (`CcerChannel`), four bits each.
+    pub struct Ccer(pub u32);
+    /// One channel's four bits of `Ccer`.
+    pub struct CcerChannel(pub u32);
+    pub struct Bdtr(pub u32);
+    pub struct Dcr(pub u32);
+}
+
+impl Cr1 {
+    bitfield_accessors! {
+        /// Ratio between the timer clock and the sampling clock used by the
+        /// dead-time generator and digital filters.
+        pub total [9:8] get_ckd / with_ckd: ClockDivision,
+        /// Buffers ARR, so that changes take effect at the next update event.
+        pub total [7] get_arpe / with_arpe: bool,
+        pub total [6:5] get_cms / with_cms: Alignment,
+        pub total [4] get_dir / with_dir: Direction,
+        /// One-pulse mode: stop the counter at the next update event.
+        pub total [3] get_opm / with_opm: bool,
+        /// Restricts update interrupts and DMA requests to counter
+        /// overflow/underflow, excluding `UG` and slave mode resets.
+        pub total [2] get_urs / with_urs: bool,
+        pub total [1] get_udis / with_udis: bool,
+        pub total [0] get_cen / with_cen: bool,
+    }
+}
+
+impl Cr2 {
+    bitfield_accessors! {
+        pub total [14] get_ois4 / with_ois4: bool,
+        pub total [13] get_ois3n / with_ois3n: bool,
+        pub total [12] get_ois3 / with_ois3: bool,
+        pub total [11] get_ois2n / with_ois2n: bool,
+        pub total [10] get_ois2 / with_ois2: bool,
+        pub total [ 9] get_ois1n / with_ois1n: bool,
+        pub total [ 8] get_ois1 / with_ois1: bool,
+        pub total [ 7] get_ti1s / with_ti1s: bool,
+        pub total [6:4] get_mms / with_mms: MasterMode,
+        pub total [ 3] get_ccds / with_ccds: bool,
+        pub total [ 2] get_ccus / with_ccus: bool,
+        pub total [ 0] get_ccpc / with_ccpc: bool,
+    }
+
+    /// Sets the idle state of `channel`'s output: its level while `moe` is
+    /// clear (after dead time).
+    pub fn with_ois(self, channel: Channel, v: bool) -> Self {
+        let bit = 1 << (8 + 2 * channel.index());
+        Cr2(if v { self.0 | bit } else { self.0 & !bit })
+    }
+
+    /// Sets the idle state of `channel`'s complementary output.
+    pub fn with_oisn(self, channel: Channel, v: bool) -> Self {
+        let bit = 1 << (9 + 2 * channel.index());
+        Cr2(if v { self.0 | bit } else { self.0 & !bit })
+    }
+}
+
+impl Smcr {
+    bitfield_accessors! {
+        pub total [15] get_etp / with_etp: bool,
+        pub total [14] get_ece / with_ece: bool,
+        pub total [13:12] get_etps / with_etps: u32,
+        pub total [11:8] get_etf / with_etf: u32,
+        /// Master/slave mode: delays the trigger input slightly so that
+        /// this timer and its slaves start in step.
+        pub total [7] get_msm / with_msm: bool,
+        pub total [6:4] get_ts / with_ts: TriggerSelect,
+        pub total [2:0] get_sms / with_sms: SlaveMode,
+    }
+}
+
+impl Dier {
+    bitfield_accessors! {
+        pub total [14] get_tde / with_tde: bool,
+        pub total [13] get_comde / with_comde: bool,
+        pub total [12] get_cc4de / with_cc4de: bool,
+        pub total [11] get_cc3de / with_cc3de: bool,
+        pub total [10] get_cc2de / with_cc2de: bool,
+        pub total [ 9] get_cc1de / with_cc1de: bool,
+        pub total [ 8] get_ude / with_ude: bool,
+        pub total [ 7] get_bie / with_bie: bool,
+        pub total [ 6] get_tie / with_tie: bool,
+        pub total [ 5] get_comie / with_comie: bool,
+        pub total [ 4] get_cc4ie / with_cc4ie: bool,
+        pub total [ 3] get_cc3ie / with_cc3ie: bool,
+        pub total [ 2] get_cc2ie / with_cc2ie: bool,
+        pub total [ 1] get_cc1ie / with_cc1ie: bool,
+        pub total [ 0] get_uie / with_uie: bool,
+    }
+
+    /// Sets or clears the capture/compare interrupt enable of `channel`.
+    pub fn with_ccie(self, channel: Channel, v: bool) -> Self {
+        let bit = 1 << (1 + channel.index());
+        Dier(if v { self.0 | bit } else { self.0 & !bit })
+    }
+}
+
+impl Sr {
+    bitfield_accessors! {
+        pub total [12] get_cc4of / with_cc4of: bool,
+        pub total [11] get_cc3of / with_cc3of: bool,
+        pub total [10] get_cc2of / with_cc2of: bool,
+        pub total [ 9] get_cc1of / with_cc1of: bool,
+        pub total [ 7] get_bif / with_bif: bool,
+        pub total [ 6] get_tif / with_tif: bool,
+        pub total [ 5] get_comif / with_comif: bool,
+        pub total [ 4] get_cc4if / with_cc4if: bool,
+        pub total [ 3] get_cc3if / with_cc3if: bool,
+        pub total [ 2] get_cc2if / with_cc2if: bool,
+        pub total [ 1] get_cc1if / with_cc1if: bool,
+        pub total [ 0] get_uif / with_uif: bool,
+    }
+
+    /// Checks the capture/compare flag of `channel`.
+    pub fn get_ccif(self, channel: Channel) -> bool {
+        (self.0 & (1 << (1 + channel.index()))) != 0
+    }
+
+    /// Sets or clears the capture/compare flag of `channel`.
+    pub fn with_ccif(self, channel: Channel, v: bool) -> Self {
+        let bit = 1 << (1 + channel.index());
+        Sr(if v { self.0 | bit } else { self.0 & !bit })
+    }
+
+    /// Checks the overcapture flag of `channel`.
+    pub fn get_ccof(self, channel: Channel) -> bool {
+        (self.0 & (1 << (9 + channel.index()))) != 0
+    }
+
+    /// Sets or clears the overcapture flag of `channel`.
+    pub fn with_ccof(self, channel: Channel, v: bool) -> Self {
+        let bit = 1 << (9 + channel.index());
+        Sr(if v { self.0 | bit } else { self.0 & !bit })
+    }
+}
+
+impl Egr {
+    bitfield_accessors! {
+        pub total [7] get_bg / with_bg: bool,
+        pub total [6] get_tg / with_tg: bool,
+        pub total [5] get_comg / with_comg: bool,
+        pub total [4] get_cc4g / with_cc4g: bool,
+        pub total [3] get_cc3g / with_cc3g: bool,
+        pub total [2] get_cc2g / with_cc2g: bool,
+        pub total [1] get_cc1g / with_cc1g: bool,
+        /// Reinitializes the counter and loads the preload registers.
+        pub total [0] get_ug / with_ug: bool,
+    }
+}
+
+impl Ccmr {
+    bitfield_accessors! {
+        /// Configuration of the second channel in this register (2 or 4).
+        pub total [15:8] get_high / with_high: u32,
+        /// Configuration of the first channel in this register (1 or 3).
+        pub total [ 7:0] get_low / with_low: u32,
+    }
+
+    /// Gets the output compare configuration of `channel`, which must be one
+    /// of the two channels this register controls.
+    pub fn get_oc(self, channel: Channel) -> OcConfig {
+        OcConfig(if channel.is_high_half() {
+            self.get_high()
+        } else {
+            self.get_low()
+        })
+    }
+
+    /// Replaces the output compare configuration of `channel`.
+    pub fn with_oc(self, channel: Channel, v: OcConfig) -> Self {
+        if channel.is_high_half() {
+            self.with_high(v.0)
+        } else {
+            self.with_low(v.0)
+        }
+    }
+
+    /// Gets the input capture configuration of `channel`, which must be one
+    /// of the two channels this register controls.
+    pub fn get_ic(self, channel: Channel) -> IcConfig {
+        IcConfig(self.get_oc(channel).0)
+    }
+
+    /// Replaces the input capture configuration of `channel`.
+    pub fn with_ic(self, channel: Channel, v: IcConfig) -> Self {
+        self.with_oc(channel, OcConfig(v.0))
+    }
+}
+
+impl OcConfig {
+    bitfield_accessors! {
+        /// Clears the output when ETRF goes high.
+        pub total [7] get_occe / with_occe: bool,
+        pub total [6:4] get_ocm / with_ocm: OutputCompareMode,
+        /// Buffers CCR, so that changes take effect at the next update event.
+        pub total [3] get_ocpe / with_ocpe: bool,
+        pub total [2] get_ocfe / with_ocfe: bool,
+        pub total [1:0] get_ccs / with_ccs: CaptureCompareSelection,
+    }
+}
+
+impl IcConfig {
+    bitfield_accessors! {
+        /// Digital filter: the sampling rate and number of consecutive equal
+        /// samples required to accept an edge, encoded per RM0090 (0 for no
+        /// filtering, up to 15 for eight samples at f_DTS/32).
+        pub total [7:4] get_icf / with_icf: u32,
+        pub total [3:2] get_icpsc / with_icpsc: IcPrescaler,
+        pub total [1:0] get_ccs / with_ccs: CaptureCompareSelection,
+    }
+}
+
+impl Ccer {
+    bitfield_accessors! {
+        pub total [15:12] get_cc4 / with_cc4: u32,
+        pub total [11: 8] get_cc3 / with_cc3: u32,
+        pub total [ 7: 4] get_cc2 / with_cc2: u32,
+        pub total [ 3: 0] get_cc1 / with_cc1: u32,
+    }
+
+    /// Gets the enable and polarity settings for `channel`.
+    pub fn get_channel(self, channel: Channel) -> CcerChannel {
+        CcerChannel(match channel {
+            Channel::Ch1 => self.get_cc1(),
+            Channel::Ch2 => self.get_cc2(),
+            Channel::Ch3 => self.get_cc3(),
+            Channel::Ch4 => self.get_cc4(),
+        })
+    }
+
+    /// Replaces the enable and polarity settings for `channel`.
+    pub fn with_channel(self, channel: Channel, v: CcerChannel) -> Self {
+        match channel {
+            Channel::Ch1 => self.with_cc1(v.0),
+            Channel::Ch2 => self.with_cc2(v.0),
+            Channel::Ch3 => self.with_cc3(v.0),
+            Channel::Ch4 => self.with_cc4(v.0),
+        }
+    }
+}
+
+impl CcerChannel {
+    bitfield_accessors! {
+        /// Complementary output polarity (advanced timers only).
+        pub total [3] get_ccnp / with_ccnp: Polarity,
+        /// Complementary output enable (advanced timers only).
+        pub total [2] get_ccne / with_ccne: bool,
+        pub total [1] get_ccp / with_ccp: Polarity,
+        pub total [0] get_cce / with_cce: bool,
+    }
+}
+
+impl Bdtr {
+    bitfield_accessors! {
+        /// Main output enable.  On the advanced timers, no output is driven
+        /// until this is set.
+        pub total [15] get_moe / with_moe: bool,
+        /// Automatic output enable: set `moe` at the next update event.
+        pub total [14] get_aoe / with_aoe: bool,
+        pub total [13] get_bkp / with_bkp: BreakPolarity,
+        pub total [12] get_bke / with_bke: bool,
+        /// Off-state selection for run mode: drive disabled outputs to their
+        /// inactive level (rather than releasing them) while `moe` is set.
+        pub total [11] get_ossr / with_ossr: bool,
+        /// Off-state selection for idle mode: drive outputs to their idle
+        /// level (rather than releasing them) while `moe` is clear.
+        pub total [10] get_ossi / with_ossi: bool,
+        pub total [9:8] get_lock / with_lock: LockLevel,
+        /// Dead-time generator setup; see `advanced::DeadTime`.
+        pub total [7:0] get_dtg / with_dtg: u32,
+    }
+}
+
+impl Dcr {
+    bitfield_accessors! {
+        /// DMA burst length: the number of registers written (or read) for
+        /// each DMA request, minus one.
+        pub total [12:8] get_dbl / with_dbl: u32,
+        /// DMA base address: the first register of each burst, as a word
+        /// offset from `cr1`.
+        pub total [4:0] get_dba / with_dba: u32,
+    }
+}
+
+bit_enums! {
+    pub bit_enum ClockDivision {
+        Div1 = 0b00,
+        Div2 = 0b01,
+        Div4 = 0b10,
+        Reserved = 0b11,
+    }
+
+    /// Counter alignment: edge-aligned, or one of the three center-aligned
+    /// modes (which differ in when compare interrupts fire).
+    pub bit_enum Alignment {
+        Edge = 0b00,
+        Center1 = 0b01,
+        Center2 = 0b10,
+        Center3 = 0b11,
+    }
+
+    pub bit_enum Direction {
+        Up = 0,
+        Down = 1,
+    }
+
+    pub bit_enum OutputCompareMode {
+        Frozen = 0b000,
+        ActiveOnMatch = 0b001,
+        InactiveOnMatch = 0b010,
+        Toggle = 0b011,
+        ForceInactive = 0b100,
+        ForceActive = 0b101,
+        /// Active while the counter is below CCR (counting up).
+        Pwm1 = 0b110,
+        /// Inactive while the counter is below CCR (counting up).
+        Pwm2 = 0b111,
+    }
+
+    /// Direction of a capture/compare channel, and for inputs, which timer
+    /// input it captures.
+    pub bit_enum CaptureCompareSelection {
+        Output = 0b00,
+        /// The channel's own input (TI1 for channel 1, TI2 for channel 2...).
+        InputDirect = 0b01,
+        /// The other input of the pair (TI2 for channel 1, TI1 for channel 2,
+        /// etc.).
+        InputIndirect = 0b10,
+        /// The internal trigger input, TRC.
+        InputTrc = 0b11,
+    }
+
+    /// What a timer sends out on TRGO, to its slaves (and to the ADC and DAC
+    /// trigger inputs).
+    pub bit_enum MasterMode {
+        /// The `UG` bit, or a slave mode reset.
+        Reset = 0b000,
+        /// The counter enable.
+        Enable = 0b001,
+        /// Update events.
+        Update = 0b010,
+        /// A pulse when channel 1 captures or matches.
+        ComparePulse = 0b011,
+        Oc1Ref = 0b100,
+        Oc2Ref = 0b101,
+        Oc3Ref = 0b110,
+        Oc4Ref = 0b111,
+    }
+
+    /// Source of a timer's trigger input, TRGI.  See `trigger::Route` for
+    /// which timer drives each internal trigger.
+    pub bit_enum TriggerSelect {
+        Itr0 = 0b000,
+        Itr1 = 0b001,
+        Itr2 = 0b010,
+        Itr3 = 0b011,
+        /// Edges of TI1.
+        Ti1FEdge = 0b100,
+        /// Filtered TI1.
+        Ti1Fp1 = 0b101,
+        /// Filtered TI2.
+        Ti2Fp2 = 0b110,
+        /// The external trigger input, ETR.
+        Etrf = 0b111,
+    }
+
+    /// Input capture prescaler: capture on every Nth edge.
+    pub bit_enum IcPrescaler {
+        Div1 = 0b00,
+        Div2 = 0b01,
+        Div4 = 0b10,
+        Div8 = 0b11,
+    }
+
+    /// How a timer responds to its trigger input (or, in the encoder modes,
+    /// to TI1 and TI2).
+    pub bit_enum SlaveMode {
+        Disabled = 0b000,
+        /// Count TI1 edges, in a direction depending on the level of TI2.
+        Encoder1 = 0b001,
+        /// Count TI2 edges, in a direction depending on the level of TI1.
+        Encoder2 = 0b010,
+        /// Count edges on both TI1 and TI2 (full quadrature resolution).
+        Encoder3 = 0b011,
+        /// Reset the counter on a trigger rising edge.
+        Reset = 0b100,
+        /// Count only while the trigger is high.
+        Gated = 0b101,
+        /// Start the counter on a trigger rising edge.
+        Trigger = 0b110,
+        /// Count trigger rising edges.
+        ExternalClock1 = 0b111,
+    }
+
+    /// Output (or, for inputs, edge) polarity.  Note that the break input's
+    /// polarity bit has the opposite sense, and uses `BreakPolarity`.
+    pub bit_enum Polarity {
+        ActiveHigh = 0,
+        ActiveLow = 1,
+    }
+
+    pub bit_enum BreakPolarity {
+        ActiveLow = 0,
+        ActiveHigh = 1,
+    }
+
+    /// Write protection of the advanced timers' safety-related settings.
+    /// Once set to anything other than `Off`, the level can't be changed
+    /// until reset.
+    pub bit_enum LockLevel {
+        Off = 0b00,
+        /// Protects BDTR's dead time, break, and automatic output settings,
+        /// and the OIS bits in CR2.
+        Level1 = 0b01,
+        /// Also protects the CCER polarity bits and the off-state selections.
+        Level2 = 0b10,
+        /// Also protects the output compare modes and preload enables.
+        Level3 = 0b11,
+    }
+}
+
+/*******************************************************************************
+ * Driver.
+ */
+
+/// Ways that timebase computation can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum TimebaseError {
+    /// The requested frequency leaves less than two counts per period.
+    TooFast,
+    /// The requested frequency can't be reached even with the largest
+    /// prescaler.
+    TooSlow,
+}
+
+/// A computed prescaler and auto-reload setting.
+#[derive(Copy, Clone, Debug)]
+pub struct Timebase {
+    /// Value for the prescaler register; the counter runs at the timer clock
+    /// divided by `psc + 1`.
+    pub psc: u32,
+    /// Value for the auto-reload register; each period is `arr + 1` counts.
+    pub arr: u32,
+    /// Frequency this configuration actually achieves.
+    pub actual_hz: f32,
+}
+
+impl Timebase {
+    /// Computes the timebase reaching closest to `hz` from a timer clock of
+    /// `clock_hz`, using the smallest prescaler that lets the period fit in
+    /// `arr_max` -- and thus giving the finest resolution within each period.
+    pub fn compute(clock_hz: f32, hz: f32, arr_max: u32)
+        -> Result<Timebase, TimebaseError> {
+        if !(hz > 0.) {
+            return Err(TimebaseError::TooSlow)
+        }
+
+        let ticks = clock_hz / hz;
+        if ticks < 2. {
+            return Err(TimebaseError::TooFast)
+        }
+
+        // Prescale by the smallest integer that brings the period in range,
+        // i.e. the ceiling of this ratio.  (No `ceil` in `core`.)
+        let ratio = ticks / (arr_max as f32 + 1.);
+        let mut div = ratio as u32;
+        if (div as f32) < ratio {
+            div += 1
+        }
+        if div == 0 {
+            div = 1
+        }
+        if div > 0x1_0000 {
+            return Err(TimebaseError::TooSlow)
+        }
+
+        let period = (ticks / (div as f32) + 0.5) as u32;
+        let arr = if period - 1 > arr_max { arr_max } else { period - 1 };
+        Ok(Timebase {
+            psc: div - 1,
+            arr: arr,
+            actual_hz: clock_hz / ((div as f32) * (arr as f32 + 1.)),
+        })
+    }
+}
+
+/// Names the four capture/compare channels.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Channel {
+    Ch1,
+    Ch2,
+    Ch3,
+    Ch4,
+}
+
+impl Channel {
+    /// Zero-based index of the channel, for indexing e.g. `ccr`.
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Index of the `Ccmr` register that controls this channel.
+    fn ccmr_index(self) -> usize {
+        self.index() / 2
+    }
+
+    fn is_high_half(self) -> bool {
+        (self.index() & 1) != 0
+    }
+}
+
+/// The families of timer, as described in the module docs.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Kind {
+    Advanced,
+    GeneralPurpose,
+    Basic,
+}
+
+pub struct Timer {
+    reg: usize,
+    /// Name of this timer in the RCC.
+    peripheral: ApbPeripheral,
+    kind: Kind,
+    /// Number of capture/compare channels.
+    channels: usize,
+    /// Largest counter value, determined by the counter width.
+    counter_max: u32,
+    /// Alternate function that routes this timer's channels to pins, if it
+    /// has any.
+    af: Option<gpio::Function>,
+}
+
+macro_rules! reg_accessors {
+    ($name:ident, $ty:ident, $read:ident, $write:ident, $update:ident) => {
+        pub fn $write(&self, v: $ty) {
+            self.reg().$name.set(v)
+        }
+
+        pub fn $read(&self) -> $ty {
+            self.reg().$name.get()
+        }
+
+        pub fn $update<F: FnOnce($ty) -> $ty>(&self, f: F) {
+            self.$write(f(self.$read()))
+        }
+    };
+}
+
+impl Timer {
+    fn reg(&self) -> &Registers {
+        unsafe {
+            mmio(self.reg)
+        }
+    }
+
+    reg_accessors!(cr1, Cr1, read_cr1, write_cr1, update_cr1);
+    reg_accessors!(cr2, Cr2, read_cr2, write_cr2, update_cr2);
+    reg_accessors!(smcr, Smcr, read_smcr, write_smcr, update_smcr);
+    reg_accessors!(dier, Dier, read_dier, write_dier, update_dier);
+    reg_accessors!(ccer, Ccer, read_ccer, write_ccer, update_ccer);
+    reg_accessors!(bdtr, Bdtr, read_bdtr, write_bdtr, update_bdtr);
+    reg_accessors!(dcr, Dcr, read_dcr, write_dcr, update_dcr);
+
+    pub fn read_sr(&self) -> Sr {
+        self.reg().sr.get()
+    }
+
+    /// Clears the status flags set in `flags`.  The flags are
+    /// clear-on-write-zero, so this doesn't disturb flags set by the hardware
+    /// concurrently.
+    pub fn clear_sr(&self, flags: Sr) {
+        self.reg().sr.set(Sr(!flags.0))
+    }
+
+    /// Generates the events set in `events`.
+    pub fn write_egr(&self, events: Egr) {
+        self.reg().egr.set(events)
+    }
+
+    pub fn read_ccmr(&self, channel: Channel) -> Ccmr {
+        self.reg().ccmr[channel.ccmr_index()].get()
+    }
+
+    /// Updates the `Ccmr` register that controls `channel`.  Note that this
+    /// register is shared with one other channel.
+    pub fn update_ccmr<F: FnOnce(Ccmr) -> Ccmr>(&self, channel: Channel, f: F) {
+        self.reg().ccmr[channel.ccmr_index()].update(f)
+    }
+
+    /// Enables this timer's clock in the RCC.
+    pub fn enable_clock(&self) {
+        RCC.enable_clock(self.peripheral)
+    }
+
+    /// Gets the frequency at which this timer's prescaler is clocked.
+    pub fn get_clock(&self, speeds: &ClockSpeeds) -> f32 {
+        speeds.get_timer_clock_for(self.peripheral)
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    /// Checks whether this timer implements `channel`.
+    pub fn has_channel(&self, channel: Channel) -> bool {
+        channel.index() < self.channels
+    }
+
+    /// Largest value the counter (and so ARR and CCR) can hold: `0xFFFF`,
+    /// except for the 32-bit counters of TIM2 and TIM5.
+    pub fn counter_max(&self) -> u32 {
+        self.counter_max
+    }
+
+    pub fn get_counter(&self) -> u32 {
+        self.reg().cnt.get()
+    }
+
+    pub fn set_counter(&self, v: u32) {
+        self.reg().cnt.set(v)
+    }
+
+    /// Sets the prescaler.  The counter is clocked at the timer clock divided
+    /// by `psc + 1`.  Takes effect at the next update event.
+    pub fn set_prescaler(&self, psc: u32) {
+        self.reg().psc.set(psc)
+    }
+
+    pub fn get_prescaler(&self) -> u32 {
+        self.reg().psc.get()
+    }
+
+    /// Sets the auto-reload value; the counter period is `arr + 1` counts.
+    pub fn set_auto_reload(&self, arr: u32) {
+        self.reg().arr.set(arr)
+    }
+
+    pub fn get_auto_reload(&self) -> u32 {
+        self.reg().arr.get()
+    }
+
+    pub fn set_compare(&self, channel: Channel, v: u32) {
+        self.reg().ccr[channel.index()].set(v)
+    }
+
+    pub fn get_compare(&self, channel: Channel) -> u32 {
+        self.reg().ccr[channel.index()].get()
+    }
+
+    /// Address of `channel`'s compare register, for use as a DMA peripheral
+    /// address.
+    pub fn ccr_address(&self, channel: Channel) -> *const () {
+        &self.reg().ccr[channel.index()] as *const Reg<u32> as *const ()
+    }
+
+    /// Address of the DMA burst register, DMAR, for use as a DMA peripheral
+    /// address with `set_dma_burst`.
+    pub fn dmar_address(&self) -> *const () {
+        &self.reg().dmar as *const Reg<u32> as *const ()
+    }
+
+    /// Sets up DMA burst mode: each DMA transfer through `dmar_address` is
+    /// redirected to the next of `count` consecutive registers (from 1 to
+    /// 18), starting with the one at byte offset `offset` in the register
+    /// block -- `CCR1_OFFSET`, say, to load several channels' compare values
+    /// at each update event.
+    ///
+    /// # Panics
+    ///
+    /// If `offset` isn't a word offset within the register block, or
+    /// `count` is out of range.
+    pub fn set_dma_burst(&self, offset: usize, count: usize) {
+        assert!(offset % 4 == 0 && offset <= 0x50);
+        assert!(count >= 1 && count <= 18);
+        self.write_dcr(Dcr::default()
+                       .with_dba((offset / 4) as u32)
+                       .with_dbl((count - 1) as u32))
+    }
+
+    /// Generates an update event, reinitializing the counter and loading the
+    /// preloaded registers (PSC, and ARR and CCR if buffered), without setting
+    /// the update flag or triggering its interrupt.
+    pub fn load_preloads(&self) {
+        let urs = self.read_cr1().get_urs();
+        self.update_cr1(|v| v.with_urs(true));
+        self.write_egr(Egr::default().with_ug(true));
+        self.update_cr1(|v| v.with_urs(urs))
+    }
+
+    /// Applies `tb` to the prescaler and (buffered) auto-reload registers.
+    /// The new prescaler takes effect at the next update event; see
+    /// `load_preloads`.
+    pub fn set_timebase(&self, tb: Timebase) {
+        self.set_prescaler(tb.psc);
+        self.set_auto_reload(tb.arr);
+        self.update_cr1(|v| v.with_arpe(true))
+    }
+
+    /// Starts the counter.
+    pub fn start(&self) {
+        self.update_cr1(|v| v.with_cen(true))
+    }
+
+    /// Stops the counter, leaving its value in place.
+    pub fn stop(&self) {
+        self.update_cr1(|v| v.with_cen(false))
+    }
+
+    /// On the advanced timers, enables or disables all outputs at once using
+    /// the BDTR main output enable.  Other timers have no such gate, and this
+    /// does nothing.
+    pub fn set_main_output(&self, enable: bool) {
+        if self.kind == Kind::Advanced {
+            self.update_bdtr(|v| v.with_moe(enable))
+        }
+    }
+
+    /// Routes `pins` to this timer's channels by selecting the appropriate
+    /// alternate function.  Which pins carry which channels is part-specific;
+    /// see the datasheet, or `pwm::Pwm::route_pins` for checked routing.
+    ///
+    /// # Panics
+    ///
+    /// If this is a basic timer, which has no pins.
+    pub fn configure_pins(&self, pins: &Pins) {
+        let af = self.af.expect("timer has no pins");
+        pins.configure_alternate(af, gpio::Pull::None)
+    }
+}
+
+macro_rules! static_timer {
+    ($name:ident, $addr:expr, $periph:ident, $kind:ident, $channels:expr,
+     $max:expr, $af:expr) => {
+        pub static $name: Timer = Timer {
+            reg: $addr,
+            peripheral: ApbPeripheral::$periph,
+            kind: Kind::$kind,
+            channels: $channels,
+            counter_max: $max,
+            af: $af,
+        };
+    };
+}
+
+static_timer!(TIM1, 0x40010000, Tim1, Advanced, 4, 0xFFFF,
+              Some(gpio::Function::AF1));
+static_timer!(TIM2, 0x40000000, Tim2, GeneralPurpose, 4, 0xFFFF_FFFF,
+              Some(gpio::Function::AF1));
+static_timer!(TIM3, 0x40000400, Tim3, GeneralPurpose, 4, 0xFFFF,
+              Some(gpio::Function::AF2));
+static_timer!(TIM4, 0x40000800, Tim4, GeneralPurpose, 4, 0xFFFF,
+              Some(gpio::Function::AF2));
+static_timer!(TIM5, 0x40000C00, Tim5, GeneralPurpose, 4, 0xFFFF_FFFF,
+              Some(gpio::Function::AF2));
+static_timer!(TIM6, 0x40001000, Tim6, Basic, 0, 0xFFFF, None);
+static_timer!(TIM7, 0x40001400, Tim7, Basic, 0, 0xFFFF, None);
+static_timer!(TIM8, 0x40010400, Tim8, Advanced, 4, 0xFFFF,
+              Some(gpio::Function::AF3));
+static_timer!(TIM9, 0x40014000, Tim9, GeneralPurpose, 2, 0xFFFF,
+              Some(gpio::Function::AF3));
+static_timer!(TIM10, 0x40014400, Tim10, GeneralPurpose, 1, 0xFFFF,
+              Some(gpio::Function::AF3));
+static_timer!(TIM11, 0x40014800, Tim11, GeneralPurpose, 1, 0xFFFF,
+              Some(gpio::Function::AF3));
+static_timer!(TIM12, 0x40001800, Tim12, GeneralPurpose, 2, 0xFFFF,
+              Some(gpio::Function::AF9));
+static_timer!(TIM13, 0x40001C00, Tim13, GeneralPurpose, 1, 0xFFFF,
+              Some(gpio::Function::AF9));
+static_timer!(TIM14, 0x40002000, Tim14, GeneralPurpose, 1, 0xFFFF,
+              Some(gpio::Function::AF9));
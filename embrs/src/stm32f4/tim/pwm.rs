@@ -0,0 +1,273 @@
+//! Pulse-width modulated output on a timer channel.
+//!
+//! All channels of a timer share its prescaler and auto-reload register, and
+//! thus its frequency.  To drive several channels of one timer, configure the
+//! first with `Pwm::configure` and the others with `configure_channel`, then
+//! set each duty cycle independently.
+
+use clock;
+use super::super::gpio::{self, GpioPort, PinMask, Pins};
+use super::super::rcc::{ApbPeripheral, ClockSpeeds};
+use super::{Channel, CaptureCompareSelection, OutputCompareMode, Polarity,
+            Timebase, TimebaseError, Timer};
+
+/// The two PWM output compare modes.  With the counter counting up, mode 1
+/// drives the output active for the first `CCR` counts of each period; mode 2
+/// drives it inactive for those counts.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum PwmMode {
+    Mode1,
+    Mode2,
+}
+
+impl PwmMode {
+    fn to_ocm(self) -> OutputCompareMode {
+        match self {
+            PwmMode::Mode1 => OutputCompareMode::Pwm1,
+            PwmMode::Mode2 => OutputCompareMode::Pwm2,
+        }
+    }
+}
+
+/// Ways that PWM configuration can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum PwmError {
+    /// The timer doesn't implement the requested channel.
+    NoSuchChannel,
+    /// The requested frequency leaves less than two counts per period.
+    TooFast,
+    /// The requested frequency can't be reached even with the largest
+    /// prescaler.
+    TooSlow,
+    /// A pin isn't known to carry the channel.
+    NoSuchPin,
+}
+
+impl From<TimebaseError> for PwmError {
+    fn from(e: TimebaseError) -> PwmError {
+        match e {
+            TimebaseError::TooFast => PwmError::TooFast,
+            TimebaseError::TooSlow => PwmError::TooSlow,
+        }
+    }
+}
+
+/// A PWM output on one channel of one timer.
+#[derive(Copy, Clone)]
+pub struct Pwm {
+    timer: &'static Timer,
+    channel: Channel,
+}
+
+impl Pwm {
+    /// Creates a PWM driver for `channel` of `timer`, checking that the timer
+    /// has that channel.  This doesn't touch the hardware.
+    pub fn new(timer: &'static Timer, channel: Channel)
+        -> Result<Pwm, PwmError> {
+        if timer.has_channel(channel) {
+            Ok(Pwm { timer: timer, channel: channel })
+        } else {
+            Err(PwmError::NoSuchChannel)
+        }
+    }
+
+    pub fn timer(&self) -> &'static Timer {
+        self.timer
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.channel
+    }
+
+    /// Configures the timer to run at (close to) `hz`, sets up this channel
+    /// in `mode` with active-high polarity and `duty` (from 0 to 1), and
+    /// starts the output.  The chosen timebase is returned.
+    ///
+    /// This reprograms the timer's frequency, which is shared by its other
+    /// channels, and restarts its counter.  The timer's clock must already be
+    /// enabled.
+    pub fn configure(&self, speeds: &ClockSpeeds, hz: f32, duty: f32,
+                     mode: PwmMode)
+        -> Result<Timebase, PwmError> {
+        let tb = try!(Timebase::compute(self.timer.get_clock(speeds),
+                                        hz,
+                                        self.timer.counter_max()));
+        self.timer.stop();
+        self.timer.set_timebase(tb);
+        self.configure_channel(mode, Polarity::ActiveHigh);
+        self.set_duty(duty);
+        self.timer.load_preloads();
+        self.enable();
+        self.timer.start();
+        Ok(tb)
+    }
+
+    /// Like `configure`, using the clock speeds recorded by `clock::freeze`.
+    ///
+    /// # Panics
+    ///
+    /// If the clock speeds have not been frozen.
+    pub fn configure_frozen(&self, hz: f32, duty: f32, mode: PwmMode)
+        -> Result<Timebase, PwmError> {
+        self.configure(clock::frozen(), hz, duty, mode)
+    }
+
+    /// Sets up this channel as a buffered PWM output, without changing the
+    /// timer's frequency.  The output remains disabled until `enable`.
+    pub fn configure_channel(&self, mode: PwmMode, polarity: Polarity) {
+        let ch = self.channel;
+        self.timer.update_ccmr(ch, |v| {
+            let oc = v.get_oc(ch)
+                .with_ccs(CaptureCompareSelection::Output)
+                .with_ocm(mode.to_ocm())
+                .with_ocpe(true);
+            v.with_oc(ch, oc)
+        });
+        self.timer.update_ccer(|v| {
+            let c = v.get_channel(ch).with_ccp(polarity);
+            v.with_channel(ch, c)
+        })
+    }
+
+    /// Number of counts in each period; a compare value equal to this (or
+    /// larger) holds the output active in mode 1.
+    pub fn get_period(&self) -> u32 {
+        let arr = self.timer.get_auto_reload();
+        if arr == 0xFFFF_FFFF { arr } else { arr + 1 }
+    }
+
+    /// Sets the duty cycle as a fraction of the period, from 0 to 1; values
+    /// outside that range are clamped.  Takes effect at the start of the next
+    /// period.
+    pub fn set_duty(&self, duty: f32) {
+        let duty = if duty < 0. { 0. } else if duty > 1. { 1. } else { duty };
+        let period = self.get_period();
+        self.set_compare((period as f32 * duty + 0.5) as u32)
+    }
+
+    /// Sets the compare value directly, in counts (see `get_period`).
+    pub fn set_compare(&self, ccr: u32) {
+        self.timer.set_compare(self.channel, ccr)
+    }
+
+    pub fn get_compare(&self) -> u32 {
+        self.timer.get_compare(self.channel)
+    }
+
+    /// Enables this channel's output.  On the advanced timers this also sets
+    /// the main output enable, which gates all channels.
+    pub fn enable(&self) {
+        let ch = self.channel;
+        self.timer.update_ccer(|v| {
+            let c = v.get_channel(ch).with_cce(true);
+            v.with_channel(ch, c)
+        });
+        self.timer.set_main_output(true)
+    }
+
+    /// Disables this channel's output, leaving the pin to idle per its
+    /// `OIS` setting (advanced timers) or low.  The main output enable, being
+    /// shared, is left alone.
+    pub fn disable(&self) {
+        let ch = self.channel;
+        self.timer.update_ccer(|v| {
+            let c = v.get_channel(ch).with_cce(false);
+            v.with_channel(ch, c)
+        })
+    }
+
+    /// Routes `pins` to this channel, after checking that each of them is a
+    /// known mapping for it on the STM32F40x/41x.  Pins are configured as
+    /// push-pull alternate function outputs without pulls.
+    pub fn route_pins(&self, pins: &Pins) -> Result<(), PwmError> {
+        for i in 0..16 {
+            let pin = match PinMask::from_bits(1 << i) {
+                Some(p) if pins.pins.contains(p) => p,
+                _ => continue,
+            };
+            if self.find_mapping(pins.port, pin).is_none() {
+                return Err(PwmError::NoSuchPin)
+            }
+        }
+
+        (pins.port)().set_output_type(pins.pins, gpio::OutputType::PushPull);
+        self.timer.configure_pins(pins);
+        Ok(())
+    }
+
+    /// Returns the first known pin mapping for this channel, if any.
+    pub fn default_pins(&self) -> Option<Pins> {
+        PIN_MAP.iter()
+            .find(|m| m.timer == self.timer.peripheral
+                      && m.channel == self.channel)
+            .map(|m| Pins { port: m.port, pins: m.pin })
+    }
+
+    fn find_mapping(&self, port: fn() -> &'static GpioPort, pin: PinMask)
+        -> Option<&'static PinMapping> {
+        PIN_MAP.iter().find(|m| m.timer == self.timer.peripheral
+                                && m.channel == self.channel
+                                && m.port == port
+                                && m.pin == pin)
+    }
+}
+
+/// A pin that can carry a timer channel.
+struct PinMapping {
+    timer: ApbPeripheral,
+    channel: Channel,
+    port: fn() -> &'static GpioPort,
+    pin: PinMask,
+}
+
+macro_rules! pin_map {
+    ($($tim:ident $ch:ident: $($port:ident $pin:ident),+;)*) => {
+        /// Channel pin mappings from the STM32F40x/41x datasheet (DS8626,
+        /// table 9), in the order given there.
+        static PIN_MAP: &'static [PinMapping] = &[
+            $($(
+                PinMapping {
+                    timer: ApbPeripheral::$tim,
+                    channel: Channel::$ch,
+                    port: gpio::$port,
+                    pin: gpio::$pin,
+                },
+            )+)*
+        ];
+    };
+}
+
+pin_map! {
+    Tim1 Ch1: gpioa P8, gpioe P9;
+    Tim1 Ch2: gpioa P9, gpioe P11;
+    Tim1 Ch3: gpioa P10, gpioe P13;
+    Tim1 Ch4: gpioa P11, gpioe P14;
+    Tim2 Ch1: gpioa P0, gpioa P5, gpioa P15;
+    Tim2 Ch2: gpioa P1, gpiob P3;
+    Tim2 Ch3: gpioa P2, gpiob P10;
+    Tim2 Ch4: gpioa P3, gpiob P11;
+    Tim3 Ch1: gpioa P6, gpiob P4, gpioc P6;
+    Tim3 Ch2: gpioa P7, gpiob P5, gpioc P7;
+    Tim3 Ch3: gpiob P0, gpioc P8;
+    Tim3 Ch4: gpiob P1, gpioc P9;
+    Tim4 Ch1: gpiob P6, gpiod P12;
+    Tim4 Ch2: gpiob P7, gpiod P13;
+    Tim4 Ch3: gpiob P8, gpiod P14;
+    Tim4 Ch4: gpiob P9, gpiod P15;
+    Tim5 Ch1: gpioa P0, gpioh P10;
+    Tim5 Ch2: gpioa P1, gpioh P11;
+    Tim5 Ch3: gpioa P2, gpioh P12;
+    Tim5 Ch4: gpioa P3, gpioi P0;
+    Tim8 Ch1: gpioc P6, gpioi P5;
+    Tim8 Ch2: gpioc P7, gpioi P6;
+    Tim8 Ch3: gpioc P8, gpioi P7;
+    Tim8 Ch4: gpioc P9, gpioi P2;
+    Tim9 Ch1: gpioa P2, gpioe P5;
+    Tim9 Ch2: gpioa P3, gpioe P6;
+    Tim10 Ch1: gpiob P8, gpiof P6;
+    Tim11 Ch1: gpiob P9, gpiof P7;
+    Tim12 Ch1: gpiob P14, gpioh P6;
+    Tim12 Ch2: gpiob P15, gpioh P9;
+    Tim13 Ch1: gpioa P6, gpiof P8;
+    Tim14 Ch1: gpioa P7, gpiof P9;
+}
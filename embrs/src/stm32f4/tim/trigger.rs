@@ -0,0 +1,138 @@
+//! Timer synchronization through the trigger network.
+//!
+//! Each timer can act as a master, emitting a trigger output (TRGO) chosen by
+//! its `MasterMode`, and as a slave, reacting to a trigger input according to
+//! its `SlaveMode`.  Between timers, triggers travel over the internal trigger
+//! lines ITR0-3, whose wiring is fixed and differs for each slave.  `Route`
+//! names the connections that exist, so that a routing not in the silicon
+//! can't be expressed:
+//!
+//!     // TIM3 counts only while TIM2's channel 1 output is active.
+//!     trigger::chain(Route::Tim2ToTim3, MasterMode::Oc1Ref,
+//!                    SlaveMode::Gated);
+//!
+//! TRGO also feeds the ADC and DAC external trigger inputs, for which only
+//! the master side (`Timer::set_master_mode`) needs configuring here.
+
+use super::{MasterMode, SlaveMode, Timer, TriggerSelect};
+use super::{TIM1, TIM2, TIM3, TIM4, TIM5, TIM8, TIM9, TIM10, TIM11, TIM12,
+            TIM13, TIM14};
+
+macro_rules! routes {
+    ($(
+        $(#[$m:meta])*
+        $name:ident = $master:ident -> $slave:ident : $itr:ident,
+    )*) => {
+        /// The internal trigger connections between timers, from RM0090's
+        /// "TIMx internal trigger connection" tables.
+        #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+        pub enum Route {
+            $(
+                $(#[$m])*
+                $name,
+            )*
+        }
+
+        impl Route {
+            /// The timer driving this route.
+            pub fn master(self) -> &'static Timer {
+                match self {
+                    $(Route::$name => &$master,)*
+                }
+            }
+
+            /// The timer receiving this route.
+            pub fn slave(self) -> &'static Timer {
+                match self {
+                    $(Route::$name => &$slave,)*
+                }
+            }
+
+            /// The slave's trigger selection for this route.
+            pub fn trigger(self) -> TriggerSelect {
+                match self {
+                    $(Route::$name => TriggerSelect::$itr,)*
+                }
+            }
+        }
+    };
+}
+
+routes! {
+    Tim5ToTim1 = TIM5 -> TIM1 : Itr0,
+    Tim2ToTim1 = TIM2 -> TIM1 : Itr1,
+    Tim3ToTim1 = TIM3 -> TIM1 : Itr2,
+    Tim4ToTim1 = TIM4 -> TIM1 : Itr3,
+
+    Tim1ToTim8 = TIM1 -> TIM8 : Itr0,
+    Tim2ToTim8 = TIM2 -> TIM8 : Itr1,
+    Tim4ToTim8 = TIM4 -> TIM8 : Itr2,
+    Tim5ToTim8 = TIM5 -> TIM8 : Itr3,
+
+    Tim1ToTim2 = TIM1 -> TIM2 : Itr0,
+    /// Available only while TIM2's option register selects TIM8 (the reset
+    /// default) rather than the Ethernet PTP or USB OTG SOF signals.
+    Tim8ToTim2 = TIM8 -> TIM2 : Itr1,
+    Tim3ToTim2 = TIM3 -> TIM2 : Itr2,
+    Tim4ToTim2 = TIM4 -> TIM2 : Itr3,
+
+    Tim1ToTim3 = TIM1 -> TIM3 : Itr0,
+    Tim2ToTim3 = TIM2 -> TIM3 : Itr1,
+    Tim5ToTim3 = TIM5 -> TIM3 : Itr2,
+    Tim4ToTim3 = TIM4 -> TIM3 : Itr3,
+
+    Tim1ToTim4 = TIM1 -> TIM4 : Itr0,
+    Tim2ToTim4 = TIM2 -> TIM4 : Itr1,
+    Tim3ToTim4 = TIM3 -> TIM4 : Itr2,
+    Tim8ToTim4 = TIM8 -> TIM4 : Itr3,
+
+    Tim2ToTim5 = TIM2 -> TIM5 : Itr0,
+    Tim3ToTim5 = TIM3 -> TIM5 : Itr1,
+    Tim4ToTim5 = TIM4 -> TIM5 : Itr2,
+    Tim8ToTim5 = TIM8 -> TIM5 : Itr3,
+
+    Tim2ToTim9 = TIM2 -> TIM9 : Itr0,
+    Tim3ToTim9 = TIM3 -> TIM9 : Itr1,
+    /// Carries TIM10's OC1REF, not its TRGO (TIM10 has none).
+    Tim10ToTim9 = TIM10 -> TIM9 : Itr2,
+    /// Carries TIM11's OC1REF, not its TRGO (TIM11 has none).
+    Tim11ToTim9 = TIM11 -> TIM9 : Itr3,
+
+    Tim4ToTim12 = TIM4 -> TIM12 : Itr0,
+    Tim5ToTim12 = TIM5 -> TIM12 : Itr1,
+    /// Carries TIM13's OC1REF, not its TRGO (TIM13 has none).
+    Tim13ToTim12 = TIM13 -> TIM12 : Itr2,
+    /// Carries TIM14's OC1REF, not its TRGO (TIM14 has none).
+    Tim14ToTim12 = TIM14 -> TIM12 : Itr3,
+}
+
+impl Timer {
+    /// Selects what this timer emits on TRGO.
+    pub fn set_master_mode(&self, mode: MasterMode) {
+        self.update_cr2(|v| v.with_mms(mode))
+    }
+
+    /// Enables master/slave mode, which delays this timer's own trigger
+    /// input so that it stays in step with the slaves it triggers.
+    pub fn set_master_slave_sync(&self, enable: bool) {
+        self.update_smcr(|v| v.with_msm(enable))
+    }
+
+    /// Selects how this timer responds to trigger input `trigger`.
+    ///
+    /// Following the Reference Manual's advice, the slave mode is disabled
+    /// while the trigger selection changes, to avoid spurious edges.
+    pub fn set_slave_mode(&self, mode: SlaveMode, trigger: TriggerSelect) {
+        self.update_smcr(|v| v.with_sms(SlaveMode::Disabled));
+        self.update_smcr(|v| v.with_ts(trigger));
+        self.update_smcr(|v| v.with_sms(mode))
+    }
+}
+
+/// Connects `route`'s master to its slave: the master emits `master_mode` on
+/// TRGO, and the slave responds to it per `slave_mode`.  Neither timer is
+/// started; for `SlaveMode::Trigger`, start only the master.
+pub fn chain(route: Route, master_mode: MasterMode, slave_mode: SlaveMode) {
+    route.master().set_master_mode(master_mode);
+    route.slave().set_slave_mode(slave_mode, route.trigger())
+}
@@ -1,208 +1,1417 @@
 //! Universal Synchronous/Asychronous Receiver/Transmitter (USART) support.
 
-use arm_m::reg::Reg;
-
-#[repr(C, packed)]
-pub struct Registers {
-    pub sr:   Reg<u32>,
-    pub dr:   Reg<u32>,
-    pub brr:  Reg<u32>,
-    pub cr1:  Reg<u32>,
-    pub cr2:  Reg<u32>,
-    pub cr3:  Reg<u32>,
-    pub gtpr: Reg<u32>,
-}
-
-bit_wrappers! {
-    pub struct Sr(pub u32);
-    pub struct Dr(pub u32);
-    pub struct Brr(pub u32);
-    pub struct Cr1(pub u32);
-    pub struct Cr2(pub u32);
-    pub struct Cr3(pub u32);
-    pub struct Gtpr(pub u32);
+use core::{fmt, slice};
+use core::sync::atomic::{self, AtomicUsize, Ordering};
+
+use arm_m::dwt::DWT;
+use arm_m::reg::{mmio, Reg};
+use clock;
+use hal::{NbError, NbResult, SerialRead, SerialWrite};
+use proto::modbus::DriverEnable;
+use super::ccm;
+use super::dma::{self, Request};
+use super::gpio::{self, Pins};
+use super::iwdg::IWDG;
+use super::rcc::{ApbPeripheral, ClockSpeeds, RCC};
+
+pub use stm32::usart::{Brr, Cr1, Cr2, Cr3, Dr, Gtpr, Registers, Sr};
+pub use stm32::usart::{BreakLength, ClockPhase, ClockPolarity, Parity};
+pub use stm32::usart::{SampleMethod, StopBits, WakeupMethod, WordLength};
+pub use stm32::usart::SerialError;
+
+/// The SR flags that are cleared by writing zero; the rest are read-only.
+const SR_CLEARABLE : u32 = 1 << 9 | 1 << 8 | 1 << 6 | 1 << 5;
+
+
+// ------------------------------------------------------------------
+
+pub struct Usart {
+    reg: *const Registers,
+    /// Alternate function that routes this USART's signals to pins.
+    af: gpio::Function,
+    /// Name of this USART in the RCC.
+    peripheral: ApbPeripheral,
 }
 
-impl Sr {
-    bitfield_accessors! {
-        pub total [9] get_cts / with_cts: bool,
-        pub total [8] get_lbd / with_lbd: bool,
-        pub total [7] get_txe / with_txe: bool,
-        pub total [6] get_tc / with_tc: bool,
-        pub total [5] get_rxne / with_rxne: bool,
-        pub total [4] get_idle / with_idle: bool,
-        pub total [3] get_ore / with_ore: bool,
-        pub total [2] get_nf / with_nf: bool,
-        pub total [1] get_fe / with_fe: bool,
-        pub total [0] get_pe / with_pe: bool,
+/// Receiver oversampling options.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum Oversampling {
+    /// Sixteen samples per bit: better noise and clock-mismatch tolerance.
+    By16,
+    /// Eight samples per bit: higher maximum baud rate.
+    By8,
+}
+
+/// Largest baud rate error `Usart::set_baud` accepts with 16x oversampling
+/// before trying 8x.
+pub const MAX_AUTO_BAUD_ERROR_PERCENT : f32 = 1.0;
+
+/// Ways that baud rate computation can fail.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum BaudError {
+    /// The requested rate is too high for the peripheral clock.
+    TooFast,
+    /// The requested rate is too low for the peripheral clock.
+    TooSlow,
+}
+
+/// A computed baud rate configuration.
+#[derive(Copy, Clone)]
+pub struct BaudConfig {
+    /// Oversampling mode the `brr` value assumes.
+    pub oversampling: Oversampling,
+    /// Value for the Baud Rate Register.
+    pub brr: Brr,
+    /// Baud rate this configuration actually achieves.
+    pub actual_baud: f32,
+    /// Error of `actual_baud` relative to the requested rate, in percent.
+    /// Positive means too fast.
+    pub error_percent: f32,
+}
+
+impl BaudConfig {
+    /// Computes the Baud Rate Register setting giving the closest achievable
+    /// rate to `baud`, from a peripheral clock of `clock_hz`.
+    ///
+    /// With 16x oversampling, BRR holds USARTDIV as a 12.4 fixed point number;
+    /// with 8x, the fraction is only three bits wide (bit 3 must be zero).
+    pub fn compute(clock_hz: f32, baud: u32, oversampling: Oversampling)
+        -> Result<BaudConfig, BaudError> {
+        if baud == 0 {
+            return Err(BaudError::TooSlow)
+        }
+
+        let frac_bits = match oversampling {
+            Oversampling::By16 => 4,
+            Oversampling::By8 => 3,
+        };
+
+        // USARTDIV scaled by the fraction width, which conveniently equals
+        // the number of peripheral clocks per bit.
+        let div = (clock_hz / (baud as f32) + 0.5) as u32;
+        let mantissa = div >> frac_bits;
+        let fraction = div & ((1 << frac_bits) - 1);
+
+        if mantissa == 0 {
+            return Err(BaudError::TooFast)
+        }
+        if mantissa > 0xFFF {
+            return Err(BaudError::TooSlow)
+        }
+
+        let actual = clock_hz / (div as f32);
+        Ok(BaudConfig {
+            oversampling: oversampling,
+            brr: Brr::default().with_mantissa(mantissa).with_fraction(fraction),
+            actual_baud: actual,
+            error_percent: (actual - baud as f32) * 100. / (baud as f32),
+        })
+    }
+
+    /// Magnitude of `error_percent`.
+    pub fn abs_error_percent(&self) -> f32 {
+        if self.error_percent < 0. {
+            -self.error_percent
+        } else {
+            self.error_percent
+        }
     }
 }
 
-impl Dr {
-    bitfield_accessors! {
-        pub total [7:0] get_data / with_data: u8,
+/// Hardware flow control options.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum FlowControl {
+    /// No hardware flow control.
+    None,
+    /// We assert RTS when we can accept data.
+    Rts,
+    /// We only transmit while CTS is asserted.
+    Cts,
+    /// Both of the above.
+    RtsCts,
+}
+
+/// IrDA SIR encoder modes.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum IrdaMode {
+    /// Pulses of 3/16 of a bit time.
+    Normal,
+    /// Pulses of three periods of a low-power clock derived from the
+    /// peripheral clock, nominally `IRDA_LOW_POWER_HZ`, which saves power at
+    /// low baud rates.
+    LowPower,
+}
+
+/// Nominal frequency of the IrDA low-power pulse clock.
+pub const IRDA_LOW_POWER_HZ : u32 = 1843200;
+/// The range the IrDA standard allows for the low-power pulse clock.
+pub const IRDA_LOW_POWER_MIN_HZ : u32 = 1420000;
+pub const IRDA_LOW_POWER_MAX_HZ : u32 = 2120000;
+
+/// Smartcard (ISO 7816-3) options.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct SmartcardConfig {
+    /// Frequency of the clock supplied to the card on CK, at most
+    /// `clock_hz / 2`; typically 1-5 MHz.
+    pub card_clock_hz: u32,
+    /// Card clocks per bit (the elementary time unit).  372 until the card
+    /// negotiates otherwise.
+    pub etu_clocks: u32,
+    /// Extra guard time after each transmitted character, in bit times.
+    pub guard_time: u8,
+    /// Signal parity errors in received characters by pulling the line low
+    /// (NACK), asking the card to repeat them.
+    pub nack: bool,
+}
+
+/// Card clocks per bit that a smartcard uses after reset.
+pub const SMARTCARD_DEFAULT_ETU : u32 = 372;
+
+/// Ways that configuring an IrDA or smartcard mode can fail.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum ModeError {
+    /// No prescaler gives a clock in the required range.
+    Prescaler,
+    /// The baud rate can't be reached; see `BaudError`.
+    Baud(BaudError),
+}
+
+macro_rules! reg_accessors {
+    ($name:ident, $ty:ident, $read:ident, $write:ident, $update:ident) => {
+        pub fn $write(&self, v: $ty) {
+            self.reg().$name.set(v.0)
+        }
+
+        pub fn $read(&self) -> $ty {
+            $ty(self.reg().$name.get())
+        }
+
+        pub fn $update<F: FnOnce($ty) -> $ty>(&self, f: F) {
+            self.$write(f(self.$read()))
+        }
+    };
+}
+
+impl Usart {
+    fn reg(&self) -> &Registers {
+        unsafe {
+            mmio(self.reg as usize)
+        }
+    }
+
+    reg_accessors!(sr, Sr, read_sr, write_sr, update_sr);
+    reg_accessors!(dr, Dr, read_dr, write_dr, update_dr);
+    reg_accessors!(cr1, Cr1, read_cr1, write_cr1, update_cr1);
+    reg_accessors!(cr2, Cr2, read_cr2, write_cr2, update_cr2);
+    reg_accessors!(cr3, Cr3, read_cr3, write_cr3, update_cr3);
+    reg_accessors!(brr, Brr, read_brr, write_brr, update_brr);
+    reg_accessors!(gtpr, Gtpr, read_gtpr, write_gtpr, update_gtpr);
+
+    /// Clears the SR flags set in `flags`, leaving the others alone.  The
+    /// flags software can clear (CTS, LBD, TC and RXNE) are cleared by
+    /// writing zero, so `update_sr` could clear one that the hardware set
+    /// between its read and write; this writes one to all the others.
+    pub fn clear_sr(&self, flags: Sr) {
+        self.write_sr(Sr(SR_CLEARABLE & !flags.0))
     }
+
+    /// Address of the data register, for use as a DMA peripheral address.
+    pub fn dr_address(&self) -> *const () {
+        &self.reg().dr as *const Reg<u32> as *const ()
+    }
+
+    /// Enables this USART's clock in the RCC.
+    pub fn enable_clock(&self) {
+        RCC.enable_clock(self.peripheral)
+    }
+
+    /// Sets the baud rate, given the current clock speeds.
+    ///
+    /// This tries 16x oversampling first, since it's more tolerant of clock
+    /// mismatch, and falls back to 8x if that can't get within
+    /// `MAX_AUTO_BAUD_ERROR_PERCENT` of the target (or can't reach it at all).
+    /// The chosen configuration is returned.
+    ///
+    /// Oversampling should only be changed while the USART is disabled (UE
+    /// clear).
+    pub fn set_baud(&self, speeds: &ClockSpeeds, baud: u32)
+        -> Result<BaudConfig, BaudError> {
+        let clk = speeds.get_clock_for(self.peripheral);
+
+        let cfg = match BaudConfig::compute(clk, baud, Oversampling::By16) {
+            Ok(c16) if c16.abs_error_percent() <= MAX_AUTO_BAUD_ERROR_PERCENT
+                => c16,
+            r16 => {
+                let r8 = BaudConfig::compute(clk, baud, Oversampling::By8);
+                match (r16, r8) {
+                    (Ok(c16), Ok(c8)) =>
+                        if c8.abs_error_percent() < c16.abs_error_percent() {
+                            c8
+                        } else {
+                            c16
+                        },
+                    (Ok(c), Err(_)) | (Err(_), Ok(c)) => c,
+                    (Err(e), Err(_)) => return Err(e),
+                }
+            },
+        };
+
+        self.apply_baud(cfg);
+        Ok(cfg)
+    }
+
+    /// Sets the baud rate using the clock speeds recorded by `clock::freeze`.
+    /// See `set_baud`.
+    ///
+    /// # Panics
+    ///
+    /// If the clock speeds have not been frozen.
+    pub fn set_baud_frozen(&self, baud: u32) -> Result<BaudConfig, BaudError> {
+        self.set_baud(clock::frozen(), baud)
+    }
+
+    /// Applies a precomputed baud configuration, including its oversampling
+    /// mode.
+    pub fn apply_baud(&self, cfg: BaudConfig) {
+        self.update_cr1(|v| v.with_over8(cfg.oversampling == Oversampling::By8));
+        self.write_brr(cfg.brr)
+    }
+
+    pub fn send8(&self, v: u8) {
+        self.write_dr(Dr::default().with_data(v))
+    }
+
+    /// Sends a nine-bit word.  With `WordLength::NineBits` and parity
+    /// disabled, all nine bits are transmitted; otherwise the hardware ignores
+    /// or replaces the upper bit(s).
+    pub fn send9(&self, v: u16) {
+        self.write_dr(Dr::default().with_data9(v))
+    }
+
+    /// Reads the low eight bits of the most recently received word.
+    pub fn recv8(&self) -> u8 {
+        self.read_dr().get_data()
+    }
+
+    /// Reads all nine bits of the most recently received word.  When parity
+    /// is enabled, the parity bit is included in the result.
+    pub fn recv9(&self) -> u16 {
+        self.read_dr().get_data9()
+    }
+
+    /// Configures for multi-drop (e.g. RS-485) addressing with the given node
+    /// address (0-15): nine-bit words without parity, where the ninth bit
+    /// (sent as mark or space) distinguishes address from data frames.  The
+    /// receiver is muted until it sees a frame addressed to this node.
+    ///
+    /// Transmit with `send_address` and `send_data`.
+    pub fn configure_multidrop(&self, address: u32) {
+        self.update_cr2(|v| v.with_add(address));
+        self.update_cr1(|v| v.with_m(WordLength::NineBits)
+                        .with_pce(false)
+                        .with_wake(WakeupMethod::AddressMark));
+        self.update_cr1(|v| v.with_rwu(true))
+    }
+
+    /// In multi-drop mode, sends an address frame (ninth bit mark).
+    pub fn send_address(&self, address: u8) {
+        self.send9(0x100 | address as u16)
+    }
+
+    /// In multi-drop mode, sends a data frame (ninth bit space).
+    pub fn send_data(&self, v: u8) {
+        self.send9(v as u16)
+    }
+
+    /// In multi-drop mode, checks whether a received nine-bit word is an
+    /// address frame.
+    pub fn is_address_frame(word: u16) -> bool {
+        (word & 0x100) != 0
+    }
+
+    /// Routes `pins` to this USART's signals (TX, RX, RTS, CTS, CK -- whichever
+    /// the pins carry) by selecting the appropriate alternate function.  Pull
+    /// ups are enabled so that lines idle high if undriven.
+    ///
+    /// Which pins carry which signals is part-specific; see the datasheet.
+    pub fn configure_pins(&self, pins: &Pins) {
+        pins.configure_alternate(self.af, gpio::Pull::Up)
+    }
+
+    /// Enables or disables RTS/CTS hardware flow control.  The RTS and CTS
+    /// pins must also be routed using `configure_pins`.
+    pub fn set_flow_control(&self, fc: FlowControl) {
+        let (rts, cts) = match fc {
+            FlowControl::None => (false, false),
+            FlowControl::Rts => (true, false),
+            FlowControl::Cts => (false, true),
+            FlowControl::RtsCts => (true, true),
+        };
+        self.update_cr3(|v| v.with_rtse(rts).with_ctse(cts))
+    }
+
+    /// Switches the USART into degraded (polling-only) mode by masking its
+    /// transmit interrupts and disconnecting transmit DMA.  Any interrupt- or
+    /// DMA-driven transmission in progress is abandoned where it stands.
+    ///
+    /// This is intended for use by panic and fault handlers, which cannot trust
+    /// the normal driver state.  Nothing restores the previous settings.
+    pub fn enter_polling_mode(&self) {
+        self.update_cr1(|v| v.with_txeie(false).with_tcie(false));
+        self.update_cr3(|v| v.with_dmat(false));
+    }
+
+    /// Routes `pins` to this USART's signals, as `configure_pins` does, but
+    /// with open-drain outputs, for lines shared with other drivers: the
+    /// smartcard IO line, or a single-wire bus.  Pull ups are enabled, but
+    /// long or busy lines will want stronger external ones.
+    pub fn configure_pins_open_drain(&self, pins: &Pins) {
+        ((pins.port)()).set_output_type(pins.pins, gpio::OutputType::OpenDrain);
+        pins.configure_alternate(self.af, gpio::Pull::Up)
+    }
+
+    /// Turns off the special modes (LIN, clock output, smartcard,
+    /// half-duplex, IrDA) so that one of them can be turned on alone.
+    fn clear_special_modes(&self) {
+        self.update_cr2(|v| v.with_linen(false).with_clken(false));
+        self.update_cr3(|v| v.with_scen(false)
+                        .with_hdsel(false)
+                        .with_iren(false)
+                        .with_irlp(false))
+    }
+
+    /// Configures the USART as an IrDA SIR encoder/decoder at `baud`, given
+    /// the current clock speeds, and enables it.  The TX and RX pins drive
+    /// and receive infrared pulses rather than NRZ bits, and the receiver
+    /// ignores pulses too short to be valid.
+    ///
+    /// In `LowPower` mode, the pulse clock prescaler is chosen to bring the
+    /// peripheral clock as near as possible to `IRDA_LOW_POWER_HZ`.
+    pub fn configure_irda(&self, speeds: &ClockSpeeds, baud: u32,
+                          mode: IrdaMode) -> Result<(), ModeError> {
+        let clk = speeds.get_clock_for(self.peripheral) as u32;
+        let psc = match mode {
+            // The prescaler must be one in normal mode.
+            IrdaMode::Normal => 1,
+            IrdaMode::LowPower => {
+                let psc = (clk + IRDA_LOW_POWER_HZ / 2) / IRDA_LOW_POWER_HZ;
+                if psc == 0 || psc > 0xFF {
+                    return Err(ModeError::Prescaler)
+                }
+                let f = clk / psc;
+                if f < IRDA_LOW_POWER_MIN_HZ || f > IRDA_LOW_POWER_MAX_HZ {
+                    return Err(ModeError::Prescaler)
+                }
+                psc
+            },
+        };
+
+        self.update_cr1(|v| v.with_ue(false));
+        self.clear_special_modes();
+        // IrDA needs 16x oversampling, and one stop bit.
+        let cfg = try!(BaudConfig::compute(clk as f32, baud,
+                                           Oversampling::By16)
+                       .map_err(ModeError::Baud));
+        self.apply_baud(cfg);
+        self.update_cr2(|v| v.with_stop(StopBits::One));
+        self.update_gtpr(|v| v.with_psc(psc as u8));
+        self.update_cr3(|v| v.with_irlp(mode == IrdaMode::LowPower)
+                        .with_iren(true));
+        self.update_cr1(|v| v.with_te(true).with_re(true).with_ue(true));
+        Ok(())
+    }
+
+    /// Configures the USART as an ISO 7816-3 smartcard interface, given the
+    /// current clock speeds, and enables it.  Only USART1, 2, 3 and 6 have
+    /// this mode.
+    ///
+    /// The card's clock comes from CK (route it with `configure_pins`), and
+    /// its IO line is TX alone, which must be open-drain (route it with
+    /// `configure_pins_open_drain`).  Characters are eight data bits with
+    /// even parity and 1.5 stop bits, at `card_clock_hz / etu_clocks` baud.
+    pub fn configure_smartcard(&self, speeds: &ClockSpeeds,
+                               config: &SmartcardConfig)
+        -> Result<(), ModeError> {
+        let clk = speeds.get_clock_for(self.peripheral) as u32;
+        // CK is the peripheral clock divided by twice the five-bit
+        // prescaler.
+        if config.card_clock_hz == 0 || config.etu_clocks == 0 {
+            return Err(ModeError::Prescaler)
+        }
+        let psc = (clk + config.card_clock_hz) / (2 * config.card_clock_hz);
+        if psc == 0 || psc > 0x1F {
+            return Err(ModeError::Prescaler)
+        }
+        let card_clock = clk / (2 * psc);
+
+        self.update_cr1(|v| v.with_ue(false));
+        self.clear_special_modes();
+        let cfg = try!(BaudConfig::compute(clk as f32,
+                                           card_clock / config.etu_clocks,
+                                           Oversampling::By16)
+                       .map_err(ModeError::Baud));
+        self.apply_baud(cfg);
+        self.write_gtpr(Gtpr::default()
+                        .with_gt(config.guard_time)
+                        .with_psc(psc as u8));
+        self.update_cr1(|v| v.with_m(WordLength::NineBits)
+                        .with_pce(true)
+                        .with_ps(Parity::Even));
+        self.update_cr2(|v| v.with_stop(StopBits::OneAndAHalf)
+                        .with_clken(true));
+        self.update_cr3(|v| v.with_nack(config.nack).with_scen(true));
+        self.update_cr1(|v| v.with_te(true).with_re(true).with_ue(true));
+        Ok(())
+    }
+
+    /// Checks whether the USART is in smartcard mode.
+    pub fn is_smartcard(&self) -> bool {
+        self.read_cr3().get_scen()
+    }
+
+    /// Transmits a break after the current character: all zeroes, for at
+    /// least ten bits (thirteen, in LIN mode).
+    pub fn send_break(&self) {
+        self.update_cr1(|v| v.with_sbk(true))
+    }
+}
+
+/// Software-managed driver enable (DE) for RS-485 transceivers.
+///
+/// The STM32F4 USART has no hardware DE output, so we drive a GPIO: asserted
+/// by `begin_transmit`, and deasserted from the transmission complete (TC)
+/// interrupt once the last stop bit has left the wire -- not merely the
+/// transmit register, which would chop the final byte.
+///
+/// The application must route the USART's interrupt to a handler that calls
+/// `handle_interrupt`.
+pub struct Rs485<'a> {
+    usart: &'a Usart,
+    de: Pins,
 }
 
-impl Brr {
-    bitfield_accessors! {
-        pub total [15:4] get_mantissa / with_mantissa: u32,
-        pub total [3:0] get_fraction / with_fraction: u32,
+impl<'a> Rs485<'a> {
+    /// Creates a DE manager for `usart` using pin(s) `de`, configuring them as
+    /// outputs and deasserting them.
+    pub fn new(usart: &'a Usart, de: Pins) -> Rs485<'a> {
+        de.clear();
+        de.configure_output();
+        Rs485 {
+            usart: usart,
+            de: de,
+        }
+    }
+
+    /// Asserts DE, claiming the bus.  Call before writing the first byte.
+    pub fn begin_transmit(&self) {
+        self.usart.update_cr1(|v| v.with_tcie(false));
+        self.de.set()
+    }
+
+    /// Arranges for DE to be deasserted once transmission completes.  Call
+    /// after writing the last byte.
+    pub fn end_transmit(&self) {
+        self.usart.update_cr1(|v| v.with_tcie(true))
+    }
+
+    /// Checks whether DE is currently asserted.
+    pub fn is_transmitting(&self) -> bool {
+        !self.de.get().is_empty()
+    }
+
+    /// To be called from the USART's interrupt handler.  If the transmission
+    /// we were waiting for has completed, releases the bus and returns `true`.
+    pub fn handle_interrupt(&self) -> bool {
+        if !(self.usart.read_cr1().get_tcie() && self.usart.read_sr().get_tc()) {
+            return false
+        }
+
+        self.usart.update_cr1(|v| v.with_tcie(false));
+        self.usart.clear_sr(Sr::default().with_tc(true));
+        self.de.clear();
+        true
     }
 }
 
-impl Cr1 {
-    bitfield_accessors! {
-        pub total [15] get_over8 / with_over8: bool,
-        pub total [13] get_ue / with_ue: bool,
-        pub total [12] get_m / with_m: WordLength,
-        pub total [11] get_wake / with_wake: WakeupMethod,
-        pub total [10] get_pce / with_pce: bool,
-        pub total [ 9] get_ps / with_ps: Parity,
-        pub total [ 8] get_peie / with_peie: bool,
-        pub total [ 7] get_txeie / with_txeie: bool,
-        pub total [ 6] get_tcie / with_tcie: bool,
-        pub total [ 5] get_rxneie / with_rxneie: bool,
-        pub total [ 4] get_idleie / with_idleie: bool,
-        pub total [ 3] get_te / with_te: bool,
-        pub total [ 2] get_re / with_re: bool,
-        pub total [ 1] get_rwu / with_rwu: bool,
-        pub total [ 0] get_sbk / with_sbk: bool,
+impl<'a> DriverEnable for Rs485<'a> {
+    fn begin_transmit(&self) {
+        Rs485::begin_transmit(self)
+    }
 
+    fn end_transmit(&self) {
+        Rs485::end_transmit(self)
     }
 }
 
-impl Cr2 {
-    bitfield_accessors! {
-        pub total [14] get_linen / with_linen: bool,
-        pub total [13:12] get_stop / with_stop: StopBits,
-        pub total [11] get_clken / with_clken: bool,
-        pub total [10] get_cpol / with_cpol: ClockPolarity,
-        pub total [ 9] get_cpha / with_cpha: ClockPhase,
-        pub total [ 8] get_lbcl / with_lbcl: bool,
-        pub total [ 6] get_lbdie / with_lbdie: bool,
-        pub total [ 5] get_lbdl / with_lbdl: BreakLength,
-        pub total [3:0] get_add / with_add: u32,
+/// Errors from half-duplex transmission.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum HalfDuplexError {
+    /// The byte at this index came back different: another device drove
+    /// the line while we were sending.  Transmission stopped there.
+    Collision(usize),
+}
+
+/// Single-wire, half-duplex operation, as used by servo and ESC telemetry
+/// and assorted sensors.
+///
+/// In half-duplex mode (`HDSEL`) the USART transmits and receives on its TX
+/// pin alone, which should be open-drain (see `configure_pins_open_drain`)
+/// so that the other end can drive it too.  Since the receiver hears our
+/// own transmissions, `write` manages the turnaround: either the receiver is
+/// off while we send and back on once the last stop bit is out, or, with
+/// collision detection, it stays on and each byte's echo is checked.
+pub struct HalfDuplex<'a> {
+    usart: &'a Usart,
+    detect_collisions: bool,
+}
+
+impl<'a> HalfDuplex<'a> {
+    /// Puts `usart` in half-duplex mode and enables it, listening.  Its baud
+    /// rate and framing should already be set.  If `detect_collisions`, each
+    /// byte written is checked against its echo.
+    pub fn new(usart: &'a Usart, detect_collisions: bool) -> HalfDuplex<'a> {
+        usart.update_cr1(|v| v.with_ue(false));
+        usart.clear_special_modes();
+        usart.update_cr3(|v| v.with_hdsel(true));
+        usart.update_cr1(|v| v.with_te(true).with_re(true).with_ue(true));
+        HalfDuplex {
+            usart: usart,
+            detect_collisions: detect_collisions,
+        }
+    }
+
+    /// Sends `data`, waiting until the last byte has left the wire, and then
+    /// returns to listening.  Anything received before the call is
+    /// discarded.
+    pub fn write(&self, data: &[u8]) -> Result<(), HalfDuplexError> {
+        let u = self.usart;
+        if !self.detect_collisions {
+            u.update_cr1(|v| v.with_re(false))
+        }
+        // Drop stale input, so echoes line up.
+        let _ = u.recv8();
+
+        let mut result = Ok(());
+        for (i, &b) in data.iter().enumerate() {
+            while !u.read_sr().get_txe() {}
+            u.send8(b);
+            if self.detect_collisions {
+                while !u.read_sr().get_rxne() {}
+                if u.recv8() != b {
+                    result = Err(HalfDuplexError::Collision(i));
+                    break
+                }
+            }
+        }
+
+        self.turn_around();
+        result
+    }
+
+    /// Waits for transmission to complete (TC), so the line is released,
+    /// and then makes sure the receiver is on.  Enabling it any earlier would
+    /// hear the tail of our own last byte.
+    pub fn turn_around(&self) {
+        let u = self.usart;
+        while !u.read_sr().get_tc() {}
+        if !u.read_cr1().get_re() {
+            // Anything in DR predates the receiver being turned off.
+            let _ = u.recv8();
+            u.update_cr1(|v| v.with_re(true))
+        }
+    }
+
+    /// Checks whether we're transmitting, i.e. the last byte written hasn't
+    /// finished.
+    pub fn is_transmitting(&self) -> bool {
+        !self.usart.read_sr().get_tc()
+    }
+
+    /// Receives a byte, if one has arrived.
+    pub fn try_read(&self) -> NbResult<u8, SerialError> {
+        SerialRead::try_read(self.usart)
     }
 }
 
-impl Cr3 {
-    bitfield_accessors! {
-        pub total [11] get_onebit / with_onebit: SampleMethod,
-        pub total [10] get_ctsie / with_ctsie: bool,
-        pub total [ 9] get_ctse / with_ctse: bool,
-        pub total [ 8] get_rtse / with_rtse: bool,
-        pub total [ 7] get_dmat / with_dmat: bool,
-        pub total [ 6] get_dmar / with_dmar: bool,
-        pub total [ 5] get_scen / with_scen: bool,
-        pub total [ 4] get_nack / with_nack: bool,
-        pub total [ 3] get_hdsel / with_hdsel: bool,
-        pub total [ 2] get_irlp / with_irlp: bool,
-        pub total [ 1] get_iren / with_iren: bool,
-        pub total [ 0] get_eie / with_eie: bool,
+/// Sync field that follows the break in every LIN header.
+pub const LIN_SYNC : u8 = 0x55;
+
+/// Largest LIN frame identifier.
+pub const LIN_MAX_ID : u8 = 0x3F;
+
+/// Most data bytes in a LIN response.
+pub const LIN_MAX_DATA : usize = 8;
+
+/// Computes the protected identifier for LIN frame `id` (0-63): the
+/// identifier, with two parity bits in bits 7:6.
+pub fn lin_protected_id(id: u8) -> u8 {
+    let bit = |n: u8| (id >> n) & 1;
+    let p0 = bit(0) ^ bit(1) ^ bit(2) ^ bit(4);
+    let p1 = !(bit(1) ^ bit(3) ^ bit(4) ^ bit(5)) & 1;
+    (id & LIN_MAX_ID) | (p0 << 6) | (p1 << 7)
+}
+
+/// LIN checksum models.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum LinChecksum {
+    /// LIN 1.x: data bytes only.
+    Classic,
+    /// LIN 2.x: data bytes and protected identifier.  Diagnostic frames
+    /// (identifiers 0x3C and up) use `Classic` regardless.
+    Enhanced,
+}
+
+/// Computes the checksum of a LIN response carrying `data`, for frame
+/// identifier `pid` (protected or not): the inverted eight-bit sum, with
+/// carries wrapped around.
+pub fn lin_checksum(model: LinChecksum, pid: u8, data: &[u8]) -> u8 {
+    let enhanced = model == LinChecksum::Enhanced && (pid & LIN_MAX_ID) < 0x3C;
+    let init = if enhanced {
+        lin_protected_id(pid & LIN_MAX_ID) as u32
+    } else {
+        0
+    };
+    let sum = data.iter().fold(init, |s, &b| {
+        let s = s + b as u32;
+        if s > 0xFF { s - 0xFF } else { s }
+    });
+    !(sum as u8)
+}
+
+/// What a LIN node does with a frame, once it's seen the header.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum LinAction {
+    /// Send the first `len` bytes of `data` as the response.
+    Publish { data: [u8; LIN_MAX_DATA], len: usize },
+    /// Receive a response of `len` bytes.
+    Subscribe(usize),
+    /// The frame is none of our business.
+    Ignore,
+}
+
+/// Ways that a LIN frame can go wrong.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum LinError {
+    /// A break wasn't followed by `LIN_SYNC`.
+    Sync,
+    /// The protected identifier's parity bits were wrong.
+    Parity,
+    /// The response's checksum didn't match.
+    Checksum,
+    /// A byte we sent came back different: someone else drove the bus.
+    Bit,
+    /// A byte had a framing error.
+    Framing,
+    /// A new break arrived before the response was complete.
+    Incomplete,
+}
+
+/// Application side of a LIN node; see `Lin`.
+pub trait LinNode {
+    /// Decides what to do with frame `id`, whose header has just arrived.
+    fn header(&mut self, id: u8) -> LinAction;
+
+    /// Receives the verified response to frame `id`, which we subscribed to.
+    fn response(&mut self, id: u8, data: &[u8]);
+
+    /// Notes an error in frame `id` (or, for `Sync` and `Parity` errors, in
+    /// a header whose identifier isn't known).
+    fn error(&mut self, _id: u8, _err: LinError) {}
+}
+
+/// Progress through a LIN frame.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum LinState {
+    /// Waiting for a break.
+    Idle,
+    /// Break seen; expecting the sync field.
+    Sync,
+    /// Expecting the protected identifier.
+    Pid,
+    /// Collecting a response of `len` bytes plus checksum.
+    Receiving { id: u8, len: usize, got: usize },
+    /// Sending a response of `total` bytes including checksum, checking each
+    /// byte's echo.
+    Transmitting { id: u8, total: usize, sent: usize },
+}
+
+/// LIN master or slave node, interrupt-driven.
+///
+/// LIN is a single-wire bus, so the receiver hears everything, including our
+/// own transmissions.  `Lin` parses every header it hears -- its own, if it's
+/// the master -- and asks the application's `LinNode` what to do with each
+/// frame: publish a response, subscribe to one, or ignore it.  Bytes we send
+/// are checked against their echoes to catch collisions.
+///
+/// The application routes the USART interrupt to `handle_interrupt`.  Since
+/// that needs `&mut self`, keep the `Lin` in an `IrqCell` owned by the USART
+/// interrupt.  A master sends headers with `send_header`, typically on the
+/// cue of a `LinSchedule`.
+///
+/// LIN specifies timeouts for responses; there's no timer here, so the
+/// application should `reset` the node if a frame is still in progress when
+/// its slot ends.
+pub struct Lin<'a> {
+    usart: &'a Usart,
+    checksum: LinChecksum,
+    state: LinState,
+    /// Protected identifier we're to send, once our sync field echoes back.
+    pending_pid: Option<u8>,
+    buf: [u8; LIN_MAX_DATA + 1],
+}
+
+impl<'a> Lin<'a> {
+    /// Creates a LIN node on `usart`, which should already have its baud
+    /// rate set and its pins configured.  Checksums use `checksum`.
+    pub fn new(usart: &'a Usart, checksum: LinChecksum) -> Lin<'a> {
+        Lin {
+            usart: usart,
+            checksum: checksum,
+            state: LinState::Idle,
+            pending_pid: None,
+            buf: [0; LIN_MAX_DATA + 1],
+        }
+    }
+
+    /// Switches the USART into LIN mode and enables the interrupts
+    /// `handle_interrupt` needs.  LIN mode requires one stop bit and no
+    /// clock output, smartcard, half-duplex or IrDA modes, so those are
+    /// turned off.
+    pub fn enable(&mut self) {
+        let u = self.usart;
+        u.update_cr1(|v| v.with_ue(false));
+        u.clear_special_modes();
+        u.update_cr2(|v| v.with_stop(StopBits::One)
+                     .with_lbdl(BreakLength::ElevenBits)
+                     .with_lbdie(true)
+                     .with_linen(true));
+        u.update_cr1(|v| v.with_m(WordLength::EightBits)
+                     .with_pce(false)
+                     .with_te(true)
+                     .with_re(true)
+                     .with_rxneie(true)
+                     .with_ue(true));
+        self.reset()
+    }
+
+    /// Abandons any frame in progress, and waits for the next break.
+    pub fn reset(&mut self) {
+        self.state = LinState::Idle;
+        self.pending_pid = None
+    }
+
+    /// Checks whether a frame is in progress.
+    pub fn is_busy(&self) -> bool {
+        self.state != LinState::Idle || self.pending_pid.is_some()
+    }
+
+    /// As master, starts a frame by sending the header for `id`: a break,
+    /// the sync field, and the protected identifier.  The response, from
+    /// whichever node publishes it, is handled by `handle_interrupt`.
+    pub fn send_header(&mut self, id: u8) {
+        self.state = LinState::Idle;
+        self.pending_pid = Some(lin_protected_id(id));
+        self.usart.send_break();
+        // Queued behind the break, once the data register is free: the
+        // previous frame's last byte may still be on its way out.
+        while !self.usart.read_sr().get_txe() {}
+        self.usart.send8(LIN_SYNC)
+    }
+
+    /// To be called from the USART's interrupt handler.  Advances the frame
+    /// in progress, calling `node` as its parts arrive.
+    pub fn handle_interrupt<N: LinNode>(&mut self, node: &mut N) {
+        let sr = self.usart.read_sr();
+        if sr.get_lbd() {
+            self.usart.clear_sr(Sr::default().with_lbd(true));
+            if sr.get_rxne() {
+                // The break's own all-zeroes character.
+                let _ = self.usart.recv8();
+            }
+            match self.state {
+                LinState::Receiving { id, .. }
+                    | LinState::Transmitting { id, .. } =>
+                    node.error(id, LinError::Incomplete),
+                _ => (),
+            }
+            self.state = LinState::Sync;
+            return
+        }
+
+        if !sr.get_rxne() {
+            return
+        }
+        // Reading DR after SR clears the error flags.
+        let b = self.usart.recv8();
+        if sr.get_fe() {
+            if b == 0 {
+                // The break's own all-zeroes character, arriving separately
+                // from LBD (before or after it).  LBD starts the frame, so
+                // this must leave the state -- and a master's pending
+                // identifier -- alone.
+                return
+            }
+            match self.state {
+                LinState::Receiving { id, .. }
+                    | LinState::Transmitting { id, .. } =>
+                    node.error(id, LinError::Framing),
+                _ => (),
+            }
+            self.reset();
+            return
+        }
+
+        let state = self.state;
+        self.state = match state {
+            LinState::Idle => LinState::Idle,
+
+            LinState::Sync => if b == LIN_SYNC {
+                if let Some(pid) = self.pending_pid.take() {
+                    self.usart.send8(pid)
+                }
+                LinState::Pid
+            } else {
+                node.error(0, LinError::Sync);
+                self.pending_pid = None;
+                LinState::Idle
+            },
+
+            LinState::Pid => {
+                let id = b & LIN_MAX_ID;
+                if lin_protected_id(id) != b {
+                    node.error(id, LinError::Parity);
+                    LinState::Idle
+                } else {
+                    self.begin_response(node, id)
+                }
+            },
+
+            LinState::Receiving { id, len, got } => {
+                self.buf[got] = b;
+                if got < len {
+                    LinState::Receiving { id: id, len: len, got: got + 1 }
+                } else {
+                    let data = &self.buf[..len];
+                    if lin_checksum(self.checksum, id, data) == b {
+                        node.response(id, data)
+                    } else {
+                        node.error(id, LinError::Checksum)
+                    }
+                    LinState::Idle
+                }
+            },
+
+            LinState::Transmitting { id, total, sent } => {
+                if b != self.buf[sent] {
+                    node.error(id, LinError::Bit);
+                    LinState::Idle
+                } else if sent + 1 == total {
+                    LinState::Idle
+                } else {
+                    self.usart.send8(self.buf[sent + 1]);
+                    LinState::Transmitting {
+                        id: id,
+                        total: total,
+                        sent: sent + 1,
+                    }
+                }
+            },
+        }
+    }
+
+    /// Asks `node` about frame `id`, and starts on the response.
+    fn begin_response<N: LinNode>(&mut self, node: &mut N, id: u8)
+        -> LinState {
+        match node.header(id) {
+            LinAction::Publish { data, len } => {
+                let len = if len > LIN_MAX_DATA { LIN_MAX_DATA } else { len };
+                self.buf[..len].copy_from_slice(&data[..len]);
+                self.buf[len] = lin_checksum(self.checksum, id, &data[..len]);
+                self.usart.send8(self.buf[0]);
+                LinState::Transmitting { id: id, total: len + 1, sent: 0 }
+            },
+            LinAction::Subscribe(len) if len > 0 => LinState::Receiving {
+                id: id,
+                len: if len > LIN_MAX_DATA { LIN_MAX_DATA } else { len },
+                got: 0,
+            },
+            _ => LinState::Idle,
+        }
     }
 }
 
-impl Gtpr {
-    bitfield_accessors! {
-        pub total [15:8] get_gt / with_gt: u8,
-        pub total [ 7:0] get_psc / with_psc: u8,
+/// One slot of a LIN schedule table.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct LinSlot {
+    /// Frame to send the header for.
+    pub id: u8,
+    /// Length of the slot, in milliseconds, before the next begins.
+    pub duration_ms: u32,
+}
+
+/// A master's schedule table: the frames it polls, in order, repeating.
+///
+///     static TABLE: [LinSlot; 2] = [
+///         LinSlot { id: 0x10, duration_ms: 10 },
+///         LinSlot { id: 0x21, duration_ms: 20 },
+///     ];
+///
+///     if let Some(id) = schedule.poll(time::now_ms()) {
+///         lin.lock(|l| { l.reset(); l.send_header(id) })
+///     }
+pub struct LinSchedule<'s> {
+    table: &'s [LinSlot],
+    next: usize,
+    /// Time at which slot `next` begins.
+    due: u32,
+}
+
+impl<'s> LinSchedule<'s> {
+    /// Creates a schedule running through `table`, whose first slot begins
+    /// at time `now`.
+    pub fn new(table: &'s [LinSlot], now: u32) -> LinSchedule<'s> {
+        LinSchedule {
+            table: table,
+            next: 0,
+            due: now,
+        }
+    }
+
+    /// Switches to another table, starting at its first slot at time `now`.
+    pub fn switch(&mut self, table: &'s [LinSlot], now: u32) {
+        *self = LinSchedule::new(table, now)
     }
+
+    /// If the next slot has begun, by time `now` in milliseconds, returns
+    /// the identifier whose header should be sent.  Call this at least as
+    /// often as the shortest slot.
+    pub fn poll(&mut self, now: u32) -> Option<u8> {
+        if self.table.is_empty() || (now.wrapping_sub(self.due) as i32) < 0 {
+            return None
+        }
+        let slot = self.table[self.next];
+        self.due = self.due.wrapping_add(slot.duration_ms);
+        self.next = (self.next + 1) % self.table.len();
+        Some(slot.id)
+    }
+}
+
+/// Ways that starting a DMA transfer can fail.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum DmaError {
+    /// A transfer is already in progress.
+    Busy,
+    /// The buffer is empty, or longer than the 65535 bytes one transfer can
+    /// move.
+    Length,
+    /// The buffer is in memory that DMA can't access (see `ccm`).
+    Inaccessible,
 }
 
-bit_enums! {
-    pub bit_enum WordLength {
-        EightBits = 0,
-        NineBits = 1,
+/// Checks that the `len` bytes at `addr` can be moved by a single DMA
+/// transfer.
+fn check_dma_buffer(addr: usize, len: usize) -> Result<(), DmaError> {
+    if len == 0 || len > u16::max_value() as usize {
+        Err(DmaError::Length)
+    } else if ccm::contains(addr, len) {
+        Err(DmaError::Inaccessible)
+    } else {
+        Ok(())
     }
+}
+
+/// DMA-driven USART operation.
+///
+/// Transmission sends a `'static` buffer with `send_dma`, reporting completion
+/// through `handle_tx_interrupt`.
+///
+/// Reception runs continuously: a circular DMA transfer fills a `'static`
+/// buffer, and whenever the line goes idle (or the buffer reaches its half or
+/// end) `handle_rx_interrupt` delivers whatever has arrived since the last
+/// call.  This yields variable-length frames without per-byte interrupts.  If
+/// the application falls a full buffer behind, data is lost without notice,
+/// so size the buffer for the worst-case latency.
+///
+/// The application must route the USART interrupt, and the DMA stream
+/// interrupts, to handlers that call the `handle_*` methods.
+pub struct DmaUsart<'a> {
+    usart: &'a Usart,
+    tx: Request,
+    rx: Request,
+    /// Receive buffer address and length; zero when not receiving.
+    rx_buf: AtomicUsize,
+    rx_len: AtomicUsize,
+    /// Index in the receive buffer up to which data has been delivered.
+    rx_pos: AtomicUsize,
+}
 
-    pub bit_enum WakeupMethod {
-        IdleLine = 0,
-        AddressMark = 1,
+impl<'a> DmaUsart<'a> {
+    /// Creates a DMA driver for `usart`, using the given DMA requests for
+    /// transmit and receive -- e.g. `Request::Usart2Tx` and
+    /// `Request::Usart2Rx` for USART2.
+    pub const fn new(usart: &'a Usart, tx: Request, rx: Request)
+        -> DmaUsart<'a> {
+        DmaUsart {
+            usart: usart,
+            tx: tx,
+            rx: rx,
+            rx_buf: AtomicUsize::new(0),
+            rx_len: AtomicUsize::new(0),
+            rx_pos: AtomicUsize::new(0),
+        }
     }
 
-    pub bit_enum Parity {
-        Even = 0,
-        Odd = 1,
+    /// Checks whether a DMA transmission is in progress.
+    pub fn is_tx_busy(&self) -> bool {
+        self.tx.route().get_stream().is_enabled()
     }
 
-    pub bit_enum StopBits {
-        One = 0b00,
-        Half = 0b01,
-        Two = 0b10,
-        OneAndAHalf = 0b11,
+    /// Starts transmitting `data` by DMA, returning immediately.
+    pub fn send_dma(&self, data: &'static [u8]) -> Result<(), DmaError> {
+        try!(check_dma_buffer(data.as_ptr() as usize, data.len()));
+        if self.is_tx_busy() {
+            return Err(DmaError::Busy)
+        }
+
+        let route = self.tx.route();
+        let stream = route.get_stream();
+        route.clear_interrupt_flags(dma::InterruptFlags::all());
+        stream.par.set(self.usart.dr_address());
+        stream.mar[0].set(data.as_ptr() as *const ());
+        stream.ndtr.set(dma::Ndtr::default().with_ndt(data.len() as u16));
+        stream.cr.set(dma::Cr::default()
+                      .with_chsel(route.get_channel())
+                      .with_dir(dma::Direction::MemoryToPeripheral)
+                      .with_msize(dma::TransferSize::Byte)
+                      .with_psize(dma::TransferSize::Byte)
+                      .with_minc(true)
+                      .with_tcie(true)
+                      .with_teie(true));
+
+        // Clear TC so that it reliably marks the end of *this* transfer.
+        self.usart.clear_sr(Sr::default().with_tc(true));
+        self.usart.update_cr3(|v| v.with_dmat(true));
+        stream.cr.update(|v| v.with_en(true));
+        Ok(())
     }
 
-    pub bit_enum ClockPolarity {
-        IdleLow = 0,
-        IdleHigh = 1,
+    /// To be called from the transmit DMA stream's interrupt handler.
+    /// Returns `true` if a transmission has just finished (or failed), making
+    /// the transmitter available again.
+    ///
+    /// Note that the final byte is still on the wire at this point; wait for
+    /// the USART's TC flag before, say, turning off an RS-485 driver.
+    pub fn handle_tx_interrupt(&self) -> bool {
+        let done = dma::TRANSFER_COMPLETE | dma::TRANSFER_ERROR;
+        let route = self.tx.route();
+        match route.get_interrupt_flags() {
+            Ok(f) if f.intersects(done) => {
+                route.clear_interrupt_flags(done);
+                true
+            },
+            _ => false,
+        }
     }
 
-    pub bit_enum ClockPhase {
-        CaptureOnFirstEdge = 0,
-        CaptureOnSecondEdge = 1,
+    /// Starts continuous reception into `buf`.  Any previous reception is
+    /// stopped, and its buffer forgotten.
+    pub fn start_receive(&self, buf: &'static mut [u8])
+        -> Result<(), DmaError> {
+        try!(check_dma_buffer(buf.as_ptr() as usize, buf.len()));
+        self.stop_receive();
+
+        let route = self.rx.route();
+        let stream = route.get_stream();
+        self.rx_buf.store(buf.as_ptr() as usize, Ordering::Relaxed);
+        self.rx_len.store(buf.len(), Ordering::Relaxed);
+        self.rx_pos.store(0, Ordering::Release);
+
+        route.clear_interrupt_flags(dma::InterruptFlags::all());
+        stream.par.set(self.usart.dr_address());
+        stream.mar[0].set(buf.as_ptr() as *const ());
+        stream.ndtr.set(dma::Ndtr::default().with_ndt(buf.len() as u16));
+        stream.cr.set(dma::Cr::default()
+                      .with_chsel(route.get_channel())
+                      .with_dir(dma::Direction::PeripheralToMemory)
+                      .with_msize(dma::TransferSize::Byte)
+                      .with_psize(dma::TransferSize::Byte)
+                      .with_minc(true)
+                      .with_circ(true)
+                      .with_htie(true)
+                      .with_tcie(true));
+
+        self.usart.update_cr3(|v| v.with_dmar(true));
+        self.usart.update_cr1(|v| v.with_idleie(true));
+        stream.cr.update(|v| v.with_en(true));
+        Ok(())
     }
 
-    pub bit_enum BreakLength {
-        TenBits = 0,
-        ElevenBits = 1,
+    /// Stops reception.  Data received but not yet delivered is discarded.
+    pub fn stop_receive(&self) {
+        self.usart.update_cr1(|v| v.with_idleie(false));
+        self.usart.update_cr3(|v| v.with_dmar(false));
+        let _ = self.rx.route().get_stream().abort();
+        self.rx_len.store(0, Ordering::Release);
     }
 
-    pub bit_enum SampleMethod {
-        ThreeBit = 0,
-        OneBit = 1,
+    /// To be called from both the USART's interrupt handler and the receive
+    /// DMA stream's interrupt handler.  Delivers any data received since the
+    /// last call to `frame`, as one slice -- or two, if it wraps around the
+    /// end of the buffer.  Returns `true` if anything was delivered.
+    pub fn handle_rx_interrupt<F: FnMut(&[u8])>(&self, mut frame: F) -> bool {
+        // Acknowledge the interrupt sources.  IDLE is cleared by reading SR
+        // then DR; this can't lose data, since DMA has already emptied DR.
+        if self.usart.read_sr().get_idle() {
+            let _ = self.usart.read_dr();
+        }
+        let route = self.rx.route();
+        route.clear_interrupt_flags(dma::HALF_TRANSFER
+                                    | dma::TRANSFER_COMPLETE);
+
+        let len = self.rx_len.load(Ordering::Acquire);
+        if len == 0 {
+            return false
+        }
+        let buf = unsafe {
+            slice::from_raw_parts(
+                self.rx_buf.load(Ordering::Relaxed) as *const u8, len)
+        };
+
+        // NDTR counts down from len, and reloads in circular mode.
+        let remaining = route.get_stream().remaining() as usize;
+        let head = (len - remaining) % len;
+        // Ensure our reads of the buffer aren't hoisted above NDTR.
+        atomic::fence(Ordering::Acquire);
+
+        let tail = self.rx_pos.load(Ordering::Relaxed);
+        if head == tail {
+            return false
+        }
+
+        if head > tail {
+            frame(&buf[tail .. head]);
+        } else {
+            frame(&buf[tail ..]);
+            if head > 0 {
+                frame(&buf[.. head]);
+            }
+        }
+        self.rx_pos.store(head, Ordering::Relaxed);
+        true
     }
 }
 
+/// Error produced by the polling transmit path when the transmitter fails to
+/// make progress within the allotted number of cycles.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct Timeout;
 
-// ------------------------------------------------------------------
-
-pub struct Usart {
-    reg: *const Registers,
+/// A raw, polling-only transmitter for use on the panic/fault path.
+///
+/// Every wait is bounded by a cycle-count timeout (measured with the DWT cycle
+/// counter) and feeds the independent watchdog while spinning, so a wedged or
+/// unclocked USART can't hang the handler forever, and a slow one won't get us
+/// reset halfway through the report.
+///
+/// `PanicWriter` implements `fmt::Write`, so it can be used with `write!` from
+/// an application's `panic_fmt`.
+pub struct PanicWriter<'a> {
+    usart: &'a Usart,
+    timeout_cycles: u32,
 }
 
-macro_rules! reg_accessors {
-    ($name:ident, $ty:ident, $read:ident, $write:ident, $update:ident) => {
-        pub fn $write(&self, v: $ty) {
-            self.reg().$name.set(v.0)
+impl<'a> PanicWriter<'a> {
+    /// Takes over `usart` for degraded-mode output.  Each byte (and the final
+    /// flush) is allowed up to `timeout_cycles` CPU cycles.
+    ///
+    /// This calls `enter_polling_mode` on `usart` and starts the DWT cycle
+    /// counter if it isn't already running.
+    pub fn new(usart: &'a Usart, timeout_cycles: u32) -> PanicWriter<'a> {
+        usart.enter_polling_mode();
+        DWT.enable_cycle_counter();
+        PanicWriter {
+            usart: usart,
+            timeout_cycles: timeout_cycles,
         }
+    }
 
-        pub fn $read(&self) -> $ty {
-            $ty(self.reg().$name.get())
+    /// Sends a single byte, waiting for room in the transmit register.
+    pub fn send8(&self, v: u8) -> Result<(), Timeout> {
+        try!(self.wait(|sr| sr.get_txe()));
+        self.usart.send8(v);
+        Ok(())
+    }
+
+    /// Waits for the final byte to leave the shift register.
+    pub fn flush(&self) -> Result<(), Timeout> {
+        self.wait(|sr| sr.get_tc())
+    }
+
+    fn wait<F: Fn(Sr) -> bool>(&self, ready: F) -> Result<(), Timeout> {
+        let start = DWT.read_cycle_count();
+        while !ready(self.usart.read_sr()) {
+            IWDG.feed();
+            if DWT.read_cycle_count().wrapping_sub(start) > self.timeout_cycles {
+                return Err(Timeout)
+            }
         }
+        Ok(())
+    }
+}
 
-        pub fn $update<F: FnOnce($ty) -> $ty>(&self, f: F) {
-            self.$write(f(self.$read()))
+impl<'a> fmt::Write for PanicWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for b in s.bytes() {
+            try!(self.send8(b).map_err(|_| fmt::Error));
         }
-    };
+        self.flush().map_err(|_| fmt::Error)
+    }
 }
 
-impl Usart {
-    fn reg(&self) -> &Registers {
-        unsafe {
-            &*self.reg
+impl SerialWrite for Usart {
+    type Error = SerialError;
+
+    fn try_write(&self, byte: u8) -> NbResult<(), SerialError> {
+        if self.read_sr().get_txe() {
+            self.send8(byte);
+            Ok(())
+        } else {
+            Err(NbError::WouldBlock)
         }
     }
 
-    reg_accessors!(cr1, Cr1, read_cr1, write_cr1, update_cr1);
-    reg_accessors!(brr, Brr, read_brr, write_brr, update_brr);
+    fn try_flush(&self) -> NbResult<(), SerialError> {
+        if self.read_sr().get_tc() {
+            Ok(())
+        } else {
+            Err(NbError::WouldBlock)
+        }
+    }
+}
 
-    pub fn send8(&self, v: u8) {
-        self.reg().dr.set(v as u32)
+impl SerialRead for Usart {
+    type Error = SerialError;
+
+    fn try_read(&self) -> NbResult<u8, SerialError> {
+        let sr = self.read_sr();
+        let err = if sr.get_ore() {
+            Some(SerialError::Overrun)
+        } else if sr.get_fe() {
+            Some(SerialError::Framing)
+        } else if sr.get_nf() {
+            Some(SerialError::Noise)
+        } else if sr.get_pe() {
+            Some(SerialError::Parity)
+        } else {
+            None
+        };
+
+        match err {
+            Some(e) => {
+                // Reading DR after SR clears the error flags.
+                let _ = self.recv8();
+                Err(NbError::Other(e))
+            },
+            None if sr.get_rxne() => Ok(self.recv8()),
+            None => Err(NbError::WouldBlock),
+        }
     }
 }
 
 unsafe impl Sync for Usart {}
 
 macro_rules! static_usart {
-    ($name:ident, $addr:expr) => {
+    ($name:ident, $addr:expr, $af:ident, $periph:ident) => {
         pub static $name: Usart = Usart {
             reg: $addr as *const Registers,
+            af: gpio::Function::$af,
+            peripheral: ApbPeripheral::$periph,
         };
     };
 }
 
-static_usart!(USART2, 0x40004400);
+static_usart!(USART1, 0x40011000, AF7, Usart1);
+static_usart!(USART2, 0x40004400, AF7, Usart2);
+static_usart!(USART3, 0x40004800, AF7, Usart3);
+static_usart!(UART4, 0x40004c00, AF8, Uart4);
+static_usart!(UART5, 0x40005000, AF8, Uart5);
+static_usart!(USART6, 0x40011400, AF8, Usart6);
 
 
 
+
+#[cfg(all(test, feature = "host-test"))]
+mod tests {
+    use arm_m::sim::{self, AccessKind};
+    use stm32f4::rcc::ClockSpeeds;
+    use super::*;
+
+    const USART2_SR : usize = 0x40004400;
+    const USART2_CR1 : usize = 0x4000440C;
+    const USART2_BRR : usize = 0x40004408;
+
+    const SPEEDS : ClockSpeeds = ClockSpeeds {
+        cpu: 168_000_000.,
+        ahb: 168_000_000.,
+        apb1: 42_000_000.,
+        apb2: 84_000_000.,
+        pll48: 48_000_000.,
+    };
+
+    #[test]
+    fn set_baud_uses_16x_when_close_enough() {
+        sim::reset();
+        let cfg = USART2.set_baud(&SPEEDS, 115_200).ok().unwrap();
+        assert!(cfg.oversampling == Oversampling::By16);
+
+        // 42MHz / 115200 = 364.6, rounded to 365 = 22 + 13/16.
+        assert_eq!(cfg.brr.get_mantissa(), 22);
+        assert_eq!(cfg.brr.get_fraction(), 13);
+        assert_eq!(sim::peek(USART2_BRR), 0x16D);
+        assert!(!Cr1(sim::peek(USART2_CR1)).get_over8());
+        assert!(cfg.abs_error_percent() < 0.2);
+
+        let log = sim::take_log();
+        assert!(log.iter().any(|a| a.kind == AccessKind::Write
+                                   && a.address == USART2_BRR
+                                   && a.value == 0x16D));
+    }
+
+    #[test]
+    fn set_baud_falls_back_to_8x() {
+        sim::reset();
+        // Too fast for 16x: USARTDIV would be below one.
+        let cfg = USART2.set_baud(&SPEEDS, 4_000_000).ok().unwrap();
+        assert!(cfg.oversampling == Oversampling::By8);
+        assert_eq!(cfg.brr.get_mantissa(), 1);
+        assert_eq!(cfg.brr.get_fraction(), 3);
+        assert_eq!(sim::peek(USART2_BRR), 0x13);
+        assert!(Cr1(sim::peek(USART2_CR1)).get_over8());
+    }
+
+    #[test]
+    fn clear_sr_leaves_other_flags_alone() {
+        sim::reset();
+        USART2.clear_sr(Sr::default().with_tc(true));
+        let sr = Sr(sim::peek(USART2_SR));
+        assert!(!sr.get_tc());
+        assert!(sr.get_cts() && sr.get_lbd() && sr.get_rxne());
+        // Read-only bits are written as zero.
+        assert_eq!(sr.0 & !SR_CLEARABLE, 0);
+    }
+
+    #[test]
+    fn set_baud_rejects_unreachable_rates() {
+        sim::reset();
+        match USART2.set_baud(&SPEEDS, 100) {
+            Err(BaudError::TooSlow) => (),
+            _ => panic!("100 baud should be too slow"),
+        }
+        match BaudConfig::compute(42_000_000., 0, Oversampling::By16) {
+            Err(BaudError::TooSlow) => (),
+            _ => panic!("0 baud should be too slow"),
+        }
+        // Nothing should have been written.
+        assert_eq!(sim::peek(USART2_BRR), 0);
+    }
+}
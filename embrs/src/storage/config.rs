@@ -0,0 +1,376 @@
+//! Versioned application settings, kept in an emulated EEPROM.
+//!
+//! An application describes its settings as a struct implementing
+//! `Settings`, which encodes the fields, in order, as a list of 32-bit
+//! words, and decodes them again.  `save` stores the words under a range of
+//! `Eeprom` keys, and `load` gets them back:
+//!
+//!     struct Prefs { volume: u8, gain: f32 }
+//!
+//!     impl Settings for Prefs {
+//!         fn version() -> u16 { 1 }
+//!         fn defaults() -> Prefs { Prefs { volume: 5, gain: 1.0 } }
+//!         fn encode(&self, out: &mut Encoder) {
+//!             out.put_u8(self.volume);
+//!             out.put_f32(self.gain);
+//!         }
+//!         fn decode(input: &mut Decoder) -> Result<Prefs, DecodeError> {
+//!             Ok(Prefs {
+//!                 volume: try!(input.get_u8()),
+//!                 gain: try!(input.get_f32()),
+//!             })
+//!         }
+//!     }
+//!
+//!     let (prefs, _) = try!(config::load::<Prefs, _>(&eeprom, 0x100));
+//!
+//! The stored words carry the schema version they were encoded with.  When
+//! the struct changes, bump `version` and implement `migrate`, which is given
+//! older words to make sense of; fields appended to the end can often be
+//! handled by checking `Decoder::remaining`.  Anything `load` can't use --
+//! nothing stored yet, a failed checksum, a version newer than the code, a
+//! migration that gives up -- yields `defaults`, and `load` says which
+//! happened, so the application can decide whether to save.
+//!
+//! There are two copies of the words, each with a sequence number and CRC,
+//! and `save` overwrites the older one, so settings survive a power failure
+//! part way through a save.  Saving settings that haven't changed writes
+//! nothing, and since the EEPROM skips writes that change nothing, saving a
+//! change writes little more than the words that differ.
+
+use core::mem;
+
+use crc::crc32_continue;
+use hal::NorFlash;
+use super::eeprom::{Eeprom, EepromError, Value};
+
+/// Most words a settings struct can encode to.
+pub const MAX_WORDS : usize = 32;
+/// Keys in each copy's header: sequence number, version and length, CRC.
+const HEADER_KEYS : u16 = 3;
+/// Keys used by each copy.
+const COPY_KEYS : u16 = HEADER_KEYS + MAX_WORDS as u16;
+/// Number of keys used, starting at the base key given to `load` and
+/// `save`.
+pub const KEYS_USED : u16 = 2 * COPY_KEYS;
+
+/// A struct that can be stored with `save` and `load`.
+pub trait Settings: Sized {
+    /// The schema version.  Change it whenever the encoding changes.
+    fn version() -> u16;
+
+    /// The settings to use when there are none stored, or they can't be
+    /// used.
+    fn defaults() -> Self;
+
+    /// Encodes the fields, in order.
+    fn encode(&self, out: &mut Encoder);
+
+    /// Decodes fields encoded by `encode`.
+    fn decode(input: &mut Decoder) -> Result<Self, DecodeError>;
+
+    /// Decodes fields encoded by schema `version`, which is older than the
+    /// current one.  By default, this gives up, and `load` returns the
+    /// defaults.
+    fn migrate(_version: u16, _input: &mut Decoder)
+        -> Result<Self, DecodeError> {
+        Err(DecodeError)
+    }
+}
+
+/// Where `load` got the settings from.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Origin {
+    /// The stored settings, as they were.
+    Stored,
+    /// Stored settings from the given, older, schema version, passed
+    /// through `Settings::migrate`.
+    Migrated(u16),
+    /// `Settings::defaults`.
+    Defaults,
+}
+
+/// Ways that `save` can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ConfigError<E> {
+    Eeprom(EepromError<E>),
+    /// The settings encoded to more than `MAX_WORDS` words.
+    TooBig,
+}
+
+/// Error returned by `Decoder` when the words run out or don't make sense.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct DecodeError;
+
+/// Collects encoded fields; see `Settings::encode`.
+pub struct Encoder {
+    words: [u32; MAX_WORDS],
+    len: usize,
+    overflow: bool,
+}
+
+impl Encoder {
+    pub fn put_u32(&mut self, v: u32) {
+        if self.len < MAX_WORDS {
+            self.words[self.len] = v;
+            self.len += 1
+        } else {
+            self.overflow = true
+        }
+    }
+
+    pub fn put_i32(&mut self, v: i32) {
+        self.put_u32(v as u32)
+    }
+
+    pub fn put_u16(&mut self, v: u16) {
+        self.put_u32(v as u32)
+    }
+
+    pub fn put_u8(&mut self, v: u8) {
+        self.put_u32(v as u32)
+    }
+
+    pub fn put_bool(&mut self, v: bool) {
+        self.put_u32(v as u32)
+    }
+
+    pub fn put_f32(&mut self, v: f32) {
+        self.put_u32(unsafe { mem::transmute(v) })
+    }
+
+    /// Encodes a byte string -- a name, say -- as its length followed by its
+    /// bytes, four to a word.
+    pub fn put_bytes(&mut self, v: &[u8]) {
+        self.put_u32(v.len() as u32);
+        for chunk in v.chunks(4) {
+            let mut word = 0;
+            for (i, &b) in chunk.iter().enumerate() {
+                word |= (b as u32) << (8 * i)
+            }
+            self.put_u32(word)
+        }
+    }
+}
+
+/// Hands out encoded fields; see `Settings::decode`.
+pub struct Decoder<'a> {
+    words: &'a [u32],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    /// Returns the number of words not yet decoded.
+    pub fn remaining(&self) -> usize {
+        self.words.len() - self.pos
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32, DecodeError> {
+        match self.words.get(self.pos) {
+            Some(&w) => {
+                self.pos += 1;
+                Ok(w)
+            }
+            None => Err(DecodeError),
+        }
+    }
+
+    pub fn get_i32(&mut self) -> Result<i32, DecodeError> {
+        self.get_u32().map(|v| v as i32)
+    }
+
+    pub fn get_u16(&mut self) -> Result<u16, DecodeError> {
+        match try!(self.get_u32()) {
+            v @ 0...0xFFFF => Ok(v as u16),
+            _ => Err(DecodeError),
+        }
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8, DecodeError> {
+        match try!(self.get_u32()) {
+            v @ 0...0xFF => Ok(v as u8),
+            _ => Err(DecodeError),
+        }
+    }
+
+    pub fn get_bool(&mut self) -> Result<bool, DecodeError> {
+        match try!(self.get_u32()) {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError),
+        }
+    }
+
+    pub fn get_f32(&mut self) -> Result<f32, DecodeError> {
+        self.get_u32().map(|v| unsafe { mem::transmute(v) })
+    }
+
+    /// Decodes a byte string into `buf`, returning its length.  Fails if it
+    /// doesn't fit.
+    pub fn get_bytes(&mut self, buf: &mut [u8]) -> Result<usize, DecodeError> {
+        let len = try!(self.get_u32()) as usize;
+        if len > buf.len() {
+            return Err(DecodeError)
+        }
+        for chunk in buf[..len].chunks_mut(4) {
+            let word = try!(self.get_u32());
+            for (i, b) in chunk.iter_mut().enumerate() {
+                *b = (word >> (8 * i)) as u8
+            }
+        }
+        Ok(len)
+    }
+}
+
+/// One stored copy of the settings.
+struct Snapshot {
+    sequence: u32,
+    version: u16,
+    words: [u32; MAX_WORDS],
+    len: usize,
+}
+
+/// Computes the CRC stored with a copy, over its version-and-length word
+/// and its contents.
+fn copy_crc(header: u32, words: &[u32]) -> u32 {
+    let mut crc = crc32_continue(0, &le32_bytes(header));
+    for &w in words {
+        crc = crc32_continue(crc, &le32_bytes(w))
+    }
+    crc
+}
+
+fn le32_bytes(v: u32) -> [u8; 4] {
+    [v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]
+}
+
+fn read_word<F: NorFlash>(eeprom: &Eeprom<F>, key: u16)
+    -> Result<Option<u32>, EepromError<F::Error>> {
+    match try!(eeprom.read(key)) {
+        Some(Value::U32(v)) => Ok(Some(v)),
+        _ => Ok(None),
+    }
+}
+
+/// Reads the copy starting at `base`, if it's intact.
+fn read_copy<F: NorFlash>(eeprom: &Eeprom<F>, base: u16)
+    -> Result<Option<Snapshot>, EepromError<F::Error>> {
+    let mut header = [0; HEADER_KEYS as usize];
+    for (i, h) in header.iter_mut().enumerate() {
+        match try!(read_word(eeprom, base + i as u16)) {
+            Some(v) => *h = v,
+            None => return Ok(None),
+        }
+    }
+
+    let len = (header[1] & 0xFFFF) as usize;
+    if len > MAX_WORDS {
+        return Ok(None)
+    }
+    let mut words = [0; MAX_WORDS];
+    for (i, w) in words[..len].iter_mut().enumerate() {
+        match try!(read_word(eeprom, base + HEADER_KEYS + i as u16)) {
+            Some(v) => *w = v,
+            None => return Ok(None),
+        }
+    }
+
+    if copy_crc(header[1], &words[..len]) != header[2] {
+        return Ok(None)
+    }
+    Ok(Some(Snapshot {
+        sequence: header[0],
+        version: (header[1] >> 16) as u16,
+        words: words,
+        len: len,
+    }))
+}
+
+/// Finds the newer intact copy, returning which it is (0 or 1) too.
+fn newest<F: NorFlash>(eeprom: &Eeprom<F>, base: u16)
+    -> Result<Option<(u16, Snapshot)>, EepromError<F::Error>> {
+    let a = try!(read_copy(eeprom, base));
+    let b = try!(read_copy(eeprom, base + COPY_KEYS));
+    Ok(match (a, b) {
+        (Some(a), Some(b)) => {
+            if (b.sequence.wrapping_sub(a.sequence) as i32) > 0 {
+                Some((1, b))
+            } else {
+                Some((0, a))
+            }
+        }
+        (Some(a), None) => Some((0, a)),
+        (None, Some(b)) => Some((1, b)),
+        (None, None) => None,
+    })
+}
+
+/// Loads settings stored by `save` under the `KEYS_USED` keys starting at
+/// `base`, falling back to the defaults if there are none that can be used.
+/// Only Flash errors are reported as errors.
+pub fn load<S: Settings, F: NorFlash>(eeprom: &Eeprom<F>, base: u16)
+    -> Result<(S, Origin), EepromError<F::Error>> {
+    let copy = match try!(newest(eeprom, base)) {
+        Some((_, copy)) => copy,
+        None => return Ok((S::defaults(), Origin::Defaults)),
+    };
+
+    let mut input = Decoder {
+        words: &copy.words[..copy.len],
+        pos: 0,
+    };
+    let result = if copy.version == S::version() {
+        S::decode(&mut input).map(|s| (s, Origin::Stored))
+    } else if copy.version < S::version() {
+        S::migrate(copy.version, &mut input)
+            .map(|s| (s, Origin::Migrated(copy.version)))
+    } else {
+        Err(DecodeError)
+    };
+    Ok(result.unwrap_or_else(|_| (S::defaults(), Origin::Defaults)))
+}
+
+/// Stores `settings` under the `KEYS_USED` keys starting at `base`, in place
+/// of the older of the two copies.
+pub fn save<S: Settings, F: NorFlash>(eeprom: &mut Eeprom<F>,
+                                      base: u16,
+                                      settings: &S)
+    -> Result<(), ConfigError<F::Error>> {
+    let mut out = Encoder {
+        words: [0; MAX_WORDS],
+        len: 0,
+        overflow: false,
+    };
+    settings.encode(&mut out);
+    if out.overflow {
+        return Err(ConfigError::TooBig)
+    }
+
+    let (target, sequence) =
+        match try!(newest(eeprom, base).map_err(ConfigError::Eeprom)) {
+            Some((_, ref copy)) if copy.version == S::version()
+                && copy.words[..copy.len] == out.words[..out.len] => {
+                // Nothing has changed.
+                return Ok(())
+            }
+            Some((which, copy)) => (1 - which, copy.sequence.wrapping_add(1)),
+            None => (0, 0),
+        };
+    let target = base + target * COPY_KEYS;
+
+    // Contents first, then the header, with the sequence number last: until
+    // it's written, the other copy is still the newer one.
+    let header = (S::version() as u32) << 16 | out.len as u32;
+    let crc = copy_crc(header, &out.words[..out.len]);
+    for (i, &w) in out.words[..out.len].iter().enumerate() {
+        try!(eeprom.write(target + HEADER_KEYS + i as u16, Value::U32(w))
+             .map_err(ConfigError::Eeprom));
+    }
+    try!(eeprom.write(target + 1, Value::U32(header))
+         .map_err(ConfigError::Eeprom));
+    try!(eeprom.write(target + 2, Value::U32(crc))
+         .map_err(ConfigError::Eeprom));
+    try!(eeprom.write(target, Value::U32(sequence))
+         .map_err(ConfigError::Eeprom));
+    Ok(())
+}
@@ -0,0 +1,398 @@
+//! Emulated EEPROM: a small key/value store in two Flash sectors.
+//!
+//! This follows the scheme in ST's application note AN3969.  Writes append a
+//! record -- a 16-bit key and a 16- or 32-bit value -- to the active sector,
+//! and the latest record for a key wins, so most writes need no erase.  When
+//! the active sector fills up, the latest value of each key is copied to the
+//! other sector, and the old one is erased: a *compaction*.  Each sector
+//! starts with a state word saying where it stands in that cycle:
+//!
+//! - *erased*: unused.
+//! - *receiving*: the target of a compaction in progress.
+//! - *valid*: holds the current data.
+//!
+//! A compaction copies the data, erases the old sector, and only then marks
+//! the new one valid, so `Eeprom::mount` can always tell which sector to
+//! trust after a reset or power loss part way through, and either finishes
+//! the compaction or starts it over.  Records are programmed value first,
+//! key last, so one cut short is recognizably incomplete and is ignored --
+//! the `write` it belonged to never returned.
+//!
+//! Finding the latest value of each key needs no RAM, but takes time
+//! quadratic in the number of records; compacting a full 16KiB sector
+//! takes a few tens of milliseconds, plus the erase.
+
+use hal::NorFlash;
+
+/// State word of an unused sector.
+const STATE_ERASED : u32 = 0xFFFF_FFFF;
+/// State word of a sector being filled by a compaction.
+const STATE_RECEIVING : u32 = 0xEEEE_EEEE;
+/// State word of the sector holding the current data.
+const STATE_VALID : u32 = 0x0000_0000;
+
+/// Bytes at the start of each sector holding the state word, padded to keep
+/// the records aligned.
+const HEADER_SIZE : usize = 8;
+/// Bytes per record: key, kind, then value, little-endian.
+const RECORD_SIZE : usize = 8;
+
+/// Record kind for a 16-bit value.
+const KIND_U16 : u16 = 0x0010;
+/// Record kind for a 32-bit value.
+const KIND_U32 : u16 = 0x0020;
+
+/// The one key that can't be used: it's what an unprogrammed record holds.
+pub const RESERVED_KEY : u16 = 0xFFFF;
+
+/// A stored value.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Value {
+    U16(u16),
+    U32(u32),
+}
+
+/// Ways that an `Eeprom` operation can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum EepromError<E> {
+    /// The Flash reported an error.
+    Flash(E),
+    /// The key was `RESERVED_KEY`.
+    InvalidKey,
+    /// Every record is in use by a distinct key, so even a compaction can't
+    /// make room.
+    Full,
+}
+
+/// Contents of one record slot.
+enum Slot {
+    Free,
+    /// Partially programmed, and so ignored.
+    Torn,
+    Record(u16, Value),
+}
+
+fn decode(raw: &[u8; RECORD_SIZE]) -> Slot {
+    if raw.iter().all(|&b| b == 0xFF) {
+        return Slot::Free
+    }
+
+    let key = raw[0] as u16 | (raw[1] as u16) << 8;
+    let kind = raw[2] as u16 | (raw[3] as u16) << 8;
+    let value = raw[4] as u32
+        | (raw[5] as u32) << 8
+        | (raw[6] as u32) << 16
+        | (raw[7] as u32) << 24;
+
+    match kind {
+        _ if key == RESERVED_KEY => Slot::Torn,
+        KIND_U16 if value <= 0xFFFF => {
+            Slot::Record(key, Value::U16(value as u16))
+        }
+        KIND_U32 => Slot::Record(key, Value::U32(value)),
+        _ => Slot::Torn,
+    }
+}
+
+/// A key/value store in the first two sectors of a `NorFlash` region.
+pub struct Eeprom<F: NorFlash> {
+    flash: F,
+    /// The valid sector, 0 or 1.
+    active: u32,
+    /// Offset within the active sector of the first free record.
+    next: usize,
+}
+
+impl<F: NorFlash> Eeprom<F> {
+    /// Mounts the store in the first two sectors of `flash`, finishing or
+    /// restarting any compaction that was interrupted.  If neither sector
+    /// holds valid data -- on first use, say -- both are erased and the
+    /// store starts out empty.
+    ///
+    /// # Panics
+    ///
+    /// If `flash` has fewer than two sectors.
+    pub fn mount(flash: F) -> Result<Eeprom<F>, EepromError<F::Error>> {
+        assert!(flash.sector_count() >= 2);
+        let mut eeprom = Eeprom {
+            flash: flash,
+            active: 0,
+            next: HEADER_SIZE,
+        };
+        let states = [try!(eeprom.state(0)), try!(eeprom.state(1))];
+        try!(eeprom.recover(states));
+        Ok(eeprom)
+    }
+
+    /// Returns the Flash region, giving up the store.
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// Reads the latest value of `key`, if it has one.
+    pub fn read(&self, key: u16)
+        -> Result<Option<Value>, EepromError<F::Error>> {
+        let mut offset = self.next;
+        while offset > HEADER_SIZE {
+            offset -= RECORD_SIZE;
+            if let Slot::Record(k, v) = try!(self.slot(self.active, offset)) {
+                if k == key {
+                    return Ok(Some(v))
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Sets the value of `key`.  Writing the value `key` already has does
+    /// nothing, to save wear.
+    pub fn write(&mut self, key: u16, value: Value)
+        -> Result<(), EepromError<F::Error>> {
+        if key == RESERVED_KEY {
+            return Err(EepromError::InvalidKey)
+        }
+        if try!(self.read(key)) == Some(value) {
+            return Ok(())
+        }
+
+        if self.next + RECORD_SIZE > self.flash.sector_size() {
+            return self.compact(Some((key, value)))
+        }
+
+        try!(self.append(self.active, self.next, key, value));
+        self.next += RECORD_SIZE;
+        Ok(())
+    }
+
+    /// Iterates over the keys that have values, with their latest values,
+    /// in the order they were last written.
+    pub fn iter(&self) -> Iter<F> {
+        Iter {
+            eeprom: self,
+            offset: HEADER_SIZE,
+        }
+    }
+
+    /// Returns the number of records that can be written before the next
+    /// compaction.
+    pub fn free_records(&self) -> usize {
+        (self.flash.sector_size() - self.next) / RECORD_SIZE
+    }
+
+    /// Erases every key.
+    pub fn format(&mut self) -> Result<(), EepromError<F::Error>> {
+        try!(self.ensure_erased(0));
+        try!(self.ensure_erased(1));
+        try!(self.set_state(0, STATE_VALID));
+        self.active = 0;
+        self.next = HEADER_SIZE;
+        Ok(())
+    }
+
+    /// Works out which sector to use, given their states, and repairs the
+    /// other.
+    fn recover(&mut self, states: [u32; 2])
+        -> Result<(), EepromError<F::Error>> {
+        for &(a, b) in [(0, 1), (1, 0)].iter() {
+            match (states[a as usize], states[b as usize]) {
+                (STATE_VALID, STATE_VALID) => break,
+                (STATE_VALID, STATE_RECEIVING) => {
+                    // The copy into b was cut short: start over.
+                    try!(self.open(a));
+                    return self.compact(None)
+                }
+                (STATE_VALID, STATE_ERASED) => return self.open(a),
+                (STATE_VALID, _) => {
+                    try!(self.open(a));
+                    return self.ensure_erased(b)
+                }
+                (STATE_RECEIVING, STATE_VALID)
+                    | (STATE_RECEIVING, STATE_RECEIVING) => (),
+                (STATE_RECEIVING, _) => {
+                    // The copy into a finished, and b was being erased, or a
+                    // was about to be marked valid.
+                    try!(self.ensure_erased(b));
+                    try!(self.set_state(a, STATE_VALID));
+                    return self.open(a)
+                }
+                _ => (),
+            }
+        }
+        self.format()
+    }
+
+    /// Makes `sector` active, finding the end of its records.
+    fn open(&mut self, sector: u32) -> Result<(), EepromError<F::Error>> {
+        let mut next = HEADER_SIZE;
+        let mut offset = HEADER_SIZE;
+        while offset + RECORD_SIZE <= self.flash.sector_size() {
+            match try!(self.slot(sector, offset)) {
+                Slot::Free => (),
+                _ => next = offset + RECORD_SIZE,
+            }
+            offset += RECORD_SIZE;
+        }
+        self.active = sector;
+        self.next = next;
+        Ok(())
+    }
+
+    /// Copies the latest value of each key, plus `pending`, into the other
+    /// sector, and switches to it.
+    fn compact(&mut self, pending: Option<(u16, Value)>)
+        -> Result<(), EepromError<F::Error>> {
+        let skip = pending.map(|(k, _)| k).unwrap_or(RESERVED_KEY);
+
+        // Make sure everything fits before changing anything.
+        let mut needed = if pending.is_some() { 1 } else { 0 };
+        for r in self.iter() {
+            let (k, _) = try!(r);
+            if k != skip {
+                needed += 1
+            }
+        }
+        if needed > (self.flash.sector_size() - HEADER_SIZE) / RECORD_SIZE {
+            return Err(EepromError::Full)
+        }
+
+        let from = self.active;
+        let to = 1 - from;
+        try!(self.ensure_erased(to));
+        try!(self.set_state(to, STATE_RECEIVING));
+
+        let mut next = HEADER_SIZE;
+        if let Some((k, v)) = pending {
+            try!(self.append(to, next, k, v));
+            next += RECORD_SIZE;
+        }
+        for r in self.iter() {
+            let (k, v) = try!(r);
+            if k != skip {
+                try!(self.append(to, next, k, v));
+                next += RECORD_SIZE;
+            }
+        }
+
+        try!(self.flash.erase_sector(from).map_err(EepromError::Flash));
+        try!(self.set_state(to, STATE_VALID));
+        self.active = to;
+        self.next = next;
+        Ok(())
+    }
+
+    /// Checks whether a record for `key` appears in the active sector at or
+    /// after `offset`.
+    fn is_superseded(&self, key: u16, mut offset: usize)
+        -> Result<bool, EepromError<F::Error>> {
+        while offset < self.next {
+            if let Slot::Record(k, _) = try!(self.slot(self.active, offset)) {
+                if k == key {
+                    return Ok(true)
+                }
+            }
+            offset += RECORD_SIZE;
+        }
+        Ok(false)
+    }
+
+    fn slot(&self, sector: u32, offset: usize)
+        -> Result<Slot, EepromError<F::Error>> {
+        let mut raw = [0; RECORD_SIZE];
+        try!(self.flash.read(self.address(sector, offset), &mut raw)
+             .map_err(EepromError::Flash));
+        Ok(decode(&raw))
+    }
+
+    fn append(&self, sector: u32, offset: usize, key: u16, value: Value)
+        -> Result<(), EepromError<F::Error>> {
+        let (kind, v) = match value {
+            Value::U16(v) => (KIND_U16, v as u32),
+            Value::U32(v) => (KIND_U32, v),
+        };
+        let address = self.address(sector, offset);
+        try!(self.flash.program(address + 4,
+                                &[v as u8, (v >> 8) as u8,
+                                  (v >> 16) as u8, (v >> 24) as u8])
+             .map_err(EepromError::Flash));
+        self.flash.program(address,
+                           &[key as u8, (key >> 8) as u8,
+                             kind as u8, (kind >> 8) as u8])
+            .map_err(EepromError::Flash)
+    }
+
+    fn state(&self, sector: u32) -> Result<u32, EepromError<F::Error>> {
+        let mut raw = [0; 4];
+        try!(self.flash.read(self.address(sector, 0), &mut raw)
+             .map_err(EepromError::Flash));
+        Ok(raw[0] as u32
+           | (raw[1] as u32) << 8
+           | (raw[2] as u32) << 16
+           | (raw[3] as u32) << 24)
+    }
+
+    /// Programs a sector's state word.  Each state only clears bits of the
+    /// one before, so this works without an erase.
+    fn set_state(&self, sector: u32, state: u32)
+        -> Result<(), EepromError<F::Error>> {
+        self.flash.program(self.address(sector, 0),
+                           &[state as u8, (state >> 8) as u8,
+                             (state >> 16) as u8, (state >> 24) as u8])
+            .map_err(EepromError::Flash)
+    }
+
+    /// Erases `sector` unless it already reads as erased.
+    fn ensure_erased(&self, sector: u32)
+        -> Result<(), EepromError<F::Error>> {
+        let mut offset = 0;
+        while offset < self.flash.sector_size() {
+            let mut raw = [0; RECORD_SIZE];
+            try!(self.flash.read(self.address(sector, offset), &mut raw)
+                 .map_err(EepromError::Flash));
+            if raw.iter().any(|&b| b != 0xFF) {
+                return self.flash.erase_sector(sector)
+                    .map_err(EepromError::Flash)
+            }
+            offset += RECORD_SIZE;
+        }
+        Ok(())
+    }
+
+    fn address(&self, sector: u32, offset: usize) -> usize {
+        sector as usize * self.flash.sector_size() + offset
+    }
+}
+
+/// Iterator over the keys in an `Eeprom`; see `Eeprom::iter`.
+pub struct Iter<'a, F: NorFlash + 'a> {
+    eeprom: &'a Eeprom<F>,
+    offset: usize,
+}
+
+impl<'a, F: NorFlash + 'a> Iterator for Iter<'a, F> {
+    type Item = Result<(u16, Value), EepromError<F::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let e = self.eeprom;
+        while self.offset < e.next {
+            let offset = self.offset;
+            self.offset += RECORD_SIZE;
+            let (k, v) = match e.slot(e.active, offset) {
+                Ok(Slot::Record(k, v)) => (k, v),
+                Ok(_) => continue,
+                Err(err) => {
+                    self.offset = e.next;
+                    return Some(Err(err))
+                }
+            };
+            match e.is_superseded(k, self.offset) {
+                Ok(false) => return Some(Ok((k, v))),
+                Ok(true) => (),
+                Err(err) => {
+                    self.offset = e.next;
+                    return Some(Err(err))
+                }
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,10 @@
+//! Persistent storage in Flash.
+//!
+//! These layers are written against `hal::NorFlash` -- on the STM32F4,
+//! `stm32f4::flash_sectors::FlashSectors` -- and spread their writes across
+//! the sectors they're given, since each sector survives only around ten
+//! thousand erases.  They need no heap.
+
+pub mod config;
+pub mod eeprom;
+pub mod ring_log;
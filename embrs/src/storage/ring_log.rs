@@ -0,0 +1,318 @@
+//! Append-only circular log, for telemetry and black-box recording.
+//!
+//! `RingLog` appends variable-length records to a run of Flash sectors, in
+//! turn.  When the sector being written fills up, the log moves on to the
+//! next, erasing it first -- and when it wraps around, that's the oldest
+//! sector, so the log keeps the most recent records and forgets the rest a
+//! sector at a time.  It needs at least two sectors, so that a sector's worth
+//! of history survives each wrap.
+//!
+//! Each sector starts with a header holding a magic number and a sequence
+//! number, which increases with each sector started; the highest marks the
+//! newest.  Each record has a header holding its length and the CRC-32 of
+//! its contents.  Contents are programmed before the header, and a reader
+//! stops at the first record in a sector whose header or CRC doesn't check
+//! out, so a record cut short by reset or power loss is never returned.
+//! After a reset, `mount` starts a fresh sector rather than write after such
+//! a record.
+
+use core::cmp;
+
+use crc::{crc32, crc32_continue};
+use hal::NorFlash;
+
+/// Magic number opening each sector in use.
+const SECTOR_MAGIC : u32 = 0x4c4f_4721;
+/// Bytes of sector header: magic, then sequence number.
+const SECTOR_HEADER_SIZE : usize = 8;
+/// Bytes of record header: length, its complement, then CRC.
+const RECORD_HEADER_SIZE : usize = 8;
+/// Largest record length the header can express; `0xFFFF` would look
+/// unprogrammed.
+const MAX_LEN : usize = 0xFFFE;
+
+/// Ways that a `RingLog` operation can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum LogError<E> {
+    /// The Flash reported an error.
+    Flash(E),
+    /// The record won't fit in a sector (see `RingLog::max_record`).
+    TooLong,
+}
+
+fn le32(raw: &[u8]) -> u32 {
+    raw[0] as u32
+        | (raw[1] as u32) << 8
+        | (raw[2] as u32) << 16
+        | (raw[3] as u32) << 24
+}
+
+fn le32_bytes(v: u32) -> [u8; 4] {
+    [v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]
+}
+
+/// A circular log in a `NorFlash` region.
+pub struct RingLog<F: NorFlash> {
+    flash: F,
+    /// The sector being written.
+    head: u32,
+    /// The sequence number of `head`.
+    sequence: u32,
+    /// Offset within `head` of the next record.
+    next: usize,
+}
+
+impl<F: NorFlash> RingLog<F> {
+    /// Mounts the log in `flash`, finding the end of the records already
+    /// there.  If there are none, the log is started in sector 0.
+    ///
+    /// # Panics
+    ///
+    /// If `flash` has fewer than two sectors.
+    pub fn mount(flash: F) -> Result<RingLog<F>, LogError<F::Error>> {
+        assert!(flash.sector_count() >= 2);
+        let mut log = RingLog {
+            flash: flash,
+            head: 0,
+            sequence: 0,
+            next: SECTOR_HEADER_SIZE,
+        };
+
+        let mut newest = None;
+        for sector in 0..log.flash.sector_count() {
+            if let Some(seq) = try!(log.sector_sequence(sector)) {
+                match newest {
+                    Some((_, s)) if s >= seq => (),
+                    _ => newest = Some((sector, seq)),
+                }
+            }
+        }
+
+        match newest {
+            None => try!(log.start_sector(0, 0)),
+            Some((sector, seq)) => {
+                log.head = sector;
+                log.sequence = seq;
+                let mut next = SECTOR_HEADER_SIZE;
+                while let Some(len) = try!(log.record_at(sector, next)) {
+                    next += RECORD_HEADER_SIZE + len;
+                }
+                log.next = next;
+                // Anything past the last good record is a torn one, which
+                // can't be written over.
+                if !try!(log.is_erased_from(sector, next)) {
+                    try!(log.advance())
+                }
+            }
+        }
+        Ok(log)
+    }
+
+    /// Returns the Flash region, giving up the log.
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// Returns the length of the longest record that can be appended.
+    pub fn max_record(&self) -> usize {
+        let room = self.flash.sector_size()
+            - SECTOR_HEADER_SIZE - RECORD_HEADER_SIZE;
+        cmp::min(room, MAX_LEN)
+    }
+
+    /// Appends `data` as one record, erasing the oldest sector if the
+    /// current one is full.
+    pub fn append(&mut self, data: &[u8]) -> Result<(), LogError<F::Error>> {
+        if data.len() > self.max_record() {
+            return Err(LogError::TooLong)
+        }
+        let size = RECORD_HEADER_SIZE + data.len();
+        if self.next + size > self.flash.sector_size() {
+            try!(self.advance())
+        }
+
+        let len = data.len() as u16;
+        let crc = le32_bytes(crc32(data));
+        let header = [len as u8, (len >> 8) as u8,
+                      !len as u8, (!len >> 8) as u8,
+                      crc[0], crc[1], crc[2], crc[3]];
+        let address = self.address(self.head, self.next);
+        let result = self.flash.program(address + RECORD_HEADER_SIZE, data)
+            .and_then(|_| self.flash.program(address, &header));
+        match result {
+            Ok(()) => {
+                self.next += size;
+                Ok(())
+            }
+            Err(e) => {
+                // The space is no longer erased; move on from this sector
+                // next time.
+                self.next = self.flash.sector_size();
+                Err(LogError::Flash(e))
+            }
+        }
+    }
+
+    /// Erases the whole log.
+    pub fn clear(&mut self) -> Result<(), LogError<F::Error>> {
+        for sector in 1..self.flash.sector_count() {
+            try!(self.flash.erase_sector(sector).map_err(LogError::Flash))
+        }
+        self.start_sector(0, 0)
+    }
+
+    /// Returns a reader positioned at the oldest record.
+    pub fn reader(&self) -> Reader<F> {
+        Reader {
+            log: self,
+            sector: self.head,
+            remaining: self.flash.sector_count(),
+            offset: 0,
+        }
+    }
+
+    /// Moves on to the next sector, erasing it.
+    fn advance(&mut self) -> Result<(), LogError<F::Error>> {
+        let sector = (self.head + 1) % self.flash.sector_count();
+        let sequence = self.sequence.wrapping_add(1);
+        self.start_sector(sector, sequence)
+    }
+
+    fn start_sector(&mut self, sector: u32, sequence: u32)
+        -> Result<(), LogError<F::Error>> {
+        let address = self.address(sector, 0);
+        try!(self.flash.erase_sector(sector).map_err(LogError::Flash));
+        // Sequence number first, so that a sector with good magic has a
+        // good sequence number.
+        try!(self.flash.program(address + 4, &le32_bytes(sequence))
+             .map_err(LogError::Flash));
+        try!(self.flash.program(address, &le32_bytes(SECTOR_MAGIC))
+             .map_err(LogError::Flash));
+        self.head = sector;
+        self.sequence = sequence;
+        self.next = SECTOR_HEADER_SIZE;
+        Ok(())
+    }
+
+    /// Returns the sequence number of `sector`, if it's in use.
+    fn sector_sequence(&self, sector: u32)
+        -> Result<Option<u32>, LogError<F::Error>> {
+        let mut raw = [0; SECTOR_HEADER_SIZE];
+        try!(self.flash.read(self.address(sector, 0), &mut raw)
+             .map_err(LogError::Flash));
+        if le32(&raw[..4]) == SECTOR_MAGIC {
+            Ok(Some(le32(&raw[4..])))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Checks for an intact record at `offset` in `sector`, returning the
+    /// length of its contents.
+    fn record_at(&self, sector: u32, offset: usize)
+        -> Result<Option<usize>, LogError<F::Error>> {
+        let sector_size = self.flash.sector_size();
+        if offset + RECORD_HEADER_SIZE > sector_size {
+            return Ok(None)
+        }
+        let mut header = [0; RECORD_HEADER_SIZE];
+        let address = self.address(sector, offset);
+        try!(self.flash.read(address, &mut header).map_err(LogError::Flash));
+
+        let len = header[0] as u16 | (header[1] as u16) << 8;
+        let check = header[2] as u16 | (header[3] as u16) << 8;
+        if len != !check || len as usize > MAX_LEN
+            || offset + RECORD_HEADER_SIZE + len as usize > sector_size {
+            return Ok(None)
+        }
+
+        let mut crc = 0;
+        let mut done = 0;
+        let mut chunk = [0; 32];
+        while done < len as usize {
+            let n = cmp::min(len as usize - done, chunk.len());
+            try!(self.flash.read(address + RECORD_HEADER_SIZE + done,
+                                 &mut chunk[..n])
+                 .map_err(LogError::Flash));
+            crc = crc32_continue(crc, &chunk[..n]);
+            done += n;
+        }
+        if crc == le32(&header[4..]) {
+            Ok(Some(len as usize))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Checks that `sector` is erased from `offset` to its end.
+    fn is_erased_from(&self, sector: u32, mut offset: usize)
+        -> Result<bool, LogError<F::Error>> {
+        let mut chunk = [0; 32];
+        let end = self.flash.sector_size();
+        while offset < end {
+            let n = cmp::min(end - offset, chunk.len());
+            try!(self.flash.read(self.address(sector, offset),
+                                 &mut chunk[..n])
+                 .map_err(LogError::Flash));
+            if chunk[..n].iter().any(|&b| b != 0xFF) {
+                return Ok(false)
+            }
+            offset += n;
+        }
+        Ok(true)
+    }
+
+    fn address(&self, sector: u32, offset: usize) -> usize {
+        sector as usize * self.flash.sector_size() + offset
+    }
+}
+
+/// Reads the records in a `RingLog`, oldest first; see `RingLog::reader`.
+pub struct Reader<'a, F: NorFlash + 'a> {
+    log: &'a RingLog<F>,
+    /// The sector being read.
+    sector: u32,
+    /// Sectors left to read, including this one.
+    remaining: u32,
+    /// Offset of the next record, or 0 before the sector header has been
+    /// checked.
+    offset: usize,
+}
+
+impl<'a, F: NorFlash + 'a> Reader<'a, F> {
+    /// Reads the next record, copying as much of it as fits into `buf`, and
+    /// returns its full length -- or `None` once every record has been read.
+    pub fn next(&mut self, buf: &mut [u8])
+        -> Result<Option<usize>, LogError<F::Error>> {
+        let log = self.log;
+        let count = log.flash.sector_count();
+        while self.remaining > 0 {
+            if self.offset == 0 {
+                self.sector = (self.sector + 1) % count;
+                // Sectors are written in turn, so this one should be as many
+                // sectors older than the head as it is behind it.
+                let expected = log.sequence.wrapping_sub(self.remaining - 1);
+                if try!(log.sector_sequence(self.sector)) == Some(expected) {
+                    self.offset = SECTOR_HEADER_SIZE
+                } else {
+                    self.remaining -= 1;
+                    continue
+                }
+            }
+
+            if let Some(len) = try!(log.record_at(self.sector, self.offset)) {
+                let n = cmp::min(len, buf.len());
+                let address = log.address(self.sector, self.offset)
+                    + RECORD_HEADER_SIZE;
+                try!(log.flash.read(address, &mut buf[..n])
+                     .map_err(LogError::Flash));
+                self.offset += RECORD_HEADER_SIZE + len;
+                return Ok(Some(len))
+            }
+
+            self.offset = 0;
+            self.remaining -= 1;
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,115 @@
+//! Driver state shared between an interrupt handler and the code it preempts.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+use arm_m;
+use arm_m::nvic::NVIC;
+use arm_m::scb::{SystemException, SCB};
+
+/// Mutable state owned by one interrupt.
+///
+/// The owning interrupt's handler may use the contents freely; anything else
+/// must mask the interrupt first, which `lock` does.  That is only sound for
+/// code that the owning interrupt could otherwise preempt -- thread code, and
+/// handlers of lower priority.  A handler of equal or higher priority may
+/// itself have interrupted the owner mid-update, so `lock` refuses (panics)
+/// if called from one.  Priorities are checked on each call, so changing them
+/// at runtime is safe, if unwise.
+///
+///     static RX: IrqCell<RxState> =
+///         IrqCell::new(Interrupt::Usart2 as u32, RxState::new());
+///
+/// This replaces the `static mut` that would otherwise be needed, and the
+/// unchecked reasoning that goes with it.
+pub struct IrqCell<T> {
+    /// NVIC interrupt number of the owner.
+    irq: u32,
+    /// Set while a `lock` is in progress, to catch reentrant use.
+    busy: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for IrqCell<T> {}
+
+/// Gets the priority of the exception numbered `exc`, or `None` if it's
+/// fixed above all configurable priorities (reset, NMI, HardFault).
+fn exception_priority(exc: u32) -> Option<u8> {
+    let sys = match exc {
+        0 ... 3 => return None,
+        4 => SystemException::MemManage,
+        5 => SystemException::BusFault,
+        6 => SystemException::UsageFault,
+        11 => SystemException::SvCall,
+        12 => SystemException::DebugMonitor,
+        14 => SystemException::PendSv,
+        15 => SystemException::SysTick,
+        // Reserved numbers can't be active.
+        7 ... 13 => return None,
+        _ => return Some(NVIC.get_priority_raw(exc - 16)),
+    };
+    Some(SCB.get_priority_raw(sys))
+}
+
+impl<T> IrqCell<T> {
+    /// Creates a cell owned by NVIC interrupt `irq`.
+    pub const fn new(irq: u32, value: T) -> IrqCell<T> {
+        IrqCell {
+            irq: irq,
+            busy: ATOMIC_BOOL_INIT,
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Runs `body` with access to the contents.  From anywhere but the
+    /// owning handler, the owning interrupt is disabled for the duration
+    /// (and re-enabled afterwards if it was enabled before).
+    ///
+    /// # Panics
+    ///
+    /// If called from a handler that the owning interrupt can't preempt, or
+    /// while another `lock` is in progress -- from within `body`, or from a
+    /// lower-priority handler that preempted it.
+    pub fn lock<R, F: FnOnce(&mut T) -> R>(&self, body: F) -> R {
+        let exc = arm_m::get_ipsr();
+        let owner = self.irq + 16;
+
+        if exc != 0 && exc != owner {
+            let ours = NVIC.get_priority_raw(self.irq);
+            match exception_priority(exc) {
+                Some(p) if p > ours => (),
+                _ => panic!("IrqCell used above its owner's priority"),
+            }
+        }
+
+        if exc == owner {
+            self.enter();
+            let r = body(unsafe { &mut *self.value.get() });
+            self.leave();
+            r
+        } else {
+            let was_enabled = NVIC.is_irq_enabled_raw(self.irq);
+            NVIC.disable_irq_raw(self.irq);
+            self.enter();
+            let r = body(unsafe { &mut *self.value.get() });
+            self.leave();
+            if was_enabled {
+                NVIC.enable_irq_raw(self.irq)
+            }
+            r
+        }
+    }
+
+    /// Marks the cell in use.  Contexts that can get here preempt each other
+    /// only in nested fashion, so a plain load and store suffice.
+    fn enter(&self) {
+        if self.busy.load(Ordering::Acquire) {
+            panic!("IrqCell locked reentrantly")
+        }
+        self.busy.store(true, Ordering::Relaxed)
+    }
+
+    fn leave(&self) {
+        self.busy.store(false, Ordering::Release)
+    }
+}
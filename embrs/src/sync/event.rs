@@ -0,0 +1,137 @@
+//! Wakeups from interrupt handlers to thread code.
+
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use arm_m;
+
+use super::fetch_update;
+
+/// Sleeps until `poll` returns `Some`, and returns its contents.
+///
+/// `poll` runs with interrupts masked, and the processor sleeps (with `WFI`)
+/// without unmasking them.  An interrupt that arrives after the check still
+/// wakes the processor -- WFI ignores PRIMASK -- and its handler runs as soon
+/// as interrupts are unmasked, before the next check.  So no wakeup can fall
+/// between `poll` deciding to sleep and the sleep itself.
+///
+/// If interrupts were already masked on entry, handlers never get to run, and
+/// this will sleep forever; don't do that.
+fn block_on<R, F: FnMut() -> Option<R>>(mut poll: F) -> R {
+    loop {
+        let was_masked = arm_m::get_primask();
+        arm_m::set_primask(true);
+        let r = poll();
+        if r.is_none() {
+            arm_m::wait_for_interrupt();
+        }
+        if !was_masked {
+            arm_m::set_primask(false)
+        }
+        if let Some(r) = r {
+            return r
+        }
+    }
+}
+
+/// A counting semaphore, for handing units of work (received packets,
+/// completed transfers) from interrupt handlers to thread code.
+///
+/// `signal` may be called from any context; it also sends an event, so a
+/// thread sleeping in its own `wait_for_event` loop (see
+/// `arm_m::clear_event_register`) wakes as well.
+pub struct Semaphore {
+    count: AtomicUsize,
+}
+
+impl Semaphore {
+    pub const fn new() -> Semaphore {
+        Semaphore {
+            count: ATOMIC_USIZE_INIT,
+        }
+    }
+
+    /// Adds one to the count, saturating.
+    pub fn signal(&self) {
+        let _ = fetch_update(&self.count, |c| c.checked_add(1));
+        arm_m::send_event()
+    }
+
+    /// Current count.  This may be stale by the time it's returned.
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Acquire)
+    }
+
+    /// Takes one from the count, if it's nonzero.
+    pub fn try_take(&self) -> bool {
+        fetch_update(&self.count, |c| c.checked_sub(1)).is_ok()
+    }
+
+    /// Takes one from the count, sleeping until it's nonzero.  Thread code
+    /// only (see `block_on` above).
+    pub fn take(&self) {
+        block_on(|| if self.try_take() { Some(()) } else { None })
+    }
+}
+
+/// A group of independent flags, one per bit of a `usize`, set by interrupt
+/// handlers and consumed by thread code.
+///
+/// Like `Semaphore::signal`, `set` sends an event.
+pub struct EventFlags {
+    bits: AtomicUsize,
+}
+
+impl EventFlags {
+    pub const fn new() -> EventFlags {
+        EventFlags {
+            bits: ATOMIC_USIZE_INIT,
+        }
+    }
+
+    /// Sets the flags in `bits`.
+    pub fn set(&self, bits: usize) {
+        let _ = fetch_update(&self.bits, |b| Some(b | bits));
+        arm_m::send_event()
+    }
+
+    /// Clears the flags in `bits`.
+    pub fn clear(&self, bits: usize) {
+        let _ = fetch_update(&self.bits, |b| Some(b & !bits));
+    }
+
+    /// Reads all flags without changing them.
+    pub fn peek(&self) -> usize {
+        self.bits.load(Ordering::Acquire)
+    }
+
+    /// Clears and returns those flags in `mask` that are set, or returns
+    /// `None` (changing nothing) if there are none.
+    pub fn try_take_any(&self, mask: usize) -> Option<usize> {
+        match fetch_update(&self.bits, |b| {
+            if b & mask != 0 { Some(b & !mask) } else { None }
+        }) {
+            Ok(b) => Some(b & mask),
+            Err(_) => None,
+        }
+    }
+
+    /// Clears the flags in `mask` if all of them are set, returning `true`,
+    /// or returns `false` (changing nothing) otherwise.
+    pub fn try_take_all(&self, mask: usize) -> bool {
+        fetch_update(&self.bits, |b| {
+            if b & mask == mask { Some(b & !mask) } else { None }
+        }).is_ok()
+    }
+
+    /// Sleeps until at least one flag in `mask` is set, then clears and
+    /// returns them.  Thread code only.
+    pub fn wait_any(&self, mask: usize) -> usize {
+        block_on(|| self.try_take_any(mask))
+    }
+
+    /// Sleeps until every flag in `mask` is set, then clears them.  Thread
+    /// code only.
+    pub fn wait_all(&self, mask: usize) {
+        block_on(|| if self.try_take_all(mask) { Some(()) } else { None })
+    }
+}
@@ -0,0 +1,147 @@
+//! Lock-free communication between interrupt handlers and thread code.
+//!
+//! The queues here have fixed capacity, chosen by the type of their backing
+//! array, and `const` constructors, so they can live in `static`s:
+//!
+//!     static RX: SpscQueue<[u8; 64]> = SpscQueue::new([0; 64]);
+//!
+//! Capacities must be powers of two from 2 to 1024.
+//!
+//! - `SpscQueue` has one producer and one consumer -- say, a UART receive
+//!   interrupt and the main loop.  It needs nothing but ordinary loads and
+//!   stores.
+//! - `MpscQueue` accepts items from any number of producers, including
+//!   interrupt handlers that preempt one another, but still has only one
+//!   consumer.  It claims slots with exclusive loads and stores (on ARMv6-M,
+//!   by briefly masking interrupts), as `arm_m::reg::AtomicReg` does.
+//!
+//! For state that isn't a stream of items:
+//!
+//! - `IrqCell` holds mutable driver state owned by one interrupt handler,
+//!   which other code reaches by masking that interrupt.
+//! - `OnceInit` holds a value set once at runtime and read-only thereafter.
+//!
+//! Between them, these cover what drivers would otherwise use `static mut`
+//! for.
+//!
+//! And for waking thread code when a handler has news for it:
+//!
+//! - `Semaphore` counts occurrences (packets received, transfers completed).
+//! - `EventFlags` records which of several things have happened.
+//!
+//! Both have blocking waits that sleep with `WFI` without losing wakeups, and
+//! both send an event when signaled, so they also work with hand-written
+//! `wait_for_event` loops.
+
+use core::sync::atomic::AtomicUsize;
+#[cfg(any(feature = "arch:armv6-m", feature = "host-test"))]
+use core::sync::atomic::Ordering;
+
+#[cfg(any(feature = "arch:armv6-m", feature = "host-test"))]
+use arm_m;
+
+mod cell;
+mod event;
+mod mpsc;
+mod once;
+mod spsc;
+
+pub use self::cell::IrqCell;
+pub use self::event::{EventFlags, Semaphore};
+pub use self::mpsc::MpscQueue;
+pub use self::once::OnceInit;
+pub use self::spsc::SpscQueue;
+
+/// A fixed-size array that can back a queue.
+pub trait Storage {
+    type Item: Copy;
+
+    /// Number of items; always a power of two.
+    fn capacity() -> usize;
+
+    /// Gets a pointer to slot `index` (less than `capacity()`) of the array
+    /// at `this`.  Queues reach their slots this way, one at a time, rather
+    /// than through a reference to the whole array: the producer and the
+    /// consumer each hold one while the other is busy with its own slot.
+    unsafe fn slot(this: *mut Self, index: usize) -> *mut Self::Item;
+}
+
+macro_rules! storage_impls {
+    ($($n:expr)*) => {
+        $(
+            impl<T: Copy> Storage for [T; $n] {
+                type Item = T;
+
+                fn capacity() -> usize {
+                    $n
+                }
+
+                unsafe fn slot(this: *mut Self, index: usize) -> *mut T {
+                    (this as *mut T).offset(index as isize)
+                }
+            }
+        )*
+    };
+}
+
+storage_impls!(2 4 8 16 32 64 128 256 512 1024);
+
+/// Atomically replaces the contents of `cell` with `f` of its contents,
+/// unless `f` returns `None`.  Returns the contents before the update, as
+/// `Ok` if it happened or `Err` if not.  Like `AtomicReg`, this retries if
+/// interrupted, so `f` may be called several times.
+#[cfg(not(any(feature = "arch:armv6-m", feature = "host-test")))]
+pub fn fetch_update<F>(cell: &AtomicUsize, f: F) -> Result<usize, usize>
+    where F: Fn(usize) -> Option<usize> {
+    let p = cell as *const AtomicUsize;
+    loop {
+        let old: usize;
+        unsafe {
+            asm!("ldrex $0, [$1]"
+                 : "=r"(old)
+                 : "r"(p)
+                 : "memory"
+                 : "volatile")
+        }
+        match f(old) {
+            None => {
+                unsafe {
+                    asm!("clrex" ::: "memory" : "volatile")
+                }
+                return Err(old)
+            },
+            Some(new) => {
+                let failed: u32;
+                unsafe {
+                    asm!("strex $0, $1, [$2]"
+                         : "=&r"(failed)
+                         : "r"(new), "r"(p)
+                         : "memory"
+                         : "volatile")
+                }
+                if failed == 0 {
+                    return Ok(old)
+                }
+            },
+        }
+    }
+}
+
+/// Atomically replaces the contents of `cell` with `f` of its contents,
+/// unless `f` returns `None`.  Returns the contents before the update, as
+/// `Ok` if it happened or `Err` if not.  Here, without exclusive access
+/// instructions, interrupts are masked for the duration.
+#[cfg(any(feature = "arch:armv6-m", feature = "host-test"))]
+pub fn fetch_update<F>(cell: &AtomicUsize, f: F) -> Result<usize, usize>
+    where F: Fn(usize) -> Option<usize> {
+    arm_m::without_interrupts(|| {
+        let old = cell.load(Ordering::Relaxed);
+        match f(old) {
+            None => Err(old),
+            Some(new) => {
+                cell.store(new, Ordering::Relaxed);
+                Ok(old)
+            },
+        }
+    })
+}
@@ -0,0 +1,130 @@
+//! Multiple-producer, single-consumer queue.
+
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use super::{fetch_update, Storage};
+
+// The producer side's state is packed into one word so that it can be updated
+// with a single exclusive access:
+//
+// - `head`, bits 11:0: count of items published to the consumer.
+// - `reserved`, bits 23:12: count of slots claimed by producers.
+// - `writers`, bits 31:24: number of producers between claiming a slot and
+//   publishing it.
+//
+// Counts are modulo 4096, which every supported capacity divides.
+const COUNT_MASK : usize = 0xFFF;
+const RESERVED_SHIFT : usize = 12;
+const WRITERS_SHIFT : usize = 24;
+const WRITERS_ONE : usize = 1 << WRITERS_SHIFT;
+
+fn head(s: usize) -> usize {
+    s & COUNT_MASK
+}
+
+fn reserved(s: usize) -> usize {
+    (s >> RESERVED_SHIFT) & COUNT_MASK
+}
+
+fn writers(s: usize) -> usize {
+    s >> WRITERS_SHIFT
+}
+
+/// A queue with any number of producers and one consumer.
+///
+/// Producers claim a slot, fill it, and then publish it.  Because interrupt
+/// handlers nest, a producer that preempts another finishes before the one it
+/// interrupted resumes; so items are published in batches, when the last
+/// producer in flight finishes.  The consumer thus never sees a slot that's
+/// claimed but not yet written.
+pub struct MpscQueue<S: Storage> {
+    storage: UnsafeCell<S>,
+    /// Packed producer state; see above.
+    state: AtomicUsize,
+    /// Count of items popped, modulo 4096.  Written only by the consumer.
+    tail: AtomicUsize,
+}
+
+unsafe impl<S: Storage> Sync for MpscQueue<S> where S::Item: Send {}
+
+impl<S: Storage> MpscQueue<S> {
+    /// Creates an empty queue backed by `storage`, whose initial contents
+    /// don't matter.
+    pub const fn new(storage: S) -> MpscQueue<S> {
+        MpscQueue {
+            storage: UnsafeCell::new(storage),
+            state: ATOMIC_USIZE_INIT,
+            tail: ATOMIC_USIZE_INIT,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        S::capacity()
+    }
+
+    /// Number of published items waiting.
+    pub fn len(&self) -> usize {
+        // As for `SpscQueue`: the tail first, so the head can't be behind it.
+        let tail = self.tail.load(Ordering::Acquire);
+        let h = head(self.state.load(Ordering::Acquire));
+        let n = h.wrapping_sub(tail) & COUNT_MASK;
+        if n > S::capacity() { S::capacity() } else { n }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds `item` to the queue, or hands it back if the queue is full.  Any
+    /// context may call this.
+    pub fn push(&self, item: S::Item) -> Result<(), S::Item> {
+        // The tail only advances, so a stale value just makes the queue look
+        // fuller than it is.
+        let tail = self.tail.load(Ordering::Acquire);
+        let claimed = fetch_update(&self.state, |s| {
+            let r = reserved(s);
+            if (r.wrapping_sub(tail) & COUNT_MASK) >= S::capacity() {
+                None
+            } else {
+                let r = (r + 1) & COUNT_MASK;
+                Some((s & !(COUNT_MASK << RESERVED_SHIFT))
+                     + (r << RESERVED_SHIFT) + WRITERS_ONE)
+            }
+        });
+        let index = match claimed {
+            Ok(s) => reserved(s),
+            Err(_) => return Err(item),
+        };
+
+        unsafe {
+            let slot = S::slot(self.storage.get(), index & (S::capacity() - 1));
+            ptr::write(slot, item)
+        }
+
+        // Publish, if we're the last producer in flight.
+        let _ = fetch_update(&self.state, |s| {
+            let s = s - WRITERS_ONE;
+            if writers(s) == 0 {
+                Some((s & !COUNT_MASK) | reserved(s))
+            } else {
+                Some(s)
+            }
+        });
+        Ok(())
+    }
+
+    /// Takes the oldest published item, if any.  Consumer only.
+    pub fn pop(&self) -> Option<S::Item> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if head(self.state.load(Ordering::Acquire)) == tail {
+            return None
+        }
+        let item = unsafe {
+            ptr::read(S::slot(self.storage.get(), tail & (S::capacity() - 1)))
+        };
+        self.tail.store((tail + 1) & COUNT_MASK, Ordering::Release);
+        Some(item)
+    }
+}
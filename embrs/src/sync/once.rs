@@ -0,0 +1,84 @@
+//! One-time initialization of statics.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use super::fetch_update;
+
+/// No value has been set.
+const UNSET : usize = 0;
+/// A call to `set` is storing the value.
+const SETTING : usize = 1;
+/// The value has been stored and is now read-only.
+const SET : usize = 2;
+
+/// A static that is filled in once, at runtime, and read-only thereafter --
+/// for driver singletons whose configuration isn't known at compile time.
+///
+///     static SPEEDS: OnceInit<ClockSpeeds> = OnceInit::new();
+///
+///     SPEEDS.set(CLOCKS.compute_speeds()).ok().expect("set twice");
+///     let speeds = SPEEDS.get();
+///
+/// There's no waiting: a context that finds initialization in progress (an
+/// interrupt handler that preempted `set`, say) is told that the value isn't
+/// there yet, rather than spinning forever on the context it preempted.
+pub struct OnceInit<T> {
+    state: AtomicUsize,
+    value: UnsafeCell<Option<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for OnceInit<T> {}
+
+impl<T> OnceInit<T> {
+    pub const fn new() -> OnceInit<T> {
+        OnceInit {
+            state: ATOMIC_USIZE_INIT,
+            value: UnsafeCell::new(None),
+        }
+    }
+
+    /// Stores `value`, unless a value has already been stored (or is being
+    /// stored), in which case it's handed back.
+    pub fn set(&self, value: T) -> Result<&T, T> {
+        // Claim the right to initialize; only one caller can.
+        let claimed = fetch_update(&self.state, |s| {
+            if s == UNSET { Some(SETTING) } else { None }
+        });
+        if claimed.is_err() {
+            return Err(value)
+        }
+
+        unsafe {
+            *self.value.get() = Some(value);
+        }
+        self.state.store(SET, Ordering::Release);
+        Ok(self.get())
+    }
+
+    /// Gets the value, or `None` if it hasn't been stored (yet).
+    pub fn try_get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == SET {
+            unsafe { (*self.value.get()).as_ref() }
+        } else {
+            None
+        }
+    }
+
+    /// Gets the value.
+    ///
+    /// # Panics
+    ///
+    /// If the value hasn't been stored.
+    pub fn get(&self) -> &T {
+        match self.try_get() {
+            Some(v) => v,
+            None => panic!("OnceInit read before set"),
+        }
+    }
+
+    /// Checks whether the value has been stored.
+    pub fn is_set(&self) -> bool {
+        self.state.load(Ordering::Acquire) == SET
+    }
+}
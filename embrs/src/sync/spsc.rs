@@ -0,0 +1,86 @@
+//! Single-producer, single-consumer queue.
+
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use super::Storage;
+
+/// A queue with one producer and one consumer, which may be in different
+/// contexts (e.g. an interrupt handler and thread code).
+///
+/// Only the producer may call `push`, and only the consumer `pop`; the queue
+/// can't check this.  Each of `head` and `tail` is written by one side only,
+/// so no read-modify-write is ever needed.
+pub struct SpscQueue<S: Storage> {
+    storage: UnsafeCell<S>,
+    /// Count of items pushed, wrapping.  Written only by the producer.
+    head: AtomicUsize,
+    /// Count of items popped, wrapping.  Written only by the consumer.
+    tail: AtomicUsize,
+}
+
+unsafe impl<S: Storage> Sync for SpscQueue<S> where S::Item: Send {}
+
+impl<S: Storage> SpscQueue<S> {
+    /// Creates an empty queue backed by `storage`, whose initial contents
+    /// don't matter.
+    pub const fn new(storage: S) -> SpscQueue<S> {
+        SpscQueue {
+            storage: UnsafeCell::new(storage),
+            head: ATOMIC_USIZE_INIT,
+            tail: ATOMIC_USIZE_INIT,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        S::capacity()
+    }
+
+    /// Number of items waiting.
+    pub fn len(&self) -> usize {
+        // Load the tail first: the head can only have moved further on
+        // since, so the difference can't go negative.  It can, though,
+        // count pushes into slots freed after the tail was read.
+        let tail = self.tail.load(Ordering::Acquire);
+        let n = self.head.load(Ordering::Acquire).wrapping_sub(tail);
+        if n > S::capacity() { S::capacity() } else { n }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == S::capacity()
+    }
+
+    /// Adds `item` to the queue, or hands it back if the queue is full.
+    /// Producer only.
+    pub fn push(&self, item: S::Item) -> Result<(), S::Item> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) == S::capacity() {
+            return Err(item)
+        }
+        unsafe {
+            let slot = S::slot(self.storage.get(), head & (S::capacity() - 1));
+            ptr::write(slot, item)
+        }
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Takes the oldest item from the queue, if any.  Consumer only.
+    pub fn pop(&self) -> Option<S::Item> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if self.head.load(Ordering::Acquire) == tail {
+            return None
+        }
+        let item = unsafe {
+            ptr::read(S::slot(self.storage.get(), tail & (S::capacity() - 1)))
+        };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(item)
+    }
+}
@@ -0,0 +1,57 @@
+//! Monotonic time and software timers.
+//!
+//! `start` sets SysTick to interrupt every millisecond; the application's
+//! SysTick handler calls `tick`, which advances the count reported by
+//! `now_ms`.  Software timers (see `TimerWheel`) hang off the same tick.
+//!
+//!     extern "C" fn sys_tick_isr() {
+//!         time::tick();
+//!         WHEEL.tick(time::now_ms());
+//!     }
+
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use arm_m::sys_tick::{self, SYS_TICK, TickRateError};
+use clock::ClockSpeeds;
+
+mod wheel;
+
+pub use self::wheel::{SoftTimer, TimerCallback, TimerError, TimerId,
+                      TimerWheel};
+
+/// Rate at which `tick` must be called.
+pub const TICK_HZ : u32 = 1000;
+
+/// Milliseconds since `start`, wrapping at the word size.
+static MILLIS : AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Starts SysTick interrupting at `TICK_HZ`.  The application must route the
+/// SysTick vector to a handler that calls `tick`.
+pub fn start(speeds: &ClockSpeeds) -> Result<(), TickRateError> {
+    let source = try!(SYS_TICK.configure_for_hz(speeds, TICK_HZ));
+    SYS_TICK.configure(sys_tick::Config {
+        enable: true,
+        tickint: true,
+        clksource: source,
+    });
+    Ok(())
+}
+
+/// Advances the millisecond count.  Call this from the SysTick handler (and
+/// nowhere else).
+pub fn tick() {
+    let t = MILLIS.load(Ordering::Relaxed);
+    MILLIS.store(t.wrapping_add(1), Ordering::Relaxed)
+}
+
+/// Milliseconds elapsed since `start`, wrapping every 49.7 days.  Compare
+/// times with `wrapping_sub`, as `deadline_passed` does.
+pub fn now_ms() -> u32 {
+    MILLIS.load(Ordering::Relaxed) as u32
+}
+
+/// Checks whether `now` is at or after `deadline`, allowing for wrap, as long
+/// as the two are less than about 24 days apart.
+pub fn deadline_passed(now: u32, deadline: u32) -> bool {
+    (now.wrapping_sub(deadline) as i32) >= 0
+}
@@ -0,0 +1,251 @@
+//! Software timers multiplexed onto the millisecond tick.
+//!
+//! A `TimerWheel` manages a fixed set of `SoftTimer` slots, declared by the
+//! application so that their number is chosen at compile time:
+//!
+//!     static TIMERS: [SoftTimer; 4] = [SoftTimer::new(), SoftTimer::new(),
+//!                                      SoftTimer::new(), SoftTimer::new()];
+//!     static WHEEL: TimerWheel = TimerWheel::new(&TIMERS);
+//!
+//! Drivers `claim` a slot and then start, restart, or cancel it as they like.
+//! Expiry is noticed in `tick`, which runs in the SysTick handler and so must
+//! be quick; callbacks are run later, from `dispatch` in the PendSV handler,
+//! whose priority (`set_dispatch_priority`) thus controls which interrupts
+//! they can preempt.  Both vectors must be routed by the application:
+//!
+//!     extern "C" fn pend_sv_isr() {
+//!         WHEEL.dispatch()
+//!     }
+//!
+//! With the handful of timers a firmware typically needs, scanning every
+//! slot on each tick is cheaper than keeping them sorted.
+
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use arm_m;
+use arm_m::scb::{SystemException, SCB};
+use super::{deadline_passed, now_ms};
+
+/// Identifies a slot in a `TimerWheel`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct TimerId(pub usize);
+
+/// Function called when a timer expires, given the timer's identity.
+pub type TimerCallback = fn(TimerId);
+
+/// Ways a timer operation can fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum TimerError {
+    /// The `TimerId` doesn't name a slot of this wheel.
+    NoSuchTimer,
+    /// The slot hasn't been claimed.
+    NotClaimed,
+    /// `restart` was used on a timer that has never been started.
+    NeverStarted,
+}
+
+/// Slot states.
+const FREE : usize = 0;
+/// Claimed, but not running.
+const IDLE : usize = 1;
+/// Running, waiting for its deadline.
+const ARMED : usize = 2;
+/// Deadline passed; waiting for `dispatch`.
+const DUE : usize = 3;
+
+/// One software timer slot.
+pub struct SoftTimer {
+    state: AtomicUsize,
+    deadline: AtomicUsize,
+    /// Delay for one-shots, period for periodic timers, in milliseconds.
+    interval: AtomicUsize,
+    periodic: AtomicUsize,
+    callback: AtomicUsize,
+}
+
+impl SoftTimer {
+    pub const fn new() -> SoftTimer {
+        SoftTimer {
+            state: ATOMIC_USIZE_INIT,
+            deadline: ATOMIC_USIZE_INIT,
+            interval: ATOMIC_USIZE_INIT,
+            periodic: ATOMIC_USIZE_INIT,
+            callback: ATOMIC_USIZE_INIT,
+        }
+    }
+}
+
+/// A set of software timers; see the module docs.
+pub struct TimerWheel {
+    timers: &'static [SoftTimer],
+}
+
+impl TimerWheel {
+    pub const fn new(timers: &'static [SoftTimer]) -> TimerWheel {
+        TimerWheel {
+            timers: timers,
+        }
+    }
+
+    /// Sets the priority at which callbacks run, by setting the priority of
+    /// PendSV.  This is shared with anything else that uses PendSV.
+    pub fn set_dispatch_priority(&self, priority: u8) {
+        SCB.set_priority_raw(SystemException::PendSv, priority)
+    }
+
+    /// Number of slots.
+    pub fn capacity(&self) -> usize {
+        self.timers.len()
+    }
+
+    /// Claims a free slot, if there is one.
+    pub fn claim(&self) -> Option<TimerId> {
+        arm_m::without_interrupts(|| {
+            self.timers.iter()
+                .position(|t| t.state.load(Ordering::Relaxed) == FREE)
+                .map(|i| {
+                    self.timers[i].state.store(IDLE, Ordering::Relaxed);
+                    TimerId(i)
+                })
+        })
+    }
+
+    /// Cancels the timer `id` and returns its slot to the pool.
+    pub fn release(&self, id: TimerId) {
+        if let Some(t) = self.timers.get(id.0) {
+            t.state.store(FREE, Ordering::Relaxed)
+        }
+    }
+
+    fn claimed(&self, id: TimerId) -> Result<&SoftTimer, TimerError> {
+        match self.timers.get(id.0) {
+            None => Err(TimerError::NoSuchTimer),
+            Some(t) if t.state.load(Ordering::Relaxed) == FREE =>
+                Err(TimerError::NotClaimed),
+            Some(t) => Ok(t),
+        }
+    }
+
+    fn arm(&self, id: TimerId, interval_ms: u32, periodic: bool,
+           callback: TimerCallback)
+        -> Result<(), TimerError> {
+        let t = try!(self.claimed(id));
+        arm_m::without_interrupts(|| {
+            t.interval.store(interval_ms as usize, Ordering::Relaxed);
+            t.periodic.store(periodic as usize, Ordering::Relaxed);
+            t.callback.store(callback as usize, Ordering::Relaxed);
+            t.deadline.store(now_ms().wrapping_add(interval_ms) as usize,
+                             Ordering::Relaxed);
+            t.state.store(ARMED, Ordering::Relaxed)
+        });
+        Ok(())
+    }
+
+    /// Starts timer `id` to call `callback` once, `delay_ms` from now.  A
+    /// timer that's already running is rescheduled.
+    pub fn start_one_shot(&self, id: TimerId, delay_ms: u32,
+                          callback: TimerCallback)
+        -> Result<(), TimerError> {
+        self.arm(id, delay_ms, false, callback)
+    }
+
+    /// Starts timer `id` to call `callback` every `period_ms`, the first
+    /// time `period_ms` from now.  Periods are measured from deadline to
+    /// deadline, so a late callback doesn't make the next one late too.
+    pub fn start_periodic(&self, id: TimerId, period_ms: u32,
+                          callback: TimerCallback)
+        -> Result<(), TimerError> {
+        self.arm(id, period_ms, true, callback)
+    }
+
+    /// Restarts timer `id` with its previous interval and callback, counting
+    /// from now.  This is the usual way to push back a timeout.
+    pub fn restart(&self, id: TimerId) -> Result<(), TimerError> {
+        let t = try!(self.claimed(id));
+        if t.callback.load(Ordering::Relaxed) == 0 {
+            return Err(TimerError::NeverStarted)
+        }
+        arm_m::without_interrupts(|| {
+            let interval = t.interval.load(Ordering::Relaxed) as u32;
+            t.deadline.store(now_ms().wrapping_add(interval) as usize,
+                             Ordering::Relaxed);
+            t.state.store(ARMED, Ordering::Relaxed)
+        });
+        Ok(())
+    }
+
+    /// Stops timer `id`.  If it had expired but its callback hadn't run yet,
+    /// the callback won't run.
+    pub fn cancel(&self, id: TimerId) -> Result<(), TimerError> {
+        let t = try!(self.claimed(id));
+        t.state.store(IDLE, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Checks whether timer `id` is running (or expired and awaiting
+    /// dispatch).
+    pub fn is_active(&self, id: TimerId) -> bool {
+        match self.timers.get(id.0) {
+            Some(t) => {
+                let s = t.state.load(Ordering::Relaxed);
+                s == ARMED || s == DUE
+            },
+            None => false,
+        }
+    }
+
+    /// Notes expired timers and, if there are any, pends PendSV to run their
+    /// callbacks.  Call this from the SysTick handler, after `time::tick`.
+    pub fn tick(&self, now: u32) {
+        let mut any = false;
+        for t in self.timers {
+            // A higher-priority handler may cancel or restart the timer, so
+            // the check and the transition to DUE must not be split.
+            let due = arm_m::without_interrupts(|| {
+                let d = t.deadline.load(Ordering::Relaxed) as u32;
+                if t.state.load(Ordering::Relaxed) == ARMED
+                    && deadline_passed(now, d) {
+                    t.state.store(DUE, Ordering::Relaxed);
+                    true
+                } else {
+                    false
+                }
+            });
+            any |= due;
+        }
+        if any {
+            SCB.set_pendsv()
+        }
+    }
+
+    /// Runs the callbacks of expired timers, rearming periodic ones.  Call
+    /// this from the PendSV handler.
+    pub fn dispatch(&self) {
+        for (i, t) in self.timers.iter().enumerate() {
+            // The state can't change under us except by `tick` (which only
+            // moves ARMED to DUE) or by a higher-priority handler starting or
+            // cancelling the timer, so claim the expiry with interrupts off.
+            let callback = arm_m::without_interrupts(|| {
+                if t.state.load(Ordering::Relaxed) != DUE {
+                    return 0
+                }
+                if t.periodic.load(Ordering::Relaxed) != 0 {
+                    let d = t.deadline.load(Ordering::Relaxed) as u32;
+                    let p = t.interval.load(Ordering::Relaxed) as u32;
+                    t.deadline.store(d.wrapping_add(p) as usize,
+                                     Ordering::Relaxed);
+                    t.state.store(ARMED, Ordering::Relaxed);
+                } else {
+                    t.state.store(IDLE, Ordering::Relaxed);
+                }
+                t.callback.load(Ordering::Relaxed)
+            });
+
+            if callback != 0 {
+                let f: TimerCallback = unsafe { mem::transmute(callback) };
+                f(TimerId(i))
+            }
+        }
+    }
+}
@@ -0,0 +1,465 @@
+//! USB CDC Ethernet Control Model (ECM) class, device side.
+//!
+//! `CdcEcm` presents the device to a host as a USB network adapter, and to
+//! the application as a `net::NetDevice`, just like the Ethernet MAC -- so
+//! a board without a PHY can still run an IP stack, talking to the host
+//! over its USB port.  Ethernet frames travel whole over a pair of bulk
+//! endpoints, each split into max-size packets and ended by a short one;
+//! an interrupt endpoint tells the host when the link comes up.
+//!
+//! As with `msc`, the class doesn't drive a USB controller itself.  The
+//! controller's device driver provides the endpoints through
+//! `EcmEndpoints` (on the STM32F4, `stm32f4::otg_fs::device::Endpoints`),
+//! and the application calls `CdcEcm::poll` whenever they
+//! might have made progress, and before and after running its IP stack.
+//! Class requests arriving on endpoint 0 go to
+//! `CdcEcm::handle_class_request`, and the host's choice of alternate
+//! setting for the data interface goes to `CdcEcm::set_alternate`.
+//!
+//! Linux and macOS drive ECM devices with their built-in drivers.  Windows
+//! doesn't, and wants RNDIS instead, which isn't implemented here.
+//!
+//! The device buffers one frame each way, so it's no speed demon, and
+//! supports only full-speed endpoints.
+
+use core::cmp;
+
+use hal::{NbError, NbResult};
+use net::{Capabilities, NetDevice, NetError, RxToken, TxToken,
+          NO_CHECKSUM_OFFLOAD};
+use super::{SetupPacket, CLASS_CDC, CLASS_CDC_DATA, DESC_CS_INTERFACE,
+            DESC_ENDPOINT, DESC_INTERFACE, DESC_INTERFACE_ASSOCIATION,
+            DESC_STRING, DIR_IN};
+use super::msc::BulkEndpoints;
+
+/// Communications interface subclass: Ethernet Control Model.
+pub const SUBCLASS_ECM : u8 = 0x06;
+
+// Functional descriptor subtypes.
+const FUNC_HEADER : u8 = 0x00;
+const FUNC_UNION : u8 = 0x06;
+const FUNC_ETHERNET : u8 = 0x0F;
+
+// Class requests.
+const SET_ETHERNET_MULTICAST_FILTERS : u8 = 0x40;
+const SET_ETHERNET_PACKET_FILTER : u8 = 0x43;
+
+// Notifications.
+const NETWORK_CONNECTION : u8 = 0x00;
+const CONNECTION_SPEED_CHANGE : u8 = 0x2A;
+
+/// Bit rate reported to the host: full speed USB.
+const BIT_RATE : u32 = 12_000_000;
+
+/// Packet filter bits, as set by the host with `SET_ETHERNET_PACKET_FILTER`.
+pub const FILTER_PROMISCUOUS : u16 = 1 << 0;
+pub const FILTER_ALL_MULTICAST : u16 = 1 << 1;
+pub const FILTER_DIRECTED : u16 = 1 << 2;
+pub const FILTER_BROADCAST : u16 = 1 << 3;
+pub const FILTER_MULTICAST : u16 = 1 << 4;
+
+/// Longest frame, without CRC.
+pub const MTU : usize = 1514;
+/// Largest bulk packet supported: the full speed maximum.
+pub const MAX_PACKET : usize = 64;
+/// Largest notification, which sets the notification endpoint's packet
+/// size.
+const NOTIFY_LEN : usize = 16;
+
+/// Receive buffer length: a maximum-length frame, plus room to take one
+/// more packet before noticing it's too long.
+const RX_BUFFER_LEN : usize = MTU + MAX_PACKET;
+
+/// The endpoints a `CdcEcm` talks through: the bulk pair carrying frames,
+/// plus an interrupt IN endpoint for notifications.  The bulk endpoints'
+/// `max_packet` must not exceed `MAX_PACKET`.
+pub trait EcmEndpoints: BulkEndpoints {
+    /// Queues `data` (at most 16 bytes) for the notification endpoint,
+    /// returning `WouldBlock` while the previous notification is unsent.
+    fn try_notify(&self, data: &[u8]) -> NbResult<(), Self::Error>;
+}
+
+/// Length of the descriptors returned by `interface_descriptors`.
+pub const INTERFACE_DESCRIPTORS_LEN : usize =
+    8 + 9 + 5 + 5 + 13 + 7 + 9 + 9 + 7 + 7;
+
+/// Builds the descriptors for an ECM function, for inclusion in the
+/// device's configuration descriptor.  The function uses two interfaces:
+/// `interface` for control and `interface + 1` for data.  `ep_notify`,
+/// `ep_in` and `ep_out` are endpoint numbers, without direction bits, and
+/// `mac_string` is the index of a string descriptor built by
+/// `mac_string_descriptor`.
+///
+/// An Interface Association Descriptor ties the two interfaces together,
+/// so that hosts bind them as one function even in a composite device.  The
+/// device descriptor should then give class 0xEF, subclass 2, protocol 1,
+/// as the IAD specification asks.
+pub fn interface_descriptors(interface: u8,
+                             ep_notify: u8,
+                             ep_in: u8,
+                             ep_out: u8,
+                             max_packet: u16,
+                             mac_string: u8)
+    -> [u8; INTERFACE_DESCRIPTORS_LEN] {
+    let mps_lo = max_packet as u8;
+    let mps_hi = (max_packet >> 8) as u8;
+    let mtu_lo = MTU as u8;
+    let mtu_hi = (MTU >> 8) as u8;
+    let data = interface + 1;
+    [
+        // The function: two interfaces, starting with `interface`.
+        8, DESC_INTERFACE_ASSOCIATION, interface, 2,
+            CLASS_CDC, SUBCLASS_ECM, 0, 0,
+
+        // Communications interface, and its functional descriptors:
+        // CDC 1.10, the interfaces making up the function, and the
+        // Ethernet parameters (no statistics or filters).
+        9, DESC_INTERFACE, interface, 0, 1, CLASS_CDC, SUBCLASS_ECM, 0, 0,
+        5, DESC_CS_INTERFACE, FUNC_HEADER, 0x10, 0x01,
+        5, DESC_CS_INTERFACE, FUNC_UNION, interface, data,
+        13, DESC_CS_INTERFACE, FUNC_ETHERNET, mac_string,
+            0, 0, 0, 0, mtu_lo, mtu_hi, 0, 0, 0,
+        7, DESC_ENDPOINT, DIR_IN | ep_notify, 3, NOTIFY_LEN as u8, 0, 32,
+
+        // Data interface: no endpoints in the default setting, so the link
+        // is down until the host selects the second.
+        9, DESC_INTERFACE, data, 0, 0, CLASS_CDC_DATA, 0, 0, 0,
+        9, DESC_INTERFACE, data, 1, 2, CLASS_CDC_DATA, 0, 0, 0,
+        7, DESC_ENDPOINT, DIR_IN | ep_in, 2, mps_lo, mps_hi, 0,
+        7, DESC_ENDPOINT, ep_out, 2, mps_lo, mps_hi, 0,
+    ]
+}
+
+/// Length of the descriptor returned by `mac_string_descriptor`.
+pub const MAC_STRING_DESCRIPTOR_LEN : usize = 2 + 12 * 2;
+
+/// Builds the string descriptor giving the MAC address of the host's end of
+/// the link, as twelve hex digits.  This must differ from the device's own
+/// address, passed to `CdcEcm::new`.
+pub fn mac_string_descriptor(host_mac: &[u8; 6])
+    -> [u8; MAC_STRING_DESCRIPTOR_LEN] {
+    const HEX : &'static [u8; 16] = b"0123456789ABCDEF";
+    let mut d = [0; MAC_STRING_DESCRIPTOR_LEN];
+    d[0] = MAC_STRING_DESCRIPTOR_LEN as u8;
+    d[1] = DESC_STRING;
+    for (i, b) in host_mac.iter().enumerate() {
+        d[2 + i * 4] = HEX[(b >> 4) as usize];
+        d[4 + i * 4] = HEX[(b & 0xF) as usize];
+    }
+    d
+}
+
+/// Notifications waiting to go to the host.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Notify {
+    None,
+    /// The link state, then (if up) the speed.
+    Connection,
+    Speed,
+}
+
+/// A frame arriving from the host.
+struct RxState {
+    buf: [u8; RX_BUFFER_LEN],
+    len: usize,
+    /// The frame is complete, and waiting for the stack.
+    ready: bool,
+    /// The frame grew too long, and the rest of it is being dropped.
+    discarding: bool,
+}
+
+/// A frame going to the host.
+struct TxState {
+    buf: [u8; MTU],
+    len: usize,
+    /// Bytes sent so far.
+    pos: usize,
+    busy: bool,
+}
+
+/// An ECM network function.
+pub struct CdcEcm {
+    interface: u8,
+    mac: [u8; 6],
+    connected: bool,
+    notify: Notify,
+    filter: u16,
+    rx: RxState,
+    tx: TxState,
+    dropped: usize,
+}
+
+impl CdcEcm {
+    /// Creates the function, using control interface number `interface`
+    /// (as given to `interface_descriptors`), with `mac` as the device's
+    /// own address.  The link starts down.
+    pub fn new(interface: u8, mac: &[u8; 6]) -> CdcEcm {
+        CdcEcm {
+            interface: interface,
+            mac: *mac,
+            connected: false,
+            notify: Notify::None,
+            filter: 0,
+            rx: RxState {
+                buf: [0; RX_BUFFER_LEN],
+                len: 0,
+                ready: false,
+                discarding: false,
+            },
+            tx: TxState {
+                buf: [0; MTU],
+                len: 0,
+                pos: 0,
+                busy: false,
+            },
+            dropped: 0,
+        }
+    }
+
+    /// Takes the link down and drops any frames in flight, as after a bus
+    /// reset or a change of configuration.
+    pub fn reset(&mut self) {
+        self.connected = false;
+        self.notify = Notify::None;
+        self.filter = 0;
+        self.drop_frames()
+    }
+
+    fn drop_frames(&mut self) {
+        self.rx.len = 0;
+        self.rx.ready = false;
+        self.rx.discarding = false;
+        self.tx.busy = false;
+    }
+
+    /// Checks whether the host has brought up the link.
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// The packet filter the host last set, as `FILTER_*` bits.  The
+    /// device passes every frame through regardless; a stack that cares
+    /// can apply the filter itself.
+    pub fn packet_filter(&self) -> u16 {
+        self.filter
+    }
+
+    /// Number of received frames dropped for being too long.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Handles the host selecting alternate setting `alt` of the data
+    /// interface: setting 1 brings the link up, and setting 0 takes it
+    /// down.  Returns `false` for settings that don't exist.
+    pub fn set_alternate(&mut self, alt: u8) -> bool {
+        match alt {
+            0 | 1 => {
+                self.connected = alt == 1;
+                self.notify = Notify::Connection;
+                self.drop_frames();
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Handles a class request addressed to our control interface.  Returns
+    /// the response length, or `None` if the request isn't ours (and should
+    /// be stalled).  None of the requests supported have a data stage to
+    /// respond with.
+    pub fn handle_class_request(&mut self, setup: &SetupPacket)
+        -> Option<usize> {
+        if setup.is_in() {
+            return None
+        }
+        match setup.request {
+            SET_ETHERNET_PACKET_FILTER if setup.length == 0 => {
+                self.filter = setup.value;
+                Some(0)
+            },
+            // We don't filter, so there's no need to keep the list.
+            SET_ETHERNET_MULTICAST_FILTERS => Some(0),
+            _ => None,
+        }
+    }
+
+    /// Makes as much progress as the endpoints allow, without waiting:
+    /// sends notifications, takes in a received frame unless one is already
+    /// waiting, and sends the outgoing frame.
+    pub fn poll<E: EcmEndpoints>(&mut self, ep: &E) -> Result<(), E::Error> {
+        try!(self.poll_notify(ep));
+        if !self.connected {
+            return Ok(())
+        }
+        try!(self.poll_rx(ep));
+        self.poll_tx(ep)
+    }
+
+    fn poll_notify<E: EcmEndpoints>(&mut self, ep: &E)
+        -> Result<(), E::Error> {
+        let iface = self.interface;
+        let mut n = [0xA1, 0, 0, 0, iface, 0, 0, 0,
+                     0, 0, 0, 0, 0, 0, 0, 0];
+        let len = match self.notify {
+            Notify::None => return Ok(()),
+            Notify::Connection => {
+                n[1] = NETWORK_CONNECTION;
+                n[2] = self.connected as u8;
+                8
+            },
+            Notify::Speed => {
+                n[1] = CONNECTION_SPEED_CHANGE;
+                n[6] = 8;
+                put_le32(&mut n[8..12], BIT_RATE);
+                put_le32(&mut n[12..16], BIT_RATE);
+                16
+            },
+        };
+        match ep.try_notify(&n[..len]) {
+            Ok(()) => (),
+            Err(NbError::WouldBlock) => return Ok(()),
+            Err(NbError::Other(e)) => return Err(e),
+        }
+        self.notify = match self.notify {
+            Notify::Connection if self.connected => Notify::Speed,
+            _ => Notify::None,
+        };
+        Ok(())
+    }
+
+    fn poll_rx<E: EcmEndpoints>(&mut self, ep: &E) -> Result<(), E::Error> {
+        let mp = ep.max_packet();
+        let rx = &mut self.rx;
+        // While a frame waits for the stack, the host is held off.
+        while !rx.ready {
+            let start = if rx.discarding { 0 } else { rx.len };
+            let n = match ep.try_receive(&mut rx.buf[start..start + mp]) {
+                Ok(n) => n,
+                Err(NbError::WouldBlock) => return Ok(()),
+                Err(NbError::Other(e)) => return Err(e),
+            };
+            if !rx.discarding {
+                rx.len += n;
+                if rx.len > MTU {
+                    rx.discarding = true;
+                }
+            }
+            if n < mp {
+                // A short packet ends the frame.
+                if rx.discarding {
+                    self.dropped += 1;
+                    rx.discarding = false;
+                    rx.len = 0;
+                } else if rx.len > 0 {
+                    rx.ready = true;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_tx<E: EcmEndpoints>(&mut self, ep: &E) -> Result<(), E::Error> {
+        let mp = ep.max_packet();
+        let tx = &mut self.tx;
+        while tx.busy {
+            // A frame that's a multiple of the packet size ends with a
+            // zero-length packet.
+            let n = cmp::min(mp, tx.len - tx.pos);
+            match ep.try_send(&tx.buf[tx.pos..tx.pos + n]) {
+                Ok(()) => (),
+                Err(NbError::WouldBlock) => return Ok(()),
+                Err(NbError::Other(e)) => return Err(e),
+            }
+            tx.pos += n;
+            if n < mp {
+                tx.busy = false
+            }
+        }
+        Ok(())
+    }
+}
+
+fn put_le32(b: &mut [u8], v: u32) {
+    b[0] = v as u8;
+    b[1] = (v >> 8) as u8;
+    b[2] = (v >> 16) as u8;
+    b[3] = (v >> 24) as u8;
+}
+
+/// A frame received from the host.  The buffer is released, and the host
+/// allowed to send the next frame, when the token is dropped.
+pub struct EcmRxToken<'a> {
+    rx: &'a mut RxState,
+}
+
+impl<'a> RxToken for EcmRxToken<'a> {
+    fn consume<R, F: FnOnce(&[u8]) -> R>(self, f: F) -> R {
+        f(&self.rx.buf[..self.rx.len])
+    }
+}
+
+impl<'a> Drop for EcmRxToken<'a> {
+    fn drop(&mut self) {
+        self.rx.len = 0;
+        self.rx.ready = false;
+    }
+}
+
+/// The right to send a frame to the host.  The frame goes out over the
+/// following calls to `CdcEcm::poll`.
+pub struct EcmTxToken<'a> {
+    tx: &'a mut TxState,
+}
+
+impl<'a> TxToken for EcmTxToken<'a> {
+    fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F)
+        -> Result<R, NetError> {
+        if len > MTU {
+            return Err(NetError::FrameTooLong)
+        }
+        // A token handed out with a receive token doesn't promise a free
+        // buffer.
+        if self.tx.busy {
+            return Err(NetError::Exhausted)
+        }
+        let r = f(&mut self.tx.buf[..len]);
+        self.tx.len = len;
+        self.tx.pos = 0;
+        self.tx.busy = true;
+        Ok(r)
+    }
+}
+
+impl<'a> NetDevice<'a> for CdcEcm {
+    type Rx = EcmRxToken<'a>;
+    type Tx = EcmTxToken<'a>;
+
+    fn receive(&'a mut self) -> Option<(EcmRxToken<'a>, EcmTxToken<'a>)> {
+        if !self.rx.ready {
+            return None
+        }
+        let rx = EcmRxToken { rx: &mut self.rx };
+        let tx = EcmTxToken { tx: &mut self.tx };
+        Some((rx, tx))
+    }
+
+    fn transmit(&'a mut self) -> Option<EcmTxToken<'a>> {
+        if !self.connected || self.tx.busy {
+            return None
+        }
+        Some(EcmTxToken { tx: &mut self.tx })
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            mtu: MTU,
+            max_burst_size: Some(1),
+            checksum: NO_CHECKSUM_OFFLOAD,
+        }
+    }
+
+    fn mac_address(&self) -> [u8; 6] {
+        self.mac
+    }
+}
@@ -0,0 +1,391 @@
+//! USB protocol definitions shared by host and device drivers.
+//!
+//! This covers chapter 9 of the USB 2.0 specification -- setup packets,
+//! standard requests, and descriptors -- plus the few class requests the
+//! drivers here need.  It knows nothing about any particular controller.
+
+pub mod ecm;
+pub mod msc;
+
+/// Request type bit: data flows device-to-host.
+pub const DIR_IN : u8 = 0x80;
+pub const TYPE_STANDARD : u8 = 0 << 5;
+pub const TYPE_CLASS : u8 = 1 << 5;
+pub const TYPE_VENDOR : u8 = 2 << 5;
+pub const RECIPIENT_DEVICE : u8 = 0;
+pub const RECIPIENT_INTERFACE : u8 = 1;
+pub const RECIPIENT_ENDPOINT : u8 = 2;
+
+// Standard request codes.
+pub const GET_STATUS : u8 = 0;
+pub const CLEAR_FEATURE : u8 = 1;
+pub const SET_FEATURE : u8 = 3;
+pub const SET_ADDRESS : u8 = 5;
+pub const GET_DESCRIPTOR : u8 = 6;
+pub const GET_CONFIGURATION : u8 = 8;
+pub const SET_CONFIGURATION : u8 = 9;
+pub const GET_INTERFACE : u8 = 10;
+pub const SET_INTERFACE : u8 = 11;
+
+/// Feature selector for `CLEAR_FEATURE` on an endpoint: clears a halt.
+pub const ENDPOINT_HALT : u16 = 0;
+
+// Descriptor types.
+pub const DESC_DEVICE : u8 = 1;
+pub const DESC_CONFIGURATION : u8 = 2;
+pub const DESC_STRING : u8 = 3;
+pub const DESC_INTERFACE : u8 = 4;
+pub const DESC_ENDPOINT : u8 = 5;
+/// Interface Association Descriptor, grouping the interfaces of one function
+/// in a composite device.
+pub const DESC_INTERFACE_ASSOCIATION : u8 = 0x0B;
+pub const DESC_HID : u8 = 0x21;
+/// Class-specific interface descriptor, used by CDC functional descriptors.
+pub const DESC_CS_INTERFACE : u8 = 0x24;
+
+// Interface class codes.
+pub const CLASS_CDC : u8 = 0x02;
+pub const CLASS_HID : u8 = 0x03;
+pub const CLASS_MASS_STORAGE : u8 = 0x08;
+pub const CLASS_CDC_DATA : u8 = 0x0A;
+
+/// Transfer types, as encoded in the low bits of an endpoint descriptor's
+/// attributes.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum EndpointType {
+    Control = 0,
+    Isochronous = 1,
+    Bulk = 2,
+    Interrupt = 3,
+}
+
+impl EndpointType {
+    pub fn from_attributes(attr: u8) -> EndpointType {
+        match attr & 3 {
+            0 => EndpointType::Control,
+            1 => EndpointType::Isochronous,
+            2 => EndpointType::Bulk,
+            _ => EndpointType::Interrupt,
+        }
+    }
+}
+
+/// The eight bytes that begin every control transfer.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    /// Length of the data stage.
+    pub length: u16,
+}
+
+impl SetupPacket {
+    pub fn to_bytes(&self) -> [u8; 8] {
+        [
+            self.request_type,
+            self.request,
+            self.value as u8,
+            (self.value >> 8) as u8,
+            self.index as u8,
+            (self.index >> 8) as u8,
+            self.length as u8,
+            (self.length >> 8) as u8,
+        ]
+    }
+
+    pub fn from_bytes(b: &[u8; 8]) -> SetupPacket {
+        SetupPacket {
+            request_type: b[0],
+            request: b[1],
+            value: le16(&b[2..]),
+            index: le16(&b[4..]),
+            length: le16(&b[6..]),
+        }
+    }
+
+    /// Checks whether the data stage (if any) flows device-to-host.
+    pub fn is_in(&self) -> bool {
+        self.request_type & DIR_IN != 0
+    }
+
+    /// Requests `length` bytes of descriptor `kind` number `index`.
+    pub fn get_descriptor(kind: u8, index: u8, length: u16) -> SetupPacket {
+        SetupPacket {
+            request_type: DIR_IN | TYPE_STANDARD | RECIPIENT_DEVICE,
+            request: GET_DESCRIPTOR,
+            value: ((kind as u16) << 8) | index as u16,
+            index: 0,
+            length: length,
+        }
+    }
+
+    pub fn set_address(address: u8) -> SetupPacket {
+        SetupPacket {
+            request_type: TYPE_STANDARD | RECIPIENT_DEVICE,
+            request: SET_ADDRESS,
+            value: address as u16,
+            index: 0,
+            length: 0,
+        }
+    }
+
+    pub fn set_configuration(value: u8) -> SetupPacket {
+        SetupPacket {
+            request_type: TYPE_STANDARD | RECIPIENT_DEVICE,
+            request: SET_CONFIGURATION,
+            value: value as u16,
+            index: 0,
+            length: 0,
+        }
+    }
+
+    /// Clears a halt (stall) on endpoint `endpoint` (including its direction
+    /// bit), resetting its data toggle.
+    pub fn clear_halt(endpoint: u8) -> SetupPacket {
+        SetupPacket {
+            request_type: TYPE_STANDARD | RECIPIENT_ENDPOINT,
+            request: CLEAR_FEATURE,
+            value: ENDPOINT_HALT,
+            index: endpoint as u16,
+            length: 0,
+        }
+    }
+
+    /// HID `SET_IDLE`: report only on change (`duration` 0) or at most every
+    /// `duration` * 4ms.
+    pub fn hid_set_idle(interface: u8, duration: u8, report: u8)
+        -> SetupPacket {
+        SetupPacket {
+            request_type: TYPE_CLASS | RECIPIENT_INTERFACE,
+            request: 0x0A,
+            value: ((duration as u16) << 8) | report as u16,
+            index: interface as u16,
+            length: 0,
+        }
+    }
+
+    /// HID `SET_PROTOCOL`: the fixed boot protocol (for keyboards and mice)
+    /// if `boot`, else the device's report protocol.
+    pub fn hid_set_protocol(interface: u8, boot: bool) -> SetupPacket {
+        SetupPacket {
+            request_type: TYPE_CLASS | RECIPIENT_INTERFACE,
+            request: 0x0B,
+            value: if boot { 0 } else { 1 },
+            index: interface as u16,
+            length: 0,
+        }
+    }
+
+    /// Mass storage `GET_MAX_LUN`, answered with one byte.
+    pub fn msc_get_max_lun(interface: u8) -> SetupPacket {
+        SetupPacket {
+            request_type: DIR_IN | TYPE_CLASS | RECIPIENT_INTERFACE,
+            request: 0xFE,
+            value: 0,
+            index: interface as u16,
+            length: 1,
+        }
+    }
+
+    /// Mass storage Bulk-Only Mass Storage Reset.
+    pub fn msc_reset(interface: u8) -> SetupPacket {
+        SetupPacket {
+            request_type: TYPE_CLASS | RECIPIENT_INTERFACE,
+            request: 0xFF,
+            value: 0,
+            index: interface as u16,
+            length: 0,
+        }
+    }
+}
+
+fn le16(b: &[u8]) -> u16 {
+    (b[0] as u16) | ((b[1] as u16) << 8)
+}
+
+/// The fields of a device descriptor that drivers care about.
+#[derive(Copy, Clone, Debug)]
+pub struct DeviceDescriptor {
+    pub usb_version: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    /// Largest packet endpoint 0 accepts.
+    pub max_packet_size0: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub device_version: u16,
+    pub num_configurations: u8,
+}
+
+/// Length of a complete device descriptor.
+pub const DEVICE_DESCRIPTOR_LEN : usize = 18;
+
+impl DeviceDescriptor {
+    /// Parses a device descriptor, or returns `None` if `b` isn't one (or is
+    /// truncated).
+    pub fn parse(b: &[u8]) -> Option<DeviceDescriptor> {
+        if b.len() < DEVICE_DESCRIPTOR_LEN || b[1] != DESC_DEVICE {
+            return None
+        }
+        Some(DeviceDescriptor {
+            usb_version: le16(&b[2..]),
+            class: b[4],
+            subclass: b[5],
+            protocol: b[6],
+            max_packet_size0: b[7],
+            vendor_id: le16(&b[8..]),
+            product_id: le16(&b[10..]),
+            device_version: le16(&b[12..]),
+            num_configurations: b[17],
+        })
+    }
+}
+
+/// A configuration descriptor's header.
+#[derive(Copy, Clone, Debug)]
+pub struct ConfigurationDescriptor {
+    /// Length of the configuration descriptor and everything after it.
+    pub total_length: u16,
+    pub num_interfaces: u8,
+    /// Value to pass to `SET_CONFIGURATION`.
+    pub value: u8,
+    pub attributes: u8,
+    /// Maximum bus current, in units of 2mA.
+    pub max_power: u8,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct InterfaceDescriptor {
+    pub number: u8,
+    pub alternate: u8,
+    pub num_endpoints: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct EndpointDescriptor {
+    /// Endpoint number, with `DIR_IN` set for IN endpoints.
+    pub address: u8,
+    pub kind: EndpointType,
+    pub max_packet_size: u16,
+    /// Polling interval, in frames, for interrupt endpoints.
+    pub interval: u8,
+}
+
+impl EndpointDescriptor {
+    pub fn number(&self) -> u8 {
+        self.address & 0xF
+    }
+
+    pub fn is_in(&self) -> bool {
+        self.address & DIR_IN != 0
+    }
+}
+
+/// One descriptor from a configuration.
+#[derive(Copy, Clone, Debug)]
+pub enum Descriptor<'a> {
+    Configuration(ConfigurationDescriptor),
+    Interface(InterfaceDescriptor),
+    Endpoint(EndpointDescriptor),
+    /// Anything else (class-specific descriptors, mostly), as its type and
+    /// raw bytes.
+    Other(u8, &'a [u8]),
+}
+
+/// Walks the descriptors in a configuration, as returned by `GET_DESCRIPTOR`
+/// for `DESC_CONFIGURATION`.  Stops at the first malformed descriptor.
+pub struct Descriptors<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Descriptors<'a> {
+    pub fn new(config: &'a [u8]) -> Descriptors<'a> {
+        Descriptors { rest: config }
+    }
+}
+
+impl<'a> Iterator for Descriptors<'a> {
+    type Item = Descriptor<'a>;
+
+    fn next(&mut self) -> Option<Descriptor<'a>> {
+        if self.rest.len() < 2 {
+            return None
+        }
+        let len = self.rest[0] as usize;
+        if len < 2 || len > self.rest.len() {
+            self.rest = &[];
+            return None
+        }
+        let (b, rest) = self.rest.split_at(len);
+        self.rest = rest;
+
+        Some(match b[1] {
+            DESC_CONFIGURATION if len >= 9 =>
+                Descriptor::Configuration(ConfigurationDescriptor {
+                    total_length: le16(&b[2..]),
+                    num_interfaces: b[4],
+                    value: b[5],
+                    attributes: b[7],
+                    max_power: b[8],
+                }),
+            DESC_INTERFACE if len >= 9 =>
+                Descriptor::Interface(InterfaceDescriptor {
+                    number: b[2],
+                    alternate: b[3],
+                    num_endpoints: b[4],
+                    class: b[5],
+                    subclass: b[6],
+                    protocol: b[7],
+                }),
+            DESC_ENDPOINT if len >= 7 =>
+                Descriptor::Endpoint(EndpointDescriptor {
+                    address: b[2],
+                    kind: EndpointType::from_attributes(b[3]),
+                    max_packet_size: le16(&b[4..]) & 0x7FF,
+                    interval: b[6],
+                }),
+            kind => Descriptor::Other(kind, b),
+        })
+    }
+}
+
+/// Finds the first interface (in its default setting) of class `class` in
+/// configuration `config`.
+pub fn find_interface(config: &[u8], class: u8)
+    -> Option<InterfaceDescriptor> {
+    for d in Descriptors::new(config) {
+        if let Descriptor::Interface(i) = d {
+            if i.class == class && i.alternate == 0 {
+                return Some(i)
+            }
+        }
+    }
+    None
+}
+
+/// Finds the first endpoint of type `kind`, in direction IN if `is_in`,
+/// belonging to interface `interface` (in its default setting).
+pub fn find_endpoint(config: &[u8],
+                     interface: u8,
+                     kind: EndpointType,
+                     is_in: bool) -> Option<EndpointDescriptor> {
+    let mut inside = false;
+    for d in Descriptors::new(config) {
+        match d {
+            Descriptor::Interface(i) =>
+                inside = i.number == interface && i.alternate == 0,
+            Descriptor::Endpoint(e) =>
+                if inside && e.kind == kind && e.is_in() == is_in {
+                    return Some(e)
+                },
+            _ => (),
+        }
+    }
+    None
+}
@@ -0,0 +1,516 @@
+//! USB mass storage class, device side.
+//!
+//! `MassStorage` exposes any `BlockDevice` to a host as a SCSI disk, using
+//! the Bulk-Only Transport: the host sends a Command Block Wrapper (CBW) on
+//! the bulk OUT endpoint, data moves on whichever endpoint the command calls
+//! for, and the device finishes with a Command Status Wrapper (CSW) on the
+//! bulk IN endpoint.
+//!
+//! The class doesn't drive a USB controller itself.  Instead the controller's
+//! device driver provides the two bulk endpoints through `BulkEndpoints` (on
+//! the STM32F4, `stm32f4::otg_fs::device::Endpoints`), and the application
+//! calls `MassStorage::poll` whenever they might have made progress (on every
+//! endpoint interrupt, say).  Class requests arriving on endpoint 0 go to
+//! `MassStorage::handle_class_request`.
+//!
+//! Only a single logical unit is supported, and only the SCSI commands that
+//! common hosts actually send to a removable disk.
+
+use hal::{BlockDevice, NbError, NbResult, BLOCK_SIZE};
+use super::{SetupPacket, CLASS_MASS_STORAGE, DESC_ENDPOINT, DESC_INTERFACE};
+use super::DIR_IN;
+
+/// Interface subclass: SCSI transparent command set.
+pub const SUBCLASS_SCSI : u8 = 0x06;
+/// Interface protocol: Bulk-Only Transport.
+pub const PROTOCOL_BULK_ONLY : u8 = 0x50;
+
+const REQUEST_GET_MAX_LUN : u8 = 0xFE;
+const REQUEST_RESET : u8 = 0xFF;
+
+const CBW_SIGNATURE : u32 = 0x43425355;  // "USBC"
+const CBW_LEN : usize = 31;
+const CSW_SIGNATURE : u32 = 0x53425355;  // "USBS"
+const CSW_LEN : usize = 13;
+
+// CSW status codes.
+const STATUS_PASSED : u8 = 0;
+const STATUS_FAILED : u8 = 1;
+const STATUS_PHASE_ERROR : u8 = 2;
+
+// SCSI operation codes.
+const TEST_UNIT_READY : u8 = 0x00;
+const REQUEST_SENSE : u8 = 0x03;
+const INQUIRY : u8 = 0x12;
+const MODE_SENSE_6 : u8 = 0x1A;
+const START_STOP_UNIT : u8 = 0x1B;
+const PREVENT_ALLOW_REMOVAL : u8 = 0x1E;
+const READ_FORMAT_CAPACITIES : u8 = 0x23;
+const READ_CAPACITY_10 : u8 = 0x25;
+const READ_10 : u8 = 0x28;
+const WRITE_10 : u8 = 0x2A;
+const VERIFY_10 : u8 = 0x2F;
+const SYNCHRONIZE_CACHE_10 : u8 = 0x35;
+
+/// SCSI sense data: what went wrong with the last failed command.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+struct Sense {
+    key: u8,
+    asc: u8,
+    ascq: u8,
+}
+
+const NO_SENSE : Sense = Sense { key: 0, asc: 0, ascq: 0 };
+const INVALID_COMMAND : Sense = Sense { key: 0x05, asc: 0x20, ascq: 0 };
+const LBA_OUT_OF_RANGE : Sense = Sense { key: 0x05, asc: 0x21, ascq: 0 };
+const READ_ERROR : Sense = Sense { key: 0x03, asc: 0x11, ascq: 0 };
+const WRITE_ERROR : Sense = Sense { key: 0x03, asc: 0x0C, ascq: 0 };
+
+/// The pair of bulk endpoints a `MassStorage` talks through, provided by a
+/// USB device controller driver.
+pub trait BulkEndpoints {
+    type Error;
+
+    /// Largest packet the endpoints carry (64 at full speed).
+    fn max_packet(&self) -> usize;
+
+    /// Takes the next packet received on the OUT endpoint into `buf`, which
+    /// is at least `max_packet` long, and returns its length.
+    fn try_receive(&self, buf: &mut [u8]) -> NbResult<usize, Self::Error>;
+
+    /// Queues `data` (at most `max_packet` bytes) for the IN endpoint.  This
+    /// returns `WouldBlock` while the previous packet is unsent, or while the
+    /// endpoint is stalled.
+    fn try_send(&self, data: &[u8]) -> NbResult<(), Self::Error>;
+
+    /// Stalls the IN endpoint until the host clears the halt.
+    fn stall_in(&self);
+
+    /// Stalls the OUT endpoint until the host clears the halt.
+    fn stall_out(&self);
+}
+
+/// Length of the descriptors returned by `interface_descriptors`.
+pub const INTERFACE_DESCRIPTORS_LEN : usize = 9 + 7 + 7;
+
+/// Builds the interface and endpoint descriptors for a mass storage
+/// interface, for inclusion in the device's configuration descriptor.
+/// `ep_in` and `ep_out` are endpoint numbers, without direction bits.
+pub fn interface_descriptors(interface: u8,
+                             ep_in: u8,
+                             ep_out: u8,
+                             max_packet: u16)
+    -> [u8; INTERFACE_DESCRIPTORS_LEN] {
+    let mps_lo = max_packet as u8;
+    let mps_hi = (max_packet >> 8) as u8;
+    [
+        9, DESC_INTERFACE, interface, 0, 2,
+        CLASS_MASS_STORAGE, SUBCLASS_SCSI, PROTOCOL_BULK_ONLY, 0,
+
+        7, DESC_ENDPOINT, DIR_IN | ep_in, 2, mps_lo, mps_hi, 0,
+
+        7, DESC_ENDPOINT, ep_out, 2, mps_lo, mps_hi, 0,
+    ]
+}
+
+/// Strings reported in response to SCSI `INQUIRY`, space padded.
+#[derive(Copy, Clone, Debug)]
+pub struct Identity {
+    pub vendor: &'static [u8; 8],
+    pub product: &'static [u8; 16],
+    pub revision: &'static [u8; 4],
+}
+
+pub const DEFAULT_IDENTITY : Identity = Identity {
+    vendor: b"embrs   ",
+    product: b"Block device    ",
+    revision: b"1.0 ",
+};
+
+/// A decoded Command Block Wrapper.
+#[derive(Copy, Clone)]
+struct Cbw {
+    tag: u32,
+    data_length: u32,
+    is_in: bool,
+    command: [u8; 16],
+}
+
+impl Cbw {
+    fn parse(b: &[u8]) -> Option<Cbw> {
+        if b.len() != CBW_LEN || le32(&b[0..]) != CBW_SIGNATURE {
+            return None
+        }
+        let len = b[14] as usize & 0x1F;
+        if len == 0 || len > 16 {
+            return None
+        }
+        let mut command = [0; 16];
+        command[..len].copy_from_slice(&b[15..15 + len]);
+        Some(Cbw {
+            tag: le32(&b[4..]),
+            data_length: le32(&b[8..]),
+            is_in: b[12] & 0x80 != 0,
+            command: command,
+        })
+    }
+}
+
+fn le32(b: &[u8]) -> u32 {
+    (b[0] as u32)
+        | ((b[1] as u32) << 8)
+        | ((b[2] as u32) << 16)
+        | ((b[3] as u32) << 24)
+}
+
+fn be32(b: &[u8]) -> u32 {
+    ((b[0] as u32) << 24)
+        | ((b[1] as u32) << 16)
+        | ((b[2] as u32) << 8)
+        | (b[3] as u32)
+}
+
+fn put_le32(b: &mut [u8], v: u32) {
+    b[0] = v as u8;
+    b[1] = (v >> 8) as u8;
+    b[2] = (v >> 16) as u8;
+    b[3] = (v >> 24) as u8;
+}
+
+fn put_be32(b: &mut [u8], v: u32) {
+    b[0] = (v >> 24) as u8;
+    b[1] = (v >> 16) as u8;
+    b[2] = (v >> 8) as u8;
+    b[3] = v as u8;
+}
+
+fn min(a: usize, b: usize) -> usize {
+    if a < b { a } else { b }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum State {
+    /// Waiting for a CBW.
+    Command,
+    /// Sending `buf[pos..len]`, then any blocks remaining.
+    DataIn,
+    /// Receiving into `buf[pos..]`, writing each block as it fills.
+    DataOut,
+    /// Sending the CSW.
+    Status,
+    /// A bad CBW arrived; both endpoints stay stalled until a reset.
+    Stalled,
+}
+
+/// A Bulk-Only Transport mass storage device backed by a `BlockDevice`.
+pub struct MassStorage<'a, B: BlockDevice + 'a> {
+    device: &'a B,
+    identity: Identity,
+    state: State,
+    buf: [u8; BLOCK_SIZE],
+    pos: usize,
+    len: usize,
+    /// Next block to transfer, and how many remain.
+    block: u32,
+    blocks_left: u32,
+    /// Data stage bytes the host expects that haven't moved yet.
+    residue: u32,
+    tag: u32,
+    status: u8,
+    sense: Sense,
+}
+
+impl<'a, B: BlockDevice + 'a> MassStorage<'a, B> {
+    pub fn new(device: &'a B, identity: Identity) -> MassStorage<'a, B> {
+        MassStorage {
+            device: device,
+            identity: identity,
+            state: State::Command,
+            buf: [0; BLOCK_SIZE],
+            pos: 0,
+            len: 0,
+            block: 0,
+            blocks_left: 0,
+            residue: 0,
+            tag: 0,
+            status: STATUS_PASSED,
+            sense: NO_SENSE,
+        }
+    }
+
+    /// Abandons any command in progress and waits for the next CBW, as
+    /// after a Bulk-Only Mass Storage Reset.  The host clears any endpoint
+    /// halts itself.
+    pub fn reset(&mut self) {
+        self.state = State::Command;
+        self.pos = 0;
+        self.len = 0;
+        self.blocks_left = 0;
+        self.residue = 0;
+    }
+
+    /// Handles a class request addressed to our interface, writing any
+    /// response into `response`.  Returns the response length, or `None` if
+    /// the request isn't ours (and should be stalled).
+    pub fn handle_class_request(&mut self,
+                                setup: &SetupPacket,
+                                response: &mut [u8]) -> Option<usize> {
+        match setup.request {
+            REQUEST_GET_MAX_LUN if setup.is_in() && setup.length >= 1 => {
+                response[0] = 0;
+                Some(1)
+            },
+            REQUEST_RESET if !setup.is_in() && setup.length == 0 => {
+                self.reset();
+                Some(0)
+            },
+            _ => None,
+        }
+    }
+
+    /// Makes as much progress as the endpoints allow, without waiting.
+    pub fn poll<E: BulkEndpoints>(&mut self, ep: &E) -> Result<(), E::Error> {
+        loop {
+            let progressed = match self.state {
+                State::Command => try!(self.poll_command(ep)),
+                State::DataIn => try!(self.poll_data_in(ep)),
+                State::DataOut => try!(self.poll_data_out(ep)),
+                State::Status => try!(self.poll_status(ep)),
+                State::Stalled => false,
+            };
+            if !progressed {
+                return Ok(())
+            }
+        }
+    }
+
+    fn poll_command<E: BulkEndpoints>(&mut self, ep: &E)
+        -> Result<bool, E::Error> {
+        let n = match ep.try_receive(&mut self.buf) {
+            Ok(n) => n,
+            Err(NbError::WouldBlock) => return Ok(false),
+            Err(NbError::Other(e)) => return Err(e),
+        };
+        match Cbw::parse(&self.buf[..n]) {
+            Some(cbw) => self.execute(ep, &cbw),
+            None => {
+                ep.stall_in();
+                ep.stall_out();
+                self.state = State::Stalled
+            },
+        }
+        Ok(true)
+    }
+
+    fn execute<E: BulkEndpoints>(&mut self, ep: &E, cbw: &Cbw) {
+        self.tag = cbw.tag;
+        self.residue = cbw.data_length;
+        self.status = STATUS_PASSED;
+        let cmd = &cbw.command;
+
+        match cmd[0] {
+            TEST_UNIT_READY | START_STOP_UNIT | PREVENT_ALLOW_REMOVAL
+                | VERIFY_10 | SYNCHRONIZE_CACHE_10 =>
+                self.no_data(ep, cbw),
+            REQUEST_SENSE => {
+                let sense = self.sense;
+                self.sense = NO_SENSE;
+                self.buf[..18].copy_from_slice(&[
+                    0x70, 0, sense.key, 0, 0, 0, 0, 10,
+                    0, 0, 0, 0, sense.asc, sense.ascq, 0, 0, 0, 0,
+                ]);
+                self.respond(ep, cbw, min(18, cmd[4] as usize))
+            },
+            INQUIRY => {
+                self.buf[..8].copy_from_slice(&[
+                    0x00,  // direct access block device
+                    0x80,  // removable
+                    0x04,  // SPC-2
+                    0x02,  // response data format
+                    31,    // additional length
+                    0, 0, 0,
+                ]);
+                self.buf[8..16].copy_from_slice(self.identity.vendor);
+                self.buf[16..32].copy_from_slice(self.identity.product);
+                self.buf[32..36].copy_from_slice(self.identity.revision);
+                self.respond(ep, cbw, min(36, cmd[4] as usize))
+            },
+            MODE_SENSE_6 => {
+                // Header only: no block descriptors or pages, not write
+                // protected.
+                self.buf[..4].copy_from_slice(&[3, 0, 0, 0]);
+                self.respond(ep, cbw, min(4, cmd[4] as usize))
+            },
+            READ_FORMAT_CAPACITIES => {
+                let count = self.device.block_count();
+                self.buf[..4].copy_from_slice(&[0, 0, 0, 8]);
+                put_be32(&mut self.buf[4..8], count);
+                // Formatted media, then the block length.
+                put_be32(&mut self.buf[8..12], 0x02000000 | BLOCK_SIZE as u32);
+                let alloc = ((cmd[7] as usize) << 8) | cmd[8] as usize;
+                self.respond(ep, cbw, min(12, alloc))
+            },
+            READ_CAPACITY_10 => {
+                let last = self.device.block_count().wrapping_sub(1);
+                put_be32(&mut self.buf[0..4], last);
+                put_be32(&mut self.buf[4..8], BLOCK_SIZE as u32);
+                self.respond(ep, cbw, 8)
+            },
+            READ_10 | WRITE_10 => {
+                let block = be32(&cmd[2..6]);
+                let count = ((cmd[7] as u32) << 8) | cmd[8] as u32;
+                let total = self.device.block_count();
+                if block > total || count > total - block {
+                    return self.fail(ep, cbw, LBA_OUT_OF_RANGE)
+                }
+                self.start_blocks(ep, cbw, cmd[0] == READ_10, block, count)
+            },
+            _ => self.fail(ep, cbw, INVALID_COMMAND),
+        }
+    }
+
+    /// Finishes a command that moves no data.
+    fn no_data<E: BulkEndpoints>(&mut self, ep: &E, cbw: &Cbw) {
+        if cbw.data_length > 0 {
+            // The host expected data we don't have.
+            if cbw.is_in { ep.stall_in() } else { ep.stall_out() }
+        }
+        self.state = State::Status
+    }
+
+    /// Fails the command with `sense`, skipping any data stage.
+    fn fail<E: BulkEndpoints>(&mut self, ep: &E, cbw: &Cbw, sense: Sense) {
+        self.sense = sense;
+        self.status = STATUS_FAILED;
+        self.no_data(ep, cbw)
+    }
+
+    /// Sends the first `len` bytes of `buf` as the data stage.
+    fn respond<E: BulkEndpoints>(&mut self, ep: &E, cbw: &Cbw, len: usize) {
+        if !cbw.is_in || cbw.data_length == 0 {
+            // The host isn't expecting what we have to send.
+            if cbw.data_length > 0 {
+                ep.stall_out()
+            }
+            self.status = STATUS_PHASE_ERROR;
+            self.state = State::Status;
+            return
+        }
+        self.pos = 0;
+        self.len = min(len, cbw.data_length as usize);
+        self.blocks_left = 0;
+        self.state = State::DataIn
+    }
+
+    /// Sets up a READ or WRITE of `count` blocks from `block`.
+    fn start_blocks<E: BulkEndpoints>(&mut self,
+                                      ep: &E,
+                                      cbw: &Cbw,
+                                      read: bool,
+                                      block: u32,
+                                      count: u32) {
+        let bytes = count as u64 * BLOCK_SIZE as u64;
+        if count == 0 || cbw.is_in != read {
+            if count != 0 {
+                self.status = STATUS_PHASE_ERROR
+            }
+            return self.no_data(ep, cbw)
+        }
+        if (cbw.data_length as u64) < bytes {
+            // We'll move what the host asked for, but the command can't
+            // complete.
+            self.status = STATUS_PHASE_ERROR
+        }
+        self.block = block;
+        self.blocks_left = count;
+        self.pos = 0;
+        self.len = 0;
+        self.state = if read { State::DataIn } else { State::DataOut }
+    }
+
+    fn poll_data_in<E: BulkEndpoints>(&mut self, ep: &E)
+        -> Result<bool, E::Error> {
+        if self.pos == self.len {
+            if self.blocks_left == 0 || self.residue == 0 {
+                // The host wanted more than we had: stall to end the data
+                // stage, and report the shortfall in the CSW.
+                if self.residue > 0 {
+                    ep.stall_in()
+                }
+                self.state = State::Status;
+                return Ok(true)
+            }
+            if self.device.read_blocks(self.block, &mut self.buf).is_err() {
+                self.sense = READ_ERROR;
+                self.status = STATUS_FAILED;
+                ep.stall_in();
+                self.state = State::Status;
+                return Ok(true)
+            }
+            self.block += 1;
+            self.blocks_left -= 1;
+            self.pos = 0;
+            self.len = min(BLOCK_SIZE, self.residue as usize);
+        }
+
+        let end = min(self.pos + ep.max_packet(), self.len);
+        match ep.try_send(&self.buf[self.pos..end]) {
+            Ok(()) => {
+                self.residue -= (end - self.pos) as u32;
+                self.pos = end;
+                Ok(true)
+            },
+            Err(NbError::WouldBlock) => Ok(false),
+            Err(NbError::Other(e)) => Err(e),
+        }
+    }
+
+    fn poll_data_out<E: BulkEndpoints>(&mut self, ep: &E)
+        -> Result<bool, E::Error> {
+        let n = match ep.try_receive(&mut self.buf[self.pos..]) {
+            Ok(n) => n,
+            Err(NbError::WouldBlock) => return Ok(false),
+            Err(NbError::Other(e)) => return Err(e),
+        };
+        self.pos += n;
+        self.residue = self.residue.saturating_sub(n as u32);
+
+        if self.pos == BLOCK_SIZE {
+            self.pos = 0;
+            if self.device.write_blocks(self.block, &self.buf).is_err() {
+                self.sense = WRITE_ERROR;
+                self.status = STATUS_FAILED;
+                self.blocks_left = 0;
+            } else {
+                self.block += 1;
+                self.blocks_left -= 1;
+            }
+        }
+
+        if self.blocks_left == 0 || self.residue == 0 || n < ep.max_packet() {
+            if self.residue > 0 {
+                ep.stall_out()
+            }
+            self.state = State::Status
+        }
+        Ok(true)
+    }
+
+    fn poll_status<E: BulkEndpoints>(&mut self, ep: &E)
+        -> Result<bool, E::Error> {
+        let mut csw = [0; CSW_LEN];
+        put_le32(&mut csw[0..4], CSW_SIGNATURE);
+        put_le32(&mut csw[4..8], self.tag);
+        put_le32(&mut csw[8..12], self.residue);
+        csw[12] = self.status;
+
+        match ep.try_send(&csw) {
+            Ok(()) => {
+                self.state = State::Command;
+                Ok(true)
+            },
+            Err(NbError::WouldBlock) => Ok(false),
+            Err(NbError::Other(e)) => Err(e),
+        }
+    }
+}
@@ -8,7 +8,8 @@
 extern crate embrs;
 
 use embrs::arm_m::{self, exc, sys_tick};
-use embrs::stm32f4::rcc::{self, RCC, AhbPeripheral, ApbPeripheral};
+use embrs::clock;
+use embrs::stm32f4::rcc::{self, RCC, AhbPeripheral};
 use embrs::stm32f4::gpio::{self, gpioa, gpiod};
 
 /******************************************************************************/
@@ -22,7 +23,6 @@ fn led_pins() -> gpio::PinMask {
 
 /// Frequency of toggling (= half frequency of blinking).
 const TOGGLE_HZ : u32 = 10;
-const HZ : u32 = 160_000_000;
 
 const CLOCKS : rcc::ClockConfig = rcc::ClockConfig {
     crystal_hz: 8_000_000_f32,
@@ -41,21 +41,26 @@ const CLOCKS : rcc::ClockConfig = rcc::ClockConfig {
 /// The application entry point.
 #[no_mangle]
 pub extern fn embrs_main() -> ! {
-    RCC.configure_clocks(&CLOCKS);
+    // If the crystal or PLL fails, keep running (slowly) on the HSI.
+    let speeds = match RCC.configure_clocks(&CLOCKS) {
+        Ok(()) => CLOCKS.compute_speeds(),
+        Err(_) => rcc::DEGRADED_SPEEDS,
+    };
+    clock::freeze(speeds);
 
     init_leds();
     init_uart();
 
     // Configure the SysTick timer to generate interrupts at our toggle
     // frequency.
-    let cycles_per_toggle = HZ / TOGGLE_HZ;
-    sys_tick::SYS_TICK.write_rvr(cycles_per_toggle - 1);
-
-    sys_tick::SYS_TICK.write_csr(
-        sys_tick::SYS_TICK.read_csr()
-        .with_enable(true)
-        .with_tickint(true)
-        .with_clksource(sys_tick::ClkSource::ProcessorClock));
+    let source = sys_tick::SYS_TICK
+        .configure_for_hz(clock::frozen(), TOGGLE_HZ)
+        .ok().expect("toggle rate unachievable");
+    sys_tick::SYS_TICK.configure(sys_tick::Config {
+        enable: true,
+        tickint: true,
+        clksource: source,
+    });
 
     // Put the processor in an idle state waiting for interrupts from SysTick.
     loop {
@@ -76,18 +81,10 @@ fn init_uart() {
     use embrs::stm32f4::usart::*;
 
     // Enable clock to USART2.
-    RCC.enable_clock(ApbPeripheral::Usart2);
+    USART2.enable_clock();
 
     USART2.update_cr1(|v| v.with_ue(true));
-
-    let speeds = CLOCKS.compute_speeds();
-
-    let clk = speeds.get_clock_for(ApbPeripheral::Usart2);
-    let brr = (clk / 115200_f32 + 0.5) as u32;
-
-    USART2.update_brr(|v| v.with_mantissa(brr >> 4)
-                      .with_fraction(brr & 0xF));
-
+    USART2.set_baud_frozen(115200).ok().expect("baud rate unachievable");
     USART2.update_cr1(|v| v.with_te(true));
 
     RCC.enable_clock(AhbPeripheral::GpioA);
@@ -145,6 +142,12 @@ pub static ISR_VECTORS : exc::ExceptionTable = exc::ExceptionTable {
 
 // Application environment.
 
+/// Facts about the board's memory, generated by `build.rs`.
+#[allow(dead_code)]
+mod board {
+    include!(concat!(env!("OUT_DIR"), "/board.rs"));
+}
+
 extern {
     /// This symbol is exported by the linker script, and defines the initial
     /// stack pointer.